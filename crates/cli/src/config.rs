@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Project defaults read from the workspace root.
+pub const CONFIG_FILE_NAME: &str = ".bazel-differrous.toml";
+
+/// Defaults for the hashing options, keyed like the command-line flags they
+/// stand in for. A flag given on the command line wins over its key; paths
+/// are relative to the workspace root.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub bazel_path: Option<PathBuf>,
+    pub bazel_startup_options: Option<Vec<String>>,
+    pub bazel_command_options: Option<Vec<String>>,
+    pub cquery_command_options: Option<Vec<String>>,
+    pub use_cquery: Option<bool>,
+    pub fail_on_query_errors: Option<bool>,
+    pub include_target_type: Option<bool>,
+    pub exclude_external_targets: Option<bool>,
+    pub ignored_rule_hashing_attributes: Option<Vec<String>>,
+    pub no_default_ignored_attributes: Option<bool>,
+    pub exclude_patterns: Option<Vec<String>>,
+    pub fine_grained_hash_external_repos: Option<Vec<String>>,
+    pub fine_grained_hash_external_repos_file: Option<PathBuf>,
+    pub seed_build_flags_file: Option<PathBuf>,
+    pub offline: Option<bool>,
+    pub repository_cache: Option<PathBuf>,
+    pub hash_repo_markers: Option<bool>,
+    pub jobs: Option<u16>,
+    pub content_cache: Option<PathBuf>,
+}
+
+impl ProjectConfig {
+    /// Reads `.bazel-differrous.toml` from `workspace`, if there is one.
+    pub fn load(workspace: &Path) -> Result<Option<Self>> {
+        let path = workspace.join(CONFIG_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let problems = validate(&path)?;
+        if !problems.is_empty() {
+            bail!(
+                "invalid {}:\n  {}\n(run `bazel-differrous config validate` for details)",
+                path.display(),
+                problems.join("\n  ")
+            );
+        }
+        let text = read(&path)?;
+        let mut config: Self =
+            toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+        config.resolve_paths(workspace);
+        Ok(Some(config))
+    }
+
+    /// Makes relative paths relative to `workspace`. A bare `bazelPath` such
+    /// as `bazelisk` is left to be looked up on `PATH`.
+    fn resolve_paths(&mut self, workspace: &Path) {
+        let bazel_path = self
+            .bazel_path
+            .as_mut()
+            .filter(|path| path.components().count() > 1);
+        let paths = [
+            bazel_path,
+            self.fine_grained_hash_external_repos_file.as_mut(),
+            self.seed_build_flags_file.as_mut(),
+            self.repository_cache.as_mut(),
+            self.content_cache.as_mut(),
+        ];
+        for path in paths.into_iter().flatten() {
+            if path.is_relative() {
+                *path = workspace.join(&*path);
+            }
+        }
+    }
+}
+
+/// Every problem of the config file at `path`: unknown keys and values of the
+/// wrong type, one message per key. Empty when the file is valid.
+pub fn validate(path: &Path) -> Result<Vec<String>> {
+    let text = read(path)?;
+    let table: toml::Table = match toml::from_str(&text) {
+        Ok(table) => table,
+        Err(err) => return Ok(vec![format!("not valid TOML: {}", err.message())]),
+    };
+    let mut problems = Vec::new();
+    for (key, value) in table {
+        let single = toml::Value::Table(toml::Table::from_iter([(key.clone(), value)]));
+        if let Err(err) = single.try_into::<ProjectConfig>() {
+            problems.push(format!("{key}: {}", err.message().trim()));
+        }
+    }
+    Ok(problems)
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))
+}
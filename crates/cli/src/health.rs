@@ -0,0 +1,197 @@
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// How long a health or metrics client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most of a request read; the rest of larger requests is ignored.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// The longest pause between failed accepts, e.g. while the process is out
+/// of file descriptors.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Request counters of `serve`, exposed on `/metrics`.
+#[derive(Debug)]
+pub struct ServeMetrics {
+    started: Instant,
+    draining: AtomicBool,
+    in_flight: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    /// Time spent running requests, in microseconds.
+    busy_micros: AtomicU64,
+}
+
+impl ServeMetrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            draining: AtomicBool::new(false),
+            in_flight: AtomicU64::new(0),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            busy_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn start_request(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finish_request(&self, ok: bool, elapsed: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let answered = if ok { &self.succeeded } else { &self.failed };
+        answered.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.busy_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Marks the server as shutting down, so `/healthz` fails and load
+    /// balancers stop sending it work. Returns the requests still running.
+    pub fn drain(&self) -> u64 {
+        self.draining.store(true, Ordering::Relaxed);
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let busy = load(&self.busy_micros) as f64 / 1e6;
+        let uptime = self.started.elapsed().as_secs_f64();
+        format!(
+            "# HELP bazel_differrous_serve_requests_total Requests answered, by status.\n\
+             # TYPE bazel_differrous_serve_requests_total counter\n\
+             bazel_differrous_serve_requests_total{{status=\"ok\"}} {}\n\
+             bazel_differrous_serve_requests_total{{status=\"error\"}} {}\n\
+             # HELP bazel_differrous_serve_request_seconds_total Time spent running requests.\n\
+             # TYPE bazel_differrous_serve_request_seconds_total counter\n\
+             bazel_differrous_serve_request_seconds_total {busy}\n\
+             # HELP bazel_differrous_serve_in_flight_requests Requests running now.\n\
+             # TYPE bazel_differrous_serve_in_flight_requests gauge\n\
+             bazel_differrous_serve_in_flight_requests {}\n\
+             # HELP bazel_differrous_serve_draining Whether the server is shutting down.\n\
+             # TYPE bazel_differrous_serve_draining gauge\n\
+             bazel_differrous_serve_draining {}\n\
+             # HELP bazel_differrous_serve_uptime_seconds Time since the server started.\n\
+             # TYPE bazel_differrous_serve_uptime_seconds gauge\n\
+             bazel_differrous_serve_uptime_seconds {uptime}\n",
+            load(&self.succeeded),
+            load(&self.failed),
+            load(&self.in_flight),
+            u8::from(self.is_draining()),
+        )
+    }
+}
+
+/// Answers `GET /healthz` and `GET /metrics` on `address` until the process
+/// exits. Each connection gets one response and is closed.
+pub async fn listen(address: SocketAddr, metrics: Arc<ServeMetrics>) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("failed to listen on {address}"))?;
+    info!(
+        address = %listener.local_addr().unwrap_or(address),
+        "serving /healthz and /metrics"
+    );
+    tokio::spawn(async move {
+        let mut backoff = Duration::ZERO;
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => {
+                    backoff = Duration::ZERO;
+                    stream
+                }
+                Err(err) => {
+                    backoff = (backoff * 2)
+                        .max(Duration::from_millis(10))
+                        .min(MAX_ACCEPT_BACKOFF);
+                    debug!("failed to accept an HTTP connection, retrying in {backoff:?}: {err}");
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(err) = respond(stream, &metrics).await {
+                    debug!("dropping HTTP connection: {err:#}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn respond(stream: TcpStream, metrics: &ServeMetrics) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let reader = BufReader::new(reader.take(MAX_REQUEST_BYTES));
+    let path = tokio::time::timeout(REQUEST_TIMEOUT, request_path(reader))
+        .await
+        .context("timed out reading the request")?;
+    let (status, content_type, body) = match path {
+        Err(err) => {
+            debug!("bad HTTP request: {err:#}");
+            ("400 Bad Request", "text/plain", "bad request\n".to_string())
+        }
+        Ok(None) => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "only GET is supported\n".to_string(),
+        ),
+        Ok(Some(path)) => match path.as_str() {
+            "/healthz" if metrics.is_draining() => (
+                "503 Service Unavailable",
+                "text/plain",
+                "draining\n".to_string(),
+            ),
+            "/healthz" => ("200 OK", "text/plain", "ok\n".to_string()),
+            "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        },
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// The path of a `GET` request, without its query string; `None` for other
+/// methods. The headers are read and ignored; `reader` should be limited, as
+/// lines are read until a newline or the end of input.
+async fn request_path<R: tokio::io::AsyncRead + Unpin>(
+    mut reader: BufReader<R>,
+) -> Result<Option<String>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("malformed request line {request_line:?}");
+    };
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    if method != "GET" {
+        return Ok(None);
+    }
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Some(path.to_string()))
+}
@@ -1,15 +1,23 @@
 use anyhow::{bail, Context, Result};
 use bazel_differrous_core as core;
-use clap::{ArgAction, Args, Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use tracing::{error, info};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
+mod config;
+#[cfg(unix)]
+mod health;
+mod style;
+
 #[derive(Parser, Debug)]
 #[command(
     name = "bazel-differrous",
@@ -22,6 +30,27 @@ struct Cli {
     #[arg(short = 'v', long, global = true, action = ArgAction::SetTrue)]
     verbose: bool,
 
+    /// Accept another tool's command-line syntax so existing wrapper scripts
+    /// keep working. Also enabled when the binary is invoked as `bazel-diff`.
+    #[arg(long = "compat", value_enum, value_name = "TOOL", global = true)]
+    compat: Option<CompatMode>,
+
+    /// Append every Bazel command this run spawns to FILE as a JSON line with
+    /// its arguments, working directory, duration and exit code.
+    #[arg(long = "logBazelCommands", value_name = "FILE", global = true)]
+    log_bazel_commands: Option<PathBuf>,
+
+    /// When to color logs, errors and reports: `auto` colors terminals unless
+    /// NO_COLOR is set.
+    #[arg(
+        long = "color",
+        value_enum,
+        value_name = "WHEN",
+        global = true,
+        default_value_t = style::ColorChoice::Auto
+    )]
+    color: style::ColorChoice,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -33,22 +62,156 @@ enum Commands {
     GenerateHashes(GenerateHashesArgs),
     /// Compute impacted targets between two hash sets.
     GetImpactedTargets(GetImpactedTargetsArgs),
+    /// Convert a dep edges file between the v1 (label map) and v2 (indexed) formats.
+    ConvertDepEdges(ConvertDepEdgesArgs),
+    /// Generate hashes for the workspace and report targets impacted since a baseline.
+    Diff(DiffArgs),
+    /// Report impacted target counts between consecutive hash snapshots.
+    Trend(TrendArgs),
+    /// Compare hash-based impacted targets with `bazel query rdeps()` for a
+    /// sample of changed files, reporting false negatives and positives.
+    ValidateImpact(ValidateImpactArgs),
+    /// Check hash and dep edges files for malformed structure, labels and hash
+    /// strings, printing diagnostics with the surrounding lines.
+    Validate(ValidateArgs),
+    /// Hash a single target from a `deps()` query and print its digests and
+    /// the input digests they were computed from.
+    HashTarget(HashTargetArgs),
+    /// Remove temporary files and baseline worktrees left behind by runs that
+    /// were killed, printing each removed path.
+    Clean(CleanArgs),
+    /// Remove the cached state of workspaces not used for a while, then of
+    /// the least recently used ones until the cache fits its size limit.
+    Gc(GcArgs),
+    /// Combine two impacted-target lists, e.g. to subtract quarantined targets
+    /// or keep only deployable ones.
+    SetOp(SetOpArgs),
+    /// Print the dependencies of a set of labels from an existing dep edges
+    /// file, e.g. to warm a remote cache, without running Bazel.
+    ExtractDeps(ExtractDepsArgs),
+    /// Answer repeated commands over a Unix socket, keeping the query result
+    /// and source digests in memory between them.
+    Serve(ServeArgs),
+    /// Inspect the `.bazel-differrous.toml` project defaults.
+    Config(ConfigArgs),
 }
 
 #[derive(Args, Debug)]
 struct GenerateHashesArgs {
-    /// Path to the Bazel workspace to inspect.
+    #[command(flatten)]
+    hashing: HashingArgs,
+    /// Output path (STDOUT if omitted).
+    #[arg(value_name = "OUTPUT")]
+    output_path: Option<PathBuf>,
+    /// Hash output format. `ndjson` is written incrementally while hashing and
+    /// requires an OUTPUT path.
+    #[arg(long = "outputFormat", value_enum, default_value_t = HashOutputFormat::Json)]
+    output_format: HashOutputFormat,
+    /// Partial NDJSON output of an interrupted run; records that still match the
+    /// query result and options are reused so only missing targets are hashed.
+    #[arg(long = "resumeFrom", value_name = "FILE")]
+    resume_from: Option<PathBuf>,
+    /// Print the Bazel commands hashing would run, as a shell script with the
+    /// query files they read, and exit without running any of them.
+    #[arg(long = "dryRun", action = ArgAction::SetTrue)]
+    dry_run: bool,
+    /// Extra flag names whose values are redacted from the command line recorded
+    /// in `meta.generatedBy` (json-v2), on top of tokens, passwords and secrets.
+    #[arg(long = "redactArgs", value_name = "NAME", value_delimiter = ',')]
+    redact_args: Vec<String>,
+    /// Write only the hashes that differ from this previous hash file, plus the
+    /// labels removed since, as a delta for `get-impacted-targets --applyDelta`.
+    #[arg(long = "deltaAgainst", value_name = "FILE")]
+    delta_against: Option<PathBuf>,
+    /// Shell command the serialized hashes are piped through before writing;
+    /// its stdout becomes the output (not supported with ndjson).
+    #[arg(long = "postProcess", value_name = "CMD")]
+    post_process: Option<String>,
+    /// Optional dep edges output file; `-` writes them to stdout.
+    #[arg(
+        short = 'd',
+        long = "depEdgesFile",
+        alias = "dep-edges-file",
+        value_name = "FILE"
+    )]
+    dep_edges_file: Option<PathBuf>,
+    /// Write hashes and dep edges as one JSON document,
+    /// `{"hashes": {...}, "depEdges": {...}}`, that every reader accepts both as
+    /// a hash file and as a dep edges file.
+    #[arg(
+        long = "combinedOutput",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["delta_against", "resume_from"]
+    )]
+    combined_output: bool,
+    /// Dep edges file format: v1 label map (upstream) or v2 indexed adjacency lists.
+    #[arg(long = "depEdgesFormat", value_enum, default_value_t = DepEdgesFormat::V1)]
+    dep_edges_format: DepEdgesFormat,
+    /// Which way the dep edges file points: `forward` maps targets to their
+    /// dependencies (upstream), `reverse` to their dependents, `both` writes
+    /// both maps.
+    #[arg(
+        long = "depEdgesDirection",
+        value_enum,
+        default_value_t = DepEdgesDirection::Forward
+    )]
+    dep_edges_direction: DepEdgesDirection,
+    /// Also record, as `execDepEdges` in the dep edges file, the edges to deps
+    /// cquery only resolved in an exec configuration, such as compilers and
+    /// code generators. Requires --useCquery.
+    #[arg(
+        long = "execDepEdges",
+        action = ArgAction::SetTrue,
+        requires = "dep_edges_file",
+        conflicts_with = "combined_output"
+    )]
+    exec_dep_edges: bool,
+    /// Record per-rule hashing and per-source IO timings and write a JSON report
+    /// of the slowest targets (with byte counts) to this file.
+    #[arg(long = "hashProfile", value_name = "FILE")]
+    hash_profile: Option<PathBuf>,
+    /// Number of slowest rules/sources to keep in the --hashProfile report.
+    #[arg(long = "hashProfileTopK", value_name = "N", default_value_t = 25)]
+    hash_profile_top_k: usize,
+    /// Write the workspace's source files that no hashed rule uses as an
+    /// input, one label per line.
+    #[arg(long = "orphanSourcesFile", value_name = "FILE")]
+    orphan_sources_file: Option<PathBuf>,
+    /// Write a JSON audit of every file read while hashing sources, with byte
+    /// counts and whether it lies in the workspace or an external repository.
+    #[arg(long = "auditFileAccess", value_name = "FILE")]
+    audit_file_access: Option<PathBuf>,
+    /// After writing the outputs, write a JSON report of their sizes, label
+    /// counts by target type, average entry size and estimated gzip ratios.
+    #[arg(long = "artifactReport", value_name = "FILE")]
+    artifact_report: Option<PathBuf>,
+    /// Stop hashing after this many seconds, hashing recently changed packages
+    /// first, and record the packages left unhashed in `meta.coverage`.
+    /// Requires --outputFormat json-v2 or --combinedOutput.
+    #[arg(long = "timeBudget", value_name = "SECS")]
+    time_budget: Option<u64>,
+    /// Write a JSON report of things worth reviewing that did not fail the run,
+    /// such as the files changed by --normalizeWhitespace/--normalizeComments
+    /// and the dependency cycles broken by --onCycle.
+    #[arg(long = "warningsFile", value_name = "FILE")]
+    warnings_file: Option<PathBuf>,
+}
+
+/// Options controlling how a workspace is queried and hashed; shared by every
+/// subcommand that runs Bazel.
+#[derive(Args, Debug, Clone)]
+struct HashingArgs {
+    /// Path to the Bazel workspace to inspect. Repeat for nested workspaces; labels
+    /// from every workspace after the first are namespaced as `<dirname>!//pkg:t`.
     #[arg(
         short = 'w',
         long = "workspacePath",
         alias = "workspace-path",
         value_name = "DIR",
-        required = true
+        required = true,
+        action = ArgAction::Append
     )]
-    workspace_path: PathBuf,
-    /// Output JSON path (STDOUT if omitted).
-    #[arg(value_name = "OUTPUT")]
-    output_path: Option<PathBuf>,
+    workspace_path: Vec<PathBuf>,
     /// Optional Bazel binary to invoke.
     #[arg(short = 'b', long = "bazelPath", alias = "bazel-path")]
     bazel_path: Option<PathBuf>,
@@ -59,7 +222,7 @@ struct GenerateHashesArgs {
         value_delimiter = ' ',
         num_args = 0..
     )]
-    bazel_startup_options: Vec<String>,
+    bazel_startup_options: Option<Vec<String>>,
     /// Additional Bazel command options.
     #[arg(
         long = "bazelCommandOptions",
@@ -67,7 +230,7 @@ struct GenerateHashesArgs {
         value_delimiter = ' ',
         num_args = 0..
     )]
-    bazel_command_options: Vec<String>,
+    bazel_command_options: Option<Vec<String>>,
     /// Additional Bazel cquery command options (only when --useCquery is set).
     #[arg(
         long = "cqueryCommandOptions",
@@ -75,44 +238,123 @@ struct GenerateHashesArgs {
         value_delimiter = ' ',
         num_args = 0..
     )]
-    bazel_cquery_options: Vec<String>,
+    bazel_cquery_options: Option<Vec<String>>,
     /// Prefer cquery over query when generating the graph.
-    #[arg(long = "useCquery", action = ArgAction::SetTrue)]
-    use_cquery: bool,
+    #[arg(
+        long = "useCquery",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    use_cquery: Option<bool>,
     /// Whether to keep going on Bazel errors (mirrors upstream default=true).
-    #[arg(short = 'k', long = "keep_going", default_value_t = true)]
+    #[arg(
+        short = 'k',
+        long = "keep_going",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true"
+    )]
     keep_going: bool,
+    /// Fail when Bazel reports errors loading packages instead of hashing
+    /// without their targets, as --keep_going otherwise does.
+    #[arg(
+        long = "failOnQueryErrors",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    fail_on_query_errors: Option<bool>,
     /// Include target type prefix (Rule/GeneratedFile/SourceFile) in hash values.
     #[arg(
         long = "includeTargetType",
         alias = "include-target-type",
-        action = ArgAction::SetTrue
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
     )]
-    include_target_type: bool,
+    include_target_type: Option<bool>,
     /// Placeholder for content hash map support (accepted for compatibility).
     #[arg(long = "contentHashPath", alias = "content-hash-path")]
     content_hash_path: Option<PathBuf>,
+    /// Build Event Protocol JSON (`--build_event_json_file`) of an earlier
+    /// `bazel build`; the output digests it reports are mixed into the hashes
+    /// of the matching generated files.
+    #[arg(long = "bepJsonFile", value_name = "FILE")]
+    bep_json_file: Option<PathBuf>,
+    /// Bazel `--disk_cache` directory (or `file://` URL), HTTP cache or
+    /// `grpc://`/`grpcs://` Remote Execution API cache whose cached action
+    /// results supply output digests like `--bepJsonFile`. Needs
+    /// `--executionLogJsonFile`.
+    #[arg(
+        long = "remoteCache",
+        value_name = "CACHE",
+        value_parser = core::RemoteCache::parse,
+        requires = "execution_log"
+    )]
+    remote_cache: Option<core::RemoteCache>,
+    /// Instance name (Bazel's `--remote_instance_name`) of a gRPC
+    /// `--remoteCache`.
+    #[arg(
+        long = "remoteInstanceName",
+        value_name = "NAME",
+        requires = "remote_cache"
+    )]
+    remote_instance_name: Option<String>,
+    /// Execution log (`--execution_log_json_file`, Bazel 6+) of the build
+    /// whose actions `--remoteCache` is asked for.
+    #[arg(long = "executionLogJsonFile", value_name = "FILE")]
+    execution_log: Option<PathBuf>,
     /// Attributes to ignore when hashing rules.
     #[arg(
         long = "ignoredRuleHashingAttributes",
         alias = "ignored-rule-hashing-attributes",
         value_delimiter = ','
     )]
-    ignored_attrs: Vec<String>,
+    ignored_attrs: Option<Vec<String>>,
+    /// Main repository packages left out of the query, e.g.
+    /// `//third_party/chromium/...`, on top of the directories listed in
+    /// `.bazelignore`. Their sources are hashed by path only and their targets
+    /// are not output.
+    #[arg(
+        long = "excludePatterns",
+        value_name = "PATTERN",
+        value_delimiter = ','
+    )]
+    exclude_patterns: Option<Vec<String>>,
+    /// Hash the attributes ignored by default (`generator_location`) too, so
+    /// moving a macro call impacts the targets it creates.
+    #[arg(
+        long = "noDefaultIgnoredAttributes",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    no_default_ignored_attrs: Option<bool>,
     /// Whether to exclude external targets.
     #[arg(
         long = "excludeExternalTargets",
         alias = "exclude-external-targets",
-        action = ArgAction::SetTrue
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
     )]
-    exclude_external_targets: bool,
+    exclude_external_targets: Option<bool>,
     /// Optional list of external repos to hash fine-grained targets for.
     #[arg(
         long = "fineGrainedHashExternalRepos",
         alias = "fine-grained-hash-external-repos",
-        value_delimiter = ','
+        value_delimiter = ',',
+        value_parser = parse_repo_name
     )]
-    fine_grained_external_repos: Vec<String>,
+    fine_grained_external_repos: Option<Vec<String>>,
     /// File containing newline-separated external repos for fine-grained hashing.
     #[arg(
         long = "fineGrainedHashExternalReposFile",
@@ -122,9 +364,181 @@ struct GenerateHashesArgs {
     /// Seed filepaths list; contents are hashed and mixed into all digests.
     #[arg(short = 's', long = "seed-filepaths")]
     seed_filepaths: Option<PathBuf>,
+    /// `.bazelrc` fragment with the flags CI builds use; its normalized flags
+    /// are mixed into the seed, so a flag change (e.g. a new `--copt`)
+    /// invalidates every baseline hashed with the old flags.
+    #[arg(long = "seedBuildFlagsFile", value_name = "FILE")]
+    seed_build_flags_file: Option<PathBuf>,
     /// Modified filepaths list; restricts which source files contribute content bytes.
     #[arg(short = 'm', long = "modified-filepaths")]
     modified_filepaths: Option<PathBuf>,
+    /// Compute the modified filepaths with `git diff` against this ref
+    /// (committed and uncommitted changes, renames and deletions).
+    #[arg(
+        long = "gitDiffBase",
+        value_name = "REF",
+        conflicts_with = "modified_filepaths"
+    )]
+    git_diff_base: Option<String>,
+    /// NDJSON hash file of an earlier run; source digests of main repository
+    /// files unchanged since (per `--modified-filepaths`/`--gitDiffBase`, or
+    /// modification times) are reused instead of reading the files.
+    #[arg(long = "previousHashes", value_name = "FILE")]
+    previous_hashes: Option<PathBuf>,
+    /// Never fetch external repositories (`--nofetch`); fail if a fine-grained
+    /// repo is not already available locally.
+    #[arg(
+        long = "offline",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    offline: Option<bool>,
+    /// Repository cache passed to every Bazel query as `--repository_cache`.
+    #[arg(long = "repositoryCache", value_name = "DIR")]
+    repository_cache: Option<PathBuf>,
+    /// Hash this `--output=streamed_proto` query (or cquery) result, or a query
+    /// `--output=streamed_jsonproto` result, instead of running Bazel query;
+    /// `-` reads it from stdin.
+    #[arg(long = "queryProtoPath", value_name = "FILE")]
+    query_proto_path: Option<PathBuf>,
+    /// Output format requested from `bazel query`. `streamed_jsonproto`
+    /// needs Bazel 7.0.0 or newer and falls back to `streamed_proto` on older
+    /// versions; cquery uses `streamed_proto` unless this is `proto`.
+    #[arg(
+        long = "queryOutputFormat",
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t = QueryOutputArg::StreamedProto
+    )]
+    query_output: QueryOutputArg,
+    /// Re-check the size and mtime of every hashed source file after hashing and
+    /// fail (default) or warn when any changed mid-run.
+    #[arg(
+        long = "detectConcurrentModifications",
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "fail"
+    )]
+    detect_concurrent_modifications: Option<ConcurrentModificationArg>,
+    /// Query mode: skip targets whose `target_compatible_with` cannot be met by a
+    /// platform with these constraint values (e.g. `@platforms//os:linux`).
+    #[arg(
+        long = "platformConstraints",
+        value_name = "LABEL",
+        value_delimiter = ',',
+        value_parser = parse_label,
+        conflicts_with = "use_cquery"
+    )]
+    platform_constraints: Option<Vec<String>>,
+    /// JSON rules rewriting cquery rule inputs per external repository (e.g.
+    /// vendored `@corp_*` repos), applied before the upstream heuristics.
+    #[arg(long = "inputRewriteConfig", value_name = "FILE")]
+    input_rewrite_config: Option<PathBuf>,
+    /// Also emit a `//pkg:__BUILD__` pseudo-target (type `BuildFile`) per
+    /// package hashing only its BUILD file, to see which packages had BUILD edits.
+    #[arg(long = "buildFileTargets", action = ArgAction::SetTrue)]
+    build_file_targets: bool,
+    /// Hash each source file's visibility (from `exports_files` or the package
+    /// default) into its digest, so visibility-only BUILD edits are impacted.
+    #[arg(long = "hashSourceVisibility", action = ArgAction::SetTrue)]
+    hash_source_visibility: bool,
+    /// Algorithm behind every hash. Recorded in the output metadata, so
+    /// anything but sha256 needs `--outputFormat json`, `json-v2` or
+    /// `--combinedOutput`; `get-impacted-targets` refuses to compare hashes of
+    /// different algorithms.
+    #[arg(
+        long = "hashFunction",
+        value_enum,
+        value_name = "FUNCTION",
+        default_value_t = HashFunctionArg::Sha256
+    )]
+    hash_function: HashFunctionArg,
+    /// What to do when rules depend on each other in a cycle. `break` and
+    /// `warn` hash the edge closing each cycle by the dep's label only, as
+    /// upstream bazel-diff, and list the cycles under `cycles` in
+    /// `--warningsFile`.
+    #[arg(
+        long = "onCycle",
+        value_enum,
+        value_name = "POLICY",
+        default_value_t = OnCycleArg::Error
+    )]
+    on_cycle: OnCycleArg,
+    /// Hash source files by path and size instead of contents: a quick check
+    /// of whether rule attributes or the build graph changed.
+    #[arg(long = "structureOnly", action = ArgAction::SetTrue)]
+    structure_only: bool,
+    /// Mix the `@<repo>.marker` file Bazel writes when fetching a fine-grained
+    /// external repo into its files' hashes, so re-fetches with a new patch or
+    /// environment impact dependents.
+    #[arg(
+        long = "hashRepoMarkers",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    hash_repo_markers: Option<bool>,
+    /// File extensions (or extensionless names such as `BUILD`) whose trailing
+    /// whitespace is ignored when hashing.
+    #[arg(
+        long = "normalizeWhitespace",
+        value_name = "EXT",
+        value_delimiter = ','
+    )]
+    normalize_whitespace: Vec<String>,
+    /// File extensions whose comments, trailing whitespace and blank lines are
+    /// ignored when hashing, so formatting-only commits impact nothing.
+    #[arg(long = "normalizeComments", value_name = "EXT", value_delimiter = ',')]
+    normalize_comments: Vec<String>,
+    /// Hash source files that `.gitattributes` marks `linguist-generated`,
+    /// `linguist-vendored` or `export-ignore` by path only (default) or skip
+    /// them in the output.
+    #[arg(
+        long = "gitAttributes",
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "path-only"
+    )]
+    git_attributes: Option<GitAttributesArg>,
+    /// Threads used to hash source files and rules (default: one per CPU).
+    #[arg(long = "jobs", value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: Option<u16>,
+    /// Source file digest cache reused across runs (default:
+    /// `content-cache.msgpack` in the workspace's state directory under
+    /// `$XDG_CACHE_HOME/bazel-differrous`).
+    #[arg(long = "contentCache", value_name = "FILE")]
+    content_cache: Option<PathBuf>,
+    /// Read every source file instead of reusing cached digests.
+    #[arg(
+        long = "noContentCache",
+        alias = "no-content-cache",
+        action = ArgAction::SetTrue,
+        conflicts_with = "content_cache"
+    )]
+    no_content_cache: bool,
+    /// Write nothing inside the workspaces, e.g. when CI mounts them
+    /// read-only: Bazel's query files go to the system temporary directory,
+    /// the content cache is not used, and outputs inside a workspace are
+    /// refused.
+    #[arg(long = "readOnly", action = ArgAction::SetTrue)]
+    read_only: bool,
+    /// Build the graph by parsing BUILD files instead of running Bazel, when
+    /// Bazel is unavailable or too slow to start. Macros are not expanded and
+    /// external repositories are left out, so the hashes are approximate and
+    /// marked so in the output metadata.
+    #[arg(
+        long = "noBazel",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["use_cquery", "fine_grained_external_repos", "fine_grained_external_repos_file"]
+    )]
+    no_bazel: bool,
     /// Target types to keep in the output.
     #[arg(
         short = 't',
@@ -134,14 +548,145 @@ struct GenerateHashesArgs {
         num_args = 1..
     )]
     target_types: Option<Vec<String>>,
-    /// Optional dep edges output file.
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CompatMode {
+    /// Upstream bazel-diff: `--flag=true|false` booleans, `--no-keep_going`,
+    /// and `generate-hashes -o FILE` for the output path.
+    BazelDiff,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HashOutputFormat {
+    /// A single `{"label": "hash"}` map, as written by upstream bazel-diff.
+    Json,
+    /// `{"meta": {"generatedBy": ...}, "hashes": {...}}` recording how the
+    /// hashes were produced.
+    JsonV2,
+    /// A map of labels to `{"transitive": ..., "direct": ..., "type": ...}`
+    /// objects instead of `Type#transitive~direct` strings.
+    DetailedJson,
+    /// A fingerprint header followed by one checksummed record per line.
+    Ndjson,
+    /// Length-delimited protobuf messages (`hashes.proto`): a header, then one
+    /// entry per target in label order.
+    Proto,
+    /// A MessagePack map of labels to hashes.
+    Msgpack,
+}
+
+impl HashOutputFormat {
+    fn is_binary(self) -> bool {
+        matches!(self, Self::Proto | Self::Msgpack)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HashFunctionArg {
+    /// SHA-256, as upstream bazel-diff.
+    Sha256,
+    /// BLAKE3, several times faster on large files.
+    Blake3,
+    /// 128-bit XXH3, the fastest; not collision resistant.
+    Xxh3,
+}
+
+impl From<HashFunctionArg> for core::HashFunction {
+    fn from(arg: HashFunctionArg) -> Self {
+        match arg {
+            HashFunctionArg::Sha256 => Self::Sha256,
+            HashFunctionArg::Blake3 => Self::Blake3,
+            HashFunctionArg::Xxh3 => Self::Xxh3,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnCycleArg {
+    /// Fail, naming the cycle.
+    Error,
+    /// Break each cycle silently.
+    Break,
+    /// Break each cycle and log it.
+    Warn,
+}
+
+impl From<OnCycleArg> for core::CyclePolicy {
+    fn from(arg: OnCycleArg) -> Self {
+        match arg {
+            OnCycleArg::Error => Self::Error,
+            OnCycleArg::Break => Self::Break,
+            OnCycleArg::Warn => Self::Warn,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum QueryOutputArg {
+    /// Length-delimited protobuf messages, the fastest to decode.
+    #[value(name = "streamed_proto")]
+    StreamedProto,
+    /// One JSON target per line, easier to archive and inspect.
+    #[value(name = "streamed_jsonproto")]
+    StreamedJsonproto,
+    /// One buffered protobuf message, for wrappers that break streaming.
+    #[value(name = "proto")]
+    Proto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ConcurrentModificationArg {
+    /// Log the files that changed and keep the output.
+    Warn,
+    /// Fail the run.
+    Fail,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GitAttributesArg {
+    /// Hash the label only, so content changes impact nothing.
+    PathOnly,
+    /// Hash the label only and leave the files out of the output.
+    Skip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DepEdgesFormat {
+    /// `{"label": ["dep", ...]}` as written by upstream bazel-diff.
+    V1,
+    /// `{"labels": [...], "edges": [[0, [1, 2]], ...]}` with labels stored once.
+    V2,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DepEdgesDirection {
+    /// `{"label": [deps...]}`.
+    Forward,
+    /// `{"reverseDepEdges": {"label": [dependents...]}}`.
+    Reverse,
+    /// `{"depEdges": {...}, "reverseDepEdges": {...}}`.
+    Both,
+}
+
+#[derive(Args, Debug)]
+struct ConvertDepEdgesArgs {
+    /// Dep edges file to read (either format).
+    #[arg(value_name = "INPUT")]
+    input: PathBuf,
+    /// Output path (stdout if omitted).
+    #[arg(value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Format to write.
+    #[arg(long = "to", value_enum)]
+    to: DepEdgesFormat,
+    /// Direction to write.
     #[arg(
-        short = 'd',
-        long = "depEdgesFile",
-        alias = "dep-edges-file",
-        value_name = "FILE"
+        long = "depEdgesDirection",
+        value_enum,
+        default_value_t = DepEdgesDirection::Forward
     )]
-    dep_edges_file: Option<PathBuf>,
+    direction: DepEdgesDirection,
 }
 
 #[derive(Args, Debug)]
@@ -151,158 +696,2188 @@ struct GetImpactedTargetsArgs {
         short = 's',
         long = "startingHashes",
         value_name = "FILE",
-        required = true
+        required_unless_present = "changed_targets_file"
     )]
-    start_hashes: PathBuf,
-    /// Path to the updated hash JSON.
+    start_hashes: Option<PathBuf>,
+    /// Path to the updated hash JSON. With --applyDelta it defaults to
+    /// --startingHashes.
     #[arg(
         short = 'f',
         long = "finalHashes",
         value_name = "FILE",
-        required = true
+        required_unless_present_any = ["changed_targets_file", "apply_delta"]
     )]
-    final_hashes: PathBuf,
+    final_hashes: Option<PathBuf>,
+    /// Delta file from `generate-hashes --deltaAgainst` applied on top of the
+    /// final hashes; repeat to apply a chain of deltas in order.
+    #[arg(long = "applyDelta", value_name = "FILE", action = ArgAction::Append)]
+    apply_delta: Vec<PathBuf>,
+    /// Newline-separated labels already known to have changed; skips hash
+    /// comparison and reports their reverse dependency closure over --depEdgesFile.
+    #[arg(
+        long = "changedTargetsFile",
+        value_name = "FILE",
+        conflicts_with_all = ["start_hashes", "final_hashes", "apply_delta"],
+        requires = "dep_edges"
+    )]
+    changed_targets_file: Option<PathBuf>,
     /// Optional dependency edges JSON file.
     #[arg(short = 'd', long = "depEdgesFile", value_name = "FILE")]
     dep_edges: Option<PathBuf>,
+    /// Leave the edges --depEdgesFile records as exec-only out of distances
+    /// and --changedTargetsFile closures; hashes still change through them.
+    /// The file must be written with `generate-hashes --execDepEdges`.
+    #[arg(long = "ignoreExecEdges", action = ArgAction::SetTrue, requires = "dep_edges")]
+    ignore_exec_edges: bool,
+    /// Dep edges of the starting hashes, compared with --depEdgesFile: writes,
+    /// instead of the impacted targets, a JSON list of the deps each impacted
+    /// target gained, lost, or kept while they changed. Every impacted target
+    /// is listed, whatever --coverageMap, --expandTestSuites or --filterScript
+    /// would keep.
+    #[arg(
+        long = "compareDepEdges",
+        value_name = "FILE",
+        requires = "dep_edges",
+        conflicts_with_all = [
+            "tests_only", "group_by_prefix", "collapse_generated_files", "workspace_namespace",
+        ]
+    )]
+    compare_dep_edges: Option<PathBuf>,
+    /// JSON map of label to build cost in seconds (e.g. averaged from BEP
+    /// history); adds a cost-weighted `weightedDistance` to each distance.
+    #[arg(long = "costFile", value_name = "FILE", requires = "dep_edges")]
+    cost_file: Option<PathBuf>,
     /// Target types to filter (requires hashes generated with --includeTargetType).
     #[arg(short = 't', long = "targetType", value_delimiter = ',', num_args = 1..)]
     target_types: Option<Vec<String>>,
-    /// Optional output path (stdout if omitted).
-    #[arg(short = 'o', long = "output", value_name = "FILE")]
-    output: Option<PathBuf>,
+    /// Compare hashes without their target types, so a file generated with
+    /// --includeTargetType can be compared to one generated without it.
+    #[arg(long = "normalizeTargetType", action = ArgAction::SetTrue)]
+    normalize_target_type: bool,
+    /// JSON map of test label to the workspace files it reads, e.g. from
+    /// coverage data; tests covering an impacted file are reported too, even
+    /// when the build graph doesn't link them to it.
+    #[arg(long = "coverageMap", value_name = "FILE")]
+    coverage_map: Option<PathBuf>,
+    /// Newline-separated workspace-relative files changed between the hashes,
+    /// e.g. from `git diff --name-only`; tests covering them are reported too,
+    /// even when no target lists the file.
+    #[arg(
+        long = "modified-filepaths",
+        value_name = "FILE",
+        requires = "coverage_map"
+    )]
+    modified_filepaths: Option<PathBuf>,
+    /// Compare label-sorted hash files (JSON, or NDJSON sorted by label) in
+    /// constant memory, writing impacted labels in label order as they are
+    /// found. Only plain label output is supported.
+    #[arg(
+        long = "streaming",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = [
+            "dep_edges", "apply_delta", "changed_targets_file", "tests_only",
+            "group_by_prefix", "post_process", "filter_script",
+            "collapse_generated_files", "expand_test_suites", "workspace_namespace",
+            "coverage_map", "compare_dep_edges",
+        ]
+    )]
+    streaming: bool,
+    #[command(flatten)]
+    tests: TestSelectionArgs,
+    #[command(flatten)]
+    output: ImpactOutputArgs,
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = Cli::parse_from(normalize_args(env::args_os()));
-    init_tracing(cli.verbose);
-
-    if let Err(err) = run(cli).await {
-        error!(error = %err, "command failed");
-        eprintln!("{err}");
-        process::exit(1);
-    }
+/// Options replacing the impacted targets with the tests to run for them.
+#[derive(Args, Debug)]
+struct TestSelectionArgs {
+    /// Report only the runnable test targets depending on the impacted
+    /// targets, queried with `kind(test, rdeps(<universe>, <impacted>))`;
+    /// `test_suite`s are left out.
+    #[arg(long = "testsOnly", requires = "workspace_path")]
+    tests_only: bool,
+    /// Bazel workspace queried for --testsOnly.
+    #[arg(
+        short = 'w',
+        long = "workspacePath",
+        value_name = "DIR",
+        requires = "tests_only"
+    )]
+    workspace_path: Option<PathBuf>,
+    /// Optional Bazel binary to invoke.
+    #[arg(short = 'b', long = "bazelPath", requires = "tests_only")]
+    bazel_path: Option<PathBuf>,
+    /// Additional Bazel startup options (before command).
+    #[arg(
+        long = "bazelStartupOptions",
+        value_delimiter = ' ',
+        num_args = 0..,
+        requires = "tests_only"
+    )]
+    bazel_startup_options: Vec<String>,
+    /// Additional Bazel query options.
+    #[arg(
+        long = "bazelCommandOptions",
+        value_delimiter = ' ',
+        num_args = 0..,
+        requires = "tests_only"
+    )]
+    bazel_command_options: Vec<String>,
+    /// Query universe for `rdeps()`.
+    #[arg(
+        long = "universe",
+        value_name = "PATTERN",
+        default_value = "//...",
+        requires = "tests_only"
+    )]
+    universe: String,
+    /// Split the selected tests into this many shards for parallel CI jobs.
+    #[arg(
+        long = "shardCount",
+        value_name = "N",
+        requires_all = ["tests_only", "shard_index"]
+    )]
+    shard_count: Option<usize>,
+    /// Zero-based shard written by this run.
+    #[arg(long = "shardIndex", value_name = "I", requires = "shard_count")]
+    shard_index: Option<usize>,
 }
 
-async fn run(cli: Cli) -> Result<()> {
-    match cli.command {
-        Commands::GenerateHashes(args) => handle_generate_hashes(args).await,
-        Commands::GetImpactedTargets(args) => handle_get_impacted_targets(args),
+impl TestSelectionArgs {
+    /// Replaces the impacted targets with the tests to run for them, or the
+    /// requested shard of those tests.
+    async fn select(
+        &self,
+        result: core::ImpactedTargetsResult,
+    ) -> Result<core::ImpactedTargetsResult> {
+        if !self.tests_only {
+            return Ok(result);
+        }
+        core::validate_target_pattern(&self.universe)?;
+        let Some(workspace) = &self.workspace_path else {
+            bail!("--testsOnly requires --workspacePath");
+        };
+        let opts = core::BazelOptions {
+            workspace: workspace.clone(),
+            bazel_path: self.bazel_path.clone().unwrap_or_default(),
+            startup_options: self.bazel_startup_options.clone(),
+            command_options: self.bazel_command_options.clone(),
+            keep_going: true,
+            ..Default::default()
+        };
+        let mut tests = core::query_test_targets(&opts, &self.universe, &result.impacted).await?;
+        info!(count = tests.len(), "selected impacted test targets");
+        if let (Some(count), Some(index)) = (self.shard_count, self.shard_index) {
+            tests = core::shard_labels(&tests, count, index)?;
+        }
+        Ok(core::with_impacted_labels(result, tests))
     }
 }
 
-async fn handle_generate_hashes(args: GenerateHashesArgs) -> Result<()> {
-    if let Some(path) = &args.content_hash_path {
-        if !path.is_file() {
-            bail!("Incorrect contentHashFilePath: file doesn't exist or can't be read.");
-        }
-    }
-    if args.fine_grained_external_repos_file.is_some()
-        && !args.fine_grained_external_repos.is_empty()
-    {
-        bail!(
-            "fineGrainedHashExternalReposFile and fineGrainedHashExternalRepos are mutually exclusive"
-        );
-    }
+/// Options controlling how impacted targets are filtered and written.
+#[derive(Args, Debug)]
+struct ImpactOutputArgs {
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Only report targets from this workspace namespace (`//` for the root
+    /// workspace), stripping the `<namespace>!` prefix from labels.
+    #[arg(long = "workspaceNamespace", value_name = "NAME")]
+    workspace_namespace: Option<String>,
+    /// Output format: upstream-compatible labels/JSON, or a markdown PR comment.
+    #[arg(long = "format", value_enum, default_value_t = ImpactedOutputFormat::Default)]
+    format: ImpactedOutputFormat,
+    /// Maximum number of packages listed in the markdown report.
+    #[arg(long = "markdownMaxPackages", value_name = "N", default_value_t = 50)]
+    markdown_max_packages: usize,
+    /// Maximum number of targets listed per package in the markdown report.
+    #[arg(
+        long = "markdownMaxTargetsPerPackage",
+        value_name = "N",
+        default_value_t = 25
+    )]
+    markdown_max_targets_per_package: usize,
+    /// Write impacted targets as JSON groups keyed by their first N package path
+    /// components (`depth=N`), listing at most `cap=M` targets per group.
+    #[arg(long = "groupByPrefix", value_name = "depth=N[,cap=M]")]
+    group_by_prefix: Option<core::GroupingOptions>,
+    /// Shell command the rendered output is piped through before writing; its
+    /// stdout becomes the output.
+    #[arg(long = "postProcess", value_name = "CMD")]
+    post_process: Option<String>,
+    /// Starlark script defining `keep(label, type, distance)`; only targets it
+    /// returns True for are written. Requires a build with the `starlark`
+    /// feature.
+    #[arg(long = "filterScript", value_name = "FILE")]
+    filter_script: Option<PathBuf>,
+    /// Report impacted generated files as the rules generating them, each
+    /// rule once. Needs dep edges and hashes generated with
+    /// `--includeTargetType`.
+    #[arg(long = "collapseGeneratedFiles", action = ArgAction::SetTrue)]
+    collapse_generated_files: bool,
+    /// Replace impacted `test_suite`s with their member tests that are
+    /// impacted themselves. Needs final hashes written with
+    /// `--outputFormat json-v2`, which record suite members.
+    #[arg(long = "expandTestSuites", action = ArgAction::SetTrue)]
+    expand_test_suites: bool,
+    /// Exit with status 3 after writing the output when no targets are
+    /// impacted, so pipelines can skip work without parsing the output.
+    #[arg(long = "failIfNoneImpacted", action = ArgAction::SetTrue)]
+    fail_if_none_impacted: bool,
+}
 
-    let config = core::hash::GenerateHashesConfig {
-        workspace: args.workspace_path.clone(),
-        include_target_type: args.include_target_type,
-        use_cquery: args.use_cquery,
-        keep_going: args.keep_going,
-        bazel_path: args.bazel_path.unwrap_or_default(),
-        startup_options: args.bazel_startup_options.clone(),
-        command_options: args.bazel_command_options.clone(),
-        cquery_options: args.bazel_cquery_options.clone(),
-        exclude_external_targets: args.exclude_external_targets,
-        ignored_attrs: args.ignored_attrs.clone(),
-        fine_grained_external_repos: args.fine_grained_external_repos.clone(),
-        fine_grained_external_repos_file: args.fine_grained_external_repos_file.clone(),
-        content_hash_path: args.content_hash_path.clone(),
-        seed_filepaths: args.seed_filepaths.clone(),
-        modified_filepaths: args.modified_filepaths.clone(),
-        target_types: args.target_types.clone(),
-        track_dep_edges: args.dep_edges_file.is_some(),
-    };
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ImpactedOutputFormat {
+    /// Newline labels, or distance JSON when dep edges are supplied.
+    Default,
+    /// Collapsible markdown report grouped by package.
+    Markdown,
+    /// `{"status": "ok", "impacted": [...]}` with `distances` when dep edges
+    /// are supplied, or `{"status": "error", "error": "..."}` when the run fails.
+    Json,
+    /// One `set(//a:b //c:d ...)` Bazel query expression, labels quoted where
+    /// the query language needs it.
+    #[value(name = "query-expr")]
+    QueryExpr,
+}
 
-    let result = core::hash::generate_hashes(&config).await?;
+/// Exit status of `--failIfNoneImpacted` runs that found nothing impacted;
+/// errors exit with 1 and clap's usage errors with 2.
+const NONE_IMPACTED_EXIT_CODE: i32 = 3;
 
-    let writer: Box<dyn Write> = match args.output_path {
-        Some(path) => {
-            Box::new(BufWriter::new(File::create(&path).with_context(|| {
-                format!("failed to create output file {}", path.display())
-            })?))
-        }
-        None => Box::new(BufWriter::new(std::io::stdout())),
-    };
+/// Shared by every command `serve` runs; unset otherwise.
+static WARM_CACHE: OnceLock<core::WarmCache> = OnceLock::new();
 
-    serde_json::to_writer(writer, &result.hashes).context("failed to write hash JSON")?;
+/// Returned after the output is written when `--failIfNoneImpacted` finds no
+/// impacted targets.
+#[derive(Debug)]
+struct NoneImpacted;
 
-    if let Some(dep_path) = args.dep_edges_file {
-        let mut file =
-            BufWriter::new(File::create(&dep_path).with_context(|| {
-                format!("failed to create dep edges file {}", dep_path.display())
-            })?);
-        serde_json::to_writer(&mut file, &result.dep_edges)
-            .context("failed to write dep edges JSON")?;
-        file.flush().context("failed to flush dep edges output")?;
+impl std::fmt::Display for NoneImpacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no targets are impacted (--failIfNoneImpacted)")
     }
+}
 
-    info!(count = result.hashes.len(), "finished generate-hashes",);
-    Ok(())
+impl std::error::Error for NoneImpacted {}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    #[command(flatten)]
+    hashing: HashingArgs,
+    /// Baseline hash JSON to compare the freshly generated hashes against.
+    #[arg(
+        long = "startingHashes",
+        value_name = "FILE",
+        conflicts_with_all = ["baseline_service", "since_tag"],
+        required_unless_present_any = ["baseline_service", "since_tag"]
+    )]
+    start_hashes: Option<PathBuf>,
+    /// Baseline index service to fetch the nearest-ancestor baseline from.
+    #[arg(
+        long = "baselineService",
+        value_name = "URL",
+        conflicts_with = "since_tag"
+    )]
+    baseline_service: Option<String>,
+    /// Commit to look up in the baseline service (defaults to the workspace HEAD).
+    #[arg(long = "commit", value_name = "SHA", requires = "baseline_service")]
+    commit: Option<String>,
+    /// Diff against this git tag (or any revision): its hashes are read from
+    /// the local baseline cache, or generated in a temporary worktree and
+    /// cached on first use.
+    #[arg(long = "sinceTag", value_name = "TAG")]
+    since_tag: Option<String>,
+    /// Local baseline cache for --sinceTag (defaults to `baselines` in the
+    /// workspace's state directory under `$XDG_CACHE_HOME/bazel-differrous`).
+    #[arg(long = "baselineCacheDir", value_name = "DIR", requires = "since_tag")]
+    baseline_cache_dir: Option<PathBuf>,
+    /// Also write the freshly generated hashes to this file.
+    #[arg(long = "finalHashesOutput", value_name = "FILE")]
+    final_hashes_output: Option<PathBuf>,
+    /// Compute target/package distances from the final dep graph.
+    #[arg(long = "distances", action = ArgAction::SetTrue)]
+    distances: bool,
+    /// JSON map of label to build cost in seconds; adds a cost-weighted
+    /// `weightedDistance` to each distance.
+    #[arg(long = "costFile", value_name = "FILE", requires = "distances")]
+    cost_file: Option<PathBuf>,
+    /// Write the impacted targets plus their direct deps to this file, one
+    /// per line with deps before their dependents, for a
+    /// `bazel build --remote_download_minimal --target_pattern_file` job that
+    /// primes the remote cache.
+    #[arg(long = "cachePrimeFile", value_name = "FILE")]
+    cache_prime_file: Option<PathBuf>,
+    /// Compare hashes without their target types, so a baseline generated
+    /// with a different --includeTargetType setting can be used.
+    #[arg(long = "normalizeTargetType", action = ArgAction::SetTrue)]
+    normalize_target_type: bool,
+    #[command(flatten)]
+    output: ImpactOutputArgs,
 }
 
-fn handle_get_impacted_targets(args: GetImpactedTargetsArgs) -> Result<()> {
-    info!(
-        start = %args.start_hashes.display(),
-        final = %args.final_hashes.display(),
-        dep_edges = args.dep_edges.as_ref().map(|p| p.display().to_string()),
-        "computing impacted targets"
-    );
+#[derive(Args, Debug)]
+struct TrendArgs {
+    /// Directory of hash snapshots (`*.json`/`*.ndjson`) named so that they sort
+    /// chronologically, e.g. `2024-05-01.json`.
+    #[arg(long = "hashDir", value_name = "DIR")]
+    hash_dir: PathBuf,
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+}
 
-    let result = core::get_impacted_targets(
-        &args.start_hashes,
-        &args.final_hashes,
-        args.dep_edges.as_ref(),
-        args.target_types,
-    )?;
+#[derive(Args, Debug)]
+struct ValidateImpactArgs {
+    /// Path to the Bazel workspace the changed files belong to.
+    #[arg(short = 'w', long = "workspacePath", value_name = "DIR")]
+    workspace_path: PathBuf,
+    /// Optional Bazel binary to invoke.
+    #[arg(short = 'b', long = "bazelPath")]
+    bazel_path: Option<PathBuf>,
+    /// Additional Bazel startup options (before command).
+    #[arg(long = "bazelStartupOptions", value_delimiter = ' ', num_args = 0..)]
+    bazel_startup_options: Vec<String>,
+    /// Additional Bazel query options.
+    #[arg(long = "bazelCommandOptions", value_delimiter = ' ', num_args = 0..)]
+    bazel_command_options: Vec<String>,
+    /// Path to the baseline hash JSON.
+    #[arg(short = 's', long = "startingHashes", value_name = "FILE")]
+    start_hashes: PathBuf,
+    /// Path to the updated hash JSON, generated from the workspace as it is now.
+    #[arg(short = 'f', long = "finalHashes", value_name = "FILE")]
+    final_hashes: PathBuf,
+    /// Newline-separated workspace-relative paths of the changed files, e.g. the
+    /// output of `git diff --name-only`.
+    #[arg(long = "changedFiles", value_name = "FILE")]
+    changed_files: PathBuf,
+    /// Number of changed files whose reverse dependencies are queried.
+    #[arg(long = "sampleSize", value_name = "N", default_value_t = 20)]
+    sample_size: usize,
+    /// Query universe for `rdeps()`.
+    #[arg(long = "universe", value_name = "PATTERN", default_value = "//...")]
+    universe: String,
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+}
 
-    let mut writer: Box<dyn Write> = match &args.output {
-        Some(path) => {
-            Box::new(BufWriter::new(File::create(path).with_context(|| {
-                format!("failed to create output file {}", path.display())
-            })?))
-        }
-        None => Box::new(BufWriter::new(std::io::stdout())),
-    };
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// Hash files (JSON, json-v2, NDJSON or deltas) and dep edges files (v1 or
+    /// v2) to check; the kind of each file is detected from its content.
+    #[arg(value_name = "FILE", required = true)]
+    files: Vec<PathBuf>,
+}
 
-    let impacted_count = result.impacted.len();
+#[derive(Args, Debug)]
+struct HashTargetArgs {
+    #[command(flatten)]
+    hashing: HashingArgs,
+    /// Full label of the target to hash, e.g. `//pkg:foo`.
+    #[arg(long = "target", value_name = "LABEL")]
+    target: String,
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+}
 
-    if let Some(distances) = result.distances {
-        serde_json::to_writer_pretty(&mut writer, &distances)
-            .context("failed to write impacted targets JSON")?;
-    } else {
-        for label in &result.impacted {
-            writeln!(writer, "{}", label).context("failed to write impacted target")?;
-        }
-    }
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Unix socket to listen on; requests are JSON lines such as
+    /// `{"args": ["generate-hashes", "-w", "/repo", "/tmp/hashes.json"]}`.
+    #[arg(long = "socket", value_name = "PATH")]
+    socket: PathBuf,
+    /// Also answer HTTP on this address, e.g. `127.0.0.1:9090`: `/healthz`
+    /// returns 200, or 503 once shutting down, and `/metrics` request counts
+    /// in the Prometheus text format.
+    #[arg(long = "httpAddress", value_name = "HOST:PORT")]
+    http_address: Option<std::net::SocketAddr>,
+}
 
-    writer.flush().context("failed to flush output")?;
-    info!(
-        count = impacted_count,
-        "finished computing impacted targets"
-    );
-    Ok(())
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
 }
 
-fn init_tracing(verbose: bool) {
-    let default_level = if verbose { "debug" } else { "info" };
-    let filter =
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Report unknown keys and values of the wrong type, failing if there
+    /// are any.
+    Validate {
+        /// Workspace whose `.bazel-differrous.toml` is checked.
+        #[arg(
+            short = 'w',
+            long = "workspacePath",
+            value_name = "DIR",
+            default_value = "."
+        )]
+        workspace_path: PathBuf,
+        /// Check this file instead.
+        #[arg(long = "file", value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CleanArgs {
+    /// Workspaces whose leftover query and Starlark files are removed.
+    #[arg(
+        short = 'w',
+        long = "workspacePath",
+        value_name = "DIR",
+        action = ArgAction::Append
+    )]
+    workspace_path: Vec<PathBuf>,
+    /// Output directories whose leftover partial writes are removed.
+    #[arg(value_name = "DIR")]
+    dirs: Vec<PathBuf>,
+    /// Baseline cache to clean (defaults to the baseline cache of each
+    /// --workspacePath).
+    #[arg(long = "baselineCacheDir", value_name = "DIR")]
+    baseline_cache_dir: Option<PathBuf>,
+    /// Also remove cached `--sinceTag` baselines, not just stale worktrees.
+    #[arg(long = "baselines", action = ArgAction::SetTrue)]
+    baselines: bool,
+}
+
+#[derive(Args, Debug)]
+struct GcArgs {
+    /// Remove the state of workspaces not used for this many days.
+    #[arg(long = "maxAge", value_name = "DAYS", default_value_t = 30)]
+    max_age: u64,
+    /// Then remove the least recently used state until the cache takes at
+    /// most this many MiB.
+    #[arg(long = "maxSize", value_name = "MIB", default_value_t = 5 * 1024)]
+    max_size: u64,
+}
+
+#[derive(Args, Debug)]
+struct SetOpArgs {
+    #[arg(value_enum)]
+    op: SetOpArg,
+    /// Label lists: one label per line, or the JSON written by
+    /// `get-impacted-targets`.
+    #[arg(value_name = "A")]
+    a: PathBuf,
+    #[arg(value_name = "B")]
+    b: PathBuf,
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Output format: newline labels, or `{"status": "ok", "impacted": [...]}`.
+    #[arg(long = "format", value_enum, default_value_t = LabelListFormat::Lines)]
+    format: LabelListFormat,
+}
+
+#[derive(Args, Debug)]
+struct ExtractDepsArgs {
+    /// Dep edges file written by `generate-hashes` (any format or direction).
+    #[arg(short = 'd', long = "depEdgesFile", value_name = "FILE")]
+    dep_edges_file: PathBuf,
+    /// Labels to start from: one label per line, or the JSON written by
+    /// `get-impacted-targets`.
+    #[arg(long = "targets", value_name = "FILE")]
+    targets: PathBuf,
+    /// Follow dependencies of dependencies instead of stopping at direct ones.
+    #[arg(long = "transitive", action = ArgAction::SetTrue)]
+    transitive: bool,
+    /// Optional output path (stdout if omitted).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Output format: a v1 dep edges map, or every label of the closure.
+    #[arg(long = "format", value_enum, default_value_t = ExtractDepsFormat::Edges)]
+    format: ExtractDepsFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExtractDepsFormat {
+    Edges,
+    /// Labels of the targets and their dependencies, one per line.
+    List,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SetOpArg {
+    Union,
+    Intersect,
+    /// Labels of A that are not in B.
+    Subtract,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LabelListFormat {
+    Lines,
+    Json,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = normalize_args(env::args_os());
+    let color = style::ColorChoice::from_args(&args);
+    style::init(color);
+    let matches = Cli::command().color(color.to_clap()).get_matches_from(args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    init_tracing(cli.verbose);
+
+    if let Err(err) = run(cli).await {
+        if err.is::<NoneImpacted>() {
+            info!("{err}");
+            process::exit(NONE_IMPACTED_EXIT_CODE);
+        }
+        error!(error = %err, "command failed");
+        eprintln!(
+            "{}",
+            style::paint(style::Stream::Stderr, style::Style::Error, &err.to_string())
+        );
+        process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    if let Some(path) = &cli.log_bazel_commands {
+        core::set_command_log(Box::new(
+            core::JsonLinesCommandLog::append_to(path)?,
+        ))?;
+    }
+    match cli.command {
+        Commands::GenerateHashes(args) => handle_generate_hashes(args).await,
+        Commands::GetImpactedTargets(args) => {
+            let envelope = args.output.error_envelope_output();
+            handle_get_impacted_targets(args)
+                .await
+                .map_err(|err| write_error_envelope(envelope, err))
+        }
+        Commands::ConvertDepEdges(args) => handle_convert_dep_edges(args),
+        Commands::Diff(args) => {
+            let envelope = args.output.error_envelope_output();
+            handle_diff(args)
+                .await
+                .map_err(|err| write_error_envelope(envelope, err))
+        }
+        Commands::Trend(args) => handle_trend(args),
+        Commands::ValidateImpact(args) => handle_validate_impact(args).await,
+        Commands::Validate(args) => handle_validate(args),
+        Commands::HashTarget(args) => handle_hash_target(args).await,
+        Commands::Clean(args) => handle_clean(args),
+        Commands::Gc(args) => handle_gc(args),
+        Commands::SetOp(args) => handle_set_op(args),
+        Commands::ExtractDeps(args) => handle_extract_deps(args),
+        Commands::Serve(args) => handle_serve(args).await,
+        Commands::Config(args) => handle_config(args),
+    }
+}
+
+impl HashingArgs {
+    /// The hashing configuration, with defaults from the first workspace's
+    /// `.bazel-differrous.toml` for options not given on the command line.
+    fn to_config(&self, track_dep_edges: bool) -> Result<core::GenerateHashesConfig> {
+        match config::ProjectConfig::load(&self.workspace_path[0])? {
+            Some(project) => self
+                .clone()
+                .with_defaults(project)
+                .flags_to_config(track_dep_edges),
+            None => self.flags_to_config(track_dep_edges),
+        }
+    }
+
+    /// Fills in options not given on the command line from `project`. Lists
+    /// given empty (`--excludePatterns=`) and switches given `=false` replace
+    /// the file's values too.
+    fn with_defaults(mut self, project: config::ProjectConfig) -> Self {
+        self.bazel_path = self.bazel_path.or(project.bazel_path);
+        self.bazel_startup_options = self.bazel_startup_options.or(project.bazel_startup_options);
+        self.bazel_command_options = self.bazel_command_options.or(project.bazel_command_options);
+        self.bazel_cquery_options = self.bazel_cquery_options.or(project.cquery_command_options);
+        self.ignored_attrs = self
+            .ignored_attrs
+            .or(project.ignored_rule_hashing_attributes);
+        self.exclude_patterns = self.exclude_patterns.or(project.exclude_patterns);
+        // The two fine-grained options are mutually exclusive, so a file
+        // default only applies when neither was given.
+        if self.fine_grained_external_repos.is_none()
+            && self.fine_grained_external_repos_file.is_none()
+        {
+            self.fine_grained_external_repos_file = project.fine_grained_hash_external_repos_file;
+            if self.fine_grained_external_repos_file.is_none() {
+                self.fine_grained_external_repos = project.fine_grained_hash_external_repos;
+            }
+        }
+        self.seed_build_flags_file = self.seed_build_flags_file.or(project.seed_build_flags_file);
+        self.repository_cache = self.repository_cache.or(project.repository_cache);
+        self.jobs = self.jobs.or(project.jobs);
+        if !self.no_content_cache {
+            self.content_cache = self.content_cache.or(project.content_cache);
+        }
+        self.use_cquery = self.use_cquery.or(project.use_cquery);
+        self.include_target_type = self.include_target_type.or(project.include_target_type);
+        self.exclude_external_targets = self
+            .exclude_external_targets
+            .or(project.exclude_external_targets);
+        self.offline = self.offline.or(project.offline);
+        self.hash_repo_markers = self.hash_repo_markers.or(project.hash_repo_markers);
+        self.no_default_ignored_attrs = self
+            .no_default_ignored_attrs
+            .or(project.no_default_ignored_attributes);
+        self.fail_on_query_errors = self.fail_on_query_errors.or(project.fail_on_query_errors);
+        self
+    }
+
+    fn flags_to_config(&self, track_dep_edges: bool) -> Result<core::GenerateHashesConfig> {
+        if let Some(path) = &self.content_hash_path {
+            if !path.is_file() {
+                bail!("Incorrect contentHashFilePath: file doesn't exist or can't be read.");
+            }
+        }
+        if let Some(path) = &self.bep_json_file {
+            if !path.is_file() {
+                bail!("Incorrect bepJsonFile: file doesn't exist or can't be read.");
+            }
+        }
+        let fine_grained_external_repos = given(&self.fine_grained_external_repos);
+        if self.fine_grained_external_repos_file.is_some()
+            && !fine_grained_external_repos.is_empty()
+        {
+            bail!(
+                "fineGrainedHashExternalReposFile and fineGrainedHashExternalRepos are mutually exclusive"
+            );
+        }
+        if self.query_proto_path.is_some() && self.workspace_path.len() > 1 {
+            bail!("--queryProtoPath supports a single --workspacePath");
+        }
+        let remote_cache = match (self.remote_cache.clone(), &self.remote_instance_name) {
+            (Some(core::RemoteCache::Grpc { url, .. }), Some(name)) => {
+                Some(core::RemoteCache::Grpc {
+                    url,
+                    instance_name: name.clone(),
+                })
+            }
+            (_, Some(_)) => {
+                bail!("--remoteInstanceName requires a grpc:// or grpcs:// --remoteCache")
+            }
+            (cache, None) => cache,
+        };
+        let input_rewriter = match &self.input_rewrite_config {
+            Some(path) => {
+                Some(Arc::new(core::RewriteConfig::load(path)?) as Arc<dyn core::RuleInputRewriter>)
+            }
+            None => None,
+        };
+
+        Ok(core::GenerateHashesConfig {
+            workspace: self.workspace_path[0].clone(),
+            include_target_type: self.include_target_type.unwrap_or_default(),
+            use_cquery: self.use_cquery.unwrap_or_default(),
+            keep_going: self.keep_going,
+            fail_on_query_errors: self.fail_on_query_errors.unwrap_or_default(),
+            bazel_path: self.bazel_path.clone().unwrap_or_default(),
+            startup_options: given(&self.bazel_startup_options),
+            command_options: given(&self.bazel_command_options),
+            cquery_options: given(&self.bazel_cquery_options),
+            exclude_external_targets: self.exclude_external_targets.unwrap_or_default(),
+            ignored_attrs: given(&self.ignored_attrs),
+            no_default_ignored_attrs: self.no_default_ignored_attrs.unwrap_or_default(),
+            exclude_patterns: given(&self.exclude_patterns),
+            fine_grained_external_repos,
+            fine_grained_external_repos_file: self.fine_grained_external_repos_file.clone(),
+            content_hash_path: self.content_hash_path.clone(),
+            bep_json_file: self.bep_json_file.clone(),
+            remote_cache,
+            execution_log: self.execution_log.clone(),
+            seed_filepaths: self.seed_filepaths.clone(),
+            seed_build_flags_file: self.seed_build_flags_file.clone(),
+            modified_filepaths: self.modified_filepaths.clone(),
+            git_diff_base: self.git_diff_base.clone(),
+            previous_hashes: self.previous_hashes.clone(),
+            read_only: self.read_only,
+            no_bazel: self.no_bazel,
+            target_types: self.target_types.clone(),
+            track_dep_edges,
+            profile_top_k: None,
+            checkpoint: None,
+            audit_file_access: false,
+            jobs: self.jobs.map(usize::from),
+            content_cache: match &self.content_cache {
+                _ if self.no_content_cache => None,
+                Some(path) => Some(path.clone()),
+                // `serve` keeps source digests in memory instead.
+                None if WARM_CACHE.get().is_some() => None,
+                None => core::default_content_cache_path(&self.workspace_path[0]),
+            },
+            time_budget: None,
+            warm_cache: WARM_CACHE.get().cloned(),
+            query_proto_path: self.query_proto_path.clone(),
+            query_output: match self.query_output {
+                QueryOutputArg::StreamedProto => core::QueryOutputFormat::StreamedProto,
+                QueryOutputArg::StreamedJsonproto => core::QueryOutputFormat::StreamedJsonproto,
+                QueryOutputArg::Proto => core::QueryOutputFormat::Proto,
+            },
+            offline: self.offline.unwrap_or_default(),
+            repository_cache: self.repository_cache.clone(),
+            input_rewriter,
+            detect_concurrent_modifications: self.detect_concurrent_modifications.map(|mode| {
+                match mode {
+                    ConcurrentModificationArg::Warn => core::ConcurrentModificationMode::Warn,
+                    ConcurrentModificationArg::Fail => core::ConcurrentModificationMode::Fail,
+                }
+            }),
+            platform_constraints: self
+                .platform_constraints
+                .as_ref()
+                .map(core::PlatformConstraints::new),
+            build_file_targets: self.build_file_targets,
+            orphan_sources: false,
+            hash_source_visibility: self.hash_source_visibility,
+            hash_function: self.hash_function.into(),
+            on_cycle: self.on_cycle.into(),
+            structure_only: self.structure_only,
+            hash_repo_markers: self.hash_repo_markers.unwrap_or_default(),
+            gitattributes: self.git_attributes.map(|mode| match mode {
+                GitAttributesArg::PathOnly => core::GitAttributesMode::PathOnly,
+                GitAttributesArg::Skip => core::GitAttributesMode::Skip,
+            }),
+            normalize: core::NormalizeOptions {
+                whitespace: self.normalize_whitespace.clone(),
+                comments: self.normalize_comments.clone(),
+            },
+        })
+    }
+
+    /// Hashes every workspace with `config`, which is [`Self::to_config`] plus
+    /// the caller's own options.
+    async fn generate(
+        &self,
+        config: core::GenerateHashesConfig,
+    ) -> Result<core::GenerateHashesResult> {
+        if config.checkpoint.is_some() && self.workspace_path.len() > 1 {
+            bail!("--outputFormat ndjson supports a single --workspacePath");
+        }
+        if self.workspace_path.len() > 1 {
+            let workspaces = core::workspace_specs_from_paths(&self.workspace_path)?;
+            core::generate_hashes_for_workspaces(&config, &workspaces).await
+        } else {
+            core::generate_hashes(&config).await
+        }
+    }
+
+    /// Fails when --readOnly is set and one of `outputs` lies in a workspace.
+    fn check_outputs<'a>(
+        &self,
+        outputs: impl IntoIterator<Item = Option<&'a PathBuf>>,
+    ) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let guard = core::WriteGuard::protecting(&self.workspace_path);
+        outputs
+            .into_iter()
+            .flatten()
+            .try_for_each(|path| guard.check(path))
+    }
+
+    /// The Bazel commands [`Self::generate`] would run with `config`.
+    fn plan(&self, config: &core::GenerateHashesConfig) -> Result<Vec<core::PlannedCommand>> {
+        if self.workspace_path.len() <= 1 {
+            return core::plan_bazel_commands(config);
+        }
+        let mut plan = Vec::new();
+        for spec in core::workspace_specs_from_paths(&self.workspace_path)? {
+            plan.extend(core::plan_bazel_commands(&core::GenerateHashesConfig {
+                workspace: spec.path,
+                ..config.clone()
+            })?);
+        }
+        Ok(plan)
+    }
+}
+
+async fn handle_generate_hashes(args: GenerateHashesArgs) -> Result<()> {
+    let profile_top_k = args
+        .hash_profile
+        .is_some()
+        .then_some(args.hash_profile_top_k);
+    if args.delta_against.is_some() && args.output_format != HashOutputFormat::Json {
+        bail!("--deltaAgainst requires --outputFormat json");
+    }
+    if args.combined_output && args.output_format == HashOutputFormat::Ndjson {
+        bail!("--combinedOutput is not supported with --outputFormat ndjson");
+    }
+    if args.combined_output && args.output_format == HashOutputFormat::DetailedJson {
+        bail!("--combinedOutput writes hash strings; it is not supported with --outputFormat detailed-json");
+    }
+    if args.combined_output && args.output_format.is_binary() {
+        bail!("--combinedOutput is a JSON document; it is not supported with --outputFormat proto or msgpack");
+    }
+    if args.time_budget.is_some()
+        && args.output_format != HashOutputFormat::JsonV2
+        && !args.combined_output
+    {
+        bail!("--timeBudget requires --outputFormat json-v2 or --combinedOutput to record which packages were not hashed");
+    }
+    let custom_hash_function = args.hashing.hash_function != HashFunctionArg::Sha256;
+    if args.hashing.no_bazel
+        && (args.delta_against.is_some()
+            || !matches!(
+                args.output_format,
+                HashOutputFormat::Json | HashOutputFormat::JsonV2
+            ))
+    {
+        bail!("--noBazel marks the hashes approximate in the output metadata; it needs --outputFormat json or json-v2 and no --deltaAgainst");
+    }
+    if custom_hash_function
+        && (args.delta_against.is_some()
+            || !matches!(
+                args.output_format,
+                HashOutputFormat::Json | HashOutputFormat::JsonV2
+            ))
+    {
+        bail!("--hashFunction is recorded in the output metadata; it needs --outputFormat json or json-v2 and no --deltaAgainst");
+    }
+    if args.combined_output && args.dep_edges_direction != DepEdgesDirection::Forward {
+        bail!("--combinedOutput only holds forward dep edges; write reverse edges with --depEdgesFile");
+    }
+    let dep_edges_to_stdout = args.dep_edges_file.as_deref() == Some(Path::new("-"));
+    if dep_edges_to_stdout && args.output_path.is_none() && !args.combined_output {
+        bail!("--depEdgesFile - needs an OUTPUT path for the hashes, or --combinedOutput to write both as one document");
+    }
+    let checkpoint = match (args.output_format, &args.output_path) {
+        (
+            HashOutputFormat::Json
+            | HashOutputFormat::JsonV2
+            | HashOutputFormat::DetailedJson
+            | HashOutputFormat::Proto
+            | HashOutputFormat::Msgpack,
+            _,
+        ) if args.resume_from.is_some() => {
+            bail!("--resumeFrom requires --outputFormat ndjson")
+        }
+        (
+            HashOutputFormat::Json
+            | HashOutputFormat::JsonV2
+            | HashOutputFormat::DetailedJson
+            | HashOutputFormat::Proto
+            | HashOutputFormat::Msgpack,
+            _,
+        ) => None,
+        (HashOutputFormat::Ndjson, None) => {
+            bail!("--outputFormat ndjson requires an output path")
+        }
+        (HashOutputFormat::Ndjson, _) if args.post_process.is_some() => {
+            bail!("--postProcess is not supported with --outputFormat ndjson")
+        }
+        (HashOutputFormat::Ndjson, Some(path)) => Some(core::CheckpointConfig {
+            output: path.clone(),
+            resume_from: args.resume_from.clone(),
+        }),
+    };
+    args.hashing.check_outputs([
+        args.output_path.as_ref(),
+        args.dep_edges_file
+            .as_ref()
+            .filter(|_| !dep_edges_to_stdout),
+        args.hash_profile.as_ref(),
+        args.orphan_sources_file.as_ref(),
+        args.audit_file_access.as_ref(),
+        args.artifact_report.as_ref(),
+        args.warnings_file.as_ref(),
+    ])?;
+    let streamed = checkpoint.is_some();
+    let config = core::GenerateHashesConfig {
+        profile_top_k,
+        checkpoint,
+        audit_file_access: args.audit_file_access.is_some(),
+        orphan_sources: args.orphan_sources_file.is_some(),
+        time_budget: args.time_budget.map(Duration::from_secs),
+        ..args
+            .hashing
+            .to_config(args.dep_edges_file.is_some() || args.combined_output)?
+    };
+    if args.exec_dep_edges && (!config.use_cquery || config.query_proto_path.is_some()) {
+        bail!("--execDepEdges needs the configurations of a live cquery; pass --useCquery and drop --queryProtoPath");
+    }
+    if args.dry_run {
+        let plan = args.hashing.plan(&config)?;
+        let script: Vec<String> = plan.iter().map(ToString::to_string).collect();
+        print!("{}", script.join("\n"));
+        return Ok(());
+    }
+    let use_cquery = config.use_cquery;
+    let ignored_attrs = config.effective_ignored_attrs();
+    let result = args.hashing.generate(config).await?;
+    if let Some(coverage) = result.coverage.as_ref().filter(|c| !c.complete) {
+        warn!(
+            unhashed_targets = coverage.unhashed_targets,
+            unhashed_packages = coverage.unhashed_packages.len(),
+            "time budget ran out; the output is partial"
+        );
+    }
+
+    // NDJSON output has already been streamed to disk while hashing.
+    if !streamed {
+        let mut writer = Vec::new();
+        let json_v2 = args.output_format == HashOutputFormat::JsonV2;
+        let meta =
+            (json_v2 || result.coverage.is_some() || custom_hash_function || result.approximate)
+                .then(|| {
+                    let command_line: Vec<String> = env::args_os()
+                        .map(|a| a.to_string_lossy().into_owned())
+                        .collect();
+                    core::OutputMeta {
+                        generated_by: json_v2
+                            .then(|| core::GeneratedBy::capture(&command_line, &args.redact_args)),
+                        coverage: result.coverage.clone(),
+                        hash_function: (json_v2 || custom_hash_function)
+                            .then(|| args.hashing.hash_function.into()),
+                        bazel_version: result.bazel_version.clone().filter(|_| json_v2),
+                        use_cquery: json_v2.then_some(use_cquery),
+                        ignored_attributes: json_v2.then_some(ignored_attrs),
+                        query_errors: json_v2.then(|| result.query_errors.clone()),
+                        test_suites: json_v2.then(|| result.test_suites.clone()),
+                        approximate: result.approximate.then_some(true),
+                    }
+                });
+        if args.combined_output {
+            match args.dep_edges_format {
+                DepEdgesFormat::V1 => serde_json::to_writer(
+                    &mut writer,
+                    &core::CombinedOutput {
+                        meta,
+                        hashes: &result.hashes,
+                        dep_edges: &result.dep_edges,
+                    },
+                ),
+                DepEdgesFormat::V2 => serde_json::to_writer(
+                    &mut writer,
+                    &core::CombinedOutput {
+                        meta,
+                        hashes: &result.hashes,
+                        dep_edges: core::IndexedDependencyEdges::from_edges(
+                            &result.dependency_edges(),
+                        ),
+                    },
+                ),
+            }
+        } else if let Some(meta) = meta {
+            let envelope = core::HashesEnvelope {
+                meta,
+                hashes: result.hashes.clone(),
+            };
+            serde_json::to_writer(&mut writer, &envelope)
+        } else if args.output_format == HashOutputFormat::Proto {
+            writer = core::encode_proto_hashes(&result.hashes);
+            Ok(())
+        } else if args.output_format == HashOutputFormat::Msgpack {
+            writer = core::encode_msgpack_hashes(&result.hashes)?;
+            Ok(())
+        } else if args.output_format == HashOutputFormat::DetailedJson {
+            core::write_json_map(&mut writer, &core::detailed_hashes(&result.hashes)?)
+        } else if let Some(previous) = &args.delta_against {
+            let previous = core::read_target_hashes(previous)?;
+            let delta = core::HashesDelta::between(&previous, &result.hashes);
+            info!(
+                changed = delta.changed.len(),
+                removed = delta.removed.len(),
+                "writing delta hashes"
+            );
+            serde_json::to_writer(&mut writer, &delta)
+        } else {
+            core::write_json_map(&mut writer, &result.hashes)
+        }
+        .context("failed to write hashes")?;
+        write_output(
+            args.output_path.as_ref(),
+            args.post_process.as_deref(),
+            writer,
+        )?;
+    }
+
+    // Combined output on stdout already carries the dep edges.
+    let dep_edges_in_output = args.combined_output && args.output_path.is_none();
+    if let Some(dep_path) = args
+        .dep_edges_file
+        .clone()
+        .filter(|_| !(dep_edges_to_stdout && dep_edges_in_output))
+    {
+        let mut file: Box<dyn Write> = if dep_edges_to_stdout {
+            Box::new(BufWriter::new(std::io::stdout()))
+        } else {
+            Box::new(BufWriter::new(File::create(&dep_path).with_context(
+                || format!("failed to create dep edges file {}", dep_path.display()),
+            )?))
+        };
+        let guard = (!dep_edges_to_stdout).then(|| core::OutputGuard::new(&dep_path));
+        let exec_dep_edges = args.exec_dep_edges.then_some(&result.exec_dep_edges);
+        match (
+            args.dep_edges_format,
+            args.dep_edges_direction,
+            exec_dep_edges,
+        ) {
+            (DepEdgesFormat::V1, DepEdgesDirection::Forward, None) => {
+                core::write_json_map(&mut file, &result.dep_edges)
+                    .context("failed to write dep edges JSON")?
+            }
+            (format, direction, exec) => write_dep_edges(
+                &mut file,
+                &result.dependency_edges(),
+                exec,
+                format,
+                direction,
+            )?,
+        }
+        file.flush().context("failed to flush dep edges output")?;
+        if let Some(guard) = guard {
+            guard.keep();
+        }
+    }
+
+    if let (Some(path), Some(profile)) = (&args.hash_profile, &result.profile) {
+        let mut writer = output_writer(Some(path))?;
+        serde_json::to_writer_pretty(&mut writer, profile)
+            .context("failed to write hash profile")?;
+        writer.flush().context("failed to flush hash profile")?;
+        for entry in profile.slowest_sources.iter().take(5) {
+            info!(
+                label = %entry.label,
+                micros = entry.micros,
+                bytes = entry.bytes.unwrap_or_default(),
+                "slow source"
+            );
+        }
+    }
+
+    if let (Some(path), Some(audit)) = (&args.audit_file_access, &result.file_access) {
+        let mut writer = output_writer(Some(path))?;
+        serde_json::to_writer_pretty(&mut writer, audit)
+            .context("failed to write file access audit")?;
+        writer
+            .flush()
+            .context("failed to flush file access audit")?;
+        if audit.outside > 0 {
+            warn!(
+                count = audit.outside,
+                "read files outside the workspace and external repositories"
+            );
+        }
+        info!(
+            files = audit.files.len(),
+            bytes = audit.total_bytes,
+            "wrote file access audit"
+        );
+    }
+
+    if let Some(path) = &args.orphan_sources_file {
+        let mut writer = output_writer(Some(path))?;
+        for label in &result.orphan_sources {
+            writeln!(writer, "{label}").context("failed to write orphan sources")?;
+        }
+        writer.flush().context("failed to flush orphan sources")?;
+        info!(count = result.orphan_sources.len(), "wrote orphan sources");
+    }
+
+    if let Some(path) = &args.warnings_file {
+        let warnings = serde_json::json!({
+            "normalizedSources": result.normalized_sources,
+            "cycles": result.cycles,
+        });
+        let mut writer = output_writer(Some(path))?;
+        serde_json::to_writer_pretty(&mut writer, &warnings)
+            .context("failed to write warnings file")?;
+        writer.flush().context("failed to flush warnings file")?;
+    }
+    if !result.normalized_sources.is_empty() {
+        info!(
+            count = result.normalized_sources.len(),
+            "ignored whitespace or comments in source files"
+        );
+    }
+
+    if let Some(report_path) = &args.artifact_report {
+        // Artifacts written to stdout cannot be measured.
+        let files: Vec<(&str, &Path)> = [
+            ("hashes", args.output_path.as_deref()),
+            (
+                "depEdges",
+                args.dep_edges_file
+                    .as_deref()
+                    .filter(|_| !dep_edges_to_stdout),
+            ),
+            ("orphanSources", args.orphan_sources_file.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(role, path)| Some((role, path?)))
+        .collect();
+        let report = core::artifact_size_report(&files, &result.hashes)?;
+        for file in &report.files {
+            info!(
+                role = file.role,
+                bytes = file.bytes,
+                estimated_gzip_bytes = file.estimated_gzip_bytes,
+                "artifact size"
+            );
+        }
+        let mut writer = output_writer(Some(report_path))?;
+        serde_json::to_writer_pretty(&mut writer, &report)
+            .context("failed to write artifact report")?;
+        writer.flush().context("failed to flush artifact report")?;
+    }
+
+    info!(count = result.hashes.len(), "finished generate-hashes",);
+    Ok(())
+}
+
+async fn handle_get_impacted_targets(args: GetImpactedTargetsArgs) -> Result<()> {
+    if args.streaming {
+        return stream_impacted_targets(&args);
+    }
+    let result = match &args.changed_targets_file {
+        Some(changed_path) => changed_targets_result(changed_path, &args)?,
+        None => hash_comparison_result(&args)?,
+    };
+    if let Some(starting_edges) = &args.compare_dep_edges {
+        // Deps of every impacted target are compared, whichever of them the
+        // test selection, expansion and filter steps would keep.
+        return args.write_dep_edge_changes(starting_edges, &result);
+    }
+    let result = match &args.coverage_map {
+        Some(path) => args.add_covering_tests(result, &core::CoverageMap::load(path)?)?,
+        None => result,
+    };
+    let result = args.tests.select(result).await?;
+    let result = args.output.expand(result, || args.final_test_suites())?;
+    let result = args.output.filter(result, || args.final_target_types())?;
+    args.output.write(result)
+}
+
+impl GetImpactedTargetsArgs {
+    fn impact_options(&self) -> core::ImpactOptions {
+        core::ImpactOptions {
+            target_types: self.target_types.clone(),
+            normalize_target_type: self.normalize_target_type,
+        }
+    }
+
+    /// Reads --depEdgesFile, without its exec-only edges for --ignoreExecEdges.
+    fn read_dep_edges(&self, path: &Path) -> Result<core::DirectedDependencyEdges> {
+        let dep_edges = core::read_directed_dep_edges_file(path)?;
+        if self.ignore_exec_edges {
+            return dep_edges.without_exec_edges();
+        }
+        Ok(dep_edges)
+    }
+
+    /// --compareDepEdges: how the deps of each impacted target changed.
+    fn write_dep_edge_changes(
+        &self,
+        starting_edges: &Path,
+        result: &core::ImpactedTargetsResult,
+    ) -> Result<()> {
+        if self.output.format != ImpactedOutputFormat::Default {
+            bail!("--compareDepEdges writes its own JSON report; drop --format");
+        }
+        let final_edges = self
+            .dep_edges
+            .as_ref()
+            .context("--compareDepEdges requires --depEdgesFile")?;
+        let changes = core::dep_edge_changes(
+            &result.impacted,
+            &core::read_dep_edges_file(starting_edges)?,
+            &core::read_dep_edges_file(final_edges)?,
+        );
+        let mut writer = Vec::new();
+        serde_json::to_writer_pretty(&mut writer, &changes)
+            .context("failed to write dep edge changes")?;
+        write_output(
+            self.output.output.as_ref(),
+            self.output.post_process.as_deref(),
+            writer,
+        )?;
+        info!(
+            count = changes.len(),
+            "finished comparing dep edges of impacted targets"
+        );
+        if changes.is_empty() && self.output.fail_if_none_impacted {
+            return Err(NoneImpacted.into());
+        }
+        Ok(())
+    }
+
+    /// Test suite members recorded in the meta of the final hashes.
+    fn final_test_suites(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        let Some(start_hashes) = &self.start_hashes else {
+            bail!("--expandTestSuites needs --startingHashes and --finalHashes");
+        };
+        let final_hashes = self.final_hashes.as_ref().unwrap_or(start_hashes);
+        core::read_hashes_meta(final_hashes)?
+            .and_then(|meta| meta.test_suites)
+            .with_context(|| {
+                format!(
+                    "--expandTestSuites needs test suites recorded in {}; generate it with --outputFormat json-v2",
+                    final_hashes.display()
+                )
+            })
+    }
+
+    /// Target types recorded in the final hashes; none for --changedTargetsFile.
+    fn final_target_types(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .read_final_hashes()?
+            .map(|hashes| core::target_types(&hashes))
+            .unwrap_or_default())
+    }
+
+    /// The final hashes with any deltas applied; none for --changedTargetsFile.
+    fn read_final_hashes(&self) -> Result<Option<core::TargetHashes>> {
+        let Some(start_hashes) = &self.start_hashes else {
+            return Ok(None);
+        };
+        let final_hashes = self.final_hashes.as_ref().unwrap_or(start_hashes);
+        let hashes = if self.apply_delta.is_empty() {
+            core::read_target_hashes(final_hashes)?
+        } else {
+            core::read_target_hashes_with_deltas(final_hashes, &self.apply_delta)?
+        };
+        Ok(Some(hashes))
+    }
+
+    /// --coverageMap: adds the tests covering impacted or --modified-filepaths
+    /// files that the final hashes still have.
+    fn add_covering_tests(
+        &self,
+        result: core::ImpactedTargetsResult,
+        coverage: &core::CoverageMap,
+    ) -> Result<core::ImpactedTargetsResult> {
+        let modified_paths = match &self.modified_filepaths {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read modified filepaths {}", path.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => Vec::new(),
+        };
+        let final_hashes = self.read_final_hashes()?;
+        Ok(core::add_covering_tests(
+            result,
+            coverage,
+            &modified_paths,
+            |test| match &final_hashes {
+                Some(hashes) => hashes.contains_key(test),
+                None => true,
+            },
+        ))
+    }
+}
+
+/// `get-impacted-targets --streaming`: labels are written as the merge-join
+/// finds them, so neither hash file nor the impacted set is held in memory.
+fn stream_impacted_targets(args: &GetImpactedTargetsArgs) -> Result<()> {
+    let (Some(start_hashes), Some(final_hashes)) = (&args.start_hashes, &args.final_hashes) else {
+        bail!("--streaming requires --startingHashes and --finalHashes");
+    };
+    if args.output.format != ImpactedOutputFormat::Default {
+        bail!("--streaming only writes labels; drop --format");
+    }
+    info!(
+        start = %start_hashes.display(),
+        final = %final_hashes.display(),
+        "streaming impacted targets"
+    );
+    let mut writer = output_writer(args.output.output.as_ref())?;
+    let count = core::stream_impacted_targets_with_options(
+        start_hashes,
+        final_hashes,
+        &args.impact_options(),
+        |label| writeln!(writer, "{label}").context("failed to write impacted target"),
+    )?;
+    writer.flush().context("failed to flush impacted targets")?;
+    info!(count, "finished computing impacted targets");
+    if count == 0 && args.output.fail_if_none_impacted {
+        return Err(NoneImpacted.into());
+    }
+    Ok(())
+}
+
+fn hash_comparison_result(args: &GetImpactedTargetsArgs) -> Result<core::ImpactedTargetsResult> {
+    let Some(start_hashes) = &args.start_hashes else {
+        bail!("--startingHashes is required");
+    };
+    // Without --finalHashes, deltas apply on top of the starting hashes.
+    let final_hashes = match (&args.final_hashes, args.apply_delta.is_empty()) {
+        (Some(path), _) => path,
+        (None, false) => start_hashes,
+        (None, true) => bail!("--finalHashes is required"),
+    };
+
+    info!(
+        start = %start_hashes.display(),
+        final = %final_hashes.display(),
+        dep_edges = args.dep_edges.as_ref().map(|p| p.display().to_string()),
+        "computing impacted targets"
+    );
+
+    let dep_edges = args
+        .dep_edges
+        .as_ref()
+        .map(|path| args.read_dep_edges(path))
+        .transpose()?;
+    let mut result = if args.apply_delta.is_empty() {
+        core::get_impacted_targets_with_dep_edges_and_options(
+            start_hashes,
+            final_hashes,
+            dep_edges.as_ref(),
+            &args.impact_options(),
+        )?
+    } else {
+        let start = core::read_target_hashes(start_hashes)?;
+        let last = core::read_target_hashes_with_deltas(final_hashes, &args.apply_delta)?;
+        let mut result = core::impacted_targets_from_hashes_with_options(
+            &start,
+            &last,
+            None,
+            &args.impact_options(),
+        )?;
+        if let Some(dep_edges) = &dep_edges {
+            let forward = dep_edges.forward_among(&result.impacted);
+            result.distances = Some(core::compute_distances(
+                &start,
+                &last,
+                &forward,
+                &result.impacted,
+            )?);
+        }
+        result
+    };
+    let forward = dep_edges
+        .as_ref()
+        .map(|dep_edges| dep_edges.forward_among(&result.impacted));
+    add_weighted_distances(&mut result, forward.as_deref(), args.cost_file.as_ref())?;
+    Ok(result)
+}
+
+fn changed_targets_result(
+    changed_path: &PathBuf,
+    args: &GetImpactedTargetsArgs,
+) -> Result<core::ImpactedTargetsResult> {
+    let dep_path = args
+        .dep_edges
+        .as_ref()
+        .context("--changedTargetsFile requires --depEdgesFile")?;
+    let changed: Vec<String> = std::fs::read_to_string(changed_path)
+        .with_context(|| format!("failed to read changed targets {}", changed_path.display()))?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    for label in &changed {
+        core::validate_label(label).with_context(|| format!("in {}", changed_path.display()))?;
+    }
+    let dep_edges = args.read_dep_edges(dep_path)?;
+
+    info!(
+        changed = changed.len(),
+        "computing rdeps closure of changed targets"
+    );
+    let impacted = core::impacted_from_reverse_edges(&changed, &dep_edges.reverse());
+    Ok(core::ImpactedTargetsResult {
+        impacted,
+        distances: None,
+    })
+}
+
+async fn handle_diff(args: DiffArgs) -> Result<()> {
+    if args.hashing.read_only && args.since_tag.is_some() {
+        bail!("--sinceTag adds a git worktree to the workspace's repository; it can't be combined with --readOnly");
+    }
+    args.hashing.check_outputs([
+        args.final_hashes_output.as_ref(),
+        args.cache_prime_file.as_ref(),
+        args.output.output.as_ref(),
+    ])?;
+    let (start_hashes, start_meta) = match (&args.start_hashes, &args.baseline_service) {
+        (Some(path), _) => (
+            core::read_target_hashes(path)?,
+            core::read_hashes_meta(path)?,
+        ),
+        (None, Some(url)) => {
+            let commit = match &args.commit {
+                Some(commit) => commit.clone(),
+                None => core::rev_parse(&args.hashing.workspace_path[0], "HEAD")?,
+            };
+            let baseline = core::BaselineServiceClient::new(url)
+                .fetch_nearest(&commit)
+                .await?;
+            info!(
+                requested = %commit,
+                baseline = %baseline.commit,
+                count = baseline.hashes.len(),
+                "fetched baseline from service"
+            );
+            let meta = baseline.output_meta()?;
+            (core::parse_target_hashes(baseline.hashes)?, Some(meta))
+        }
+        (None, None) => match &args.since_tag {
+            Some(tag) => cached_baseline(&args, tag).await?,
+            None => bail!("either --startingHashes, --baselineService or --sinceTag is required"),
+        },
+    };
+    // Checked before hashing, so a baseline that could never match fails fast.
+    let final_meta = core::OutputMeta {
+        hash_function: Some(args.hashing.hash_function.into()),
+        approximate: Some(args.hashing.no_bazel),
+        ..Default::default()
+    };
+    core::impact::check_comparable(start_meta, Some(final_meta))?;
+
+    let track_dep_edges = args.distances || args.cache_prime_file.is_some();
+    let generated = args
+        .hashing
+        .generate(args.hashing.to_config(track_dep_edges)?)
+        .await?;
+    if let Some(path) = &args.final_hashes_output {
+        let mut writer = output_writer(Some(path))?;
+        let meta = generated.comparison_meta();
+        if meta != core::OutputMeta::default() {
+            let envelope = core::HashesEnvelope {
+                meta,
+                hashes: generated.hashes.clone(),
+            };
+            serde_json::to_writer(&mut writer, &envelope)
+        } else {
+            core::write_json_map(&mut writer, &generated.hashes)
+        }
+        .context("failed to write hash JSON")?;
+        writer.flush().context("failed to flush hash output")?;
+    }
+
+    let final_hashes = core::parse_target_hashes(generated.hashes.clone())?;
+    let all_edges = track_dep_edges.then(|| generated.dependency_edges());
+    let dep_edges = all_edges.as_ref().filter(|_| args.distances);
+    let options = core::ImpactOptions {
+        normalize_target_type: args.normalize_target_type,
+        ..core::ImpactOptions::default()
+    };
+    let mut result = core::impacted_targets_from_hashes_with_options(
+        &start_hashes,
+        &final_hashes,
+        dep_edges,
+        &options,
+    )?;
+    add_weighted_distances(&mut result, dep_edges, args.cost_file.as_ref())?;
+    let result = args
+        .output
+        .expand(result, || Ok(generated.test_suites.clone()))?;
+    let result = args
+        .output
+        .filter(result, || Ok(core::target_types(&final_hashes)))?;
+    if let (Some(path), Some(edges)) = (&args.cache_prime_file, &all_edges) {
+        let order = core::cache_prime_order(&result.impacted, edges);
+        let mut writer = output_writer(Some(path))?;
+        for label in &order {
+            writeln!(writer, "{label}").context("failed to write cache prime file")?;
+        }
+        writer.flush().context("failed to flush cache prime file")?;
+        info!(targets = order.len(), path = %path.display(), "wrote cache prime file");
+    }
+    args.output.write(result)
+}
+
+/// Hashes of the workspace at `tag` and their metadata, from the local
+/// baseline cache or generated in a temporary worktree of that commit and
+/// cached.
+async fn cached_baseline(
+    args: &DiffArgs,
+    tag: &str,
+) -> Result<(core::TargetHashes, Option<core::OutputMeta>)> {
+    if args.hashing.workspace_path.len() > 1 || args.hashing.query_proto_path.is_some() {
+        bail!("--sinceTag supports a single --workspacePath and no --queryProtoPath");
+    }
+    let workspace = &args.hashing.workspace_path[0];
+    let commit = core::rev_parse(workspace, tag)?;
+    // The worktree is hashed with the option files keyed here, not its own.
+    let config = args
+        .hashing
+        .to_config(false)?
+        .with_absolute_option_files()?;
+    let key = config.options_key()?;
+    let cache = match &args.baseline_cache_dir {
+        Some(dir) => core::LocalBaselineCache::new(dir.clone()),
+        None => core::LocalBaselineCache::for_workspace(workspace)
+            .context("cannot locate a cache directory; pass --baselineCacheDir")?,
+    };
+
+    let _lock = cache.lock(&commit, &key)?;
+    if let Some(hashes) = cache.load(&commit, &key)? {
+        info!(tag, %commit, count = hashes.len(), "using cached baseline");
+        let meta = core::read_hashes_meta(&cache.path_for(&commit, &key))?;
+        return Ok((hashes, meta));
+    }
+
+    info!(tag, %commit, "generating baseline in a temporary worktree");
+    let prefix = core::show_prefix(workspace)?;
+    let worktree = core::Worktree::add(
+        workspace,
+        &commit,
+        &cache.dir().join(format!("worktree-{commit}-{key}")),
+    )?;
+    let generated = core::generate_hashes(&core::GenerateHashesConfig {
+        workspace: worktree.path().join(prefix),
+        ..config
+    })
+    .await?;
+    drop(worktree);
+    let meta = generated.comparison_meta();
+    let path = cache.store(&commit, &key, &generated.hashes, &meta)?;
+    info!(path = %path.display(), count = generated.hashes.len(), "cached baseline");
+    Ok((core::parse_target_hashes(generated.hashes)?, Some(meta)))
+}
+
+/// Fills in weighted distances when a `--costFile` was given.
+fn add_weighted_distances(
+    result: &mut core::ImpactedTargetsResult,
+    dep_edges: Option<&core::DependencyEdges>,
+    cost_file: Option<&PathBuf>,
+) -> Result<()> {
+    let (Some(cost_file), Some(dep_edges), Some(distances)) =
+        (cost_file, dep_edges, result.distances.as_mut())
+    else {
+        return Ok(());
+    };
+    let costs = core::read_target_costs(cost_file)?;
+    core::add_weighted_distances(distances, dep_edges, &costs)
+}
+
+fn handle_trend(args: TrendArgs) -> Result<()> {
+    let points = core::compute_trend(&args.hash_dir)?;
+    let mut writer = output_writer(args.output.as_ref())?;
+    serde_json::to_writer_pretty(&mut writer, &points).context("failed to write trend JSON")?;
+    writer.flush().context("failed to flush output")?;
+    info!(points = points.len(), "finished computing trend");
+    Ok(())
+}
+
+async fn handle_hash_target(args: HashTargetArgs) -> Result<()> {
+    if args.hashing.workspace_path.len() > 1 {
+        bail!("hash-target supports a single --workspacePath");
+    }
+    args.hashing.check_outputs([args.output.as_ref()])?;
+    let config = args.hashing.to_config(false)?;
+    let report = core::hash_target(&config, &args.target).await?;
+    let mut writer = output_writer(args.output.as_ref())?;
+    serde_json::to_writer_pretty(&mut writer, &report)
+        .context("failed to write target hash JSON")?;
+    writeln!(writer)?;
+    writer.flush().context("failed to flush output")?;
+    Ok(())
+}
+
+async fn handle_validate_impact(args: ValidateImpactArgs) -> Result<()> {
+    core::validate_target_pattern(&args.universe)?;
+    let changed: Vec<String> = std::fs::read_to_string(&args.changed_files)
+        .with_context(|| {
+            format!(
+                "failed to read changed files {}",
+                args.changed_files.display()
+            )
+        })?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    let impacted = core::get_impacted_targets(
+        &args.start_hashes,
+        &args.final_hashes,
+        None::<&PathBuf>,
+        None,
+    )?
+    .impacted;
+
+    let opts = core::BazelOptions {
+        workspace: args.workspace_path.clone(),
+        bazel_path: args.bazel_path.clone().unwrap_or_default(),
+        startup_options: args.bazel_startup_options.clone(),
+        command_options: args.bazel_command_options.clone(),
+        keep_going: true,
+        ..Default::default()
+    };
+    let report =
+        core::validate_impact(&opts, &args.universe, &changed, args.sample_size, &impacted).await?;
+
+    let mut writer = output_writer(args.output.as_ref())?;
+    serde_json::to_writer_pretty(&mut writer, &report)
+        .context("failed to write validation report")?;
+    writer.flush().context("failed to flush output")?;
+    if !report.false_negatives.is_empty() {
+        warn!(
+            count = report.false_negatives.len(),
+            "hash comparison missed targets that rdeps() reports"
+        );
+    }
+    info!(
+        sampled = report.sampled_files.len(),
+        false_negatives = report.false_negatives.len(),
+        false_positives = report.false_positives.len(),
+        "finished validating impact"
+    );
+    Ok(())
+}
+
+fn handle_validate(args: ValidateArgs) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    let mut invalid = 0;
+    for path in &args.files {
+        let report = core::validate_artifact(path)?;
+        write!(stdout, "{report}").context("failed to write validation report")?;
+        if !report.is_valid() {
+            invalid += 1;
+        }
+    }
+    stdout.flush().context("failed to flush output")?;
+    if invalid > 0 {
+        bail!(
+            "{invalid} of {} file(s) failed validation",
+            args.files.len()
+        );
+    }
+    Ok(())
+}
+
+fn handle_clean(args: CleanArgs) -> Result<()> {
+    let mut report = core::CleanReport::default();
+    for dir in args.workspace_path.iter().chain(&args.dirs) {
+        core::clean_temp_files(dir, &mut report)?;
+    }
+    let cache_dirs: Vec<PathBuf> = match args.baseline_cache_dir {
+        Some(dir) => vec![dir],
+        None => args
+            .workspace_path
+            .iter()
+            .filter_map(|workspace| core::LocalBaselineCache::default_dir(workspace))
+            .collect(),
+    };
+    for cache_dir in &cache_dirs {
+        core::clean_baseline_cache(cache_dir, args.baselines, &mut report)?;
+    }
+    for path in &report.in_use {
+        warn!(path = %path.display(), "skipping baseline in use by another process");
+    }
+    let mut stdout = std::io::stdout().lock();
+    for path in &report.removed {
+        writeln!(stdout, "{}", path.display()).context("failed to write output")?;
+    }
+    stdout.flush().context("failed to flush output")?;
+    info!(
+        removed = report.removed.len(),
+        bytes = report.bytes,
+        "cleaned up leftover files"
+    );
+    Ok(())
+}
+
+fn handle_gc(args: GcArgs) -> Result<()> {
+    let root = core::cache_root().context("cannot locate the cache directory")?;
+    let max_age = args
+        .max_age
+        .checked_mul(24 * 60 * 60)
+        .context("--maxAge is too large")?;
+    let max_bytes = args
+        .max_size
+        .checked_mul(1 << 20)
+        .context("--maxSize is too large")?;
+    let policy = core::GcPolicy {
+        max_age: Some(Duration::from_secs(max_age)),
+        max_bytes: Some(max_bytes),
+    };
+    let mut report = core::CleanReport::default();
+    core::gc(&root, &policy, &mut report)?;
+    for path in &report.in_use {
+        warn!(path = %path.display(), "skipping state in use by another process");
+    }
+    let mut stdout = std::io::stdout().lock();
+    for path in &report.removed {
+        writeln!(stdout, "{}", path.display()).context("failed to write output")?;
+    }
+    stdout.flush().context("failed to flush output")?;
+    info!(
+        removed = report.removed.len(),
+        bytes = report.bytes,
+        cache = %root.display(),
+        "collected cached state"
+    );
+    Ok(())
+}
+
+fn handle_set_op(args: SetOpArgs) -> Result<()> {
+    let op = match args.op {
+        SetOpArg::Union => core::SetOp::Union,
+        SetOpArg::Intersect => core::SetOp::Intersect,
+        SetOpArg::Subtract => core::SetOp::Subtract,
+    };
+    let a = core::read_label_set(&args.a)?;
+    let b = core::read_label_set(&args.b)?;
+    let labels: Vec<String> = op.apply(&a, &b).into_iter().collect();
+    info!(
+        a = a.len(),
+        b = b.len(),
+        result = labels.len(),
+        "combined label lists"
+    );
+    let bytes = match args.format {
+        LabelListFormat::Lines => labels
+            .iter()
+            .map(|label| format!("{label}\n"))
+            .collect::<String>()
+            .into_bytes(),
+        LabelListFormat::Json => serde_json::to_vec(&core::ImpactedTargetsEnvelope::Ok {
+            impacted: &labels,
+            distances: None,
+        })
+        .context("failed to write label JSON")?,
+    };
+    write_output(args.output.as_ref(), None, bytes)
+}
+
+fn handle_extract_deps(args: ExtractDepsArgs) -> Result<()> {
+    let edges = core::read_dep_edges_file(&args.dep_edges_file)?;
+    let targets = core::read_label_set(&args.targets)?;
+    let closure =
+        core::dep_edges_closure(&edges, targets.iter().map(String::as_str), args.transitive);
+    let unknown = targets
+        .iter()
+        .filter(|label| !edges.contains_key(*label))
+        .count();
+    if unknown > 0 {
+        warn!(
+            count = unknown,
+            "targets missing from the dep edges file are left out"
+        );
+    }
+    info!(
+        targets = targets.len(),
+        labels = closure.len(),
+        transitive = args.transitive,
+        "extracted dep edges"
+    );
+    let bytes = match args.format {
+        ExtractDepsFormat::Edges => {
+            serde_json::to_vec(&closure).context("failed to write dep edges JSON")?
+        }
+        ExtractDepsFormat::List => {
+            let labels: BTreeSet<&String> = closure
+                .iter()
+                .flat_map(|(label, deps)| std::iter::once(label).chain(deps))
+                .collect();
+            labels
+                .into_iter()
+                .map(|label| format!("{label}\n"))
+                .collect::<String>()
+                .into_bytes()
+        }
+    };
+    write_output(args.output.as_ref(), None, bytes)
+}
+
+fn handle_config(args: ConfigArgs) -> Result<()> {
+    let ConfigCommand::Validate {
+        workspace_path,
+        file,
+    } = args.command;
+    let path = file.unwrap_or_else(|| workspace_path.join(config::CONFIG_FILE_NAME));
+    if !path.is_file() {
+        bail!("{} does not exist", path.display());
+    }
+    let problems = config::validate(&path)?;
+    let shown = style::paint(
+        style::Stream::Stdout,
+        style::Style::Emphasis,
+        &path.display().to_string(),
+    );
+    for problem in &problems {
+        let problem = style::paint(style::Stream::Stdout, style::Style::Warning, problem);
+        println!("{shown}: {problem}");
+    }
+    if !problems.is_empty() {
+        bail!("{} has {} problem(s)", path.display(), problems.len());
+    }
+    let ok = style::paint(style::Stream::Stdout, style::Style::Success, "ok");
+    println!("{shown}: {ok}");
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn handle_serve(args: ServeArgs) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    if args.socket.exists() {
+        if std::os::unix::net::UnixStream::connect(&args.socket).is_ok() {
+            bail!("another server is listening on {}", args.socket.display());
+        }
+        std::fs::remove_file(&args.socket)
+            .with_context(|| format!("failed to remove stale socket {}", args.socket.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(&args.socket)
+        .with_context(|| format!("failed to listen on {}", args.socket.display()))?;
+    WARM_CACHE.get_or_init(core::WarmCache::new);
+    let metrics = Arc::new(health::ServeMetrics::new());
+    if let Some(address) = args.http_address {
+        health::listen(address, metrics.clone()).await?;
+    }
+    let mut shutdown = shutdown_signal(metrics.clone())?;
+    info!(socket = %args.socket.display(), "serving requests");
+    // Connections are served one at a time, so commands never run
+    // concurrently against the same Bazel server. Once asked to shut down,
+    // the running request is finished and answered before exiting.
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted.context("failed to accept a connection")?.0,
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let next = tokio::select! {
+                next = lines.next_line() => next,
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            };
+            let line = match next {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("dropping connection: {err}");
+                    break;
+                }
+            };
+            metrics.start_request();
+            let started = std::time::Instant::now();
+            let response = serve_request(&line).await;
+            metrics.finish_request(response["status"] == "ok", started.elapsed());
+            let mut response =
+                serde_json::to_vec(&response).context("failed to write response JSON")?;
+            response.push(b'\n');
+            if let Err(err) = writer.write_all(&response).await {
+                warn!("dropping connection: {err}");
+                break;
+            }
+        }
+    }
+    std::fs::remove_file(&args.socket).ok();
+    info!("server stopped");
+    Ok(())
+}
+
+/// Becomes `true` on SIGTERM or Ctrl-C, after marking `metrics` as draining.
+#[cfg(unix)]
+fn shutdown_signal(
+    metrics: Arc<health::ServeMetrics>,
+) -> Result<tokio::sync::watch::Receiver<bool>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).context("failed to listen for SIGTERM")?;
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        let in_flight = metrics.drain();
+        info!(in_flight, "shutting down once running requests finish");
+        stop.send_replace(true);
+    });
+    Ok(stopped)
+}
+
+#[cfg(not(unix))]
+async fn handle_serve(_args: ServeArgs) -> Result<()> {
+    bail!("serve needs Unix domain sockets, which this platform does not support")
+}
+
+/// Runs the command of one `serve` request, answering with the exit code the
+/// command would have had on its own.
+#[cfg(unix)]
+async fn serve_request(line: &str) -> serde_json::Value {
+    let args: Option<Vec<String>> = serde_json::from_str::<serde_json::Value>(line)
+        .ok()
+        .and_then(|mut request| serde_json::from_value(request.get_mut("args")?.take()).ok());
+    let cli = args
+        .context("requests must be JSON objects such as {\"args\": [\"generate-hashes\", ...]}")
+        .and_then(|args| {
+            let args = std::iter::once(OsString::from("bazel-differrous"))
+                .chain(args.into_iter().map(OsString::from));
+            Cli::try_parse_from(normalize_args(args)).map_err(anyhow::Error::from)
+        });
+    let result = match cli {
+        Ok(cli) if matches!(cli.command, Commands::Serve(_)) => {
+            Err(anyhow::anyhow!("serve cannot be requested from a server"))
+        }
+        Ok(cli) => {
+            // Boxed: `run` reaches this function through `handle_serve`.
+            let command: std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>>>> =
+                Box::pin(run(cli));
+            command.await
+        }
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => serde_json::json!({"status": "ok", "exitCode": 0}),
+        Err(err) if err.is::<NoneImpacted>() => {
+            serde_json::json!({"status": "ok", "exitCode": NONE_IMPACTED_EXIT_CODE})
+        }
+        Err(err) => {
+            error!(error = %err, "request failed");
+            serde_json::json!({"status": "error", "exitCode": 1, "error": format!("{err:#}")})
+        }
+    }
+}
+
+impl ImpactOutputArgs {
+    /// Applies --expandTestSuites; `test_suites` is only read when it is set.
+    fn expand(
+        &self,
+        result: core::ImpactedTargetsResult,
+        test_suites: impl FnOnce() -> Result<BTreeMap<String, Vec<String>>>,
+    ) -> Result<core::ImpactedTargetsResult> {
+        if !self.expand_test_suites {
+            return Ok(result);
+        }
+        let before = result.impacted.len();
+        let result = core::expand_test_suites(result, &test_suites()?);
+        info!(
+            suites = before - result.impacted.len(),
+            "expanded impacted test suites"
+        );
+        Ok(result)
+    }
+
+    /// Applies --filterScript; `target_types` is only read when a script is set.
+    fn filter(
+        &self,
+        result: core::ImpactedTargetsResult,
+        target_types: impl FnOnce() -> Result<HashMap<String, String>>,
+    ) -> Result<core::ImpactedTargetsResult> {
+        let Some(path) = &self.filter_script else {
+            return Ok(result);
+        };
+        let script = core::FilterScript::load(path)?;
+        let before = result.impacted.len();
+        let result = script.apply(result, &target_types()?)?;
+        info!(
+            kept = result.impacted.len(),
+            dropped = before - result.impacted.len(),
+            "applied filter script"
+        );
+        Ok(result)
+    }
+
+    fn write(&self, mut result: core::ImpactedTargetsResult) -> Result<()> {
+        if self.collapse_generated_files {
+            if result.distances.is_none() {
+                bail!("--collapseGeneratedFiles needs dep edges to find generating rules");
+            }
+            result = core::collapse_generated_files(result);
+        }
+        if let Some(namespace) = self.workspace_namespace.as_deref() {
+            let namespace = (namespace != "//").then_some(namespace);
+            result = core::select_workspace_namespace(result, namespace);
+        }
+
+        let mut writer = Vec::new();
+        let impacted_count = result.impacted.len();
+
+        if let Some(grouping) = &self.group_by_prefix {
+            match self.format {
+                ImpactedOutputFormat::Default => {}
+                ImpactedOutputFormat::Markdown => {
+                    bail!("--groupByPrefix cannot be combined with --format markdown")
+                }
+                ImpactedOutputFormat::Json => {
+                    bail!("--groupByPrefix cannot be combined with --format json")
+                }
+                ImpactedOutputFormat::QueryExpr => {
+                    bail!("--groupByPrefix cannot be combined with --format query-expr")
+                }
+            }
+            let groups = core::group_by_prefix(&result, grouping);
+            serde_json::to_writer_pretty(&mut writer, &groups)
+                .context("failed to write grouped impacted targets")?;
+        } else if self.format == ImpactedOutputFormat::Markdown {
+            let options = core::MarkdownReportOptions {
+                max_packages: self.markdown_max_packages,
+                max_targets_per_package: self.markdown_max_targets_per_package,
+                ..Default::default()
+            };
+            writer
+                .write_all(core::render_markdown(&result, &options).as_bytes())
+                .context("failed to write markdown report")?;
+        } else if self.format == ImpactedOutputFormat::Json {
+            serde_json::to_writer_pretty(
+                &mut writer,
+                &core::ImpactedTargetsEnvelope::from(&result),
+            )
+            .context("failed to write impacted targets JSON")?;
+        } else if self.format == ImpactedOutputFormat::QueryExpr {
+            let expression = core::set_expression(&result.impacted)?;
+            writeln!(writer, "{expression}").context("failed to write query expression")?;
+        } else if let Some(distances) = result.distances {
+            serde_json::to_writer_pretty(&mut writer, &distances)
+                .context("failed to write impacted targets JSON")?;
+        } else {
+            for label in &result.impacted {
+                writeln!(writer, "{}", label).context("failed to write impacted target")?;
+            }
+        }
+
+        write_output(self.output.as_ref(), self.post_process.as_deref(), writer)?;
+        info!(
+            count = impacted_count,
+            "finished computing impacted targets"
+        );
+        if impacted_count == 0 && self.fail_if_none_impacted {
+            return Err(NoneImpacted.into());
+        }
+        Ok(())
+    }
+
+    /// Where a failed run reports `{"status": "error"}`: `Some(output path)`
+    /// with `--format json`, where `Some(None)` means stdout.
+    fn error_envelope_output(&self) -> Option<Option<PathBuf>> {
+        (self.format == ImpactedOutputFormat::Json).then(|| self.output.clone())
+    }
+}
+
+/// Writes the error envelope for a failed `--format json` run, then hands the
+/// error back; `--failIfNoneImpacted` is not a failure and already wrote `ok`.
+fn write_error_envelope(output: Option<Option<PathBuf>>, err: anyhow::Error) -> anyhow::Error {
+    let Some(path) = output else {
+        return err;
+    };
+    if err.is::<NoneImpacted>() {
+        return err;
+    }
+    let envelope = core::ImpactedTargetsEnvelope::Error {
+        error: format!("{err:#}"),
+    };
+    let written = output_writer(path.as_ref()).and_then(|mut writer| {
+        serde_json::to_writer_pretty(&mut writer, &envelope)?;
+        writer.flush()?;
+        Ok(())
+    });
+    if let Err(write_err) = written {
+        warn!(error = %write_err, "failed to write error envelope");
+    }
+    err
+}
+
+fn handle_convert_dep_edges(args: ConvertDepEdgesArgs) -> Result<()> {
+    let mut directed = core::read_directed_dep_edges_file(&args.input)?;
+    let exec = directed.exec.take();
+    let edges = directed.into_forward();
+    let mut writer = output_writer(args.output.as_ref())?;
+    write_dep_edges(&mut writer, &edges, exec.as_ref(), args.to, args.direction)?;
+    writer.flush().context("failed to flush dep edges output")
+}
+
+/// Writes forward `edges` in `format`, inverted as `direction` asks, with the
+/// exec-only edges `exec` alongside them.
+fn write_dep_edges<W: Write>(
+    writer: &mut W,
+    edges: &core::DependencyEdges,
+    exec: Option<&core::DependencyEdges>,
+    format: DepEdgesFormat,
+    direction: DepEdgesDirection,
+) -> Result<()> {
+    let reverse = (direction != DepEdgesDirection::Forward).then(|| core::invert_dep_edges(edges));
+    let forward = (direction != DepEdgesDirection::Reverse).then_some(edges);
+    match (format, reverse, exec) {
+        (DepEdgesFormat::V1, None, None) => core::write_json_map(writer, edges),
+        (DepEdgesFormat::V2, None, None) => {
+            serde_json::to_writer(writer, &core::IndexedDependencyEdges::from_edges(edges))
+        }
+        (DepEdgesFormat::V1, reverse, exec) => serde_json::to_writer(
+            writer,
+            &core::DirectedDependencyEdgesOutput {
+                dep_edges: forward,
+                reverse_dep_edges: reverse.as_ref(),
+                exec_dep_edges: exec,
+            },
+        ),
+        (DepEdgesFormat::V2, reverse, exec) => serde_json::to_writer(
+            writer,
+            &core::DirectedDependencyEdgesOutput {
+                dep_edges: forward.map(core::IndexedDependencyEdges::from_edges),
+                reverse_dep_edges: reverse
+                    .as_ref()
+                    .map(core::IndexedDependencyEdges::from_edges),
+                exec_dep_edges: exec.map(core::IndexedDependencyEdges::from_edges),
+            },
+        ),
+    }
+    .context("failed to write dep edges JSON")
+}
+
+fn output_writer(path: Option<&PathBuf>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("failed to create output file {}", path.display())
+            })?))
+        }
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    })
+}
+
+/// Writes serialized output to `path` (stdout if omitted), first piping it
+/// through the `--postProcess` filter when one is configured.
+fn write_output(path: Option<&PathBuf>, post_process: Option<&str>, bytes: Vec<u8>) -> Result<()> {
+    let bytes = match post_process {
+        Some(command) => core::post_process(command, &bytes)?,
+        None => bytes,
+    };
+    let mut writer = output_writer(path)?;
+    // Removes a truncated output if writing fails.
+    let guard = path.map(|path| core::OutputGuard::new(path));
+    writer.write_all(&bytes).context("failed to write output")?;
+    writer.flush().context("failed to flush output")?;
+    if let Some(guard) = guard {
+        guard.keep();
+    }
+    Ok(())
+}
+
+fn parse_label(value: &str) -> Result<String> {
+    core::validate_label(value)?;
+    Ok(value.to_string())
+}
+
+fn parse_repo_name(value: &str) -> Result<String> {
+    // An empty value clears the config file's list.
+    if !value.is_empty() {
+        core::validate_repo_name(value)?;
+    }
+    Ok(value.to_string())
+}
+
+/// Values of a list option, dropping the empty ones that clear a config file
+/// default, e.g. `--excludePatterns=`.
+fn given(values: &Option<Vec<String>>) -> Vec<String> {
+    values
+        .iter()
+        .flatten()
+        .filter(|value| !value.is_empty())
+        .cloned()
+        .collect()
+}
+
+fn init_tracing(verbose: bool) {
+    let default_level = if verbose { "debug" } else { "info" };
+    let filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
     let _ = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(true)
+        .with_ansi(style::Stream::Stderr.is_styled())
         .with_writer(std::io::stderr)
         .try_init();
 }
@@ -311,7 +2886,8 @@ fn normalize_args<I>(args: I) -> Vec<OsString>
 where
     I: IntoIterator<Item = OsString>,
 {
-    args.into_iter()
+    let args: Vec<OsString> = args
+        .into_iter()
         .map(|arg| {
             let s = arg.to_string_lossy();
 
@@ -322,7 +2898,66 @@ where
                 .or_else(|| normalize_flag(&s, "-tt", "--targetType"))
                 .unwrap_or_else(|| OsString::from(s.into_owned()))
         })
-        .collect()
+        .collect();
+
+    let invoked_as_bazel_diff = args
+        .first()
+        .and_then(|program| Path::new(program).file_stem())
+        .is_some_and(|stem| stem == "bazel-diff");
+    let compat_flag = args
+        .windows(2)
+        .any(|pair| pair[0] == "--compat" && pair[1] == "bazel-diff")
+        || args.iter().any(|arg| arg == "--compat=bazel-diff");
+    if invoked_as_bazel_diff || compat_flag {
+        translate_bazel_diff_args(args)
+    } else {
+        args
+    }
+}
+
+/// Upstream boolean flags that picocli also accepts as `--flag=true|false`;
+/// `--useCquery`, `--includeTargetType` and `--excludeExternalTargets` take
+/// those values without translation.
+const BAZEL_DIFF_BOOLEAN_FLAGS: &[&str] = &["--verbose", "-v"];
+
+/// Rewrites upstream bazel-diff spellings that clap does not accept.
+fn translate_bazel_diff_args(args: Vec<OsString>) -> Vec<OsString> {
+    let generate_hashes = args.iter().any(|arg| arg == "generate-hashes");
+    let mut translated = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let s = arg.to_string_lossy();
+        if let Some((flag, value)) = s.split_once('=') {
+            if BAZEL_DIFF_BOOLEAN_FLAGS.contains(&flag) {
+                if value.eq_ignore_ascii_case("true") {
+                    translated.push(OsString::from(flag));
+                }
+                if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+                    continue;
+                }
+            }
+        }
+        if s == "--no-keep_going" {
+            translated.push(OsString::from("--keep_going=false"));
+            continue;
+        }
+        if generate_hashes {
+            // Upstream also takes the output path as `-o FILE`; here it is positional.
+            if s == "-o" || s == "--output" {
+                translated.extend(args.next());
+                continue;
+            }
+            if let Some(path) = s
+                .strip_prefix("-o=")
+                .or_else(|| s.strip_prefix("--output="))
+            {
+                translated.push(OsString::from(path));
+                continue;
+            }
+        }
+        translated.push(arg);
+    }
+    translated
 }
 
 fn normalize_flag(input: &str, short: &str, long: &str) -> Option<OsString> {
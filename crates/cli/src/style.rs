@@ -0,0 +1,123 @@
+use clap::ValueEnum;
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// When to style human-facing output with ANSI escapes.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only on terminals, unless `NO_COLOR` is set or `TERM` is `dumb`.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// The `--color` value among `args`, read before clap parses them so its
+    /// own help and errors follow it too; the last one wins.
+    pub fn from_args(args: &[OsString]) -> Self {
+        let mut choice = Self::default();
+        let mut args = args.iter().map(|arg| arg.to_string_lossy());
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("--color") {
+                Some("") => args.next().map(|value| value.into_owned()),
+                Some(rest) => rest.strip_prefix('=').map(str::to_string),
+                None => None,
+            };
+            if let Some(parsed) = value.and_then(|value| Self::from_str(&value, true).ok()) {
+                choice = parsed;
+            }
+        }
+        choice
+    }
+
+    pub fn to_clap(self) -> clap::ColorChoice {
+        match self {
+            Self::Auto => clap::ColorChoice::Auto,
+            Self::Always => clap::ColorChoice::Always,
+            Self::Never => clap::ColorChoice::Never,
+        }
+    }
+
+    fn enabled_for(self, is_terminal: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                let dumb = std::env::var_os("TERM").is_some_and(|term| term == "dumb");
+                is_terminal && !no_color && !dumb
+            }
+        }
+    }
+}
+
+/// Whether stdout and stderr are styled, decided once by [`init`].
+#[derive(Debug, Clone, Copy)]
+struct Enabled {
+    stdout: bool,
+    stderr: bool,
+}
+
+static ENABLED: OnceLock<Enabled> = OnceLock::new();
+
+/// Decides for the rest of the process whether output is styled.
+pub fn init(choice: ColorChoice) {
+    let _ = ENABLED.set(Enabled {
+        stdout: choice.enabled_for(std::io::stdout().is_terminal()),
+        stderr: choice.enabled_for(std::io::stderr().is_terminal()),
+    });
+}
+
+fn enabled() -> Enabled {
+    *ENABLED.get_or_init(|| Enabled {
+        stdout: false,
+        stderr: false,
+    })
+}
+
+/// Where styled text is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn is_styled(self) -> bool {
+        match self {
+            Self::Stdout => enabled().stdout,
+            Self::Stderr => enabled().stderr,
+        }
+    }
+}
+
+/// The styles human-facing output uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Error,
+    Warning,
+    Success,
+    Emphasis,
+}
+
+impl Style {
+    fn escape(self) -> &'static str {
+        match self {
+            Self::Error => "\x1b[1;31m",
+            Self::Warning => "\x1b[33m",
+            Self::Success => "\x1b[32m",
+            Self::Emphasis => "\x1b[1m",
+        }
+    }
+}
+
+/// `text` in `style` when `stream` is styled, unchanged otherwise.
+pub fn paint(stream: Stream, style: Style, text: &str) -> String {
+    if stream.is_styled() {
+        format!("{}{text}\x1b[0m", style.escape())
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,575 @@
+//! Stable entry point for embedding the differ in other Rust tools.
+//!
+//! [`Differ`] hashes a workspace and computes impacted targets without going
+//! through the CLI. Everything in this module follows semver: options are
+//! added as new builder methods and errors as new [`Error`] variants, while
+//! the rest of the crate may change between minor versions.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), bazel_differrous_core::api::Error> {
+//! use bazel_differrous_core::api::{Differ, Hashes};
+//!
+//! let differ = Differ::builder("/path/to/workspace")
+//!     .bazel_path("bazel")
+//!     .track_dep_edges(true)
+//!     .build()?;
+//! let baseline = Hashes::read("main.json")?;
+//! let impacted = differ.impacted_targets(&baseline).await?;
+//! for label in &impacted.impacted {
+//!     println!("{label}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::hash::{generate_hashes, GenerateHashesConfig};
+use crate::impact::{check_comparable, impacted_targets_from_hashes};
+use crate::labels::validate_repo_name;
+use crate::models::{
+    self, parse_target_hashes, read_hashes_meta, read_target_hashes, OutputMeta, TargetHashes,
+};
+use crate::remote;
+use crate::workspace::check_workspace_root;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub use crate::bazel::QueryOutputFormat;
+pub use crate::workspace::WorkspaceError;
+
+/// Direct dependencies of each target, keyed by label.
+pub type DependencyEdges = BTreeMap<String, Vec<String>>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors returned by this module. Match with a wildcard arm: new variants
+/// may be added in minor versions.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The workspace path is not the root of a Bazel workspace.
+    #[error(transparent)]
+    Workspace(#[from] WorkspaceError),
+    /// A builder option is invalid.
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// Querying Bazel or hashing the workspace failed.
+    #[error("failed to hash {}: {source}", workspace.display())]
+    Hashing {
+        workspace: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    /// A hash file could not be read or is malformed.
+    #[error("failed to read hashes from {}: {source}", path.display())]
+    Artifact {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    /// Hashes could not be compared, e.g. because one holds an invalid hash.
+    #[error("failed to compute impacted targets: {source}")]
+    Impact {
+        #[source]
+        source: BoxError,
+    },
+}
+
+/// Targets impacted between two workspace states.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ImpactedTargetsResult {
+    /// Labels of the impacted targets, sorted.
+    pub impacted: Vec<String>,
+    /// How far each impacted target is from a change, when the later state
+    /// has dep edges.
+    pub distances: Option<Vec<ImpactedTargetDistance>>,
+}
+
+impl ImpactedTargetsResult {
+    fn from_model(result: models::ImpactedTargetsResult) -> Self {
+        Self {
+            impacted: result.impacted,
+            distances: result.distances.map(|distances| {
+                distances
+                    .into_iter()
+                    .map(ImpactedTargetDistance::from_model)
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// How far an impacted target is from the nearest changed target.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ImpactedTargetDistance {
+    pub label: String,
+    /// Dependency edges between the target and the nearest change; 0 for a
+    /// target whose own inputs changed.
+    pub target_distance: usize,
+    /// Package boundaries crossed on the way from the nearest change.
+    pub package_distance: usize,
+    /// Seconds of build cost between the nearest change and the target, when
+    /// build costs were known.
+    pub weighted_distance: Option<f64>,
+    /// Rule producing the target when it is a generated file and the hashes
+    /// record target types.
+    pub generating_rule: Option<String>,
+}
+
+impl ImpactedTargetDistance {
+    /// A distance without a weighted distance or generating rule.
+    pub fn new(label: impl Into<String>, target_distance: usize, package_distance: usize) -> Self {
+        Self {
+            label: label.into(),
+            target_distance,
+            package_distance,
+            weighted_distance: None,
+            generating_rule: None,
+        }
+    }
+
+    fn from_model(distance: models::ImpactedTargetDistance) -> Self {
+        Self {
+            label: distance.label,
+            target_distance: distance.target_distance,
+            package_distance: distance.package_distance,
+            weighted_distance: distance.weighted_distance,
+            generating_rule: distance.generating_rule,
+        }
+    }
+}
+
+/// Where a [`Differ`] reads the output digests of generated files from, so
+/// they are hashed by the digests their actions recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemoteCache {
+    /// A Bazel `--disk_cache` directory.
+    Disk(PathBuf),
+    /// A cache speaking Bazel's HTTP caching protocol (`GET /ac/<hash>`).
+    Http(String),
+    /// A Remote Execution API cache at a `grpc://` or `grpcs://` URL.
+    Grpc {
+        url: String,
+        /// Bazel's `--remote_instance_name`; empty for the default instance.
+        instance_name: String,
+    },
+}
+
+impl RemoteCache {
+    /// Parses a cache as `--remoteCache` takes it: a directory, optionally as
+    /// a `file://` URL, an `http://` or `https://` cache, or a `grpc://` or
+    /// `grpcs://` cache of the default instance.
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        remote::RemoteCache::parse(spec)
+            .map(Self::from_model)
+            .map_err(|err| Error::InvalidConfig(format!("{err:#}")))
+    }
+
+    fn from_model(cache: remote::RemoteCache) -> Self {
+        match cache {
+            remote::RemoteCache::Disk(dir) => Self::Disk(dir),
+            remote::RemoteCache::Http(url) => Self::Http(url),
+            remote::RemoteCache::Grpc { url, instance_name } => Self::Grpc { url, instance_name },
+        }
+    }
+
+    fn into_model(self) -> remote::RemoteCache {
+        match self {
+            Self::Disk(dir) => remote::RemoteCache::Disk(dir),
+            Self::Http(url) => remote::RemoteCache::Http(url),
+            Self::Grpc { url, instance_name } => remote::RemoteCache::Grpc { url, instance_name },
+        }
+    }
+}
+
+impl std::fmt::Display for RemoteCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.clone().into_model())
+    }
+}
+
+/// Target hashes of one workspace state, and the dep edges between targets
+/// when they were tracked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hashes {
+    hashes: BTreeMap<String, String>,
+    dep_edges: Option<DependencyEdges>,
+    /// How the hashes were made, so that states hashed incomparably are
+    /// never compared.
+    meta: Option<OutputMeta>,
+}
+
+impl Hashes {
+    /// Hashes as written by `generate-hashes`, keyed by label.
+    pub fn from_map(hashes: BTreeMap<String, String>) -> Self {
+        Self {
+            hashes,
+            dep_edges: None,
+            meta: None,
+        }
+    }
+
+    /// Reads a hash file in any format `generate-hashes` writes, along with
+    /// the hash function and `--noBazel` marker its metadata records.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let artifact = |err: anyhow::Error| Error::Artifact {
+            path: path.to_path_buf(),
+            source: err.into(),
+        };
+        let hashes = read_target_hashes(path).map_err(artifact)?;
+        let meta = read_hashes_meta(path).map_err(artifact)?;
+        Ok(Self {
+            meta,
+            ..Self::from_map(
+                hashes
+                    .into_iter()
+                    .map(|(label, hash)| (label, hash.raw().to_string()))
+                    .collect(),
+            )
+        })
+    }
+
+    pub fn get(&self, label: &str) -> Option<&str> {
+        self.hashes.get(label).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Labels and hashes in label order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hashes
+            .iter()
+            .map(|(label, hash)| (label.as_str(), hash.as_str()))
+    }
+
+    /// The label → hash map, e.g. for serializing it.
+    pub fn as_map(&self) -> &BTreeMap<String, String> {
+        &self.hashes
+    }
+
+    /// Direct dependencies of each target, when
+    /// [`DifferBuilder::track_dep_edges`] was set.
+    pub fn dep_edges(&self) -> Option<&DependencyEdges> {
+        self.dep_edges.as_ref()
+    }
+
+    fn parse(&self) -> Result<TargetHashes, Error> {
+        parse_target_hashes(self.hashes.clone()).map_err(|err| Error::Impact { source: err.into() })
+    }
+
+    /// Targets impacted between `self` and the later state `after`, with
+    /// distances when `after` has dep edges. Fails when the two were hashed
+    /// with different hash functions, or only one of them with `--noBazel`.
+    pub fn impacted_targets(&self, after: &Hashes) -> Result<ImpactedTargetsResult, Error> {
+        let impact = |err: anyhow::Error| Error::Impact { source: err.into() };
+        check_comparable(self.meta.clone(), after.meta.clone()).map_err(impact)?;
+        impacted_targets_from_hashes(
+            &self.parse()?,
+            &after.parse()?,
+            after.dep_edges.as_ref(),
+            None,
+        )
+        .map(ImpactedTargetsResult::from_model)
+        .map_err(impact)
+    }
+}
+
+/// Hashes a Bazel workspace; create one with [`Differ::builder`].
+#[derive(Debug, Clone)]
+pub struct Differ {
+    config: GenerateHashesConfig,
+}
+
+impl Differ {
+    pub fn builder(workspace: impl Into<PathBuf>) -> DifferBuilder {
+        DifferBuilder {
+            config: GenerateHashesConfig {
+                workspace: workspace.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn workspace(&self) -> &Path {
+        &self.config.workspace
+    }
+
+    /// Queries Bazel and hashes every target of the workspace.
+    pub async fn generate_hashes(&self) -> Result<Hashes, Error> {
+        let result = generate_hashes(&self.config).await.map_err(|err| {
+            match err.downcast::<WorkspaceError>() {
+                Ok(err) => Error::Workspace(err),
+                Err(err) => Error::Hashing {
+                    workspace: self.config.workspace.clone(),
+                    source: err.into(),
+                },
+            }
+        })?;
+        let dep_edges = self
+            .config
+            .track_dep_edges
+            .then(|| result.dependency_edges());
+        let meta = OutputMeta {
+            hash_function: Some(self.config.hash_function),
+            approximate: result.approximate.then_some(true),
+            ..Default::default()
+        };
+        Ok(Hashes {
+            hashes: result.hashes,
+            dep_edges,
+            meta: Some(meta),
+        })
+    }
+
+    /// Hashes the workspace and returns the targets impacted since
+    /// `baseline`, e.g. the hashes of the merge base.
+    pub async fn impacted_targets(
+        &self,
+        baseline: &Hashes,
+    ) -> Result<ImpactedTargetsResult, Error> {
+        let current = self.generate_hashes().await?;
+        baseline.impacted_targets(&current)
+    }
+}
+
+/// Options of a [`Differ`]; the defaults match `generate-hashes` without
+/// flags.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct DifferBuilder {
+    config: GenerateHashesConfig,
+}
+
+impl DifferBuilder {
+    /// The Bazel binary; `bazel` on the `PATH` by default.
+    pub fn bazel_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.bazel_path = path.into();
+        self
+    }
+
+    /// Bazel startup options, e.g. `--output_base=...`.
+    pub fn startup_options<I, S>(mut self, options: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.startup_options = options.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Options passed to `bazel query` and `bazel cquery`.
+    pub fn command_options<I, S>(mut self, options: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.command_options = options.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Hash the configured graph from `bazel cquery` instead of `bazel query`.
+    pub fn use_cquery(mut self, use_cquery: bool) -> Self {
+        self.config.use_cquery = use_cquery;
+        self
+    }
+
+    /// Prefix each hash with the target's rule class or kind.
+    pub fn include_target_type(mut self, include: bool) -> Self {
+        self.config.include_target_type = include;
+        self
+    }
+
+    /// Leave targets of external repositories out of the hashes.
+    pub fn exclude_external_targets(mut self, exclude: bool) -> Self {
+        self.config.exclude_external_targets = exclude;
+        self
+    }
+
+    /// External repositories whose files are hashed individually.
+    pub fn fine_grained_external_repos<I, S>(mut self, repos: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.fine_grained_external_repos = repos.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Files whose contents are mixed into every hash.
+    pub fn seed_filepaths(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.seed_filepaths = Some(path.into());
+        self
+    }
+
+    /// Hash a `--output=streamed_proto` query result from this file instead
+    /// of running Bazel.
+    pub fn query_proto_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.query_proto_path = Some(path.into());
+        self
+    }
+
+    /// Ask `bazel query` for this output format, e.g.
+    /// [`QueryOutputFormat::StreamedJsonproto`] to archive the raw result.
+    pub fn query_output(mut self, format: QueryOutputFormat) -> Self {
+        self.config.query_output = format;
+        self
+    }
+
+    /// Mix the output digests of this Build Event Protocol JSON file into
+    /// the hashes of generated files.
+    pub fn bep_json_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.bep_json_file = Some(path.into());
+        self
+    }
+
+    /// Mix the output digests recorded in this cache into the hashes of
+    /// generated files.
+    pub fn remote_cache(mut self, cache: RemoteCache) -> Self {
+        self.config.remote_cache = Some(cache.into_model());
+        self
+    }
+
+    /// `.bazelrc` fragment whose normalized flags are mixed into every hash.
+    pub fn seed_build_flags_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.seed_build_flags_file = Some(path.into());
+        self
+    }
+
+    /// Record dep edges, so impacted targets come with distances.
+    pub fn track_dep_edges(mut self, track: bool) -> Self {
+        self.config.track_dep_edges = track;
+        self
+    }
+
+    /// Reuse source file digests stored in this file across runs.
+    pub fn content_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.content_cache = Some(path.into());
+        self
+    }
+
+    /// Threads used for hashing; one per CPU by default.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.config.jobs = Some(jobs);
+        self
+    }
+
+    /// Checks the options and the workspace root.
+    pub fn build(self) -> Result<Differ, Error> {
+        if self.config.jobs == Some(0) {
+            return Err(Error::InvalidConfig("jobs must be at least 1".to_string()));
+        }
+        for repo in &self.config.fine_grained_external_repos {
+            validate_repo_name(repo)
+                .map_err(|err| Error::InvalidConfig(format!("{repo}: {err}")))?;
+        }
+        if self.config.query_proto_path.is_none() {
+            check_workspace_root(&self.config.workspace)?;
+        }
+        Ok(Differ {
+            config: self.config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_differrous_proto::build;
+    use prost::Message;
+
+    fn source_file(name: &str) -> Vec<u8> {
+        build::Target {
+            r#type: build::target::Discriminator::SourceFile as i32,
+            source_file: Some(build::SourceFile {
+                name: name.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .encode_length_delimited_to_vec()
+    }
+
+    #[tokio::test]
+    async fn hashes_and_compares_through_the_facade() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), "a")?;
+        std::fs::write(tmp.path().join("b.txt"), "b")?;
+        let query = tmp.path().join("query.pb");
+        std::fs::write(
+            &query,
+            [source_file("//:a.txt"), source_file("//:b.txt")].concat(),
+        )?;
+        let differ = Differ::builder(tmp.path())
+            .query_proto_path(&query)
+            .build()?;
+
+        let before = differ.generate_hashes().await?;
+        assert_eq!(before.len(), 2);
+        std::fs::write(tmp.path().join("b.txt"), "changed")?;
+        let impacted = differ.impacted_targets(&before).await?;
+        assert_eq!(impacted.impacted, ["//:b.txt"]);
+        assert_eq!(impacted.distances, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reports_structured_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        assert!(matches!(
+            Differ::builder(tmp.path()).build(),
+            Err(Error::Workspace(WorkspaceError::NotAWorkspace { .. }))
+        ));
+        assert!(matches!(
+            Differ::builder(tmp.path()).jobs(0).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            RemoteCache::parse("ftp://cache"),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert_eq!(
+            RemoteCache::parse("file:///var/cache")?,
+            RemoteCache::Disk(PathBuf::from("/var/cache"))
+        );
+        let missing = tmp.path().join("missing.json");
+        match Hashes::read(&missing) {
+            Err(Error::Artifact { path, .. }) => assert_eq!(path, missing),
+            other => panic!("expected an artifact error, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_compare_hashes_made_differently() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let write = |name: &str, meta: &str| -> std::io::Result<PathBuf> {
+            let path = tmp.path().join(name);
+            std::fs::write(
+                &path,
+                format!(r#"{{"meta":{{{meta}}},"hashes":{{"//:a":"a1~a"}}}}"#),
+            )?;
+            Ok(path)
+        };
+        let sha256 = Hashes::read(write("sha256.json", "")?)?;
+        let blake3 = Hashes::read(write("blake3.json", r#""hashFunction":"blake3""#)?)?;
+        let approximate = Hashes::read(write("approximate.json", r#""approximate":true"#)?)?;
+
+        assert!(sha256.impacted_targets(&sha256)?.impacted.is_empty());
+        for (before, after) in [(&sha256, &blake3), (&approximate, &sha256)] {
+            assert!(matches!(
+                before.impacted_targets(after),
+                Err(Error::Impact { .. })
+            ));
+        }
+        Ok(())
+    }
+}
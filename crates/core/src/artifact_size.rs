@@ -0,0 +1,152 @@
+use crate::models::TargetHash;
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes of each file compressed to estimate its gzip ratio; larger files are
+/// extrapolated from this prefix.
+const COMPRESSION_SAMPLE_BYTES: u64 = 16 << 20;
+
+/// Bytes a `"label":"hash",` entry adds to a JSON map on top of its label and
+/// hash.
+const JSON_ENTRY_OVERHEAD: usize = 6;
+
+/// Sizes and composition of the artifacts written by one `generate-hashes`
+/// run, for choosing an output format and spotting runaway growth.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactSizeReport {
+    pub files: Vec<ArtifactFileSize>,
+    pub labels: usize,
+    /// Label count per target type; hashes written without
+    /// `--includeTargetType` are counted as `untyped`.
+    pub labels_by_type: BTreeMap<String, usize>,
+    /// Average size of a label → hash entry in the JSON map, in bytes.
+    pub average_entry_bytes: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactFileSize {
+    /// What the file holds, e.g. `hashes` or `depEdges`.
+    pub role: String,
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// Estimated size after gzip at the default level.
+    pub estimated_gzip_bytes: u64,
+    /// `bytes / estimatedGzipBytes`.
+    pub estimated_compression_ratio: f64,
+}
+
+/// Measures `files` (role and path pairs) on disk and summarizes `hashes`.
+pub fn artifact_size_report(
+    files: &[(&str, &Path)],
+    hashes: &BTreeMap<String, String>,
+) -> Result<ArtifactSizeReport> {
+    let files = files
+        .iter()
+        .map(|(role, path)| measure_file(role, path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut labels_by_type = BTreeMap::new();
+    let mut entry_bytes = 0;
+    for (label, hash) in hashes {
+        let target_type = TargetHash::parse(hash)
+            .ok()
+            .and_then(|parsed| parsed.target_type().map(str::to_string))
+            .unwrap_or_else(|| "untyped".to_string());
+        *labels_by_type.entry(target_type).or_insert(0) += 1;
+        entry_bytes += label.len() + hash.len() + JSON_ENTRY_OVERHEAD;
+    }
+    let average_entry_bytes = if hashes.is_empty() {
+        0.0
+    } else {
+        entry_bytes as f64 / hashes.len() as f64
+    };
+
+    Ok(ArtifactSizeReport {
+        files,
+        labels: hashes.len(),
+        labels_by_type,
+        average_entry_bytes,
+    })
+}
+
+fn measure_file(role: &str, path: &Path) -> Result<ArtifactFileSize> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open artifact {}", path.display()))?;
+    let bytes = file.metadata()?.len();
+
+    let mut encoder = GzEncoder::new(ByteCounter(0), Compression::default());
+    let sampled = io::copy(&mut file.take(COMPRESSION_SAMPLE_BYTES), &mut encoder)
+        .with_context(|| format!("failed to read artifact {}", path.display()))?;
+    let ByteCounter(compressed) = encoder.finish()?;
+    let sample_ratio = if sampled == 0 {
+        1.0
+    } else {
+        sampled as f64 / compressed as f64
+    };
+    let estimated_gzip_bytes = (bytes as f64 / sample_ratio).round() as u64;
+
+    Ok(ArtifactFileSize {
+        role: role.to_string(),
+        path: path.to_path_buf(),
+        bytes,
+        estimated_gzip_bytes,
+        estimated_compression_ratio: sample_ratio,
+    })
+}
+
+/// Discards written bytes, counting them.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_sizes_and_types() -> Result<()> {
+        let hashes = BTreeMap::from([
+            ("//a:a".to_string(), "Rule#aa~bb".to_string()),
+            ("//a:b".to_string(), "Rule#cc~dd".to_string()),
+            ("//a:c.txt".to_string(), "SourceFile#ee~ee".to_string()),
+            ("//a:d".to_string(), "ff~ff".to_string()),
+        ]);
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.json");
+        std::fs::write(&path, serde_json::to_vec(&hashes)?.repeat(50))?;
+
+        let report = artifact_size_report(&[("hashes", &path)], &hashes)?;
+        assert_eq!(report.labels, 4);
+        assert_eq!(
+            report.labels_by_type,
+            BTreeMap::from([
+                ("Rule".to_string(), 2),
+                ("SourceFile".to_string(), 1),
+                ("untyped".to_string(), 1),
+            ])
+        );
+        assert_eq!(report.average_entry_bytes, 22.25);
+        let file = &report.files[0];
+        assert_eq!(file.bytes, std::fs::metadata(&path)?.len());
+        assert!(file.estimated_gzip_bytes < file.bytes / 10);
+        assert!(file.estimated_compression_ratio > 10.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Every file read while hashing sources, for checking that a run only
+/// touched the workspace and the external repositories it depends on.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAccessAudit {
+    /// Sorted by path.
+    pub files: Vec<FileAccess>,
+    pub total_bytes: u64,
+    /// Files that are neither in the workspace nor in an external repository.
+    pub outside: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAccess {
+    pub path: PathBuf,
+    /// Bytes read, summed over every read.
+    pub bytes: u64,
+    pub reads: u64,
+    pub scope: AccessScope,
+    /// Repository name for [`AccessScope::External`] files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessScope {
+    Workspace,
+    External,
+    Outside,
+}
+
+impl FileAccessAudit {
+    /// Adds the reads of another run, e.g. of another workspace.
+    pub fn merge(&mut self, other: FileAccessAudit) {
+        let mut files: BTreeMap<PathBuf, FileAccess> = std::mem::take(&mut self.files)
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect();
+        for file in other.files {
+            match files.get_mut(&file.path) {
+                Some(existing) => {
+                    existing.bytes += file.bytes;
+                    existing.reads += file.reads;
+                }
+                None => {
+                    files.insert(file.path.clone(), file);
+                }
+            }
+        }
+        *self = Self::from_files(files.into_values().collect());
+    }
+
+    fn from_files(files: Vec<FileAccess>) -> Self {
+        Self {
+            total_bytes: files.iter().map(|file| file.bytes).sum(),
+            outside: files
+                .iter()
+                .filter(|file| file.scope == AccessScope::Outside)
+                .count(),
+            files,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Reads {
+    bytes: u64,
+    count: u64,
+    repository: Option<String>,
+}
+
+/// Collects file reads while hashing. Cheap to clone; clones share the
+/// record.
+#[derive(Debug, Clone, Default)]
+pub struct FileAccessRecorder {
+    reads: Arc<Mutex<BTreeMap<PathBuf, Reads>>>,
+}
+
+impl FileAccessRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `bytes` were read from `path`, which belongs to the
+    /// external `repository` or, when `None`, to the main repository.
+    pub fn record(&self, path: &Path, bytes: u64, repository: Option<&str>) {
+        if let Ok(mut reads) = self.reads.lock() {
+            let entry = reads.entry(path.to_path_buf()).or_default();
+            entry.bytes += bytes;
+            entry.count += 1;
+            if entry.repository.is_none() {
+                entry.repository = repository.map(str::to_string);
+            }
+        }
+    }
+
+    /// The recorded reads; main repository files not under `workspace`
+    /// (e.g. reached through a symlink) count as outside.
+    pub fn report(&self, workspace: &Path) -> FileAccessAudit {
+        let reads = match self.reads.lock() {
+            Ok(reads) => reads.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let files = reads
+            .into_iter()
+            .map(|(path, reads)| {
+                let scope = if reads.repository.is_some() {
+                    AccessScope::External
+                } else if path.starts_with(workspace) {
+                    AccessScope::Workspace
+                } else {
+                    AccessScope::Outside
+                };
+                FileAccess {
+                    path,
+                    bytes: reads.bytes,
+                    reads: reads.count,
+                    scope,
+                    repository: reads.repository,
+                }
+            })
+            .collect();
+        FileAccessAudit::from_files(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_and_merges_reads() {
+        let recorder = FileAccessRecorder::new();
+        recorder.record(Path::new("/ws/a.txt"), 10, None);
+        recorder.record(Path::new("/ws/a.txt"), 10, None);
+        recorder.record(Path::new("/out/external/dep/b.txt"), 5, Some("dep"));
+        recorder.record(Path::new("/elsewhere/c.txt"), 1, None);
+
+        let mut audit = recorder.report(Path::new("/ws"));
+        let scopes: Vec<_> = audit.files.iter().map(|file| file.scope).collect();
+        assert_eq!(
+            scopes,
+            [
+                AccessScope::Outside,
+                AccessScope::External,
+                AccessScope::Workspace
+            ]
+        );
+        assert_eq!(audit.files[2].reads, 2);
+        assert_eq!(audit.total_bytes, 26);
+        assert_eq!(audit.outside, 1);
+
+        audit.merge(audit.clone());
+        assert_eq!(audit.files.len(), 3);
+        assert_eq!(audit.total_bytes, 52);
+    }
+}
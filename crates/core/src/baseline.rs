@@ -0,0 +1,357 @@
+use crate::lock::{write_atomically, CacheLock, DEFAULT_LOCK_TIMEOUT};
+use crate::models::{read_target_hashes, HashesEnvelope, OutputMeta, TargetHashes};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::debug;
+
+/// A baseline hash artifact as returned by a baseline index service.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Baseline {
+    /// Commit the baseline was generated at (the nearest indexed ancestor).
+    pub commit: String,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    /// Label → hash map in the `generate-hashes` output format.
+    pub hashes: BTreeMap<String, String>,
+}
+
+impl Baseline {
+    /// The hash function and `--noBazel` flag of the hashes, from the
+    /// `hashFunction` and `approximate` entries of
+    /// [`metadata`](Self::metadata), named as in a hash file's `meta`; SHA-256
+    /// and exact hashes when they are absent.
+    pub fn output_meta(&self) -> Result<OutputMeta> {
+        Ok(OutputMeta {
+            hash_function: self.metadata_entry("hashFunction")?,
+            approximate: self.metadata_entry("approximate")?,
+            ..OutputMeta::default()
+        })
+    }
+
+    fn metadata_entry<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.metadata
+            .get(key)
+            .map(|value| serde_json::from_value(value.clone()))
+            .transpose()
+            .with_context(|| format!("invalid {key} in the metadata of baseline {}", self.commit))
+    }
+}
+
+/// Client for a service that indexes hash artifacts by commit.
+///
+/// The service answers `GET <base>/v1/baselines/nearest?commit=<sha>` with a
+/// [`Baseline`] JSON document for the nearest ancestor of `sha` it has a
+/// baseline for, or `404` when there is none.
+#[derive(Debug, Clone)]
+pub struct BaselineServiceClient {
+    base_url: String,
+    timeout: Duration,
+}
+
+impl BaselineServiceClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            timeout: Duration::from_secs(300),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn nearest_url(&self, commit: &str) -> String {
+        format!("{}/v1/baselines/nearest?commit={commit}", self.base_url)
+    }
+
+    pub async fn fetch_nearest(&self, commit: &str) -> Result<Baseline> {
+        if commit.is_empty() || !commit.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("invalid commit {commit:?} for baseline lookup");
+        }
+        let url = self.nearest_url(commit);
+        let timeout = self.timeout;
+        tokio::task::spawn_blocking(move || fetch_baseline(&url, timeout))
+            .await
+            .context("baseline fetch task panicked")?
+    }
+}
+
+fn fetch_baseline(url: &str, timeout: Duration) -> Result<Baseline> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => {
+            bail!("baseline service has no baseline for this commit or its ancestors ({url})")
+        }
+        Err(ureq::Error::Status(code, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            bail!("baseline service returned HTTP {code} for {url}: {body}")
+        }
+        Err(err) => return Err(anyhow!(err).context(format!("failed to reach {url}"))),
+    };
+    let body = response
+        .into_string()
+        .with_context(|| format!("failed to read baseline response from {url}"))?;
+    serde_json::from_str(&body).with_context(|| format!("invalid baseline document from {url}"))
+}
+
+/// Baselines generated on this machine, stored as `<commit>-<key>.json`
+/// where `key` identifies the hashing options (see
+/// [`GenerateHashesConfig::options_key`](crate::GenerateHashesConfig::options_key)).
+#[derive(Debug, Clone)]
+pub struct LocalBaselineCache {
+    dir: PathBuf,
+    legacy_dir: Option<PathBuf>,
+}
+
+impl LocalBaselineCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            legacy_dir: None,
+        }
+    }
+
+    /// The cache in [`default_dir`](Self::default_dir), migrating entries
+    /// from the [legacy cache](crate::state::legacy_baselines_dir).
+    pub fn for_workspace(workspace: &Path) -> Option<Self> {
+        let root = crate::state::cache_root()?;
+        let state = crate::state::StateDir::new(&root, workspace);
+        Some(
+            Self::new(state.baselines_dir())
+                .with_legacy_dir(crate::state::legacy_baselines_dir(&root)),
+        )
+    }
+
+    /// Moves an entry missing here out of the cache in `dir` on lookup, so
+    /// baselines generated before the cache moved are not generated again.
+    pub fn with_legacy_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.legacy_dir = Some(dir.into());
+        self
+    }
+
+    /// `baselines` in the [`StateDir`](crate::state::StateDir) of `workspace`.
+    pub fn default_dir(workspace: &Path) -> Option<PathBuf> {
+        crate::state::StateDir::for_workspace(workspace).map(|state| state.baselines_dir())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path_for(&self, commit: &str, key: &str) -> PathBuf {
+        self.dir.join(format!("{commit}-{key}.json"))
+    }
+
+    /// Serializes generation of one baseline across processes; hold it while
+    /// checking for, generating and storing the entry.
+    pub fn lock(&self, commit: &str, key: &str) -> Result<CacheLock> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create baseline cache {}", self.dir.display()))?;
+        CacheLock::exclusive(&self.path_for(commit, key), DEFAULT_LOCK_TIMEOUT)
+    }
+
+    /// The cached hashes, or `None` when this commit and key were never stored.
+    pub fn load(&self, commit: &str, key: &str) -> Result<Option<TargetHashes>> {
+        let path = self.path_for(commit, key);
+        if !path.is_file() && !self.migrate(commit, key, &path)? {
+            return Ok(None);
+        }
+        read_target_hashes(&path).map(Some)
+    }
+
+    /// Moves the entry for `commit` and `key` from the legacy cache to
+    /// `path`, returning whether there was one.
+    fn migrate(&self, commit: &str, key: &str, path: &Path) -> Result<bool> {
+        let Some(legacy_dir) = &self.legacy_dir else {
+            return Ok(false);
+        };
+        let legacy = legacy_dir.join(format!("{commit}-{key}.json"));
+        if !legacy.is_file() {
+            return Ok(false);
+        }
+        let _lock = CacheLock::exclusive(&legacy, DEFAULT_LOCK_TIMEOUT)?;
+        if !legacy.is_file() {
+            return Ok(false);
+        }
+        std::fs::rename(&legacy, path).with_context(|| {
+            format!(
+                "failed to move cached baseline {} to {}",
+                legacy.display(),
+                path.display()
+            )
+        })?;
+        debug!(from = %legacy.display(), to = %path.display(), "migrated cached baseline");
+        Ok(true)
+    }
+
+    /// Stores `hashes`, wrapped with `meta` unless it is empty, so the entry
+    /// records which hashes it can be compared with.
+    pub fn store(
+        &self,
+        commit: &str,
+        key: &str,
+        hashes: &BTreeMap<String, String>,
+        meta: &OutputMeta,
+    ) -> Result<PathBuf> {
+        let path = self.path_for(commit, key);
+        write_atomically(&path, |file| {
+            if *meta == OutputMeta::default() {
+                serde_json::to_writer(file, hashes)
+            } else {
+                serde_json::to_writer(
+                    file,
+                    &HashesEnvelope {
+                        meta: meta.clone(),
+                        hashes: hashes.clone(),
+                    },
+                )
+            }
+            .context("failed to write cached baseline")
+        })?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_function::HashFunction;
+    use crate::models::{parse_target_hashes, read_hashes_meta};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn serve_once(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            request_line
+        });
+        (format!("http://{addr}/"), handle)
+    }
+
+    #[tokio::test]
+    async fn fetches_nearest_baseline() -> Result<()> {
+        let (url, server) = serve_once(
+            "200 OK",
+            r#"{"commit":"abc123","metadata":{"bazel":"7.1.0"},"hashes":{"//pkg:a":"h1"}}"#,
+        );
+        let baseline = BaselineServiceClient::new(url)
+            .fetch_nearest("def456")
+            .await?;
+        assert_eq!(baseline.commit, "abc123");
+        assert_eq!(
+            baseline.hashes.get("//pkg:a").map(String::as_str),
+            Some("h1")
+        );
+        assert_eq!(baseline.metadata["bazel"], "7.1.0");
+        assert_eq!(baseline.output_meta()?, OutputMeta::default());
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /v1/baselines/nearest?commit=def456 "));
+        Ok(())
+    }
+
+    #[test]
+    fn baseline_metadata_names_the_hash_function() -> Result<()> {
+        let baseline: Baseline = serde_json::from_str(
+            r#"{"commit":"abc123","metadata":{"hashFunction":"blake3","approximate":true},"hashes":{}}"#,
+        )?;
+        let meta = baseline.output_meta()?;
+        assert_eq!(meta.hash_function, Some(HashFunction::Blake3));
+        assert_eq!(meta.approximate, Some(true));
+
+        let invalid: Baseline = serde_json::from_str(
+            r#"{"commit":"abc123","metadata":{"hashFunction":"md5"},"hashes":{}}"#,
+        )?;
+        assert!(invalid.output_meta().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_baseline_is_a_clear_error() {
+        let (url, _server) = serve_once("404 Not Found", "{}");
+        let err = BaselineServiceClient::new(url)
+            .fetch_nearest("def456")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no baseline for this commit"));
+    }
+
+    #[test]
+    fn local_cache_is_keyed_by_commit_and_options() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cache = LocalBaselineCache::new(tmp.path().join("baselines"));
+        let hashes = BTreeMap::from([("//pkg:a".to_string(), "h1~h1".to_string())]);
+
+        let _lock = cache.lock("abc123", "k1")?;
+        assert_eq!(cache.load("abc123", "k1")?, None);
+        cache.store("abc123", "k1", &hashes, &OutputMeta::default())?;
+        assert_eq!(
+            cache.load("abc123", "k1")?,
+            Some(parse_target_hashes(hashes.clone())?)
+        );
+        assert_eq!(cache.load("abc123", "k2")?, None);
+        assert_eq!(cache.load("def456", "k1")?, None);
+
+        let meta = OutputMeta {
+            hash_function: Some(HashFunction::Blake3),
+            ..OutputMeta::default()
+        };
+        let path = cache.store("abc123", "k3", &hashes, &meta)?;
+        assert_eq!(read_hashes_meta(&path)?, Some(meta));
+        assert_eq!(
+            cache.load("abc123", "k3")?,
+            Some(parse_target_hashes(hashes)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_baselines_move_into_the_workspace_cache() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let legacy = LocalBaselineCache::new(tmp.path().join("baselines"));
+        let hashes = BTreeMap::from([("//pkg:a".to_string(), "h1~h1".to_string())]);
+        std::fs::create_dir_all(legacy.dir())?;
+        legacy.store("abc123", "k1", &hashes, &OutputMeta::default())?;
+
+        let cache =
+            LocalBaselineCache::new(tmp.path().join("ws/baselines")).with_legacy_dir(legacy.dir());
+        let _lock = cache.lock("abc123", "k1")?;
+        assert_eq!(cache.load("abc123", "k2")?, None);
+        assert_eq!(
+            cache.load("abc123", "k1")?,
+            Some(parse_target_hashes(hashes)?)
+        );
+        assert!(cache.path_for("abc123", "k1").is_file());
+        assert!(!legacy.path_for("abc123", "k1").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_suspicious_commit_strings() {
+        let client = BaselineServiceClient::new("http://127.0.0.1:9");
+        assert!(client.fetch_nearest("abc&x=1").await.is_err());
+    }
+}
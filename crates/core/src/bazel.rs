@@ -1,11 +1,30 @@
+use crate::cleanup::{temp_file_in, TEMP_PREFIX};
+use crate::command_log::record_command;
 use anyhow::{bail, Context, Result};
 use bazel_differrous_proto::{analysis, build};
 use prost::Message;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
 use std::path::{Path, PathBuf};
-use tempfile::NamedTempFile;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
 use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Upper bound on the Bazel stderr kept for error messages; the tail is kept
+/// because Bazel reports the failure reason last.
+pub const MAX_CAPTURED_STDERR_BYTES: usize = 64 * 1024;
+
+/// `ERROR:` lines of a command's stderr kept as [`QueryError`]s; later ones
+/// are only counted.
+const MAX_CAPTURED_ERRORS: usize = 1_000;
+
+/// Longest stderr line read at once; longer lines are forwarded in pieces.
+const MAX_STDERR_LINE_BYTES: u64 = 64 * 1024;
 
 #[derive(Debug, Clone, Default)]
 pub struct BazelOptions {
@@ -16,6 +35,54 @@ pub struct BazelOptions {
     pub cquery_options: Vec<String>,
     pub use_cquery: bool,
     pub keep_going: bool,
+    /// Output format of `bazel query`; cquery streams protobuf unless this
+    /// is [`QueryOutputFormat::Proto`].
+    pub query_output: QueryOutputFormat,
+    /// Directory the query and Starlark files Bazel reads are written to;
+    /// the workspace when unset.
+    pub scratch_dir: Option<PathBuf>,
+}
+
+/// How `bazel query` writes its result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QueryOutputFormat {
+    /// Length-delimited `Target` messages; the fastest to decode.
+    #[default]
+    StreamedProto,
+    /// One `Target` per line in the proto3 JSON mapping, which is easier to
+    /// archive and inspect. Needs Bazel 7.0.0 or newer.
+    StreamedJsonproto,
+    /// A single `QueryResult` (or `CqueryResult`) message, read in full
+    /// before decoding. Slower, but survives wrappers that break streaming.
+    Proto,
+}
+
+impl QueryOutputFormat {
+    /// The `--output` value passed to Bazel.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::StreamedProto => "streamed_proto",
+            Self::StreamedJsonproto => "streamed_jsonproto",
+            Self::Proto => "proto",
+        }
+    }
+
+    /// The format to request from a Bazel at `version`: falls back to
+    /// `streamed_proto` when it cannot write `self`, or when its version is
+    /// unknown.
+    pub(crate) fn negotiate(self, version: Option<BazelVersion>) -> Self {
+        match self {
+            Self::StreamedJsonproto if !version.is_some_and(|v| v.at_least(7, 0, 0)) => {
+                warn!(
+                    version = ?version,
+                    "--output=streamed_jsonproto needs Bazel 7.0.0 or newer; using streamed_proto"
+                );
+                Self::StreamedProto
+            }
+            format => format,
+        }
+    }
 }
 
 impl BazelOptions {
@@ -26,6 +93,10 @@ impl BazelOptions {
             &self.bazel_path
         }
     }
+
+    fn scratch_dir(&self) -> &Path {
+        self.scratch_dir.as_deref().unwrap_or(&self.workspace)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -47,6 +118,80 @@ impl BazelVersion {
     pub fn at_least(&self, major: u32, minor: u32, patch: u32) -> bool {
         (self.major, self.minor, self.patch) >= (major, minor, patch)
     }
+
+    /// Parses `X.Y.Z` with an optional `bazel ` or `release ` prefix and
+    /// pre-release suffix (`7.1.0-rc2`); missing components are zero.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let version_str = text
+            .strip_prefix("bazel ")
+            .or_else(|| text.strip_prefix("release "))
+            .unwrap_or(text);
+        let base = version_str.split('-').next().unwrap_or(version_str);
+        let mut parts = base.split('.').map(|s| s.parse::<u32>());
+        let major = parts.next().transpose()?.unwrap_or(0);
+        let minor = parts.next().transpose()?.unwrap_or(0);
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+/// Values reported by `bazel info`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BazelInfo {
+    /// Every requested key with its value, e.g. `output_base`.
+    pub values: BTreeMap<String, String>,
+}
+
+impl BazelInfo {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn output_base(&self) -> Option<PathBuf> {
+        self.get("output_base").map(PathBuf::from)
+    }
+
+    pub fn execution_root(&self) -> Option<PathBuf> {
+        self.get("execution_root").map(PathBuf::from)
+    }
+
+    pub fn workspace(&self) -> Option<PathBuf> {
+        self.get("workspace").map(PathBuf::from)
+    }
+
+    /// The server's version from the `release` key (`release 7.4.1`).
+    pub fn release(&self) -> Option<Result<BazelVersion>> {
+        self.get("release").map(BazelVersion::parse)
+    }
+
+    /// Parses `bazel info` output for `keys`: a bare value when a single key
+    /// was requested, `key: value` lines otherwise.
+    fn parse(keys: &[&str], stdout: &str) -> Result<Self> {
+        let mut values = BTreeMap::new();
+        if let [key] = keys {
+            let value = stdout.lines().next().map(str::trim).unwrap_or_default();
+            values.insert(key.to_string(), value.to_string());
+        } else {
+            for line in stdout.lines().filter(|l| !l.trim().is_empty()) {
+                let (key, value) = line
+                    .split_once(':')
+                    .with_context(|| format!("unexpected bazel info line {line:?}"))?;
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        for key in keys {
+            if values
+                .get(*key)
+                .map(String::as_str)
+                .unwrap_or_default()
+                .is_empty()
+            {
+                bail!("bazel info returned no value for {key}");
+            }
+        }
+        Ok(Self { values })
+    }
 }
 
 pub async fn bazel_version(opts: &BazelOptions) -> Result<BazelVersion> {
@@ -55,98 +200,456 @@ pub async fn bazel_version(opts: &BazelOptions) -> Result<BazelVersion> {
     cmd.current_dir(&opts.workspace);
     cmd.args(&opts.startup_options);
 
-    let output = cmd
-        .output()
+    let output = run_streaming(cmd, "bazel --version")
         .await
         .context("failed to run bazel --version")?;
     if !output.status.success() {
-        bail!("bazel --version failed with {}", output.status);
+        bail!(
+            "bazel --version failed with {}: {}",
+            output.status,
+            output.stderr
+        );
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let line = stdout.lines().next().unwrap_or_default();
     // Expected: "bazel X.Y.Z" with optional suffixes.
-    let version_str = line.trim().strip_prefix("bazel ").unwrap_or(line.trim());
-    let base = version_str.split('-').next().unwrap_or(version_str);
-    let mut parts = base.split('.').map(|s| s.parse::<u32>());
-    let major = parts.next().transpose()?.unwrap_or(0);
-    let minor = parts.next().transpose()?.unwrap_or(0);
-    let patch = parts.next().transpose()?.unwrap_or(0);
-    Ok(BazelVersion::new(major, minor, patch))
+    BazelVersion::parse(stdout.lines().next().unwrap_or_default())
 }
 
 pub async fn bazel_output_base(opts: &BazelOptions) -> Result<PathBuf> {
+    bazel_info(opts, &["output_base"])
+        .await?
+        .output_base()
+        .context("bazel info output_base returned no path")
+}
+
+/// Keys fetched by [`preflight_info`] in a single `bazel info` call.
+pub const PREFLIGHT_INFO_KEYS: &[&str] = &["output_base", "execution_root", "release", "workspace"];
+
+type InfoCacheKey = (PathBuf, Vec<String>, PathBuf);
+
+/// [`PREFLIGHT_INFO_KEYS`] for `opts`, queried once per Bazel binary, startup
+/// options and workspace for the lifetime of the process. Warns when Bazel
+/// resolves `opts.workspace` to a different workspace root, e.g. because a
+/// subdirectory was passed as `--workspacePath`.
+pub async fn preflight_info(opts: &BazelOptions) -> Result<BazelInfo> {
+    static CACHE: OnceLock<Mutex<HashMap<InfoCacheKey, BazelInfo>>> = OnceLock::new();
+    let key = (
+        opts.bazel_binary().to_path_buf(),
+        opts.startup_options.clone(),
+        opts.workspace.clone(),
+    );
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(info) = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return Ok(info.clone());
+    }
+
+    let info = bazel_info(opts, PREFLIGHT_INFO_KEYS).await?;
+    debug!(info = ?info.values, "bazel info");
+    if let Some(server_workspace) = info.workspace() {
+        if !same_directory(&opts.workspace, &server_workspace) {
+            warn!(
+                workspace = %opts.workspace.display(),
+                bazel_workspace = %server_workspace.display(),
+                "--workspacePath is not the workspace root Bazel resolved; source paths are \
+                 resolved against --workspacePath"
+            );
+        }
+    }
+    cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, info.clone());
+    Ok(info)
+}
+
+fn same_directory(left: &Path, right: &Path) -> bool {
+    match (fs::canonicalize(left), fs::canonicalize(right)) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+/// Runs `bazel info` once for all of `keys` (every key when empty).
+pub async fn bazel_info(opts: &BazelOptions, keys: &[&str]) -> Result<BazelInfo> {
+    let output = run_streaming(info_command(opts, keys), "bazel info")
+        .await
+        .context("failed to run bazel info")?;
+    if !output.status.success() {
+        bail!(
+            "bazel info {} failed with {}: {}",
+            keys.join(" "),
+            output.status,
+            output.stderr
+        );
+    }
+    BazelInfo::parse(keys, &String::from_utf8_lossy(&output.stdout))
+}
+
+fn info_command(opts: &BazelOptions, keys: &[&str]) -> Command {
     let mut cmd = Command::new(opts.bazel_binary());
     cmd.args(&opts.startup_options);
     cmd.arg("info");
-    cmd.arg("output_base");
+    cmd.args(keys);
     cmd.current_dir(&opts.workspace);
+    cmd
+}
 
-    let output = cmd.output().await.context("failed to run bazel info")?;
-    if !output.status.success() {
-        bail!("bazel info output_base failed with {}", output.status);
+/// Reserved words of the query language; a pattern spelled like one must be quoted.
+const QUERY_KEYWORDS: &[&str] = &["let", "in", "set", "union", "intersect", "except"];
+
+/// Builds the union of `patterns` as a query expression, one operand per line,
+/// for use with `--query_file` (no shell is involved, so no shell quoting).
+pub fn build_query_expression(patterns: &[String]) -> Result<String> {
+    if patterns.is_empty() {
+        bail!("cannot build a query expression from zero patterns");
     }
+    let words = patterns
+        .iter()
+        .map(|p| query_word(p))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(words.join("\nunion "))
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let path = stdout
-        .lines()
-        .next()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
-        .ok_or_else(|| anyhow::anyhow!("bazel info output_base returned no path"))?;
+/// `set(...)` of `labels` as a single-line query expression, each label
+/// quoted as [`query_word`] does; `set()` when there are none.
+pub fn set_expression(labels: &[String]) -> Result<String> {
+    let words = labels
+        .iter()
+        .map(|label| query_word(label))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!("set({})", words.join(" ")))
+}
+
+/// Renders `pattern` as a single query-language word. Plain labels stay bare;
+/// anything the lexer would split or treat as an operator (`+` in canonical
+/// repo names, spaces, non-ASCII, keywords) is quoted. The query language has
+/// no escapes, so a pattern containing both quote characters is rejected.
+pub fn query_word(pattern: &str) -> Result<String> {
+    if pattern.is_empty() {
+        bail!("query pattern may not be empty");
+    }
+    let bare = !pattern.starts_with('-')
+        && !QUERY_KEYWORDS.contains(&pattern)
+        && pattern
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/@._:$~*-".contains(c));
+    if bare {
+        Ok(pattern.to_string())
+    } else if !pattern.contains('"') {
+        Ok(format!("\"{pattern}\""))
+    } else if !pattern.contains('\'') {
+        Ok(format!("'{pattern}'"))
+    } else {
+        bail!("query pattern {pattern} contains both single and double quotes")
+    }
+}
 
-    Ok(PathBuf::from(path))
+/// Query for the test rules in `universe` that depend on any of `targets`,
+/// leaving out `test_suite`s, whose expansion would also run tests that are
+/// not impacted.
+pub fn test_targets_expression(universe: &str, targets: &[String]) -> Result<String> {
+    Ok(format!(
+        "let impacted = rdeps({}, {}) in\nkind(test, $impacted) except kind(test_suite, $impacted)",
+        query_word(universe)?,
+        build_query_expression(targets)?
+    ))
 }
 
-pub fn build_query_expression(patterns: &[String]) -> String {
-    patterns
+/// Runnable test targets in `universe` depending on `targets`, sorted. Only
+/// labels of the main repository and its external repositories are queried;
+/// labels namespaced with `<workspace>!` belong to another workspace.
+pub async fn query_test_targets(
+    opts: &BazelOptions,
+    universe: &str,
+    targets: &[String],
+) -> Result<Vec<String>> {
+    let queryable: Vec<String> = targets
+        .iter()
+        .filter(|label| label.starts_with("//") || label.starts_with('@'))
+        .cloned()
+        .collect();
+    if queryable.is_empty() {
+        return Ok(Vec::new());
+    }
+    let expression = test_targets_expression(universe, &queryable)?;
+    let mut tests: Vec<String> = run_query(opts, &expression)
+        .await
+        .context("failed to query impacted test targets")?
         .iter()
-        .map(|p| format!("'{p}'"))
-        .collect::<Vec<_>>()
-        .join(" + ")
+        .filter_map(target_label)
+        .map(str::to_string)
+        .collect();
+    tests.sort();
+    tests.dedup();
+    Ok(tests)
 }
 
 pub async fn run_query(opts: &BazelOptions, expression: &str) -> Result<Vec<build::Target>> {
-    let stdout = execute_bazel(opts, "query", expression, &opts.command_options, false).await?;
-    decode_streamed_targets(&stdout)
+    let mut targets = Vec::new();
+    let errors = stream_query(opts, expression, |target| targets.push(target)).await?;
+    warn_query_errors(&errors);
+    Ok(dedup_targets(targets))
+}
+
+/// Logs each error Bazel reported loading packages for a query.
+pub(crate) fn warn_query_errors(errors: &[QueryError]) {
+    for error in errors {
+        warn!(
+            package = error.package.as_deref().unwrap_or_default(),
+            "bazel query error: {}", error.message
+        );
+    }
+}
+
+/// Runs `bazel query` and hands each target to `on_target` as soon as it is
+/// decoded from Bazel's stdout, so the raw output is never held in memory.
+/// When the streamed output cannot be decoded the query is run again with
+/// `--output=proto`, so targets decoded before that are handed out twice.
+/// Returns the errors Bazel reported loading packages, which only leave the
+/// query incomplete under `--keep_going`.
+pub async fn stream_query(
+    opts: &BazelOptions,
+    expression: &str,
+    mut on_target: impl FnMut(build::Target),
+) -> Result<Vec<QueryError>> {
+    match query_as(opts, expression, opts.query_output, &mut on_target).await {
+        Err(err) if can_fall_back(&err, opts.query_output) => {
+            warn!(error = %format!("{err:#}"), "retrying bazel query with --output=proto");
+            query_as(opts, expression, QueryOutputFormat::Proto, &mut on_target).await
+        }
+        result => result,
+    }
+}
+
+async fn query_as(
+    opts: &BazelOptions,
+    expression: &str,
+    format: QueryOutputFormat,
+    on_target: &mut dyn FnMut(build::Target),
+) -> Result<Vec<QueryError>> {
+    execute_bazel(
+        opts,
+        "query",
+        expression,
+        &opts.command_options,
+        format,
+        |message| match format {
+            QueryOutputFormat::StreamedJsonproto => decode_json_target(message, &mut *on_target),
+            _ => decode_target_frame(message, &mut *on_target),
+        },
+    )
+    .await
 }
 
 pub async fn run_cquery(opts: &BazelOptions, expression: &str) -> Result<Vec<build::Target>> {
+    let mut targets = Vec::new();
+    let mut exec_configurations = HashSet::new();
+    let errors = stream_cquery(
+        opts,
+        expression,
+        |target| targets.push(target),
+        &mut exec_configurations,
+    )
+    .await?;
+    warn_query_errors(&errors);
+    Ok(dedup_targets(targets))
+}
+
+/// [`stream_query`] for `bazel cquery`, leaving out targets incompatible with
+/// the target platform. The checksums of the exec configurations Bazel reports
+/// are added to `exec_configurations`.
+pub async fn stream_cquery(
+    opts: &BazelOptions,
+    expression: &str,
+    mut on_target: impl FnMut(build::Target),
+    exec_configurations: &mut HashSet<String>,
+) -> Result<Vec<QueryError>> {
     let compatible = compatible_target_set(opts, expression)
         .await
         .unwrap_or_default();
-    let stdout = execute_bazel(opts, "cquery", expression, &opts.cquery_options, true).await?;
-    let mut targets = decode_cquery_results(&stdout)?;
-    if !compatible.is_empty() {
-        targets.retain(|t| {
-            target_label(t)
-                .map(|label| compatible.contains(label))
-                .unwrap_or(false)
-        });
+    let format = cquery_format(opts);
+    match cquery_as(
+        opts,
+        expression,
+        format,
+        &compatible,
+        &mut on_target,
+        exec_configurations,
+    )
+    .await
+    {
+        Err(err) if can_fall_back(&err, format) => {
+            warn!(error = %format!("{err:#}"), "retrying bazel cquery with --output=proto");
+            let proto = QueryOutputFormat::Proto;
+            cquery_as(
+                opts,
+                expression,
+                proto,
+                &compatible,
+                &mut on_target,
+                exec_configurations,
+            )
+            .await
+        }
+        result => result,
     }
-    Ok(targets)
 }
 
+/// The cquery counterpart of `opts.query_output`: cquery has no streamed JSON
+/// output.
+fn cquery_format(opts: &BazelOptions) -> QueryOutputFormat {
+    match opts.query_output {
+        QueryOutputFormat::Proto => QueryOutputFormat::Proto,
+        _ => QueryOutputFormat::StreamedProto,
+    }
+}
+
+async fn cquery_as(
+    opts: &BazelOptions,
+    expression: &str,
+    format: QueryOutputFormat,
+    compatible: &HashSet<String>,
+    on_target: &mut dyn FnMut(build::Target),
+    exec_configurations: &mut HashSet<String>,
+) -> Result<Vec<QueryError>> {
+    execute_bazel(
+        opts,
+        "cquery",
+        expression,
+        &opts.cquery_options,
+        format,
+        |frame| {
+            let result = analysis::CqueryResult::decode(frame)
+                .context("failed to decode cquery protobuf message")?;
+            exec_configurations.extend(
+                result
+                    .configurations
+                    .iter()
+                    .filter(|config| is_exec_configuration(config))
+                    .map(|config| config.checksum.clone()),
+            );
+            for target in result.results.into_iter().filter_map(|c| c.target) {
+                let keep = compatible.is_empty()
+                    || target_label(&target).is_some_and(|label| compatible.contains(label));
+                if keep {
+                    on_target(target);
+                }
+            }
+            Ok(())
+        },
+    )
+    .await
+}
+
+/// Whether `config` builds tools for the exec platform. Bazel releases that
+/// predate `is_tool` only tell by the `-exec` in the mnemonic.
+fn is_exec_configuration(config: &analysis::Configuration) -> bool {
+    config.is_tool || config.mnemonic.contains("-exec")
+}
+
+/// Whether a query in `format` failed because its output could not be
+/// decoded, which a retry with `--output=proto` may avoid.
+fn can_fall_back(err: &anyhow::Error, format: QueryOutputFormat) -> bool {
+    format != QueryOutputFormat::Proto && err.downcast_ref::<UndecodableOutput>().is_some()
+}
+
+/// Drops targets a retried query handed out again, keeping the first of each.
+fn dedup_targets(targets: Vec<build::Target>) -> Vec<build::Target> {
+    let mut seen = HashSet::new();
+    targets
+        .into_iter()
+        .filter(|target| match target_label(target) {
+            Some(label) => seen.insert(label.to_string()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Reads `bazel query|cquery --output=streamed_proto` (or query
+/// `--output=streamed_jsonproto`) output captured by the caller, from `path`
+/// or from stdin when `path` is `-`.
+pub fn read_query_proto(path: &Path) -> Result<Vec<build::Target>> {
+    let bytes = if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut bytes)
+            .context("failed to read query proto from stdin")?;
+        bytes
+    } else {
+        fs::read(path).with_context(|| format!("failed to read query proto {}", path.display()))?
+    };
+    // A length prefix of 123 also reads as `{`, so JSON is only assumed when
+    // every line parses.
+    if bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+        if let Ok(targets) = decode_json_targets(&bytes) {
+            return Ok(targets);
+        }
+    }
+    decode_streamed_targets(&bytes)
+        .with_context(|| format!("failed to decode query proto {}", path.display()))
+}
+
+/// Runs a query writing `format`, handing each message of its stdout (a
+/// length-delimited protobuf message, or a line of streamed jsonproto) to
+/// `on_frame` while Bazel is still running; `--output=proto` output is one
+/// message handed over once Bazel is done.
 async fn execute_bazel(
     opts: &BazelOptions,
     subcommand: &str,
     expression: &str,
     command_opts: &[String],
-    is_cquery: bool,
-) -> Result<Vec<u8>> {
-    let query_file =
-        NamedTempFile::new_in(&opts.workspace).context("failed to create temporary query file")?;
+    format: QueryOutputFormat,
+    on_frame: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<Vec<QueryError>> {
+    let query_file = temp_file_in(opts.scratch_dir(), ".query")?;
     fs::write(query_file.path(), expression).context("failed to write query expression")?;
+    let cmd = query_command(opts, subcommand, command_opts, format, query_file.path());
+
+    let (status, stderr) =
+        run_streaming_frames(cmd, &format!("bazel {subcommand}"), format, on_frame)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to run bazel {} with query file {}",
+                    subcommand,
+                    query_file.path().display()
+                )
+            })?;
 
+    if !is_allowed_status(&status, opts.keep_going) {
+        bail!("bazel {subcommand} failed: {}", stderr.tail);
+    }
+    if stderr.dropped_errors > 0 {
+        warn!(
+            dropped = stderr.dropped_errors,
+            "bazel {subcommand} reported more errors than are recorded"
+        );
+    }
+
+    Ok(stderr
+        .errors
+        .iter()
+        .map(|error| QueryError::parse(error, &opts.workspace))
+        .collect())
+}
+
+fn query_command(
+    opts: &BazelOptions,
+    subcommand: &str,
+    command_opts: &[String],
+    format: QueryOutputFormat,
+    query_file: &Path,
+) -> Command {
+    let is_cquery = subcommand == "cquery";
     let mut cmd = Command::new(opts.bazel_binary());
     cmd.args(&opts.startup_options);
     cmd.arg(subcommand);
 
     if is_cquery {
         cmd.arg("--transitions=lite");
-        cmd.arg("--output=streamed_proto");
-    } else {
-        cmd.arg("--output=streamed_proto");
+    }
+    cmd.arg(format!("--output={}", format.as_str()));
+    if !is_cquery {
         cmd.arg("--order_output=no");
     }
 
@@ -160,28 +663,14 @@ async fn execute_bazel(
     }
 
     cmd.arg("--query_file");
-    cmd.arg(query_file.path());
+    cmd.arg(query_file);
     cmd.current_dir(&opts.workspace);
-
-    let output = cmd.output().await.with_context(|| {
-        format!(
-            "failed to run bazel {} with query file {}",
-            subcommand,
-            query_file.path().display()
-        )
-    })?;
-
-    if !is_allowed_status(&output.status, opts.keep_going) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("bazel {subcommand} failed: {stderr}");
-    }
-
-    Ok(output.stdout)
+    cmd
 }
 
-async fn compatible_target_set(opts: &BazelOptions, expression: &str) -> Result<HashSet<String>> {
-    let starlark = r#"
-def format(target):
+/// Starlark output formatter printing the labels of targets compatible with
+/// the target platform.
+const COMPATIBLE_TARGETS_STARLARK: &str = r#"def format(target):
     if providers(target) == None:
         return ""
     if "IncompatiblePlatformProvider" not in providers(target):
@@ -192,68 +681,514 @@ def format(target):
     return ""
 "#;
 
-    let query_file = NamedTempFile::new_in(&opts.workspace)?;
+async fn compatible_target_set(opts: &BazelOptions, expression: &str) -> Result<HashSet<String>> {
+    let query_file = temp_file_in(opts.scratch_dir(), ".query")?;
     fs::write(query_file.path(), expression)?;
 
-    let starlark_file = NamedTempFile::new_in(&opts.workspace)?;
-    fs::write(starlark_file.path(), starlark)?;
+    let starlark_file = temp_file_in(opts.scratch_dir(), ".cquery.bzl")?;
+    fs::write(starlark_file.path(), COMPATIBLE_TARGETS_STARLARK)?;
 
+    let cmd = compatible_targets_command(opts, starlark_file.path(), query_file.path());
+    let output = run_streaming(cmd, "bazel cquery (compat)").await?;
+    if !is_allowed_status(&output.status, opts.keep_going) {
+        bail!("bazel cquery (compat) failed: {}", output.stderr);
+    }
+
+    let set = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.trim().to_string())
+        .collect::<HashSet<_>>();
+    Ok(set)
+}
+
+fn compatible_targets_command(
+    opts: &BazelOptions,
+    starlark_file: &Path,
+    query_file: &Path,
+) -> Command {
     let mut cmd = Command::new(opts.bazel_binary());
     cmd.args(&opts.startup_options);
     cmd.arg("cquery");
     cmd.arg("--output");
     cmd.arg("starlark");
     cmd.arg("--starlark:file");
-    cmd.arg(starlark_file.path());
+    cmd.arg(starlark_file);
     if opts.keep_going {
         cmd.arg("--keep_going");
     }
     cmd.args(&opts.cquery_options);
     cmd.arg("--consistent_labels");
     cmd.arg("--query_file");
-    cmd.arg(query_file.path());
+    cmd.arg(query_file);
     cmd.current_dir(&opts.workspace);
+    cmd
+}
 
-    let output = cmd.output().await?;
-    if !is_allowed_status(&output.status, opts.keep_going) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("bazel cquery (compat) failed: {stderr}");
-    }
+/// A Bazel command as [`plan_info`], [`plan_query`] and [`plan_cquery`] would
+/// run it, for printing instead of running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedCommand {
+    pub cwd: PathBuf,
+    pub argv: Vec<String>,
+    /// Files written before the command runs, with their contents.
+    pub files: Vec<(PathBuf, String)>,
+}
 
-    let set = String::from_utf8_lossy(&output.stdout)
-        .lines()
-        .filter(|l| !l.trim().is_empty())
-        .map(|l| l.trim().to_string())
-        .collect::<HashSet<_>>();
-    Ok(set)
+impl PlannedCommand {
+    fn new(cmd: &Command, files: Vec<(PathBuf, String)>) -> Self {
+        let cmd = cmd.as_std();
+        Self {
+            cwd: cmd
+                .get_current_dir()
+                .unwrap_or(Path::new("."))
+                .to_path_buf(),
+            argv: std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            files,
+        }
+    }
 }
 
-fn decode_cquery_results(bytes: &[u8]) -> Result<Vec<build::Target>> {
-    let mut targets = Vec::new();
-    for result in decode_length_delimited::<analysis::CqueryResult>(bytes)? {
-        for configured in result.results {
-            if let Some(target) = configured.target {
-                targets.push(target);
+/// A shell script running the command: `cd` into its directory, a heredoc per
+/// file it reads, then the command line itself.
+impl std::fmt::Display for PlannedCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "cd {}", shell_word(&self.cwd.to_string_lossy()))?;
+        for (path, contents) in &self.files {
+            let mut delimiter = "EOF".to_string();
+            while contents.lines().any(|line| line == delimiter) {
+                delimiter.push('_');
             }
+            writeln!(
+                f,
+                "cat > {} <<'{delimiter}'",
+                shell_word(&path.to_string_lossy())
+            )?;
+            writeln!(f, "{}", contents.trim_end_matches('\n'))?;
+            writeln!(f, "{delimiter}")?;
         }
+        let words: Vec<_> = self.argv.iter().map(|arg| shell_word(arg)).collect();
+        writeln!(f, "{}", words.join(" "))
     }
-    Ok(targets)
 }
 
-fn decode_length_delimited<T>(mut bytes: &[u8]) -> Result<Vec<T>>
+/// `word` quoted for a POSIX shell unless it only has characters the shell
+/// leaves alone.
+fn shell_word(word: &str) -> String {
+    let bare = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/@._:=,+-%".contains(c));
+    if bare {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Where a dry run pretends the file with `suffix` is written; the real file
+/// gets a random name.
+fn dry_run_file(opts: &BazelOptions, suffix: &str) -> PathBuf {
+    opts.scratch_dir()
+        .join(format!("{TEMP_PREFIX}dry-run{suffix}"))
+}
+
+/// The `bazel info` [`bazel_info`] runs for `keys`.
+pub fn plan_info(opts: &BazelOptions, keys: &[&str]) -> PlannedCommand {
+    PlannedCommand::new(&info_command(opts, keys), Vec::new())
+}
+
+/// The `bazel query` [`stream_query`] runs for `expression`, leaving out the
+/// retry with `--output=proto`.
+pub fn plan_query(opts: &BazelOptions, expression: &str) -> PlannedCommand {
+    let query_file = dry_run_file(opts, ".query");
+    let cmd = query_command(
+        opts,
+        "query",
+        &opts.command_options,
+        opts.query_output,
+        &query_file,
+    );
+    PlannedCommand::new(&cmd, vec![(query_file, expression.to_string())])
+}
+
+/// The two `bazel cquery` commands [`stream_cquery`] runs for `expression`:
+/// the one listing compatible targets, then the cquery itself.
+pub fn plan_cquery(opts: &BazelOptions, expression: &str) -> Vec<PlannedCommand> {
+    let query_file = dry_run_file(opts, ".query");
+    let starlark_file = dry_run_file(opts, ".cquery.bzl");
+    let compatible = compatible_targets_command(opts, &starlark_file, &query_file);
+    let cquery = query_command(
+        opts,
+        "cquery",
+        &opts.cquery_options,
+        cquery_format(opts),
+        &query_file,
+    );
+    vec![
+        PlannedCommand::new(
+            &compatible,
+            vec![
+                (query_file.clone(), expression.to_string()),
+                (starlark_file, COMPATIBLE_TARGETS_STARLARK.to_string()),
+            ],
+        ),
+        PlannedCommand::new(&cquery, vec![(query_file, expression.to_string())]),
+    ]
+}
+
+/// Output of a Bazel subprocess whose stderr was streamed while it ran.
+#[derive(Debug)]
+struct StreamedOutput {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    /// The last [`MAX_CAPTURED_STDERR_BYTES`] of stderr.
+    stderr: String,
+}
+
+/// Runs `cmd`, forwarding each stderr line to tracing (visible with `-v`) as it
+/// arrives and keeping only a bounded tail of it for error reporting.
+async fn run_streaming(mut cmd: Command, prefix: &str) -> Result<StreamedOutput> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let (started, clock) = (SystemTime::now(), Instant::now());
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            record_command(cmd.as_std(), started, clock.elapsed(), None);
+            return Err(err.into());
+        }
+    };
+    let mut stdout = child.stdout.take().context("missing stdout pipe")?;
+    let stderr = child.stderr.take().context("missing stderr pipe")?;
+    let stderr_task = forward_stderr(stderr, prefix);
+
+    let mut out = Vec::new();
+    stdout.read_to_end(&mut out).await?;
+    let status = child.wait().await?;
+    record_command(cmd.as_std(), started, clock.elapsed(), Some(status));
+    let stderr = stderr_task.await.unwrap_or_default().tail;
+
+    Ok(StreamedOutput {
+        status,
+        stdout: out,
+        stderr,
+    })
+}
+
+/// [`run_streaming`] for commands writing a stream of messages in `format`:
+/// each message is handed to `on_frame` as soon as it has been read, and only
+/// one message is buffered at a time. Returns the exit status and what was
+/// captured of stderr. The command is killed if a message cannot be read or
+/// handled.
+async fn run_streaming_frames(
+    mut cmd: Command,
+    prefix: &str,
+    format: QueryOutputFormat,
+    on_frame: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(ExitStatus, CapturedStderr)> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let (started, clock) = (SystemTime::now(), Instant::now());
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            record_command(cmd.as_std(), started, clock.elapsed(), None);
+            return Err(err.into());
+        }
+    };
+    let stdout = child.stdout.take().context("missing stdout pipe")?;
+    let stderr = child.stderr.take().context("missing stderr pipe")?;
+    let stderr_task = forward_stderr(stderr, prefix);
+
+    let read = match format {
+        QueryOutputFormat::StreamedProto => read_frames(stdout, on_frame).await,
+        QueryOutputFormat::StreamedJsonproto => read_json_lines(stdout, on_frame).await,
+        QueryOutputFormat::Proto => read_message(stdout, on_frame).await,
+    };
+    if let Err(err) = read {
+        // Best effort: the process may already have exited.
+        let _ = child.kill().await;
+        record_command(cmd.as_std(), started, clock.elapsed(), None);
+        let stderr = stderr_task.await.unwrap_or_default().tail;
+        return Err(err.context(UndecodableOutput {
+            command: prefix.to_string(),
+            stderr,
+        }));
+    }
+    let status = child.wait().await?;
+    record_command(cmd.as_std(), started, clock.elapsed(), Some(status));
+    let stderr = stderr_task.await.unwrap_or_default();
+    Ok((status, stderr))
+}
+
+/// Context of an error reading or decoding a command's output, as opposed to
+/// the command failing.
+#[derive(Debug)]
+struct UndecodableOutput {
+    command: String,
+    stderr: String,
+}
+
+impl std::fmt::Display for UndecodableOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} output could not be decoded; stderr: {}",
+            self.command, self.stderr
+        )
+    }
+}
+
+/// What [`forward_stderr`] kept of a command's stderr.
+#[derive(Debug, Default)]
+struct CapturedStderr {
+    /// The last [`MAX_CAPTURED_STDERR_BYTES`] of it.
+    tail: String,
+    /// Its `ERROR:` lines, without the prefix; at most
+    /// [`MAX_CAPTURED_ERRORS`] of them.
+    errors: Vec<String>,
+    /// `ERROR:` lines beyond [`MAX_CAPTURED_ERRORS`].
+    dropped_errors: usize,
+}
+
+/// Forwards each stderr line to tracing (visible with `-v`) as it arrives,
+/// resolving to a bounded tail of it for error reporting and its `ERROR:`
+/// lines. Invalid UTF-8 is replaced rather than ending the forwarding, and
+/// stderr is drained to its end so the command never blocks on a full pipe.
+fn forward_stderr<R>(stderr: R, prefix: &str) -> JoinHandle<CapturedStderr>
 where
-    T: Message + Default,
+    R: AsyncRead + Unpin + Send + 'static,
 {
-    let mut out = Vec::new();
-    while !bytes.is_empty() {
-        let message = T::decode_length_delimited(&mut bytes)
-            .context("failed to decode streamed protobuf message")?;
-        out.push(message);
+    let prefix = prefix.to_string();
+    tokio::spawn(async move {
+        let mut tail = StderrTail::new(MAX_CAPTURED_STDERR_BYTES);
+        let mut errors = Vec::new();
+        let mut dropped_errors = 0;
+        let mut reader = BufReader::new(stderr);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            // A longer line is forwarded in pieces, bounding what is held.
+            let mut piece = (&mut reader).take(MAX_STDERR_LINE_BYTES);
+            match piece.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) => {
+                    debug!(target: "bazel", "[{prefix}] stopped reading stderr: {err}");
+                    break;
+                }
+            }
+            let bytes = buf.strip_suffix(b"\n").unwrap_or(&buf);
+            let line = String::from_utf8_lossy(bytes.strip_suffix(b"\r").unwrap_or(bytes));
+            debug!(target: "bazel", "[{prefix}] {line}");
+            if let Some(error) = line.strip_prefix("ERROR: ") {
+                if errors.len() < MAX_CAPTURED_ERRORS {
+                    errors.push(error.to_string());
+                } else {
+                    dropped_errors += 1;
+                }
+            }
+            tail.push(line.into_owned());
+        }
+        CapturedStderr {
+            tail: tail.into_string(),
+            errors,
+            dropped_errors,
+        }
+    })
+}
+
+/// An error Bazel reported while loading packages for a query, e.g. a BUILD
+/// file that does not parse. With `--keep_going` the query still succeeds and
+/// the targets of the package are missing from its result.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryError {
+    /// Package the error is about, e.g. `//pkg`, when Bazel names one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// The `ERROR:` line without its prefix.
+    pub message: String,
+}
+
+impl QueryError {
+    /// Parses an `ERROR:` line (without the prefix) of a command run in
+    /// `workspace`.
+    pub fn parse(message: &str, workspace: &Path) -> Self {
+        Self {
+            package: error_package(message, workspace),
+            message: message.to_string(),
+        }
     }
-    Ok(out)
 }
 
-fn target_label(target: &build::Target) -> Option<&str> {
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.package {
+            Some(package) => write!(f, "{package}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// The package an error message is about: quoted after `package`
+/// (`error loading package 'pkg'`, `no such package '@repo//pkg'`), listed by
+/// `package contains errors: pkg`, or whose BUILD file location starts the
+/// message.
+fn error_package(message: &str, workspace: &Path) -> Option<String> {
+    let as_label = |package: &str| {
+        if package.starts_with("//") || package.starts_with('@') {
+            package.to_string()
+        } else {
+            format!("//{package}")
+        }
+    };
+    if let Some((_, rest)) = message.split_once("package '") {
+        return rest.split_once('\'').map(|(package, _)| as_label(package));
+    }
+    if let Some((_, rest)) = message.split_once("package contains errors: ") {
+        return Some(as_label(rest.trim()));
+    }
+    let location = message.split_once(": ")?.0;
+    let file = location.split(':').next()?;
+    let file = Path::new(file);
+    if !matches!(file.file_name()?.to_str()?, "BUILD" | "BUILD.bazel") {
+        return None;
+    }
+    let dir = file.parent()?.strip_prefix(workspace).ok()?;
+    Some(as_label(&crate::paths::relative_key(dir)))
+}
+
+/// Reads varint length-prefixed messages from `reader` until it ends,
+/// reusing one buffer for every message.
+async fn read_frames<R: AsyncRead + Unpin>(
+    reader: R,
+    mut on_frame: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut frame = Vec::new();
+    while let Some(len) = read_frame_len(&mut reader).await? {
+        frame.resize(len, 0);
+        reader
+            .read_exact(&mut frame)
+            .await
+            .context("streamed protobuf message is truncated")?;
+        on_frame(&frame)?;
+    }
+    Ok(())
+}
+
+/// Reads all of `reader` as one message.
+async fn read_message<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut on_message: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut message = Vec::new();
+    reader.read_to_end(&mut message).await?;
+    on_message(&message)
+}
+
+/// Reads newline-delimited JSON messages from `reader` until it ends, skipping
+/// blank lines.
+async fn read_json_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    mut on_line: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.iter().any(|b| !b.is_ascii_whitespace()) {
+            on_line(&line)?;
+        }
+    }
+}
+
+/// The varint length prefix of the next message, or `None` at the end of
+/// the stream.
+async fn read_frame_len<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<usize>> {
+    let mut len: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        if reader.read(&mut byte).await? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            bail!("streamed protobuf length prefix is truncated");
+        }
+        len |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(usize::try_from(len)?));
+        }
+    }
+    bail!("streamed protobuf length prefix is longer than 10 bytes")
+}
+
+/// Bounded buffer holding the most recent stderr lines.
+struct StderrTail {
+    lines: VecDeque<String>,
+    bytes: usize,
+    dropped: usize,
+    cap: usize,
+}
+
+impl StderrTail {
+    fn new(cap: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+            cap,
+        }
+    }
+
+    /// Adds `line`, keeping only its first `cap` bytes.
+    fn push(&mut self, mut line: String) {
+        if line.len() > self.cap {
+            let mut end = self.cap;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let omitted = line.len() - end;
+            line.truncate(end);
+            line.push_str(&format!(" [... {omitted} bytes omitted]"));
+        }
+        self.bytes += line.len() + 1;
+        self.lines.push_back(line);
+        while self.bytes > self.cap && self.lines.len() > 1 {
+            if let Some(old) = self.lines.pop_front() {
+                self.bytes -= old.len() + 1;
+                self.dropped += old.len() + 1;
+            }
+        }
+    }
+
+    fn into_string(self) -> String {
+        let mut out = String::new();
+        if self.dropped > 0 {
+            out.push_str(&format!(
+                "[... {} earlier bytes of stderr omitted ...]\n",
+                self.dropped
+            ));
+        }
+        for line in self.lines {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+pub(crate) fn target_label(target: &build::Target) -> Option<&str> {
     target
         .rule
         .as_ref()
@@ -270,47 +1205,475 @@ fn decode_streamed_targets(bytes: &[u8]) -> Result<Vec<build::Target>> {
     let mut targets = Vec::new();
     let mut slice = bytes;
     while !slice.is_empty() {
-        if let Ok((qr, remaining)) = decode_single::<build::QueryResult>(slice) {
-            targets.extend(qr.target);
-            slice = remaining;
-            continue;
+        let len = prost::encoding::decode_varint(&mut slice)
+            .context("failed to decode streamed protobuf message")?;
+        let len = usize::try_from(len)?;
+        if len > slice.len() {
+            bail!("streamed protobuf message is truncated");
         }
+        let (frame, rest) = slice.split_at(len);
+        decode_target_frame(frame, |target| targets.push(target))?;
+        slice = rest;
+    }
+    Ok(targets)
+}
 
-        if let Ok((cqr, remaining)) = decode_single::<analysis::CqueryResult>(slice) {
-            for ct in cqr.results {
-                if let Some(t) = ct.target {
-                    targets.push(t);
-                }
-            }
-            slice = remaining;
-            continue;
+fn decode_json_targets(bytes: &[u8]) -> Result<Vec<build::Target>> {
+    let mut targets = Vec::new();
+    for line in bytes.split(|b| *b == b'\n') {
+        if line.iter().any(|b| !b.is_ascii_whitespace()) {
+            decode_json_target(line, |target| targets.push(target))?;
         }
+    }
+    Ok(targets)
+}
 
-        if let Ok((ct, remaining)) = decode_single::<analysis::ConfiguredTarget>(slice) {
-            if let Some(t) = ct.target {
-                targets.push(t);
-            }
-            slice = remaining;
-            continue;
-        }
+/// Decodes one line of `--output=streamed_jsonproto`: a `Target` in the
+/// proto3 JSON mapping.
+fn decode_json_target(line: &[u8], mut on_target: impl FnMut(build::Target)) -> Result<()> {
+    let target =
+        serde_json::from_slice(line).context("failed to decode streamed jsonproto line")?;
+    on_target(target);
+    Ok(())
+}
 
-        if let Ok((target, remaining)) = decode_single::<build::Target>(slice) {
-            targets.push(target);
-            slice = remaining;
-            continue;
+/// Decodes one message of streamed query output, which depending on the
+/// command and Bazel version is a query result, a cquery result, a
+/// configured target or a bare target.
+fn decode_target_frame(frame: &[u8], mut on_target: impl FnMut(build::Target)) -> Result<()> {
+    if let Ok(qr) = build::QueryResult::decode(frame) {
+        qr.target.into_iter().for_each(on_target);
+    } else if let Ok(cqr) = analysis::CqueryResult::decode(frame) {
+        cqr.results
+            .into_iter()
+            .filter_map(|ct| ct.target)
+            .for_each(on_target);
+    } else if let Ok(ct) = analysis::ConfiguredTarget::decode(frame) {
+        if let Some(target) = ct.target {
+            on_target(target);
         }
-
+    } else if let Ok(target) = build::Target::decode(frame) {
+        on_target(target);
+    } else {
         bail!("failed to decode streamed protobuf message");
     }
-    Ok(targets)
+    Ok(())
 }
 
-fn decode_single<T>(input: &[u8]) -> Result<(T, &[u8]), prost::DecodeError>
-where
-    T: Message + Default,
-{
-    let mut buf = input;
-    let message = T::decode_length_delimited(&mut buf)?;
-    let consumed = input.len() - buf.len();
-    Ok((message, &input[consumed..]))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stderr_tail_keeps_most_recent_lines() {
+        let mut tail = StderrTail::new(10);
+        for line in ["first", "second", "third"] {
+            tail.push(line.to_string());
+        }
+        let rendered = tail.into_string();
+        assert!(rendered.starts_with("[... 13 earlier bytes of stderr omitted ...]"));
+        assert!(rendered.ends_with("third\n"));
+        assert!(!rendered.contains("first"));
+    }
+
+    #[test]
+    fn stderr_tail_truncates_long_lines() {
+        let mut tail = StderrTail::new(3);
+        tail.push("ab\u{e9}defghij".to_string());
+        assert_eq!(tail.into_string(), "ab [... 9 bytes omitted]\n");
+    }
+
+    #[tokio::test]
+    async fn stderr_is_drained_past_invalid_utf8() -> Result<()> {
+        let stderr: &'static [u8] =
+            b"ERROR: first\r\n\xff\xfe garbled\nERROR: after it\nno newline";
+        let captured = forward_stderr(stderr, "test").await?;
+        assert_eq!(captured.errors, ["first", "after it"]);
+        assert_eq!(
+            captured.tail,
+            "ERROR: first\n\u{fffd}\u{fffd} garbled\nERROR: after it\nno newline\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stderr_tail_untruncated_when_small() {
+        let mut tail = StderrTail::new(1024);
+        tail.push("ERROR: boom".to_string());
+        assert_eq!(tail.into_string(), "ERROR: boom\n");
+    }
+
+    #[test]
+    fn parses_versions_and_info_output() -> Result<()> {
+        assert_eq!(
+            BazelVersion::parse("bazel 7.1.0-rc2")?,
+            BazelVersion::new(7, 1, 0)
+        );
+        assert_eq!(
+            BazelVersion::parse("release 8")?,
+            BazelVersion::new(8, 0, 0)
+        );
+
+        let single = BazelInfo::parse(&["output_base"], "/tmp/ob\n")?;
+        assert_eq!(single.output_base(), Some(PathBuf::from("/tmp/ob")));
+
+        let info = BazelInfo::parse(
+            &["output_base", "release"],
+            "output_base: /tmp/ob\nrelease: release 7.4.1\n",
+        )?;
+        assert_eq!(
+            info.release().transpose()?,
+            Some(BazelVersion::new(7, 4, 1))
+        );
+        assert!(BazelInfo::parse(&["output_base", "workspace"], "output_base: /x\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn set_expression_quotes_each_label() -> Result<()> {
+        let labels = ["//a:b", "@@rules_go+//go:def", "//c:d e"].map(String::from);
+        assert_eq!(
+            set_expression(&labels)?,
+            "set(//a:b \"@@rules_go+//go:def\" \"//c:d e\")"
+        );
+        assert_eq!(set_expression(&[])?, "set()");
+        Ok(())
+    }
+
+    #[test]
+    fn query_words_quote_only_when_needed() -> Result<()> {
+        assert_eq!(
+            query_word("//pkg/sub:all-targets")?,
+            "//pkg/sub:all-targets"
+        );
+        assert_eq!(query_word("@repo//...:*")?, "@repo//...:*");
+        assert_eq!(
+            query_word("@@rules_go+//...:all-targets")?,
+            "\"@@rules_go+//...:all-targets\""
+        );
+        assert_eq!(query_word("//a b:c")?, "\"//a b:c\"");
+        assert_eq!(query_word("//ünï:côdé")?, "\"//ünï:côdé\"");
+        assert_eq!(query_word("union")?, "\"union\"");
+        assert_eq!(query_word("-//pkg:t")?, "\"-//pkg:t\"");
+        assert_eq!(query_word("//pkg:\"q\"")?, "'//pkg:\"q\"'");
+        assert!(query_word("//pkg:'a\"b").is_err());
+        assert!(query_word("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_expression_unions_patterns() -> Result<()> {
+        let patterns = vec![
+            "//...:all-targets".to_string(),
+            "@@dep+//...:all-targets".to_string(),
+        ];
+        assert_eq!(
+            build_query_expression(&patterns)?,
+            "//...:all-targets\nunion \"@@dep+//...:all-targets\""
+        );
+        assert!(build_query_expression(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_targets_expression_excludes_suites() -> Result<()> {
+        assert_eq!(
+            test_targets_expression("//...", &["//a:lib".into(), "@dep//:x".into()])?,
+            "let impacted = rdeps(//..., //a:lib\nunion @dep//:x) in\n\
+             kind(test, $impacted) except kind(test_suite, $impacted)"
+        );
+        assert!(test_targets_expression("//...", &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_query_proto_decodes_streamed_targets() -> Result<()> {
+        let target = build::Target {
+            r#type: build::target::Discriminator::SourceFile as i32,
+            source_file: Some(build::SourceFile {
+                name: "//pkg:file.txt".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("query.pb");
+        fs::write(&path, target.encode_length_delimited_to_vec())?;
+
+        let targets = read_query_proto(&path)?;
+        assert_eq!(targets.len(), 1);
+        assert_eq!(target_label(&targets[0]), Some("//pkg:file.txt"));
+        Ok(())
+    }
+
+    fn source_target(name: &str) -> build::Target {
+        build::Target {
+            r#type: build::target::Discriminator::SourceFile as i32,
+            source_file: Some(build::SourceFile {
+                name: name.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn streamed_jsonproto_decodes_to_the_same_targets() -> Result<()> {
+        let rule = build::Target {
+            r#type: build::target::Discriminator::Rule as i32,
+            rule: Some(build::Rule {
+                name: "//pkg:lib".to_string(),
+                rule_class: "genrule".to_string(),
+                attribute: vec![build::Attribute {
+                    name: "srcs".to_string(),
+                    r#type: build::attribute::Discriminator::LabelList as i32,
+                    string_list_value: vec!["//pkg:a.txt".to_string()],
+                    explicitly_specified: Some(true),
+                    ..Default::default()
+                }],
+                rule_input: vec!["//pkg:a.txt".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let lines = r#"{"type":"SOURCE_FILE","sourceFile":{"name":"//pkg:a.txt"}}
+
+{"type":"RULE","rule":{"name":"//pkg:lib","ruleClass":"genrule","attribute":[{"name":"srcs","type":"LABEL_LIST","stringListValue":["//pkg:a.txt"],"explicitlySpecified":true}],"ruleInput":["//pkg:a.txt"]}}
+"#;
+        let expected = vec![source_target("//pkg:a.txt"), rule];
+
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("query.jsonl");
+        fs::write(&path, lines)?;
+        assert_eq!(read_query_proto(&path)?, expected);
+
+        let mut cmd = Command::new("cat");
+        cmd.arg(&path);
+        let mut targets = Vec::new();
+        let (status, _) =
+            run_streaming_frames(cmd, "cat", QueryOutputFormat::StreamedJsonproto, |line| {
+                decode_json_target(line, |target| targets.push(target))
+            })
+            .await?;
+        assert!(status.success());
+        assert_eq!(targets, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_jsonproto_falls_back_on_older_bazel() {
+        let json = QueryOutputFormat::StreamedJsonproto;
+        assert_eq!(
+            json.negotiate(Some(BazelVersion::new(7, 0, 0))),
+            QueryOutputFormat::StreamedJsonproto
+        );
+        assert_eq!(
+            json.negotiate(Some(BazelVersion::new(6, 5, 0))),
+            QueryOutputFormat::StreamedProto
+        );
+        assert_eq!(json.negotiate(None), QueryOutputFormat::StreamedProto);
+        assert_eq!(
+            QueryOutputFormat::StreamedProto.negotiate(Some(BazelVersion::new(8, 0, 0))),
+            QueryOutputFormat::StreamedProto
+        );
+    }
+
+    #[tokio::test]
+    async fn read_frames_decodes_one_message_at_a_time() -> Result<()> {
+        let mut bytes = source_target("//pkg:a.txt").encode_length_delimited_to_vec();
+        bytes.extend(source_target(&"x".repeat(300)).encode_length_delimited_to_vec());
+        let mut labels = Vec::new();
+        read_frames(bytes.as_slice(), |frame| {
+            decode_target_frame(frame, |target| {
+                labels.push(target_label(&target).unwrap_or_default().len())
+            })
+        })
+        .await?;
+        assert_eq!(labels, [11, 300]);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(read_frames(truncated, |_| Ok(())).await.is_err());
+        assert!(read_frames(&[0x80u8][..], |_| Ok(())).await.is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_streaming_frames_stops_the_command_on_bad_output() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("query.pb");
+        fs::write(
+            &path,
+            source_target("//pkg:a.txt").encode_length_delimited_to_vec(),
+        )?;
+
+        let mut cmd = Command::new("cat");
+        cmd.arg(&path);
+        let mut count = 0;
+        let (status, _) =
+            run_streaming_frames(cmd, "cat", QueryOutputFormat::StreamedProto, |_| {
+                count += 1;
+                Ok(())
+            })
+            .await?;
+        assert!(status.success());
+        assert_eq!(count, 1);
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("printf '\\001\\377'; echo boom >&2; sleep 30");
+        let err = run_streaming_frames(cmd, "sh", QueryOutputFormat::StreamedProto, |frame| {
+            decode_target_frame(frame, |_| {})
+        })
+        .await
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("failed to decode streamed protobuf message"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn undecodable_streamed_query_is_retried_as_proto() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir()?;
+        let result = build::QueryResult {
+            target: vec![source_target("//pkg:a.txt"), source_target("//pkg:b.txt")],
+        };
+        fs::write(tmp.path().join("result.pb"), result.encode_to_vec())?;
+        let bazel = tmp.path().join("bazel");
+        fs::write(
+            &bazel,
+            format!(
+                "#!/bin/sh\ncase \"$*\" in\n  *--output=proto*) cat '{}' ;;\n  *) printf '\\001\\377' ;;\nesac\n",
+                tmp.path().join("result.pb").display()
+            ),
+        )?;
+        fs::set_permissions(&bazel, fs::Permissions::from_mode(0o755))?;
+        let opts = BazelOptions {
+            workspace: tmp.path().to_path_buf(),
+            bazel_path: bazel,
+            ..Default::default()
+        };
+
+        let targets = run_query(&opts, "//...").await?;
+        let labels: Vec<_> = targets.iter().filter_map(target_label).collect();
+        assert_eq!(labels, ["//pkg:a.txt", "//pkg:b.txt"]);
+
+        let proto = BazelOptions {
+            query_output: QueryOutputFormat::Proto,
+            ..opts
+        };
+        assert_eq!(run_query(&proto, "//...").await?.len(), 2);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_streaming_captures_stdout_and_stderr() -> Result<()> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2; exit 3");
+        let output = run_streaming(cmd, "sh").await?;
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, "err\n");
+        assert_eq!(output.status.code(), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn planned_commands_print_as_shell_scripts() {
+        let opts = BazelOptions {
+            workspace: PathBuf::from("/ws"),
+            startup_options: vec!["--output_base=/tmp/my base".to_string()],
+            command_options: vec!["--define=x=it's".to_string()],
+            keep_going: true,
+            ..Default::default()
+        };
+        let query = plan_query(&opts, "//...:all-targets\nunion\nEOF");
+        assert_eq!(
+            query.to_string(),
+            "cd /ws\n\
+             cat > /ws/.bazel-differrous-dry-run.query <<'EOF_'\n\
+             //...:all-targets\nunion\nEOF\n\
+             EOF_\n\
+             bazel '--output_base=/tmp/my base' query --output=streamed_proto \
+             --order_output=no --keep_going '--define=x=it'\\''s' \
+             --query_file /ws/.bazel-differrous-dry-run.query\n"
+        );
+
+        let cquery = plan_cquery(&opts, "deps(//...)");
+        assert_eq!(cquery.len(), 2);
+        assert_eq!(cquery[0].files.len(), 2);
+        assert!(cquery[1].argv.contains(&"--transitions=lite".to_string()));
+        assert_eq!(
+            plan_info(&opts, &["release"]).argv,
+            ["bazel", "--output_base=/tmp/my base", "info", "release"]
+        );
+    }
+
+    #[test]
+    fn query_errors_name_the_failing_package() {
+        let workspace = Path::new("/ws");
+        let cases = [
+            (
+                "error loading package 'pkg/sub': Unable to find package",
+                Some("//pkg/sub"),
+            ),
+            (
+                "no such package '@repo//lib': BUILD file not found",
+                Some("@repo//lib"),
+            ),
+            ("package contains errors: broken", Some("//broken")),
+            (
+                "/ws/app/BUILD.bazel:3:10: name 'cc_libary' is not defined",
+                Some("//app"),
+            ),
+            ("/elsewhere/BUILD:1:1: syntax error", None),
+            (
+                "Evaluation of query \"//...\" failed: errors were encountered",
+                None,
+            ),
+        ];
+        for (message, package) in cases {
+            let error = QueryError::parse(message, workspace);
+            assert_eq!(error.package.as_deref(), package, "{message}");
+            assert_eq!(error.message, message);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keep_going_query_returns_package_errors() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir()?;
+        let bazel = tmp.path().join("bazel");
+        fs::write(
+            &bazel,
+            "#!/bin/sh\n\
+             echo \"ERROR: $PWD/broken/BUILD:1:1: syntax error at 'x'\" >&2\n\
+             echo 'WARNING: --keep_going specified, ignoring errors' >&2\n\
+             exit 3\n",
+        )?;
+        fs::set_permissions(&bazel, fs::Permissions::from_mode(0o755))?;
+        let opts = BazelOptions {
+            workspace: tmp.path().canonicalize()?,
+            bazel_path: bazel,
+            keep_going: true,
+            ..Default::default()
+        };
+
+        let errors = stream_query(&opts, "//...", |_| {}).await?;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].package.as_deref(), Some("//broken"));
+        assert!(errors[0].message.ends_with("syntax error at 'x'"));
+
+        let strict = BazelOptions {
+            keep_going: false,
+            ..opts
+        };
+        assert!(stream_query(&strict, "//...", |_| {}).await.is_err());
+        Ok(())
+    }
 }
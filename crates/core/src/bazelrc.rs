@@ -0,0 +1,330 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Canonical form of a `.bazelrc` fragment: one line per command (`build`,
+/// `test:ci`, ...) in sorted order, holding its flags in the order given.
+/// Comments, blank lines, line continuations, quoting and spacing do not
+/// matter, and neither does how the lines of different commands interleave,
+/// so only changes to the effective flags change the result. Lines without a
+/// command (a bare `--copt=-O2`) are grouped under an empty command.
+pub fn normalize_bazelrc(text: &str) -> Result<String> {
+    let mut commands: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (number, line) in logical_lines(text) {
+        let words = split_words(&line).with_context(|| format!("on line {number}"))?;
+        let Some(first) = words.first() else {
+            continue;
+        };
+        let (command, flags) = if first.starts_with('-') {
+            (String::new(), &words[..])
+        } else {
+            (first.clone(), &words[1..])
+        };
+        commands
+            .entry(command)
+            .or_default()
+            .extend_from_slice(flags);
+    }
+    let mut normalized = String::new();
+    for (command, flags) in commands {
+        normalized.push_str(&command);
+        for flag in flags {
+            normalized.push('\0');
+            normalized.push_str(&flag);
+        }
+        normalized.push('\n');
+    }
+    Ok(normalized)
+}
+
+/// SHA-256 of the normalized flags in the `.bazelrc` fragment at `path`,
+/// with the files it imports inlined (see [`inline_imports`]).
+pub fn build_flags_digest(path: &Path, workspace: &Path) -> Result<Vec<u8>> {
+    let text = inline_imports(path, workspace)?;
+    let normalized = normalize_bazelrc(&text).with_context(|| format!("in {}", path.display()))?;
+    Ok(Sha256::digest(normalized.as_bytes()).to_vec())
+}
+
+/// The rc file at `path` with each `import` and `try-import` line replaced by
+/// the contents of the file it names, recursively, the way Bazel reads them;
+/// paths resolve as in [`imported_files`]. A missing `try-import` file is
+/// skipped, while a missing `import` file and an import cycle are errors.
+pub fn inline_imports(path: &Path, workspace: &Path) -> Result<String> {
+    let mut text = String::new();
+    inline_into(path, workspace, &mut vec![path.to_path_buf()], &mut text)?;
+    Ok(text)
+}
+
+fn inline_into(
+    path: &Path,
+    workspace: &Path,
+    stack: &mut Vec<PathBuf>,
+    out: &mut String,
+) -> Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read build flags {}", path.display()))?;
+    for (number, line) in logical_lines(&text) {
+        let words = split_words(&line)
+            .with_context(|| format!("on line {number} of {}", path.display()))?;
+        let import = match &words[..] {
+            [command, file] if command == "import" || command == "try-import" => {
+                Some((command == "try-import", resolve_import(file, workspace)))
+            }
+            _ => None,
+        };
+        let Some((optional, import)) = import else {
+            out.push_str(&line);
+            out.push('\n');
+            continue;
+        };
+        if stack.contains(&import) {
+            bail!(
+                "{} imports itself through line {number} of {}",
+                import.display(),
+                path.display()
+            );
+        }
+        if optional && !import.is_file() {
+            continue;
+        }
+        stack.push(import.clone());
+        inline_into(&import, workspace, stack, out)
+            .with_context(|| format!("imported on line {number} of {}", path.display()))?;
+        stack.pop();
+    }
+    Ok(())
+}
+
+/// The flags `text` gives to any of `commands`, in file order. Lines for a
+/// config (`build:ci`) are skipped, as they only apply with `--config`.
+pub fn command_flags(text: &str, commands: &[&str]) -> Result<Vec<String>> {
+    let mut flags = Vec::new();
+    for (number, line) in logical_lines(text) {
+        let words = split_words(&line).with_context(|| format!("on line {number}"))?;
+        if let Some((command, rest)) = words.split_first() {
+            if commands.contains(&command.as_str()) {
+                flags.extend_from_slice(rest);
+            }
+        }
+    }
+    Ok(flags)
+}
+
+/// Files named by the `import` and `try-import` lines of the rc file at
+/// `path`, and of the files they import in turn, with `%workspace%` standing
+/// for `workspace`; relative paths are relative to `workspace`, like Bazel's
+/// working directory. Files that do not exist are listed but not followed.
+pub(crate) fn imported_files(path: &Path, workspace: &Path) -> Result<Vec<PathBuf>> {
+    let mut imported = Vec::new();
+    let mut seen = HashSet::from([path.to_path_buf()]);
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(rc) = pending.pop() {
+        let Ok(text) = std::fs::read_to_string(&rc) else {
+            continue;
+        };
+        for import in imports(&text, workspace).with_context(|| format!("in {}", rc.display()))? {
+            if seen.insert(import.clone()) {
+                imported.push(import.clone());
+                pending.push(import);
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// The files imported by the lines of `text`, in order.
+fn imports(text: &str, workspace: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for (number, line) in logical_lines(text) {
+        let words = split_words(&line).with_context(|| format!("on line {number}"))?;
+        if let [command, file] = &words[..] {
+            if command == "import" || command == "try-import" {
+                files.push(resolve_import(file, workspace));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn resolve_import(file: &str, workspace: &Path) -> PathBuf {
+    match file.strip_prefix("%workspace%") {
+        Some(rest) => workspace.join(rest.trim_start_matches(['/', '\\'])),
+        None => workspace.join(file),
+    }
+}
+
+/// Lines with `\`-continuations joined and comments removed, numbered by the
+/// physical line they start on.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (index, raw) in text.lines().enumerate() {
+        let (start, mut line) = current.take().unwrap_or((index + 1, String::new()));
+        let continued = raw.ends_with('\\') && !raw.ends_with("\\\\");
+        line.push_str(if continued {
+            &raw[..raw.len() - 1]
+        } else {
+            raw
+        });
+        if continued {
+            current = Some((start, line));
+        } else {
+            lines.push((start, strip_comment(&line).to_string()));
+        }
+    }
+    if let Some((start, line)) = current {
+        lines.push((start, strip_comment(&line).to_string()));
+    }
+    lines
+}
+
+/// Drops a `#` comment that starts a line or follows whitespace outside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if previous.is_whitespace() => return &line[..index],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Splits a line into words the way Bazel reads rc files: on whitespace,
+/// with quotes grouping words and a backslash escaping the next character.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    word.get_or_insert_with(String::new).push(escaped);
+                }
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (Some(q), _) if q == c => quote = None,
+            (None, _) if c.is_whitespace() => words.extend(word.take()),
+            _ => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("unterminated quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_does_not_change_normalized_flags() -> Result<()> {
+        let original = "\
+# CI flags
+build --copt=-O2 --jobs=8
+test:ci --test_output=errors
+build --define='mode=ci'   # trailing comment
+";
+        let reformatted = "\
+test:ci   --test_output=errors
+
+build \\
+    --copt=-O2 --jobs=8
+build \"--define=mode=ci\"
+";
+        assert_eq!(
+            normalize_bazelrc(original)?,
+            normalize_bazelrc(reformatted)?
+        );
+        assert_eq!(
+            normalize_bazelrc(original)?,
+            "build\0--copt=-O2\0--jobs=8\0--define=mode=ci\ntest:ci\0--test_output=errors\n"
+        );
+
+        let new_copt = original.replace("-O2", "-O3");
+        assert_ne!(normalize_bazelrc(original)?, normalize_bazelrc(&new_copt)?);
+        let reordered = "build --jobs=8 --copt=-O2\n";
+        assert_ne!(
+            normalize_bazelrc("build --copt=-O2 --jobs=8\n")?,
+            normalize_bazelrc(reordered)?
+        );
+        assert_eq!(normalize_bazelrc("--copt=-g\n")?, "\0--copt=-g\n");
+        assert!(normalize_bazelrc("build --define='x\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn imported_flags_are_part_of_the_digest() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        std::fs::create_dir_all(workspace.join("tools"))?;
+        let rc = workspace.join("ci.bazelrc");
+        std::fs::write(
+            &rc,
+            "build --jobs=8\nimport %workspace%/tools/remote.bazelrc\ntry-import user.bazelrc\n",
+        )?;
+        std::fs::write(
+            workspace.join("tools/remote.bazelrc"),
+            "build --remote_cache=a\n",
+        )?;
+
+        let base = build_flags_digest(&rc, workspace)?;
+        assert_eq!(
+            inline_imports(&rc, workspace)?,
+            "build --jobs=8\nbuild --remote_cache=a\n"
+        );
+        std::fs::write(
+            workspace.join("tools/remote.bazelrc"),
+            "build --remote_cache=b\n",
+        )?;
+        assert_ne!(build_flags_digest(&rc, workspace)?, base);
+        std::fs::write(workspace.join("user.bazelrc"), "build --copt=-g\n")?;
+        assert!(inline_imports(&rc, workspace)?.ends_with("build --copt=-g\n"));
+
+        std::fs::remove_file(workspace.join("tools/remote.bazelrc"))?;
+        assert!(build_flags_digest(&rc, workspace).is_err());
+        std::fs::write(
+            workspace.join("tools/remote.bazelrc"),
+            "import %workspace%/ci.bazelrc\n",
+        )?;
+        let err = build_flags_digest(&rc, workspace).unwrap_err();
+        assert!(format!("{err:#}").contains("imports itself"));
+        Ok(())
+    }
+
+    #[test]
+    fn imports_are_followed_through_the_workspace() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        std::fs::create_dir_all(workspace.join("tools"))?;
+        std::fs::write(
+            workspace.join(".bazelrc"),
+            "import %workspace%/tools/ci.bazelrc\ntry-import %workspace%/user.bazelrc\n",
+        )?;
+        std::fs::write(
+            workspace.join("tools/ci.bazelrc"),
+            "build --jobs=8\nimport tools/remote.bazelrc\nimport %workspace%/.bazelrc\n",
+        )?;
+        assert_eq!(
+            imported_files(&workspace.join(".bazelrc"), workspace)?,
+            [
+                workspace.join("tools/ci.bazelrc"),
+                workspace.join("user.bazelrc"),
+                workspace.join("tools/remote.bazelrc"),
+            ]
+        );
+        Ok(())
+    }
+}
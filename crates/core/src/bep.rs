@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The parts of a Build Event Protocol event (`--build_event_json_file`)
+/// that carry output files.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildEvent {
+    #[serde(default)]
+    named_set_of_files: Option<NamedSetOfFiles>,
+}
+
+#[derive(Deserialize)]
+struct NamedSetOfFiles {
+    #[serde(default)]
+    files: Vec<BepFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BepFile {
+    /// Path below the configuration's output directory, e.g. `pkg/gen.txt`.
+    name: String,
+    #[serde(default)]
+    digest: Option<String>,
+    /// E.g. `["bazel-out", "k8-fastbuild", "bin"]`.
+    #[serde(default)]
+    path_prefix: Vec<String>,
+}
+
+/// Digests of the output files a `bazel build` reported in its BEP JSON
+/// file, keyed by their path below the output directory (see
+/// [`output_path`]). Files built in several configurations with different
+/// contents are left out, since their label alone cannot tell them apart.
+pub fn read_output_digests(path: &Path) -> Result<HashMap<String, String>> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open BEP file {}", path.display()))?;
+    let mut digests: HashMap<String, Option<String>> = HashMap::new();
+    let events = serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter();
+    for (index, event) in events.enumerate() {
+        let event: BuildEvent = event.with_context(|| {
+            format!(
+                "failed to parse build event {} of {}",
+                index + 1,
+                path.display()
+            )
+        })?;
+        for file in event
+            .named_set_of_files
+            .into_iter()
+            .flat_map(|set| set.files)
+        {
+            let Some(digest) = file.digest.filter(|digest| !digest.is_empty()) else {
+                continue;
+            };
+            // Only outputs of the bin and genfiles trees have labels.
+            if file
+                .path_prefix
+                .first()
+                .is_some_and(|dir| dir != "bazel-out")
+            {
+                continue;
+            }
+            digests
+                .entry(file.name)
+                .and_modify(|known| {
+                    if known.as_ref() != Some(&digest) {
+                        *known = None;
+                    }
+                })
+                .or_insert(Some(digest));
+        }
+    }
+    let ambiguous = digests.values().filter(|digest| digest.is_none()).count();
+    if ambiguous > 0 {
+        warn!(
+            count = ambiguous,
+            "ignoring BEP outputs built with different contents in several configurations"
+        );
+    }
+    let digests: HashMap<String, String> = digests
+        .into_iter()
+        .filter_map(|(name, digest)| Some((name, digest?)))
+        .collect();
+    info!(outputs = digests.len(), bep = %path.display(), "read output digests");
+    Ok(digests)
+}
+
+/// Where the output file `label` appears in BEP: `//pkg:gen.txt` is
+/// `pkg/gen.txt` and `@repo//pkg:gen.txt` is `external/repo/pkg/gen.txt`.
+pub fn output_path(label: &str) -> Option<String> {
+    let (repo, rest) = label.trim_start_matches('@').split_once("//")?;
+    let (package, name) = rest.split_once(':')?;
+    let mut path = if label.starts_with('@') && !repo.is_empty() {
+        format!("external/{repo}/")
+    } else {
+        String::new()
+    };
+    if !package.is_empty() {
+        path.push_str(package);
+        path.push('/');
+    }
+    path.push_str(name);
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_output_digests_from_build_events() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let bep = tmp.path().join("bep.json");
+        let file = |name: &str, config: &str, digest: &str| {
+            format!(
+                r#"{{"name": "{name}", "pathPrefix": ["bazel-out", "{config}", "bin"], "digest": "{digest}", "length": "3"}}"#
+            )
+        };
+        let events = [
+            r#"{"id": {"started": {}}, "started": {"uuid": "x"}}"#.to_string(),
+            format!(
+                r#"{{"id": {{"namedSet": {{"id": "0"}}}}, "namedSetOfFiles": {{"files": [{}, {}]}}}}"#,
+                file("pkg/gen.txt", "k8-fastbuild", "aaa"),
+                file("external/dep/lib.a", "k8-fastbuild", "bbb"),
+            ),
+            format!(
+                r#"{{"id": {{"namedSet": {{"id": "1"}}}}, "namedSetOfFiles": {{"files": [{}, {}, {{"name": "nodigest.txt"}}]}}}}"#,
+                file("pkg/gen.txt", "k8-opt", "aaa"),
+                file("pkg/multi.txt", "k8-opt", "ccc"),
+            ),
+            format!(
+                r#"{{"id": {{"namedSet": {{"id": "2"}}}}, "namedSetOfFiles": {{"files": [{}]}}}}"#,
+                file("pkg/multi.txt", "k8-fastbuild", "ddd"),
+            ),
+        ];
+        std::fs::write(&bep, events.join("\n"))?;
+
+        let digests = read_output_digests(&bep)?;
+        assert_eq!(
+            digests,
+            HashMap::from([
+                ("pkg/gen.txt".to_string(), "aaa".to_string()),
+                ("external/dep/lib.a".to_string(), "bbb".to_string()),
+            ])
+        );
+        assert_eq!(output_path("//pkg:gen.txt").as_deref(), Some("pkg/gen.txt"));
+        assert_eq!(output_path("//:top.txt").as_deref(), Some("top.txt"));
+        assert_eq!(
+            output_path("@@dep//:lib.a").as_deref(),
+            Some("external/dep/lib.a")
+        );
+
+        std::fs::write(&bep, "{not json")?;
+        assert!(read_output_digests(&bep).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use crate::git::recently_changed_paths;
+use crate::models::namespace_label;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::time::Instant;
+use tracing::warn;
+
+/// Commits scanned for recently changed packages.
+const RECENT_COMMITS: usize = 500;
+
+/// Which targets a time-budgeted run left out of its output, written as
+/// `meta.coverage`. Consumers should treat every target of an unhashed
+/// package as impacted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HashCoverage {
+    /// Every target was hashed before the budget ran out.
+    pub complete: bool,
+    pub hashed_targets: usize,
+    pub unhashed_targets: usize,
+    /// Packages with at least one target missing from the output, e.g.
+    /// `//pkg` or `@repo//pkg`; sorted.
+    pub unhashed_packages: Vec<String>,
+}
+
+impl HashCoverage {
+    pub(crate) fn new(hashed_targets: usize, unhashed: &[&str]) -> Self {
+        let packages: BTreeSet<&str> = unhashed.iter().map(|label| package_of(label)).collect();
+        Self {
+            complete: unhashed.is_empty(),
+            hashed_targets,
+            unhashed_targets: unhashed.len(),
+            unhashed_packages: packages.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    /// Adds the coverage of another workspace, namespacing its packages.
+    pub fn merge(&mut self, other: HashCoverage, namespace: Option<&str>) {
+        self.complete &= other.complete;
+        self.hashed_targets += other.hashed_targets;
+        self.unhashed_targets += other.unhashed_targets;
+        self.unhashed_packages.extend(
+            other
+                .unhashed_packages
+                .iter()
+                .map(|package| namespace_label(namespace, package)),
+        );
+        self.unhashed_packages.sort();
+    }
+}
+
+/// Package part of a label, e.g. `//pkg` for `//pkg:target`.
+pub fn package_of(label: &str) -> &str {
+    label.rsplit_once(':').map_or(label, |(package, _)| package)
+}
+
+/// When a time-budgeted run stops hashing, and the packages it hashes in
+/// order until then.
+#[derive(Debug, Clone)]
+pub(crate) struct HashBudget {
+    pub(crate) deadline: Instant,
+    pub(crate) packages: Vec<String>,
+}
+
+impl HashBudget {
+    pub(crate) fn expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+/// Orders `packages` so those whose files changed most recently in the git
+/// history of `workspace` come first, then the rest in label order. Outside a
+/// git checkout only label order is used.
+pub(crate) fn prioritize_packages(workspace: &Path, packages: BTreeSet<String>) -> Vec<String> {
+    let changed = recently_changed_paths(workspace, RECENT_COMMITS).unwrap_or_else(|err| {
+        warn!("cannot order packages by recent changes: {err:#}");
+        Vec::new()
+    });
+    order_packages(&changed, packages)
+}
+
+/// Moves the packages of `changed_paths` (workspace-relative, most recent
+/// first) to the front of `packages`. A path belongs to the package of its
+/// closest enclosing directory.
+fn order_packages(changed_paths: &[String], packages: BTreeSet<String>) -> Vec<String> {
+    let mut ordered = Vec::new();
+    let mut taken = HashSet::new();
+    for path in changed_paths {
+        let package = Path::new(path)
+            .ancestors()
+            .skip(1)
+            .map(|dir| format!("//{}", dir.to_string_lossy()))
+            .find(|package| packages.contains(package));
+        if let Some(package) = package {
+            if taken.insert(package.clone()) {
+                ordered.push(package);
+            }
+        }
+    }
+    ordered.extend(packages.into_iter().filter(|p| !taken.contains(p)));
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_recently_changed_packages_first() {
+        let packages: BTreeSet<String> = ["//", "//a", "//a/b", "//c", "@dep//x"]
+            .map(str::to_string)
+            .into();
+        let changed = ["a/b/c/file.txt", "README.md", "a/b/BUILD", "c/d.txt"].map(str::to_string);
+        assert_eq!(
+            order_packages(&changed, packages),
+            ["//a/b", "//", "//c", "//a", "@dep//x"]
+        );
+
+        let mut coverage = HashCoverage::new(3, &["//a/b:x", "//a/b:y", "@dep//x:z"]);
+        assert_eq!(coverage.unhashed_packages, ["//a/b", "@dep//x"]);
+        assert!(!coverage.complete);
+        coverage.merge(HashCoverage::new(2, &["//c:d"]), Some("ws2"));
+        assert_eq!(coverage.hashed_targets, 5);
+        assert_eq!(coverage.unhashed_targets, 4);
+        assert_eq!(coverage.unhashed_packages, ["//a/b", "@dep//x", "ws2!//c"]);
+    }
+}
@@ -0,0 +1,1306 @@
+//! Reads BUILD files without Bazel for `--noBazel`. A small parser for the
+//! subset of Starlark that BUILD files use turns every package of the main
+//! repository into the targets a query would return, so hashing can run where
+//! starting Bazel is too slow. Macros are not expanded, `select()` keeps every
+//! branch and only main repository labels are followed, so the graph, and the
+//! hashes made from it, are approximate.
+
+use crate::exclude::PackageExclusions;
+use anyhow::{Context, Result};
+use bazel_differrous_proto::build;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+const BUILD_FILE_NAMES: &[&str] = &["BUILD.bazel", "BUILD"];
+
+/// Attributes naming the files a rule generates.
+const OUTPUT_ATTRS: &[&str] = &["out", "outs"];
+
+/// Attributes whose strings are never dependencies.
+const NON_INPUT_ATTRS: &[&str] = &["name", "visibility", "out", "outs"];
+
+/// The targets of every package below `workspace`, leaving out the
+/// directories `exclusions` names.
+pub fn scan_workspace(
+    workspace: &Path,
+    exclusions: &PackageExclusions,
+) -> Result<Vec<build::Target>> {
+    let mut packages = BTreeMap::new();
+    find_packages(workspace, "", exclusions, &mut packages);
+    let package_dirs: BTreeSet<String> = packages.keys().cloned().collect();
+
+    let mut parsed = Vec::new();
+    for (package, build_file) in &packages {
+        let text = std::fs::read_to_string(workspace.join(build_file))
+            .with_context(|| format!("failed to read {build_file}"))?;
+        let context = PackageContext {
+            workspace,
+            package,
+            build_file,
+            package_dirs: &package_dirs,
+        };
+        parsed.push(parse_package(&context, &text));
+    }
+
+    let generated: BTreeSet<&str> = parsed
+        .iter()
+        .flat_map(|package| &package.rules)
+        .flat_map(|rule| std::iter::once(&rule.label).chain(&rule.outputs))
+        .map(String::as_str)
+        .collect();
+    let mut sources = BTreeSet::new();
+    let mut targets = Vec::new();
+    for package in &parsed {
+        for file in package.exports.iter().chain(&package.loads) {
+            if source_exists(workspace, file) {
+                sources.insert(file.clone());
+            }
+        }
+        for rule in &package.rules {
+            let mut inputs = BTreeSet::new();
+            for (name, value) in &rule.attrs {
+                if NON_INPUT_ATTRS.contains(&name.as_str()) {
+                    continue;
+                }
+                for string in value.strings() {
+                    let Some(label) = resolve_label(&package.name, string) else {
+                        continue;
+                    };
+                    if label == rule.label {
+                        continue;
+                    }
+                    if generated.contains(label.as_str()) {
+                        inputs.insert(label);
+                    } else if source_exists(workspace, &label) {
+                        sources.insert(label.clone());
+                        inputs.insert(label);
+                    }
+                }
+            }
+            // Macros and rules loaded from a .bzl file change with it.
+            for load in &package.loads {
+                if source_exists(workspace, load) {
+                    inputs.insert(load.clone());
+                }
+            }
+            targets.push(rule.to_target(inputs.into_iter().collect()));
+            for output in &rule.outputs {
+                targets.push(build::Target {
+                    r#type: build::target::Discriminator::GeneratedFile as i32,
+                    generated_file: Some(build::GeneratedFile {
+                        name: output.clone(),
+                        generating_rule: rule.label.clone(),
+                        location: Some(rule.location.clone()),
+                    }),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    let rules = targets.len();
+    for source in sources {
+        if generated.contains(source.as_str()) {
+            continue;
+        }
+        let location = format!("{}:1:1", label_path(&source).unwrap_or_default());
+        targets.push(build::Target {
+            r#type: build::target::Discriminator::SourceFile as i32,
+            source_file: Some(build::SourceFile {
+                name: source,
+                location: Some(location),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+    info!(
+        packages = packages.len(),
+        rules_and_outputs = rules,
+        sources = targets.len() - rules,
+        "read BUILD files without Bazel; the graph is approximate"
+    );
+    Ok(targets)
+}
+
+/// Maps each package below `dir` to its BUILD file, both workspace-relative.
+/// Hidden directories, Bazel's convenience symlinks and symlinked directories
+/// are not entered. A directory that cannot be listed, or whose name is not
+/// UTF-8 and so cannot be part of a label, is skipped with a warning.
+fn find_packages(
+    workspace: &Path,
+    dir: &str,
+    exclusions: &PackageExclusions,
+    packages: &mut BTreeMap<String, String>,
+) {
+    let absolute = workspace.join(dir);
+    if let Some(name) = BUILD_FILE_NAMES
+        .iter()
+        .find(|name| absolute.join(name).is_file())
+    {
+        packages.insert(dir.to_string(), join_relative(dir, name));
+    }
+    let entries = match std::fs::read_dir(&absolute) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(dir = %absolute.display(), "skipping a directory that cannot be listed: {err}");
+            return;
+        }
+    };
+    let mut children = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!(dir = %absolute.display(), "skipping an entry that cannot be read: {err}");
+                continue;
+            }
+        };
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            continue;
+        }
+        let Ok(name) = entry.file_name().into_string() else {
+            warn!(path = %entry.path().display(), "skipping a directory whose name is not UTF-8");
+            continue;
+        };
+        if name.starts_with('.') || (dir.is_empty() && name.starts_with("bazel-")) {
+            continue;
+        }
+        let child = join_relative(dir, &name);
+        if !exclusions.contains_path(&child) {
+            children.push(child);
+        }
+    }
+    children.sort();
+    for child in children {
+        find_packages(workspace, &child, exclusions, packages);
+    }
+}
+
+fn join_relative(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// `//pkg:name` for `string` as written in `package`: a label, or a target or
+/// file name relative to the package. External labels give `None`.
+fn resolve_label(package: &str, string: &str) -> Option<String> {
+    let string = string
+        .strip_prefix("@@")
+        .or_else(|| string.strip_prefix('@'))
+        .filter(|rest| rest.starts_with("//"))
+        .unwrap_or(string);
+    if string.is_empty() || string.starts_with('@') || string.contains(char::is_whitespace) {
+        return None;
+    }
+    let (target_package, name) = if let Some(rest) = string.strip_prefix("//") {
+        match rest.split_once(':') {
+            Some((target_package, name)) => (target_package, name),
+            None => (rest, rest.rsplit('/').next().unwrap_or(rest)),
+        }
+    } else {
+        (package, string.strip_prefix(':').unwrap_or(string))
+    };
+    let valid = |part: &str| part.split('/').all(|s| !s.is_empty() && s != "..");
+    if name.is_empty() || !valid(name) || (!target_package.is_empty() && !valid(target_package)) {
+        return None;
+    }
+    Some(format!("//{target_package}:{name}"))
+}
+
+/// The workspace-relative path of a main repository file label.
+fn label_path(label: &str) -> Option<String> {
+    let (package, name) = label.strip_prefix("//")?.split_once(':')?;
+    Some(join_relative(package, name))
+}
+
+fn source_exists(workspace: &Path, label: &str) -> bool {
+    label_path(label).is_some_and(|path| workspace.join(path).is_file())
+}
+
+struct PackageContext<'a> {
+    workspace: &'a Path,
+    /// Workspace-relative, `""` for the root package.
+    package: &'a str,
+    build_file: &'a str,
+    package_dirs: &'a BTreeSet<String>,
+}
+
+#[derive(Debug, Default)]
+struct ParsedPackage {
+    name: String,
+    rules: Vec<ParsedRule>,
+    /// Files named by `exports_files`.
+    exports: Vec<String>,
+    /// `.bzl` files named by `load`.
+    loads: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ParsedRule {
+    label: String,
+    rule_class: String,
+    /// `path:line:column` of the call.
+    location: String,
+    attrs: Vec<(String, Value)>,
+    outputs: Vec<String>,
+}
+
+impl ParsedRule {
+    fn to_target(&self, inputs: Vec<String>) -> build::Target {
+        let attribute = self
+            .attrs
+            .iter()
+            .filter_map(|(name, value)| value.to_attribute(name))
+            .collect();
+        build::Target {
+            r#type: build::target::Discriminator::Rule as i32,
+            rule: Some(build::Rule {
+                name: self.label.clone(),
+                rule_class: self.rule_class.clone(),
+                location: Some(self.location.clone()),
+                attribute,
+                rule_input: inputs,
+                rule_output: self.outputs.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// The value of an expression, as far as it can be evaluated without running
+/// Starlark; anything else is kept as its source text.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    None,
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Unknown(String),
+}
+
+impl Value {
+    /// Every string in the value, including dictionary keys, which may be
+    /// labels too.
+    fn strings(&self) -> Vec<&str> {
+        let mut strings = Vec::new();
+        self.collect_strings(&mut strings);
+        strings
+    }
+
+    fn collect_strings<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Self::Str(string) => out.push(string),
+            Self::List(items) => items.iter().for_each(|item| item.collect_strings(out)),
+            Self::Dict(entries) => entries.iter().for_each(|(key, value)| {
+                key.collect_strings(out);
+                value.collect_strings(out);
+            }),
+            _ => {}
+        }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Self::Str(string) => format!("{string:?}"),
+            Self::Int(value) => value.to_string(),
+            Self::Bool(true) => "True".to_string(),
+            Self::Bool(false) => "False".to_string(),
+            Self::None => "None".to_string(),
+            Self::List(items) => {
+                let items: Vec<String> = items.iter().map(Self::render).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Self::Dict(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key.render(), value.render()))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Self::Unknown(text) => text.clone(),
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Str(left), Self::Str(right)) => Self::Str(left + &right),
+            (Self::List(mut left), Self::List(right)) => {
+                left.extend(right);
+                Self::List(left)
+            }
+            (left, right) => Self::Unknown(format!("{} + {}", left.render(), right.render())),
+        }
+    }
+
+    fn to_attribute(&self, name: &str) -> Option<build::Attribute> {
+        use build::attribute::Discriminator;
+        let mut attribute = build::Attribute {
+            name: name.to_string(),
+            explicitly_specified: Some(true),
+            ..Default::default()
+        };
+        match self {
+            Self::None => return None,
+            Self::Str(string) => {
+                attribute.r#type = Discriminator::String as i32;
+                attribute.string_value = Some(string.clone());
+            }
+            Self::Int(value) => {
+                attribute.r#type = Discriminator::Integer as i32;
+                attribute.int_value = i32::try_from(*value).ok();
+                attribute.string_value = Some(value.to_string());
+            }
+            Self::Bool(value) => {
+                attribute.r#type = Discriminator::Boolean as i32;
+                attribute.int_value = Some(i32::from(*value));
+                attribute.boolean_value = Some(*value);
+            }
+            Self::List(items) if items.iter().all(|item| matches!(item, Self::Str(_))) => {
+                attribute.r#type = Discriminator::StringList as i32;
+                attribute.string_list_value =
+                    self.strings().into_iter().map(str::to_string).collect();
+            }
+            _ => {
+                attribute.r#type = Discriminator::String as i32;
+                attribute.string_value = Some(self.render());
+            }
+        }
+        Some(attribute)
+    }
+}
+
+fn parse_package(context: &PackageContext, text: &str) -> ParsedPackage {
+    let mut parser = Parser {
+        tokens: tokenize(text),
+        pos: 0,
+        vars: HashMap::new(),
+        context,
+    };
+    let mut package = ParsedPackage {
+        name: context.package.to_string(),
+        ..Default::default()
+    };
+    while let Some(statement) = parser.statement() {
+        match statement {
+            Statement::Load(label) => package.loads.extend(resolve_label(context.package, &label)),
+            Statement::Call {
+                function,
+                line,
+                column,
+                args,
+            } => package.add_call(context, &function, (line, column), args),
+        }
+    }
+    debug!(
+        package = context.package,
+        rules = package.rules.len(),
+        "parsed BUILD file"
+    );
+    package
+}
+
+impl ParsedPackage {
+    fn add_call(
+        &mut self,
+        context: &PackageContext,
+        function: &str,
+        (line, column): (usize, usize),
+        args: Vec<(Option<String>, Value)>,
+    ) {
+        let function = function.strip_prefix("native.").unwrap_or(function);
+        if function == "exports_files" {
+            if let Some((_, files)) = args.first() {
+                let files = files.strings().into_iter();
+                self.exports
+                    .extend(files.filter_map(|file| resolve_label(context.package, file)));
+            }
+            return;
+        }
+        let name = args.iter().find_map(|(name, value)| match (name, value) {
+            (Some(name), Value::Str(value)) if name == "name" => Some(value.clone()),
+            _ => None,
+        });
+        // `package()`, `licenses()` and macros without a name make no target.
+        let Some(label) = name.and_then(|name| resolve_label(context.package, &name)) else {
+            return;
+        };
+        let mut outputs = Vec::new();
+        let mut attrs = Vec::new();
+        for (index, (name, value)) in args.into_iter().enumerate() {
+            let name = name.unwrap_or_else(|| format!("${index}"));
+            if OUTPUT_ATTRS.contains(&name.as_str()) {
+                outputs.extend(
+                    value
+                        .strings()
+                        .into_iter()
+                        .filter_map(|output| resolve_label(context.package, output)),
+                );
+            }
+            attrs.push((name, value));
+        }
+        attrs.sort_by(|(left, _), (right, _)| left.cmp(right));
+        self.rules.push(ParsedRule {
+            label,
+            rule_class: function.to_string(),
+            location: format!("{}:{line}:{}", context.build_file, column + 1),
+            attrs,
+            outputs,
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Op(String),
+    Newline,
+}
+
+#[derive(Debug, Clone)]
+struct Tok {
+    token: Token,
+    line: usize,
+    column: usize,
+}
+
+/// Splits `text` into tokens. Line breaks inside brackets and escaped line
+/// breaks are dropped, so a `Newline` always ends a statement.
+fn tokenize(text: &str) -> Vec<Tok> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut line_start, mut depth) = (0, 1, 0, 0usize);
+    let push_newline = |tokens: &mut Vec<Tok>, line: usize| {
+        if tokens
+            .last()
+            .is_some_and(|tok: &Tok| tok.token != Token::Newline)
+        {
+            tokens.push(Tok {
+                token: Token::Newline,
+                line,
+                column: 0,
+            });
+        }
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i - line_start;
+        match c {
+            '\n' => {
+                if depth == 0 {
+                    push_newline(&mut tokens, line);
+                }
+                i += 1;
+                line += 1;
+                line_start = i;
+            }
+            '\\' if chars.get(i + 1) == Some(&'\n') => {
+                i += 2;
+                line += 1;
+                line_start = i;
+            }
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            c if c.is_whitespace() => i += 1,
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let token = match digits.parse() {
+                    Ok(value) => Token::Int(value),
+                    Err(_) => Token::Ident(digits),
+                };
+                tokens.push(Tok {
+                    token,
+                    line,
+                    column,
+                });
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let (start, start_line) = (i, line);
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let quote = chars.get(i).filter(|q| **q == '"' || **q == '\'');
+                let prefix = matches!(word.as_str(), "r" | "R" | "b" | "B" | "rb" | "br" | "Rb");
+                let token = match quote {
+                    Some(_) if prefix => {
+                        let raw = word.contains(['r', 'R']);
+                        let (string, end, lines) = read_string(&chars, i, raw);
+                        i = end;
+                        line += lines;
+                        if lines > 0 {
+                            line_start = chars[..i]
+                                .iter()
+                                .rposition(|c| *c == '\n')
+                                .map_or(line_start, |n| n + 1);
+                        }
+                        Token::Str(string)
+                    }
+                    _ => Token::Ident(word),
+                };
+                tokens.push(Tok {
+                    token,
+                    line: start_line,
+                    column,
+                });
+            }
+            '"' | '\'' => {
+                let start_line = line;
+                let (string, end, lines) = read_string(&chars, i, false);
+                i = end;
+                line += lines;
+                if lines > 0 {
+                    line_start = chars[..i]
+                        .iter()
+                        .rposition(|c| *c == '\n')
+                        .map_or(line_start, |n| n + 1);
+                }
+                tokens.push(Tok {
+                    token: Token::Str(string),
+                    line: start_line,
+                    column,
+                });
+            }
+            _ => {
+                let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+                let op = if ["==", "!=", "<=", ">=", "+=", "-=", "**", "->", "//"]
+                    .contains(&two.as_str())
+                {
+                    two
+                } else {
+                    c.to_string()
+                };
+                match c {
+                    '(' | '[' | '{' => depth += 1,
+                    ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                i += op.chars().count();
+                tokens.push(Tok {
+                    token: Token::Op(op),
+                    line,
+                    column,
+                });
+            }
+        }
+    }
+    push_newline(&mut tokens, line);
+    tokens
+}
+
+/// Reads the string literal whose quote is at `start`, returning its value,
+/// the index after it and how many line breaks it spans.
+fn read_string(chars: &[char], start: usize, raw: bool) -> (String, usize, usize) {
+    let quote = chars[start];
+    let triple = chars.get(start + 1) == Some(&quote) && chars.get(start + 2) == Some(&quote);
+    let mut i = start + if triple { 3 } else { 1 };
+    let mut value = String::new();
+    let mut lines = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == quote
+            && (!triple || (chars.get(i + 1) == Some(&quote) && chars.get(i + 2) == Some(&quote)))
+        {
+            return (value, i + if triple { 3 } else { 1 }, lines);
+        }
+        if c == '\n' {
+            if !triple {
+                break;
+            }
+            lines += 1;
+        }
+        if c == '\\' && i + 1 < chars.len() {
+            let next = chars[i + 1];
+            i += 2;
+            if raw {
+                value.push(c);
+                value.push(next);
+                continue;
+            }
+            match next {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                '\n' => lines += 1,
+                '\\' | '\'' | '"' => value.push(next),
+                _ => {
+                    value.push(c);
+                    value.push(next);
+                }
+            }
+            continue;
+        }
+        value.push(c);
+        i += 1;
+    }
+    (value, i, lines)
+}
+
+enum Statement {
+    Load(String),
+    Call {
+        function: String,
+        line: usize,
+        column: usize,
+        args: Vec<(Option<String>, Value)>,
+    },
+}
+
+struct Parser<'a> {
+    tokens: Vec<Tok>,
+    pos: usize,
+    /// Values of top-level assignments, for later references.
+    vars: HashMap<String, Value>,
+    context: &'a PackageContext<'a>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|tok| &tok.token)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset).map(|tok| &tok.token)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Token::Op(o)) if o == op)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let matched = self.is_op(op);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn skip_statement(&mut self) {
+        while let Some(token) = self.peek() {
+            let newline = *token == Token::Newline;
+            self.pos += 1;
+            if newline {
+                break;
+            }
+        }
+    }
+
+    /// The next load or call at the top level; assignments are recorded and
+    /// everything else (function definitions, conditionals) is skipped.
+    fn statement(&mut self) -> Option<Statement> {
+        loop {
+            let tok = self.tokens.get(self.pos)?.clone();
+            let Token::Ident(word) = tok.token else {
+                self.skip_statement();
+                continue;
+            };
+            if tok.column > 0 {
+                self.skip_statement();
+                continue;
+            }
+            if matches!(word.as_str(), "def" | "if" | "for" | "while") {
+                self.skip_statement();
+                while self.tokens.get(self.pos).is_some_and(|tok| tok.column > 0) {
+                    self.skip_statement();
+                }
+                continue;
+            }
+            let mut function = word.clone();
+            let mut offset = 1;
+            while matches!(self.peek_at(offset), Some(Token::Op(op)) if op == ".") {
+                match self.peek_at(offset + 1) {
+                    Some(Token::Ident(part)) => function = format!("{function}.{part}"),
+                    _ => break,
+                }
+                offset += 2;
+            }
+            match self.peek_at(offset) {
+                Some(Token::Op(op)) if op == "(" => {
+                    self.pos += offset + 1;
+                    let args = self.arguments();
+                    self.skip_statement();
+                    if function == "load" {
+                        if let Some((None, Value::Str(label))) = args.into_iter().next() {
+                            return Some(Statement::Load(label));
+                        }
+                        continue;
+                    }
+                    return Some(Statement::Call {
+                        function,
+                        line: tok.line,
+                        column: tok.column,
+                        args,
+                    });
+                }
+                Some(Token::Op(op)) if offset == 1 && (op == "=" || op == "+=") => {
+                    let extend = op == "+=";
+                    self.pos += 2;
+                    let value = self.expression();
+                    let value = match self.vars.remove(&word) {
+                        Some(previous) if extend => previous.add(value),
+                        _ => value,
+                    };
+                    self.vars.insert(word, value);
+                    self.skip_statement();
+                }
+                _ => self.skip_statement(),
+            }
+        }
+    }
+
+    /// Call arguments after the opening parenthesis, through the closing one.
+    fn arguments(&mut self) -> Vec<(Option<String>, Value)> {
+        let mut args = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::Newline) => break,
+                Some(Token::Op(op)) if op == ")" => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(Token::Op(op)) if op == "," => self.pos += 1,
+                Some(Token::Ident(name)) if matches!(self.peek_at(1), Some(Token::Op(op)) if op == "=") =>
+                {
+                    let name = name.clone();
+                    self.pos += 2;
+                    args.push((Some(name), self.expression()));
+                }
+                _ => args.push((None, self.expression())),
+            }
+        }
+        args
+    }
+
+    fn at_delimiter(&self) -> bool {
+        match self.peek() {
+            None | Some(Token::Newline) => true,
+            Some(Token::Op(op)) => matches!(op.as_str(), "," | ")" | "]" | "}" | ":"),
+            _ => false,
+        }
+    }
+
+    /// An expression up to the next delimiter. What can't be evaluated is
+    /// kept as its source text, so that editing it still changes the hash.
+    fn expression(&mut self) -> Value {
+        let start = self.pos;
+        match self.sum() {
+            Some(value) if self.at_delimiter() => value,
+            _ => {
+                self.pos = start;
+                let mut depth = 0usize;
+                while let Some(token) = self.peek() {
+                    match token {
+                        Token::Newline => break,
+                        Token::Op(op) if matches!(op.as_str(), "(" | "[" | "{") => depth += 1,
+                        Token::Op(op)
+                            if depth == 0 && matches!(op.as_str(), "," | ")" | "]" | "}" | ":") =>
+                        {
+                            break
+                        }
+                        Token::Op(op) if matches!(op.as_str(), ")" | "]" | "}") => depth -= 1,
+                        _ => {}
+                    }
+                    self.pos += 1;
+                }
+                Value::Unknown(self.source(start, self.pos))
+            }
+        }
+    }
+
+    fn source(&self, start: usize, end: usize) -> String {
+        let words: Vec<String> = self.tokens[start..end]
+            .iter()
+            .map(|tok| match &tok.token {
+                Token::Ident(word) | Token::Op(word) => word.clone(),
+                Token::Str(string) => format!("{string:?}"),
+                Token::Int(value) => value.to_string(),
+                Token::Newline => String::new(),
+            })
+            .collect();
+        words.join(" ")
+    }
+
+    fn sum(&mut self) -> Option<Value> {
+        let mut value = self.term()?;
+        while self.eat_op("+") {
+            value = value.add(self.term()?);
+        }
+        Some(value)
+    }
+
+    fn term(&mut self) -> Option<Value> {
+        let start = self.pos;
+        let value = match self.peek()?.clone() {
+            Token::Str(mut string) => {
+                self.pos += 1;
+                while let Some(Token::Str(next)) = self.peek() {
+                    string.push_str(next);
+                    self.pos += 1;
+                }
+                Value::Str(string)
+            }
+            Token::Int(value) => {
+                self.pos += 1;
+                Value::Int(value)
+            }
+            Token::Op(op) if op == "-" => {
+                self.pos += 1;
+                match self.peek()? {
+                    Token::Int(value) => {
+                        let value = -value;
+                        self.pos += 1;
+                        Value::Int(value)
+                    }
+                    _ => return None,
+                }
+            }
+            Token::Op(op) if op == "[" => {
+                self.pos += 1;
+                Value::List(self.items("]")?)
+            }
+            Token::Op(op) if op == "(" => {
+                self.pos += 1;
+                let mut items = self.items(")")?;
+                if items.len() == 1
+                    && !matches!(self.tokens[self.pos - 2].token, Token::Op(ref op) if op == ",")
+                {
+                    items.pop()?
+                } else {
+                    Value::List(items)
+                }
+            }
+            Token::Op(op) if op == "{" => {
+                self.pos += 1;
+                self.dict()?
+            }
+            Token::Ident(word) => {
+                self.pos += 1;
+                let mut name = word;
+                while self.is_op(".") {
+                    match self.peek_at(1) {
+                        Some(Token::Ident(part)) => {
+                            name = format!("{name}.{part}");
+                            self.pos += 2;
+                        }
+                        _ => return None,
+                    }
+                }
+                if self.eat_op("(") {
+                    let args = self.arguments();
+                    self.call(&name, args)
+                        .unwrap_or_else(|| Value::Unknown(self.source(start, self.pos)))
+                } else {
+                    match name.as_str() {
+                        "True" => Value::Bool(true),
+                        "False" => Value::Bool(false),
+                        "None" => Value::None,
+                        _ => self
+                            .vars
+                            .get(&name)
+                            .cloned()
+                            .unwrap_or(Value::Unknown(name)),
+                    }
+                }
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Comma-separated expressions through `close`; `None` for
+    /// comprehensions and slices.
+    fn items(&mut self, close: &str) -> Option<Vec<Value>> {
+        let mut items = Vec::new();
+        loop {
+            if self.eat_op(close) {
+                return Some(items);
+            }
+            let start = self.pos;
+            items.push(self.expression());
+            if self.tokens[start..self.pos]
+                .iter()
+                .any(|tok| tok.token == Token::Ident("for".to_string()))
+            {
+                return None;
+            }
+            if !self.eat_op(",") && !self.is_op(close) {
+                return None;
+            }
+        }
+    }
+
+    fn dict(&mut self) -> Option<Value> {
+        let mut entries = Vec::new();
+        loop {
+            if self.eat_op("}") {
+                return Some(Value::Dict(entries));
+            }
+            if self.eat_op(",") {
+                continue;
+            }
+            let key = self.expression();
+            if !self.eat_op(":") {
+                return None;
+            }
+            let value = self.expression();
+            entries.push((key, value));
+            if !self.is_op(",") && !self.is_op("}") {
+                return None;
+            }
+        }
+    }
+
+    /// Evaluates the calls BUILD files use to compute attribute values.
+    fn call(&self, function: &str, args: Vec<(Option<String>, Value)>) -> Option<Value> {
+        let function = function.strip_prefix("native.").unwrap_or(function);
+        let arg = |position: usize, keyword: &str| {
+            args.iter()
+                .find(|(name, _)| name.as_deref() == Some(keyword))
+                .or_else(|| args.iter().filter(|(name, _)| name.is_none()).nth(position))
+                .map(|(_, value)| value)
+        };
+        match function {
+            "glob" => {
+                let include = arg(0, "include")?.strings();
+                let exclude = arg(1, "exclude").map(Value::strings).unwrap_or_default();
+                let files = glob(self.context, &include, &exclude);
+                Some(Value::List(files.into_iter().map(Value::Str).collect()))
+            }
+            "select" => {
+                let Value::Dict(branches) = arg(0, "x")? else {
+                    return None;
+                };
+                let mut union = Vec::new();
+                for (_, value) in branches {
+                    match value {
+                        Value::List(items) => union.extend(items.iter().cloned()),
+                        value => union.push(value.clone()),
+                    }
+                }
+                Some(Value::List(union))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Files of the package matching `include` and none of `exclude`, relative to
+/// the package and sorted. Like Bazel's glob, subpackages are not entered.
+fn glob(context: &PackageContext, include: &[&str], exclude: &[&str]) -> Vec<String> {
+    let mut files = Vec::new();
+    package_files(context, context.package, &mut files);
+    let include: Vec<Vec<&str>> = include.iter().map(|p| p.split('/').collect()).collect();
+    let exclude: Vec<Vec<&str>> = exclude.iter().map(|p| p.split('/').collect()).collect();
+    let mut matched: Vec<String> = files
+        .into_iter()
+        .filter(|file| {
+            let segments: Vec<&str> = file.split('/').collect();
+            include
+                .iter()
+                .any(|pattern| path_matches(pattern, &segments))
+                && !exclude
+                    .iter()
+                    .any(|pattern| path_matches(pattern, &segments))
+        })
+        .collect();
+    matched.sort();
+    matched
+}
+
+/// Files below `dir`, relative to the package.
+fn package_files(context: &PackageContext, dir: &str, files: &mut Vec<String>) {
+    let Ok(entries) = std::fs::read_dir(context.workspace.join(dir)) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = join_relative(dir, &name);
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if !context.package_dirs.contains(&path) {
+                package_files(context, &path, files);
+            }
+        } else if !BUILD_FILE_NAMES.contains(&name.as_str()) || dir != context.package {
+            let relative = match context.package {
+                "" => path,
+                package => path[package.len() + 1..].to_string(),
+            };
+            files.push(relative);
+        }
+    }
+}
+
+fn path_matches(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            path_matches(&pattern[1..], path)
+                || (!path.is_empty() && path_matches(pattern, &path[1..]))
+        }
+        (Some(segment), Some(name)) => {
+            segment_matches(segment.as_bytes(), name.as_bytes())
+                && path_matches(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+fn segment_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            segment_matches(&pattern[1..], name)
+                || (!name.is_empty() && segment_matches(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => segment_matches(&pattern[1..], &name[1..]),
+        (Some(expected), Some(actual)) if expected == actual => {
+            segment_matches(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, path: &str, contents: &str) -> Result<()> {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().expect("path has a parent"))?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn rule<'a>(targets: &'a [build::Target], label: &str) -> &'a build::Rule {
+        targets
+            .iter()
+            .filter_map(|target| target.rule.as_ref())
+            .find(|rule| rule.name == label)
+            .unwrap_or_else(|| panic!("no rule {label}"))
+    }
+
+    fn attribute<'a>(rule: &'a build::Rule, name: &str) -> &'a build::Attribute {
+        rule.attribute
+            .iter()
+            .find(|attribute| attribute.name == name)
+            .unwrap_or_else(|| panic!("no attribute {name}"))
+    }
+
+    const PKG_BUILD: &str = r#"
+load("//tools:defs.bzl", "my_macro")
+
+# Inputs of the genrule.
+SRCS = ["a.txt"]
+
+genrule(
+    name = "gen",
+    srcs = SRCS + glob(
+        ["data/**/*.json"],
+        exclude = ["data/skip.json"],
+    ),
+    outs = ["gen.out"],
+    cmd = """cat $(SRCS) \
+        > $@""",
+)
+
+sh_test(
+    name = "t",
+    srcs = [":gen.out"],
+    data = select({
+        "//conditions:default": ["b.txt"],
+        ":opt": ["//other:c.txt"],
+    }),
+    deps = ["@rules_foo//:lib", "//other"],
+    size = "small",
+    flaky = True,
+    shard_count = 2,
+    env = {"KEY": "v" if True else "w"},
+)
+
+def helper():
+    native.genrule(name = "hidden")
+
+my_macro(name = "from_macro", srcs = [x for x in SRCS])
+
+exports_files(["a.txt", "missing.txt"])
+"#;
+
+    #[test]
+    fn build_files_become_an_approximate_graph() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let ws = tmp.path();
+        write(ws, "MODULE.bazel", "")?;
+        write(ws, "pkg/BUILD.bazel", PKG_BUILD)?;
+        for file in [
+            "pkg/a.txt",
+            "pkg/b.txt",
+            "pkg/data/x/1.json",
+            "pkg/data/skip.json",
+            "pkg/data/sub/2.json",
+            "other/c.txt",
+            "tools/defs.bzl",
+        ] {
+            write(ws, file, file)?;
+        }
+        write(ws, "pkg/data/sub/BUILD", "")?;
+        write(
+            ws,
+            "other/BUILD",
+            "filegroup(name = \"other\", srcs = [\"c.txt\"])\n",
+        )?;
+        write(ws, "tools/BUILD.bazel", "")?;
+        write(ws, "bazel-out/BUILD", "filegroup(name = \"x\")\n")?;
+        write(ws, ".git/BUILD", "filegroup(name = \"x\")\n")?;
+        write(ws, "vendor/BUILD", "filegroup(name = \"x\")\n")?;
+
+        let exclusions = PackageExclusions::load(ws, &["//vendor/...".to_string()])?;
+        let targets = scan_workspace(ws, &exclusions)?;
+        let rules: Vec<&str> = targets
+            .iter()
+            .filter_map(|target| target.rule.as_ref())
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(
+            rules,
+            ["//other:other", "//pkg:gen", "//pkg:t", "//pkg:from_macro"]
+        );
+
+        let gen = rule(&targets, "//pkg:gen");
+        assert_eq!(gen.rule_class, "genrule");
+        assert_eq!(gen.location.as_deref(), Some("pkg/BUILD.bazel:7:1"));
+        assert_eq!(
+            gen.rule_input,
+            ["//pkg:a.txt", "//pkg:data/x/1.json", "//tools:defs.bzl"]
+        );
+        assert_eq!(gen.rule_output, ["//pkg:gen.out"]);
+        assert_eq!(
+            attribute(gen, "cmd").string_value.as_deref(),
+            Some("cat $(SRCS)         > $@")
+        );
+        let generated = targets
+            .iter()
+            .filter_map(|target| target.generated_file.as_ref())
+            .find(|file| file.name == "//pkg:gen.out")
+            .expect("generated file");
+        assert_eq!(generated.generating_rule, "//pkg:gen");
+
+        let test = rule(&targets, "//pkg:t");
+        assert_eq!(
+            test.rule_input,
+            [
+                "//other:c.txt",
+                "//other:other",
+                "//pkg:b.txt",
+                "//pkg:gen.out",
+                "//tools:defs.bzl"
+            ]
+        );
+        assert_eq!(
+            attribute(test, "data").string_list_value,
+            ["b.txt", "//other:c.txt"]
+        );
+        assert_eq!(attribute(test, "flaky").boolean_value, Some(true));
+        assert_eq!(attribute(test, "shard_count").int_value, Some(2));
+        assert_eq!(
+            attribute(test, "env").string_value.as_deref(),
+            Some(r#"{"KEY": "v" if True else "w"}"#)
+        );
+
+        let from_macro = rule(&targets, "//pkg:from_macro");
+        assert_eq!(from_macro.rule_class, "my_macro");
+        assert_eq!(
+            attribute(from_macro, "srcs").string_value.as_deref(),
+            Some("[ x for x in SRCS ]")
+        );
+
+        let sources: Vec<&str> = targets
+            .iter()
+            .filter_map(|target| target.source_file.as_ref())
+            .map(|file| file.name.as_str())
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                "//other:c.txt",
+                "//pkg:a.txt",
+                "//pkg:b.txt",
+                "//pkg:data/x/1.json",
+                "//tools:defs.bzl"
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn directories_that_cannot_be_packages_are_skipped() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir()?;
+        let ws = tmp.path();
+        write(ws, "MODULE.bazel", "")?;
+        write(ws, "pkg/BUILD", "exports_files([\"a.txt\"])\n")?;
+        write(ws, "pkg/a.txt", "a")?;
+        let bad = ws.join(std::ffi::OsStr::from_bytes(b"bad\xff"));
+        std::fs::create_dir(&bad)?;
+        std::fs::write(bad.join("BUILD"), "")?;
+
+        let mut packages = BTreeMap::new();
+        find_packages(ws, "", &PackageExclusions::default(), &mut packages);
+        assert_eq!(
+            packages,
+            BTreeMap::from([("pkg".to_string(), "pkg/BUILD".to_string())])
+        );
+        find_packages(ws, "missing", &PackageExclusions::default(), &mut packages);
+        assert_eq!(packages.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn labels_resolve_relative_to_the_package() {
+        assert_eq!(
+            resolve_label("pkg", "a.txt").as_deref(),
+            Some("//pkg:a.txt")
+        );
+        assert_eq!(resolve_label("pkg", ":lib").as_deref(), Some("//pkg:lib"));
+        assert_eq!(resolve_label("", ":lib").as_deref(), Some("//:lib"));
+        assert_eq!(resolve_label("pkg", "//a/b").as_deref(), Some("//a/b:b"));
+        assert_eq!(resolve_label("pkg", "@//a:b").as_deref(), Some("//a:b"));
+        assert_eq!(resolve_label("pkg", "@@//a:b").as_deref(), Some("//a:b"));
+        assert_eq!(resolve_label("pkg", "@repo//a:b"), None);
+        assert_eq!(resolve_label("pkg", "../up.txt"), None);
+        assert_eq!(resolve_label("pkg", "echo hi"), None);
+    }
+
+    #[test]
+    fn glob_patterns_match_path_segments() {
+        let matches = |pattern: &str, path: &str| {
+            let pattern: Vec<&str> = pattern.split('/').collect();
+            let path: Vec<&str> = path.split('/').collect();
+            path_matches(&pattern, &path)
+        };
+        assert!(matches("*.rs", "lib.rs"));
+        assert!(!matches("*.rs", "src/lib.rs"));
+        assert!(matches("**/*.rs", "lib.rs"));
+        assert!(matches("**/*.rs", "src/a/lib.rs"));
+        assert!(matches("src/**", "src/a/b"));
+        assert!(matches("f?o.txt", "foo.txt"));
+        assert!(!matches("f?o.txt", "fo.txt"));
+    }
+}
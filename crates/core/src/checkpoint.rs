@@ -0,0 +1,592 @@
+use crate::lock::{write_atomically, FileLock, DEFAULT_LOCK_TIMEOUT};
+use crate::models::{parse_target_hashes, TargetHashes};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Number of records buffered between flushes; an interrupted run loses at
+/// most this many hashes.
+const FLUSH_EVERY: usize = 256;
+
+/// Prefix every NDJSON hash file starts with, used to tell it apart from the
+/// upstream JSON map without reading the whole file.
+const HEADER_PREFIX: &[u8] = b"{\"fingerprint\":";
+
+/// Where `generate-hashes` streams its NDJSON output, and an optional partial
+/// output from an interrupted run to reuse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    pub output: PathBuf,
+    pub resume_from: Option<PathBuf>,
+}
+
+/// First line of an NDJSON hash file. The fingerprint covers the query result
+/// and every hashing option, so records are only reused for an identical run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Header {
+    pub(crate) fingerprint: String,
+    /// Covers only the options source digests depend on, so a later run over
+    /// a different query result can still reuse them (`--previousHashes`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sources: Option<String>,
+}
+
+/// Files modified this close to their stat are not stamped: a write in the
+/// same timestamp tick could change them without changing their stamp.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Size, modification time and inode of a source file, taken before its
+/// digest was computed. A recorded digest is only reused while the file still
+/// has the same stamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceStamp {
+    pub size: u64,
+    pub mtime_nanos: u64,
+    pub inode: u64,
+}
+
+impl SourceStamp {
+    /// The stamp of the file at `path`; `None` if it cannot be stat'ed or was
+    /// modified too recently to be told apart from a later write.
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?;
+        let age = SystemTime::now().duration_since(modified).ok()?;
+        if age < RACY_WINDOW {
+            return None;
+        }
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Some(Self {
+            size: metadata.len(),
+            mtime_nanos: u64::try_from(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+                .ok()?,
+            inode,
+        })
+    }
+}
+
+/// One hashed target. `sourceDigest`, `sourceStamp` and `deps` carry the
+/// engine state needed to skip the target when resuming; `check` guards
+/// against torn or foreign lines.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HashRecord {
+    pub label: String,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_stamp: Option<SourceStamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deps: Option<Vec<String>>,
+    pub check: String,
+}
+
+impl HashRecord {
+    fn new(
+        fingerprint: &str,
+        label: &str,
+        hash: &str,
+        source_digest: Option<String>,
+        source_stamp: Option<SourceStamp>,
+        deps: Option<Vec<String>>,
+    ) -> Self {
+        let mut record = Self {
+            label: label.to_string(),
+            hash: hash.to_string(),
+            source_digest,
+            source_stamp,
+            deps,
+            check: String::new(),
+        };
+        record.check = record.checksum(fingerprint);
+        record
+    }
+
+    pub(crate) fn checksum(&self, fingerprint: &str) -> String {
+        let mut hasher = Sha256::new();
+        for part in [fingerprint, &self.label, &self.hash] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        if let Some(digest) = &self.source_digest {
+            hasher.update(digest.as_bytes());
+        }
+        hasher.update([0]);
+        for dep in self.deps.iter().flatten() {
+            hasher.update(dep.as_bytes());
+            hasher.update([0]);
+        }
+        // Only hashed when present, so records of older versions still verify.
+        if let Some(stamp) = &self.source_stamp {
+            for part in [stamp.size, stamp.mtime_nanos, stamp.inode] {
+                hasher.update(part.to_le_bytes());
+            }
+        }
+        hex::encode(&hasher.finalize()[..8])
+    }
+}
+
+/// Appends hash records to an NDJSON file as they are computed. The output is
+/// locked for the writer's lifetime so concurrent jobs sharing a path queue up
+/// instead of interleaving lines.
+pub struct CheckpointWriter {
+    writer: BufWriter<File>,
+    fingerprint: String,
+    pending: usize,
+    _lock: FileLock,
+}
+
+/// Fingerprints written to the header of an NDJSON hash file.
+#[derive(Debug, Clone, Copy)]
+pub struct Fingerprints<'a> {
+    /// The whole run; see [`Header::fingerprint`].
+    pub run: &'a str,
+    /// See [`Header::sources`].
+    pub sources: Option<&'a str>,
+}
+
+impl CheckpointWriter {
+    /// Starts a fresh NDJSON file at `path`.
+    #[cfg(test)]
+    pub fn create(path: &Path, fingerprints: Fingerprints) -> Result<Self> {
+        let lock = FileLock::exclusive(path, DEFAULT_LOCK_TIMEOUT)?;
+        Self::start(path, fingerprints, &HashMap::new(), lock)
+    }
+
+    /// Starts the NDJSON file for `config`, returning the resumable records of
+    /// `config.resume_from` alongside the writer. The reused records are copied
+    /// into the new file, which then atomically replaces the output; a retry
+    /// interrupted again therefore never loses records it had recovered.
+    pub fn open(
+        config: &CheckpointConfig,
+        fingerprints: Fingerprints,
+    ) -> Result<(Self, HashMap<String, HashRecord>)> {
+        let lock = FileLock::exclusive(&config.output, DEFAULT_LOCK_TIMEOUT)?;
+        let resumed = match &config.resume_from {
+            Some(path) if path.exists() => {
+                let _shared = (path != &config.output)
+                    .then(|| FileLock::shared(path, DEFAULT_LOCK_TIMEOUT))
+                    .transpose()?;
+                load_resumable(path, fingerprints.run)?
+            }
+            Some(path) => {
+                warn!(path = %path.display(), "resume file does not exist; hashing from scratch");
+                HashMap::new()
+            }
+            None => HashMap::new(),
+        };
+        let writer = Self::start(&config.output, fingerprints, &resumed, lock)?;
+        Ok((writer, resumed))
+    }
+
+    fn start(
+        path: &Path,
+        fingerprints: Fingerprints,
+        carried: &HashMap<String, HashRecord>,
+        lock: FileLock,
+    ) -> Result<Self> {
+        let header = Header {
+            fingerprint: fingerprints.run.to_string(),
+            sources: fingerprints.sources.map(str::to_string),
+        };
+        let file = write_atomically(path, |file| {
+            let mut out = BufWriter::new(file);
+            serde_json::to_writer(&mut out, &header)?;
+            out.write_all(b"\n")?;
+            for record in carried.values() {
+                serde_json::to_writer(&mut out, record)?;
+                out.write_all(b"\n")?;
+            }
+            Ok(out.flush()?)
+        })
+        .with_context(|| format!("failed to create NDJSON output {}", path.display()))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            fingerprint: fingerprints.run.to_string(),
+            pending: 0,
+            _lock: lock,
+        })
+    }
+
+    pub fn append(
+        &mut self,
+        label: &str,
+        hash: &str,
+        source_digest: Option<&[u8]>,
+        source_stamp: Option<SourceStamp>,
+        deps: Option<&[String]>,
+    ) -> Result<()> {
+        let record = HashRecord::new(
+            &self.fingerprint,
+            label,
+            hash,
+            source_digest.map(hex::encode),
+            source_stamp,
+            deps.map(<[String]>::to_vec),
+        );
+        self.write_line(&record)?;
+        self.pending += 1;
+        if self.pending >= FLUSH_EVERY {
+            self.pending = 0;
+            self.writer
+                .flush()
+                .context("failed to flush NDJSON output")?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush().context("failed to flush NDJSON output")
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, value).context("failed to write NDJSON line")?;
+        self.writer
+            .write_all(b"\n")
+            .context("failed to write NDJSON line")
+    }
+}
+
+/// Whether `path` looks like an NDJSON hash file written by [`CheckpointWriter`].
+pub fn is_ndjson_hashes(path: &Path) -> Result<bool> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open hashes file {}", path.display()))?;
+    let mut prefix = Vec::with_capacity(HEADER_PREFIX.len());
+    Read::take(file, HEADER_PREFIX.len() as u64).read_to_end(&mut prefix)?;
+    Ok(is_ndjson_data(&prefix))
+}
+
+/// Whether `data` starts like an NDJSON hash file.
+pub(crate) fn is_ndjson_data(data: &[u8]) -> bool {
+    data.starts_with(HEADER_PREFIX)
+}
+
+/// Records recovered from a (possibly partial) NDJSON hash file.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    pub fingerprint: String,
+    /// See [`Fingerprints::sources`]; absent in files of older versions.
+    pub sources_fingerprint: Option<String>,
+    pub records: HashMap<String, HashRecord>,
+    /// Lines that were torn, unparsable, or failed their checksum.
+    pub rejected: usize,
+}
+
+/// Reads an NDJSON hash file, keeping only lines whose checksum matches the
+/// header. A missing trailing newline marks a torn final line, which is dropped.
+pub fn read_checkpoint(path: &Path) -> Result<Checkpoint> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open NDJSON file {}", path.display()))?;
+    parse_checkpoint(BufReader::new(file), &path.display().to_string())
+}
+
+/// [`read_checkpoint`] for any reader; `source` names it in errors.
+fn parse_checkpoint<R: BufRead>(mut reader: R, source: &str) -> Result<Checkpoint> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header: Header = serde_json::from_str(line.trim_end())
+        .with_context(|| format!("{source} has no NDJSON hash header"))?;
+
+    let mut checkpoint = Checkpoint {
+        fingerprint: header.fingerprint,
+        sources_fingerprint: header.sources,
+        ..Default::default()
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        if !line.ends_with('\n') {
+            checkpoint.rejected += 1;
+            break;
+        }
+        let record = match serde_json::from_str::<HashRecord>(line.trim_end()) {
+            Ok(record) if record.check == record.checksum(&checkpoint.fingerprint) => record,
+            _ => {
+                checkpoint.rejected += 1;
+                continue;
+            }
+        };
+        checkpoint.records.insert(record.label.clone(), record);
+    }
+    Ok(checkpoint)
+}
+
+/// Loads the records of `path` that can be reused by a run with `fingerprint`.
+/// A checkpoint from a different run is ignored rather than treated as an error
+/// so that a retry always makes progress.
+pub fn load_resumable(path: &Path, fingerprint: &str) -> Result<HashMap<String, HashRecord>> {
+    let checkpoint = read_checkpoint(path)?;
+    if checkpoint.fingerprint != fingerprint {
+        warn!(
+            path = %path.display(),
+            "resume file was produced from a different query result or options; hashing from scratch"
+        );
+        return Ok(HashMap::new());
+    }
+    if checkpoint.rejected > 0 {
+        warn!(
+            path = %path.display(),
+            rejected = checkpoint.rejected,
+            "ignoring damaged lines in resume file"
+        );
+    }
+    Ok(checkpoint.records)
+}
+
+/// A source digest recorded in an NDJSON hash file, with the stamp the file
+/// had when it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRecord {
+    pub digest: Vec<u8>,
+    pub stamp: Option<SourceStamp>,
+}
+
+/// Source digests recorded in the NDJSON hash file of an earlier run, keyed
+/// by label, for a run whose source digests depend on the same options
+/// (`sources_fingerprint`). Files of other formats record no source digests
+/// and are rejected; a file made with other options yields nothing.
+pub fn load_previous_source_digests(
+    path: &Path,
+    sources_fingerprint: &str,
+) -> Result<HashMap<String, SourceRecord>> {
+    if !is_ndjson_hashes(path)? {
+        bail!(
+            "{} is not an NDJSON hash file; only --outputFormat ndjson records the source \
+             digests --previousHashes reuses",
+            path.display()
+        );
+    }
+    let checkpoint = read_checkpoint(path)?;
+    if checkpoint.sources_fingerprint.as_deref() != Some(sources_fingerprint) {
+        warn!(
+            path = %path.display(),
+            "previous hashes were made with other source hashing options; hashing every file"
+        );
+        return Ok(HashMap::new());
+    }
+    Ok(checkpoint
+        .records
+        .into_values()
+        .filter_map(|record| {
+            let digest = hex::decode(record.source_digest?).ok()?;
+            let stamp = record.source_stamp;
+            Some((record.label, SourceRecord { digest, stamp }))
+        })
+        .collect())
+}
+
+/// Label → hash entries of a complete NDJSON hash file in file order, read a
+/// line at a time. Damaged lines are an error, as in [`read_ndjson_hashes`].
+pub(crate) fn ndjson_entries(path: &Path) -> Result<NdjsonEntries> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open NDJSON file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header: Header = serde_json::from_str(line.trim_end())
+        .with_context(|| format!("{} has no NDJSON hash header", path.display()))?;
+    Ok(NdjsonEntries {
+        reader,
+        fingerprint: header.fingerprint,
+        source: path.display().to_string(),
+        line: 1,
+    })
+}
+
+/// See [`ndjson_entries`].
+pub(crate) struct NdjsonEntries {
+    reader: BufReader<File>,
+    fingerprint: String,
+    source: String,
+    line: usize,
+}
+
+impl Iterator for NdjsonEntries {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        self.line += 1;
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(err) => return Some(Err(err.into())),
+        }
+        let record = serde_json::from_str::<HashRecord>(line.trim_end())
+            .ok()
+            .filter(|record| {
+                line.ends_with('\n') && record.check == record.checksum(&self.fingerprint)
+            });
+        Some(match record {
+            Some(record) => Ok((record.label, record.hash)),
+            None => Err(anyhow::anyhow!(
+                "line {} of {} is damaged; was generate-hashes interrupted?",
+                self.line,
+                self.source
+            )),
+        })
+    }
+}
+
+/// Parses a complete NDJSON hash file into the label → hash map used by the
+/// impact pipeline. Unlike resuming, damaged lines are an error here.
+pub fn read_ndjson_hashes(path: &Path) -> Result<TargetHashes> {
+    checkpoint_hashes(read_checkpoint(path)?, &path.display().to_string())
+}
+
+/// [`read_ndjson_hashes`] for any reader; `source` names it in errors.
+pub(crate) fn parse_ndjson_hashes<R: BufRead>(reader: R, source: &str) -> Result<TargetHashes> {
+    checkpoint_hashes(parse_checkpoint(reader, source)?, source)
+}
+
+fn checkpoint_hashes(checkpoint: Checkpoint, source: &str) -> Result<TargetHashes> {
+    if checkpoint.rejected > 0 {
+        bail!(
+            "{source} has {} damaged line(s); was generate-hashes interrupted?",
+            checkpoint.rejected
+        );
+    }
+    let raw: BTreeMap<String, String> = checkpoint
+        .records
+        .into_values()
+        .map(|record| (record.label, record.hash))
+        .collect();
+    parse_target_hashes(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAMP: SourceStamp = SourceStamp {
+        size: 3,
+        mtime_nanos: 4,
+        inode: 5,
+    };
+
+    fn write_sample(path: &Path, fingerprint: &str) -> Result<()> {
+        let fingerprints = Fingerprints {
+            run: fingerprint,
+            sources: Some("sources"),
+        };
+        let mut writer = CheckpointWriter::create(path, fingerprints)?;
+        writer.append("//a:src", "aa~aa", Some(&[1, 2]), Some(STAMP), None)?;
+        writer.append(
+            "//a:lib",
+            "bb~cc",
+            None,
+            None,
+            Some(&["//a:dep".to_string()]),
+        )?;
+        writer.finish()
+    }
+
+    #[test]
+    fn round_trips_records() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        write_sample(&path, "fp")?;
+
+        let checkpoint = read_checkpoint(&path)?;
+        assert_eq!(checkpoint.fingerprint, "fp");
+        assert_eq!(checkpoint.rejected, 0);
+        assert_eq!(
+            checkpoint.records["//a:src"].source_digest.as_deref(),
+            Some("0102")
+        );
+        assert_eq!(checkpoint.records["//a:src"].source_stamp, Some(STAMP));
+        assert_eq!(
+            checkpoint.records["//a:lib"].deps,
+            Some(vec!["//a:dep".to_string()])
+        );
+
+        let hashes = read_ndjson_hashes(&path)?;
+        assert_eq!(hashes["//a:lib"].direct_hash(), Some("cc"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_torn_and_tampered_lines() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        write_sample(&path, "fp")?;
+        let content = std::fs::read_to_string(&path)?
+            .replace("bb~cc", "bb~dd")
+            .to_string()
+            + "{\"label\":\"//a:torn\"";
+        std::fs::write(&path, content)?;
+
+        let checkpoint = read_checkpoint(&path)?;
+        assert_eq!(checkpoint.rejected, 2);
+        assert_eq!(
+            checkpoint.records.keys().collect::<Vec<_>>(),
+            vec!["//a:src"]
+        );
+        assert!(is_ndjson_hashes(&path)?);
+        assert!(read_ndjson_hashes(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn source_stamps_are_checksummed() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        write_sample(&path, "fp")?;
+        let content = std::fs::read_to_string(&path)?.replace("\"inode\":5", "\"inode\":6");
+        std::fs::write(&path, content)?;
+
+        let checkpoint = read_checkpoint(&path)?;
+        assert_eq!(checkpoint.rejected, 1);
+        assert!(!checkpoint.records.contains_key("//a:src"));
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_fingerprint_is_not_resumed() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        write_sample(&path, "old")?;
+        assert!(load_resumable(&path, "new")?.is_empty());
+        assert_eq!(load_resumable(&path, "old")?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn previous_source_digests_need_matching_source_options() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        write_sample(&path, "old")?;
+        let digests = load_previous_source_digests(&path, "sources")?;
+        assert_eq!(
+            digests,
+            HashMap::from([(
+                "//a:src".to_string(),
+                SourceRecord {
+                    digest: vec![1, 2],
+                    stamp: Some(STAMP),
+                }
+            )])
+        );
+        assert!(load_previous_source_digests(&path, "other")?.is_empty());
+
+        let json = tmp.path().join("hashes.json");
+        std::fs::write(&json, r#"{"//a:src": "aa~aa"}"#)?;
+        let err = load_previous_source_digests(&json, "sources").unwrap_err();
+        assert!(err.to_string().contains("not an NDJSON hash file"));
+        Ok(())
+    }
+}
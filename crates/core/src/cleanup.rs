@@ -0,0 +1,203 @@
+use crate::lock::FileLock;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// Prefix of every temporary file this tool creates: query and Starlark files
+/// in the workspace and the staging files of atomic writes.
+pub const TEMP_PREFIX: &str = ".bazel-differrous-";
+
+/// Prefix of the temporary baseline worktrees in the baseline cache.
+const WORKTREE_PREFIX: &str = "worktree-";
+
+/// A temporary file in `dir` named with [`TEMP_PREFIX`]. It is removed when
+/// dropped, including while unwinding from a panic; files left behind by a
+/// killed process are removed by [`clean_temp_files`].
+pub fn temp_file_in(dir: &Path, suffix: &str) -> Result<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .suffix(suffix)
+        .tempfile_in(dir)
+        .with_context(|| format!("failed to create temporary file in {}", dir.display()))
+}
+
+/// Removes a file being written unless [`OutputGuard::keep`] is called, so an
+/// error or a panic while writing never leaves a truncated output behind.
+#[derive(Debug)]
+pub struct OutputGuard {
+    path: Option<PathBuf>,
+}
+
+impl OutputGuard {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    /// The output is complete; leave it in place.
+    pub fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for OutputGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            // The write already failed; a missing file is the goal either way.
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// What `clean` removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanReport {
+    /// Sorted.
+    pub removed: Vec<PathBuf>,
+    pub bytes: u64,
+    /// Artifacts another process is still using.
+    pub in_use: Vec<PathBuf>,
+}
+
+/// Removes the temporary files left directly in `dir` by interrupted runs.
+/// A run in progress in the same directory loses its files, so do not clean
+/// while one may be running.
+pub fn clean_temp_files(dir: &Path, report: &mut CleanReport) -> Result<()> {
+    for entry in read_dir_sorted(dir)? {
+        let is_temp = entry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(TEMP_PREFIX));
+        if is_temp && entry.is_file() {
+            let bytes = entry.metadata().map_or(0, |metadata| metadata.len());
+            std::fs::remove_file(&entry)
+                .with_context(|| format!("failed to remove {}", entry.display()))?;
+            report.bytes += bytes;
+            report.removed.push(entry);
+        }
+    }
+    report.removed.sort();
+    Ok(())
+}
+
+/// Removes the baseline worktrees left in the baseline cache `dir` by
+/// interrupted `--sinceTag` runs, skipping those whose baseline lock is held,
+/// and the cached baselines too when `baselines` is set. The repositories'
+/// worktree metadata is pruned on their next `--sinceTag` run.
+pub fn clean_baseline_cache(dir: &Path, baselines: bool, report: &mut CleanReport) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in read_dir_sorted(dir)? {
+        let Some(name) = entry
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        let guarded = match name.strip_prefix(WORKTREE_PREFIX) {
+            Some(entry_key) if entry.is_dir() => dir.join(format!("{entry_key}.json")),
+            _ if baselines && name.ends_with(".json") && entry.is_file() => entry.clone(),
+            _ => continue,
+        };
+        let Ok(_lock) = FileLock::exclusive(&guarded, Duration::ZERO) else {
+            report.in_use.push(entry);
+            continue;
+        };
+        let bytes = disk_usage(&entry);
+        let removed = if entry.is_dir() {
+            std::fs::remove_dir_all(&entry)
+        } else {
+            std::fs::remove_file(&entry)
+        };
+        removed.with_context(|| format!("failed to remove {}", entry.display()))?;
+        report.bytes += bytes;
+        report.removed.push(entry);
+    }
+    report.removed.sort();
+    Ok(())
+}
+
+pub(crate) fn read_dir_sorted(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to list {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("failed to list {}", dir.display()))?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Bytes of the files under `path`, not following symlinks.
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_guard_removes_unfinished_outputs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let (failed, kept) = (tmp.path().join("failed.json"), tmp.path().join("kept.json"));
+        for path in [&failed, &kept] {
+            std::fs::write(path, "{")?;
+        }
+        drop(OutputGuard::new(&failed));
+        OutputGuard::new(&kept).keep();
+        assert!(!failed.exists());
+        assert!(kept.is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn removes_stale_temp_files_and_unlocked_worktrees() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let live = temp_file_in(tmp.path(), ".query")?;
+        let stale = tmp.path().join(format!("{TEMP_PREFIX}abc.query"));
+        std::fs::write(&stale, "deps(//...)")?;
+        std::fs::write(tmp.path().join("hashes.json"), "{}")?;
+
+        let cache = tmp.path().join("cache");
+        std::fs::create_dir_all(cache.join("worktree-c1-k/pkg"))?;
+        std::fs::write(cache.join("worktree-c1-k/pkg/BUILD"), "x")?;
+        std::fs::create_dir_all(cache.join("worktree-c2-k"))?;
+        std::fs::write(cache.join("c1-k.json"), "{}")?;
+        let _held = FileLock::exclusive(&cache.join("c2-k.json"), Duration::ZERO)?;
+
+        let mut report = CleanReport::default();
+        clean_temp_files(tmp.path(), &mut report)?;
+        let mut expected = vec![live.path().to_path_buf(), stale];
+        expected.sort();
+        assert_eq!(report.removed, expected);
+        assert!(tmp.path().join("hashes.json").is_file());
+
+        let mut report = CleanReport::default();
+        clean_baseline_cache(&cache, false, &mut report)?;
+        assert_eq!(report.removed, [cache.join("worktree-c1-k")]);
+        assert_eq!(report.bytes, 1);
+        assert_eq!(report.in_use, [cache.join("worktree-c2-k")]);
+        assert!(cache.join("c1-k.json").is_file());
+
+        clean_baseline_cache(&cache, true, &mut report)?;
+        assert!(!cache.join("c1-k.json").exists());
+        Ok(())
+    }
+}
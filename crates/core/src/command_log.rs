@@ -0,0 +1,139 @@
+use crate::provenance::{redact_args, rfc3339_utc};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Where every Bazel command this process runs is recorded, if anywhere.
+static COMMAND_LOG: OnceLock<Box<dyn CommandLog>> = OnceLock::new();
+
+/// One Bazel command that ran to completion (or failed to start).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandRecord {
+    /// RFC 3339 UTC time the command was started.
+    pub started: String,
+    /// Program and arguments, with secret values redacted.
+    pub argv: Vec<String>,
+    pub cwd: Option<String>,
+    pub duration_ms: u64,
+    /// `None` when the command could not be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+/// Receives a [`CommandRecord`] for every Bazel command; installed once per
+/// process with [`set_command_log`].
+pub trait CommandLog: Send + Sync {
+    fn record(&self, record: &CommandRecord);
+}
+
+/// Appends each record to a file as a JSON line.
+#[derive(Debug)]
+pub struct JsonLinesCommandLog {
+    file: Mutex<File>,
+}
+
+impl JsonLinesCommandLog {
+    pub fn append_to(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open command log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl CommandLog for JsonLinesCommandLog {
+    fn record(&self, record: &CommandRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => return warn!(error = %err, "failed to encode command log record"),
+        };
+        line.push(b'\n');
+        // One write per line, so records of concurrent processes never interleave.
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(err) = file.write_all(&line) {
+            warn!(error = %err, "failed to write command log record");
+        }
+    }
+}
+
+/// Records every Bazel command this process runs from now on in `log`.
+pub fn set_command_log(log: Box<dyn CommandLog>) -> Result<()> {
+    if COMMAND_LOG.set(log).is_err() {
+        bail!("a command log is already installed");
+    }
+    Ok(())
+}
+
+/// Hands a record of `cmd`, started at `started` and `elapsed` long, to the
+/// installed command log.
+pub(crate) fn record_command(
+    cmd: &std::process::Command,
+    started: SystemTime,
+    elapsed: Duration,
+    status: Option<ExitStatus>,
+) {
+    let Some(log) = COMMAND_LOG.get() else {
+        return;
+    };
+    let argv: Vec<String> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    log.record(&CommandRecord {
+        started: rfc3339_utc(started),
+        argv: redact_args(&argv, &[]),
+        cwd: cmd
+            .get_current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned()),
+        duration_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        exit_code: status.and_then(|status| status.code()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_appended_as_redacted_json_lines() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("commands.jsonl");
+        std::fs::write(&path, "{\"earlier\":true}\n")?;
+        let log = JsonLinesCommandLog::append_to(&path)?;
+        let record = CommandRecord {
+            started: "2024-05-01T00:00:00Z".to_string(),
+            argv: redact_args(
+                &[
+                    "bazel".into(),
+                    "query".into(),
+                    "--remote_header=token=abc".into(),
+                ],
+                &[],
+            ),
+            cwd: Some("/ws".to_string()),
+            duration_ms: 12,
+            exit_code: Some(0),
+        };
+        log.record(&record);
+
+        let text = std::fs::read_to_string(&path)?;
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let logged: serde_json::Value = serde_json::from_str(lines[1])?;
+        assert_eq!(logged["argv"][2], "--remote_header=token=<redacted>");
+        assert_eq!(logged["cwd"], "/ws");
+        assert_eq!(logged["durationMs"], 12);
+        assert_eq!(logged["exitCode"], 0);
+        Ok(())
+    }
+}
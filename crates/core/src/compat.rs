@@ -0,0 +1,118 @@
+use bazel_differrous_proto::build::Attribute;
+use std::collections::HashSet;
+
+/// Constraint value Bazel uses to mark a target as never buildable.
+const ALWAYS_INCOMPATIBLE: &str = "platforms//:incompatible";
+
+/// Constraint values of a target platform, used to approximate cquery's
+/// incompatible-target skipping in query mode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlatformConstraints {
+    values: HashSet<String>,
+}
+
+impl PlatformConstraints {
+    /// `values` are constraint value labels such as `@platforms//os:linux`;
+    /// `@` and `@@` spellings of the same repository are equivalent, as are
+    /// a module's bzlmod canonical names (`@@platforms+`, `@@platforms~`).
+    pub fn new<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            values: values
+                .into_iter()
+                .map(|v| normalize_constraint(v.as_ref()))
+                .collect(),
+        }
+    }
+
+    /// Sorted constraint values, for fingerprints and diagnostics.
+    pub fn sorted(&self) -> Vec<&str> {
+        let mut values: Vec<&str> = self.values.iter().map(String::as_str).collect();
+        values.sort();
+        values
+    }
+
+    /// Best-effort check of a rule's `target_compatible_with`: the rule is
+    /// incompatible when it lists `@platforms//:incompatible` or any constraint
+    /// value the platform lacks. Values chosen by `select()` cannot be resolved
+    /// without configuration, so such rules are kept.
+    pub fn excludes(&self, attributes: &[Attribute]) -> bool {
+        let Some(attr) = attributes
+            .iter()
+            .find(|a| a.name == "target_compatible_with")
+        else {
+            return false;
+        };
+        if attr.selector_list.is_some() {
+            return false;
+        }
+        attr.string_list_value.iter().any(|value| {
+            let value = normalize_constraint(value);
+            value == ALWAYS_INCOMPATIBLE || !self.values.contains(&value)
+        })
+    }
+}
+
+/// Drops the `@`/`@@` prefix and the canonical suffix of a module's repository
+/// name (`platforms+`, `platforms~`, `platforms~0.0.10`). Repositories of
+/// module extensions (`rules_foo++ext+repo`) keep their names.
+fn normalize_constraint(label: &str) -> String {
+    let label = label.trim().trim_start_matches('@');
+    let Some((repo, rest)) = label.split_once("//") else {
+        return label.to_string();
+    };
+    let repo = match repo.split_once(['+', '~']) {
+        Some((module, version)) if !version.contains(['+', '~']) => module,
+        _ => repo,
+    };
+    format!("{repo}//{rest}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_differrous_proto::build::attribute::SelectorList;
+
+    fn compatible_with(values: &[&str]) -> Vec<Attribute> {
+        vec![Attribute {
+            name: "target_compatible_with".to_string(),
+            string_list_value: values.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn excludes_rules_requiring_missing_constraints() {
+        let linux = PlatformConstraints::new(["@platforms//os:linux", "@@platforms//cpu:x86_64"]);
+        assert!(!linux.excludes(&[]));
+        assert!(!linux.excludes(&compatible_with(&[])));
+        assert!(!linux.excludes(&compatible_with(&[
+            "@@platforms//os:linux",
+            "@platforms//cpu:x86_64"
+        ])));
+        assert!(linux.excludes(&compatible_with(&["@platforms//os:macos"])));
+        assert!(linux.excludes(&compatible_with(&["@platforms//:incompatible"])));
+
+        let mut selected = compatible_with(&["@platforms//os:macos"]);
+        selected[0].selector_list = Some(SelectorList::default());
+        assert!(!linux.excludes(&selected));
+    }
+
+    #[test]
+    fn bzlmod_canonical_repositories_match_their_apparent_names() {
+        let linux = PlatformConstraints::new(["@@platforms+//os:linux", "@platforms~//cpu:arm64"]);
+        assert_eq!(
+            linux.sorted(),
+            ["platforms//cpu:arm64", "platforms//os:linux"]
+        );
+        assert!(!linux.excludes(&compatible_with(&[
+            "@platforms//os:linux",
+            "@@platforms~0.0.10//cpu:arm64"
+        ])));
+        assert!(linux.excludes(&compatible_with(&["@@platforms+//:incompatible"])));
+        assert!(linux.excludes(&compatible_with(&["@@rules_foo++ext+platforms//os:linux"])));
+    }
+}
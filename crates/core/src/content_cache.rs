@@ -0,0 +1,282 @@
+use crate::lock::{write_atomically, CacheLock, DEFAULT_LOCK_TIMEOUT};
+use crate::state::StateDir;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Layout version of the cache file; files of other versions are discarded.
+const CACHE_VERSION: u32 = 1;
+
+/// Entries unused for this long are dropped when the cache is saved.
+const MAX_IDLE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often the last use of an entry is refreshed on disk.
+const TOUCH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Files modified this close to the start of a run are not cached: a write in
+/// the same timestamp tick could change them without changing their stat.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// `content-cache.msgpack` in the [`StateDir`] of `workspace`.
+pub fn default_content_cache_path(workspace: &Path) -> Option<PathBuf> {
+    StateDir::for_workspace(workspace).map(|state| state.content_cache_path())
+}
+
+/// What identifies an unchanged file: size, modification time and inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStat {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    inode: u64,
+}
+
+impl FileStat {
+    fn of(metadata: &Metadata) -> Option<(Self, SystemTime)> {
+        let modified = metadata.modified().ok()?;
+        let mtime = modified.duration_since(UNIX_EPOCH).ok()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        let stat = Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            inode,
+        };
+        Some((stat, modified))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    stat: FileStat,
+    digest: Vec<u8>,
+    /// Seconds since the Unix epoch.
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// A lookup in the cache: the file, its stat, and a digest of everything
+/// else its source digest depends on (label, seed and repository marker).
+#[derive(Debug)]
+pub(crate) struct ContentKey {
+    key: String,
+    stat: FileStat,
+    cacheable: bool,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    added: HashSet<String>,
+    used: HashSet<String>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Source file digests from earlier runs, stored in a MessagePack file and
+/// keyed by path and [`FileStat`], so files that did not change are not read
+/// again. Cheap to clone; clones share the entries.
+#[derive(Debug, Clone)]
+pub(crate) struct ContentCache {
+    /// `None` for a cache that only lives in memory.
+    path: Option<PathBuf>,
+    started: SystemTime,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ContentCache {
+    /// Loads the cache at `path`; a missing, corrupt or outdated file starts
+    /// an empty cache.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let entries = if path.is_file() {
+            let _lock = CacheLock::shared(path, DEFAULT_LOCK_TIMEOUT)?;
+            read_entries(path)
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            started: SystemTime::now(),
+            state: Arc::new(Mutex::new(CacheState {
+                entries,
+                ..Default::default()
+            })),
+        })
+    }
+
+    /// An empty cache that is never saved, shared by the runs of one process.
+    pub(crate) fn in_memory() -> Self {
+        Self {
+            path: None,
+            started: SystemTime::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// The key of `file` for a source digest depending on `context`, or
+    /// `None` when its modification time cannot be read.
+    pub(crate) fn key(file: &Path, metadata: &Metadata, context: &[u8]) -> Option<ContentKey> {
+        let (stat, modified) = FileStat::of(metadata)?;
+        let cacheable = modified + RACY_WINDOW <= SystemTime::now();
+        Some(ContentKey {
+            key: format!("{}\0{}", file.display(), hex::encode(context)),
+            stat,
+            cacheable,
+        })
+    }
+
+    /// The cached digest, if the file is unchanged since it was stored.
+    pub(crate) fn get(&self, key: &ContentKey) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().ok()?;
+        let digest = state
+            .entries
+            .get(&key.key)
+            .filter(|entry| entry.stat == key.stat)
+            .map(|entry| entry.digest.clone());
+        if digest.is_some() {
+            state.hits += 1;
+            state.used.insert(key.key.clone());
+        } else {
+            state.misses += 1;
+        }
+        digest
+    }
+
+    /// Stores the digest computed for a miss.
+    pub(crate) fn insert(&self, key: ContentKey, digest: &[u8]) {
+        if !key.cacheable {
+            return;
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.added.insert(key.key.clone());
+            state.entries.insert(
+                key.key,
+                CacheEntry {
+                    stat: key.stat,
+                    digest: digest.to_vec(),
+                    last_used: unix_secs(self.started),
+                },
+            );
+        }
+    }
+
+    /// Merges this run's entries into the file, which other processes may
+    /// have updated meanwhile, and drops idle entries. Skipped when the run
+    /// added nothing and only used recently refreshed entries.
+    pub(crate) fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        info!(
+            hits = state.hits,
+            misses = state.misses,
+            cache = %path.display(),
+            "content cache"
+        );
+        let now = unix_secs(self.started);
+        let stale = state.used.iter().any(|key| {
+            state
+                .entries
+                .get(key)
+                .is_some_and(|entry| entry.last_used + TOUCH_INTERVAL.as_secs() <= now)
+        });
+        if state.added.is_empty() && !stale {
+            return Ok(());
+        }
+
+        let _lock = CacheLock::exclusive(path, DEFAULT_LOCK_TIMEOUT)?;
+        let mut entries = read_entries(path);
+        for key in state.added.iter().chain(&state.used) {
+            if let Some(entry) = state.entries.get(key) {
+                let mut entry = entry.clone();
+                entry.last_used = now;
+                entries.insert(key.clone(), entry);
+            }
+        }
+        entries.retain(|_, entry| entry.last_used + MAX_IDLE.as_secs() > now);
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            entries,
+        };
+        write_atomically(path, |out| {
+            rmp_serde::encode::write(out, &file).context("failed to write content cache")
+        })?;
+        Ok(())
+    }
+}
+
+fn read_entries(path: &Path) -> HashMap<String, CacheEntry> {
+    let Ok(data) = std::fs::read(path) else {
+        return HashMap::new();
+    };
+    match rmp_serde::from_slice::<CacheFile>(&data) {
+        Ok(file) if file.version == CACHE_VERSION => file.entries,
+        Ok(_) => HashMap::new(),
+        Err(err) => {
+            warn!(cache = %path.display(), "discarding unreadable content cache: {err}");
+            HashMap::new()
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_digests_of_unchanged_files_across_runs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("src.txt");
+        std::fs::write(&file, "contents")?;
+        let old = SystemTime::now() - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&file)?
+            .set_modified(old)?;
+        let cache_path = tmp.path().join("cache/content.msgpack");
+
+        let cache = ContentCache::load(&cache_path)?;
+        let key = |context: &[u8]| {
+            ContentCache::key(&file, &std::fs::metadata(&file).unwrap(), context).unwrap()
+        };
+        assert_eq!(cache.get(&key(b"ctx")), None);
+        cache.insert(key(b"ctx"), b"digest");
+        cache.save()?;
+
+        let cache = ContentCache::load(&cache_path)?;
+        assert_eq!(cache.get(&key(b"ctx")), Some(b"digest".to_vec()));
+        assert_eq!(cache.get(&key(b"other label")), None);
+
+        std::fs::write(&file, "changed!")?;
+        assert_eq!(cache.get(&key(b"ctx")), None);
+        // Just modified: not stored, since the stat may not change again.
+        cache.insert(key(b"ctx"), b"new digest");
+        assert!(cache.state.lock().unwrap().added.is_empty());
+
+        std::fs::write(&cache_path, b"not msgpack")?;
+        assert_eq!(ContentCache::load(&cache_path)?.get(&key(b"ctx")), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Test selection from coverage data. A coverage map lists, for each test
+//! target, the workspace files it read when it last ran, e.g. collected with
+//! `bazel coverage` or file-access tracing. Tests covering a changed file are
+//! impacted even when the build graph does not link them to it, such as
+//! runtime data a test opens by path.
+
+use crate::budget::package_of;
+use crate::labels::validate_label;
+use crate::models::{ImpactedTargetDistance, ImpactedTargetsResult};
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Tests keyed by the files they cover.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageMap {
+    /// Tests covering each file.
+    files: BTreeMap<String, BTreeSet<String>>,
+    /// Tests covering every file below each directory, for entries ending in
+    /// `/`.
+    dirs: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl CoverageMap {
+    /// Reads a JSON object mapping test labels to the files they cover.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open coverage map {}", path.display()))?;
+        let tests: BTreeMap<String, Vec<String>> =
+            serde_json::from_reader(std::io::BufReader::new(file))
+                .with_context(|| format!("failed to parse coverage map {}", path.display()))?;
+        Self::from_tests(tests)
+    }
+
+    /// Builds the map from the files each test covers. A file is a path
+    /// relative to the workspace root or the label of a main repository
+    /// source file; a path ending in `/` covers everything below it.
+    pub fn from_tests(tests: BTreeMap<String, Vec<String>>) -> Result<Self> {
+        let mut map = Self::default();
+        for (test, files) in tests {
+            validate_label(&test)?;
+            for file in files {
+                let Some(path) = workspace_path(&file) else {
+                    bail!("{test} covers {file:?}, which is neither a workspace-relative path nor a main repository label");
+                };
+                let covered = match path.strip_suffix('/') {
+                    Some(dir) => map.dirs.entry(dir.to_string()),
+                    None => map.files.entry(path),
+                };
+                covered.or_default().insert(test.clone());
+            }
+        }
+        Ok(map)
+    }
+
+    /// Tests covering the file at workspace-relative `path`, sorted.
+    pub fn tests_covering(&self, path: &str) -> BTreeSet<&str> {
+        let dirs = path
+            .match_indices('/')
+            .filter_map(|(end, _)| self.dirs.get(&path[..end]));
+        self.files
+            .get(path)
+            .into_iter()
+            .chain(dirs)
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Adds the tests covering an impacted main repository file, or one of the
+/// workspace-relative `modified_paths`, to `result`, after the targets already
+/// impacted. Modified paths catch files no target lists, such as data a test
+/// opens by path. Tests `exists` rejects, e.g. ones missing from the final
+/// hashes because they were deleted since the coverage was collected, are
+/// left out. With distances, each added test is one target away from the
+/// file it covers.
+pub fn add_covering_tests(
+    mut result: ImpactedTargetsResult,
+    coverage: &CoverageMap,
+    modified_paths: &[String],
+    exists: impl Fn(&str) -> bool,
+) -> ImpactedTargetsResult {
+    let impacted: HashSet<&str> = result.impacted.iter().map(String::as_str).collect();
+    let mut changed_files: Vec<(String, String)> = result
+        .impacted
+        .iter()
+        .filter_map(|label| Some((workspace_path(label)?, package_of(label).to_string())))
+        .collect();
+    for path in modified_paths {
+        match workspace_path(path).filter(|path| !path.ends_with('/')) {
+            Some(path) => {
+                let package = format!("//{}", path.rsplit_once('/').map_or("", |(dir, _)| dir));
+                changed_files.push((path, package));
+            }
+            None => warn!(path, "skipping modified path outside the workspace"),
+        }
+    }
+    // Package distance of each added test to the nearest file it covers.
+    let mut added: BTreeMap<String, usize> = BTreeMap::new();
+    for (path, package) in &changed_files {
+        for test in coverage.tests_covering(path) {
+            if impacted.contains(test) || !exists(test) {
+                continue;
+            }
+            let package_distance = usize::from(package_of(test) != package.as_str());
+            added
+                .entry(test.to_string())
+                .and_modify(|distance| *distance = (*distance).min(package_distance))
+                .or_insert(package_distance);
+        }
+    }
+    info!(count = added.len(), "selected tests covering changed files");
+    if let Some(distances) = &mut result.distances {
+        distances.extend(
+            added
+                .iter()
+                .map(|(test, &package_distance)| ImpactedTargetDistance {
+                    label: test.clone(),
+                    target_distance: 1,
+                    package_distance,
+                    weighted_distance: None,
+                    generating_rule: None,
+                }),
+        );
+    }
+    result.impacted.extend(added.into_keys());
+    result
+}
+
+/// The workspace-relative path named by `file`: a main repository label, or
+/// a relative path. Directories keep their trailing `/`.
+fn workspace_path(file: &str) -> Option<String> {
+    let label = file
+        .strip_prefix("@@")
+        .or_else(|| file.strip_prefix('@'))
+        .unwrap_or(file);
+    if let Some(rest) = label.strip_prefix("//") {
+        let (package, name) = rest.split_once(':')?;
+        return Some(if package.is_empty() {
+            name.to_string()
+        } else {
+            format!("{package}/{name}")
+        });
+    }
+    if file.starts_with('@') || file.contains('!') {
+        return None;
+    }
+    let path = file.strip_prefix("./").unwrap_or(file);
+    let valid = !path.is_empty()
+        && !path.starts_with('/')
+        && path
+            .trim_end_matches('/')
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    valid.then(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage(entries: &[(&str, &[&str])]) -> Result<CoverageMap> {
+        CoverageMap::from_tests(
+            entries
+                .iter()
+                .map(|(test, files)| {
+                    (
+                        test.to_string(),
+                        files.iter().map(|f| f.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn tests_covering_files_and_directories_are_found() -> Result<()> {
+        let map = coverage(&[
+            ("//app:e2e_test", &["app/fixtures/", "./pkg/data.json"]),
+            ("//pkg:unit_test", &["//pkg:data.json", "//:root.txt"]),
+        ])?;
+        let covering = |path: &str| map.tests_covering(path).into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            covering("pkg/data.json"),
+            ["//app:e2e_test", "//pkg:unit_test"]
+        );
+        assert_eq!(covering("app/fixtures/a/b.txt"), ["//app:e2e_test"]);
+        assert_eq!(covering("root.txt"), ["//pkg:unit_test"]);
+        assert!(covering("app/fixtures").is_empty());
+        assert!(covering("app/other.txt").is_empty());
+
+        for bad in ["/etc/passwd", "../up.txt", "@repo//:file"] {
+            let err = coverage(&[("//pkg:unit_test", &[bad])]).unwrap_err();
+            assert!(err.to_string().contains("neither"), "{err}");
+        }
+        assert!(coverage(&[("not a label", &["a.txt"])]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn covering_tests_are_added_after_impacted_targets() -> Result<()> {
+        let map = coverage(&[
+            ("//app:e2e_test", &["pkg/data.json"]),
+            ("//pkg:unit_test", &["pkg/data.json", "pkg/other.json"]),
+            ("//pkg:lib_test", &["pkg/data.json"]),
+        ])?;
+        let distance = |label: &str, target_distance, package_distance| {
+            ImpactedTargetDistance::new(label, target_distance, package_distance)
+        };
+        let result = ImpactedTargetsResult {
+            impacted: vec!["//pkg:data.json".to_string(), "//pkg:lib_test".to_string()],
+            distances: Some(vec![
+                distance("//pkg:data.json", 0, 0),
+                distance("//pkg:lib_test", 1, 0),
+            ]),
+        };
+
+        let result = add_covering_tests(result, &map, &[], |_| true);
+        assert_eq!(
+            result.impacted,
+            [
+                "//pkg:data.json",
+                "//pkg:lib_test",
+                "//app:e2e_test",
+                "//pkg:unit_test"
+            ]
+        );
+        assert_eq!(
+            result.distances.expect("distances")[2..],
+            [
+                distance("//app:e2e_test", 1, 1),
+                distance("//pkg:unit_test", 1, 0)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn modified_paths_select_tests_that_still_exist() -> Result<()> {
+        let map = coverage(&[
+            ("//app:e2e_test", &["app/fixtures/"]),
+            ("//app:deleted_test", &["app/fixtures/"]),
+            ("//pkg:unit_test", &["pkg/data.json"]),
+        ])?;
+        let result = ImpactedTargetsResult {
+            impacted: vec![],
+            distances: Some(vec![]),
+        };
+        let modified = ["./app/fixtures/a.txt".to_string(), "/abs.txt".to_string()];
+        let result =
+            add_covering_tests(result, &map, &modified, |test| test != "//app:deleted_test");
+        assert_eq!(result.impacted, ["//app:e2e_test"]);
+        assert_eq!(
+            result.distances.expect("distances"),
+            [ImpactedTargetDistance::new("//app:e2e_test", 1, 1)]
+        );
+        Ok(())
+    }
+}
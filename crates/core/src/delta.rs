@@ -0,0 +1,130 @@
+use crate::models::{TargetHash, TargetHashes};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Hashes that changed relative to a previous hash file, written by
+/// `generate-hashes --deltaAgainst`. `baseDigest` identifies the hashes the
+/// delta applies to, so a chain of deltas cannot be applied out of order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HashesDelta {
+    /// [`hashes_digest`] of the hashes this delta was computed against.
+    pub base_digest: String,
+    /// Added labels and labels whose hash changed, with their new hash.
+    pub changed: BTreeMap<String, String>,
+    /// Labels present in the base but no longer produced.
+    pub removed: Vec<String>,
+}
+
+impl HashesDelta {
+    /// The delta turning `previous` into `current`.
+    pub fn between(previous: &TargetHashes, current: &BTreeMap<String, String>) -> Self {
+        let changed = current
+            .iter()
+            .filter(|(label, hash)| previous.get(*label).map(|p| p.raw()) != Some(hash.as_str()))
+            .map(|(label, hash)| (label.clone(), hash.clone()))
+            .collect();
+        let removed = previous
+            .keys()
+            .filter(|label| !current.contains_key(*label))
+            .cloned()
+            .collect();
+        Self {
+            base_digest: hashes_digest(previous),
+            changed,
+            removed,
+        }
+    }
+
+    /// Applies the delta in place; `hashes` must be the hashes it was computed
+    /// against.
+    pub fn apply(&self, hashes: &mut TargetHashes) -> Result<()> {
+        let digest = hashes_digest(hashes);
+        if digest != self.base_digest {
+            bail!(
+                "delta was generated against different hashes (base digest {}, have {digest}); \
+                 apply deltas in order on top of the baseline they were generated against",
+                self.base_digest
+            );
+        }
+        for label in &self.removed {
+            hashes.remove(label);
+        }
+        for (label, raw) in &self.changed {
+            let parsed =
+                TargetHash::parse(raw).with_context(|| format!("invalid hash for {label}"))?;
+            hashes.insert(label.clone(), parsed);
+        }
+        Ok(())
+    }
+}
+
+/// Content digest of a hash map, independent of the file format it came from.
+pub fn hashes_digest(hashes: &TargetHashes) -> String {
+    let mut hasher = Sha256::new();
+    for (label, hash) in hashes {
+        hasher.update(label.as_bytes());
+        hasher.update([0]);
+        hasher.update(hash.raw().as_bytes());
+        hasher.update([b'\n']);
+    }
+    hex::encode(hasher.finalize())
+}
+
+pub fn read_hashes_delta(path: &Path) -> Result<HashesDelta> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to open delta hashes file {}", path.display()))?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("failed to parse delta hashes from {}", path.display()))
+}
+
+/// Reads `base` and applies each delta in `deltas` in order.
+pub fn read_target_hashes_with_deltas<P: AsRef<Path>>(
+    base: &Path,
+    deltas: &[P],
+) -> Result<TargetHashes> {
+    let mut hashes = crate::models::read_target_hashes(base)?;
+    for delta in deltas {
+        let delta = delta.as_ref();
+        read_hashes_delta(delta)?
+            .apply(&mut hashes)
+            .map_err(|err| anyhow!("cannot apply {}: {err}", delta.display()))?;
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::parse_target_hashes;
+
+    fn raw(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(l, h)| (l.to_string(), h.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn chained_deltas_reproduce_the_final_hashes() -> Result<()> {
+        let day0 = raw(&[("//a:a", "1~1"), ("//b:b", "2~2")]);
+        let day1 = raw(&[("//a:a", "1~1"), ("//b:b", "3~3"), ("//c:c", "4~4")]);
+        let day2 = raw(&[("//b:b", "3~3"), ("//c:c", "5~5")]);
+
+        let first = HashesDelta::between(&parse_target_hashes(day0.clone())?, &day1);
+        assert_eq!(first.changed, raw(&[("//b:b", "3~3"), ("//c:c", "4~4")]));
+        assert!(first.removed.is_empty());
+        let second = HashesDelta::between(&parse_target_hashes(day1)?, &day2);
+        assert_eq!(second.removed, vec!["//a:a".to_string()]);
+
+        let mut hashes = parse_target_hashes(day0)?;
+        assert!(second.apply(&mut hashes.clone()).is_err());
+        first.apply(&mut hashes)?;
+        second.apply(&mut hashes)?;
+        assert_eq!(hashes, parse_target_hashes(day2)?);
+        Ok(())
+    }
+}
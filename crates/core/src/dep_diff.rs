@@ -0,0 +1,122 @@
+//! Dependency structure changes of impacted targets, for review tooling that
+//! needs to tell a target impacted because a dep was added or removed from one
+//! impacted because the contents of a dep it already had changed.
+
+use crate::models::DependencyEdges;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+
+/// How the direct deps of an impacted target changed between two dep edges
+/// files. A target with all three lists empty was impacted by its own inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DepEdgeChanges {
+    pub label: String,
+    /// Deps only in the final graph.
+    #[serde(rename = "addedDeps")]
+    pub added: Vec<String>,
+    /// Deps only in the starting graph.
+    #[serde(rename = "removedDeps")]
+    pub removed: Vec<String>,
+    /// Deps in both graphs that are impacted themselves.
+    #[serde(rename = "changedDeps")]
+    pub changed: Vec<String>,
+}
+
+/// The dep changes of each of `impacted`, in that order, between the `start`
+/// and `last` forward dep edges; each list is sorted.
+pub fn dep_edge_changes(
+    impacted: &[String],
+    start: &DependencyEdges,
+    last: &DependencyEdges,
+) -> Vec<DepEdgeChanges> {
+    let impacted_set: HashSet<&str> = impacted.iter().map(String::as_str).collect();
+    let deps = |edges: &'_ DependencyEdges, label: &str| -> BTreeSet<String> {
+        edges
+            .get(label)
+            .into_iter()
+            .flatten()
+            .filter(|dep| *dep != label)
+            .cloned()
+            .collect()
+    };
+    impacted
+        .iter()
+        .map(|label| {
+            let before = deps(start, label);
+            let after = deps(last, label);
+            DepEdgeChanges {
+                label: label.clone(),
+                added: after.difference(&before).cloned().collect(),
+                removed: before.difference(&after).cloned().collect(),
+                changed: before
+                    .intersection(&after)
+                    .filter(|dep| impacted_set.contains(dep.as_str()))
+                    .cloned()
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edges(entries: &[(&str, &[&str])]) -> DependencyEdges {
+        entries
+            .iter()
+            .map(|(label, deps)| {
+                (
+                    label.to_string(),
+                    deps.iter().map(|d| d.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn added_removed_and_changed_deps_are_told_apart() {
+        let start = edges(&[
+            ("//app:bin", &["//pkg:lib", "//pkg:old"]),
+            ("//pkg:lib", &["//pkg:lib.cc"]),
+        ]);
+        let last = edges(&[
+            ("//app:bin", &["//pkg:lib", "//pkg:new", "//app:bin"]),
+            ("//pkg:lib", &["//pkg:lib.cc"]),
+            ("//pkg:new", &[]),
+        ]);
+        let impacted: Vec<String> = ["//app:bin", "//pkg:lib", "//pkg:lib.cc", "//pkg:new"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+
+        let changes = dep_edge_changes(&impacted, &start, &last);
+        let entry = |label: &str, added: &[&str], removed: &[&str], changed: &[&str]| {
+            let list = |labels: &[&str]| labels.iter().map(|l| l.to_string()).collect();
+            DepEdgeChanges {
+                label: label.to_string(),
+                added: list(added),
+                removed: list(removed),
+                changed: list(changed),
+            }
+        };
+        assert_eq!(
+            changes,
+            [
+                entry("//app:bin", &["//pkg:new"], &["//pkg:old"], &["//pkg:lib"]),
+                entry("//pkg:lib", &[], &[], &["//pkg:lib.cc"]),
+                entry("//pkg:lib.cc", &[], &[], &[]),
+                entry("//pkg:new", &[], &[], &[]),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&changes[1]).expect("serializes"),
+            serde_json::json!({
+                "label": "//pkg:lib",
+                "addedDeps": [],
+                "removedDeps": [],
+                "changedDeps": ["//pkg:lib.cc"],
+            })
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::bazel::query_word;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// File at the workspace root listing directories Bazel does not look into.
+pub const BAZELIGNORE: &str = ".bazelignore";
+
+/// Main repository directories whose packages are left out of the query and
+/// whose files are never read, from `--excludePatterns` and `.bazelignore`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageExclusions {
+    /// Workspace-relative directories of the patterns, e.g.
+    /// `third_party/chromium`.
+    dirs: BTreeSet<String>,
+    /// Directories `.bazelignore` lists, which Bazel already leaves out of
+    /// `//...` (and rejects in patterns).
+    ignored: BTreeSet<String>,
+}
+
+impl PackageExclusions {
+    /// Parses `patterns` such as `//third_party/chromium/...` and adds the
+    /// directories `.bazelignore` in `workspace` lists, if there is one.
+    pub fn load(workspace: &Path, patterns: &[String]) -> Result<Self> {
+        let dirs = patterns
+            .iter()
+            .map(|pattern| parse_pattern(pattern))
+            .collect::<Result<_>>()?;
+        let mut ignored = BTreeSet::new();
+        let path = workspace.join(BAZELIGNORE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => ignored.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| line.trim_start_matches("./").trim_end_matches('/'))
+                    .filter(|dir| !dir.is_empty())
+                    .map(str::to_string),
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        }
+        Ok(Self { dirs, ignored })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty() && self.ignored.is_empty()
+    }
+
+    /// `expression` without the targets of the packages the patterns exclude.
+    pub fn subtract_from(&self, expression: &str) -> Result<String> {
+        if self.dirs.is_empty() {
+            return Ok(expression.to_string());
+        }
+        let excluded = self
+            .dirs
+            .iter()
+            .map(|dir| query_word(&format!("//{dir}/...:all-targets")))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!(
+            "({expression})\nexcept {}",
+            excluded.join("\nexcept ")
+        ))
+    }
+
+    /// Whether `label` is in an excluded package of the main repository.
+    pub fn contains_label(&self, label: &str) -> bool {
+        let Some(rest) = ["//", "@//", "@@//"]
+            .iter()
+            .find_map(|prefix| label.strip_prefix(prefix))
+        else {
+            return false;
+        };
+        let package = rest.split_once(':').map_or(rest, |(package, _)| package);
+        self.contains_path(package)
+    }
+
+    /// Whether the workspace-relative `path` is in an excluded directory.
+    pub fn contains_path(&self, path: &str) -> bool {
+        self.dirs.iter().chain(&self.ignored).any(|dir| {
+            path.strip_prefix(dir.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+/// The directory of an exclude pattern: `//dir/...`, `//dir` or `dir`.
+fn parse_pattern(pattern: &str) -> Result<String> {
+    if pattern.starts_with('@') || pattern.contains(':') {
+        bail!("invalid exclude pattern {pattern:?}: expected a main repository package such as //third_party/chromium/...");
+    }
+    let dir = pattern.trim_start_matches("//");
+    let dir = dir.strip_suffix("...").unwrap_or(dir).trim_end_matches('/');
+    if dir.is_empty() || dir.split('/').any(|part| part.is_empty() || part == "..") {
+        bail!(
+            "invalid exclude pattern {pattern:?}: it must name a package below the workspace root"
+        );
+    }
+    Ok(dir.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_and_bazelignore_exclude_packages() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(
+            tmp.path().join(BAZELIGNORE),
+            "# vendored\nnode_modules\n./out/\n\n",
+        )?;
+        let patterns = [
+            "//third_party/chromium/...".to_string(),
+            "tools/gen".to_string(),
+        ];
+        let exclusions = PackageExclusions::load(tmp.path(), &patterns)?;
+
+        assert!(exclusions.contains_label("//third_party/chromium:base"));
+        assert!(exclusions.contains_label("@//third_party/chromium/net:http"));
+        assert!(exclusions.contains_label("//node_modules/left-pad:index.js"));
+        assert!(exclusions.contains_path("out/gen/file.txt"));
+        assert!(exclusions.contains_path("tools/gen"));
+        assert!(!exclusions.contains_label("//third_party/chromium2:base"));
+        assert!(!exclusions.contains_label("@repo//third_party/chromium:base"));
+        assert!(!exclusions.contains_path("tools/generate.py"));
+
+        assert_eq!(
+            exclusions.subtract_from("//...:all-targets")?,
+            "(//...:all-targets)\nexcept //third_party/chromium/...:all-targets\n\
+             except //tools/gen/...:all-targets"
+        );
+        assert_eq!(
+            PackageExclusions::default().subtract_from("//...")?,
+            "//..."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn exclude_patterns_must_name_a_package() {
+        for pattern in ["//...", "//", "@repo//pkg/...", "//pkg:target", "//a/../b"] {
+            assert!(parse_pattern(pattern).is_err(), "{pattern}");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::models::{ImpactedTargetsResult, TargetHashes};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Target types of the labels in `hashes` generated with `--includeTargetType`.
+pub fn target_types(hashes: &TargetHashes) -> HashMap<String, String> {
+    hashes
+        .iter()
+        .filter_map(|(label, hash)| Some((label.clone(), hash.target_type()?.to_string())))
+        .collect()
+}
+
+/// A Starlark script deciding which impacted targets are written. It must
+/// define `keep(label, type, distance)` returning a bool; `type` is the target
+/// type from hashes generated with `--includeTargetType` and `distance` the
+/// target distance when dep edges are known, each `None` otherwise.
+///
+/// ```python
+/// def keep(label, type, distance):
+///     return not label.startswith("//experimental/") and (distance == None or distance <= 2)
+/// ```
+///
+/// Only available when built with the `starlark` feature.
+pub struct FilterScript {
+    #[cfg(feature = "starlark")]
+    keep: starlark::values::OwnedFrozenValue,
+}
+
+impl std::fmt::Debug for FilterScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterScript").finish_non_exhaustive()
+    }
+}
+
+impl FilterScript {
+    /// Parses and runs the script at `path` and looks up its `keep` function.
+    #[cfg(feature = "starlark")]
+    pub fn load(path: &Path) -> Result<Self> {
+        use anyhow::{bail, Context};
+        use starlark::environment::{Globals, Module};
+        use starlark::eval::Evaluator;
+        use starlark::syntax::{AstModule, Dialect};
+
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read filter script {}", path.display()))?;
+        let ast = AstModule::parse(&path.display().to_string(), source, &Dialect::Standard)
+            .map_err(starlark::Error::into_anyhow)?;
+        let module = Module::new();
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.eval_module(ast, &Globals::standard())
+                .map_err(starlark::Error::into_anyhow)?;
+        }
+        let keep = module
+            .freeze()?
+            .get("keep")
+            .with_context(|| format!("{} does not define keep()", path.display()))?;
+        if keep.value().get_type() != "function" {
+            bail!("keep in {} is not a function", path.display());
+        }
+        Ok(Self { keep })
+    }
+
+    #[cfg(not(feature = "starlark"))]
+    pub fn load(path: &Path) -> Result<Self> {
+        anyhow::bail!(
+            "cannot run {}: this build has no Starlark support; rebuild with `--features starlark`",
+            path.display()
+        )
+    }
+
+    /// Keeps the impacted targets `keep()` accepts. `target_types` maps labels
+    /// to their target type.
+    #[cfg(feature = "starlark")]
+    pub fn apply(
+        &self,
+        result: ImpactedTargetsResult,
+        target_types: &HashMap<String, String>,
+    ) -> Result<ImpactedTargetsResult> {
+        use crate::impact::with_impacted_labels;
+        use anyhow::{anyhow, Context};
+        use starlark::environment::Module;
+        use starlark::eval::Evaluator;
+        use starlark::values::Value;
+
+        let distances: HashMap<&str, usize> = result
+            .distances
+            .iter()
+            .flatten()
+            .map(|d| (d.label.as_str(), d.target_distance))
+            .collect();
+        let module = Module::new();
+        let heap = module.heap();
+        let mut eval = Evaluator::new(&module);
+        let mut kept = Vec::new();
+        for label in &result.impacted {
+            let target_type = target_types
+                .get(label)
+                .map_or_else(Value::new_none, |kind| heap.alloc(kind.as_str()));
+            let distance = distances
+                .get(label.as_str())
+                .map_or_else(Value::new_none, |d| heap.alloc(*d as i64));
+            let verdict = eval
+                .eval_function(
+                    self.keep.value(),
+                    &[heap.alloc(label.as_str()), target_type, distance],
+                    &[],
+                )
+                .map_err(starlark::Error::into_anyhow)
+                .with_context(|| format!("keep() failed for {label}"))?;
+            let keep = verdict
+                .unpack_bool()
+                .ok_or_else(|| anyhow!("keep() returned {verdict} for {label}, not a bool"))?;
+            if keep {
+                kept.push(label.clone());
+            }
+        }
+        Ok(with_impacted_labels(result, kept))
+    }
+
+    #[cfg(not(feature = "starlark"))]
+    pub fn apply(
+        &self,
+        result: ImpactedTargetsResult,
+        _target_types: &HashMap<String, String>,
+    ) -> Result<ImpactedTargetsResult> {
+        Ok(result)
+    }
+}
+
+#[cfg(all(test, feature = "starlark"))]
+mod tests {
+    use super::*;
+    use crate::models::ImpactedTargetDistance;
+
+    #[test]
+    fn keeps_targets_the_script_accepts() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let script = tmp.path().join("filter.star");
+        std::fs::write(
+            &script,
+            "def keep(label, type, distance):\n    \
+             return type != \"SourceFile\" and (distance == None or distance < 2)\n",
+        )?;
+        let filter = FilterScript::load(&script)?;
+        let distance =
+            |label: &str, target_distance| ImpactedTargetDistance::new(label, target_distance, 0);
+        let result = ImpactedTargetsResult {
+            impacted: ["//a:src.txt", "//a:lib", "//b:far", "//c:new"]
+                .map(str::to_string)
+                .into(),
+            distances: Some(vec![
+                distance("//a:src.txt", 0),
+                distance("//a:lib", 1),
+                distance("//b:far", 3),
+            ]),
+        };
+        let types = HashMap::from([("//a:src.txt".to_string(), "SourceFile".to_string())]);
+        let filtered = filter.apply(result, &types)?;
+        assert_eq!(filtered.impacted, ["//a:lib", "//c:new"]);
+        assert_eq!(filtered.distances.as_ref().map(Vec::len), Some(1));
+
+        std::fs::write(&script, "def keep(label, type, distance):\n    return 1\n")?;
+        let err = FilterScript::load(&script)?
+            .apply(filtered, &HashMap::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("not a bool"), "{err}");
+        std::fs::write(&script, "x = 1\n")?;
+        assert!(FilterScript::load(&script).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::warn;
+
+/// Resolves `rev` to a full commit SHA in the repository containing `workspace`.
+pub fn rev_parse(workspace: &Path, rev: &str) -> Result<String> {
+    let output = Command::new("git")
+        .arg("--no-optional-locks")
+        .arg("rev-parse")
+        .arg("--verify")
+        .arg(format!("{rev}^{{commit}}"))
+        .current_dir(workspace)
+        .output()
+        .context("failed to run git rev-parse")?;
+    if !output.status.success() {
+        bail!(
+            "git rev-parse {rev} failed in {}: {}",
+            workspace.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Path of `workspace` relative to the root of its repository, with a
+/// trailing `/`, or empty at the root.
+pub fn show_prefix(workspace: &Path) -> Result<String> {
+    let output = git(workspace, &["rev-parse", "--show-prefix"])?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+/// Paths changed by the last `max_commits` commits, relative to `workspace`
+/// and limited to it, most recently changed first and without repeats.
+pub fn recently_changed_paths(workspace: &Path, max_commits: usize) -> Result<Vec<String>> {
+    let max_count = format!("--max-count={max_commits}");
+    let output = git(
+        workspace,
+        &["log", &max_count, "--name-only", "--relative", "--format="],
+    )?;
+    let mut seen = std::collections::HashSet::new();
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter(|line| seen.insert(*line))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Files that differ between `base` and the working tree of `workspace`,
+/// relative to it and limited to it: committed and uncommitted changes,
+/// deleted files and both the old and new path of renames. Sorted.
+pub fn changed_paths(workspace: &Path, base: &str) -> Result<Vec<String>> {
+    let commit = rev_parse(workspace, base)?;
+    let output = git(
+        workspace,
+        &[
+            "diff",
+            "--name-only",
+            "-z",
+            "--no-renames",
+            "--relative",
+            &commit,
+            "--",
+        ],
+    )?;
+    let mut paths: Vec<String> = output
+        .split(|b| *b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| String::from_utf8_lossy(path).into_owned())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Files of the working tree of `workspace`, relative to it and limited to
+/// it: tracked files and untracked ones that are not ignored. Sorted.
+pub fn workspace_files(workspace: &Path) -> Result<Vec<String>> {
+    let output = git(
+        workspace,
+        &[
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ],
+    )?;
+    let mut paths: Vec<String> = output
+        .split(|b| *b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| String::from_utf8_lossy(path).into_owned())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// A detached checkout of a commit, created with `git worktree add` and
+/// removed again on drop.
+#[derive(Debug)]
+pub struct Worktree {
+    repository: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    /// Checks out `commit` of the repository containing `repository` at
+    /// `path`. A checkout left at `path` by an interrupted run is replaced.
+    pub fn add(repository: &Path, commit: &str, path: &Path) -> Result<Self> {
+        if path.exists() {
+            std::fs::remove_dir_all(path)
+                .with_context(|| format!("failed to remove stale worktree {}", path.display()))?;
+            git(repository, &["worktree", "prune"])?;
+        }
+        let target = path.to_string_lossy();
+        git(
+            repository,
+            &["worktree", "add", "--detach", "--quiet", &target, commit],
+        )?;
+        Ok(Self {
+            repository: repository.to_path_buf(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let target = self.path.to_string_lossy();
+        if let Err(err) = git(
+            &self.repository,
+            &["worktree", "remove", "--force", &target],
+        ) {
+            warn!(path = %self.path.display(), "failed to remove git worktree: {err:#}");
+        }
+    }
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    // Reads must not refresh the index of a workspace that may be read-only.
+    let output = Command::new("git")
+        .arg("--no-optional-locks")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .with_context(|| format!("failed to run git {}", args[0]))?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed in {}: {}",
+            args.join(" "),
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rev_parse_fails_outside_a_repository() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(rev_parse(tmp.path(), "HEAD").is_err());
+    }
+
+    #[test]
+    fn worktree_checks_out_a_commit_until_dropped() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path().join("repo");
+        std::fs::create_dir_all(repo.join("ws"))?;
+        std::fs::write(repo.join("ws/BUILD"), "v1")?;
+        for args in [
+            &["init", "--quiet"][..],
+            &["add", "-A"],
+            &[
+                "-c",
+                "user.name=t",
+                "-c",
+                "user.email=t@t",
+                "commit",
+                "--quiet",
+                "-m",
+                "v1",
+            ],
+            &["tag", "v1"],
+        ] {
+            git(&repo, args)?;
+        }
+        std::fs::write(repo.join("ws/BUILD"), "v2")?;
+
+        let commit = rev_parse(&repo.join("ws"), "v1")?;
+        assert_eq!(show_prefix(&repo.join("ws"))?, "ws/");
+        let checkout = tmp.path().join("checkout");
+        let worktree = Worktree::add(&repo.join("ws"), &commit, &checkout)?;
+        assert_eq!(
+            std::fs::read_to_string(worktree.path().join("ws/BUILD"))?,
+            "v1"
+        );
+        drop(worktree);
+        assert!(!checkout.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn changed_paths_include_renames_deletes_and_uncommitted_edits() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let ws = tmp.path().join("ws");
+        std::fs::create_dir_all(&ws)?;
+        for name in ["a.txt", "b.txt", "c.txt", "same.txt"] {
+            std::fs::write(ws.join(name), format!("{name} contents\n"))?;
+        }
+        std::fs::write(tmp.path().join("outside.txt"), "v1")?;
+        let commit = [
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "--quiet",
+            "-am",
+            "change",
+        ];
+        git(tmp.path(), &["init", "--quiet"])?;
+        git(tmp.path(), &["add", "-A"])?;
+        git(tmp.path(), &commit)?;
+        git(tmp.path(), &["tag", "base"])?;
+
+        git(tmp.path(), &["mv", "ws/b.txt", "ws/renamed.txt"])?;
+        git(tmp.path(), &["rm", "--quiet", "ws/c.txt"])?;
+        std::fs::write(tmp.path().join("outside.txt"), "v2")?;
+        git(tmp.path(), &commit)?;
+        std::fs::write(ws.join("a.txt"), "uncommitted")?;
+
+        assert_eq!(
+            changed_paths(&ws, "base")?,
+            ["a.txt", "b.txt", "c.txt", "renamed.txt"]
+        );
+        assert!(changed_paths(&ws, "no-such-ref").is_err());
+        Ok(())
+    }
+}
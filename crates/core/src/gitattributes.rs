@@ -0,0 +1,106 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Attributes that mark a file as generated or vendored rather than authored
+/// in the repository.
+pub const MARKER_ATTRIBUTES: &[&str] =
+    &["linguist-generated", "linguist-vendored", "export-ignore"];
+
+/// How source files marked by [`MARKER_ATTRIBUTES`] in `.gitattributes` are
+/// hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitAttributesMode {
+    /// Hash the label only, so content changes impact nothing but additions
+    /// and removals still do.
+    PathOnly,
+    /// Hash the label only and leave the files out of the output.
+    Skip,
+}
+
+/// The subset of `paths` (relative to `workspace`) that `.gitattributes`
+/// marks with one of [`MARKER_ATTRIBUTES`], as reported by `git check-attr`.
+pub fn marked_paths(workspace: &Path, paths: &[String]) -> Result<HashSet<String>> {
+    if paths.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let mut child = Command::new("git")
+        .args(["--no-optional-locks", "check-attr", "--stdin", "-z"])
+        .args(MARKER_ATTRIBUTES)
+        .current_dir(workspace)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run git check-attr")?;
+    let mut stdin = child.stdin.take().context("git check-attr has no stdin")?;
+    let input: Vec<u8> = paths
+        .iter()
+        .flat_map(|path| path.bytes().chain([0]))
+        .collect();
+    // Written from a thread so a full stdout pipe cannot deadlock the write.
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child
+        .wait_with_output()
+        .context("failed to run git check-attr")?;
+    let written = writer
+        .join()
+        .map_err(|_| anyhow!("git check-attr writer panicked"))?;
+    if !output.status.success() {
+        bail!(
+            "git check-attr failed in {}; reading .gitattributes requires a git checkout: {}",
+            workspace.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    written.context("failed to write paths to git check-attr")?;
+    Ok(parse_check_attr(&output.stdout))
+}
+
+/// Parses `git check-attr -z` output, `<path> NUL <attribute> NUL <info> NUL`
+/// per line, into the paths with a set or `true` attribute.
+fn parse_check_attr(output: &[u8]) -> HashSet<String> {
+    let fields: Vec<&[u8]> = output.split(|b| *b == 0).collect();
+    fields
+        .chunks_exact(3)
+        .filter(|record| matches!(record[2], b"set" | b"true"))
+        .map(|record| String::from_utf8_lossy(record[0]).into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_generated_and_vendored_markers() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let status = Command::new("git")
+            .args(["init", "--quiet"])
+            .current_dir(tmp.path())
+            .status()?;
+        assert!(status.success());
+        std::fs::write(
+            tmp.path().join(".gitattributes"),
+            "gen/** linguist-generated\n\
+             third_party/** linguist-vendored=true\n\
+             gen/keep.txt linguist-generated=false\n",
+        )?;
+
+        let paths = [
+            "gen/a.pb.go",
+            "gen/keep.txt",
+            "third_party/x.c",
+            "src/main.c",
+        ]
+        .map(str::to_string);
+        let marked = marked_paths(tmp.path(), &paths)?;
+        assert_eq!(
+            marked,
+            HashSet::from(["gen/a.pb.go".to_string(), "third_party/x.c".to_string()])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::impact::package_segment;
+use crate::models::ImpactedTargetsResult;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+/// How impacted targets are split into per-directory groups, parsed from
+/// `depth=N[,cap=M]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupingOptions {
+    /// Number of leading package path components forming a group's prefix.
+    pub depth: usize,
+    /// Maximum number of targets listed per group; the rest are only counted.
+    pub cap: Option<usize>,
+}
+
+impl FromStr for GroupingOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut depth = None;
+        let mut cap = None;
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .with_context(|| format!("expected key=value in {spec:?}, got {part:?}"))?;
+            let value: usize = value
+                .parse()
+                .with_context(|| format!("{key} must be a non-negative integer, got {value:?}"))?;
+            match key {
+                "depth" if value > 0 => depth = Some(value),
+                "depth" => bail!("depth must be at least 1"),
+                "cap" if value > 0 => cap = Some(value),
+                "cap" => bail!("cap must be at least 1"),
+                _ => bail!("unknown grouping option {key:?}; expected depth or cap"),
+            }
+        }
+        Ok(Self {
+            depth: depth.with_context(|| format!("missing depth=N in {spec:?}"))?,
+            cap,
+        })
+    }
+}
+
+/// Impacted targets under one package prefix, e.g. `//services/payments`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImpactedGroup {
+    pub prefix: String,
+    /// Number of impacted targets in the group, including any left out by the cap.
+    pub count: usize,
+    /// Listed targets; with distances, the closest to a change come first.
+    pub targets: Vec<String>,
+    /// Set when the cap left targets out, so callers can fall back to building
+    /// the whole prefix.
+    pub truncated: bool,
+}
+
+/// Groups impacted targets by the first `options.depth` components of their
+/// package path, keeping the repository and workspace namespace in the prefix.
+/// Groups are sorted by prefix.
+pub fn group_by_prefix(
+    result: &ImpactedTargetsResult,
+    options: &GroupingOptions,
+) -> Vec<ImpactedGroup> {
+    let distances: HashMap<&str, usize> = result
+        .distances
+        .iter()
+        .flatten()
+        .map(|d| (d.label.as_str(), d.target_distance))
+        .collect();
+
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for label in &result.impacted {
+        groups
+            .entry(label_prefix(label, options.depth))
+            .or_default()
+            .push(label);
+    }
+
+    groups
+        .into_iter()
+        .map(|(prefix, mut labels)| {
+            labels.sort_by_key(|label| (distances.get(label).copied().unwrap_or(0), *label));
+            let count = labels.len();
+            let keep = options.cap.unwrap_or(count).min(count);
+            ImpactedGroup {
+                prefix,
+                count,
+                targets: labels[..keep].iter().map(|l| l.to_string()).collect(),
+                truncated: keep < count,
+            }
+        })
+        .collect()
+}
+
+/// `@repo//a/b/c:t` at depth 2 is `@repo//a/b`; the root package is `//`.
+fn label_prefix(label: &str, depth: usize) -> String {
+    let package = package_segment(label);
+    let (repo, path) = package.split_once("//").unwrap_or(("", package));
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .take(depth)
+        .collect();
+    format!("{repo}//{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ImpactedTargetDistance;
+
+    #[test]
+    fn parses_grouping_specs() -> Result<()> {
+        assert_eq!(
+            "depth=2".parse::<GroupingOptions>()?,
+            GroupingOptions {
+                depth: 2,
+                cap: None
+            }
+        );
+        assert_eq!(
+            "depth=1, cap=10".parse::<GroupingOptions>()?,
+            GroupingOptions {
+                depth: 1,
+                cap: Some(10)
+            }
+        );
+        for bad in ["", "cap=3", "depth=0", "depth=x", "depth=1,size=2", "depth"] {
+            assert!(bad.parse::<GroupingOptions>().is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn groups_by_prefix_and_caps_closest_first() {
+        let distance =
+            |label: &str, target_distance| ImpactedTargetDistance::new(label, target_distance, 0);
+        let result = ImpactedTargetsResult {
+            impacted: vec![
+                "//:root".into(),
+                "//services/a/x:bin".into(),
+                "//services/a:lib".into(),
+                "//services/b:lib".into(),
+                "@ext//lib/deep:z".into(),
+                "tools!//cli:main".into(),
+            ],
+            distances: Some(vec![
+                distance("//services/a/x:bin", 0),
+                distance("//services/a:lib", 2),
+            ]),
+        };
+        let groups = group_by_prefix(
+            &result,
+            &GroupingOptions {
+                depth: 2,
+                cap: Some(1),
+            },
+        );
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|g| (g.prefix.as_str(), g.count, g.targets.clone(), g.truncated))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("//", 1, vec!["//:root".to_string()], false),
+                (
+                    "//services/a",
+                    2,
+                    vec!["//services/a/x:bin".to_string()],
+                    true
+                ),
+                (
+                    "//services/b",
+                    1,
+                    vec!["//services/b:lib".to_string()],
+                    false
+                ),
+                (
+                    "@ext//lib/deep",
+                    1,
+                    vec!["@ext//lib/deep:z".to_string()],
+                    false
+                ),
+                (
+                    "tools!//cli",
+                    1,
+                    vec!["tools!//cli:main".to_string()],
+                    false
+                ),
+            ]
+        );
+    }
+}
@@ -1,38 +1,212 @@
+use crate::audit::{FileAccessAudit, FileAccessRecorder};
 use crate::bazel::{
-    bazel_output_base, build_query_expression, run_cquery, run_query, BazelOptions,
+    build_query_expression, plan_cquery, plan_info, plan_query, preflight_info, query_word,
+    read_query_proto, run_cquery, run_query, stream_cquery, stream_query, warn_query_errors,
+    BazelOptions, PlannedCommand, QueryError, QueryOutputFormat, PREFLIGHT_INFO_KEYS,
 };
+use crate::bazelrc::build_flags_digest;
+use crate::budget::{package_of, prioritize_packages, HashBudget, HashCoverage};
+use crate::checkpoint::{
+    load_previous_source_digests, CheckpointConfig, CheckpointWriter, Fingerprints, HashRecord,
+    SourceRecord, SourceStamp,
+};
+use crate::command_log::record_command;
+use crate::compat::PlatformConstraints;
+use crate::content_cache::ContentCache;
+use crate::exclude::PackageExclusions;
+use crate::git::changed_paths;
+use crate::gitattributes::{marked_paths, GitAttributesMode};
+use crate::hash_function::{HashFunction, Hasher};
+use crate::labels::{validate_repo_name, CanonicalRepoName};
+use crate::models::{namespace_label, DependencyEdges, OutputMeta, TargetHash};
+use crate::normalize::{NormalizeOptions, SourceNormalizer};
+use crate::paths::{self, CaseSensitivity};
+use crate::profile::{HashProfile, HashProfiler};
+use crate::remote::RemoteCache;
+use crate::repo_mapping::{has_marker, RepoMapping};
+use crate::rewrite::{DefaultRuleInputRewriter, HybridRuleInputRewriter, RuleInputRewriter};
+use crate::stamps::{ConcurrentModificationMode, FileStampRecorder};
+use crate::warm::WarmCache;
+use crate::workspace::{check_workspace_root, HybridWorkspace};
+use crate::write_guard::WriteGuard;
 use anyhow::{anyhow, bail, Context, Result};
 use bazel_differrous_proto::build::{Attribute, Rule, Target};
 use hex::encode as hex_encode;
 use prost::Message;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use tracing::{debug, warn};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
 
 const DEFAULT_IGNORED_ATTRS: &[&str] = &["generator_location"];
 
+/// Bumped whenever the serialization behind
+/// [`GenerateHashesConfig::options_key`] changes.
+const OPTIONS_KEY_VERSION: &str = "1";
+
+/// Target name of the per-package pseudo-targets written with
+/// [`GenerateHashesConfig::build_file_targets`], e.g. `//pkg:__BUILD__`.
+pub const BUILD_FILE_TARGET_NAME: &str = "__BUILD__";
+
+/// BUILD file names in the order Bazel prefers them.
+const BUILD_FILE_NAMES: &[&str] = &["BUILD.bazel", "BUILD"];
+
 #[derive(Debug, Clone)]
 pub struct GenerateHashesConfig {
     pub workspace: PathBuf,
     pub include_target_type: bool,
     pub use_cquery: bool,
     pub keep_going: bool,
+    /// Fails the run when Bazel reports errors loading packages, which
+    /// `keep_going` otherwise turns into targets missing from the hashes.
+    pub fail_on_query_errors: bool,
     pub bazel_path: PathBuf,
     pub startup_options: Vec<String>,
     pub command_options: Vec<String>,
     pub cquery_options: Vec<String>,
     pub exclude_external_targets: bool,
+    /// Main repository packages such as `//third_party/chromium/...` left out
+    /// of the query, on top of the directories `.bazelignore` lists. Sources
+    /// under them are hashed by path only and their targets are not output.
+    pub exclude_patterns: Vec<String>,
     pub ignored_attrs: Vec<String>,
+    /// Hashes the attributes ignored by default (`generator_location`) too,
+    /// so moving a macro call impacts the targets it creates.
+    pub no_default_ignored_attrs: bool,
     pub fine_grained_external_repos: Vec<String>,
     pub fine_grained_external_repos_file: Option<PathBuf>,
     pub content_hash_path: Option<PathBuf>,
+    /// Build Event Protocol JSON file (`--build_event_json_file`) of an
+    /// earlier `bazel build`; the digests of the outputs it reports are mixed
+    /// into the hashes of the matching generated files.
+    pub bep_json_file: Option<PathBuf>,
+    /// Cache whose recorded output digests are mixed into the hashes of the
+    /// matching generated files, like [`bep_json_file`](Self::bep_json_file)'s;
+    /// the BEP file wins where both know an output. Only the actions of
+    /// [`execution_log`](Self::execution_log) are looked up.
+    pub remote_cache: Option<RemoteCache>,
+    /// Execution log (`--execution_log_json_file`) of the build whose action
+    /// results [`remote_cache`](Self::remote_cache) is asked for.
+    pub execution_log: Option<PathBuf>,
     pub seed_filepaths: Option<PathBuf>,
+    /// `.bazelrc` fragment with the flags CI builds use; its normalized flags
+    /// are mixed into the seed, so hashes change whenever the flags do.
+    pub seed_build_flags_file: Option<PathBuf>,
     pub modified_filepaths: Option<PathBuf>,
+    /// Adds the files that differ between this git ref and the working tree
+    /// to the modified filepaths, so only their contents are read.
+    pub git_diff_base: Option<String>,
     pub target_types: Option<Vec<String>>,
     pub track_dep_edges: bool,
+    /// When set, per-target timings are collected and the given number of
+    /// slowest rules/sources are reported in [`GenerateHashesResult::profile`].
+    pub profile_top_k: Option<usize>,
+    /// Streams hashes to an NDJSON file while hashing, optionally reusing the
+    /// records of an interrupted run.
+    pub checkpoint: Option<CheckpointConfig>,
+    /// Streamed proto query (or cquery) output to hash instead of running Bazel
+    /// query; `-` reads it from stdin.
+    pub query_proto_path: Option<PathBuf>,
+    /// Output format requested from `bazel query`; `StreamedJsonproto` falls
+    /// back to `StreamedProto` on Bazel versions that cannot write it.
+    pub query_output: QueryOutputFormat,
+    /// Passes `--nofetch` to every Bazel query so hashing never downloads
+    /// external repositories; a fine-grained repo that is not already present
+    /// under the output base is an error instead of an empty tree.
+    pub offline: bool,
+    /// Passed to Bazel as `--repository_cache` for every query.
+    pub repository_cache: Option<PathBuf>,
+    /// Rewrites cquery rule inputs; defaults to the upstream heuristics.
+    pub input_rewriter: Option<Arc<dyn RuleInputRewriter>>,
+    /// Query mode only: leaves out rules (and their generated files) whose
+    /// `target_compatible_with` these platform constraints cannot satisfy.
+    pub platform_constraints: Option<PlatformConstraints>,
+    /// Records the size and mtime of every source file when it is read and
+    /// re-checks them once hashing finishes.
+    pub detect_concurrent_modifications: Option<ConcurrentModificationMode>,
+    /// Adds a `//pkg:__BUILD__` entry of type `BuildFile` per main repository
+    /// package, hashing only its BUILD file, so BUILD edits can be read
+    /// straight from the hash output.
+    pub build_file_targets: bool,
+    /// Lists the main repository files no rule uses in
+    /// [`GenerateHashesResult::orphan_sources`]; off by default, as it lists
+    /// every file of the workspace.
+    pub orphan_sources: bool,
+    /// Source files whose whitespace or comment changes are ignored.
+    pub normalize: NormalizeOptions,
+    /// Mixes each source file's visibility (as set by `exports_files` or the
+    /// package default) into its digest, so visibility-only BUILD edits impact
+    /// the file and everything that depends on it.
+    pub hash_source_visibility: bool,
+    /// Algorithm behind every target and source digest.
+    pub hash_function: HashFunction,
+    /// Hashes main repository source files that `.gitattributes` marks as
+    /// generated or vendored by path only, optionally leaving them out of the
+    /// output.
+    pub gitattributes: Option<GitAttributesMode>,
+    /// Hashes the size of each source file instead of its contents, for quick
+    /// checks of whether the build graph changed.
+    pub structure_only: bool,
+    /// Mixes the `@<repo>.marker` file Bazel writes when fetching a
+    /// fine-grained external repository into the digest of each of its files,
+    /// so re-fetches with different inputs impact dependents.
+    pub hash_repo_markers: bool,
+    /// Records every file read while hashing sources in
+    /// [`GenerateHashesResult::file_access`].
+    pub audit_file_access: bool,
+    /// Threads used to hash files and rules; defaults to one per CPU.
+    pub jobs: Option<usize>,
+    /// Stores source file digests in this file, keyed by path, size,
+    /// modification time and inode, so later runs do not read unchanged files
+    /// again. The hashes are the same with and without it.
+    pub content_cache: Option<PathBuf>,
+    /// Stops reading source files once this much time has passed since the
+    /// run started, hashing recently changed packages first. Targets left
+    /// unhashed are reported in [`GenerateHashesResult::coverage`].
+    pub time_budget: Option<Duration>,
+    /// Reuses the query result and source digests of earlier runs of this
+    /// process that used the same cache; see [`WarmCache`].
+    pub warm_cache: Option<WarmCache>,
+    /// NDJSON hash file of an earlier run whose source digests are reused for
+    /// main repository files that did not change since. Changes are taken
+    /// from [`modified_filepaths`](Self::modified_filepaths) or
+    /// [`git_diff_base`](Self::git_diff_base) when given; otherwise a file
+    /// changed when its size, modification time or inode differ from the
+    /// stamp recorded with its digest.
+    pub previous_hashes: Option<PathBuf>,
+    /// Writes nothing inside the workspace: Bazel's query files go to the
+    /// system temporary directory, the content cache is neither read nor
+    /// saved, and a checkpoint inside the workspace is refused.
+    pub read_only: bool,
+    /// Builds the graph by parsing the BUILD files of the main repository
+    /// instead of querying Bazel; see [`crate::build_file`]. Macros are not
+    /// expanded and external repositories are left out, so the hashes are
+    /// only approximate.
+    pub no_bazel: bool,
+    /// What to do when rules depend on each other in a cycle.
+    pub on_cycle: CyclePolicy,
+}
+
+/// What to do when rules of the query result depend on each other in a cycle,
+/// which Bazel itself refuses to build but a query can still return.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CyclePolicy {
+    /// Fail the run, naming the cycle.
+    #[default]
+    Error,
+    /// Hash the edge closing each cycle by the dep's label only, as upstream
+    /// bazel-diff does, and report the cycles in
+    /// [`GenerateHashesResult::cycles`].
+    Break,
+    /// Like [`Break`](Self::Break), and also log each cycle as a warning.
+    Warn,
 }
 
 impl Default for GenerateHashesConfig {
@@ -42,20 +216,247 @@ impl Default for GenerateHashesConfig {
             include_target_type: false,
             use_cquery: false,
             keep_going: true,
+            fail_on_query_errors: false,
             bazel_path: PathBuf::new(),
             startup_options: Vec::new(),
             command_options: Vec::new(),
             cquery_options: Vec::new(),
             exclude_external_targets: false,
+            exclude_patterns: Vec::new(),
             ignored_attrs: Vec::new(),
+            no_default_ignored_attrs: false,
             fine_grained_external_repos: Vec::new(),
             fine_grained_external_repos_file: None,
             content_hash_path: None,
+            bep_json_file: None,
+            remote_cache: None,
+            execution_log: None,
             seed_filepaths: None,
+            seed_build_flags_file: None,
             modified_filepaths: None,
+            git_diff_base: None,
             target_types: None,
             track_dep_edges: false,
+            profile_top_k: None,
+            checkpoint: None,
+            query_proto_path: None,
+            query_output: QueryOutputFormat::default(),
+            offline: false,
+            repository_cache: None,
+            input_rewriter: None,
+            platform_constraints: None,
+            detect_concurrent_modifications: None,
+            build_file_targets: false,
+            orphan_sources: false,
+            normalize: NormalizeOptions::default(),
+            hash_source_visibility: false,
+            hash_function: HashFunction::Sha256,
+            gitattributes: None,
+            structure_only: false,
+            hash_repo_markers: false,
+            audit_file_access: false,
+            jobs: None,
+            content_cache: None,
+            time_budget: None,
+            warm_cache: None,
+            previous_hashes: None,
+            read_only: false,
+            no_bazel: false,
+            on_cycle: CyclePolicy::Error,
+        }
+    }
+}
+
+impl GenerateHashesConfig {
+    /// The directories [`read_only`](Self::read_only) keeps this run from
+    /// writing in.
+    pub fn write_guard(&self) -> WriteGuard {
+        if self.read_only {
+            WriteGuard::protecting([&self.workspace])
+        } else {
+            WriteGuard::default()
+        }
+    }
+
+    /// Rule attributes left out of rule digests: the ignored attributes plus,
+    /// unless [`no_default_ignored_attrs`](Self::no_default_ignored_attrs) is
+    /// set, the default ones; sorted.
+    pub fn effective_ignored_attrs(&self) -> Vec<String> {
+        let defaults = DEFAULT_IGNORED_ATTRS
+            .iter()
+            .filter(|_| !self.no_default_ignored_attrs);
+        let attrs: BTreeSet<String> = self
+            .ignored_attrs
+            .iter()
+            .map(|s| s.to_string())
+            .chain(defaults.map(|s| s.to_string()))
+            .collect();
+        attrs.into_iter().collect()
+    }
+
+    /// Identifies every option that affects the hashes but not where the
+    /// workspace is checked out, so hashes of one commit can be reused across
+    /// checkouts. Option files are keyed by their contents, not just their path.
+    /// Options that only change how the hashes are computed, such as the
+    /// number of jobs or the caches used, leave the key alone.
+    pub fn options_key(&self) -> Result<String> {
+        fn strs(values: &[String]) -> Vec<&str> {
+            values.iter().map(String::as_str).collect()
+        }
+
+        let mut hasher = Sha256::new();
+        let mut field = |name: &str, values: &[&str]| {
+            hasher.update(name);
+            for value in values {
+                hasher.update([0]);
+                hasher.update(value);
+            }
+            hasher.update([1]);
+        };
+        let flag = |value: bool| if value { "1" } else { "0" };
+        let sorted = |values: &[String]| {
+            let mut values = values.to_vec();
+            values.sort();
+            values.dedup();
+            values
+        };
+
+        field("version", &[env!("CARGO_PKG_VERSION"), OPTIONS_KEY_VERSION]);
+        field("include_target_type", &[flag(self.include_target_type)]);
+        field("use_cquery", &[flag(self.use_cquery)]);
+        field("keep_going", &[flag(self.keep_going)]);
+        field("startup_options", &strs(&self.startup_options));
+        field("command_options", &strs(&self.command_options));
+        field("cquery_options", &strs(&self.cquery_options));
+        field(
+            "exclude_external_targets",
+            &[flag(self.exclude_external_targets)],
+        );
+        field("exclude_patterns", &strs(&sorted(&self.exclude_patterns)));
+        field("ignored_attrs", &strs(&self.effective_ignored_attrs()));
+        field(
+            "fine_grained_external_repos",
+            &strs(&sorted(&self.fine_grained_external_repos)),
+        );
+        let remote_cache = match &self.remote_cache {
+            None => Vec::new(),
+            Some(RemoteCache::Disk(dir)) => {
+                vec!["disk".to_string(), dir.to_string_lossy().into_owned()]
+            }
+            Some(RemoteCache::Http(url)) => vec!["http".to_string(), url.clone()],
+            Some(RemoteCache::Grpc { url, instance_name }) => {
+                vec!["grpc".to_string(), url.clone(), instance_name.clone()]
+            }
+        };
+        field("remote_cache", &strs(&remote_cache));
+        field("git_diff_base", &strs(self.git_diff_base.as_slice()));
+        let target_types = match &self.target_types {
+            None => Vec::new(),
+            Some(types) => [String::from("set")]
+                .into_iter()
+                .chain(sorted(types))
+                .collect(),
+        };
+        field("target_types", &strs(&target_types));
+        field("track_dep_edges", &[flag(self.track_dep_edges)]);
+        let query_proto: Vec<String> = self
+            .query_proto_path
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        field("query_proto_path", &strs(&query_proto));
+        let rewriter = self.input_rewriter.as_ref().map(|r| r.fingerprint());
+        field("input_rewriter", &strs(rewriter.as_slice()));
+        let constraints = match &self.platform_constraints {
+            None => Vec::new(),
+            Some(constraints) => [String::from("set")]
+                .into_iter()
+                .chain(constraints.sorted().into_iter().map(String::from))
+                .collect(),
+        };
+        field("platform_constraints", &strs(&constraints));
+        field("build_file_targets", &[flag(self.build_file_targets)]);
+        field(
+            "normalize_whitespace",
+            &strs(&sorted(&self.normalize.whitespace)),
+        );
+        field(
+            "normalize_comments",
+            &strs(&sorted(&self.normalize.comments)),
+        );
+        field(
+            "hash_source_visibility",
+            &[flag(self.hash_source_visibility)],
+        );
+        field("hash_function", &[self.hash_function.as_str()]);
+        let gitattributes = match self.gitattributes {
+            None => "",
+            Some(GitAttributesMode::PathOnly) => "path_only",
+            Some(GitAttributesMode::Skip) => "skip",
+        };
+        field("gitattributes", &[gitattributes]);
+        field("structure_only", &[flag(self.structure_only)]);
+        field("hash_repo_markers", &[flag(self.hash_repo_markers)]);
+        let time_budget = self.time_budget.map(|b| b.as_millis().to_string());
+        field("time_budget", &strs(time_budget.as_slice()));
+        field("no_bazel", &[flag(self.no_bazel)]);
+        // Break and Warn only differ in what they log.
+        let on_cycle = match self.on_cycle {
+            CyclePolicy::Error => "error",
+            CyclePolicy::Break | CyclePolicy::Warn => "break",
+        };
+        field("on_cycle", &[on_cycle]);
+        // Read from where a run reads them, whatever the current directory.
+        let resolved = self.clone().resolve_option_files(&self.workspace);
+        let files = [
+            &resolved.fine_grained_external_repos_file,
+            &resolved.content_hash_path,
+            &resolved.bep_json_file,
+            &resolved.execution_log,
+            &resolved.seed_filepaths,
+            &resolved.seed_build_flags_file,
+            &resolved.modified_filepaths,
+        ];
+        for path in files.into_iter().flatten() {
+            let data = std::fs::read(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            hasher.update([1]);
+            hasher.update(data);
+        }
+        Ok(hex_encode(&hasher.finalize()[..16]))
+    }
+
+    /// This config with the option files read relative to the workspace made
+    /// absolute, so that it hashes another checkout (such as a worktree of an
+    /// older commit) with the option files of this one, which
+    /// [`options_key`](Self::options_key) is keyed by.
+    pub fn with_absolute_option_files(self) -> Result<Self> {
+        let workspace = if self.workspace.is_absolute() {
+            self.workspace.clone()
+        } else {
+            std::env::current_dir()
+                .context("failed to read the current directory")?
+                .join(&self.workspace)
+        };
+        Ok(self.resolve_option_files(&workspace))
+    }
+
+    /// Joins `workspace` to the option files a run reads relative to the
+    /// workspace: all but
+    /// [`fine_grained_external_repos_file`](Self::fine_grained_external_repos_file).
+    fn resolve_option_files(mut self, workspace: &Path) -> Self {
+        let files = [
+            &mut self.content_hash_path,
+            &mut self.bep_json_file,
+            &mut self.execution_log,
+            &mut self.seed_filepaths,
+            &mut self.seed_build_flags_file,
+            &mut self.modified_filepaths,
+        ];
+        for path in files.into_iter().flatten() {
+            *path = workspace.join(&*path);
         }
+        self
     }
 }
 
@@ -63,9 +464,203 @@ impl Default for GenerateHashesConfig {
 pub struct GenerateHashesResult {
     pub hashes: BTreeMap<String, String>,
     pub dep_edges: BTreeMap<String, Option<Vec<String>>>,
+    /// The subset of [`dep_edges`](Self::dep_edges) to deps that cquery only
+    /// resolved in an exec configuration, such as compilers and code
+    /// generators; rules without such deps are left out. Empty without
+    /// `use_cquery`.
+    pub exec_dep_edges: BTreeMap<String, Vec<String>>,
+    pub profile: Option<HashProfile>,
+    /// Main repository files that no rule uses as an input, e.g. files no
+    /// target names or stale `exports_files` entries; sorted. Empty unless
+    /// [`GenerateHashesConfig::orphan_sources`] is set.
+    pub orphan_sources: Vec<String>,
+    /// Source files whose contents were changed by
+    /// [`GenerateHashesConfig::normalize`] before hashing; sorted.
+    pub normalized_sources: Vec<String>,
+    /// Files read while hashing sources, when
+    /// [`GenerateHashesConfig::audit_file_access`] is set.
+    pub file_access: Option<FileAccessAudit>,
+    /// Targets left out when [`GenerateHashesConfig::time_budget`] is set.
+    pub coverage: Option<HashCoverage>,
+    /// Version of the Bazel server that answered `bazel info`, e.g. `7.4.1`;
+    /// `None` when a supplied query result made asking unnecessary.
+    pub bazel_version: Option<String>,
+    /// Errors Bazel reported loading packages; the targets of those packages
+    /// are missing from [`hashes`](Self::hashes). Sorted.
+    pub query_errors: Vec<QueryError>,
+    /// Members of every `test_suite` in the query result: its `tests`
+    /// attribute, or the tests of its package when that is empty. Sorted.
+    pub test_suites: BTreeMap<String, Vec<String>>,
+    /// Whether the graph was read from BUILD files without Bazel
+    /// ([`GenerateHashesConfig::no_bazel`]), so the hashes are approximate.
+    pub approximate: bool,
+    /// Algorithm behind the hashes; only results made with the same one can
+    /// be compared.
+    pub hash_function: HashFunction,
+    /// Dependency cycles broken under [`CyclePolicy::Break`] or
+    /// [`CyclePolicy::Warn`], each listed from the rule it was broken at back
+    /// to that rule; sorted. The edge back is missing from
+    /// [`dep_edges`](Self::dep_edges).
+    pub cycles: Vec<Vec<String>>,
+}
+
+impl GenerateHashesResult {
+    /// Dep edges in the shape consumed by the impact pipeline.
+    pub fn dependency_edges(&self) -> DependencyEdges {
+        self.dep_edges
+            .iter()
+            .map(|(label, deps)| (label.clone(), deps.clone().unwrap_or_default()))
+            .collect()
+    }
+
+    /// The metadata deciding which hashes these can be compared with, as a
+    /// hash file records it: the hash function when it is not SHA-256, and
+    /// whether the hashes are approximate.
+    pub fn comparison_meta(&self) -> OutputMeta {
+        OutputMeta {
+            hash_function: (self.hash_function != HashFunction::default())
+                .then_some(self.hash_function),
+            approximate: self.approximate.then_some(true),
+            ..OutputMeta::default()
+        }
+    }
+}
+
+/// One target's hash and the digests it was computed from, written by
+/// `hash-target`. Digests are hex encoded.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetHashReport {
+    pub label: String,
+    /// `Rule`, `GeneratedFile` or `SourceFile`.
+    pub kind: String,
+    /// The hash as `generate-hashes` would write it.
+    pub hash: String,
+    pub overall: String,
+    pub direct: String,
+    /// Generating rule of a generated file, whose inputs are listed below.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generating_rule: Option<String>,
+    /// Digest of the rule class, name and hashed attributes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes_digest: Option<String>,
+    /// Digest of the `--seedFilepaths` files and `--seedBuildFlagsFile`
+    /// flags, mixed into every target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_digest: Option<String>,
+    /// Rule inputs in hashing order; a source file lists only itself.
+    pub inputs: Vec<InputDigest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDigest {
+    pub label: String,
+    pub kind: InputKind,
+    /// `None` when no digest could be computed, so only the label counts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputKind {
+    /// A rule whose overall digest feeds the target's overall digest.
+    Rule,
+    /// A file whose digest feeds the target's direct digest.
+    File,
+    /// An input outside the query result that could not be hashed.
+    Unresolved,
 }
 
 pub async fn generate_hashes(config: &GenerateHashesConfig) -> Result<GenerateHashesResult> {
+    let (engine_config, graph) = prepare(config, None).await?;
+    let mut engine = match &config.checkpoint {
+        Some(checkpoint) => {
+            config.write_guard().check(&checkpoint.output)?;
+            let fingerprint = engine_config.fingerprint(&graph);
+            let sources = engine_config.sources_fingerprint();
+            let fingerprints = Fingerprints {
+                run: &fingerprint,
+                sources: Some(&sources),
+            };
+            let (writer, resumed) = CheckpointWriter::open(checkpoint, fingerprints)?;
+            if !resumed.is_empty() {
+                info!(
+                    reused = resumed.len(),
+                    "resuming from partial NDJSON output"
+                );
+            }
+            HashEngine::new(engine_config).with_checkpoint(writer, resumed)
+        }
+        None => HashEngine::new(engine_config),
+    };
+
+    let mut results = engine.compute(graph)?;
+    results.approximate = config.no_bazel;
+    if let (Some(stamps), Some(mode)) = (
+        &engine.config.file_stamps,
+        config.detect_concurrent_modifications,
+    ) {
+        stamps.verify(mode)?;
+    }
+    if let (Some(profiler), Some(top_k)) = (&engine.config.profiler, config.profile_top_k) {
+        results.profile = Some(profiler.report(top_k));
+    }
+    if let Some(normalizer) = &engine.config.normalizer {
+        results.normalized_sources = normalizer.normalized_labels();
+    }
+    if let Some(recorder) = &engine.config.file_access {
+        results.file_access = Some(recorder.report(&config.workspace));
+    }
+    if let Some(cache) = &engine.config.content_cache {
+        cache.save()?;
+    }
+    Ok(results)
+}
+
+/// Hashes `target` with the options of `config`, querying only
+/// `deps(target)` instead of the whole repository, and explains which input
+/// digests its hash is made of. The hash matches a full `generate-hashes` run
+/// as long as the target's dependencies are the same in both query results.
+pub async fn hash_target(config: &GenerateHashesConfig, target: &str) -> Result<TargetHashReport> {
+    let (engine_config, graph) = prepare(config, Some(target)).await?;
+    HashEngine::new(engine_config).explain(&graph, target)
+}
+
+/// Loads the query result and resolves every option into an engine
+/// configuration; with `deps_of` set, only that target's dependencies are
+/// queried.
+async fn prepare(
+    config: &GenerateHashesConfig,
+    deps_of: Option<&str>,
+) -> Result<(HashEngineConfig, BazelGraph)> {
+    if config.use_cquery && config.platform_constraints.is_some() {
+        bail!("platform constraints only apply to query mode; cquery already skips incompatible targets");
+    }
+    if config.time_budget.is_some() && config.checkpoint.is_some() {
+        bail!("a time budget cannot be combined with NDJSON checkpoint output");
+    }
+    if config.no_bazel {
+        if config.query_proto_path.is_some() {
+            bail!("--noBazel reads BUILD files itself and cannot use a supplied query result");
+        }
+        if config.use_cquery {
+            bail!("--noBazel cannot resolve configurations; drop --useCquery");
+        }
+        if config.warm_cache.is_some() {
+            bail!("--noBazel has no query result for the warm cache to reuse");
+        }
+        if !config.fine_grained_external_repos.is_empty()
+            || config.fine_grained_external_repos_file.is_some()
+        {
+            bail!("--noBazel leaves external repositories out and cannot hash them fine-grained");
+        }
+    }
+    let deadline = config.time_budget.map(|budget| Instant::now() + budget);
+    if config.query_proto_path.is_none() {
+        check_workspace_root(&config.workspace)?;
+    }
     let fine_grained_raw = load_fine_grained_repos(
         &config.fine_grained_external_repos,
         config.fine_grained_external_repos_file.as_deref(),
@@ -84,68 +679,547 @@ pub async fn generate_hashes(config: &GenerateHashesConfig) -> Result<GenerateHa
             .content_hash_path
             .as_ref()
             .map(|p| config.workspace.join(p)),
+        &config.workspace,
+        &config.write_guard(),
     )?;
+    let cached_digests = match &config.remote_cache {
+        Some(cache) => {
+            let log = config.execution_log.as_ref().context(
+                "--remoteCache needs the --executionLogJsonFile of the build to look up",
+            )?;
+            let actions = crate::remote::read_action_digests(&config.workspace.join(log))?;
+            let cache = match cache {
+                RemoteCache::Disk(dir) => RemoteCache::Disk(config.workspace.join(dir)),
+                RemoteCache::Http(_) | RemoteCache::Grpc { .. } => cache.clone(),
+            };
+            Some(cache.read_output_digests(&actions).await?)
+        }
+        None => None,
+    };
+    let bep_digests = config
+        .bep_json_file
+        .as_ref()
+        .map(|p| crate::bep::read_output_digests(&config.workspace.join(p)))
+        .transpose()?;
+    let output_digests = match (cached_digests, bep_digests) {
+        (Some(mut cached), Some(bep)) => {
+            cached.extend(bep);
+            Some(cached)
+        }
+        (cached, bep) => bep.or(cached),
+    };
     let seed_hash = compute_seed_hash(
         config
             .seed_filepaths
             .as_ref()
             .map(|p| config.workspace.join(p)),
+        config
+            .seed_build_flags_file
+            .as_ref()
+            .map(|p| config.workspace.join(p)),
+        &config.workspace,
     )?;
-    let modified_paths = load_path_list(
+    let mut modified_paths = load_path_list(
         config
             .modified_filepaths
             .as_ref()
             .map(|p| config.workspace.join(p)),
     )?;
+    if let Some(base) = &config.git_diff_base {
+        let changed = changed_paths(&config.workspace, base)?;
+        info!(
+            base = %base,
+            changed = changed.len(),
+            "reading only the files changed since the git diff base"
+        );
+        modified_paths.extend(changed.into_iter().map(PathBuf::from));
+    }
+    // With previous hashes the modified files say which digests are stale;
+    // every other file is still read when it has no previous digest.
+    let changed_since_previous = match &config.previous_hashes {
+        Some(_) if config.modified_filepaths.is_some() || config.git_diff_base.is_some() => {
+            Some(ChangedSince::Paths(std::mem::take(&mut modified_paths)))
+        }
+        Some(_) => Some(ChangedSince::Stamp),
+        None => None,
+    };
 
-    let ignored_attrs: HashSet<String> = config
-        .ignored_attrs
-        .iter()
-        .map(|s| s.to_string())
-        .chain(DEFAULT_IGNORED_ATTRS.iter().map(|s| s.to_string()))
-        .collect();
+    let ignored_attrs: HashSet<String> = config.effective_ignored_attrs().into_iter().collect();
 
-    let bazel_opts = BazelOptions {
-        workspace: config.workspace.clone(),
-        bazel_path: config.bazel_path.clone(),
-        startup_options: config.startup_options.clone(),
-        command_options: config.command_options.clone(),
-        cquery_options: config.cquery_options.clone(),
-        use_cquery: config.use_cquery,
-        keep_going: config.keep_going,
-    };
+    let exclusions = PackageExclusions::load(&config.workspace, &config.exclude_patterns)?;
+
+    let hybrid = HybridWorkspace::detect(&config.workspace, &config.command_options)?;
+    if let Some(hybrid) = &hybrid {
+        info!(
+            module_repos = hybrid.module_repos().len(),
+            "hybrid workspace: repositories not named in MODULE.bazel resolve through WORKSPACE"
+        );
+    }
 
-    // Output base is needed to locate external repository roots.
-    let output_base = bazel_output_base(&bazel_opts).await?;
+    let fetch_options = fetch_options(config);
+    let mut bazel_opts = bazel_options(config);
+
+    // Output base is needed to locate external repository roots. A caller
+    // supplying its own query result only needs it for fine-grained repos.
+    let mut bazel_version = None;
+    let output_base = if !config.no_bazel
+        && (config.query_proto_path.is_none() || !fine_grained_raw.is_empty())
+    {
+        let info = preflight_info(&bazel_opts).await?;
+        bazel_opts.query_output = config
+            .query_output
+            .negotiate(info.release().and_then(Result::ok));
+        bazel_version = info
+            .get("release")
+            .map(|release| release.trim_start_matches("release ").to_string());
+        info.output_base()
+            .context("bazel info returned no output_base")?
+    } else {
+        PathBuf::new()
+    };
 
     let resolver = ExternalRepoResolver {
         workspace: config.workspace.clone(),
         bazel_path: bazel_opts.bazel_path.clone(),
         startup_options: bazel_opts.startup_options.clone(),
         output_base,
+        fetch_options,
+        command_options: config.command_options.clone(),
+        offline: config.offline,
+        roots: Default::default(),
+        markers: Default::default(),
+        repo_mapping: Default::default(),
+        hybrid: hybrid.clone(),
+    };
+
+    let graph = match (&config.query_proto_path, deps_of) {
+        _ if config.no_bazel => BazelGraph::from_targets(
+            crate::build_file::scan_workspace(&config.workspace, &exclusions)?,
+            config.exclude_external_targets,
+            false,
+        ),
+        (Some(path), _) => BazelGraph::from_targets(
+            read_query_proto(path)?,
+            config.exclude_external_targets,
+            config.use_cquery,
+        ),
+        (None, Some(target)) => {
+            BazelGraph::load_deps_of(&bazel_opts, target, config.exclude_external_targets).await?
+        }
+        (None, None) => match &config.warm_cache {
+            Some(warm) => {
+                warm.load_graph(
+                    &bazel_opts,
+                    &fine_grained_raw,
+                    config.exclude_external_targets,
+                    &exclusions,
+                )
+                .await?
+            }
+            None => {
+                BazelGraph::load(
+                    &bazel_opts,
+                    &fine_grained_raw,
+                    config.exclude_external_targets,
+                    &exclusions,
+                )
+                .await?
+            }
+        },
+    };
+
+    if config.fail_on_query_errors && !graph.query_errors.is_empty() {
+        let errors: Vec<String> = graph.query_errors.iter().map(ToString::to_string).collect();
+        bail!(
+            "bazel reported {} error(s) loading packages:\n  {}",
+            errors.len(),
+            errors.join("\n  ")
+        );
+    }
+
+    let mut excluded_labels = match &config.platform_constraints {
+        Some(constraints) => {
+            let excluded = graph.incompatible_labels(constraints);
+            info!(
+                excluded = excluded.len(),
+                "skipping targets incompatible with the platform constraints"
+            );
+            excluded
+        }
+        None => HashSet::new(),
+    };
+
+    let mut path_only_sources = match config.gitattributes {
+        Some(mode) => {
+            let marked = graph.gitattributes_marked_sources(&config.workspace)?;
+            info!(
+                marked = marked.len(),
+                "hashing sources marked generated or vendored by path only"
+            );
+            if mode == GitAttributesMode::Skip {
+                excluded_labels.extend(marked.iter().cloned());
+            }
+            marked
+        }
+        None => HashSet::new(),
     };
 
-    let graph = BazelGraph::load(
-        &bazel_opts,
-        &fine_grained_raw,
-        config.exclude_external_targets,
-    )
-    .await?;
+    if !exclusions.is_empty() {
+        // Left in by a supplied query result, or pulled in by dependencies.
+        let excluded = graph.labels_in(&exclusions);
+        info!(
+            excluded = excluded.len(),
+            "leaving out targets of excluded packages"
+        );
+        path_only_sources.extend(excluded.iter().cloned());
+        excluded_labels.extend(excluded);
+    }
+
+    let budget = deadline.map(|deadline| HashBudget {
+        deadline,
+        packages: prioritize_packages(&config.workspace, graph.packages()),
+    });
 
-    let mut engine = HashEngine::new(HashEngineConfig {
+    let mut engine_config = HashEngineConfig {
         include_target_type: config.include_target_type,
+        excluded_labels,
         target_types: target_type_filter,
         ignored_attrs,
         fine_grained_external_repos: fine_grained_trimmed,
         seed_hash,
         content_hashes,
+        output_digests,
         modified_filepaths: modified_paths,
+        path_only_sources,
         track_dep_edges: config.track_dep_edges,
         resolver,
-    });
+        profiler: config.profile_top_k.map(|_| HashProfiler::new()),
+        file_stamps: config
+            .detect_concurrent_modifications
+            .map(|_| FileStampRecorder::new()),
+        file_access: config.audit_file_access.then(FileAccessRecorder::new),
+        content_cache: match (&config.content_cache, &config.warm_cache) {
+            (Some(path), _) if !config.read_only => Some(ContentCache::load(path)?),
+            (_, Some(warm)) => Some(warm.content_cache()),
+            (_, None) => None,
+        },
+        jobs: config.jobs,
+        budget,
+        input_rewriter: match (&config.input_rewriter, hybrid) {
+            (Some(rewriter), _) => rewriter.clone(),
+            (None, Some(hybrid)) => Arc::new(HybridRuleInputRewriter::new(hybrid)),
+            (None, None) => Arc::new(DefaultRuleInputRewriter),
+        },
+        build_file_targets: config.build_file_targets,
+        orphan_sources: config.orphan_sources.then(|| exclusions.clone()),
+        normalizer: SourceNormalizer::new(&config.normalize)?,
+        hash_source_visibility: config.hash_source_visibility,
+        hash_function: config.hash_function,
+        on_cycle: config.on_cycle,
+        structure_only: config.structure_only,
+        hash_repo_markers: config.hash_repo_markers,
+        bazel_version,
+        previous: None,
+    };
+    if let (Some(path), Some(changed)) = (&config.previous_hashes, changed_since_previous) {
+        let digests = load_previous_source_digests(path, &engine_config.sources_fingerprint())?;
+        info!(
+            path = %path.display(),
+            sources = digests.len(),
+            "reusing source digests of unchanged files from previous hashes"
+        );
+        engine_config.previous = Some(PreviousSources { digests, changed });
+    }
+    Ok((engine_config, graph))
+}
 
-    let results = engine.compute(graph)?;
-    Ok(results)
+/// A workspace hashed as part of a multi-workspace invocation. Labels from
+/// workspaces with a namespace are emitted as `namespace!//pkg:target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceSpec {
+    pub namespace: Option<String>,
+    pub path: PathBuf,
+}
+
+/// Derives namespaces for a list of workspace paths. The first workspace is the
+/// root and stays un-namespaced; the others are named after their directory.
+pub fn workspace_specs_from_paths(paths: &[PathBuf]) -> Result<Vec<WorkspaceSpec>> {
+    let mut seen = HashSet::new();
+    let mut specs = Vec::with_capacity(paths.len());
+    for (idx, path) in paths.iter().enumerate() {
+        let namespace = if idx == 0 {
+            None
+        } else {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .filter(|n| !n.is_empty() && n != "." && n != "..")
+                .ok_or_else(|| {
+                    anyhow!("cannot derive a namespace for workspace {}", path.display())
+                })?;
+            if !seen.insert(name.clone()) {
+                bail!(
+                    "workspace namespace {name} is used by more than one --workspacePath; \
+                     rename one of the directories"
+                );
+            }
+            Some(name)
+        };
+        specs.push(WorkspaceSpec {
+            namespace,
+            path: path.clone(),
+        });
+    }
+    Ok(specs)
+}
+
+/// Hashes each workspace with `base` (overriding only the workspace path) and
+/// merges the results, namespacing labels and dep edges per workspace.
+pub async fn generate_hashes_for_workspaces(
+    base: &GenerateHashesConfig,
+    workspaces: &[WorkspaceSpec],
+) -> Result<GenerateHashesResult> {
+    if base.checkpoint.is_some() {
+        bail!("NDJSON checkpoint output supports a single workspace");
+    }
+    for spec in workspaces {
+        check_workspace_root(&spec.path)?;
+    }
+    let started = Instant::now();
+    let mut merged = GenerateHashesResult {
+        hashes: BTreeMap::new(),
+        dep_edges: BTreeMap::new(),
+        exec_dep_edges: BTreeMap::new(),
+        profile: None,
+        orphan_sources: Vec::new(),
+        normalized_sources: Vec::new(),
+        file_access: None,
+        coverage: None,
+        bazel_version: None,
+        query_errors: Vec::new(),
+        test_suites: BTreeMap::new(),
+        approximate: base.no_bazel,
+        hash_function: base.hash_function,
+        cycles: Vec::new(),
+    };
+    for spec in workspaces {
+        // The budget covers every workspace together.
+        let config = GenerateHashesConfig {
+            workspace: spec.path.clone(),
+            time_budget: base
+                .time_budget
+                .map(|budget| budget.saturating_sub(started.elapsed())),
+            ..base.clone()
+        };
+        let result = generate_hashes(&config)
+            .await
+            .with_context(|| format!("failed to hash workspace {}", spec.path.display()))?;
+        merge_namespaced(
+            &mut merged,
+            result,
+            spec.namespace.as_deref(),
+            base.profile_top_k,
+        );
+    }
+    Ok(merged)
+}
+
+fn merge_namespaced(
+    into: &mut GenerateHashesResult,
+    result: GenerateHashesResult,
+    namespace: Option<&str>,
+    profile_top_k: Option<usize>,
+) {
+    if let (Some(mut profile), Some(top_k)) = (result.profile, profile_top_k) {
+        for entry in profile
+            .slowest_rules
+            .iter_mut()
+            .chain(profile.slowest_sources.iter_mut())
+        {
+            entry.label = namespace_label(namespace, &entry.label);
+        }
+        match &mut into.profile {
+            Some(existing) => existing.merge(profile, top_k),
+            None => into.profile = Some(profile),
+        }
+    }
+    if into.bazel_version.is_none() {
+        into.bazel_version = result.bazel_version;
+    }
+    into.query_errors
+        .extend(result.query_errors.into_iter().map(|error| {
+            QueryError {
+                package: error
+                    .package
+                    .map(|package| namespace_label(namespace, &package)),
+                ..error
+            }
+        }));
+    into.query_errors.sort();
+    for (suite, members) in result.test_suites {
+        let members = members
+            .iter()
+            .map(|member| namespace_label(namespace, member))
+            .collect();
+        into.test_suites
+            .insert(namespace_label(namespace, &suite), members);
+    }
+    for (label, hash) in result.hashes {
+        into.hashes.insert(namespace_label(namespace, &label), hash);
+    }
+    for (label, deps) in result.dep_edges {
+        let deps = deps.map(|list| {
+            list.iter()
+                .map(|dep| namespace_label(namespace, dep))
+                .collect()
+        });
+        into.dep_edges
+            .insert(namespace_label(namespace, &label), deps);
+    }
+    for (label, deps) in result.exec_dep_edges {
+        let deps = deps
+            .iter()
+            .map(|dep| namespace_label(namespace, dep))
+            .collect();
+        into.exec_dep_edges
+            .insert(namespace_label(namespace, &label), deps);
+    }
+    into.orphan_sources.extend(
+        result
+            .orphan_sources
+            .iter()
+            .map(|label| namespace_label(namespace, label)),
+    );
+    into.orphan_sources.sort();
+    into.normalized_sources.extend(
+        result
+            .normalized_sources
+            .iter()
+            .map(|label| namespace_label(namespace, label)),
+    );
+    into.normalized_sources.sort();
+    into.cycles.extend(result.cycles.iter().map(|cycle| {
+        cycle
+            .iter()
+            .map(|label| namespace_label(namespace, label))
+            .collect()
+    }));
+    into.cycles.sort();
+    if let Some(file_access) = result.file_access {
+        match &mut into.file_access {
+            Some(existing) => existing.merge(file_access),
+            None => into.file_access = Some(file_access),
+        }
+    }
+    if let Some(coverage) = result.coverage {
+        into.coverage
+            .get_or_insert_with(|| HashCoverage::new(0, &[]))
+            .merge(coverage, namespace);
+    }
+}
+
+/// The options of every Bazel command `config` runs; the query output format
+/// is negotiated with the Bazel version once it is known.
+fn bazel_options(config: &GenerateHashesConfig) -> BazelOptions {
+    let fetch_options = fetch_options(config);
+    let scratch_dir = config.write_guard().scratch_dir(&config.workspace);
+    BazelOptions {
+        workspace: config.workspace.clone(),
+        bazel_path: config.bazel_path.clone(),
+        startup_options: config.startup_options.clone(),
+        command_options: [config.command_options.as_slice(), &fetch_options].concat(),
+        cquery_options: [config.cquery_options.as_slice(), &fetch_options].concat(),
+        use_cquery: config.use_cquery,
+        keep_going: config.keep_going,
+        query_output: QueryOutputFormat::StreamedProto,
+        scratch_dir: Some(scratch_dir),
+    }
+}
+
+/// The Bazel commands [`generate_hashes`] runs for `config`, in order, without
+/// running any of them; none with [`GenerateHashesConfig::no_bazel`]. The
+/// query output format is the requested one rather than the one negotiated
+/// with the Bazel version, and the `bazel mod` and `bazel query` commands that
+/// locate external repositories are left out, since which repositories need
+/// locating depends on the query result.
+pub fn plan_bazel_commands(config: &GenerateHashesConfig) -> Result<Vec<PlannedCommand>> {
+    if config.no_bazel {
+        return Ok(Vec::new());
+    }
+    let fine_grained = load_fine_grained_repos(
+        &config.fine_grained_external_repos,
+        config.fine_grained_external_repos_file.as_deref(),
+    )?;
+    let exclusions = PackageExclusions::load(&config.workspace, &config.exclude_patterns)?;
+    let opts = BazelOptions {
+        query_output: config.query_output,
+        ..bazel_options(config)
+    };
+    let mut plan = Vec::new();
+    if config.query_proto_path.is_none() || !fine_grained.is_empty() {
+        plan.push(plan_info(&opts, PREFLIGHT_INFO_KEYS));
+    }
+    if config.query_proto_path.is_none() {
+        let queries = graph_queries(
+            opts.use_cquery,
+            &fine_grained,
+            config.exclude_external_targets,
+            &exclusions,
+        )?;
+        for (cquery, expression) in queries {
+            if cquery {
+                plan.extend(plan_cquery(&opts, &expression));
+            } else {
+                plan.push(plan_query(&opts, &expression));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// The expressions [`BazelGraph::load`] queries, each with whether it is run
+/// with cquery.
+fn graph_queries(
+    use_cquery: bool,
+    fine_grained_repos: &HashSet<String>,
+    exclude_external: bool,
+    exclusions: &PackageExclusions,
+) -> Result<Vec<(bool, String)>> {
+    let mut queries = Vec::new();
+    if use_cquery {
+        let expr = format!("deps({})", exclusions.subtract_from("//...:all-targets")?);
+        queries.push((true, expr));
+        if !exclude_external {
+            let expr = build_query_expression(&["//external:all-targets".to_string()])?;
+            queries.push((false, expr));
+        }
+    } else {
+        let mut patterns = vec!["//...:all-targets".to_string()];
+        if !exclude_external {
+            patterns.push("//external:all-targets".to_string());
+        }
+        let mut repos: Vec<_> = fine_grained_repos.iter().collect();
+        repos.sort();
+        for repo in repos {
+            patterns.push(format!("{repo}//...:all-targets"));
+        }
+        let expr = exclusions.subtract_from(&build_query_expression(&patterns)?)?;
+        queries.push((false, expr));
+    }
+    Ok(queries)
+}
+
+/// Bazel flags that keep queries from fetching, or point them at a shared
+/// repository cache.
+fn fetch_options(config: &GenerateHashesConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if config.offline {
+        options.push("--nofetch".to_string());
+    }
+    if let Some(cache) = &config.repository_cache {
+        options.push(format!("--repository_cache={}", cache.display()));
+    }
+    options
 }
 
 fn load_fine_grained_repos(cli_values: &[String], file: Option<&Path>) -> Result<HashSet<String>> {
@@ -156,18 +1230,26 @@ fn load_fine_grained_repos(cli_values: &[String], file: Option<&Path>) -> Result
         let f = File::open(path)
             .with_context(|| format!("failed to open fine-grained repo file {}", path.display()))?;
         let reader = BufReader::new(f);
-        Ok(reader
+        let repos: HashSet<String> = reader
             .lines()
             .map_while(Result::ok)
             .map(|l| l.trim().to_string())
             .filter(|l| !l.is_empty())
-            .collect())
+            .collect();
+        for repo in &repos {
+            validate_repo_name(repo).with_context(|| format!("in {}", path.display()))?;
+        }
+        Ok(repos)
     } else {
         Ok(cli_values.iter().map(|s| s.to_string()).collect())
     }
 }
 
-fn load_content_hash_map(path: Option<PathBuf>) -> Result<Option<HashMap<String, String>>> {
+fn load_content_hash_map(
+    path: Option<PathBuf>,
+    workspace: &Path,
+    guard: &WriteGuard,
+) -> Result<Option<ContentHashes>> {
     match path {
         None => Ok(None),
         Some(p) => {
@@ -176,12 +1258,70 @@ fn load_content_hash_map(path: Option<PathBuf>) -> Result<Option<HashMap<String,
             let reader = BufReader::new(file);
             let map: HashMap<String, String> =
                 serde_json::from_reader(reader).context("failed to parse content hash JSON")?;
-            Ok(Some(map))
+            let case = CaseSensitivity::probe(workspace, guard);
+            debug!(?case, "probed workspace filesystem for content hash keys");
+            Ok(Some(ContentHashes::new(map, case)))
+        }
+    }
+}
+
+/// `--contentHashPath` entries keyed by [`paths::lookup_key`], so a source
+/// finds its entry however the map and the filesystem spell its path.
+#[derive(Debug, Clone)]
+struct ContentHashes {
+    hashes: HashMap<String, String>,
+    case: CaseSensitivity,
+}
+
+impl ContentHashes {
+    /// Keys naming the same file under `case` collapse into one entry; the
+    /// first in sorted order wins and the others are warned about.
+    fn new(map: HashMap<String, String>, case: CaseSensitivity) -> Self {
+        let ordered: BTreeMap<_, _> = map.into_iter().collect();
+        let mut hashes = HashMap::with_capacity(ordered.len());
+        let mut spellings: HashMap<String, String> = HashMap::new();
+        for (key, hash) in ordered {
+            let lookup = paths::lookup_key(&key, case);
+            if let Some(first) = spellings.get(&lookup) {
+                if hashes.get(&lookup) != Some(&hash) {
+                    warn!(
+                        key,
+                        first = %first,
+                        "content hash key names the same file as another with a different hash; ignoring it"
+                    );
+                }
+                continue;
+            }
+            spellings.insert(lookup.clone(), key);
+            hashes.insert(lookup, hash);
         }
+        Self { hashes, case }
     }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.hashes
+            .get(&paths::lookup_key(key, self.case))
+            .map(String::as_str)
+    }
+}
+
+fn compute_seed_hash(
+    path: Option<PathBuf>,
+    build_flags: Option<PathBuf>,
+    workspace: &Path,
+) -> Result<Vec<u8>> {
+    let files_hash = compute_seed_files_hash(path)?;
+    let Some(build_flags) = build_flags else {
+        return Ok(files_hash);
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&files_hash);
+    hasher.update(b"\0build-flags\0");
+    hasher.update(build_flags_digest(&build_flags, workspace)?);
+    Ok(hasher.finalize().to_vec())
 }
 
-fn compute_seed_hash(path: Option<PathBuf>) -> Result<Vec<u8>> {
+fn compute_seed_files_hash(path: Option<PathBuf>) -> Result<Vec<u8>> {
     let Some(path) = path else {
         return Ok(Vec::new());
     };
@@ -217,7 +1357,7 @@ fn load_path_list(path: Option<PathBuf>) -> Result<HashSet<PathBuf>> {
         if value.trim().is_empty() {
             continue;
         }
-        set.insert(PathBuf::from(value.trim()));
+        set.insert(PathBuf::from(paths::slash_key(value.trim())));
     }
     Ok(set)
 }
@@ -225,19 +1365,194 @@ fn load_path_list(path: Option<PathBuf>) -> Result<HashSet<PathBuf>> {
 #[derive(Debug)]
 struct HashEngineConfig {
     include_target_type: bool,
+    /// Targets hashed (others may depend on them) but left out of the output.
+    excluded_labels: HashSet<String>,
     target_types: Option<HashSet<String>>,
     ignored_attrs: HashSet<String>,
     fine_grained_external_repos: HashSet<String>,
     seed_hash: Vec<u8>,
-    content_hashes: Option<HashMap<String, String>>,
+    content_hashes: Option<ContentHashes>,
+    /// Output file digests read from BEP, keyed by their path below the
+    /// output directory.
+    output_digests: Option<HashMap<String, String>>,
     modified_filepaths: HashSet<PathBuf>,
+    /// Source labels hashed without reading their contents.
+    path_only_sources: HashSet<String>,
     track_dep_edges: bool,
     resolver: ExternalRepoResolver,
+    profiler: Option<HashProfiler>,
+    file_stamps: Option<FileStampRecorder>,
+    file_access: Option<FileAccessRecorder>,
+    content_cache: Option<ContentCache>,
+    jobs: Option<usize>,
+    budget: Option<HashBudget>,
+    input_rewriter: Arc<dyn RuleInputRewriter>,
+    build_file_targets: bool,
+    /// Set to list orphan sources; the files of the packages it excludes are
+    /// never orphans.
+    orphan_sources: Option<PackageExclusions>,
+    normalizer: Option<SourceNormalizer>,
+    hash_source_visibility: bool,
+    hash_function: HashFunction,
+    on_cycle: CyclePolicy,
+    /// Source files are hashed by size instead of contents.
+    structure_only: bool,
+    hash_repo_markers: bool,
+    /// Reported in [`GenerateHashesResult::bazel_version`]; not an input.
+    bazel_version: Option<String>,
+    previous: Option<PreviousSources>,
+}
+
+/// Source digests of an earlier run and how to tell which files changed since
+/// (see [`GenerateHashesConfig::previous_hashes`]).
+#[derive(Debug, Clone)]
+struct PreviousSources {
+    digests: HashMap<String, SourceRecord>,
+    changed: ChangedSince,
+}
+
+#[derive(Debug, Clone)]
+enum ChangedSince {
+    /// Workspace-relative paths of the changed files.
+    Paths(HashSet<PathBuf>),
+    /// Files whose stamp differs from the one recorded with their digest
+    /// changed, as did files recorded without one.
+    Stamp,
+}
+
+impl PreviousSources {
+    /// The previous digest of `source` if it is still valid: a main repository
+    /// file that did not change, whose seed cannot have changed either.
+    fn digest(&self, source: &BazelSource, workspace: &Path) -> Option<Vec<u8>> {
+        if !source.subincludes.is_empty() {
+            return None;
+        }
+        let record = self.digests.get(&source.name)?;
+        let path = resolve_main_repo(&source.name, workspace)?;
+        let changed = match &self.changed {
+            ChangedSince::Paths(paths) => paths.contains(Path::new(&path.workspace_relative)),
+            ChangedSince::Stamp => {
+                record.stamp.is_none() || SourceStamp::of(&path.absolute) != record.stamp
+            }
+        };
+        (!changed).then(|| record.digest.clone())
+    }
+}
+
+impl HashEngineConfig {
+    /// Identifies the options source digests depend on, so digests recorded
+    /// by another run are only reused when they would come out the same.
+    /// Visibility is part of a source's seed, so hashing it rules out reuse.
+    fn sources_fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([
+            self.structure_only as u8,
+            self.hash_repo_markers as u8,
+            self.hash_source_visibility as u8,
+        ]);
+        hasher.update(self.hash_function.as_str());
+        hasher.update([0]);
+        if let Some(normalizer) = &self.normalizer {
+            hasher.update(normalizer.fingerprint().as_bytes());
+        }
+        hasher.update([0]);
+        let mut path_only: Vec<_> = self.path_only_sources.iter().collect();
+        path_only.sort();
+        for label in path_only {
+            hasher.update(label.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update([1]);
+        let mut modified: Vec<_> = self.modified_filepaths.iter().collect();
+        modified.sort();
+        for path in modified {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update([1]);
+        if let Some(content_hashes) = &self.content_hashes {
+            let ordered: BTreeMap<_, _> = content_hashes.hashes.iter().collect();
+            for (path, hash) in ordered {
+                hasher.update(path.as_bytes());
+                hasher.update(hash.as_bytes());
+            }
+        }
+        hex_encode(hasher.finalize())
+    }
+
+    /// Identifies the inputs of a run: the query result plus every option that
+    /// affects digests. Records are only resumed across identical fingerprints.
+    fn fingerprint(&self, graph: &BazelGraph) -> String {
+        fn sorted<'a>(values: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+            let mut values: Vec<_> = values.collect();
+            values.sort();
+            values
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(&graph.digest);
+        hasher.update([
+            self.include_target_type as u8,
+            graph.use_cquery as u8,
+            self.track_dep_edges as u8,
+            self.build_file_targets as u8,
+            self.hash_source_visibility as u8,
+            self.structure_only as u8,
+            self.hash_repo_markers as u8,
+        ]);
+        hasher.update(&self.seed_hash);
+        hasher.update(self.hash_function.as_str());
+        hasher.update(self.input_rewriter.fingerprint().as_bytes());
+        hasher.update([0]);
+        if let Some(normalizer) = &self.normalizer {
+            hasher.update(normalizer.fingerprint().as_bytes());
+        }
+        hasher.update([0]);
+        let sets = [
+            sorted(self.ignored_attrs.iter()),
+            sorted(self.fine_grained_external_repos.iter()),
+            sorted(self.target_types.iter().flatten()),
+            sorted(self.excluded_labels.iter()),
+            sorted(self.path_only_sources.iter()),
+        ];
+        for set in sets {
+            for value in set {
+                hasher.update(value.as_bytes());
+                hasher.update([0]);
+            }
+            hasher.update([1]);
+        }
+        let mut modified: Vec<_> = self.modified_filepaths.iter().collect();
+        modified.sort();
+        for path in modified {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update([0]);
+        }
+        if let Some(content_hashes) = &self.content_hashes {
+            let ordered: BTreeMap<_, _> = content_hashes.hashes.iter().collect();
+            for (path, hash) in ordered {
+                hasher.update(path.as_bytes());
+                hasher.update(hash.as_bytes());
+            }
+        }
+        hasher.update([1]);
+        if let Some(output_digests) = &self.output_digests {
+            let ordered: BTreeMap<_, _> = output_digests.iter().collect();
+            for (path, digest) in ordered {
+                hasher.update(path.as_bytes());
+                hasher.update(digest.as_bytes());
+            }
+        }
+        hex_encode(hasher.finalize())
+    }
 }
 
 struct HashEngine {
     config: HashEngineConfig,
     source_hasher: SourceFileHasher,
+    /// Shared with the hashing threads, which append each level as it is done.
+    checkpoint: Option<Mutex<CheckpointWriter>>,
+    resumed: HashMap<String, HashRecord>,
 }
 
 impl HashEngine {
@@ -251,107 +1566,652 @@ impl HashEngine {
                 .cloned()
                 .collect::<HashSet<_>>(),
             config.modified_filepaths.clone(),
-        );
+        )
+        .with_path_only_sources(config.path_only_sources.clone())
+        .with_structure_only(config.structure_only)
+        .with_repo_markers(config.hash_repo_markers)
+        .with_profiler(config.profiler.clone())
+        .with_file_stamps(config.file_stamps.clone())
+        .with_file_access(config.file_access.clone())
+        .with_content_cache(config.content_cache.clone())
+        .with_normalizer(config.normalizer.clone())
+        .with_output_digests(config.output_digests.clone())
+        .with_hash_function(config.hash_function);
 
         Self {
             config,
             source_hasher,
+            checkpoint: None,
+            resumed: HashMap::new(),
         }
     }
 
-    fn compute(&mut self, graph: BazelGraph) -> Result<GenerateHashesResult> {
-        let mut source_digests: HashMap<String, Vec<u8>> = HashMap::new();
-        for source in &graph.sources {
-            let seed = seed_for_source(source);
-            let digest = self
-                .source_hasher
-                .digest(&source.name, &seed)
-                .with_context(|| format!("failed to hash source {}", source.name))?;
-            debug!(
-                source = %source.name,
-                seed = %hex_encode(&seed),
-                digest = %hex_encode(&digest),
-                "source digest"
-            );
-            source_digests.insert(source.name.clone(), digest);
+    fn with_checkpoint(
+        mut self,
+        writer: CheckpointWriter,
+        resumed: HashMap<String, HashRecord>,
+    ) -> Self {
+        self.checkpoint = Some(Mutex::new(writer));
+        self.resumed = resumed;
+        self
+    }
+
+    /// Appends a finished target to the checkpoint, if one is being written.
+    fn emit(
+        &self,
+        label: &str,
+        value: &TargetHashValue,
+        source_digest: Option<&[u8]>,
+        source_stamp: Option<SourceStamp>,
+    ) -> Result<()> {
+        let Some(writer) = &self.checkpoint else {
+            return Ok(());
+        };
+        if self.config.excluded_labels.contains(label) {
+            return Ok(());
+        }
+        if let Some(filter) = &self.config.target_types {
+            if !filter.contains(value.kind.as_str()) {
+                return Ok(());
+            }
+        }
+        let hash = value.render(self.config.include_target_type);
+        // Reused records were already copied into the output when it was opened.
+        if self
+            .resumed
+            .get(label)
+            .is_some_and(|record| record.hash == hash && record.source_stamp == source_stamp)
+        {
+            return Ok(());
+        }
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.append(
+            label,
+            &hash,
+            source_digest,
+            source_stamp,
+            value.deps.as_deref(),
+        )
+    }
+
+    /// Appends a hashed rule and the files it generates to the checkpoint.
+    fn emit_rule(
+        &self,
+        name: &str,
+        digest: &TargetDigest,
+        generated_by: &HashMap<&str, Vec<&BazelGenerated>>,
+    ) -> Result<()> {
+        if self.checkpoint.is_none() {
+            return Ok(());
+        }
+        self.emit(
+            name,
+            &TargetHashValue::new(TargetKind::Rule, digest.clone()),
+            None,
+            None,
+        )?;
+        for gen in generated_by.get(name).into_iter().flatten() {
+            self.emit(&gen.name, &self.generated_value(gen, digest), None, None)?;
+        }
+        Ok(())
+    }
+
+    fn source_value(&self, digest: &[u8]) -> TargetHashValue {
+        TargetHashValue::new(
+            TargetKind::SourceFile,
+            target_digest_from_source(digest, &self.config.seed_hash, self.config.hash_function),
+        )
+    }
+
+    /// The value of `gen`, given the digest of its generating rule.
+    fn generated_value(&self, gen: &BazelGenerated, rule: &TargetDigest) -> TargetHashValue {
+        let mut digest = rule.clone_with_deps(Some(vec![gen.generating_rule.clone()]));
+        if let Some(output) = self.source_hasher.output_digest(&gen.name) {
+            digest = digest.with_output(output, self.config.hash_function);
+        }
+        TargetHashValue::new(TargetKind::GeneratedFile, digest)
+    }
+
+    /// The resumed digest of a source file, unless the file changed since.
+    fn resumed_source_digest(&self, label: &str) -> Option<Vec<u8>> {
+        let record = self.resumed.get(label)?;
+        let digest = hex::decode(record.source_digest.as_ref()?).ok()?;
+        self.stamp_is_current(label, record.source_stamp.as_ref())
+            .then_some(digest)
+    }
+
+    /// Stamp of the file `label` is read from, taken before it is hashed.
+    fn source_stamp(&self, label: &str) -> Option<SourceStamp> {
+        let path = self.source_hasher.resolve_label(label).ok()??;
+        SourceStamp::of(&path.absolute)
+    }
+
+    /// Whether the file `label` is read from still has the `recorded` stamp.
+    /// Labels not read from disk have nothing that could change.
+    fn stamp_is_current(&self, label: &str, recorded: Option<&SourceStamp>) -> bool {
+        match self.source_hasher.resolve_label(label) {
+            Ok(None) => true,
+            Ok(Some(path)) => {
+                recorded.is_some() && SourceStamp::of(&path.absolute).as_ref() == recorded
+            }
+            Err(_) => false,
         }
+    }
+
+    /// Resumed rules that depend, directly or through other rules, on a
+    /// source file of `graph` whose resumed digest cannot be reused.
+    fn stale_resumed_rules<'g>(
+        &self,
+        graph: &BazelGraph,
+        rule_hasher: &RuleHasher<'g>,
+    ) -> HashSet<&'g str> {
+        let mut stale = HashSet::new();
+        if self.resumed.is_empty() {
+            return stale;
+        }
+        let sources: HashSet<&str> = graph.sources.iter().map(|s| s.name.as_str()).collect();
+        let reusable: HashSet<&str> = sources
+            .par_iter()
+            .filter(|source| self.resumed_source_digest(source).is_some())
+            .copied()
+            .collect();
+        for name in rule_hasher.levels.iter().flatten() {
+            let changed = rule_hasher.inputs[name].iter().any(|input| {
+                let input = input.as_str();
+                stale.contains(input) || (sources.contains(input) && !reusable.contains(input))
+            });
+            if changed {
+                stale.insert(*name);
+            }
+        }
+        stale
+    }
+
+    /// Rebuilds the digests of resumed rules so the rule hasher treats them as
+    /// already computed. `stale` rules are hashed again.
+    fn resumed_rule_digests(
+        &self,
+        rules: &HashMap<String, BazelRule>,
+        stale: &HashSet<&str>,
+    ) -> HashMap<String, TargetDigest> {
+        self.resumed
+            .iter()
+            .filter(|(label, _)| rules.contains_key(*label) && !stale.contains(label.as_str()))
+            .filter_map(|(label, record)| {
+                let parsed = TargetHash::parse(&record.hash).ok()?;
+                let deps = if self.config.track_dep_edges {
+                    Some(record.deps.clone()?)
+                } else {
+                    None
+                };
+                let digest = TargetDigest {
+                    overall: hex::decode(parsed.transitive_hash()).ok()?,
+                    direct: hex::decode(parsed.direct_hash()?).ok()?,
+                    deps,
+                };
+                Some((label.clone(), digest))
+            })
+            .collect()
+    }
+
+    /// Digests of every target in `graph`, plus the source and rule digests
+    /// they were combined from. Files and rules are hashed on a thread pool of
+    /// [`GenerateHashesConfig::jobs`] threads; checkpoint records are emitted
+    /// as soon as the source files, then each level of rules, are hashed.
+    fn digest_all(&mut self, graph: &BazelGraph) -> Result<GraphDigests> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.config.jobs.unwrap_or(0))
+            .build()
+            .context("failed to start the hashing thread pool")?;
+        let (source_digests, rule_digests, skipped, cycles) = pool.install(|| -> Result<_> {
+            let rule_hasher = RuleHasher::new(graph, &self.config)?;
+            // Taken before any file is read, so a write racing the hashing
+            // leaves a stamp the next run will not match.
+            let stamps: HashMap<&str, SourceStamp> = match &self.checkpoint {
+                Some(_) => graph
+                    .sources
+                    .par_iter()
+                    .filter_map(|source| {
+                        let stamp = self.source_stamp(&source.name)?;
+                        Some((source.name.as_str(), stamp))
+                    })
+                    .collect(),
+                None => HashMap::new(),
+            };
+            let stale = self.stale_resumed_rules(graph, &rule_hasher);
+            let mut generated_by: HashMap<&str, Vec<&BazelGenerated>> = HashMap::new();
+            for target in &graph.targets {
+                if let BazelTarget::Generated(gen) = target {
+                    generated_by
+                        .entry(gen.generating_rule.as_str())
+                        .or_default()
+                        .push(gen);
+                }
+            }
+            let (mut source_digests, skipped) = match &self.config.budget {
+                Some(budget) => {
+                    let digests = self.budgeted_source_digests(graph, &rule_hasher, budget)?;
+                    let skipped = rule_hasher.rules_missing_sources(graph, &digests)?;
+                    (digests, skipped)
+                }
+                None => (self.source_digests(graph)?, HashSet::new()),
+            };
+            for source in &graph.sources {
+                if let Some(digest) = source_digests.get(&source.name) {
+                    let stamp = stamps.get(source.name.as_str()).copied();
+                    self.emit(
+                        &source.name,
+                        &self.source_value(digest),
+                        Some(digest),
+                        stamp,
+                    )?;
+                }
+            }
+            let mut rule_digests = self.resumed_rule_digests(&graph.rule_map, &stale);
+            // Their records may have been cut off before the files they
+            // generate were written.
+            for (name, digest) in &rule_digests {
+                self.emit_rule(name, digest, &generated_by)?;
+            }
+            let soft_digests = rule_hasher.soft_digests(
+                &self.source_hasher,
+                &source_digests,
+                &rule_digests,
+                &skipped,
+            )?;
+            source_digests.extend(soft_digests);
+            rule_hasher.hash_rules(&source_digests, &mut rule_digests, &skipped, |level| {
+                level
+                    .iter()
+                    .try_for_each(|(name, digest)| self.emit_rule(name, digest, &generated_by))
+            })?;
+            let skipped: HashSet<String> = skipped.into_iter().map(str::to_string).collect();
+            Ok((source_digests, rule_digests, skipped, rule_hasher.cycles))
+        })?;
 
-        let mut rule_digests: HashMap<String, TargetDigest> = HashMap::new();
         let mut results: BTreeMap<String, TargetHashValue> = BTreeMap::new();
+        let mut unhashed = Vec::new();
+        for source in &graph.sources {
+            let Some(digest) = source_digests.get(&source.name) else {
+                unhashed.push((source.name.clone(), TargetKind::SourceFile));
+                continue;
+            };
+            results.insert(source.name.clone(), self.source_value(digest));
+        }
+        for target in &graph.targets {
+            let (name, value) = match target {
+                BazelTarget::Rule(rule) if skipped.contains(rule.name.as_str()) => {
+                    unhashed.push((rule.name.clone(), TargetKind::Rule));
+                    continue;
+                }
+                BazelTarget::Generated(gen) if skipped.contains(gen.generating_rule.as_str()) => {
+                    unhashed.push((gen.name.clone(), TargetKind::GeneratedFile));
+                    continue;
+                }
+                BazelTarget::Rule(rule) => {
+                    let digest = rule_digests[&rule.name].clone();
+                    (&rule.name, TargetHashValue::new(TargetKind::Rule, digest))
+                }
+                BazelTarget::Generated(gen) => {
+                    let digest = rule_digests.get(&gen.generating_rule).ok_or_else(|| {
+                        anyhow!(
+                            "Missing generating rule {} for {}",
+                            gen.generating_rule,
+                            gen.name
+                        )
+                    })?;
+                    (&gen.name, self.generated_value(gen, digest))
+                }
+                BazelTarget::Source(_) => continue,
+            };
+            results.insert(name.clone(), value);
+        }
 
-        {
-            let mut rule_hasher = RuleHasher {
-                use_cquery: graph.use_cquery,
-                fine_grained_external_repos: self.config.fine_grained_external_repos.clone(),
-                ignored_attrs: self.config.ignored_attrs.clone(),
-                source_hasher: self.source_hasher.clone(),
-                source_digests: &mut source_digests,
-                rule_digests: &mut rule_digests,
-                seed_hash: self.config.seed_hash.clone(),
-                track_dep_edges: self.config.track_dep_edges,
+        Ok(GraphDigests {
+            results,
+            source_digests,
+            rule_digests,
+            unhashed,
+            cycles,
+        })
+    }
+
+    /// Digests of every source file in `graph`, reusing resumed records.
+    fn source_digests(&self, graph: &BazelGraph) -> Result<HashMap<String, Vec<u8>>> {
+        graph
+            .sources
+            .par_iter()
+            .map(|source| match self.resumed_source_digest(&source.name) {
+                Some(digest) => Ok((source.name.clone(), digest)),
+                None => self.source_digest(source),
+            })
+            .collect()
+    }
+
+    /// Digests of the source files each package of `budget` depends on,
+    /// package by package until the deadline passes. Sources not reached in
+    /// time are left out.
+    fn budgeted_source_digests(
+        &self,
+        graph: &BazelGraph,
+        rule_hasher: &RuleHasher,
+        budget: &HashBudget,
+    ) -> Result<HashMap<String, Vec<u8>>> {
+        let sources: HashMap<&str, &BazelSource> = graph
+            .sources
+            .iter()
+            .map(|source| (source.name.as_str(), source))
+            .collect();
+        let mut roots: HashMap<&str, Vec<&str>> = HashMap::new();
+        for target in &graph.targets {
+            let (label, root) = match target {
+                BazelTarget::Rule(rule) => (&rule.name, &rule.name),
+                BazelTarget::Source(source) => (&source.name, &source.name),
+                BazelTarget::Generated(gen) => (&gen.name, &gen.generating_rule),
             };
+            roots.entry(package_of(label)).or_default().push(root);
+        }
 
-            for target in graph.targets.iter() {
-                match target {
-                    BazelTarget::Rule(rule) => {
-                        let digest = rule_hasher.digest(rule, &graph.rule_map, &mut Vec::new())?;
-                        let value = TargetHashValue::new(TargetKind::Rule, digest);
-                        results.insert(rule.name.clone(), value);
+        let mut visited = HashSet::new();
+        let mut digests = HashMap::new();
+        for package in &budget.packages {
+            if budget.expired() {
+                break;
+            }
+            let mut stack = roots.get(package.as_str()).cloned().unwrap_or_default();
+            let mut needed = Vec::new();
+            while let Some(label) = stack.pop() {
+                if !visited.insert(label) {
+                    continue;
+                }
+                if let Some(source) = sources.get(label) {
+                    needed.push(*source);
+                } else if let Some(inputs) = rule_hasher.inputs.get(label) {
+                    stack.extend(inputs.iter().map(String::as_str));
+                }
+            }
+            let hashed: Vec<Option<(String, Vec<u8>)>> = needed
+                .par_iter()
+                .map(|source| {
+                    if budget.expired() {
+                        return Ok(None);
                     }
-                    BazelTarget::Generated(gen) => {
-                        let digest =
-                            rule_hasher.digest_generated(gen, &graph.rule_map, &mut Vec::new())?;
-                        let value = TargetHashValue::new(TargetKind::GeneratedFile, digest);
-                        results.insert(gen.name.clone(), value);
+                    self.source_digest(source).map(Some)
+                })
+                .collect::<Result<_>>()?;
+            digests.extend(hashed.into_iter().flatten());
+        }
+        if digests.len() < sources.len() {
+            warn!(
+                hashed = digests.len(),
+                skipped = sources.len() - digests.len(),
+                "time budget ran out before every source file was hashed"
+            );
+        }
+        Ok(digests)
+    }
+
+    fn source_digest(&self, source: &BazelSource) -> Result<(String, Vec<u8>)> {
+        let previous = self
+            .config
+            .previous
+            .as_ref()
+            .filter(|_| !self.config.hash_source_visibility);
+        if let Some(digest) =
+            previous.and_then(|p| p.digest(source, &self.config.resolver.workspace))
+        {
+            return Ok((source.name.clone(), digest));
+        }
+        let seed = seed_for_source(
+            source,
+            self.config.hash_source_visibility,
+            self.config.hash_function,
+        );
+        let digest = self
+            .source_hasher
+            .digest(&source.name, &seed)
+            .with_context(|| format!("failed to hash source {}", source.name))?;
+        debug!(
+            source = %source.name,
+            seed = %hex_encode(&seed),
+            digest = %hex_encode(&digest),
+            "source digest"
+        );
+        Ok((source.name.clone(), digest))
+    }
+
+    /// Hashes `graph` and breaks down the digest of `label`.
+    fn explain(&mut self, graph: &BazelGraph, label: &str) -> Result<TargetHashReport> {
+        let digests = self.digest_all(graph)?;
+        let value = digests.results.get(label).with_context(|| {
+            format!("{label} is not in the query result; pass its full label, e.g. //pkg:name")
+        })?;
+        let generating_rule = graph.targets.iter().find_map(|target| match target {
+            BazelTarget::Generated(gen) if gen.name == label => Some(gen.generating_rule.clone()),
+            _ => None,
+        });
+        let rule = graph
+            .rule_map
+            .get(generating_rule.as_deref().unwrap_or(label));
+
+        let hex = |digest: Option<&Vec<u8>>| digest.map(hex_encode);
+        let inputs = match rule {
+            Some(rule) => rule
+                .rule_inputs(
+                    graph.use_cquery,
+                    &self.config.fine_grained_external_repos,
+                    self.config.input_rewriter.as_ref(),
+                )
+                .into_iter()
+                .map(|input| {
+                    let (kind, digest) = if graph.rule_map.contains_key(&input) {
+                        let overall = digests.rule_digests.get(&input).map(|d| &d.overall);
+                        (InputKind::Rule, hex(overall))
+                    } else {
+                        match digests.source_digests.get(&input) {
+                            Some(digest) => (InputKind::File, hex(Some(digest))),
+                            None => (InputKind::Unresolved, None),
+                        }
+                    };
+                    InputDigest {
+                        label: input,
+                        kind,
+                        digest,
                     }
-                    BazelTarget::Source(_) => {}
-                }
+                })
+                .collect(),
+            None => vec![InputDigest {
+                label: label.to_string(),
+                kind: InputKind::File,
+                digest: hex(digests.source_digests.get(label)),
+            }],
+        };
+
+        Ok(TargetHashReport {
+            label: label.to_string(),
+            kind: value.kind.as_str().to_string(),
+            hash: value.render(self.config.include_target_type),
+            overall: hex_encode(&value.overall),
+            direct: hex_encode(&value.direct),
+            generating_rule,
+            attributes_digest: rule.map(|rule| {
+                hex_encode(rule.digest(&self.config.ignored_attrs, self.config.hash_function))
+            }),
+            seed_digest: (!self.config.seed_hash.is_empty())
+                .then(|| hex_encode(&self.config.seed_hash)),
+            inputs,
+        })
+    }
+
+    fn compute(&mut self, graph: BazelGraph) -> Result<GenerateHashesResult> {
+        let orphan_sources = match &self.config.orphan_sources {
+            Some(exclusions) => {
+                let workspace_files =
+                    match crate::git::workspace_files(&self.config.resolver.workspace) {
+                        Ok(files) => Some(files),
+                        Err(err) => {
+                            debug!("listing only queried sources as orphans: {err:#}");
+                            None
+                        }
+                    };
+                graph.orphan_sources(workspace_files.as_deref(), exclusions)
+            }
+            None => Vec::new(),
+        };
+        let GraphDigests {
+            mut results,
+            unhashed,
+            cycles,
+            ..
+        } = self.digest_all(&graph)?;
+
+        if self.config.build_file_targets {
+            for (label, value) in self.build_file_targets(&results)? {
+                self.emit(&label, &value, None, None)?;
+                results.insert(label, value);
             }
         }
 
-        for source in &graph.sources {
-            let digest = target_digest_from_source(
-                source_digests
-                    .get(&source.name)
-                    .ok_or_else(|| anyhow!("missing source digest for {}", source.name))?,
-                &self.config.seed_hash,
-            );
-            let value = TargetHashValue::new(TargetKind::SourceFile, digest);
-            results.insert(source.name.clone(), value);
+        if let Some(writer) = self.checkpoint.take() {
+            writer
+                .into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+                .finish()?;
         }
 
         // Apply target type filtering, if requested.
         if let Some(filter) = &self.config.target_types {
             results.retain(|_, v| filter.contains(v.kind.as_str()));
         }
+        results.retain(|label, _| !self.config.excluded_labels.contains(label));
+
+        let coverage = self.config.budget.as_ref().map(|_| {
+            let unhashed: Vec<&str> = unhashed
+                .iter()
+                .filter(|(label, kind)| {
+                    let kept = match &self.config.target_types {
+                        Some(filter) => filter.contains(kind.as_str()),
+                        None => true,
+                    };
+                    kept && !self.config.excluded_labels.contains(label)
+                })
+                .map(|(label, _)| label.as_str())
+                .collect();
+            HashCoverage::new(results.len(), &unhashed)
+        });
 
+        let exec_only_inputs = graph.exec_only_inputs();
         let mut hashes = BTreeMap::new();
         let mut dep_edges = BTreeMap::new();
+        let mut exec_dep_edges = BTreeMap::new();
         for (label, value) in results {
             hashes.insert(label.clone(), value.render(self.config.include_target_type));
             if let Some(deps) = value.deps {
+                if let Some(exec_only) = exec_only_inputs.get(label.as_str()) {
+                    let exec_deps: Vec<String> = deps
+                        .iter()
+                        .filter(|dep| exec_only.contains(dep.as_str()))
+                        .cloned()
+                        .collect();
+                    if !exec_deps.is_empty() {
+                        exec_dep_edges.insert(label.clone(), exec_deps);
+                    }
+                }
                 dep_edges.insert(label, Some(deps));
             }
         }
 
-        Ok(GenerateHashesResult { hashes, dep_edges })
+        Ok(GenerateHashesResult {
+            hashes,
+            dep_edges,
+            exec_dep_edges,
+            profile: None,
+            orphan_sources,
+            normalized_sources: Vec::new(),
+            file_access: None,
+            coverage,
+            bazel_version: self.config.bazel_version.clone(),
+            test_suites: graph.test_suites(),
+            query_errors: graph.query_errors,
+            approximate: false,
+            hash_function: self.config.hash_function,
+            cycles,
+        })
+    }
+
+    /// Pseudo-targets hashing the BUILD file of every main repository package
+    /// that has targets; packages whose BUILD file cannot be found are skipped.
+    fn build_file_targets(
+        &self,
+        results: &BTreeMap<String, TargetHashValue>,
+    ) -> Result<Vec<(String, TargetHashValue)>> {
+        let packages: BTreeSet<&str> = results
+            .keys()
+            .filter_map(|label| Some(label.strip_prefix("//")?.split_once(':')?.0))
+            .collect();
+        let mut targets = Vec::with_capacity(packages.len());
+        for package in packages {
+            let dir = self.config.resolver.workspace.join(package);
+            let Some(path) = BUILD_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())
+            else {
+                debug!(package, "no BUILD file found for package");
+                continue;
+            };
+            if let Some(stamps) = &self.config.file_stamps {
+                stamps.record(&path, &std::fs::metadata(&path)?);
+            }
+            let contents = std::fs::read(&path)
+                .with_context(|| format!("failed to read BUILD file {}", path.display()))?;
+            if let Some(recorder) = &self.config.file_access {
+                recorder.record(&path, contents.len() as u64, None);
+            }
+            let label = format!("//{package}:{BUILD_FILE_TARGET_NAME}");
+            let mut hasher = self.config.hash_function.hasher();
+            hasher.update(label.as_bytes());
+            hasher.update(&contents);
+            let digest = target_digest_from_source(
+                &hasher.finalize(),
+                &self.config.seed_hash,
+                self.config.hash_function,
+            );
+            targets.push((label, TargetHashValue::new(TargetKind::BuildFile, digest)));
+        }
+        Ok(targets)
     }
 }
 
-fn seed_for_source(source: &BazelSource) -> Vec<u8> {
-    let mut hasher = Sha256::new();
+fn seed_for_source(
+    source: &BazelSource,
+    include_visibility: bool,
+    hash_function: HashFunction,
+) -> Vec<u8> {
+    let mut hasher = hash_function.hasher();
     hasher.update(source.name.as_bytes());
     for sub in &source.subincludes {
         hasher.update(sub.as_bytes());
     }
-    hasher.finalize().to_vec()
+    if include_visibility {
+        hasher.update([0]);
+        for label in &source.visibility {
+            hasher.update(label.as_bytes());
+            hasher.update([0]);
+        }
+    }
+    hasher.finalize()
 }
 
-fn target_digest_from_source(source_digest: &[u8], seed_hash: &[u8]) -> TargetDigest {
-    let mut hasher = Sha256::new();
+fn target_digest_from_source(
+    source_digest: &[u8],
+    seed_hash: &[u8],
+    hash_function: HashFunction,
+) -> TargetDigest {
+    let mut hasher = hash_function.hasher();
     hasher.update(source_digest);
     hasher.update(seed_hash);
-    let digest = hasher.finalize().to_vec();
+    let digest = hasher.finalize();
     TargetDigest {
         overall: digest.clone(),
         direct: digest,
@@ -359,11 +2219,23 @@ fn target_digest_from_source(source_digest: &[u8], seed_hash: &[u8]) -> TargetDi
     }
 }
 
+/// See [`HashEngine::digest_all`].
+struct GraphDigests {
+    results: BTreeMap<String, TargetHashValue>,
+    source_digests: HashMap<String, Vec<u8>>,
+    rule_digests: HashMap<String, TargetDigest>,
+    /// Targets left out because the time budget ran out, with their kind.
+    unhashed: Vec<(String, TargetKind)>,
+    /// See [`GenerateHashesResult::cycles`].
+    cycles: Vec<Vec<String>>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum TargetKind {
     Rule,
     GeneratedFile,
     SourceFile,
+    BuildFile,
 }
 
 impl TargetKind {
@@ -372,6 +2244,7 @@ impl TargetKind {
             TargetKind::Rule => "Rule",
             TargetKind::GeneratedFile => "GeneratedFile",
             TargetKind::SourceFile => "SourceFile",
+            TargetKind::BuildFile => "BuildFile",
         }
     }
 }
@@ -419,19 +2292,35 @@ impl TargetDigest {
             deps,
         }
     }
+
+    /// Mixes the digest Bazel reported for a generated file's contents in.
+    fn with_output(self, output: &str, hash_function: HashFunction) -> Self {
+        let mix = |digest: &[u8]| {
+            let mut hasher = hash_function.hasher();
+            hasher.update(digest);
+            hasher.update(b"\0output\0");
+            hasher.update(output.as_bytes());
+            hasher.finalize()
+        };
+        Self {
+            overall: mix(&self.overall),
+            direct: mix(&self.direct),
+            deps: self.deps,
+        }
+    }
 }
 
 struct DigestBuilder {
-    direct: Sha256,
-    overall: Sha256,
+    direct: Hasher,
+    overall: Hasher,
     deps: Option<Vec<String>>,
 }
 
 impl DigestBuilder {
-    fn new(track_deps: bool) -> Self {
+    fn new(track_deps: bool, hash_function: HashFunction) -> Self {
         Self {
-            direct: Sha256::new(),
-            overall: Sha256::new(),
+            direct: hash_function.hasher(),
+            overall: hash_function.hasher(),
             deps: track_deps.then(Vec::new),
         }
     }
@@ -452,9 +2341,9 @@ impl DigestBuilder {
     }
 
     fn finish(mut self) -> TargetDigest {
-        let direct_bytes = self.direct.finalize().to_vec();
+        let direct_bytes = self.direct.finalize();
         self.overall.update(&direct_bytes);
-        let overall_bytes = self.overall.finalize().to_vec();
+        let overall_bytes = self.overall.finalize();
         TargetDigest {
             overall: overall_bytes,
             direct: direct_bytes,
@@ -463,57 +2352,328 @@ impl DigestBuilder {
     }
 }
 
-#[derive(Debug)]
-struct BazelGraph {
+/// Files that define packages and repositories rather than being inputs.
+const PACKAGE_FILE_NAMES: &[&str] = &[
+    "BUILD",
+    "BUILD.bazel",
+    "MODULE.bazel",
+    "MODULE.bazel.lock",
+    "REPO.bazel",
+    "WORKSPACE",
+    "WORKSPACE.bazel",
+    "WORKSPACE.bzlmod",
+];
+
+/// The directory of the workspace-relative file `path`, `""` at the root, and
+/// its name.
+fn split_dir(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// The label of the workspace-relative file `path` in the deepest of
+/// `packages` containing it, or `None` outside every package.
+fn file_label(path: &str, packages: &HashSet<&str>) -> Option<String> {
+    let mut split = path.len();
+    loop {
+        let dir = &path[..split];
+        if split < path.len() && packages.contains(dir) {
+            return Some(format!("//{dir}:{}", &path[split + 1..]));
+        }
+        match dir.rfind('/') {
+            Some(slash) => split = slash,
+            None if packages.contains("") => return Some(format!("//:{path}")),
+            None => return None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BazelGraph {
+    /// Digest of the raw query result, used to fingerprint checkpoints.
+    digest: Vec<u8>,
     targets: Vec<BazelTarget>,
     rule_map: HashMap<String, BazelRule>,
     sources: Vec<BazelSource>,
     use_cquery: bool,
+    /// Errors Bazel reported loading packages while querying; sorted.
+    query_errors: Vec<QueryError>,
+    /// Checksums of the exec configurations cquery resolved inputs in.
+    exec_configurations: HashSet<String>,
 }
 
 impl BazelGraph {
-    async fn load(
+    /// Members of every `test_suite`, keyed by suite. A suite without `tests`
+    /// holds the tests of its package, which Bazel lists as its inputs.
+    fn test_suites(&self) -> BTreeMap<String, Vec<String>> {
+        self.rule_map
+            .values()
+            .filter(|rule| rule.rule_class == "test_suite")
+            .map(|rule| {
+                let tests = rule
+                    .attributes
+                    .iter()
+                    .find(|attr| attr.name == "tests")
+                    .map(|attr| attr.string_list_value.as_slice())
+                    .filter(|tests| !tests.is_empty())
+                    .unwrap_or(&rule.rule_inputs);
+                let members: BTreeSet<&String> = tests.iter().collect();
+                (rule.name.clone(), members.into_iter().cloned().collect())
+            })
+            .collect()
+    }
+
+    /// Inputs of each rule that cquery only resolved in exec configurations,
+    /// i.e. tools the rule runs rather than code it builds in. Empty unless
+    /// cquery reported its configurations.
+    fn exec_only_inputs(&self) -> HashMap<&str, HashSet<&str>> {
+        if self.exec_configurations.is_empty() {
+            return HashMap::new();
+        }
+        self.rule_map
+            .values()
+            .filter_map(|rule| {
+                let (exec, target): (Vec<_>, Vec<_>) = rule
+                    .input_configurations
+                    .iter()
+                    .partition(|(_, checksum)| self.exec_configurations.contains(checksum));
+                let target: HashSet<&str> =
+                    target.iter().map(|(label, _)| label.as_str()).collect();
+                let exec_only: HashSet<&str> = exec
+                    .iter()
+                    .map(|(label, _)| label.as_str())
+                    .filter(|label| !target.contains(label))
+                    .collect();
+                (!exec_only.is_empty()).then_some((rule.name.as_str(), exec_only))
+            })
+            .collect()
+    }
+
+    /// Main repository files that no rule lists as an input: the files of
+    /// `workspace_files` (workspace-relative paths) in a package of the graph,
+    /// and the source targets of the query. BUILD, repository boundary files
+    /// and the `.bzl` files BUILD files load are not inputs, and never
+    /// orphans either.
+    ///
+    /// A file belongs to the package of the nearest directory with a BUILD
+    /// file; files of packages the graph lacks (excluded, empty, or in a
+    /// nested workspace) are left out rather than given to a parent package.
+    fn orphan_sources(
+        &self,
+        workspace_files: Option<&[String]>,
+        exclusions: &PackageExclusions,
+    ) -> Vec<String> {
+        let referenced: HashSet<&str> = self
+            .rule_map
+            .values()
+            .flat_map(|rule| rule.rule_inputs.iter().chain(&rule.configured_rule_inputs))
+            .chain(self.sources.iter().flat_map(|source| &source.subincludes))
+            .map(|input| input.trim_start_matches('@'))
+            .collect();
+        let mut candidates: BTreeSet<String> = self
+            .sources
+            .iter()
+            .map(|source| source.name.clone())
+            .filter(|name| name.starts_with("//") && !exclusions.contains_label(name))
+            .collect();
+        if let Some(files) = workspace_files {
+            let packages: HashSet<&str> = self
+                .rule_map
+                .keys()
+                .chain(self.sources.iter().map(|source| &source.name))
+                .filter_map(|label| label.strip_prefix("//"))
+                .map(|label| label.rsplit_once(':').map_or(label, |(package, _)| package))
+                .collect();
+            let files: Vec<&str> = files
+                .iter()
+                .map(String::as_str)
+                .filter(|path| !exclusions.contains_path(path))
+                .collect();
+            let build_dirs: HashSet<&str> = files
+                .iter()
+                .map(|path| split_dir(path))
+                .filter(|(_, name)| BUILD_FILE_NAMES.contains(name))
+                .map(|(dir, _)| dir)
+                .collect();
+            let nested_workspaces: Vec<&str> = files
+                .iter()
+                .map(|path| split_dir(path))
+                .filter(|(dir, name)| {
+                    !dir.is_empty() && crate::workspace::WORKSPACE_ROOT_FILES.contains(name)
+                })
+                .map(|(dir, _)| dir)
+                .collect();
+            candidates.extend(
+                files
+                    .iter()
+                    .filter(|path| {
+                        !nested_workspaces.iter().any(|root| {
+                            path.strip_prefix(root)
+                                .is_some_and(|rest| rest.starts_with('/'))
+                        })
+                    })
+                    .filter_map(|path| file_label(path, &build_dirs))
+                    .filter(|label| {
+                        let package = label[2..].split_once(':').map_or("", |(dir, _)| dir);
+                        packages.contains(package)
+                    }),
+            );
+        }
+        candidates
+            .into_iter()
+            .filter(|label| !referenced.contains(label.as_str()))
+            .filter(|label| {
+                let name = label.rsplit(['/', ':']).next().unwrap_or(label);
+                !PACKAGE_FILE_NAMES.contains(&name)
+            })
+            .collect()
+    }
+
+    pub(crate) async fn load(
         opts: &BazelOptions,
         fine_grained_repos: &HashSet<String>,
         exclude_external: bool,
+        exclusions: &PackageExclusions,
     ) -> Result<Self> {
         let mut collected: HashMap<String, Target> = HashMap::new();
-        if opts.use_cquery {
-            let main_targets = run_cquery(opts, "deps(//...:all-targets)").await?;
-            for t in main_targets {
-                if let Some(label) = target_label(&t) {
-                    collected.entry(label.to_string()).or_insert(t);
-                }
+        // Targets are collected as Bazel streams them, never buffering its output.
+        let mut collect = |t: Target| {
+            if let Some(label) = target_label(&t) {
+                collected.entry(label.to_string()).or_insert(t);
             }
-            if !exclude_external {
-                let external = run_query(opts, "'//external:all-targets'").await?;
-                for t in external {
-                    if let Some(label) = target_label(&t) {
-                        collected.entry(label.to_string()).or_insert(t);
-                    }
-                }
+        };
+        let queries = graph_queries(
+            opts.use_cquery,
+            fine_grained_repos,
+            exclude_external,
+            exclusions,
+        )?;
+        let mut errors = BTreeSet::new();
+        let mut exec_configurations = HashSet::new();
+        for (cquery, expression) in queries {
+            if cquery {
+                errors.extend(
+                    stream_cquery(opts, &expression, &mut collect, &mut exec_configurations)
+                        .await?,
+                );
+            } else {
+                errors.extend(stream_query(opts, &expression, &mut collect).await?);
             }
+        }
+        let query_errors: Vec<QueryError> = errors.into_iter().collect();
+        warn_query_errors(&query_errors);
+
+        Ok(Self {
+            query_errors,
+            exec_configurations,
+            ..Self::from_collected(collected, exclude_external, opts.use_cquery)
+        })
+    }
+
+    /// Only `target` and its transitive dependencies.
+    async fn load_deps_of(
+        opts: &BazelOptions,
+        target: &str,
+        exclude_external: bool,
+    ) -> Result<Self> {
+        let expr = format!("deps({})", query_word(target)?);
+        let targets = if opts.use_cquery {
+            run_cquery(opts, &expr).await?
         } else {
-            let mut patterns = vec!["//...:all-targets".to_string()];
-            if !exclude_external {
-                patterns.push("//external:all-targets".to_string());
-            }
-            for repo in fine_grained_repos {
-                patterns.push(format!("{repo}//...:all-targets"));
-            }
-            let expr = build_query_expression(&patterns);
-            let targets = run_query(opts, &expr).await?;
-            for t in targets {
-                if let Some(label) = target_label(&t) {
-                    collected.entry(label.to_string()).or_insert(t);
+            run_query(opts, &expr).await?
+        };
+        Ok(Self::from_targets(
+            targets,
+            exclude_external,
+            opts.use_cquery,
+        ))
+    }
+
+    /// Labels of the targets in `exclusions`.
+    fn labels_in(&self, exclusions: &PackageExclusions) -> HashSet<String> {
+        self.targets
+            .iter()
+            .map(BazelTarget::label)
+            .filter(|label| exclusions.contains_label(label))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Packages of every target, e.g. `//pkg` or `@repo//pkg`.
+    fn packages(&self) -> BTreeSet<String> {
+        self.targets
+            .iter()
+            .map(|target| package_of(target.label()).to_string())
+            .collect()
+    }
+
+    /// Main repository sources that `.gitattributes` marks as generated or
+    /// vendored.
+    fn gitattributes_marked_sources(&self, workspace: &Path) -> Result<HashSet<String>> {
+        let by_path: HashMap<String, &str> = self
+            .sources
+            .iter()
+            .filter_map(|source| {
+                let path = resolve_main_repo(&source.name, workspace)?;
+                Some((path.workspace_relative, source.name.as_str()))
+            })
+            .collect();
+        let paths: Vec<String> = by_path.keys().cloned().collect();
+        Ok(marked_paths(workspace, &paths)?
+            .iter()
+            .filter_map(|path| by_path.get(path).map(|label| label.to_string()))
+            .collect())
+    }
+
+    /// Rules whose `target_compatible_with` rules out the platform, plus the
+    /// files they generate.
+    fn incompatible_labels(&self, constraints: &PlatformConstraints) -> HashSet<String> {
+        let rules: HashSet<String> = self
+            .rule_map
+            .values()
+            .filter(|rule| constraints.excludes(&rule.attributes))
+            .map(|rule| rule.name.clone())
+            .collect();
+        let generated: Vec<String> = self
+            .targets
+            .iter()
+            .filter_map(|target| match target {
+                BazelTarget::Generated(gen) if rules.contains(&gen.generating_rule) => {
+                    Some(gen.name.clone())
                 }
+                _ => None,
+            })
+            .collect();
+        rules.into_iter().chain(generated).collect()
+    }
+
+    /// Builds the graph from a query result produced outside this tool.
+    fn from_targets(targets: Vec<Target>, exclude_external: bool, use_cquery: bool) -> Self {
+        let mut collected: HashMap<String, Target> = HashMap::new();
+        for t in targets {
+            if let Some(label) = target_label(&t) {
+                collected.entry(label.to_string()).or_insert(t);
             }
         }
+        Self::from_collected(collected, exclude_external, use_cquery)
+    }
 
+    fn from_collected(
+        mut collected: HashMap<String, Target>,
+        exclude_external: bool,
+        use_cquery: bool,
+    ) -> Self {
         if exclude_external {
             collected.retain(|label, _| !label.starts_with('@'));
         }
 
+        let mut hasher = Sha256::new();
+        let ordered: BTreeMap<_, _> = collected.iter().collect();
+        for (label, target) in ordered {
+            hasher.update(label.as_bytes());
+            hasher.update(target.encode_to_vec());
+        }
+        let digest = hasher.finalize().to_vec();
+
         let mut targets = Vec::new();
         let mut rule_map = HashMap::new();
         let mut sources = Vec::new();
@@ -530,12 +2690,15 @@ impl BazelGraph {
             }
         }
 
-        Ok(Self {
+        Self {
+            digest,
             targets,
             rule_map,
             sources,
-            use_cquery: opts.use_cquery,
-        })
+            use_cquery,
+            query_errors: Vec::new(),
+            exec_configurations: HashSet::new(),
+        }
     }
 }
 
@@ -547,6 +2710,9 @@ struct BazelRule {
     attributes: Vec<Attribute>,
     rule_inputs: Vec<String>,
     configured_rule_inputs: Vec<String>,
+    /// Configured inputs with the checksum of the configuration cquery
+    /// resolved them in.
+    input_configurations: Vec<(String, String)>,
 }
 
 impl BazelRule {
@@ -562,11 +2728,16 @@ impl BazelRule {
                 .iter()
                 .filter_map(|c| c.label.clone())
                 .collect(),
+            input_configurations: rule
+                .configured_rule_input
+                .iter()
+                .filter_map(|c| Some((c.label.clone()?, c.configuration_checksum.clone()?)))
+                .collect(),
         }
     }
 
-    fn digest(&self, ignored_attrs: &HashSet<String>) -> Vec<u8> {
-        let mut hasher = Sha256::new();
+    fn digest(&self, ignored_attrs: &HashSet<String>, hash_function: HashFunction) -> Vec<u8> {
+        let mut hasher = hash_function.hasher();
         hasher.update(self.rule_class.as_bytes());
         hasher.update(self.name.as_bytes());
         if let Some(env) = &self.skylark_environment_hash_code {
@@ -584,10 +2755,15 @@ impl BazelRule {
             attr.encode(&mut buf).unwrap_or_default();
             hasher.update(&buf);
         }
-        hasher.finalize().to_vec()
+        hasher.finalize()
     }
 
-    fn rule_inputs(&self, use_cquery: bool, fine_grained_repos: &HashSet<String>) -> Vec<String> {
+    fn rule_inputs(
+        &self,
+        use_cquery: bool,
+        fine_grained_repos: &HashSet<String>,
+        rewriter: &dyn RuleInputRewriter,
+    ) -> Vec<String> {
         if use_cquery {
             let mut seen = HashSet::new();
             let mut combined = Vec::new();
@@ -599,7 +2775,7 @@ impl BazelRule {
             for ri in self
                 .rule_inputs
                 .iter()
-                .map(|ri| transform_rule_input(ri, fine_grained_repos))
+                .map(|ri| rewriter.rewrite(ri, fine_grained_repos))
             {
                 if seen.insert(ri.clone()) {
                     combined.push(ri);
@@ -616,6 +2792,8 @@ impl BazelRule {
 struct BazelSource {
     name: String,
     subincludes: Vec<String>,
+    /// Sorted visibility labels and package groups of the file.
+    visibility: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -632,14 +2810,34 @@ enum BazelTarget {
 }
 
 impl BazelTarget {
+    fn label(&self) -> &str {
+        match self {
+            BazelTarget::Rule(rule) => &rule.name,
+            BazelTarget::Source(source) => &source.name,
+            BazelTarget::Generated(gen) => &gen.name,
+        }
+    }
+
     fn from_proto(target: Target) -> Option<Self> {
         if let Some(rule) = target.rule {
             return Some(BazelTarget::Rule(BazelRule::from_proto(&rule)));
         }
         if let Some(source) = target.source_file {
+            let mut visibility: Vec<String> = source
+                .visibility_label
+                .into_iter()
+                .chain(
+                    source
+                        .package_group
+                        .into_iter()
+                        .map(|group| format!("package_group {group}")),
+                )
+                .collect();
+            visibility.sort();
             return Some(BazelTarget::Source(BazelSource {
                 name: source.name,
                 subincludes: source.subinclude,
+                visibility,
             }));
         }
         if let Some(gen) = target.generated_file {
@@ -656,71 +2854,229 @@ impl BazelTarget {
 #[derive(Clone)]
 struct SourceFileHasher {
     resolver: ExternalRepoResolver,
-    content_hashes: Option<HashMap<String, String>>,
+    content_hashes: Option<ContentHashes>,
+    output_digests: Option<HashMap<String, String>>,
     fine_grained_external_repos: HashSet<String>,
     modified_filepaths: HashSet<PathBuf>,
+    path_only_sources: HashSet<String>,
+    structure_only: bool,
+    hash_repo_markers: bool,
+    profiler: Option<HashProfiler>,
+    file_stamps: Option<FileStampRecorder>,
+    file_access: Option<FileAccessRecorder>,
+    content_cache: Option<ContentCache>,
+    normalizer: Option<SourceNormalizer>,
+    hash_function: HashFunction,
 }
 
 impl SourceFileHasher {
     fn new(
         resolver: ExternalRepoResolver,
-        content_hashes: Option<HashMap<String, String>>,
+        content_hashes: Option<ContentHashes>,
         fine_grained_external_repos: HashSet<String>,
         modified_filepaths: HashSet<PathBuf>,
     ) -> Self {
         Self {
             resolver,
             content_hashes,
+            output_digests: None,
             fine_grained_external_repos,
             modified_filepaths,
+            path_only_sources: HashSet::new(),
+            structure_only: false,
+            hash_repo_markers: false,
+            profiler: None,
+            file_stamps: None,
+            file_access: None,
+            content_cache: None,
+            normalizer: None,
+            hash_function: HashFunction::Sha256,
         }
     }
 
+    fn with_path_only_sources(mut self, path_only_sources: HashSet<String>) -> Self {
+        self.path_only_sources = path_only_sources;
+        self
+    }
+
+    fn with_structure_only(mut self, structure_only: bool) -> Self {
+        self.structure_only = structure_only;
+        self
+    }
+
+    fn with_repo_markers(mut self, hash_repo_markers: bool) -> Self {
+        self.hash_repo_markers = hash_repo_markers;
+        self
+    }
+
+    fn with_profiler(mut self, profiler: Option<HashProfiler>) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    fn with_file_stamps(mut self, file_stamps: Option<FileStampRecorder>) -> Self {
+        self.file_stamps = file_stamps;
+        self
+    }
+
+    fn with_file_access(mut self, file_access: Option<FileAccessRecorder>) -> Self {
+        self.file_access = file_access;
+        self
+    }
+
+    fn with_content_cache(mut self, content_cache: Option<ContentCache>) -> Self {
+        self.content_cache = content_cache;
+        self
+    }
+
+    fn with_normalizer(mut self, normalizer: Option<SourceNormalizer>) -> Self {
+        self.normalizer = normalizer;
+        self
+    }
+
+    fn with_output_digests(mut self, output_digests: Option<HashMap<String, String>>) -> Self {
+        self.output_digests = output_digests;
+        self
+    }
+
+    fn with_hash_function(mut self, hash_function: HashFunction) -> Self {
+        self.hash_function = hash_function;
+        self
+    }
+
+    /// Digest BEP reported for the output file `label`, if any.
+    fn output_digest(&self, label: &str) -> Option<&str> {
+        let digests = self.output_digests.as_ref()?;
+        digests
+            .get(&crate::bep::output_path(label)?)
+            .map(String::as_str)
+    }
+
     fn digest(&self, label: &str, seed: &[u8]) -> Result<Vec<u8>> {
-        let mut hasher = Sha256::new();
+        let Some(profiler) = &self.profiler else {
+            return self.digest_counting(label, seed, &mut 0);
+        };
+        let started = Instant::now();
+        let mut bytes_read = 0;
+        let digest = self.digest_counting(label, seed, &mut bytes_read)?;
+        profiler.record_source(label, started.elapsed(), bytes_read);
+        Ok(digest)
+    }
+
+    fn digest_counting(&self, label: &str, seed: &[u8], bytes_read: &mut u64) -> Result<Vec<u8>> {
+        let mut hasher = self.hash_function.hasher();
         if let Some((repo, _)) = split_external_label(label) {
-            if trim_repo_name(repo).ends_with('+') {
-                return Ok(hasher.finalize().to_vec());
+            // Files of bzlmod repositories are hashed by label only, as
+            // upstream does, unless they are fine-grained.
+            if CanonicalRepoName::parse(repo).is_some()
+                && !is_fine_grained(repo, &self.fine_grained_external_repos)
+            {
+                return Ok(hasher.finalize());
             }
         }
         let Some(path) = self.resolve_label(label)? else {
-            return Ok(hasher.finalize().to_vec());
+            return Ok(hasher.finalize());
         };
+        let mut marker = None;
+        if self.hash_repo_markers {
+            if let Some((repo, _)) = split_external_label(label) {
+                marker = self.resolver.marker(&normalize_repo(repo))?;
+            }
+        }
+        if let Some(marker) = &marker {
+            hasher.update(marker);
+        }
+        let mut cache_key = None;
 
-        let relative_key = path.workspace_relative.clone();
         if let Some(map) = &self.content_hashes {
-            if let Some(content_hash) = map.get(&relative_key) {
+            if let Some(content_hash) = map.get(&path.workspace_relative) {
                 hasher.update(content_hash.as_bytes());
                 hasher.update([0x01]);
                 hasher.update(seed);
                 hasher.update(label.as_bytes());
-                return Ok(hasher.finalize().to_vec());
+                return Ok(hasher.finalize());
             }
         }
 
         if path.absolute.exists() {
             if path.absolute.is_file() {
-                if self.modified_filepaths.is_empty()
-                    || self
-                        .modified_filepaths
-                        .iter()
-                        .any(|p| self.resolver.workspace.join(p) == path.absolute)
-                {
+                let read_contents = !self.path_only_sources.contains(label)
+                    && (self.modified_filepaths.is_empty()
+                        || self
+                            .modified_filepaths
+                            .iter()
+                            .any(|p| self.resolver.workspace.join(p) == path.absolute));
+                if read_contents && self.structure_only {
+                    // The size stands in for the contents; the path is hashed
+                    // with the label.
+                    let metadata = std::fs::metadata(&path.absolute).with_context(|| {
+                        format!("failed to stat file {}", path.absolute.display())
+                    })?;
+                    hasher.update(metadata.len().to_le_bytes());
+                } else if read_contents {
+                    // Stat before reading so a write during the read is caught.
+                    let metadata = std::fs::metadata(&path.absolute).ok();
+                    if let (Some(stamps), Some(metadata)) = (&self.file_stamps, &metadata) {
+                        stamps.record(&path.absolute, metadata);
+                    }
+                    // Normalized files are always read, so they are reported.
+                    let cache = self.content_cache.as_ref().filter(|_| {
+                        !self
+                            .normalizer
+                            .as_ref()
+                            .is_some_and(|normalizer| normalizer.applies_to(&path.absolute))
+                    });
+                    if let (Some(cache), Some(metadata)) = (cache, &metadata) {
+                        let context = self.cache_context(label, seed, marker.as_deref());
+                        cache_key = ContentCache::key(&path.absolute, metadata, &context);
+                        if let Some(digest) = cache_key.as_ref().and_then(|key| cache.get(key)) {
+                            return Ok(digest);
+                        }
+                    }
                     let data = std::fs::read(&path.absolute).with_context(|| {
                         format!("failed to read file {}", path.absolute.display())
                     })?;
+                    *bytes_read += data.len() as u64;
+                    if let Some(recorder) = &self.file_access {
+                        let repository =
+                            split_external_label(label).map(|(repo, _)| trim_repo_name(repo));
+                        recorder.record(&path.absolute, data.len() as u64, repository.as_deref());
+                    }
+                    let data = match &self.normalizer {
+                        Some(normalizer) => normalizer.normalize(label, &path.absolute, data),
+                        None => data,
+                    };
                     hasher.update(&data);
                 }
                 hasher.update([0x01]);
             }
         } else {
-            warn!("File {} not found", path.absolute.display());
+            warn!(
+                label,
+                key = %path.workspace_relative,
+                "source file not found"
+            );
             hasher.update([0x00]);
         }
 
         hasher.update(seed);
         hasher.update(label.as_bytes());
-        Ok(hasher.finalize().to_vec())
+        let digest = hasher.finalize();
+        if let (Some(cache), Some(key)) = (&self.content_cache, cache_key) {
+            cache.insert(key, &digest);
+        }
+        Ok(digest)
+    }
+
+    /// Everything a source digest depends on besides the file's contents.
+    fn cache_context(&self, label: &str, seed: &[u8], marker: Option<&[u8]>) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        let function = self.hash_function.as_str().as_bytes();
+        for part in [label.as_bytes(), seed, marker.unwrap_or_default(), function] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().to_vec()
     }
 
     fn soft_digest(&self, label: &str, seed: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -742,19 +3098,17 @@ impl SourceFileHasher {
         }
 
         if let Some((repo, rel)) = split_external_label(label) {
-            let normalized_repo = normalize_repo(repo);
-            if !self
-                .fine_grained_external_repos
-                .iter()
-                .any(|r| normalized_repo == *r)
-            {
+            if !is_fine_grained(repo, &self.fine_grained_external_repos) {
                 return Ok(None);
             }
+            let normalized_repo = normalize_repo(repo);
 
             let repo_root = self.resolver.resolve(&normalized_repo)?;
-            let absolute = repo_root.join(rel.clone());
-            let workspace_relative =
-                format!("external/{}/{}", normalized_repo, rel.to_string_lossy());
+            let rel = paths::relative_key(&rel);
+            let absolute = paths::join_key(&repo_root, &rel);
+            // Keyed by repository name, never by where the repo root points, so
+            // content hash maps stay portable across machines.
+            let workspace_relative = format!("external/{normalized_repo}/{rel}");
             return Ok(Some(ResolvedPath {
                 absolute,
                 workspace_relative,
@@ -777,38 +3131,146 @@ struct ExternalRepoResolver {
     bazel_path: PathBuf,
     startup_options: Vec<String>,
     output_base: PathBuf,
+    /// `--nofetch`/`--repository_cache` flags added to repo-mapping queries.
+    fetch_options: Vec<String>,
+    /// Options applying to every Bazel command, also passed when dumping the
+    /// repo mapping so that it sees the same registries and overrides.
+    command_options: Vec<String>,
+    offline: bool,
+    /// Canonical root of every repository resolved so far, shared by clones.
+    roots: Arc<Mutex<HashMap<String, PathBuf>>>,
+    /// Digest of the `@<repo>.marker` file of every repository resolved so
+    /// far, if Bazel wrote one.
+    markers: Arc<Mutex<HashMap<String, Option<Vec<u8>>>>>,
+    /// The main repository's repo mapping, asked for the first time a
+    /// repository is located and shared by clones; `None` inside when Bazel
+    /// has none.
+    repo_mapping: Arc<OnceLock<Option<RepoMapping>>>,
+    /// Set for hybrid workspaces, whose WORKSPACE repos are never looked up
+    /// under bzlmod names.
+    hybrid: Option<HybridWorkspace>,
 }
 
 impl ExternalRepoResolver {
+    /// Returns the canonical root of `repo`. `local_repository` and
+    /// `local_path_override` repos are symlinks under the output base; resolving
+    /// them once and canonicalizing keeps every file of a repo on the same path.
     fn resolve(&self, repo: &str) -> Result<PathBuf> {
+        if let Some(root) = self.roots.lock().ok().and_then(|r| r.get(repo).cloned()) {
+            return Ok(root);
+        }
+        let root = self.locate(repo)?;
+        if let Ok(mut markers) = self.markers.lock() {
+            markers.insert(repo.to_string(), marker_digest(&root)?);
+        }
+        let root = paths::canonicalize(&root).unwrap_or(root);
+        if let Ok(mut roots) = self.roots.lock() {
+            roots.insert(repo.to_string(), root.clone());
+        }
+        Ok(root)
+    }
+
+    /// Digest of the marker file Bazel wrote when it fetched `repo`, which
+    /// records the fetch inputs (rule attributes, patches, environment).
+    fn marker(&self, repo: &str) -> Result<Option<Vec<u8>>> {
+        self.resolve(repo)?;
+        Ok(self
+            .markers
+            .lock()
+            .ok()
+            .and_then(|markers| markers.get(repo).cloned().flatten()))
+    }
+
+    /// The directory of `repo` under the output base: named by the repo
+    /// mapping when Bazel has one, as is when that directory or its marker
+    /// exists, and otherwise wherever `bazel query` places its packages.
+    fn locate(&self, repo: &str) -> Result<PathBuf> {
         let external_root = self.output_base.join("external");
-        for candidate in [repo.to_string(), format!("{repo}+")] {
-            let path = external_root.join(&candidate);
-            if path.exists() {
-                return Ok(path);
-            }
+        if self
+            .hybrid
+            .as_ref()
+            .is_some_and(|hybrid| hybrid.is_workspace_repo(repo))
+        {
+            return self.check_available(repo, external_root.join(repo));
+        }
+        if let Some(canonical) = self.repo_mapping().and_then(|m| m.canonical(repo)) {
+            return self.check_available(repo, external_root.join(canonical));
+        }
+        // Already a canonical name, or a repository defined in WORKSPACE.
+        let path = external_root.join(repo);
+        if path.exists() || has_marker(&external_root, repo) {
+            return self.check_available(repo, path);
         }
 
         if let Some(path) = self.resolve_bzlmod_path(repo, &external_root)? {
-            return Ok(path);
+            return self.check_available(repo, path);
         }
 
-        Ok(external_root.join(repo))
+        self.check_available(repo, external_root.join(repo))
+    }
+
+    fn repo_mapping(&self) -> Option<&RepoMapping> {
+        self.repo_mapping
+            .get_or_init(|| {
+                let options = [self.command_options.as_slice(), &self.fetch_options].concat();
+                let mapping = RepoMapping::dump(
+                    &self.bazel_path,
+                    &self.startup_options,
+                    &options,
+                    &self.workspace,
+                );
+                if let Some(mapping) = &mapping {
+                    debug!(
+                        repos = mapping.len(),
+                        "read the repo mapping of the main repository"
+                    );
+                }
+                mapping
+            })
+            .as_ref()
+    }
+
+    /// In offline mode a missing or empty repository would silently hash as
+    /// "no files"; report it instead.
+    fn check_available(&self, repo: &str, path: PathBuf) -> Result<PathBuf> {
+        if !self.offline {
+            return Ok(path);
+        }
+        let populated = std::fs::read_dir(&path)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        if !populated {
+            bail!(
+                "external repository @{repo} is not available at {} and --offline forbids \
+                 fetching it; run `bazel fetch` for it or warm the --repositoryCache first",
+                path.display()
+            );
+        }
+        Ok(path)
     }
 
+    /// Asks `bazel query --output location` where the packages of `repo` are,
+    /// for Bazel releases that cannot dump their repo mapping.
     fn resolve_bzlmod_path(&self, repo: &str, external_root: &Path) -> Result<Option<PathBuf>> {
         let mut cmd = std::process::Command::new(&self.bazel_path);
         cmd.args(&self.startup_options);
         cmd.arg("query");
         cmd.arg(format!("@{repo}//..."));
         cmd.arg("--keep_going");
+        cmd.args(&self.fetch_options);
         cmd.arg("--output");
         cmd.arg("location");
         cmd.current_dir(&self.workspace);
 
-        let output = cmd
-            .output()
-            .context("failed to run bazel query for repo mapping")?;
+        let (started, clock) = (SystemTime::now(), Instant::now());
+        let output = cmd.output();
+        record_command(
+            &cmd,
+            started,
+            clock.elapsed(),
+            output.as_ref().ok().map(|output| output.status),
+        );
+        let output = output.context("failed to run bazel query for repo mapping")?;
         if !output.status.success() {
             return Ok(None);
         }
@@ -828,69 +3290,386 @@ impl ExternalRepoResolver {
     }
 }
 
+/// State of [`RuleHasher::connect`].
+struct ComponentWalk<'a> {
+    /// Rules the walk is limited to.
+    members: HashSet<&'a str>,
+    /// Order in which each rule was reached.
+    index: HashMap<&'a str, usize>,
+    /// Lowest index reachable from each rule through rules still on `stack`.
+    low: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    on_stack: HashSet<&'a str>,
+    components: Vec<Vec<&'a str>>,
+}
+
+/// Hashes rules level by level: every rule only depends on rules of earlier
+/// levels, so the rules of one level are hashed in parallel.
 struct RuleHasher<'a> {
-    use_cquery: bool,
-    fine_grained_external_repos: HashSet<String>,
-    ignored_attrs: HashSet<String>,
-    source_hasher: SourceFileHasher,
-    source_digests: &'a mut HashMap<String, Vec<u8>>,
-    rule_digests: &'a mut HashMap<String, TargetDigest>,
-    seed_hash: Vec<u8>,
+    rules: &'a HashMap<String, BazelRule>,
+    /// Inputs of every rule, in hashing order.
+    inputs: HashMap<&'a str, Vec<String>>,
+    ignored_attrs: &'a HashSet<String>,
+    seed_hash: &'a [u8],
+    hash_function: HashFunction,
     track_dep_edges: bool,
+    profiler: Option<&'a HashProfiler>,
+    /// Rules grouped into levels; each rule only depends on rules of earlier
+    /// levels, once the edges in `broken_edges` are left out.
+    levels: Vec<Vec<&'a str>>,
+    /// `(rule, dep)` edges closing a dependency cycle; the rule is hashed with
+    /// the dep's label but not its digest, and the dep is left out of its dep
+    /// edges.
+    broken_edges: HashSet<(&'a str, &'a str)>,
+    /// See [`GenerateHashesResult::cycles`].
+    cycles: Vec<Vec<String>>,
 }
 
 impl<'a> RuleHasher<'a> {
-    fn digest(
-        &mut self,
-        rule: &BazelRule,
-        all_rules: &HashMap<String, BazelRule>,
-        stack: &mut Vec<String>,
-    ) -> Result<TargetDigest> {
-        if let Some(existing) = self.rule_digests.get(&rule.name) {
-            return Ok(existing.clone());
+    fn new(graph: &'a BazelGraph, config: &'a HashEngineConfig) -> Result<Self> {
+        let inputs = graph
+            .rule_map
+            .par_iter()
+            .map(|(name, rule)| {
+                let inputs = rule.rule_inputs(
+                    graph.use_cquery,
+                    &config.fine_grained_external_repos,
+                    config.input_rewriter.as_ref(),
+                );
+                (name.as_str(), inputs)
+            })
+            .collect();
+        let mut hasher = Self {
+            rules: &graph.rule_map,
+            inputs,
+            ignored_attrs: &config.ignored_attrs,
+            seed_hash: &config.seed_hash,
+            hash_function: config.hash_function,
+            track_dep_edges: config.track_dep_edges,
+            profiler: config.profiler.as_ref(),
+            levels: Vec::new(),
+            broken_edges: HashSet::new(),
+            cycles: Vec::new(),
+        };
+        hasher.break_cycles(config.on_cycle)?;
+        hasher.levels = hasher.levels()?;
+        Ok(hasher)
+    }
+
+    /// Rules `name` depends on, other than itself.
+    fn rule_deps(&self, name: &'a str) -> impl Iterator<Item = &'a str> + '_ {
+        self.inputs[name].iter().filter_map(move |input| {
+            let (dep, _) = self.rules.get_key_value(input)?;
+            (dep != name).then_some(dep.as_str())
+        })
+    }
+
+    /// Fills `broken_edges` and `cycles` so the rules form no cycle once the
+    /// broken edges are left out. Each group of rules depending on each other
+    /// is broken at its first member in sorted order, by leaving out the
+    /// edges to it from the group, and the rest of the group is broken the
+    /// same way; the edges broken only depend on the group itself, never on
+    /// the rules depending on it.
+    fn break_cycles(&mut self, policy: CyclePolicy) -> Result<()> {
+        let mut names: Vec<&'a str> = self.rules.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        let mut groups = self.cyclic_components(&names);
+        let (mut broken_edges, mut cycles) = (HashSet::new(), Vec::new());
+        // Popped in sorted order, so the first cycle is the one reported.
+        groups.reverse();
+        while let Some(group) = groups.pop() {
+            let root = group[0];
+            let members: HashSet<&'a str> = group.iter().copied().collect();
+            let paths = self.paths_from(root, &members);
+            for &rule in &group[1..] {
+                if !self.rule_deps(rule).any(|dep| dep == root) {
+                    continue;
+                }
+                let mut cycle = vec![root.to_string()];
+                let mut at = rule;
+                let mut tail = Vec::new();
+                while at != root {
+                    tail.push(at.to_string());
+                    at = paths[at];
+                }
+                cycle.extend(tail.into_iter().rev());
+                cycle.push(root.to_string());
+                match policy {
+                    CyclePolicy::Error => {
+                        bail!("Circular dependency detected: {}", cycle.join(" -> "))
+                    }
+                    CyclePolicy::Break => {}
+                    CyclePolicy::Warn => {
+                        warn!(cycle = %cycle.join(" -> "), "breaking a dependency cycle");
+                    }
+                }
+                broken_edges.insert((rule, root));
+                cycles.push(cycle);
+            }
+            let mut rest = self.cyclic_components(&group[1..]);
+            rest.reverse();
+            groups.extend(rest);
         }
+        cycles.sort();
+        self.broken_edges = broken_edges;
+        self.cycles = cycles;
+        Ok(())
+    }
+
+    /// The groups of at least two rules among `names` that depend on each
+    /// other, following only deps among `names`; each sorted, and ordered by
+    /// their first member.
+    fn cyclic_components(&self, names: &[&'a str]) -> Vec<Vec<&'a str>> {
+        let mut walk = ComponentWalk {
+            members: names.iter().copied().collect(),
+            index: HashMap::with_capacity(names.len()),
+            low: HashMap::with_capacity(names.len()),
+            stack: Vec::new(),
+            on_stack: HashSet::new(),
+            components: Vec::new(),
+        };
+        for &name in names {
+            if !walk.index.contains_key(name) {
+                self.connect(name, &mut walk);
+            }
+        }
+        let mut components = walk.components;
+        for component in &mut components {
+            component.sort_unstable();
+        }
+        components.sort_unstable();
+        components
+    }
 
-        if stack.contains(&rule.name) {
+    /// Tarjan's strongly connected components algorithm, from `name`.
+    fn connect(&self, name: &'a str, walk: &mut ComponentWalk<'a>) {
+        let index = walk.index.len();
+        walk.index.insert(name, index);
+        walk.low.insert(name, index);
+        walk.stack.push(name);
+        walk.on_stack.insert(name);
+        for dep in self.rule_deps(name) {
+            if !walk.members.contains(dep) {
+                continue;
+            }
+            let reached = if !walk.index.contains_key(dep) {
+                self.connect(dep, walk);
+                walk.low[dep]
+            } else if walk.on_stack.contains(dep) {
+                walk.index[dep]
+            } else {
+                continue;
+            };
+            let low = walk.low[name].min(reached);
+            walk.low.insert(name, low);
+        }
+        if walk.low[name] == index {
+            let mut component = Vec::new();
+            while let Some(member) = walk.stack.pop() {
+                walk.on_stack.remove(member);
+                component.push(member);
+                if member == name {
+                    break;
+                }
+            }
+            if component.len() > 1 {
+                walk.components.push(component);
+            }
+        }
+    }
+
+    /// The rule before each of `members` on a shortest path to it from
+    /// `root`, staying within `members`.
+    fn paths_from(&self, root: &'a str, members: &HashSet<&'a str>) -> HashMap<&'a str, &'a str> {
+        let mut previous = HashMap::new();
+        let mut queue = std::collections::VecDeque::from([root]);
+        while let Some(rule) = queue.pop_front() {
+            for dep in self.rule_deps(rule) {
+                if dep != root && members.contains(dep) && !previous.contains_key(dep) {
+                    previous.insert(dep, rule);
+                    queue.push_back(dep);
+                }
+            }
+        }
+        previous
+    }
+
+    /// Rules grouped into levels; each rule only depends on rules of earlier
+    /// levels, apart from the broken edges.
+    fn levels(&self) -> Result<Vec<Vec<&'a str>>> {
+        let mut names: Vec<&'a str> = self.rules.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        let mut level_of = HashMap::with_capacity(names.len());
+        for name in names {
+            self.level(name, &mut level_of, &mut Vec::new())?;
+        }
+        let mut levels: Vec<Vec<&'a str>> = Vec::new();
+        for (name, level) in level_of {
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+            levels[level].push(name);
+        }
+        Ok(levels)
+    }
+
+    fn level(
+        &self,
+        name: &'a str,
+        level_of: &mut HashMap<&'a str, usize>,
+        stack: &mut Vec<&'a str>,
+    ) -> Result<usize> {
+        if let Some(level) = level_of.get(name) {
+            return Ok(*level);
+        }
+        if stack.contains(&name) {
             bail!("Circular dependency detected: {}", stack.join(" -> "));
         }
-        stack.push(rule.name.clone());
+        stack.push(name);
+        let mut level = 0;
+        for dep in self.rule_deps(name) {
+            if !self.broken_edges.contains(&(name, dep)) {
+                level = level.max(self.level(dep, level_of, stack)? + 1);
+            }
+        }
+        stack.pop();
+        level_of.insert(name, level);
+        Ok(level)
+    }
+
+    /// Digests of the inputs of rules still to be hashed that are neither
+    /// rules nor source files of the query result, read from disk where the
+    /// label resolves to a file.
+    fn soft_digests(
+        &self,
+        source_hasher: &SourceFileHasher,
+        source_digests: &HashMap<String, Vec<u8>>,
+        hashed: &HashMap<String, TargetDigest>,
+        skipped: &HashSet<&str>,
+    ) -> Result<HashMap<String, Vec<u8>>> {
+        let mut unknown: Vec<&str> = self
+            .inputs
+            .iter()
+            .filter(|(name, _)| !hashed.contains_key(**name) && !skipped.contains(**name))
+            .flat_map(|(_, inputs)| inputs)
+            .filter(|input| {
+                !self.rules.contains_key(*input) && !source_digests.contains_key(*input)
+            })
+            .map(String::as_str)
+            .collect();
+        unknown.sort_unstable();
+        unknown.dedup();
+        let digests: Vec<Option<(String, Vec<u8>)>> = unknown
+            .par_iter()
+            .map(|input| {
+                let Some(heuristic) = source_hasher.soft_digest(input, &[])? else {
+                    return Ok(None);
+                };
+                let canonical = input
+                    .strip_prefix("@@")
+                    .and_then(|rest| rest.split_once("//"))
+                    .is_some_and(|(repo, _)| CanonicalRepoName::parse(repo).is_some());
+                let adjusted = if canonical {
+                    target_digest_from_source(&heuristic, self.seed_hash, self.hash_function)
+                        .overall
+                } else {
+                    heuristic
+                };
+                Ok(Some((input.to_string(), adjusted)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(digests.into_iter().flatten().collect())
+    }
+
+    /// Rules depending, directly or through other rules, on a source file of
+    /// `graph` that has no digest.
+    fn rules_missing_sources(
+        &self,
+        graph: &BazelGraph,
+        source_digests: &HashMap<String, Vec<u8>>,
+    ) -> Result<HashSet<&'a str>> {
+        let missing: HashSet<&str> = graph
+            .sources
+            .iter()
+            .map(|source| source.name.as_str())
+            .filter(|name| !source_digests.contains_key(*name))
+            .collect();
+        let mut skipped = HashSet::new();
+        for level in &self.levels {
+            for &name in level {
+                let incomplete = self.inputs[name].iter().any(|input| {
+                    missing.contains(input.as_str())
+                        || (input != name && skipped.contains(input.as_str()))
+                });
+                if incomplete {
+                    skipped.insert(name);
+                }
+            }
+        }
+        Ok(skipped)
+    }
 
-        let mut builder = DigestBuilder::new(self.track_dep_edges);
-        let rule_digest = rule.digest(&self.ignored_attrs);
+    /// Hashes every rule not already in `rule_digests` or `skipped`.
+    fn hash_rules(
+        &self,
+        source_digests: &HashMap<String, Vec<u8>>,
+        rule_digests: &mut HashMap<String, TargetDigest>,
+        skipped: &HashSet<&str>,
+        mut on_level: impl FnMut(&[(String, TargetDigest)]) -> Result<()>,
+    ) -> Result<()> {
+        for level in &self.levels {
+            let hashed: Vec<(String, TargetDigest)> = level
+                .par_iter()
+                .filter(|name| !rule_digests.contains_key(**name) && !skipped.contains(**name))
+                .map(|name| {
+                    let digest = self.digest(&self.rules[*name], source_digests, rule_digests);
+                    (name.to_string(), digest)
+                })
+                .collect();
+            on_level(&hashed)?;
+            rule_digests.extend(hashed);
+        }
+        Ok(())
+    }
+
+    /// Hashes `rule`; every rule it depends on must be in `rule_digests`.
+    fn digest(
+        &self,
+        rule: &BazelRule,
+        source_digests: &HashMap<String, Vec<u8>>,
+        rule_digests: &HashMap<String, TargetDigest>,
+    ) -> TargetDigest {
+        let started = Instant::now();
+        let mut builder = DigestBuilder::new(self.track_dep_edges, self.hash_function);
+        let rule_digest = rule.digest(self.ignored_attrs, self.hash_function);
         if cfg!(debug_assertions) {
             debug!(
                 rule = %rule.name,
                 rule_digest = %hex_encode(&rule_digest),
-                seed_hash = %hex_encode(&self.seed_hash),
+                seed_hash = %hex_encode(self.seed_hash),
                 "rule digest inputs"
             );
         }
 
         builder.put_direct(&rule_digest);
-        builder.put_direct(&self.seed_hash);
-
-        let seed = Vec::new();
+        builder.put_direct(self.seed_hash);
 
-        let inputs = rule.rule_inputs(self.use_cquery, &self.fine_grained_external_repos);
+        let inputs = &self.inputs[rule.name.as_str()];
         debug!(rule = %rule.name, inputs = ?inputs, "hashing rule");
 
         for input in inputs {
             builder.put_direct(input.as_bytes());
-            if let Some(dep_rule) = all_rules.get(&input) {
-                if dep_rule.name != rule.name {
-                    let dep_digest = self.digest(dep_rule, all_rules, stack)?;
-                    builder.put_transitive(&input, &dep_digest.overall);
+            if let Some((dep, _)) = self.rules.get_key_value(input) {
+                // A broken edge is left out of the dep edges too, so they
+                // have no cycle for impact analysis to trip over.
+                let broken = self
+                    .broken_edges
+                    .contains(&(rule.name.as_str(), dep.as_str()));
+                if *input != rule.name && !broken {
+                    builder.put_transitive(input, &rule_digests[input].overall);
                 }
-            } else if let Some(source_digest) = self.source_digests.get(&input) {
+            } else if let Some(source_digest) = source_digests.get(input) {
                 builder.put_direct(source_digest);
-            } else if let Some(heuristic) = self.source_hasher.soft_digest(&input, &seed)? {
-                let adjusted = if input.starts_with("@@") && input.contains('+') {
-                    target_digest_from_source(&heuristic, &self.seed_hash).overall
-                } else {
-                    heuristic.clone()
-                };
-                self.source_digests.insert(input.clone(), adjusted.clone());
-                builder.put_direct(&adjusted);
             } else {
                 warn!(
                     "Unable to calculate digest for input {} of rule {}",
@@ -899,8 +3678,6 @@ impl<'a> RuleHasher<'a> {
             }
         }
 
-        stack.pop();
-
         let digest = builder.finish();
         if rule.name.contains("pkg:core") || rule.name.contains("pkg:tool") {
             debug!(
@@ -911,37 +3688,21 @@ impl<'a> RuleHasher<'a> {
                 "rule digest result"
             );
         }
-        self.rule_digests.insert(rule.name.clone(), digest.clone());
-        Ok(digest)
-    }
-
-    fn digest_generated(
-        &mut self,
-        generated: &BazelGenerated,
-        all_rules: &HashMap<String, BazelRule>,
-        stack: &mut Vec<String>,
-    ) -> Result<TargetDigest> {
-        let rule = all_rules.get(&generated.generating_rule).ok_or_else(|| {
-            anyhow!(
-                "Missing generating rule {} for {}",
-                generated.generating_rule,
-                generated.name
-            )
-        })?;
-        let digest = self.digest(rule, all_rules, stack)?;
-        Ok(digest.clone_with_deps(Some(vec![generated.generating_rule.clone()])))
+        if let Some(profiler) = self.profiler {
+            profiler.record_rule(&rule.name, started.elapsed());
+        }
+        digest
     }
 }
 
-fn transform_rule_input(input: &str, fine_grained: &HashSet<String>) -> String {
+pub(crate) fn transform_rule_input(input: &str, fine_grained: &HashSet<String>) -> String {
     let trimmed = input.trim_start_matches('@');
     if is_not_main_repo(trimmed) {
         let mut parts = trimmed.splitn(2, "//");
         if let Some(repo_part) = parts.next() {
-            let normalized = normalize_repo(repo_part);
-            if fine_grained.contains(&normalized) {
+            if is_fine_grained(repo_part, fine_grained) {
                 let remainder = parts.next().unwrap_or_default();
-                let canonical_repo = if repo_part.ends_with('+') {
+                let canonical_repo = if CanonicalRepoName::parse(repo_part).is_some() {
                     repo_part.to_string()
                 } else {
                     format!("{repo_part}+")
@@ -973,7 +3734,7 @@ fn resolve_main_repo(label: &str, workspace: &Path) -> Option<ResolvedPath> {
     let trimmed = &label[prefix_len..];
     let normalized = trimmed.trim_start_matches(':');
     let relative = normalized.replace(':', "/");
-    let abs = workspace.join(&relative);
+    let abs = paths::join_key(workspace, &relative);
     Some(ResolvedPath {
         absolute: abs,
         workspace_relative: relative,
@@ -996,6 +3757,22 @@ fn split_external_label(label: &str) -> Option<(&str, PathBuf)> {
     Some((repo, rel))
 }
 
+/// Digest of `<output_base>/external/@<name>.marker` for the repository
+/// directory `<output_base>/external/<name>`; `None` when there is none.
+fn marker_digest(repo_dir: &Path) -> Result<Option<Vec<u8>>> {
+    let (Some(external), Some(name)) = (repo_dir.parent(), repo_dir.file_name()) else {
+        return Ok(None);
+    };
+    let path = external.join(format!("@{}.marker", name.to_string_lossy()));
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(Some(Sha256::digest(contents).to_vec())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("failed to read repository marker {}", path.display()))
+        }
+    }
+}
+
 fn trim_repo_name(repo: &str) -> String {
     repo.trim_start_matches('@').to_string()
 }
@@ -1004,6 +3781,15 @@ fn normalize_repo(repo: &str) -> String {
     trim_repo_name(repo)
 }
 
+/// Whether `repo` is one of the fine-grained repositories, listed either by
+/// its canonical name or by the apparent name of a canonical `repo`.
+fn is_fine_grained(repo: &str, fine_grained: &HashSet<String>) -> bool {
+    let name = normalize_repo(repo);
+    fine_grained.contains(&name)
+        || CanonicalRepoName::parse(&name)
+            .is_some_and(|canonical| fine_grained.contains(canonical.apparent_name()))
+}
+
 fn target_label(target: &Target) -> Option<&str> {
     target
         .rule
@@ -1106,6 +3892,23 @@ mod tests {
         }
     });
 
+    #[test]
+    fn extension_repos_keep_their_canonical_names() {
+        let fine = HashSet::from(["pypi__foo".to_string(), "rules_go".to_string()]);
+        assert_eq!(
+            transform_rule_input("@@rules_python++pip+pypi__foo//:pkg", &fine),
+            "@@rules_python++pip+pypi__foo//:pkg"
+        );
+        assert_eq!(
+            transform_rule_input("@@rules_go~//go:def", &fine),
+            "@@rules_go~//go:def"
+        );
+        assert_eq!(
+            transform_rule_input("@@rules_python++pip+pypi__bar//:pkg", &fine),
+            "//external:rules_python++pip+pypi__bar"
+        );
+    }
+
     seq!(N in 0..160 {
         #[test]
         fn split_external_label_variants_~N() {
@@ -1134,6 +3937,85 @@ mod tests {
         }
     });
 
+    #[test]
+    fn workspace_specs_namespace_all_but_first() -> Result<()> {
+        let specs = workspace_specs_from_paths(&[
+            PathBuf::from("/repo"),
+            PathBuf::from("/repo/experimental/ws2"),
+        ])?;
+        assert_eq!(specs[0].namespace, None);
+        assert_eq!(specs[1].namespace.as_deref(), Some("ws2"));
+
+        let dup = workspace_specs_from_paths(&[
+            PathBuf::from("/repo"),
+            PathBuf::from("/a/ws2"),
+            PathBuf::from("/b/ws2"),
+        ]);
+        assert!(dup.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_namespaced_prefixes_labels_and_deps() {
+        let mut merged = GenerateHashesResult {
+            hashes: BTreeMap::new(),
+            dep_edges: BTreeMap::new(),
+            exec_dep_edges: BTreeMap::new(),
+            profile: None,
+            orphan_sources: Vec::new(),
+            normalized_sources: Vec::new(),
+            file_access: None,
+            coverage: None,
+            bazel_version: None,
+            query_errors: Vec::new(),
+            test_suites: BTreeMap::new(),
+            approximate: false,
+            hash_function: HashFunction::Sha256,
+            cycles: Vec::new(),
+        };
+        let result = GenerateHashesResult {
+            hashes: BTreeMap::from([("//pkg:a".to_string(), "h".to_string())]),
+            dep_edges: BTreeMap::from([("//pkg:a".to_string(), Some(vec!["//pkg:b".to_string()]))]),
+            exec_dep_edges: BTreeMap::from([("//pkg:a".to_string(), vec!["//pkg:b".to_string()])]),
+            profile: None,
+            orphan_sources: vec!["//pkg:unused.txt".to_string()],
+            normalized_sources: Vec::new(),
+            file_access: None,
+            coverage: None,
+            bazel_version: None,
+            query_errors: Vec::new(),
+            test_suites: BTreeMap::from([(
+                "//pkg:suite".to_string(),
+                vec!["//pkg:a_test".to_string()],
+            )]),
+            approximate: false,
+            hash_function: HashFunction::Sha256,
+            cycles: vec![vec!["//pkg:a".to_string(), "//pkg:a".to_string()]],
+        };
+        merge_namespaced(&mut merged, result, Some("ws2"), None);
+        assert_eq!(
+            merged.hashes.get("ws2!//pkg:a").map(String::as_str),
+            Some("h")
+        );
+        assert_eq!(
+            merged.dep_edges.get("ws2!//pkg:a"),
+            Some(&Some(vec!["ws2!//pkg:b".to_string()]))
+        );
+        assert_eq!(
+            merged.exec_dep_edges.get("ws2!//pkg:a"),
+            Some(&vec!["ws2!//pkg:b".to_string()])
+        );
+        assert_eq!(merged.orphan_sources, vec!["ws2!//pkg:unused.txt"]);
+        assert_eq!(merged.cycles, [["ws2!//pkg:a", "ws2!//pkg:a"]]);
+        assert_eq!(
+            merged.test_suites,
+            BTreeMap::from([(
+                "ws2!//pkg:suite".to_string(),
+                vec!["ws2!//pkg:a_test".to_string()]
+            )])
+        );
+    }
+
     #[test]
     fn transform_rule_input_main_repo_is_identity() {
         let fine = HashSet::new();
@@ -1150,6 +4032,13 @@ mod tests {
             bazel_path: PathBuf::from("bazel"),
             startup_options: Vec::new(),
             output_base: tmp.path().join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
         };
         let hasher = SourceFileHasher::new(
             resolver,
@@ -1168,6 +4057,1269 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn hybrid_workspace_repos_resolve_under_their_own_name() -> Result<()> {
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/generate/hybrid");
+        let tmp = tempfile::tempdir()?;
+        let external = tmp.path().join("out/external");
+        for dir in ["legacy_toolchain+", "rules_go+"] {
+            std::fs::create_dir_all(external.join(dir))?;
+        }
+        let resolver = ExternalRepoResolver {
+            workspace: tmp.path().to_path_buf(),
+            bazel_path: PathBuf::from("bazel"),
+            startup_options: Vec::new(),
+            output_base: tmp.path().join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: HybridWorkspace::detect(&fixture, &[])?,
+        };
+        let mapping = RepoMapping::parse_dump(r#"{"rules_go":"rules_go+"}"#)?;
+        let resolver = ExternalRepoResolver {
+            repo_mapping: Arc::new(OnceLock::from(mapping)),
+            ..resolver
+        };
+        assert_eq!(resolver.locate("rules_go")?, external.join("rules_go+"));
+        assert_eq!(
+            resolver.locate("legacy_toolchain")?,
+            external.join("legacy_toolchain")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn repos_are_located_through_the_repo_mapping_then_markers() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let external = tmp.path().join("out/external");
+        for dir in ["rules_go", "rules_go+", "rules_go++go_sdk+go_sdk"] {
+            std::fs::create_dir_all(external.join(dir))?;
+        }
+        std::fs::write(external.join("@unfetched.marker"), "")?;
+        let mapping = RepoMapping::parse_dump(
+            r#"{"rules_go":"rules_go+","go_sdk":"rules_go++go_sdk+go_sdk"}"#,
+        )?;
+        let resolver = ExternalRepoResolver {
+            workspace: tmp.path().to_path_buf(),
+            bazel_path: PathBuf::from("false"),
+            startup_options: Vec::new(),
+            output_base: tmp.path().join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Arc::new(OnceLock::from(mapping)),
+            hybrid: None,
+        };
+        // The mapping wins over a stale directory named like the repository.
+        assert_eq!(resolver.locate("rules_go")?, external.join("rules_go+"));
+        assert_eq!(
+            resolver.locate("go_sdk")?,
+            external.join("rules_go++go_sdk+go_sdk")
+        );
+        assert_eq!(resolver.locate("rules_go+")?, external.join("rules_go+"));
+        assert_eq!(resolver.locate("unfetched")?, external.join("unfetched"));
+        Ok(())
+    }
+
+    #[test]
+    fn content_cache_hits_skip_reads_and_keep_digests() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(workspace.join("data.txt"), "contents")?;
+        std::fs::File::options()
+            .write(true)
+            .open(workspace.join("data.txt"))?
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(60))?;
+        let resolver = ExternalRepoResolver {
+            workspace: workspace.to_path_buf(),
+            bazel_path: PathBuf::from("bazel"),
+            startup_options: Vec::new(),
+            output_base: workspace.join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
+        };
+        let uncached =
+            SourceFileHasher::new(resolver.clone(), None, HashSet::new(), HashSet::new())
+                .digest("//:data.txt", b"seed")?;
+
+        let cache_path = workspace.join("cache.msgpack");
+        let run = || -> Result<(Vec<u8>, FileAccessAudit)> {
+            let cache = ContentCache::load(&cache_path)?;
+            let recorder = FileAccessRecorder::new();
+            let hasher =
+                SourceFileHasher::new(resolver.clone(), None, HashSet::new(), HashSet::new())
+                    .with_content_cache(Some(cache.clone()))
+                    .with_file_access(Some(recorder.clone()));
+            let digest = hasher.digest("//:data.txt", b"seed")?;
+            cache.save()?;
+            Ok((digest, recorder.report(workspace)))
+        };
+        let (first, first_reads) = run()?;
+        let (second, second_reads) = run()?;
+        assert_eq!(first, uncached);
+        assert_eq!(second, uncached);
+        assert_eq!(first_reads.files.len(), 1);
+        assert!(second_reads.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn profiler_records_source_bytes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(workspace.join("data.bin"), vec![7u8; 1024])?;
+        let resolver = ExternalRepoResolver {
+            workspace: workspace.to_path_buf(),
+            bazel_path: PathBuf::from("bazel"),
+            startup_options: Vec::new(),
+            output_base: workspace.join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
+        };
+        let profiler = HashProfiler::new();
+        let hasher = SourceFileHasher::new(resolver, None, HashSet::new(), HashSet::new())
+            .with_profiler(Some(profiler.clone()));
+        hasher.digest("//:data.bin", b"seed")?;
+
+        let report = profiler.report(5);
+        assert_eq!(report.source_count, 1);
+        assert_eq!(report.slowest_sources[0].label, "//:data.bin");
+        assert_eq!(report.slowest_sources[0].bytes, Some(1024));
+        Ok(())
+    }
+
+    fn engine_config(workspace: &Path) -> HashEngineConfig {
+        HashEngineConfig {
+            include_target_type: true,
+            excluded_labels: HashSet::new(),
+            target_types: None,
+            ignored_attrs: HashSet::new(),
+            fine_grained_external_repos: HashSet::new(),
+            seed_hash: Vec::new(),
+            content_hashes: None,
+            output_digests: None,
+            modified_filepaths: HashSet::new(),
+            path_only_sources: HashSet::new(),
+            track_dep_edges: true,
+            resolver: ExternalRepoResolver {
+                workspace: workspace.to_path_buf(),
+                bazel_path: PathBuf::from("bazel"),
+                startup_options: Vec::new(),
+                output_base: workspace.join("out"),
+                fetch_options: Vec::new(),
+                command_options: Vec::new(),
+                offline: false,
+                roots: Default::default(),
+                markers: Default::default(),
+                repo_mapping: Default::default(),
+                hybrid: None,
+            },
+            profiler: None,
+            file_stamps: None,
+            file_access: None,
+            content_cache: None,
+            jobs: None,
+            budget: None,
+            input_rewriter: Arc::new(DefaultRuleInputRewriter),
+            build_file_targets: false,
+            orphan_sources: None,
+            normalizer: None,
+            hash_source_visibility: false,
+            hash_function: HashFunction::Sha256,
+            on_cycle: CyclePolicy::Error,
+            structure_only: false,
+            hash_repo_markers: false,
+            bazel_version: None,
+            previous: None,
+        }
+    }
+
+    fn source_and_rule_graph() -> BazelGraph {
+        let source = BazelSource {
+            name: "//:a.txt".to_string(),
+            subincludes: Vec::new(),
+            visibility: Vec::new(),
+        };
+        let rule = BazelRule {
+            name: "//:lib".to_string(),
+            rule_class: "genrule".to_string(),
+            skylark_environment_hash_code: None,
+            attributes: Vec::new(),
+            rule_inputs: vec!["//:a.txt".to_string()],
+            configured_rule_inputs: Vec::new(),
+            input_configurations: Vec::new(),
+        };
+        BazelGraph {
+            digest: vec![1],
+            targets: vec![
+                BazelTarget::Source(source.clone()),
+                BazelTarget::Rule(rule.clone()),
+            ],
+            rule_map: HashMap::from([(rule.name.clone(), rule)]),
+            sources: vec![source],
+            use_cquery: false,
+            query_errors: Vec::new(),
+            exec_configurations: HashSet::new(),
+        }
+    }
+
+    fn rule(name: &str, inputs: &[&str]) -> BazelRule {
+        BazelRule {
+            name: name.to_string(),
+            rule_class: "genrule".to_string(),
+            skylark_environment_hash_code: None,
+            attributes: Vec::new(),
+            rule_inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            configured_rule_inputs: Vec::new(),
+            input_configurations: Vec::new(),
+        }
+    }
+
+    fn rule_graph(rules: Vec<BazelRule>) -> BazelGraph {
+        BazelGraph {
+            digest: vec![1],
+            targets: rules.iter().cloned().map(BazelTarget::Rule).collect(),
+            rule_map: rules.into_iter().map(|r| (r.name.clone(), r)).collect(),
+            sources: Vec::new(),
+            use_cquery: false,
+            query_errors: Vec::new(),
+            exec_configurations: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn parallel_levels_match_a_single_thread() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        // A diamond on top of a chain, so levels have several rules each.
+        let graph = || {
+            rule_graph(vec![
+                rule("//:top", &["//:left", "//:right"]),
+                rule("//:left", &["//:base", "//:left"]),
+                rule("//:right", &["//:base"]),
+                rule("//:base", &["//:leaf"]),
+                rule("//:leaf", &[]),
+            ])
+        };
+        let hash = |jobs| -> Result<GenerateHashesResult> {
+            let config = HashEngineConfig {
+                jobs: Some(jobs),
+                ..engine_config(tmp.path())
+            };
+            HashEngine::new(config).compute(graph())
+        };
+        let serial = hash(1)?;
+        let parallel = hash(4)?;
+        assert_eq!(serial.hashes, parallel.hashes);
+        assert_eq!(serial.dep_edges, parallel.dep_edges);
+        assert_eq!(
+            serial.dep_edges["//:top"],
+            Some(vec!["//:left".to_string(), "//:right".to_string()])
+        );
+
+        let cycle = rule_graph(vec![rule("//:a", &["//:b"]), rule("//:b", &["//:a"])]);
+        let err = HashEngine::new(engine_config(tmp.path()))
+            .compute(cycle)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Circular dependency detected: //:a -> //:b"));
+        Ok(())
+    }
+
+    #[test]
+    fn cycles_are_broken_at_the_edge_closing_them() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let graph = |a_class: &str| {
+            rule_graph(vec![
+                rule("//:app", &["//:a"]),
+                BazelRule {
+                    rule_class: a_class.to_string(),
+                    ..rule("//:a", &["//:b"])
+                },
+                rule("//:b", &["//:a"]),
+            ])
+        };
+        let hash = |on_cycle, jobs, a_class| {
+            let config = HashEngineConfig {
+                on_cycle,
+                jobs: Some(jobs),
+                ..engine_config(tmp.path())
+            };
+            HashEngine::new(config).compute(graph(a_class))
+        };
+        let err = hash(CyclePolicy::Error, 1, "genrule").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Circular dependency detected: //:a -> //:b -> //:a"
+        );
+
+        let broken = hash(CyclePolicy::Break, 1, "genrule")?;
+        let cycle = ["//:a", "//:b", "//:a"].map(String::from).to_vec();
+        assert_eq!(broken.cycles, [cycle]);
+        // The broken edge is left out of the dep edges as well.
+        assert_eq!(broken.dep_edges["//:b"], Some(Vec::new()));
+        let warned = hash(CyclePolicy::Warn, 4, "genrule")?;
+        assert_eq!(broken.hashes, warned.hashes);
+        assert_eq!(broken.cycles, warned.cycles);
+
+        let changed = hash(CyclePolicy::Break, 1, "cc_library")?;
+        assert_eq!(broken.hashes["//:b"], changed.hashes["//:b"]);
+        assert_ne!(broken.hashes["//:a"], changed.hashes["//:a"]);
+        assert_ne!(broken.hashes["//:app"], changed.hashes["//:app"]);
+        Ok(())
+    }
+
+    #[test]
+    fn cycles_are_broken_the_same_way_whatever_depends_on_them() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hash = |rules: Vec<BazelRule>| {
+            let config = HashEngineConfig {
+                on_cycle: CyclePolicy::Break,
+                ..engine_config(tmp.path())
+            };
+            HashEngine::new(config).compute(rule_graph(rules))
+        };
+        let cycle = || {
+            vec![
+                rule("//pkg:a", &["//pkg:b"]),
+                rule("//pkg:b", &["//pkg:a", "//pkg:c"]),
+                rule("//pkg:c", &["//pkg:b"]),
+            ]
+        };
+        let alone = hash(cycle())?;
+        // Sorted first, so a walk from it enters the cycle at `//pkg:b`.
+        let entered_elsewhere = hash([vec![rule("//pkg:0", &["//pkg:b"])], cycle()].concat())?;
+        for member in ["//pkg:a", "//pkg:b", "//pkg:c"] {
+            assert_eq!(alone.hashes[member], entered_elsewhere.hashes[member]);
+        }
+        let cycles = |cycles: &[&[&str]]| -> Vec<Vec<String>> {
+            cycles
+                .iter()
+                .map(|cycle| cycle.iter().map(|l| l.to_string()).collect())
+                .collect()
+        };
+        let expected = cycles(&[
+            &["//pkg:a", "//pkg:b", "//pkg:a"],
+            &["//pkg:b", "//pkg:c", "//pkg:b"],
+        ]);
+        assert_eq!(alone.cycles, expected);
+        assert_eq!(entered_elsewhere.cycles, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn deps_only_resolved_in_exec_configurations_are_recorded_apart() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let configured = |name: &str, inputs: &[(&str, &str)]| BazelRule {
+            configured_rule_inputs: inputs.iter().map(|(l, _)| l.to_string()).collect(),
+            input_configurations: inputs
+                .iter()
+                .map(|(label, checksum)| (label.to_string(), checksum.to_string()))
+                .collect(),
+            ..rule(name, &[])
+        };
+        let graph = BazelGraph {
+            use_cquery: true,
+            exec_configurations: HashSet::from(["exec".to_string()]),
+            ..rule_graph(vec![
+                configured(
+                    "//:app",
+                    &[
+                        ("//:lib", "target"),
+                        ("//:protoc", "exec"),
+                        ("//:both", "target"),
+                        ("//:both", "exec"),
+                    ],
+                ),
+                configured("//:lib", &[("//:protoc", "exec")]),
+                rule("//:protoc", &[]),
+                rule("//:both", &[]),
+            ])
+        };
+
+        let result = HashEngine::new(engine_config(tmp.path())).compute(graph)?;
+        assert_eq!(
+            result.dep_edges["//:app"],
+            Some(vec![
+                "//:lib".to_string(),
+                "//:protoc".to_string(),
+                "//:both".to_string()
+            ])
+        );
+        assert_eq!(
+            result.exec_dep_edges,
+            BTreeMap::from([
+                ("//:app".to_string(), vec!["//:protoc".to_string()]),
+                ("//:lib".to_string(), vec!["//:protoc".to_string()]),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn explain_matches_the_full_run_and_lists_inputs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), b"contents")?;
+        let full = HashEngine::new(engine_config(tmp.path())).compute(source_and_rule_graph())?;
+
+        let report = HashEngine::new(engine_config(tmp.path()))
+            .explain(&source_and_rule_graph(), "//:lib")?;
+        assert_eq!(report.hash, full.hashes["//:lib"]);
+        assert_eq!(report.kind, "Rule");
+        assert!(report.attributes_digest.is_some());
+        assert_eq!(report.inputs.len(), 1);
+        assert_eq!(report.inputs[0].label, "//:a.txt");
+        assert_eq!(report.inputs[0].kind, InputKind::File);
+        assert!(report.inputs[0].digest.is_some());
+
+        let source = HashEngine::new(engine_config(tmp.path()))
+            .explain(&source_and_rule_graph(), "//:a.txt")?;
+        assert_eq!(source.hash, full.hashes["//:a.txt"]);
+        assert_eq!(source.attributes_digest, None);
+
+        let missing = HashEngine::new(engine_config(tmp.path()))
+            .explain(&source_and_rule_graph(), "//:nope")
+            .unwrap_err();
+        assert!(missing.to_string().contains("not in the query result"));
+        Ok(())
+    }
+
+    #[test]
+    fn previous_digests_are_reused_for_unchanged_files_only() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("a.txt");
+        std::fs::write(&file, b"contents")?;
+        File::options()
+            .write(true)
+            .open(&file)?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        let source = |subincludes: Vec<String>| BazelSource {
+            name: "//:a.txt".to_string(),
+            subincludes,
+            visibility: Vec::new(),
+        };
+        let previous = |changed, stamp| PreviousSources {
+            digests: HashMap::from([(
+                "//:a.txt".to_string(),
+                SourceRecord {
+                    digest: vec![7],
+                    stamp,
+                },
+            )]),
+            changed,
+        };
+        let stamp = SourceStamp::of(&file);
+        assert!(stamp.is_some());
+        let reused = |changed, stamp, source: &BazelSource| {
+            previous(changed, stamp).digest(source, tmp.path())
+        };
+
+        let plain = source(Vec::new());
+        assert_eq!(
+            reused(ChangedSince::Paths(HashSet::new()), None, &plain),
+            Some(vec![7])
+        );
+        let listed = HashSet::from([PathBuf::from("a.txt")]);
+        assert_eq!(reused(ChangedSince::Paths(listed), stamp, &plain), None);
+        assert_eq!(reused(ChangedSince::Stamp, stamp, &plain), Some(vec![7]));
+        assert_eq!(reused(ChangedSince::Stamp, None, &plain), None);
+        let other = stamp.map(|stamp| SourceStamp {
+            size: stamp.size + 1,
+            ..stamp
+        });
+        assert_eq!(reused(ChangedSince::Stamp, other, &plain), None);
+        let with_subincludes = source(vec!["//:defs.bzl".to_string()]);
+        assert_eq!(
+            reused(
+                ChangedSince::Paths(HashSet::new()),
+                stamp,
+                &with_subincludes
+            ),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resumed_records_skip_rehashing_until_the_file_changes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        let file = workspace.join("a.txt");
+        let a_minute_ago = SystemTime::now() - Duration::from_secs(60);
+        let write = |contents: &[u8], modified: SystemTime| -> Result<()> {
+            std::fs::write(&file, contents)?;
+            Ok(File::options()
+                .write(true)
+                .open(&file)?
+                .set_modified(modified)?)
+        };
+        write(b"contents", a_minute_ago)?;
+        let fingerprints = Fingerprints {
+            run: "fp",
+            sources: None,
+        };
+        let first_path = workspace.join("first.ndjson");
+        let first = HashEngine::new(engine_config(workspace))
+            .with_checkpoint(
+                CheckpointWriter::create(&first_path, fingerprints)?,
+                HashMap::new(),
+            )
+            .compute(source_and_rule_graph())?;
+        let resume = |name: &str| -> Result<GenerateHashesResult> {
+            let (writer, resumed) = CheckpointWriter::open(
+                &CheckpointConfig {
+                    output: workspace.join(name),
+                    resume_from: Some(first_path.clone()),
+                },
+                fingerprints,
+            )?;
+            assert_eq!(resumed.len(), 2);
+            HashEngine::new(engine_config(workspace))
+                .with_checkpoint(writer, resumed)
+                .compute(source_and_rule_graph())
+        };
+
+        // Same size, time and inode: hashing it again would change both digests.
+        write(b"CONTENTS", a_minute_ago)?;
+        let second = resume("second.ndjson")?;
+        assert_eq!(first.hashes, second.hashes);
+        assert_eq!(first.dep_edges, second.dep_edges);
+        assert_eq!(
+            crate::checkpoint::read_ndjson_hashes(&workspace.join("second.ndjson"))?.len(),
+            2
+        );
+
+        write(b"CONTENTS", a_minute_ago + Duration::from_secs(1))?;
+        let third = resume("third.ndjson")?;
+        let fresh = HashEngine::new(engine_config(workspace)).compute(source_and_rule_graph())?;
+        assert_ne!(first.hashes, third.hashes);
+        assert_eq!(fresh.hashes, third.hashes);
+        let written = crate::checkpoint::read_ndjson_hashes(&workspace.join("third.ndjson"))?;
+        assert_eq!(written["//:a.txt"].raw(), third.hashes["//:a.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_records_are_written_level_by_level() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("hashes.ndjson");
+        let writer = CheckpointWriter::create(
+            &path,
+            Fingerprints {
+                run: "fp",
+                sources: None,
+            },
+        )?;
+        let graph = rule_graph(vec![
+            rule("//:app", &["//:lib"]),
+            rule("//:lib", &["//:base"]),
+            rule("//:base", &[]),
+        ]);
+        HashEngine::new(engine_config(tmp.path()))
+            .with_checkpoint(writer, HashMap::new())
+            .compute(graph)?;
+
+        let labels: Vec<String> = std::fs::read_to_string(&path)?
+            .lines()
+            .skip(1)
+            .map(|line| Ok(serde_json::from_str::<HashRecord>(line)?.label))
+            .collect::<Result<_>>()?;
+        assert_eq!(labels, ["//:base", "//:lib", "//:app"]);
+        Ok(())
+    }
+
+    #[test]
+    fn orphan_sources_are_sources_no_rule_uses() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), b"used")?;
+        std::fs::write(tmp.path().join("stale.txt"), b"unused")?;
+        let mut graph = source_and_rule_graph();
+        let stale = BazelSource {
+            name: "//:stale.txt".to_string(),
+            subincludes: Vec::new(),
+            visibility: Vec::new(),
+        };
+        graph.targets.push(BazelTarget::Source(stale.clone()));
+        graph.sources.push(stale);
+
+        let result = HashEngine::new(engine_config(tmp.path())).compute(graph.clone())?;
+        assert!(result.orphan_sources.is_empty());
+
+        let config = HashEngineConfig {
+            orphan_sources: Some(PackageExclusions::default()),
+            ..engine_config(tmp.path())
+        };
+        let result = HashEngine::new(config).compute(graph)?;
+        assert_eq!(result.orphan_sources, vec!["//:stale.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn orphan_sources_include_workspace_files_outside_the_graph() {
+        let mut graph = source_and_rule_graph();
+        let build = BazelSource {
+            name: "//:BUILD".to_string(),
+            subincludes: vec!["//:defs.bzl".to_string()],
+            visibility: Vec::new(),
+        };
+        graph.sources.push(build);
+        graph.rule_map.insert(
+            "//sub:gen".to_string(),
+            rule("//sub:gen", &["//sub:used.txt"]),
+        );
+        let files = [
+            "BUILD",
+            "MODULE.bazel",
+            "a.txt",
+            "defs.bzl",
+            "docs/notes.md",
+            "sub/BUILD.bazel",
+            "sub/data/stale.json",
+            "sub/used.txt",
+        ]
+        .map(String::from);
+
+        let none = PackageExclusions::default();
+        assert_eq!(
+            graph.orphan_sources(Some(&files), &none),
+            ["//:docs/notes.md", "//sub:data/stale.json"]
+        );
+        assert!(graph.orphan_sources(None, &none).is_empty());
+
+        let packages = HashSet::from(["sub"]);
+        assert_eq!(
+            file_label("sub/x/y.txt", &packages).as_deref(),
+            Some("//sub:x/y.txt")
+        );
+        assert_eq!(file_label("top.txt", &packages), None);
+        assert_eq!(file_label("subway/z.txt", &packages), None);
+    }
+
+    #[test]
+    fn orphan_sources_leave_out_files_of_packages_the_graph_lacks() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let graph = source_and_rule_graph();
+        let files = [
+            "BUILD",
+            "a.txt",
+            "empty/BUILD.bazel",
+            "empty/data.txt",
+            "third_party/chromium/BUILD",
+            "third_party/chromium/foo.cc",
+            "third_party/chromium/base/bar.cc",
+            "top.txt",
+        ]
+        .map(String::from);
+        let exclusions = PackageExclusions::load(tmp.path(), &["//third_party/...".to_string()])?;
+
+        assert_eq!(
+            graph.orphan_sources(Some(&files), &exclusions),
+            ["//:top.txt"]
+        );
+        // Without the exclusion the package is still not in the graph.
+        assert_eq!(
+            graph.orphan_sources(Some(&files), &PackageExclusions::default()),
+            ["//:top.txt"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn orphan_sources_leave_out_nested_workspaces() {
+        let graph = source_and_rule_graph();
+        let files = [
+            "BUILD",
+            "MODULE.bazel",
+            "a.txt",
+            "nested/MODULE.bazel",
+            "nested/README.md",
+            "nested/lib/BUILD",
+            "nested/lib/lib.cc",
+            "nestedness.txt",
+        ]
+        .map(String::from);
+
+        assert_eq!(
+            graph.orphan_sources(Some(&files), &PackageExclusions::default()),
+            ["//:nestedness.txt"]
+        );
+    }
+
+    #[test]
+    fn test_suites_list_their_tests_or_their_package_tests() {
+        let suite = |name: &str, tests: &[&str], inputs: &[&str]| BazelRule {
+            rule_class: "test_suite".to_string(),
+            attributes: vec![Attribute {
+                name: "tests".to_string(),
+                string_list_value: tests.iter().map(|test| test.to_string()).collect(),
+                ..Default::default()
+            }],
+            ..rule(name, inputs)
+        };
+        let graph = rule_graph(vec![
+            suite(
+                "//pkg:explicit",
+                &["//pkg:b_test", "//pkg:a_test", "//pkg:b_test"],
+                &["//pkg:a_test", "//pkg:b_test"],
+            ),
+            suite("//pkg:implicit", &[], &["//pkg:c_test", "//pkg:a_test"]),
+            rule("//pkg:a_test", &[]),
+        ]);
+        assert_eq!(
+            graph.test_suites(),
+            BTreeMap::from([
+                (
+                    "//pkg:explicit".to_string(),
+                    vec!["//pkg:a_test".to_string(), "//pkg:b_test".to_string()]
+                ),
+                (
+                    "//pkg:implicit".to_string(),
+                    vec!["//pkg:a_test".to_string(), "//pkg:c_test".to_string()]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn normalized_whitespace_changes_keep_hashes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let normalizer = SourceNormalizer::new(&NormalizeOptions {
+            whitespace: vec!["txt".to_string()],
+            ..Default::default()
+        })?;
+        let hash = |contents: &str| -> Result<BTreeMap<String, String>> {
+            std::fs::write(tmp.path().join("a.txt"), contents)?;
+            let config = HashEngineConfig {
+                normalizer: normalizer.clone(),
+                ..engine_config(tmp.path())
+            };
+            Ok(HashEngine::new(config)
+                .compute(source_and_rule_graph())?
+                .hashes)
+        };
+
+        let clean = hash("contents\n")?;
+        assert_eq!(hash("contents \t\n")?, clean);
+        assert_ne!(hash("contents!\n")?, clean);
+        assert_eq!(
+            normalizer.expect("normalizer").normalized_labels(),
+            vec!["//:a.txt"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn file_access_records_source_reads() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), b"contents")?;
+        let recorder = FileAccessRecorder::new();
+        let config = HashEngineConfig {
+            file_access: Some(recorder.clone()),
+            ..engine_config(tmp.path())
+        };
+        HashEngine::new(config).compute(source_and_rule_graph())?;
+
+        let audit = recorder.report(tmp.path());
+        assert_eq!(audit.files.len(), 1);
+        assert_eq!(audit.files[0].path, tmp.path().join("a.txt"));
+        assert_eq!(audit.files[0].bytes, 8);
+        assert_eq!(audit.files[0].scope, crate::audit::AccessScope::Workspace);
+        assert_eq!(audit.outside, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn path_only_sources_ignore_content_changes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hash = |contents: &str, path_only: &[&str]| -> Result<BTreeMap<String, String>> {
+            std::fs::write(tmp.path().join("a.txt"), contents)?;
+            let config = HashEngineConfig {
+                path_only_sources: path_only.iter().map(|label| label.to_string()).collect(),
+                ..engine_config(tmp.path())
+            };
+            Ok(HashEngine::new(config)
+                .compute(source_and_rule_graph())?
+                .hashes)
+        };
+
+        assert_eq!(hash("v1", &["//:a.txt"])?, hash("v2", &["//:a.txt"])?);
+        assert_ne!(hash("v1", &[])?, hash("v2", &[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn structure_only_hashes_sizes_instead_of_contents() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hash = |contents: &str, structure_only: bool| -> Result<BTreeMap<String, String>> {
+            std::fs::write(tmp.path().join("a.txt"), contents)?;
+            let config = HashEngineConfig {
+                structure_only,
+                ..engine_config(tmp.path())
+            };
+            Ok(HashEngine::new(config)
+                .compute(source_and_rule_graph())?
+                .hashes)
+        };
+
+        assert_eq!(hash("v1", true)?, hash("v2", true)?);
+        assert_ne!(hash("v1", true)?, hash("v10", true)?);
+        assert_ne!(hash("v1", true)?, hash("v1", false)?);
+        assert_ne!(hash("v1", false)?, hash("v2", false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn time_budget_reports_the_packages_it_did_not_reach() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), "contents")?;
+        let hash = |budget: Option<Duration>| -> Result<GenerateHashesResult> {
+            let config = HashEngineConfig {
+                budget: budget.map(|budget| HashBudget {
+                    deadline: Instant::now() + budget,
+                    packages: vec!["//".to_string()],
+                }),
+                ..engine_config(tmp.path())
+            };
+            HashEngine::new(config).compute(source_and_rule_graph())
+        };
+
+        let full = hash(None)?;
+        assert_eq!(full.coverage, None);
+        let ample = hash(Some(Duration::from_secs(3600)))?;
+        assert_eq!(ample.hashes, full.hashes);
+        assert_eq!(ample.coverage, Some(HashCoverage::new(2, &[])));
+
+        let expired = hash(Some(Duration::ZERO))?;
+        assert!(expired.hashes.is_empty());
+        let coverage = expired.coverage.expect("coverage");
+        assert!(!coverage.complete);
+        assert_eq!(coverage.unhashed_targets, 2);
+        assert_eq!(coverage.unhashed_packages, ["//"]);
+        Ok(())
+    }
+
+    #[test]
+    fn options_key_ignores_the_checkout_location() -> Result<()> {
+        let config = GenerateHashesConfig {
+            workspace: PathBuf::from("/a"),
+            ..Default::default()
+        };
+        let moved = GenerateHashesConfig {
+            workspace: PathBuf::from("/b"),
+            ..config.clone()
+        };
+        let typed = GenerateHashesConfig {
+            include_target_type: true,
+            ..config.clone()
+        };
+        assert_eq!(config.options_key()?, moved.options_key()?);
+        assert_ne!(config.options_key()?, typed.options_key()?);
+        Ok(())
+    }
+
+    #[test]
+    fn options_key_reads_option_files_relative_to_the_workspace() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("seeds.txt"), "a\n")?;
+        let config = GenerateHashesConfig {
+            workspace: tmp.path().to_path_buf(),
+            seed_filepaths: Some(PathBuf::from("seeds.txt")),
+            ..Default::default()
+        };
+        let key = config.options_key()?;
+        let absolute = config.clone().with_absolute_option_files()?;
+        assert_eq!(absolute.seed_filepaths, Some(tmp.path().join("seeds.txt")));
+        assert_eq!(absolute.options_key()?, key);
+
+        std::fs::write(tmp.path().join("seeds.txt"), "b\n")?;
+        assert_ne!(config.options_key()?, key);
+        Ok(())
+    }
+
+    #[test]
+    fn options_key_ignores_options_that_leave_the_hashes_alone() -> Result<()> {
+        let config = GenerateHashesConfig {
+            on_cycle: CyclePolicy::Break,
+            ..Default::default()
+        };
+        let tuned = GenerateHashesConfig {
+            on_cycle: CyclePolicy::Warn,
+            jobs: Some(3),
+            profile_top_k: Some(5),
+            read_only: true,
+            ignored_attrs: vec!["generator_location".to_string()],
+            ..config.clone()
+        };
+        let failing = GenerateHashesConfig {
+            on_cycle: CyclePolicy::Error,
+            ..config.clone()
+        };
+        assert_eq!(config.options_key()?, tuned.options_key()?);
+        assert_ne!(config.options_key()?, failing.options_key()?);
+        Ok(())
+    }
+
+    #[test]
+    fn default_ignored_attrs_can_be_hashed() {
+        let config = GenerateHashesConfig {
+            ignored_attrs: vec!["tags".to_string(), "generator_location".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.effective_ignored_attrs(),
+            ["generator_location", "tags"]
+        );
+        let config = GenerateHashesConfig {
+            ignored_attrs: vec!["tags".to_string()],
+            no_default_ignored_attrs: true,
+            ..config
+        };
+        assert_eq!(config.effective_ignored_attrs(), ["tags"]);
+    }
+
+    #[test]
+    fn dry_run_plans_info_and_the_graph_query() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let config = GenerateHashesConfig {
+            workspace: tmp.path().to_path_buf(),
+            fine_grained_external_repos: vec!["@zlib".to_string(), "@abseil".to_string()],
+            exclude_patterns: vec!["//vendor/...".to_string()],
+            offline: true,
+            query_output: QueryOutputFormat::StreamedJsonproto,
+            ..Default::default()
+        };
+        let plan = plan_bazel_commands(&config)?;
+        assert_eq!(plan.len(), 2);
+        assert_eq!(
+            plan[0].argv[1..],
+            [
+                "info",
+                "output_base",
+                "execution_root",
+                "release",
+                "workspace"
+            ]
+        );
+        assert!(plan[1]
+            .argv
+            .contains(&"--output=streamed_jsonproto".to_string()));
+        assert!(plan[1].argv.contains(&"--nofetch".to_string()));
+        assert_eq!(
+            plan[1].files[0].1,
+            "(//...:all-targets\nunion //external:all-targets\nunion @abseil//...:all-targets\n\
+             union @zlib//...:all-targets)\nexcept //vendor/...:all-targets"
+        );
+
+        let cquery = GenerateHashesConfig {
+            use_cquery: true,
+            exclude_external_targets: true,
+            ..config.clone()
+        };
+        assert_eq!(plan_bazel_commands(&cquery)?.len(), 3);
+        let captured = GenerateHashesConfig {
+            query_proto_path: Some(tmp.path().join("query.pb")),
+            fine_grained_external_repos: Vec::new(),
+            ..config
+        };
+        assert!(plan_bazel_commands(&captured)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn source_visibility_is_hashed_behind_a_flag() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("a.txt"), b"contents")?;
+        let hash = |hash_source_visibility: bool, visibility: &[&str]| -> Result<_> {
+            let mut graph = source_and_rule_graph();
+            let visibility: Vec<String> = visibility.iter().map(|v| v.to_string()).collect();
+            graph.sources[0].visibility = visibility.clone();
+            if let BazelTarget::Source(source) = &mut graph.targets[0] {
+                source.visibility = visibility;
+            }
+            let config = HashEngineConfig {
+                hash_source_visibility,
+                ..engine_config(tmp.path())
+            };
+            Ok(HashEngine::new(config).compute(graph)?.hashes)
+        };
+
+        let private = ["//visibility:private"];
+        let public = ["//visibility:public"];
+        assert_eq!(hash(false, &private)?, hash(false, &public)?);
+        let (before, after) = (hash(true, &private)?, hash(true, &public)?);
+        assert_ne!(before["//:a.txt"], after["//:a.txt"]);
+        assert_ne!(before["//:lib"], after["//:lib"]);
+        Ok(())
+    }
+
+    #[test]
+    fn build_file_targets_hash_only_the_build_file() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(workspace.join("a.txt"), b"contents")?;
+        std::fs::write(workspace.join("BUILD.bazel"), b"genrule(name = 'lib')")?;
+        let config = || HashEngineConfig {
+            build_file_targets: true,
+            ..engine_config(workspace)
+        };
+
+        let first = HashEngine::new(config()).compute(source_and_rule_graph())?;
+        let build = first.hashes.get("//:__BUILD__").expect("pseudo-target");
+        assert!(build.starts_with("BuildFile#"));
+        assert!(!first.dep_edges.contains_key("//:__BUILD__"));
+
+        std::fs::write(workspace.join("BUILD.bazel"), b"genrule(name = 'lib')\n")?;
+        let second = HashEngine::new(config()).compute(source_and_rule_graph())?;
+        assert_ne!(second.hashes["//:__BUILD__"], *build);
+        assert_eq!(second.hashes["//:lib"], first.hashes["//:lib"]);
+        Ok(())
+    }
+
+    #[test]
+    fn repo_markers_are_mixed_into_fine_grained_files() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let external = tmp.path().join("out/external");
+        std::fs::create_dir_all(external.join("dep"))?;
+        std::fs::write(external.join("dep/file.txt"), "contents")?;
+        let source = BazelSource {
+            name: "@dep//:file.txt".to_string(),
+            subincludes: Vec::new(),
+            visibility: Vec::new(),
+        };
+        let hash = |marker: &str, hash_repo_markers: bool| -> Result<String> {
+            std::fs::write(external.join("@dep.marker"), marker)?;
+            let config = HashEngineConfig {
+                fine_grained_external_repos: HashSet::from(["dep".to_string()]),
+                hash_repo_markers,
+                ..engine_config(tmp.path())
+            };
+            let graph = BazelGraph {
+                digest: vec![1],
+                targets: vec![BazelTarget::Source(source.clone())],
+                rule_map: HashMap::new(),
+                sources: vec![source.clone()],
+                use_cquery: false,
+                query_errors: Vec::new(),
+                exec_configurations: HashSet::new(),
+            };
+            Ok(HashEngine::new(config).compute(graph)?.hashes["@dep//:file.txt"].clone())
+        };
+
+        assert_eq!(hash("key 1", false)?, hash("key 2", false)?);
+        assert_ne!(hash("key 1", true)?, hash("key 2", true)?);
+        assert_ne!(hash("key 1", true)?, hash("key 1", false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn fine_grained_files_of_canonical_repos_are_read() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path().join("out/external/dep+");
+        std::fs::create_dir_all(&repo)?;
+        let source = BazelSource {
+            name: "@@dep+//:file.txt".to_string(),
+            subincludes: Vec::new(),
+            visibility: Vec::new(),
+        };
+        let hash = |contents: &str, fine_grained: &[&str]| -> Result<String> {
+            std::fs::write(repo.join("file.txt"), contents)?;
+            let config = HashEngineConfig {
+                fine_grained_external_repos: fine_grained.iter().map(|r| r.to_string()).collect(),
+                ..engine_config(tmp.path())
+            };
+            let graph = BazelGraph {
+                digest: vec![1],
+                targets: vec![BazelTarget::Source(source.clone())],
+                rule_map: HashMap::new(),
+                sources: vec![source.clone()],
+                use_cquery: false,
+                query_errors: Vec::new(),
+                exec_configurations: HashSet::new(),
+            };
+            Ok(HashEngine::new(config).compute(graph)?.hashes["@@dep+//:file.txt"].clone())
+        };
+
+        assert_ne!(hash("before", &["dep"])?, hash("after", &["dep"])?);
+        assert_eq!(hash("before", &[])?, hash("after", &[])?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn offline_resolver_rejects_unfetched_repos() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let output_base = tmp.path().join("out");
+        std::fs::create_dir_all(output_base.join("external/present"))?;
+        std::fs::write(output_base.join("external/present/BUILD"), "")?;
+        std::fs::create_dir_all(output_base.join("external/empty"))?;
+        let resolver = ExternalRepoResolver {
+            workspace: tmp.path().to_path_buf(),
+            bazel_path: PathBuf::from("false"),
+            startup_options: Vec::new(),
+            output_base,
+            fetch_options: vec!["--nofetch".to_string()],
+            command_options: Vec::new(),
+            offline: true,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
+        };
+
+        assert!(resolver.resolve("present")?.ends_with("external/present"));
+        for repo in ["empty", "missing"] {
+            let err = resolver.resolve(repo).unwrap_err().to_string();
+            assert!(err.contains("--offline forbids fetching"), "{err}");
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_repo_resolves_to_canonical_root_with_portable_key() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let source = tmp.path().join("checkout/extlib");
+        std::fs::create_dir_all(source.join("pkg"))?;
+        std::fs::write(source.join("pkg/data.txt"), b"data")?;
+        let external = tmp.path().join("out/external");
+        std::fs::create_dir_all(&external)?;
+        std::os::unix::fs::symlink(&source, external.join("extlib"))?;
+
+        let resolver = ExternalRepoResolver {
+            workspace: tmp.path().to_path_buf(),
+            bazel_path: PathBuf::from("false"),
+            startup_options: Vec::new(),
+            output_base: tmp.path().join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
+        };
+        let content_hashes = HashMap::from([(
+            "external/extlib/pkg/data.txt".to_string(),
+            "pinned".to_string(),
+        )]);
+        let hasher = SourceFileHasher::new(
+            resolver,
+            Some(ContentHashes::new(
+                content_hashes,
+                CaseSensitivity::Sensitive,
+            )),
+            HashSet::from(["extlib".to_string()]),
+            HashSet::new(),
+        );
+
+        let resolved = hasher
+            .resolve_label("@extlib//pkg:data.txt")?
+            .expect("fine-grained repo resolves");
+        assert_eq!(
+            resolved.absolute,
+            paths::canonicalize(&source.join("pkg/data.txt"))?
+        );
+        assert_eq!(resolved.workspace_relative, "external/extlib/pkg/data.txt");
+
+        // The content hash map entry wins over the bytes behind the symlink.
+        std::fs::write(source.join("pkg/data.txt"), b"changed")?;
+        let first = hasher.digest("@extlib//pkg:data.txt", b"seed")?;
+        std::fs::write(source.join("pkg/data.txt"), b"changed again")?;
+        assert_eq!(first, hasher.digest("@extlib//pkg:data.txt", b"seed")?);
+        Ok(())
+    }
+
+    #[test]
+    fn content_hash_keys_match_any_spelling_on_insensitive_filesystems() {
+        let map = HashMap::from([
+            ("pkg/Caf\u{e9}.txt".to_string(), "pinned".to_string()),
+            ("./pkg/README.md".to_string(), "readme".to_string()),
+            ("pkg/readme.md".to_string(), "other".to_string()),
+            ("pkg/STRASSE.txt".to_string(), "upper".to_string()),
+            ("pkg/stra\u{df}e.txt".to_string(), "sharp".to_string()),
+        ]);
+        let sensitive = ContentHashes::new(map.clone(), CaseSensitivity::Sensitive);
+        assert_eq!(sensitive.get("pkg/Caf\u{e9}.txt"), Some("pinned"));
+        assert_eq!(
+            sensitive.get("pkg/Cafe\u{301}.txt").is_some(),
+            paths::NORMALIZATION_INSENSITIVE
+        );
+        assert_eq!(sensitive.get("pkg/caf\u{e9}.txt"), None);
+        assert_eq!(sensitive.get("pkg/README.md"), Some("readme"));
+        assert_eq!(sensitive.get("pkg/readme.md"), Some("other"));
+
+        let insensitive = ContentHashes::new(map, CaseSensitivity::Insensitive);
+        assert_eq!(insensitive.get("PKG/CAF\u{c9}.TXT"), Some("pinned"));
+        assert_eq!(
+            insensitive.get("PKG/cafe\u{301}.TXT").is_some(),
+            paths::NORMALIZATION_INSENSITIVE
+        );
+        // Both spellings name one file; the first in sorted order wins.
+        assert_eq!(insensitive.hashes.len(), 4);
+        assert_eq!(insensitive.get("pkg/readme.md"), Some("readme"));
+        // Names that only full case folding would merge are different files.
+        assert_eq!(insensitive.get("pkg/strasse.txt"), Some("upper"));
+        assert_eq!(insensitive.get("PKG/STRA\u{df}E.TXT"), Some("sharp"));
+    }
+
+    #[test]
+    fn output_digests_are_found_by_generated_file_label() {
+        let resolver = ExternalRepoResolver {
+            workspace: PathBuf::from("/ws"),
+            bazel_path: PathBuf::from("false"),
+            startup_options: Vec::new(),
+            output_base: PathBuf::from("/out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
+        };
+        let hasher = SourceFileHasher::new(resolver, None, HashSet::new(), HashSet::new())
+            .with_output_digests(Some(HashMap::from([
+                ("pkg/gen.txt".to_string(), "aaa".to_string()),
+                ("external/dep/lib.a".to_string(), "bbb".to_string()),
+            ])));
+        assert_eq!(hasher.output_digest("//pkg:gen.txt"), Some("aaa"));
+        assert_eq!(hasher.output_digest("@dep//:lib.a"), Some("bbb"));
+        assert_eq!(hasher.output_digest("//pkg:other.txt"), None);
+
+        let digest = TargetDigest {
+            overall: vec![1],
+            direct: vec![2],
+            deps: None,
+        };
+        let changed = digest
+            .clone_with_deps(None)
+            .with_output("aaa", HashFunction::Sha256);
+        assert_ne!(changed.overall, digest.overall);
+        assert_ne!(
+            changed.overall,
+            digest
+                .clone_with_deps(None)
+                .with_output("bbb", HashFunction::Sha256)
+                .overall
+        );
+    }
+
     #[test]
     fn soft_digest_hashes_main_repo_files() -> Result<()> {
         let tmp = tempfile::tempdir()?;
@@ -1180,6 +5332,13 @@ mod tests {
             bazel_path: PathBuf::from("bazel"),
             startup_options: Vec::new(),
             output_base: workspace.join("out"),
+            fetch_options: Vec::new(),
+            command_options: Vec::new(),
+            offline: false,
+            roots: Default::default(),
+            markers: Default::default(),
+            repo_mapping: Default::default(),
+            hybrid: None,
         };
         std::fs::create_dir_all(&resolver.output_base)?;
 
@@ -1189,4 +5348,20 @@ mod tests {
         assert!(!digest.unwrap().is_empty());
         Ok(())
     }
+
+    #[test]
+    fn build_flags_change_the_seed_but_formatting_does_not() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let flags = tmp.path().join("ci.bazelrc");
+        let seed = |contents: &str| -> Result<Vec<u8>> {
+            std::fs::write(&flags, contents)?;
+            compute_seed_hash(None, Some(flags.clone()), tmp.path())
+        };
+        let base = seed("build --copt=-O2\n")?;
+        assert!(!base.is_empty());
+        assert_eq!(base, seed("# ci\nbuild   --copt=-O2  \n")?);
+        assert_ne!(base, seed("build --copt=-O2 --copt=-g\n")?);
+        assert_eq!(compute_seed_hash(None, None, tmp.path())?, Vec::<u8>::new());
+        Ok(())
+    }
 }
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Algorithm behind every target hash. Hashes made with different functions
+/// never match, so hash files record theirs and are only compared with files
+/// made with the same one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashFunction {
+    /// SHA-256, as written by upstream bazel-diff.
+    #[default]
+    Sha256,
+    /// BLAKE3: still cryptographic, and several times faster on large files.
+    Blake3,
+    /// 128-bit XXH3: the fastest, but not collision resistant against
+    /// crafted inputs.
+    Xxh3,
+}
+
+impl HashFunction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+            Self::Xxh3 => "xxh3",
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Blake3 => Hasher::Blake3(Box::default()),
+            Self::Xxh3 => Hasher::Xxh3(Box::default()),
+        }
+    }
+}
+
+impl fmt::Display for HashFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Incremental state of a [`HashFunction`].
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, bytes: impl AsRef<[u8]>) {
+        let bytes = bytes.as_ref();
+        match self {
+            Self::Sha256(hasher) => hasher.update(bytes),
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Self::Xxh3(hasher) => hasher.update(bytes),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Self::Xxh3(hasher) => hasher.digest128().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(function: HashFunction, bytes: &[u8]) -> Vec<u8> {
+        let mut hasher = function.hasher();
+        hasher.update(bytes);
+        hasher.finalize()
+    }
+
+    #[test]
+    fn functions_differ_and_stream_like_one_shot_digests() {
+        let functions = [
+            HashFunction::Sha256,
+            HashFunction::Blake3,
+            HashFunction::Xxh3,
+        ];
+        for function in functions {
+            let mut hasher = function.hasher();
+            hasher.update(b"hello ");
+            hasher.update(b"world");
+            assert_eq!(hasher.finalize(), digest(function, b"hello world"));
+        }
+        assert_eq!(
+            hex::encode(digest(HashFunction::Sha256, b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(digest(HashFunction::Xxh3, b"").len(), 16);
+        let digests: std::collections::HashSet<_> =
+            functions.iter().map(|f| digest(*f, b"x")).collect();
+        assert_eq!(digests.len(), 3);
+        assert_eq!(
+            serde_json::to_string(&HashFunction::Blake3).unwrap(),
+            "\"blake3\""
+        );
+    }
+}
@@ -1,18 +1,62 @@
+use crate::hash::GenerateHashesResult;
 use crate::models::{
-    read_dep_edges_file, read_target_hashes, DependencyEdges, ImpactedTargetDistance,
-    ImpactedTargetsResult, TargetHash, TargetHashes,
+    hashes_meta, invert_dep_edges, parse_target_hashes, read_dep_edges_from,
+    read_directed_dep_edges_file, read_hashes_meta, read_target_hashes_from,
+    split_namespaced_label, DependencyEdges, DirectedDependencyEdges, ImpactedTargetDistance,
+    ImpactedTargetsResult, OutputMeta, TargetCosts, TargetHash, TargetHashes,
 };
-use anyhow::{anyhow, bail, Result};
+use crate::stream::{
+    load_target_hashes, stream_sorted_target_hashes, stream_target_hashes, UnsortedHashes,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::thread;
+
+/// Below this many impacted targets, distances are computed on one thread.
+const PARALLEL_DISTANCES_THRESHOLD: usize = 4096;
+
+/// Hashes of a changed label before and after; `None` on the side where the
+/// label does not exist.
+type HashPair = (Option<TargetHash>, Option<TargetHash>);
 
+/// Why a target is impacted.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ImpactKind {
+pub enum ImpactKind {
+    /// The target's own inputs changed, or it was added or removed.
     Direct,
+    /// Only the target's dependencies changed.
     Indirect,
 }
 
+/// How the `*_with_options` functions compare two sets of hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImpactOptions {
+    /// Only targets of these types are reported.
+    pub target_types: Option<Vec<String>>,
+    /// Compares hashes without their target types, so hashes generated with
+    /// and without `--includeTargetType` can be compared.
+    pub normalize_target_type: bool,
+}
+
+impl ImpactOptions {
+    fn of_types(target_types: Option<Vec<String>>) -> Self {
+        Self {
+            target_types,
+            ..Self::default()
+        }
+    }
+
+    fn target_types_set(&self) -> Option<HashSet<String>> {
+        let types = self.target_types.as_ref()?;
+        Some(types.iter().cloned().collect())
+    }
+}
+
 pub fn get_impacted_targets<P, Q, R>(
     start_path: P,
     final_path: Q,
@@ -24,123 +68,672 @@ where
     Q: AsRef<Path>,
     R: AsRef<Path>,
 {
-    let start_hashes = read_target_hashes(&start_path)?;
-    let final_hashes = read_target_hashes(&final_path)?;
-    let target_types_set = target_types.map(|t| t.into_iter().collect::<HashSet<_>>());
+    get_impacted_targets_with_options(
+        start_path,
+        final_path,
+        dep_edges_path,
+        &ImpactOptions::of_types(target_types),
+    )
+}
 
-    let impacted =
-        compute_impacted_targets(&start_hashes, &final_hashes, target_types_set.as_ref())?;
+/// Same as [`get_impacted_targets`], comparing the hashes as `options` say.
+pub fn get_impacted_targets_with_options<P, Q, R>(
+    start_path: P,
+    final_path: Q,
+    dep_edges_path: Option<R>,
+    options: &ImpactOptions,
+) -> Result<ImpactedTargetsResult>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let deps = dep_edges_path
+        .map(read_directed_dep_edges_file)
+        .transpose()?;
+    get_impacted_targets_with_dep_edges_and_options(start_path, final_path, deps.as_ref(), options)
+}
 
-    if let Some(dep_path) = dep_edges_path {
-        let deps = read_dep_edges_file(dep_path)?;
-        let distances = compute_distances(&start_hashes, &final_hashes, &deps, &impacted)?;
-        Ok(ImpactedTargetsResult {
-            impacted,
-            distances: Some(distances),
-        })
-    } else {
-        Ok(ImpactedTargetsResult {
-            impacted,
-            distances: None,
+/// Same as [`get_impacted_targets`] for dep edges already in memory; the hash
+/// files are still streamed. Reverse edges are used as they are.
+pub fn get_impacted_targets_with_dep_edges<P, Q>(
+    start_path: P,
+    final_path: Q,
+    dep_edges: Option<&DirectedDependencyEdges>,
+    target_types: Option<Vec<String>>,
+) -> Result<ImpactedTargetsResult>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    get_impacted_targets_with_dep_edges_and_options(
+        start_path,
+        final_path,
+        dep_edges,
+        &ImpactOptions::of_types(target_types),
+    )
+}
+
+/// Same as [`get_impacted_targets_with_dep_edges`], comparing the hashes as
+/// `options` say.
+pub fn get_impacted_targets_with_dep_edges_and_options<P, Q>(
+    start_path: P,
+    final_path: Q,
+    dep_edges: Option<&DirectedDependencyEdges>,
+    options: &ImpactOptions,
+) -> Result<ImpactedTargetsResult>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    check_comparable(
+        read_hashes_meta(start_path.as_ref())?,
+        read_hashes_meta(final_path.as_ref())?,
+    )?;
+    let target_types = options.target_types_set();
+    let merge = |start, end| {
+        merge_changed_hashes(
+            start,
+            end,
+            target_types.as_ref(),
+            options.normalize_target_type,
+        )
+    };
+    // Files not written by generate-hashes may be unsorted; those are loaded
+    // and sorted instead.
+    let changed = match merge(
+        stream_target_hashes(start_path.as_ref())?,
+        stream_target_hashes(final_path.as_ref())?,
+    ) {
+        Err(err) if err.is::<UnsortedHashes>() => merge(
+            load_target_hashes(start_path.as_ref())?,
+            load_target_hashes(final_path.as_ref())?,
+        )?,
+        changed => changed?,
+    };
+    let impacted = order_impacted(&changed);
+
+    let distances = dep_edges
+        .map(|deps| {
+            let forward = deps.forward_among(&impacted);
+            let mut distances = distances_for_kinds(&forward, &impact_kinds(&changed), &impacted)?;
+            set_generating_rules(&mut distances, &forward, |label| {
+                let (start, end) = changed.get(label)?;
+                end.as_ref()
+                    .and_then(TargetHash::target_type)
+                    .or_else(|| start.as_ref().and_then(TargetHash::target_type))
+            });
+            Ok::<_, anyhow::Error>(distances)
         })
+        .transpose()?;
+    Ok(ImpactedTargetsResult {
+        impacted,
+        distances,
+    })
+}
+
+/// Hands each impacted target to `emit` in label order while comparing the
+/// hash files, holding only the current entry of each in memory, and returns
+/// how many there were. Both files must be sorted by label (see
+/// [`stream_sorted_target_hashes`]). Unlike [`get_impacted_targets`] the
+/// labels are not grouped by target type and no distances are computed.
+pub fn stream_impacted_targets<P, Q>(
+    start_path: P,
+    final_path: Q,
+    target_types: Option<Vec<String>>,
+    emit: impl FnMut(&str) -> Result<()>,
+) -> Result<usize>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    stream_impacted_targets_with_options(
+        start_path,
+        final_path,
+        &ImpactOptions::of_types(target_types),
+        emit,
+    )
+}
+
+/// Same as [`stream_impacted_targets`], comparing the hashes as `options` say.
+pub fn stream_impacted_targets_with_options<P, Q>(
+    start_path: P,
+    final_path: Q,
+    options: &ImpactOptions,
+    mut emit: impl FnMut(&str) -> Result<()>,
+) -> Result<usize>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    check_comparable(
+        read_hashes_meta(start_path.as_ref())?,
+        read_hashes_meta(final_path.as_ref())?,
+    )?;
+    let mut count = 0;
+    for_each_changed_hash(
+        stream_sorted_target_hashes(start_path.as_ref())?,
+        stream_sorted_target_hashes(final_path.as_ref())?,
+        options.target_types_set().as_ref(),
+        options.normalize_target_type,
+        |label, _| {
+            count += 1;
+            emit(&label)
+        },
+    )?;
+    Ok(count)
+}
+
+/// Same as [`get_impacted_targets`] for artifacts read from anywhere, e.g. a
+/// service holding them downloaded from object storage. Unlike the path-based
+/// version the hash files are loaded in full rather than streamed.
+pub fn get_impacted_targets_from_readers<S, F, D>(
+    start: S,
+    last: F,
+    dep_edges: Option<D>,
+    target_types: Option<Vec<String>>,
+) -> Result<ImpactedTargetsResult>
+where
+    S: Read,
+    F: Read,
+    D: Read,
+{
+    get_impacted_targets_from_readers_with_options(
+        start,
+        last,
+        dep_edges,
+        &ImpactOptions::of_types(target_types),
+    )
+}
+
+/// Same as [`get_impacted_targets_from_readers`], comparing the hashes as
+/// `options` say.
+pub fn get_impacted_targets_from_readers_with_options<S, F, D>(
+    start: S,
+    last: F,
+    dep_edges: Option<D>,
+    options: &ImpactOptions,
+) -> Result<ImpactedTargetsResult>
+where
+    S: Read,
+    F: Read,
+    D: Read,
+{
+    let start = read_all(start, "starting hashes")?;
+    let last = read_all(last, "final hashes")?;
+    check_comparable(
+        hashes_meta(&start, "starting hashes")?,
+        hashes_meta(&last, "final hashes")?,
+    )?;
+    let start_hashes = read_target_hashes_from(start.as_slice(), "starting hashes")?;
+    let final_hashes = read_target_hashes_from(last.as_slice(), "final hashes")?;
+    let dep_edges = dep_edges
+        .map(|reader| read_dep_edges_from(reader, "dep edges"))
+        .transpose()?;
+    impacted_targets_from_hashes_with_options(
+        &start_hashes,
+        &final_hashes,
+        dep_edges.as_ref(),
+        options,
+    )
+}
+
+fn read_all(mut reader: impl Read, source: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read {source}"))?;
+    Ok(data)
+}
+
+/// Fails unless hashes with these metadata can be compared: hashes made with
+/// different hash functions never match, and approximate `--noBazel` hashes
+/// never match ones made from a query, so comparing them would report every
+/// target as impacted.
+pub fn check_comparable(start: Option<OutputMeta>, last: Option<OutputMeta>) -> Result<()> {
+    let approximate = |meta: &Option<OutputMeta>| {
+        meta.as_ref()
+            .and_then(|m| m.approximate)
+            .unwrap_or_default()
+    };
+    match (approximate(&start), approximate(&last)) {
+        (true, false) => bail!(
+            "the starting hashes were read from BUILD files with --noBazel and the final \
+             hashes were not; generate both the same way"
+        ),
+        (false, true) => bail!(
+            "the final hashes were read from BUILD files with --noBazel and the starting \
+             hashes were not; generate both the same way"
+        ),
+        _ => {}
+    }
+    let function =
+        |meta: Option<OutputMeta>| meta.and_then(|m| m.hash_function).unwrap_or_default();
+    let (start, last) = (function(start), function(last));
+    if start != last {
+        bail!(
+            "the starting hashes were made with {start} and the final hashes with {last}; \
+             regenerate one of them with --hashFunction {last} or --hashFunction {start}"
+        );
     }
+    Ok(())
 }
 
+/// Same as [`get_impacted_targets`] for hashes and dep edges already in memory.
+pub fn impacted_targets_from_hashes(
+    start_hashes: &TargetHashes,
+    final_hashes: &TargetHashes,
+    dep_edges: Option<&DependencyEdges>,
+    target_types: Option<Vec<String>>,
+) -> Result<ImpactedTargetsResult> {
+    impacted_targets_from_hashes_with_options(
+        start_hashes,
+        final_hashes,
+        dep_edges,
+        &ImpactOptions::of_types(target_types),
+    )
+}
+
+/// Same as [`impacted_targets_from_hashes`], comparing the hashes as
+/// `options` say.
+pub fn impacted_targets_from_hashes_with_options(
+    start_hashes: &TargetHashes,
+    final_hashes: &TargetHashes,
+    dep_edges: Option<&DependencyEdges>,
+    options: &ImpactOptions,
+) -> Result<ImpactedTargetsResult> {
+    let impacted = compute_impacted_targets_with_options(start_hashes, final_hashes, options)?;
+
+    let distances = dep_edges
+        .map(|deps| compute_distances(start_hashes, final_hashes, deps, &impacted))
+        .transpose()?;
+    Ok(ImpactedTargetsResult {
+        impacted,
+        distances,
+    })
+}
+
+/// Same as [`get_impacted_targets`] for two in-memory hash runs, e.g.
+/// successive runs of a watch loop, without going through a hash file.
+/// Distances are included when `last` tracked dep edges.
+pub fn diff_results(
+    start: &GenerateHashesResult,
+    last: &GenerateHashesResult,
+) -> Result<ImpactedTargetsResult> {
+    check_comparable(Some(start.comparison_meta()), Some(last.comparison_meta()))?;
+    let start_hashes = parse_target_hashes(start.hashes.clone())?;
+    let final_hashes = parse_target_hashes(last.hashes.clone())?;
+    let dep_edges = (!last.dep_edges.is_empty()).then(|| last.dependency_edges());
+    impacted_targets_from_hashes(&start_hashes, &final_hashes, dep_edges.as_ref(), None)
+}
+
+/// Labels added, removed or changed between the two hash maps. A label hashed
+/// with its target type on one side only is an error, since its hashes could
+/// never match; see [`ImpactOptions::normalize_target_type`].
 pub fn compute_impacted_targets(
     start_hashes: &TargetHashes,
     final_hashes: &TargetHashes,
     target_types: Option<&HashSet<String>>,
 ) -> Result<Vec<String>> {
-    let mut impacted = Vec::new();
+    let changed = merge_changed_hashes(
+        start_hashes.iter().map(Ok),
+        final_hashes.iter().map(Ok),
+        target_types,
+        false,
+    )?;
+    Ok(order_impacted(&changed))
+}
 
-    let mut all_labels = BTreeSet::new();
-    all_labels.extend(start_hashes.keys().cloned());
-    all_labels.extend(final_hashes.keys().cloned());
+/// Same as [`compute_impacted_targets`], comparing the hashes as `options` say.
+pub fn compute_impacted_targets_with_options(
+    start_hashes: &TargetHashes,
+    final_hashes: &TargetHashes,
+    options: &ImpactOptions,
+) -> Result<Vec<String>> {
+    let changed = merge_changed_hashes(
+        start_hashes.iter().map(Ok),
+        final_hashes.iter().map(Ok),
+        options.target_types_set().as_ref(),
+        options.normalize_target_type,
+    )?;
+    Ok(order_impacted(&changed))
+}
 
-    for label in all_labels.iter() {
-        let start_hash = start_hashes.get(label);
-        let final_hash = final_hashes.get(label);
-        let changed = match (start_hash, final_hash) {
-            (Some(start), Some(end)) => start.raw != end.raw,
-            (None, Some(_)) | (Some(_), None) => true,
-            (None, None) => false,
+/// Merge-joins two label-sorted hash sequences and keeps only the labels that
+/// were added, removed, or changed (and match `target_types`), so memory grows
+/// with the impacted set rather than with the whole universe.
+fn merge_changed_hashes<S, F, L, H>(
+    start: S,
+    end: F,
+    target_types: Option<&HashSet<String>>,
+    normalize_target_type: bool,
+) -> Result<BTreeMap<String, HashPair>>
+where
+    S: IntoIterator<Item = Result<(L, H)>>,
+    F: IntoIterator<Item = Result<(L, H)>>,
+    L: AsRef<str> + Into<String>,
+    H: Borrow<TargetHash>,
+{
+    let mut changed = BTreeMap::new();
+    for_each_changed_hash(
+        start,
+        end,
+        target_types,
+        normalize_target_type,
+        |label, pair| {
+            changed.insert(label, pair);
+            Ok(())
+        },
+    )?;
+    Ok(changed)
+}
+
+/// The merge-join behind [`merge_changed_hashes`], handing each changed label
+/// to `visit` in label order instead of collecting them.
+fn for_each_changed_hash<S, F, L, H>(
+    start: S,
+    end: F,
+    target_types: Option<&HashSet<String>>,
+    normalize_target_type: bool,
+    mut visit: impl FnMut(String, HashPair) -> Result<()>,
+) -> Result<()>
+where
+    S: IntoIterator<Item = Result<(L, H)>>,
+    F: IntoIterator<Item = Result<(L, H)>>,
+    L: AsRef<str> + Into<String>,
+    H: Borrow<TargetHash>,
+{
+    let mut start = start.into_iter();
+    let mut end = end.into_iter();
+    let mut next_start = start.next().transpose()?;
+    let mut next_end = end.next().transpose()?;
+
+    loop {
+        let order = match (&next_start, &next_end) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((left, _)), Some((right, _))) => left.as_ref().cmp(right.as_ref()),
+        };
+        let (label, pair) = match order {
+            Ordering::Less => {
+                let (label, hash) = next_start.take().expect("start entry is present");
+                next_start = start.next().transpose()?;
+                (label, (Some(hash.borrow().clone()), None))
+            }
+            Ordering::Greater => {
+                let (label, hash) = next_end.take().expect("final entry is present");
+                next_end = end.next().transpose()?;
+                (label, (None, Some(hash.borrow().clone())))
+            }
+            Ordering::Equal => {
+                let (label, start_hash) = next_start.take().expect("start entry is present");
+                let (_, end_hash) = next_end.take().expect("final entry is present");
+                next_start = start.next().transpose()?;
+                next_end = end.next().transpose()?;
+                if same_hash(
+                    label.as_ref(),
+                    start_hash.borrow(),
+                    end_hash.borrow(),
+                    normalize_target_type,
+                )? {
+                    continue;
+                }
+                (
+                    label,
+                    (
+                        Some(start_hash.borrow().clone()),
+                        Some(end_hash.borrow().clone()),
+                    ),
+                )
+            }
         };
 
-        if changed {
-            impacted.push(label.clone());
+        let label = label.into();
+        if let Some(allowed_types) = target_types {
+            let target_type = pair
+                .1
+                .as_ref()
+                .and_then(TargetHash::target_type)
+                .or_else(|| pair.0.as_ref().and_then(TargetHash::target_type))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No target type info for {label}; regenerate hashes with --includeTargetType"
+                    )
+                })?;
+            if !allowed_types.contains(target_type) {
+                continue;
+            }
         }
+        visit(label, pair)?;
     }
 
-    if let Some(allowed_types) = target_types {
-        let mut filtered = Vec::new();
-        for label in impacted.into_iter() {
-            let hash = target_hash_for_label(&label, start_hashes, final_hashes)?;
+    Ok(())
+}
 
-            let target_type = hash.target_type().ok_or_else(|| {
-                anyhow!(
-                    "No target type info for {label}; regenerate hashes with --includeTargetType"
-                )
-            })?;
+/// Whether a label's hashes match. When only one side carries a target type
+/// the files were generated with and without `--includeTargetType`, and every
+/// target would look changed, so that is an error unless the types are
+/// ignored.
+fn same_hash(
+    label: &str,
+    start: &TargetHash,
+    end: &TargetHash,
+    normalize_target_type: bool,
+) -> Result<bool> {
+    if normalize_target_type {
+        return Ok(start.untyped() == end.untyped());
+    }
+    let (side, other) = match (start.target_type(), end.target_type()) {
+        (Some(_), None) => ("starting", "final"),
+        (None, Some(_)) => ("final", "starting"),
+        _ => return Ok(start.raw() == end.raw()),
+    };
+    bail!(
+        "{label} has a target type in the {side} hashes but not in the {other} hashes; \
+         generate both with or both without --includeTargetType, or compare them with \
+         --normalizeTargetType"
+    )
+}
 
-            if allowed_types.contains(target_type) {
-                filtered.push(label);
-            }
+/// Impacted labels ordered by target type (source files first), then label.
+fn order_impacted(changed: &BTreeMap<String, HashPair>) -> Vec<String> {
+    let rank = |(start, end): &HashPair| {
+        let target_type = end
+            .as_ref()
+            .and_then(TargetHash::target_type)
+            .or_else(|| start.as_ref().and_then(TargetHash::target_type));
+        match target_type {
+            Some("SourceFile") => 0,
+            Some("GeneratedFile") => 1,
+            Some("Rule") => 2,
+            Some(_) => 3,
+            None => 4,
         }
-        impacted = filtered;
+    };
+    let mut impacted: Vec<(&String, &HashPair)> = changed.iter().collect();
+    impacted.sort_by_key(|(label, pair)| (rank(pair), *label));
+    impacted
+        .into_iter()
+        .map(|(label, _)| label.clone())
+        .collect()
+}
+
+fn impact_kinds(changed: &BTreeMap<String, HashPair>) -> BTreeMap<String, ImpactKind> {
+    changed
+        .iter()
+        .map(|(label, (start, end))| (label.clone(), classify_impact(start.as_ref(), end.as_ref())))
+        .collect()
+}
+
+/// Fills in [`ImpactedTargetDistance::weighted_distance`]. Like the target
+/// distance, directly impacted targets are at 0, but each step from an
+/// impacted dependency to its dependent adds the dependent's cost in seconds
+/// from `costs` instead of one hop; labels without a cost add nothing.
+pub fn add_weighted_distances(
+    distances: &mut [ImpactedTargetDistance],
+    dep_edges: &DependencyEdges,
+    costs: &TargetCosts,
+) -> Result<()> {
+    let direct: HashSet<String> = distances
+        .iter()
+        .filter(|d| d.target_distance == 0)
+        .map(|d| d.label.clone())
+        .collect();
+    let impacted: HashSet<String> = distances.iter().map(|d| d.label.clone()).collect();
+    let graph = WeightedGraph {
+        dep_edges,
+        costs,
+        direct: &direct,
+        impacted: &impacted,
+    };
+
+    let mut memo = HashMap::new();
+    let mut visiting = HashSet::new();
+    for distance in distances.iter_mut() {
+        distance.weighted_distance =
+            Some(graph.distance(&distance.label, &mut memo, &mut visiting)?);
     }
+    Ok(())
+}
 
-    impacted.sort_by(|a, b| compare_by_type_then_label(a, b, start_hashes, final_hashes));
+struct WeightedGraph<'a> {
+    dep_edges: &'a DependencyEdges,
+    costs: &'a TargetCosts,
+    direct: &'a HashSet<String>,
+    impacted: &'a HashSet<String>,
+}
+
+impl WeightedGraph<'_> {
+    fn distance(
+        &self,
+        label: &str,
+        memo: &mut HashMap<String, f64>,
+        visiting: &mut HashSet<String>,
+    ) -> Result<f64> {
+        if let Some(cached) = memo.get(label) {
+            return Ok(*cached);
+        }
+        if self.direct.contains(label) {
+            return Ok(0.0);
+        }
+        if !visiting.insert(label.to_string()) {
+            bail!("cycle detected while computing weighted distance for {label}");
+        }
 
-    impacted.dedup();
+        let mut nearest: Option<f64> = None;
+        for dep in self.dep_edges.get(label).into_iter().flatten() {
+            if self.impacted.contains(dep) {
+                let distance = self.distance(dep, memo, visiting)?;
+                nearest = Some(nearest.map_or(distance, |n| n.min(distance)));
+            }
+        }
+        let Some(nearest) = nearest else {
+            bail!("{label} was indirectly impacted but has no impacted dependencies");
+        };
+        let result = nearest + self.costs.get(label).copied().unwrap_or(0.0);
 
-    Ok(impacted)
+        visiting.remove(label);
+        memo.insert(label.to_string(), result);
+        Ok(result)
+    }
 }
 
-fn target_hash_for_label<'a>(
-    label: &str,
-    start_hashes: &'a TargetHashes,
-    final_hashes: &'a TargetHashes,
-) -> Result<&'a TargetHash> {
-    final_hashes
-        .get(label)
-        .or_else(|| start_hashes.get(label))
-        .ok_or_else(|| anyhow!("missing hash entry for {label}"))
+/// Returns `changed` plus every target that transitively depends on one of
+/// them according to `dep_edges`, sorted by label. No hashes are compared.
+pub fn impacted_from_changed_labels(
+    changed: &[String],
+    dep_edges: &DependencyEdges,
+) -> Vec<String> {
+    impacted_from_reverse_edges(changed, &invert_dep_edges(dep_edges))
 }
 
-fn target_type_for_label<'a>(
-    label: &str,
-    start_hashes: &'a TargetHashes,
-    final_hashes: &'a TargetHashes,
-) -> Option<&'a str> {
-    final_hashes
-        .get(label)
-        .and_then(|hash| hash.target_type())
-        .or_else(|| start_hashes.get(label).and_then(|hash| hash.target_type()))
-}
-
-fn compare_by_type_then_label(
-    left: &str,
-    right: &str,
-    start_hashes: &TargetHashes,
-    final_hashes: &TargetHashes,
-) -> Ordering {
-    let left_type = target_type_for_label(left, start_hashes, final_hashes);
-    let right_type = target_type_for_label(right, start_hashes, final_hashes);
-
-    let rank = |value: Option<&str>| match value {
-        Some("SourceFile") => 0,
-        Some("GeneratedFile") => 1,
-        Some("Rule") => 2,
-        Some(_) => 3,
-        None => 4,
+/// Same as [`impacted_from_changed_labels`] for reverse edges, which map each
+/// target to its direct dependents.
+pub fn impacted_from_reverse_edges(changed: &[String], reverse: &DependencyEdges) -> Vec<String> {
+    let mut impacted: BTreeSet<&str> = changed.iter().map(String::as_str).collect();
+    let mut queue: VecDeque<&str> = impacted.iter().copied().collect();
+    while let Some(label) = queue.pop_front() {
+        for dependent in reverse.get(label).into_iter().flatten() {
+            if impacted.insert(dependent) {
+                queue.push_back(dependent);
+            }
+        }
+    }
+
+    impacted.into_iter().map(str::to_string).collect()
+}
+
+/// Keeps only impacted targets from one workspace namespace (`None` selects the
+/// root workspace) and strips the namespace so labels can be passed to Bazel.
+pub fn select_workspace_namespace(
+    result: ImpactedTargetsResult,
+    namespace: Option<&str>,
+) -> ImpactedTargetsResult {
+    let keep = |label: &str| -> Option<String> {
+        let (ns, plain) = split_namespaced_label(label);
+        (ns == namespace).then(|| plain.to_string())
     };
 
-    rank(left_type)
-        .cmp(&rank(right_type))
-        .then_with(|| left.cmp(right))
+    ImpactedTargetsResult {
+        impacted: result.impacted.iter().filter_map(|l| keep(l)).collect(),
+        distances: result.distances.map(|distances| {
+            distances
+                .into_iter()
+                .filter_map(|d| {
+                    let label = keep(&d.label)?;
+                    let generating_rule = d.generating_rule.as_deref().and_then(keep);
+                    Some(ImpactedTargetDistance {
+                        label,
+                        generating_rule,
+                        ..d
+                    })
+                })
+                .collect()
+        }),
+    }
 }
 
-fn compute_distances(
+/// Replaces the impacted targets with `labels`, e.g. the tests selected for
+/// them, keeping the distances of labels that have one.
+pub fn with_impacted_labels(
+    result: ImpactedTargetsResult,
+    labels: Vec<String>,
+) -> ImpactedTargetsResult {
+    let distances = result.distances.map(|distances| {
+        let keep: HashSet<&str> = labels.iter().map(String::as_str).collect();
+        distances
+            .into_iter()
+            .filter(|d| keep.contains(d.label.as_str()))
+            .collect()
+    });
+    ImpactedTargetsResult {
+        impacted: labels,
+        distances,
+    }
+}
+
+/// Shard `index` (zero-based) of `count`: labels are sorted and dealt out in
+/// turn, so shards differ in size by at most one and every label lands in
+/// exactly one shard.
+pub fn shard_labels(labels: &[String], count: usize, index: usize) -> Result<Vec<String>> {
+    if count == 0 || index >= count {
+        bail!("shard index {index} is out of range for {count} shards");
+    }
+    let mut sorted: Vec<&String> = labels.iter().collect();
+    sorted.sort();
+    Ok(sorted
+        .into_iter()
+        .skip(index)
+        .step_by(count)
+        .cloned()
+        .collect())
+}
+
+/// Distances of each `impacted` label (as returned by
+/// [`compute_impacted_targets`]) from the nearest directly impacted target,
+/// with the direct/indirect split taken from the two hash maps.
+pub fn compute_distances(
     start_hashes: &TargetHashes,
     final_hashes: &TargetHashes,
     dep_edges: &DependencyEdges,
@@ -156,23 +749,318 @@ fn compute_distances(
         kind_by_label.insert(label.clone(), kind);
     }
 
-    let mut memo: HashMap<String, ImpactedTargetDistance> = HashMap::new();
-    let mut visiting = HashSet::new();
-    let mut results = Vec::with_capacity(impacted.len());
+    let mut distances = distances_for_kinds(dep_edges, &kind_by_label, impacted)?;
+    set_generating_rules(&mut distances, dep_edges, |label| {
+        final_hashes
+            .get(label)
+            .and_then(TargetHash::target_type)
+            .or_else(|| start_hashes.get(label).and_then(TargetHash::target_type))
+    });
+    Ok(distances)
+}
+
+/// Fills in [`ImpactedTargetDistance::generating_rule`] for generated files,
+/// whose only dep edge is the rule producing them. Without target types no
+/// label is known to be a generated file and nothing is set.
+fn set_generating_rules<'a>(
+    distances: &mut [ImpactedTargetDistance],
+    dep_edges: &DependencyEdges,
+    target_type: impl Fn(&str) -> Option<&'a str>,
+) {
+    for distance in distances {
+        if target_type(&distance.label) != Some("GeneratedFile") {
+            continue;
+        }
+        if let Some([rule]) = dep_edges.get(&distance.label).map(Vec::as_slice) {
+            distance.generating_rule = Some(rule.clone());
+        }
+    }
+}
+
+/// Reports each impacted generated file as the rule generating it, for
+/// consumers that build or test rules rather than individual outputs. Only
+/// files with a known [`ImpactedTargetDistance::generating_rule`] collapse;
+/// each rule is listed once, where its first file or the rule itself was.
+pub fn collapse_generated_files(result: ImpactedTargetsResult) -> ImpactedTargetsResult {
+    let Some(distances) = result.distances else {
+        return result;
+    };
+    let rules: HashMap<&str, &str> = distances
+        .iter()
+        .filter_map(|d| Some((d.label.as_str(), d.generating_rule.as_deref()?)))
+        .collect();
+    let mut seen = HashSet::new();
+    let impacted: Vec<String> = result
+        .impacted
+        .iter()
+        .map(|label| rules.get(label.as_str()).copied().unwrap_or(label))
+        .filter(|label| seen.insert(*label))
+        .map(str::to_string)
+        .collect();
+
+    let by_label: HashMap<&str, &ImpactedTargetDistance> =
+        distances.iter().map(|d| (d.label.as_str(), d)).collect();
+    let collapsed = impacted
+        .iter()
+        .filter_map(|label| {
+            by_label
+                .get(label.as_str())
+                .map(|d| (*d).clone())
+                .or_else(|| {
+                    // The rule itself is not impacted: keep its file's distance.
+                    let file = distances
+                        .iter()
+                        .find(|d| d.generating_rule.as_deref() == Some(label))?;
+                    Some(ImpactedTargetDistance {
+                        label: label.clone(),
+                        generating_rule: None,
+                        ..file.clone()
+                    })
+                })
+        })
+        .collect();
+    ImpactedTargetsResult {
+        impacted,
+        distances: Some(collapsed),
+    }
+}
+
+/// Replaces each impacted `test_suite` in `suites` (members keyed by suite)
+/// with its member tests that are impacted themselves, so running the result
+/// skips members nothing changed for. A changed member is impacted on its own
+/// and already listed, as are the members of nested suites, so expanding
+/// drops the suites; suites without recorded members are kept.
+pub fn expand_test_suites(
+    result: ImpactedTargetsResult,
+    suites: &BTreeMap<String, Vec<String>>,
+) -> ImpactedTargetsResult {
+    let labels: Vec<String> = result
+        .impacted
+        .iter()
+        .filter(|label| !suites.contains_key(*label))
+        .cloned()
+        .collect();
+    with_impacted_labels(result, labels)
+}
 
+/// The impacted targets plus their direct deps, ordered for a remote cache
+/// priming build: every target comes after the deps it has in the list, so
+/// leaves are built (and uploaded) first. Targets on a dependency cycle come
+/// last. Ties are broken by label.
+pub fn cache_prime_order(impacted: &[String], dep_edges: &DependencyEdges) -> Vec<String> {
+    let mut targets: BTreeSet<&str> = impacted.iter().map(String::as_str).collect();
     for label in impacted {
-        let distance =
-            calculate_distance(label, dep_edges, &kind_by_label, &mut memo, &mut visiting)?;
-        results.push(distance);
+        targets.extend(
+            dep_edges
+                .get(label)
+                .into_iter()
+                .flatten()
+                .map(String::as_str),
+        );
+    }
+    // Per target, how many of its deps in the list are not ordered yet.
+    let mut waiting: HashMap<&str, usize> = HashMap::with_capacity(targets.len());
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for &label in &targets {
+        let deps: BTreeSet<&str> = dep_edges
+            .get(label)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .filter(|dep| *dep != label && targets.contains(dep))
+            .collect();
+        for &dep in &deps {
+            dependents.entry(dep).or_default().push(label);
+        }
+        waiting.insert(label, deps.len());
     }
 
-    Ok(results)
+    let mut order = Vec::with_capacity(targets.len());
+    let mut layer: Vec<&str> = targets
+        .iter()
+        .copied()
+        .filter(|label| waiting[label] == 0)
+        .collect();
+    while !layer.is_empty() {
+        let mut next = Vec::new();
+        for label in &layer {
+            for &dependent in dependents.get(label).into_iter().flatten() {
+                let count = waiting.get_mut(dependent).expect("dependent is listed");
+                *count -= 1;
+                if *count == 0 {
+                    next.push(dependent);
+                }
+            }
+        }
+        order.extend(layer.iter().map(|label| label.to_string()));
+        next.sort_unstable();
+        layer = next;
+    }
+    // Whatever is still waiting is on, or depends on, a cycle.
+    order.extend(
+        targets
+            .iter()
+            .filter(|label| waiting[*label] > 0)
+            .map(|label| label.to_string()),
+    );
+    order
 }
 
-fn classify_impact(start_hash: Option<&TargetHash>, final_hash: Option<&TargetHash>) -> ImpactKind {
+fn distances_for_kinds(
+    dep_edges: &DependencyEdges,
+    kind_by_label: &BTreeMap<String, ImpactKind>,
+    impacted: &[String],
+) -> Result<Vec<ImpactedTargetDistance>> {
+    let workers = if impacted.len() < PARALLEL_DISTANCES_THRESHOLD {
+        1
+    } else {
+        thread::available_parallelism().map_or(1, NonZeroUsize::get)
+    };
+    sharded_distances(dep_edges, kind_by_label, impacted, workers)
+}
+
+/// Computes distances on up to `workers` threads. Distances only depend on the
+/// impacted targets connected to a label, so each connected component of the
+/// impacted subgraph is computed by one thread with its own memo table; the
+/// results and the first error (in `impacted` order) match a serial run.
+fn sharded_distances(
+    dep_edges: &DependencyEdges,
+    kind_by_label: &BTreeMap<String, ImpactKind>,
+    impacted: &[String],
+    workers: usize,
+) -> Result<Vec<ImpactedTargetDistance>> {
+    let compute_shard = |shard: &[usize]| {
+        let mut memo: HashMap<String, ImpactedTargetDistance> = HashMap::new();
+        let mut visiting = HashSet::new();
+        shard
+            .iter()
+            .map(|&index| {
+                calculate_distance(
+                    &impacted[index],
+                    dep_edges,
+                    kind_by_label,
+                    &mut memo,
+                    &mut visiting,
+                )
+                .map_err(|err| (index, err))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    if workers < 2 {
+        let all: Vec<usize> = (0..impacted.len()).collect();
+        return compute_shard(&all).map_err(|(_, err)| err);
+    }
+
+    let shards = independent_shards(dep_edges, kind_by_label, impacted, workers);
+    let shard_results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = shards
+            .iter()
+            .map(|shard| scope.spawn(|| compute_shard(shard)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("distance worker panicked"))
+            .collect()
+    });
+
+    let mut results: Vec<Option<ImpactedTargetDistance>> = vec![None; impacted.len()];
+    let mut first_error: Option<(usize, anyhow::Error)> = None;
+    for (shard, result) in shards.iter().zip(shard_results) {
+        match result {
+            Ok(distances) => {
+                for (&index, distance) in shard.iter().zip(distances) {
+                    results[index] = Some(distance);
+                }
+            }
+            Err((index, err)) => {
+                let earliest = match &first_error {
+                    Some((first, _)) => index < *first,
+                    None => true,
+                };
+                if earliest {
+                    first_error = Some((index, err));
+                }
+            }
+        }
+    }
+    if let Some((_, err)) = first_error {
+        return Err(err);
+    }
+    Ok(results
+        .into_iter()
+        .map(|distance| distance.expect("every impacted label belongs to a shard"))
+        .collect())
+}
+
+/// Splits the indices of `impacted` into at most `shards` groups such that no
+/// dep edge between impacted targets crosses groups. Indices stay ascending
+/// within a group.
+fn independent_shards(
+    dep_edges: &DependencyEdges,
+    kind_by_label: &BTreeMap<String, ImpactKind>,
+    impacted: &[String],
+    shards: usize,
+) -> Vec<Vec<usize>> {
+    fn find(parent: &mut [usize], mut node: usize) -> usize {
+        while parent[node] != node {
+            parent[node] = parent[parent[node]];
+            node = parent[node];
+        }
+        node
+    }
+
+    // Union-find over every labelled target, so components also follow edges
+    // through impacted targets that are not being reported.
+    let node_of: HashMap<&str, usize> = kind_by_label
+        .keys()
+        .map(String::as_str)
+        .chain(impacted.iter().map(String::as_str))
+        .fold(HashMap::new(), |mut nodes, label| {
+            let next = nodes.len();
+            nodes.entry(label).or_insert(next);
+            nodes
+        });
+    let mut parent: Vec<usize> = (0..node_of.len()).collect();
+    for (label, &node) in &node_of {
+        for dep in dep_edges.get(*label).into_iter().flatten() {
+            if let Some(&dep_node) = node_of.get(dep.as_str()) {
+                let (a, b) = (find(&mut parent, node), find(&mut parent, dep_node));
+                if a != b {
+                    parent[a] = b;
+                }
+            }
+        }
+    }
+
+    let mut components: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (index, label) in impacted.iter().enumerate() {
+        let root = find(&mut parent, node_of[label.as_str()]);
+        components.entry(root).or_default().push(index);
+    }
+    let mut components: Vec<Vec<usize>> = components.into_values().collect();
+    components.sort_by_key(|component| std::cmp::Reverse(component.len()));
+
+    let mut grouped: Vec<Vec<usize>> = vec![Vec::new(); shards.min(components.len())];
+    for component in components {
+        if let Some(lightest) = grouped.iter_mut().min_by_key(|shard| shard.len()) {
+            lightest.extend(component);
+        }
+    }
+    for shard in &mut grouped {
+        shard.sort_unstable();
+    }
+    grouped
+}
+
+/// Classifies a label from its hashes before and after; `None` where the
+/// label does not exist.
+pub fn classify_impact(
+    start_hash: Option<&TargetHash>,
+    final_hash: Option<&TargetHash>,
+) -> ImpactKind {
     match (start_hash, final_hash) {
         (None, _) | (_, None) => ImpactKind::Direct,
-        (Some(start), Some(end)) => match (&start.direct_hash, &end.direct_hash) {
+        (Some(start), Some(end)) => match (start.direct_hash(), end.direct_hash()) {
             (Some(start_direct), Some(end_direct)) if start_direct == end_direct => {
                 ImpactKind::Indirect
             }
@@ -201,6 +1089,8 @@ fn calculate_distance(
             label: label.to_string(),
             target_distance: 0,
             package_distance: 0,
+            weighted_distance: None,
+            generating_rule: None,
         },
         Some(ImpactKind::Indirect) => {
             let deps = dep_edges.get(label).ok_or_else(|| {
@@ -233,6 +1123,8 @@ fn calculate_distance(
                 label: label.to_string(),
                 target_distance,
                 package_distance,
+                weighted_distance: None,
+                generating_rule: None,
             }
         }
         None => bail!("{label} was not marked as impacted"),
@@ -243,13 +1135,14 @@ fn calculate_distance(
     Ok(result)
 }
 
-fn package_segment(label: &str) -> &str {
+pub(crate) fn package_segment(label: &str) -> &str {
     label.split(':').next().unwrap_or(label)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hash_function::HashFunction;
     use crate::models::TargetHash;
 
     fn hash(value: &str) -> TargetHash {
@@ -280,6 +1173,82 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn hashes_with_and_without_target_type_are_not_compared_unless_normalized() {
+        let start = BTreeMap::from([
+            ("//pkg:a".into(), hash("h1~d1")),
+            ("//pkg:b".into(), hash("h2~d2")),
+            ("//pkg:gone".into(), hash("Rule#h3")),
+        ]);
+        let final_map = BTreeMap::from([
+            ("//pkg:a".into(), hash("Rule#h1~d1")),
+            ("//pkg:b".into(), hash("Rule#h4~d2")),
+            ("//pkg:new".into(), hash("SourceFile#h5")),
+        ]);
+        let err = compute_impacted_targets(&start, &final_map, None).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("//pkg:a has a target type in the final hashes but not in the starting"),
+            "{err}"
+        );
+        assert!(err.to_string().contains("--normalizeTargetType"), "{err}");
+
+        let mut options = ImpactOptions {
+            normalize_target_type: true,
+            ..ImpactOptions::default()
+        };
+        let impacted = compute_impacted_targets_with_options(&start, &final_map, &options).unwrap();
+        assert_eq!(impacted, vec!["//pkg:new", "//pkg:b", "//pkg:gone"]);
+        options.target_types = Some(vec!["Rule".to_string()]);
+        let impacted = compute_impacted_targets_with_options(&final_map, &start, &options).unwrap();
+        assert_eq!(impacted, vec!["//pkg:b", "//pkg:gone"]);
+    }
+
+    #[test]
+    fn namespaced_labels_diff_and_select_per_workspace() {
+        let start = BTreeMap::from([
+            ("//pkg:a".into(), hash("h1")),
+            ("ws2!//pkg:a".into(), hash("h1")),
+        ]);
+        let final_map = BTreeMap::from([
+            ("//pkg:a".into(), hash("h1")),
+            ("ws2!//pkg:a".into(), hash("h2")),
+        ]);
+        let impacted = compute_impacted_targets(&start, &final_map, None).unwrap();
+        assert_eq!(impacted, vec!["ws2!//pkg:a"]);
+
+        let result = ImpactedTargetsResult {
+            impacted,
+            distances: None,
+        };
+        let ws2 = select_workspace_namespace(result.clone(), Some("ws2"));
+        assert_eq!(ws2.impacted, vec!["//pkg:a"]);
+        let root = select_workspace_namespace(result, None);
+        assert!(root.impacted.is_empty());
+    }
+
+    #[test]
+    fn changed_labels_expand_to_reverse_dependency_closure() {
+        let deps = BTreeMap::from([
+            ("//app:bin".into(), vec!["//pkg:lib".into()]),
+            ("//pkg:lib".into(), vec!["//pkg:src".into()]),
+            ("//pkg:other".into(), vec!["//pkg:unrelated".into()]),
+            (
+                "//pkg:cycle".into(),
+                vec!["//pkg:cycle".into(), "//pkg:src".into()],
+            ),
+        ]);
+        let impacted = impacted_from_changed_labels(&["//pkg:src".to_string()], &deps);
+        assert_eq!(
+            impacted,
+            vec!["//app:bin", "//pkg:cycle", "//pkg:lib", "//pkg:src"]
+        );
+        assert_eq!(
+            impacted_from_changed_labels(&["//not:in_graph".to_string()], &deps),
+            vec!["//not:in_graph"]
+        );
+    }
+
     #[test]
     fn computes_distances_for_indirect_changes() {
         let start = BTreeMap::from([
@@ -310,4 +1279,373 @@ mod tests {
         let b_metrics = sorted.iter().find(|d| d.label == "//pkg:b").unwrap();
         assert_eq!(b_metrics.target_distance, 0);
     }
+
+    #[test]
+    fn diff_results_compares_in_memory_runs() -> Result<()> {
+        let run = |hashes: &[(&str, &str)], deps: &[(&str, &[&str])]| GenerateHashesResult {
+            hashes: hashes
+                .iter()
+                .map(|(label, hash)| (label.to_string(), hash.to_string()))
+                .collect(),
+            dep_edges: deps
+                .iter()
+                .map(|(label, deps)| {
+                    let deps = deps.iter().map(|d| d.to_string()).collect();
+                    (label.to_string(), Some(deps))
+                })
+                .collect(),
+            exec_dep_edges: BTreeMap::new(),
+            profile: None,
+            orphan_sources: Vec::new(),
+            normalized_sources: Vec::new(),
+            file_access: None,
+            coverage: None,
+            bazel_version: None,
+            query_errors: Vec::new(),
+            test_suites: BTreeMap::new(),
+            approximate: false,
+            hash_function: HashFunction::Sha256,
+            cycles: Vec::new(),
+        };
+        let start = run(&[("//pkg:a", "Rule#a~1"), ("//pkg:b", "Rule#b")], &[]);
+        let last = run(&[("//pkg:a", "Rule#x~1"), ("//pkg:b", "Rule#c")], &[]);
+        let result = diff_results(&start, &last)?;
+        assert_eq!(result.impacted, vec!["//pkg:a", "//pkg:b"]);
+        assert_eq!(result.distances, None);
+
+        let last = run(
+            &[("//pkg:a", "Rule#x~1"), ("//pkg:b", "Rule#c")],
+            &[("//pkg:a", &["//pkg:b"]), ("//pkg:b", &[])],
+        );
+        let distances = diff_results(&start, &last)?.distances.unwrap();
+        let a = distances.iter().find(|d| d.label == "//pkg:a").unwrap();
+        assert_eq!(a.target_distance, 1);
+
+        let blake3 = GenerateHashesResult {
+            hash_function: HashFunction::Blake3,
+            ..last.clone()
+        };
+        let err = diff_results(&start, &blake3).unwrap_err();
+        assert!(err.to_string().contains("made with sha256"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cache_prime_order_builds_leaves_first() {
+        let edges = |pairs: &[(&str, &[&str])]| -> DependencyEdges {
+            pairs
+                .iter()
+                .map(|(label, deps)| {
+                    (
+                        label.to_string(),
+                        deps.iter().map(|d| d.to_string()).collect(),
+                    )
+                })
+                .collect()
+        };
+        let dep_edges = edges(&[
+            ("//app:bin", &["//lib:a", "//lib:b"]),
+            ("//lib:a", &["//lib:b", "//third:x"]),
+            ("//lib:b", &["//lib:b.cc"]),
+            ("//cycle:a", &["//cycle:b"]),
+            ("//cycle:b", &["//cycle:a"]),
+        ]);
+        let impacted = ["//app:bin", "//lib:a", "//cycle:a"].map(String::from);
+
+        assert_eq!(
+            cache_prime_order(&impacted, &dep_edges),
+            [
+                "//lib:b",
+                "//third:x",
+                "//lib:a",
+                "//app:bin",
+                "//cycle:a",
+                "//cycle:b"
+            ]
+        );
+        assert!(cache_prime_order(&[], &dep_edges).is_empty());
+    }
+
+    #[test]
+    fn generated_files_name_their_rule_and_collapse_into_it() {
+        let start = BTreeMap::from([
+            ("//pkg:gen".into(), hash("Rule#old~d1")),
+            ("//pkg:out.txt".into(), hash("GeneratedFile#old~d1")),
+            ("//pkg:src.txt".into(), hash("SourceFile#old")),
+        ]);
+        let final_map = BTreeMap::from([
+            ("//pkg:gen".into(), hash("Rule#old~d2")),
+            ("//pkg:out.txt".into(), hash("GeneratedFile#old~d2")),
+            ("//pkg:src.txt".into(), hash("SourceFile#new")),
+        ]);
+        let deps = BTreeMap::from([
+            ("//pkg:gen".into(), vec!["//pkg:src.txt".into()]),
+            ("//pkg:out.txt".into(), vec!["//pkg:gen".into()]),
+        ]);
+        let result = impacted_targets_from_hashes(&start, &final_map, Some(&deps), None).unwrap();
+        let rule_of = |label: &str| {
+            let distances = result.distances.as_ref().unwrap();
+            let distance = distances.iter().find(|d| d.label == label).unwrap();
+            distance.generating_rule.clone()
+        };
+        assert_eq!(rule_of("//pkg:out.txt").as_deref(), Some("//pkg:gen"));
+        assert_eq!(rule_of("//pkg:gen"), None);
+
+        let collapsed = collapse_generated_files(result);
+        assert_eq!(collapsed.impacted, vec!["//pkg:src.txt", "//pkg:gen"]);
+        let labels: Vec<&str> = collapsed
+            .distances
+            .iter()
+            .flatten()
+            .map(|d| d.label.as_str())
+            .collect();
+        assert_eq!(labels, vec!["//pkg:src.txt", "//pkg:gen"]);
+    }
+
+    #[test]
+    fn impacted_test_suites_expand_into_their_impacted_tests() {
+        let start = BTreeMap::from([
+            ("//pkg:a_test".into(), hash("Rule#a~1")),
+            ("//pkg:b_test".into(), hash("Rule#b")),
+            ("//pkg:all".into(), hash("Rule#all~1")),
+            ("//pkg:nested".into(), hash("Rule#nested~1")),
+            ("//pkg:unknown".into(), hash("Rule#unknown~1")),
+        ]);
+        let final_map = BTreeMap::from([
+            ("//pkg:a_test".into(), hash("Rule#a~2")),
+            ("//pkg:b_test".into(), hash("Rule#b")),
+            ("//pkg:all".into(), hash("Rule#all~2")),
+            ("//pkg:nested".into(), hash("Rule#nested~2")),
+            ("//pkg:unknown".into(), hash("Rule#unknown~2")),
+        ]);
+        let deps = BTreeMap::from([
+            ("//pkg:a_test".into(), vec![]),
+            (
+                "//pkg:all".into(),
+                vec!["//pkg:a_test".into(), "//pkg:b_test".into()],
+            ),
+            ("//pkg:nested".into(), vec!["//pkg:all".into()]),
+        ]);
+        let suites = BTreeMap::from([
+            (
+                "//pkg:all".to_string(),
+                vec!["//pkg:a_test".to_string(), "//pkg:b_test".to_string()],
+            ),
+            ("//pkg:nested".to_string(), vec!["//pkg:all".to_string()]),
+        ]);
+        let result = impacted_targets_from_hashes(&start, &final_map, Some(&deps), None).unwrap();
+
+        let expanded = expand_test_suites(result, &suites);
+        assert_eq!(expanded.impacted, vec!["//pkg:a_test", "//pkg:unknown"]);
+        let labels: Vec<&str> = expanded
+            .distances
+            .iter()
+            .flatten()
+            .map(|d| d.label.as_str())
+            .collect();
+        assert_eq!(labels, vec!["//pkg:a_test", "//pkg:unknown"]);
+    }
+
+    #[test]
+    fn sharded_distances_match_a_serial_run() -> Result<()> {
+        // Chains //cN:t0 <- //cN:t1 <- ... plus one target joining chains 0 and 1.
+        let mut deps = DependencyEdges::new();
+        let mut kinds = BTreeMap::new();
+        for chain in 0..6 {
+            kinds.insert(format!("//c{chain}:t0"), ImpactKind::Direct);
+            for step in 1..5 {
+                let label = format!("//c{chain}:t{step}");
+                deps.insert(label.clone(), vec![format!("//c{chain}:t{}", step - 1)]);
+                kinds.insert(label, ImpactKind::Indirect);
+            }
+        }
+        deps.insert(
+            "//join:t".to_string(),
+            vec!["//c0:t4".to_string(), "//c1:t2".to_string()],
+        );
+        kinds.insert("//join:t".to_string(), ImpactKind::Indirect);
+        let impacted: Vec<String> = kinds.keys().rev().cloned().collect();
+
+        let shards = independent_shards(&deps, &kinds, &impacted, 4);
+        assert_eq!(shards.len(), 4);
+        let serial = sharded_distances(&deps, &kinds, &impacted, 1)?;
+        assert_eq!(sharded_distances(&deps, &kinds, &impacted, 4)?, serial);
+        let join = serial.iter().find(|d| d.label == "//join:t").unwrap();
+        assert_eq!(join.target_distance, 3);
+
+        // Two broken chains: the error reported is the one a serial run hits first.
+        deps.remove("//c2:t3");
+        deps.insert("//c4:t2".to_string(), vec!["//c4:t2".to_string()]);
+        let serial_err = sharded_distances(&deps, &kinds, &impacted, 1).unwrap_err();
+        let sharded_err = sharded_distances(&deps, &kinds, &impacted, 4).unwrap_err();
+        assert_eq!(sharded_err.to_string(), serial_err.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn weighted_distances_take_the_cheapest_path() -> Result<()> {
+        let distance =
+            |label: &str, target_distance| ImpactedTargetDistance::new(label, target_distance, 0);
+        // //app:bin is two hops from the change through either library, but
+        // only the path through the cheap one counts.
+        let deps = BTreeMap::from([
+            (
+                "//app:bin".to_string(),
+                vec!["//lib:slow".to_string(), "//lib:fast".to_string()],
+            ),
+            ("//lib:slow".to_string(), vec!["//src:changed".to_string()]),
+            ("//lib:fast".to_string(), vec!["//src:changed".to_string()]),
+        ]);
+        let costs = TargetCosts::from([
+            ("//app:bin".to_string(), 5.0),
+            ("//lib:slow".to_string(), 120.0),
+            ("//lib:fast".to_string(), 0.5),
+            ("//src:changed".to_string(), 99.0),
+        ]);
+        let mut distances = vec![
+            distance("//app:bin", 2),
+            distance("//lib:fast", 1),
+            distance("//lib:slow", 1),
+            distance("//src:changed", 0),
+        ];
+        add_weighted_distances(&mut distances, &deps, &costs)?;
+        let weighted: Vec<_> = distances.iter().map(|d| d.weighted_distance).collect();
+        assert_eq!(weighted, vec![Some(5.5), Some(0.5), Some(120.0), Some(0.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_files_match_in_memory_comparison() -> Result<()> {
+        let start = BTreeMap::from([
+            ("//pkg:a".to_string(), "Rule#old_a~d1".to_string()),
+            ("//pkg:b".to_string(), "Rule#b~d2".to_string()),
+            ("//pkg:gone".to_string(), "Rule#g~g".to_string()),
+            ("//pkg:same".to_string(), "SourceFile#s~s".to_string()),
+        ]);
+        let final_map = BTreeMap::from([
+            ("//pkg:a".to_string(), "Rule#new_a~d1".to_string()),
+            ("//pkg:b".to_string(), "Rule#new_b~d3".to_string()),
+            ("//pkg:new".to_string(), "SourceFile#n~n".to_string()),
+            ("//pkg:same".to_string(), "SourceFile#s~s".to_string()),
+        ]);
+        let deps = BTreeMap::from([
+            ("//pkg:a".to_string(), vec!["//pkg:b".to_string()]),
+            ("//pkg:b".to_string(), Vec::new()),
+        ]);
+
+        let tmp = tempfile::tempdir()?;
+        let paths = ["start.json", "final.json", "deps.json"].map(|name| tmp.path().join(name));
+        std::fs::write(&paths[0], serde_json::to_vec(&start)?)?;
+        std::fs::write(&paths[1], serde_json::to_vec(&final_map)?)?;
+        std::fs::write(&paths[2], serde_json::to_vec(&deps)?)?;
+
+        let streamed = get_impacted_targets(&paths[0], &paths[1], Some(&paths[2]), None)?;
+        let mut emitted = Vec::new();
+        let count = stream_impacted_targets(&paths[0], &paths[1], None, |label| {
+            emitted.push(label.to_string());
+            Ok(())
+        })?;
+        assert_eq!(count, 4);
+        assert_eq!(
+            emitted,
+            vec!["//pkg:a", "//pkg:b", "//pkg:gone", "//pkg:new"]
+        );
+        let in_memory = impacted_targets_from_hashes(
+            &crate::models::parse_target_hashes(start)?,
+            &crate::models::parse_target_hashes(final_map)?,
+            Some(&deps),
+            None,
+        )?;
+        assert_eq!(streamed, in_memory);
+        assert_eq!(
+            streamed.impacted,
+            vec!["//pkg:new", "//pkg:a", "//pkg:b", "//pkg:gone"]
+        );
+
+        let read = |path: &std::path::PathBuf| std::fs::read(path).map(std::io::Cursor::new);
+        let from_readers = get_impacted_targets_from_readers(
+            read(&paths[0])?,
+            read(&paths[1])?,
+            Some(read(&paths[2])?),
+            None,
+        )?;
+        assert_eq!(from_readers, streamed);
+        let err = get_impacted_targets_from_readers(
+            &b"{}"[..],
+            &b"{\"//pkg:a\": 1}"[..],
+            None::<&[u8]>,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("final hashes"));
+        Ok(())
+    }
+
+    #[test]
+    fn shards_cover_every_label_once() -> Result<()> {
+        let labels: Vec<String> = ["//c:t", "//a:t", "//b:t", "//d:t", "//e:t"]
+            .map(str::to_string)
+            .into();
+        assert_eq!(shard_labels(&labels, 2, 0)?, ["//a:t", "//c:t", "//e:t"]);
+        assert_eq!(shard_labels(&labels, 2, 1)?, ["//b:t", "//d:t"]);
+        assert_eq!(shard_labels(&labels, 1, 0)?.len(), 5);
+        assert!(shard_labels(&labels, 2, 2).is_err());
+        assert!(shard_labels(&labels, 0, 0).is_err());
+
+        let result = ImpactedTargetsResult {
+            impacted: vec!["//a:lib".into(), "//a:test".into()],
+            distances: Some(vec![ImpactedTargetDistance {
+                label: "//a:test".into(),
+                target_distance: 1,
+                package_distance: 0,
+                weighted_distance: None,
+                generating_rule: None,
+            }]),
+        };
+        let tests = with_impacted_labels(result, vec!["//a:test".into(), "//b:test".into()]);
+        assert_eq!(tests.impacted, ["//a:test", "//b:test"]);
+        assert_eq!(tests.distances.map(|d| d.len()), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_of_different_functions_are_not_compared() -> Result<()> {
+        use crate::models::HashesEnvelope;
+
+        let envelope = |function: Option<HashFunction>| {
+            serde_json::to_vec(&HashesEnvelope {
+                meta: OutputMeta {
+                    hash_function: function,
+                    ..Default::default()
+                },
+                hashes: BTreeMap::from([("//pkg:a".to_string(), "h1".to_string())]),
+            })
+        };
+        let tmp = tempfile::tempdir()?;
+        let sha256 = tmp.path().join("sha256.json");
+        let implicit = tmp.path().join("implicit.json");
+        let blake3 = tmp.path().join("blake3.json");
+        std::fs::write(&sha256, envelope(Some(HashFunction::Sha256))?)?;
+        std::fs::write(&implicit, br#"{"//pkg:a": "h1"}"#)?;
+        std::fs::write(&blake3, envelope(Some(HashFunction::Blake3))?)?;
+
+        let none = None::<&Path>;
+        assert!(get_impacted_targets(&sha256, &implicit, none, None)?
+            .impacted
+            .is_empty());
+        let err = get_impacted_targets(&implicit, &blake3, none, None).unwrap_err();
+        assert!(err.to_string().contains("made with sha256"), "{err}");
+        let err = get_impacted_targets_from_readers(
+            std::fs::File::open(&blake3)?,
+            std::fs::File::open(&sha256)?,
+            None::<std::fs::File>,
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("final hashes with sha256"),
+            "{err}"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,283 @@
+use anyhow::{bail, Result};
+
+/// Checks an external repository name such as `@rules_go` or `@@rules_go+`.
+pub fn validate_repo_name(repo: &str) -> Result<()> {
+    let Some(name) = repo.strip_prefix('@') else {
+        bail!("invalid repository {repo:?}: @repo must start with @ or @@");
+    };
+    let name = name.strip_prefix('@').unwrap_or(name);
+    check_no_whitespace(repo)?;
+    if name.contains("//") {
+        bail!("invalid repository {repo:?}: pass the repository name without a package");
+    }
+    check_repo_chars(repo, name)
+}
+
+/// Checks an absolute label such as `//pkg:name` or `@repo//pkg:name`.
+pub fn validate_label(label: &str) -> Result<()> {
+    let (package, target) = split_absolute(label)?;
+    check_package(label, package, false)?;
+    let Some(target) = target else {
+        return Ok(());
+    };
+    if target.is_empty() {
+        bail!("invalid label {label:?}: target name after ':' may not be empty");
+    }
+    if target.contains(':') {
+        bail!("invalid label {label:?}: only one ':' is allowed");
+    }
+    Ok(())
+}
+
+/// Spells `label` the way query output does, after validating it: main
+/// repository labels lose their `@//` or `@@//` prefix and a missing target
+/// name defaults to the last package component, so `@//pkg/sub` becomes
+/// `//pkg/sub:sub`. A `namespace!` prefix of multi-workspace labels is kept.
+pub fn normalize_label(label: &str) -> Result<String> {
+    let (namespace, bare) = match label.split_once('!') {
+        Some((namespace, bare)) => (Some(namespace), bare),
+        None => (None, label),
+    };
+    validate_label(bare)?;
+    let bare = bare
+        .strip_prefix("@@//")
+        .or_else(|| bare.strip_prefix("@//"))
+        .map_or_else(|| bare.to_string(), |rest| format!("//{rest}"));
+    let (package, target) = split_absolute(&bare)?;
+    let mut normalized = match (target, package.rsplit('/').next()) {
+        (None, Some(name)) if !name.is_empty() => format!("{bare}:{name}"),
+        _ => bare.clone(),
+    };
+    if let Some(namespace) = namespace {
+        normalized = format!("{namespace}!{normalized}");
+    }
+    Ok(normalized)
+}
+
+/// A bzlmod canonical repository name: `rules_go+` for a module,
+/// `rules_go+0.50.0` when several versions of it are in the graph,
+/// `rules_python++pip+pypi__foo` for a repository created by a module
+/// extension and `+_repo_rules+local` for a `use_repo_rule` repository of
+/// the root module. Bazel 7 separates the parts with `~` instead of `+`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanonicalRepoName<'a> {
+    /// Module that owns the repository; empty (or `_main`) for the root module.
+    pub module: &'a str,
+    /// Module version, empty unless several versions of the module are used.
+    pub version: &'a str,
+    /// Extension (or `_repo_rules`) and the name of the repository it created.
+    pub extension: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> CanonicalRepoName<'a> {
+    /// Parses `name`, with or without its `@`/`@@` prefix; `None` for apparent
+    /// names such as `rules_go` and for names that do not follow the scheme.
+    pub fn parse(name: &'a str) -> Option<Self> {
+        let name = name.trim_start_matches('@');
+        let (separator, other) = if name.contains('+') {
+            ('+', '~')
+        } else {
+            ('~', '+')
+        };
+        if name.contains(other) {
+            return None;
+        }
+        let parts: Vec<&str> = name.split(separator).collect();
+        match parts[..] {
+            [module, version] if !module.is_empty() => Some(Self {
+                module,
+                version,
+                extension: None,
+            }),
+            // The root module's canonical name is empty (`_main` in Bazel 7),
+            // so its extension repos have no version part.
+            [module @ ("" | "_main"), extension, repo]
+                if !extension.is_empty() && !repo.is_empty() =>
+            {
+                Some(Self {
+                    module,
+                    version: "",
+                    extension: Some((extension, repo)),
+                })
+            }
+            [module, version, extension, repo] if !extension.is_empty() && !repo.is_empty() => {
+                Some(Self {
+                    module,
+                    version,
+                    extension: Some((extension, repo)),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The name the repository is referred to by: the repository an extension
+    /// created, or else the module.
+    pub fn apparent_name(&self) -> &'a str {
+        self.extension.map_or(self.module, |(_, repo)| repo)
+    }
+}
+
+/// Checks a target pattern such as `//...`, `//pkg:all`, `@repo//pkg/...:*` or
+/// a negative pattern `-//pkg/...`.
+pub fn validate_target_pattern(pattern: &str) -> Result<()> {
+    let positive = pattern.strip_prefix('-').unwrap_or(pattern);
+    let (package, target) = split_absolute(positive)?;
+    check_package(pattern, package, true)?;
+    if let Some(target) = target {
+        if target.is_empty() || target.contains(':') {
+            bail!("invalid pattern {pattern:?}: expected a single target name after ':'");
+        }
+    }
+    Ok(())
+}
+
+/// Splits `@repo//pkg:target` into the package and optional target name after
+/// validating the repository part.
+fn split_absolute(label: &str) -> Result<(&str, Option<&str>)> {
+    if label.is_empty() {
+        bail!("pattern may not be empty");
+    }
+    check_no_whitespace(label)?;
+    let rest = if label.starts_with('@') {
+        let Some((repo, rest)) = label.split_once("//") else {
+            bail!("invalid label {label:?}: @repo must be followed by //");
+        };
+        let name = repo.trim_start_matches('@');
+        if repo.len() - name.len() > 2 {
+            bail!("invalid label {label:?}: @repo must start with @ or @@");
+        }
+        check_repo_chars(label, name)?;
+        rest
+    } else if let Some(rest) = label.strip_prefix("//") {
+        rest
+    } else {
+        bail!("invalid label {label:?}: labels must start with //, @ or @@");
+    };
+    Ok(match rest.split_once(':') {
+        Some((package, target)) => (package, Some(target)),
+        None => (rest, None),
+    })
+}
+
+fn check_no_whitespace(value: &str) -> Result<()> {
+    if value.chars().any(char::is_whitespace) {
+        bail!("invalid pattern {value:?}: pattern may not contain spaces");
+    }
+    Ok(())
+}
+
+fn check_repo_chars(value: &str, name: &str) -> Result<()> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "._-~+".contains(*c)))
+    {
+        bail!("invalid repository in {value:?}: {c:?} is not allowed in a repository name");
+    }
+    Ok(())
+}
+
+fn check_package(value: &str, package: &str, allow_wildcard: bool) -> Result<()> {
+    let package = if allow_wildcard {
+        package
+            .strip_suffix("...")
+            .map(|p| p.strip_suffix('/').unwrap_or(p))
+            .unwrap_or(package)
+    } else {
+        package
+    };
+    if package.starts_with('/') || package.ends_with('/') || package.contains("//") {
+        bail!("invalid label {value:?}: package path has an empty segment");
+    }
+    if package.contains('\\') {
+        bail!("invalid label {value:?}: use '/' to separate package directories");
+    }
+    if !allow_wildcard && package.split('/').any(|segment| segment == "...") {
+        bail!("invalid label {value:?}: '...' is only allowed in target patterns");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(result: Result<()>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_common_forms() -> Result<()> {
+        for repo in ["@rules_go", "@@rules_go+", "@@+ext+dep~1.2"] {
+            validate_repo_name(repo)?;
+        }
+        for label in ["//:BUILD", "//pkg/sub:lib", "@repo//pkg", "@@repo+//:f.txt"] {
+            validate_label(label)?;
+        }
+        for pattern in [
+            "//...",
+            "//pkg/...:all",
+            "@repo//...:*",
+            "-//pkg/...",
+            "//:all",
+        ] {
+            validate_target_pattern(pattern)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_canonical_repo_names() {
+        let parse = |name| CanonicalRepoName::parse(name).map(|c| (c, c.apparent_name()));
+        assert_eq!(
+            parse("@@rules_go+"),
+            Some((
+                CanonicalRepoName {
+                    module: "rules_go",
+                    version: "",
+                    extension: None
+                },
+                "rules_go"
+            ))
+        );
+        assert_eq!(parse("rules_go~0.50.0").unwrap().0.version, "0.50.0");
+        let (pip, apparent) = parse("rules_python++pip+pypi__foo").unwrap();
+        assert_eq!(pip.extension, Some(("pip", "pypi__foo")));
+        assert_eq!(apparent, "pypi__foo");
+        assert_eq!(parse("_main~ext~local").unwrap().1, "local");
+        assert_eq!(parse("+_repo_rules+local").unwrap().0.module, "");
+        for name in ["rules_go", "+", "dep++", "a+b~c", "a+b+c", "a++ext+"] {
+            assert_eq!(CanonicalRepoName::parse(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn normalizes_query_spellings() -> Result<()> {
+        for (label, normalized) in [
+            ("//pkg:lib", "//pkg:lib"),
+            ("@//pkg/sub", "//pkg/sub:sub"),
+            ("@@//:BUILD", "//:BUILD"),
+            ("@repo//pkg", "@repo//pkg:pkg"),
+            ("ws!@//pkg", "ws!//pkg:pkg"),
+        ] {
+            assert_eq!(normalize_label(label)?, normalized);
+        }
+        assert!(normalize_label("pkg:lib").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reports_specific_problems() {
+        assert!(message(validate_repo_name("rules_go")).contains("must start with @ or @@"));
+        assert!(message(validate_repo_name("@rules go")).contains("may not contain spaces"));
+        assert!(message(validate_repo_name("@repo//pkg")).contains("without a package"));
+        assert!(message(validate_label("pkg:lib")).contains("must start with //"));
+        assert!(message(validate_label("@@@repo//pkg")).contains("must start with @ or @@"));
+        assert!(message(validate_label("@repo")).contains("followed by //"));
+        assert!(message(validate_label("//pkg:a:b")).contains("only one ':'"));
+        assert!(message(validate_label("//pkg/:a")).contains("empty segment"));
+        assert!(message(validate_label("//pkg/...")).contains("only allowed in target patterns"));
+        assert!(message(validate_target_pattern("")).contains("may not be empty"));
+        assert!(message(validate_target_pattern("//a b/...")).contains("may not contain spaces"));
+    }
+}
@@ -1,14 +1,118 @@
-pub mod bazel;
-pub mod hash;
+pub mod api;
+pub(crate) mod artifact_size;
+pub(crate) mod audit;
+pub(crate) mod baseline;
+pub(crate) mod bazel;
+pub(crate) mod bazelrc;
+pub(crate) mod bep;
+pub(crate) mod budget;
+pub(crate) mod build_file;
+pub(crate) mod checkpoint;
+pub(crate) mod cleanup;
+pub(crate) mod command_log;
+pub(crate) mod compat;
+pub(crate) mod content_cache;
+pub(crate) mod coverage;
+pub(crate) mod delta;
+pub(crate) mod dep_diff;
+pub(crate) mod exclude;
+pub(crate) mod filter_script;
+pub(crate) mod git;
+pub(crate) mod gitattributes;
+pub(crate) mod group;
+pub(crate) mod hash;
+pub(crate) mod hash_function;
+#[doc(hidden)]
 pub mod impact;
-pub mod models;
+pub(crate) mod labels;
+pub(crate) mod lock;
+pub(crate) mod models;
+pub(crate) mod normalize;
+pub(crate) mod paths;
+pub(crate) mod postprocess;
+pub mod prelude;
+pub(crate) mod profile;
+pub(crate) mod provenance;
+pub(crate) mod remote;
+pub(crate) mod repo_mapping;
+pub(crate) mod report;
+pub(crate) mod rewrite;
+pub(crate) mod setops;
+pub(crate) mod stamps;
+pub(crate) mod state;
+pub(crate) mod stream;
+pub(crate) mod trend;
+pub(crate) mod validate;
+pub(crate) mod validate_impact;
+pub(crate) mod warm;
+pub(crate) mod workspace;
+pub(crate) mod write_guard;
 
-pub use hash::{generate_hashes, GenerateHashesConfig, GenerateHashesResult};
-pub use impact::{compute_impacted_targets, get_impacted_targets};
+pub use artifact_size::{artifact_size_report, ArtifactFileSize, ArtifactSizeReport};
+pub use audit::{AccessScope, FileAccess, FileAccessAudit};
+pub use baseline::{Baseline, BaselineServiceClient, LocalBaselineCache};
+pub use bazel::{
+    bazel_info, bazel_output_base, bazel_version, query_test_targets, set_expression, BazelInfo,
+    BazelOptions, BazelVersion, PlannedCommand, QueryError, QueryOutputFormat,
+};
+pub use budget::HashCoverage;
+pub use checkpoint::CheckpointConfig;
+pub use cleanup::{clean_baseline_cache, clean_temp_files, CleanReport, OutputGuard};
+pub use command_log::{set_command_log, CommandLog, CommandRecord, JsonLinesCommandLog};
+pub use compat::PlatformConstraints;
+pub use content_cache::default_content_cache_path;
+pub use coverage::{add_covering_tests, CoverageMap};
+pub use delta::{read_target_hashes_with_deltas, HashesDelta};
+pub use dep_diff::{dep_edge_changes, DepEdgeChanges};
+pub use filter_script::{target_types, FilterScript};
+pub use git::{rev_parse, show_prefix, Worktree};
+pub use gitattributes::GitAttributesMode;
+pub use group::{group_by_prefix, GroupingOptions, ImpactedGroup};
+pub use hash::{
+    generate_hashes, generate_hashes_for_workspaces, hash_target, plan_bazel_commands,
+    workspace_specs_from_paths, CyclePolicy, GenerateHashesConfig, GenerateHashesResult,
+    InputDigest, InputKind, TargetHashReport, WorkspaceSpec,
+};
+pub use hash_function::HashFunction;
+pub use impact::{
+    add_weighted_distances, cache_prime_order, classify_impact, collapse_generated_files,
+    compute_distances, compute_impacted_targets, compute_impacted_targets_with_options,
+    diff_results, expand_test_suites, get_impacted_targets, get_impacted_targets_from_readers,
+    get_impacted_targets_from_readers_with_options, get_impacted_targets_with_dep_edges,
+    get_impacted_targets_with_dep_edges_and_options, get_impacted_targets_with_options,
+    impacted_from_changed_labels, impacted_from_reverse_edges, impacted_targets_from_hashes,
+    impacted_targets_from_hashes_with_options, select_workspace_namespace, shard_labels,
+    stream_impacted_targets, stream_impacted_targets_with_options, with_impacted_labels,
+    ImpactKind, ImpactOptions,
+};
+pub use labels::{validate_label, validate_repo_name, validate_target_pattern};
+pub use lock::CacheLock;
 pub use models::{
-    read_dep_edges_file, read_target_hashes, DependencyEdges, ImpactedTargetDistance,
-    ImpactedTargetsResult, TargetHash, TargetHashes,
+    dep_edges_closure, detailed_hashes, encode_msgpack_hashes, encode_proto_hashes,
+    invert_dep_edges, namespace_label, parse_target_hashes, read_dep_edges_file,
+    read_dep_edges_from, read_directed_dep_edges_file, read_hashes_meta, read_target_costs,
+    read_target_hashes, read_target_hashes_from, split_namespaced_label, write_json_map,
+    CombinedOutput, DependencyEdges, DirectedDependencyEdges, DirectedDependencyEdgesOutput,
+    HashFileFormat, HashesEnvelope, ImpactedTargetDistance, ImpactedTargetsEnvelope,
+    ImpactedTargetsResult, IndexedDependencyEdges, OutputMeta, TargetCosts, TargetHash,
+    TargetHashDetailed, TargetHashes,
 };
+pub use normalize::NormalizeOptions;
+pub use postprocess::post_process;
+pub use profile::{HashProfile, HistogramBucket, TimedEntry};
+pub use provenance::GeneratedBy;
+pub use remote::RemoteCache;
+pub use report::{render_markdown, MarkdownReportOptions};
+pub use rewrite::{RewriteConfig, RuleInputRewriter};
+pub use setops::{read_label_set, SetOp};
+pub use stamps::ConcurrentModificationMode;
+pub use state::{cache_root, gc, GcPolicy};
+pub use trend::{compute_trend, TrendPoint};
+pub use validate::{validate_artifact, ArtifactKind, ArtifactReport, Diagnostic, Severity};
+pub use validate_impact::{validate_impact, ImpactValidation};
+pub use warm::WarmCache;
+pub use workspace::{check_workspace_root, WorkspaceError};
+pub use write_guard::WriteGuard;
 
 /// Returns the current crate version; helpful for tracing and diagnostics.
 pub fn version() -> &'static str {
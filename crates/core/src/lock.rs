@@ -0,0 +1,254 @@
+use crate::cleanup::temp_file_in;
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::info;
+
+/// How long to wait for another process to release a cache lock.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Lock file inside a cache directory, see [`CacheLock`].
+pub const DIR_LOCK_FILE: &str = ".lock";
+
+/// Advisory lock on a `<path>.lock` file next to a persistent cache artifact,
+/// released on drop. Locks are advisory: they only coordinate processes of
+/// this tool, which all go through this type.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Takes an exclusive lock for writing `target`.
+    pub fn exclusive(target: &Path, timeout: Duration) -> Result<Self> {
+        Self::acquire(lock_path_for(target), timeout, true)
+    }
+
+    /// Takes a shared lock for reading `target`; blocks only while a writer holds it.
+    pub fn shared(target: &Path, timeout: Duration) -> Result<Self> {
+        Self::acquire(lock_path_for(target), timeout, false)
+    }
+
+    /// Takes an exclusive lock on the cache directory `dir`, which waits for
+    /// every [`CacheLock`] on an entry in it to be released; hold it while
+    /// removing the directory.
+    pub fn exclusive_dir(dir: &Path, timeout: Duration) -> Result<Self> {
+        Self::acquire(dir.join(DIR_LOCK_FILE), timeout, true)
+    }
+
+    fn acquire(path: PathBuf, timeout: Duration, exclusive: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file {}", path.display()))?;
+
+        let started = Instant::now();
+        let mut backoff = Duration::from_millis(25);
+        let mut announced = false;
+        loop {
+            let attempt = if exclusive {
+                fs2::FileExt::try_lock_exclusive(&file)
+            } else {
+                fs2::FileExt::try_lock_shared(&file)
+            };
+            match attempt {
+                Ok(()) => return Ok(Self { file, path }),
+                Err(err) if is_contended(&err) => {
+                    if started.elapsed() >= timeout {
+                        bail!(
+                            "timed out after {}s waiting for {}; another bazel-differrous \
+                             process is using it",
+                            timeout.as_secs(),
+                            path.display()
+                        );
+                    }
+                    if !announced {
+                        info!(lock = %path.display(), "waiting for another process to release lock");
+                        announced = true;
+                    }
+                    wait(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("failed to lock {}", path.display()))
+                }
+            }
+        }
+    }
+}
+
+/// A lock on an entry of a persistent cache that also holds a shared lock on
+/// the directory containing it, so a `gc` that takes the directory
+/// exclusively (see [`FileLock::exclusive_dir`]) never removes the directory
+/// while the entry is being read or written.
+#[derive(Debug)]
+pub struct CacheLock {
+    _entry: FileLock,
+    _dir: FileLock,
+}
+
+impl CacheLock {
+    /// Takes an exclusive lock for writing the cache entry `target`, creating
+    /// its directory.
+    pub fn exclusive(target: &Path, timeout: Duration) -> Result<Self> {
+        Self::acquire(target, timeout, true)
+    }
+
+    /// Takes a shared lock for reading the cache entry `target`, creating its
+    /// directory.
+    pub fn shared(target: &Path, timeout: Duration) -> Result<Self> {
+        Self::acquire(target, timeout, false)
+    }
+
+    fn acquire(target: &Path, timeout: Duration, exclusive: bool) -> Result<Self> {
+        let dir = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let dir_lock = FileLock::acquire(dir.join(DIR_LOCK_FILE), timeout, false)?;
+        Ok(Self {
+            _entry: FileLock::acquire(lock_path_for(target), timeout, exclusive)?,
+            _dir: dir_lock,
+        })
+    }
+}
+
+/// Sleeps between lock attempts. On a multi-threaded Tokio runtime the
+/// worker's other tasks are handed to another thread first, so a caller in
+/// async code doesn't stall them while it waits.
+fn wait(backoff: Duration) {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| thread::sleep(backoff))
+        }
+        _ => thread::sleep(backoff),
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs2::FileExt::unlock(&self.file);
+        tracing::debug!(lock = %self.path.display(), "released lock");
+    }
+}
+
+fn is_contended(err: &std::io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock
+        || err.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// The lock file guarding `target`.
+pub fn lock_path_for(target: &Path) -> PathBuf {
+    let mut name = target
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".lock");
+    target.with_file_name(name)
+}
+
+/// Atomic write protocol: `write` fills a temporary file in the target's
+/// directory, which is flushed, fsynced and renamed over `target`, so readers
+/// see either the old or the new content and never a torn file. Returns the
+/// still-open file so callers can keep appending.
+pub fn write_atomically<F>(target: &Path, write: F) -> Result<File>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut temp = temp_file_in(dir, ".partial")?;
+    write(temp.as_file_mut())?;
+    temp.as_file_mut().flush()?;
+    temp.as_file()
+        .sync_all()
+        .with_context(|| format!("failed to sync {}", temp.path().display()))?;
+    temp.persist(target)
+        .with_context(|| format!("failed to replace {}", target.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_lock_times_out_while_held() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let target = tmp.path().join("cache.ndjson");
+        let held = FileLock::exclusive(&target, Duration::ZERO)?;
+        assert!(lock_path_for(&target).ends_with("cache.ndjson.lock"));
+
+        let err = FileLock::shared(&target, Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        drop(held);
+        let _first = FileLock::shared(&target, Duration::ZERO)?;
+        let _second = FileLock::shared(&target, Duration::ZERO)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cache_locks_hold_their_directory() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().join("state");
+        let entry = CacheLock::exclusive(&dir.join("cache.msgpack"), Duration::ZERO)?;
+        let _reader = CacheLock::shared(&dir.join("other.json"), Duration::ZERO)?;
+        assert!(FileLock::exclusive_dir(&dir, Duration::ZERO).is_err());
+        assert!(CacheLock::shared(&dir.join("cache.msgpack"), Duration::ZERO).is_err());
+
+        drop(entry);
+        drop(_reader);
+        let _gc = FileLock::exclusive_dir(&dir, Duration::ZERO)?;
+        assert!(CacheLock::shared(&dir.join("cache.msgpack"), Duration::ZERO).is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn waiting_for_a_lock_leaves_the_runtime_running() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let target = tmp.path().join("cache.msgpack");
+        let held = FileLock::exclusive(&target, Duration::ZERO)?;
+        // Both tasks run on the only worker, so the release can only run
+        // while the waiting task has handed it off.
+        let waiter =
+            tokio::spawn(async move { FileLock::exclusive(&target, Duration::from_secs(5)) });
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        })
+        .await?;
+        waiter.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_write_replaces_content() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let target = tmp.path().join("out.txt");
+        std::fs::write(&target, "old")?;
+
+        let mut file = write_atomically(&target, |f| Ok(f.write_all(b"new")?))?;
+        file.write_all(b" and appended")?;
+        drop(file);
+        assert_eq!(std::fs::read_to_string(&target)?, "new and appended");
+
+        let failed = write_atomically(&target, |_| bail!("boom"));
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&target)?, "new and appended");
+        Ok(())
+    }
+}
@@ -1,89 +1,944 @@
+use crate::bazel::QueryError;
+use crate::budget::HashCoverage;
+use crate::hash_function::HashFunction;
+use crate::provenance::GeneratedBy;
 use anyhow::{bail, Context, Result};
-use serde::Serialize;
-use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+use bazel_differrous_proto::hashes as proto_hashes;
+use prost::Message;
+use rayon::prelude::*;
+use serde::de::{IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    io::{Read, Write},
+    path::Path,
+};
 
 pub type TargetHashes = BTreeMap<String, TargetHash>;
 pub type DependencyEdges = BTreeMap<String, Vec<String>>;
+/// Build or test cost in seconds per label, e.g. aggregated from BEP history.
+pub type TargetCosts = HashMap<String, f64>;
 
+/// Maps with fewer entries than this are serialized on one thread.
+const PARALLEL_JSON_THRESHOLD: usize = 65_536;
+
+/// Entries serialized per task by [`write_json_map`].
+const JSON_CHUNK_ENTRIES: usize = 16_384;
+
+/// Separates a workspace namespace from a label, e.g. `ws2!//pkg:t`.
+pub const WORKSPACE_NAMESPACE_SEPARATOR: char = '!';
+
+/// Prefixes `label` with `namespace`, leaving root-workspace labels untouched.
+pub fn namespace_label(namespace: Option<&str>, label: &str) -> String {
+    match namespace {
+        Some(ns) => format!("{ns}{WORKSPACE_NAMESPACE_SEPARATOR}{label}"),
+        None => label.to_string(),
+    }
+}
+
+/// Splits a possibly namespaced label into its workspace namespace and the
+/// plain Bazel label. Labels from the root workspace have no namespace.
+pub fn split_namespaced_label(label: &str) -> (Option<&str>, &str) {
+    if let Some((ns, rest)) = label.split_once(WORKSPACE_NAMESPACE_SEPARATOR) {
+        let plausible_ns = !ns.is_empty() && !ns.contains(['/', ':', '@']);
+        if plausible_ns && (rest.starts_with("//") || rest.starts_with('@')) {
+            return (Some(ns), rest);
+        }
+    }
+    (None, label)
+}
+
+/// A parsed `Type#transitive~direct` hash string. The string is stored once
+/// and its parts are read through offsets into it, so large hash files cost
+/// one allocation per target; [`raw`](Self::raw),
+/// [`transitive_hash`](Self::transitive_hash),
+/// [`direct_hash`](Self::direct_hash) and [`target_type`](Self::target_type)
+/// read what were once public fields of the same names.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TargetHash {
-    pub raw: String,
-    pub transitive_hash: String,
-    pub direct_hash: Option<String>,
-    pub target_type: Option<String>,
+    raw: Box<str>,
+    /// Offset of the `#` ending the target type, if there is one.
+    type_end: Option<u32>,
+    /// Offset of the `~` starting the direct hash, if there is one.
+    direct_start: Option<u32>,
 }
 
 impl TargetHash {
     pub fn parse(raw: &str) -> Result<Self> {
-        let (target_type, remainder) = match raw.split_once('#') {
-            Some((kind, rest)) => (Some(kind.to_string()), rest.to_string()),
-            None => (None, raw.to_string()),
-        };
+        Self::parse_owned(raw.to_string())
+    }
 
-        let (transitive_hash, direct_hash) = match remainder.split_once('~') {
-            Some((transitive, direct)) => (transitive.to_string(), Some(direct.to_string())),
-            None => (remainder.clone(), None),
+    /// [`parse`](Self::parse) without copying `raw`.
+    pub fn parse_owned(raw: String) -> Result<Self> {
+        let offset = |index: usize| {
+            u32::try_from(index).map_err(|_| anyhow::anyhow!("target hash string is too long"))
         };
-
-        if transitive_hash.is_empty() {
+        let type_end = raw.find('#');
+        let transitive_start = type_end.map_or(0, |end| end + 1);
+        let direct_start = raw[transitive_start..]
+            .find('~')
+            .map(|index| transitive_start + index);
+        if direct_start.unwrap_or(raw.len()) == transitive_start {
             bail!("target hash string cannot be empty");
         }
-
         Ok(Self {
-            raw: raw.to_string(),
-            transitive_hash,
-            direct_hash,
-            target_type,
+            type_end: type_end.map(offset).transpose()?,
+            direct_start: direct_start.map(offset).transpose()?,
+            raw: raw.into_boxed_str(),
         })
     }
 
+    /// The hash string as written, e.g. `Rule#abc~def`.
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// The hash covering the target and everything it depends on.
+    pub fn transitive_hash(&self) -> &str {
+        let start = self.type_end.map_or(0, |end| end as usize + 1);
+        let end = self
+            .direct_start
+            .map_or(self.raw.len(), |start| start as usize);
+        &self.raw[start..end]
+    }
+
+    /// The hash of the target's own attributes and sources, if recorded.
+    pub fn direct_hash(&self) -> Option<&str> {
+        self.direct_start
+            .map(|start| &self.raw[start as usize + 1..])
+    }
+
+    /// The target type, e.g. `Rule`, when the hashes record it.
     pub fn target_type(&self) -> Option<&str> {
-        self.target_type.as_deref()
+        self.type_end.map(|end| &self.raw[..end as usize])
+    }
+
+    /// The hash without its target type, as generated without
+    /// `--includeTargetType`.
+    pub fn untyped(&self) -> &str {
+        &self.raw[self.type_end.map_or(0, |end| end as usize + 1)..]
+    }
+
+    /// The parts of the hash as separate fields.
+    pub fn detailed(&self) -> TargetHashDetailed {
+        TargetHashDetailed {
+            transitive: self.transitive_hash().to_string(),
+            direct: self.direct_hash().map(str::to_string),
+            target_type: self.target_type().map(str::to_string),
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// A [`TargetHash`] with its parts as separate fields, as written by
+/// `--outputFormat detailed-json`:
+/// `{"transitive": "...", "direct": "...", "type": "Rule"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetHashDetailed {
+    pub transitive: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub target_type: Option<String>,
+}
+
+impl TargetHashDetailed {
+    /// The `Type#transitive~direct` hash string of these parts.
+    pub fn render(&self) -> Result<String> {
+        let separators = |part: &str, name: &str| {
+            if part.contains(['#', '~']) {
+                bail!("{name} hash {part:?} may not contain '#' or '~'");
+            }
+            Ok(())
+        };
+        separators(&self.transitive, "transitive")?;
+        let mut raw = String::new();
+        if let Some(target_type) = &self.target_type {
+            if target_type.contains('#') {
+                bail!("target type {target_type:?} may not contain '#'");
+            }
+            raw.push_str(target_type);
+            raw.push('#');
+        }
+        raw.push_str(&self.transitive);
+        if let Some(direct) = &self.direct {
+            separators(direct, "direct")?;
+            raw.push('~');
+            raw.push_str(direct);
+        }
+        Ok(raw)
+    }
+
+    pub fn to_target_hash(&self) -> Result<TargetHash> {
+        TargetHash::parse_owned(self.render()?)
+    }
+}
+
+/// `hashes` as written by `--outputFormat detailed-json`.
+pub fn detailed_hashes(
+    hashes: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, TargetHashDetailed>> {
+    hashes
+        .iter()
+        .map(|(label, raw)| {
+            let hash =
+                TargetHash::parse(raw).with_context(|| format!("invalid hash for {label}"))?;
+            Ok((label.clone(), hash.detailed()))
+        })
+        .collect()
+}
+
+/// How far an impacted target is from the nearest change. Fields may be added,
+/// so values from outside this crate are made with
+/// [`ImpactedTargetDistance::new`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[non_exhaustive]
 pub struct ImpactedTargetDistance {
     pub label: String,
     #[serde(rename = "targetDistance")]
     pub target_distance: usize,
     #[serde(rename = "packageDistance")]
     pub package_distance: usize,
+    /// Seconds of build cost between the nearest change and this target; only
+    /// set when a cost file is supplied (see
+    /// [`add_weighted_distances`](crate::impact::add_weighted_distances)).
+    #[serde(rename = "weightedDistance", skip_serializing_if = "Option::is_none")]
+    pub weighted_distance: Option<f64>,
+    /// Rule producing this target when it is a generated file; only set when
+    /// the hashes record target types (`--includeTargetType`).
+    #[serde(rename = "generatingRule", skip_serializing_if = "Option::is_none")]
+    pub generating_rule: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl ImpactedTargetDistance {
+    /// A distance without a weighted distance or generating rule.
+    pub fn new(label: impl Into<String>, target_distance: usize, package_distance: usize) -> Self {
+        Self {
+            label: label.into(),
+            target_distance,
+            package_distance,
+            weighted_distance: None,
+            generating_rule: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ImpactedTargetsResult {
     pub impacted: Vec<String>,
     pub distances: Option<Vec<ImpactedTargetDistance>>,
 }
 
+/// Machine-readable outcome of an impact run, tagged with `status` so that an
+/// empty impacted set (`{"status": "ok", "impacted": []}`) cannot be mistaken
+/// for a failed run (`{"status": "error", "error": "..."}`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum ImpactedTargetsEnvelope<'a> {
+    Ok {
+        impacted: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        distances: Option<&'a [ImpactedTargetDistance]>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl<'a> From<&'a ImpactedTargetsResult> for ImpactedTargetsEnvelope<'a> {
+    fn from(result: &'a ImpactedTargetsResult) -> Self {
+        Self::Ok {
+            impacted: &result.impacted,
+            distances: result.distances.as_deref(),
+        }
+    }
+}
+
+/// Reads a hash file written in any `--outputFormat`, detected from its
+/// first bytes (see [`HashFileFormat::detect`]).
 pub fn read_target_hashes<P: AsRef<Path>>(path: P) -> Result<TargetHashes> {
     let path_ref = path.as_ref();
-    let file = File::open(path_ref)
+    if crate::checkpoint::is_ndjson_hashes(path_ref)? {
+        return crate::checkpoint::read_ndjson_hashes(path_ref);
+    }
+    let data = std::fs::read(path_ref)
         .with_context(|| format!("failed to open hashes file {}", path_ref.display()))?;
-    let reader = BufReader::new(file);
-    let raw_map: BTreeMap<String, String> = serde_json::from_reader(reader)
-        .with_context(|| format!("failed to parse JSON hashes from {}", path_ref.display()))?;
+    parse_hashes(&data, &path_ref.display().to_string())
+}
+
+/// Same as [`read_target_hashes`] for a hash file that is not on disk, e.g.
+/// one downloaded from object storage; `source` names it in errors.
+pub fn read_target_hashes_from<R: Read>(mut reader: R, source: &str) -> Result<TargetHashes> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read hashes from {source}"))?;
+    parse_hashes(&data, source)
+}
+
+/// Value of `HashFileHeader.magic` in `--outputFormat proto` hash files.
+pub const PROTO_HASHES_MAGIC: &str = "bazel-differrous-hashes";
+
+/// Version written to `HashFileHeader.version`.
+const PROTO_HASHES_VERSION: u32 = 1;
+
+/// Encodings of a hash file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFileFormat {
+    /// A label → hash map, a json-v2 envelope, a combined document or a delta.
+    Json,
+    Ndjson,
+    /// Length-delimited `hashes.proto` messages: a header, then one entry per
+    /// target.
+    Proto,
+    /// A MessagePack map of labels to hashes.
+    Msgpack,
+}
+
+impl HashFileFormat {
+    /// Tells the formats apart by their first bytes: NDJSON starts with its
+    /// fingerprint header, proto with its magic header and MessagePack with
+    /// a map marker, which no JSON document starts with.
+    pub fn detect(data: &[u8]) -> Self {
+        if crate::checkpoint::is_ndjson_data(data) {
+            Self::Ndjson
+        } else if proto_header(data).is_some() {
+            Self::Proto
+        } else if matches!(data.first(), Some(0x80..=0x8f | 0xde | 0xdf)) {
+            Self::Msgpack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Encodes `hashes` as a `--outputFormat proto` hash file.
+pub fn encode_proto_hashes(hashes: &BTreeMap<String, String>) -> Vec<u8> {
+    let header = proto_hashes::HashFileHeader {
+        magic: PROTO_HASHES_MAGIC.to_string(),
+        version: PROTO_HASHES_VERSION,
+    };
+    let mut out = header.encode_length_delimited_to_vec();
+    for (label, hash) in hashes {
+        let entry = proto_hashes::TargetHashEntry {
+            label: label.clone(),
+            hash: hash.clone(),
+        };
+        out.extend(entry.encode_length_delimited_to_vec());
+    }
+    out
+}
+
+/// Encodes `hashes` as a `--outputFormat msgpack` hash file.
+pub fn encode_msgpack_hashes(hashes: &BTreeMap<String, String>) -> Result<Vec<u8>> {
+    rmp_serde::to_vec(hashes).context("failed to encode hashes as MessagePack")
+}
+
+/// Writes `map` as compact JSON, byte for byte what `serde_json::to_writer`
+/// writes, serializing chunks of large maps on all threads and writing them in
+/// key order.
+pub fn write_json_map<W: Write, V: Serialize + Sync>(
+    mut writer: W,
+    map: &BTreeMap<String, V>,
+) -> serde_json::Result<()> {
+    if map.len() < PARALLEL_JSON_THRESHOLD {
+        return serde_json::to_writer(writer, map);
+    }
+    let entries: Vec<(&String, &V)> = map.iter().collect();
+    let chunks = entries
+        .par_chunks(JSON_CHUNK_ENTRIES)
+        .map(|chunk| {
+            let mut buf = Vec::with_capacity(chunk.len() * 96);
+            for (i, (key, value)) in chunk.iter().enumerate() {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                serde_json::to_writer(&mut buf, key)?;
+                buf.push(b':');
+                serde_json::to_writer(&mut buf, value)?;
+            }
+            Ok(buf)
+        })
+        .collect::<serde_json::Result<Vec<Vec<u8>>>>()?;
+
+    writer.write_all(b"{").map_err(serde_json::Error::io)?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",").map_err(serde_json::Error::io)?;
+        }
+        writer.write_all(chunk).map_err(serde_json::Error::io)?;
+    }
+    writer.write_all(b"}").map_err(serde_json::Error::io)
+}
+
+/// The header of a proto hash file and the messages after it.
+fn proto_header(mut data: &[u8]) -> Option<(proto_hashes::HashFileHeader, &[u8])> {
+    let header = proto_hashes::HashFileHeader::decode_length_delimited(&mut data).ok()?;
+    (header.magic == PROTO_HASHES_MAGIC).then_some((header, data))
+}
+
+fn parse_hashes(data: &[u8], source: &str) -> Result<TargetHashes> {
+    match HashFileFormat::detect(data) {
+        HashFileFormat::Ndjson => crate::checkpoint::parse_ndjson_hashes(data, source),
+        HashFileFormat::Proto => parse_proto_hashes(data, source),
+        HashFileFormat::Msgpack => {
+            let raw = rmp_serde::from_slice::<RawHashes>(data)
+                .with_context(|| format!("failed to parse MessagePack hashes from {source}"))?;
+            check_raw_hashes(raw, source)
+        }
+        HashFileFormat::Json => parse_json_hashes(data, source),
+    }
+}
+
+fn parse_proto_hashes(data: &[u8], source: &str) -> Result<TargetHashes> {
+    let Some((header, mut entries)) = proto_header(data) else {
+        bail!("{source} is not a proto hash file");
+    };
+    if header.version != PROTO_HASHES_VERSION {
+        bail!(
+            "{source} is a version {} proto hash file; this build reads version {PROTO_HASHES_VERSION}",
+            header.version
+        );
+    }
+    let mut raw = RawHashes::default();
+    while !entries.is_empty() {
+        let entry = proto_hashes::TargetHashEntry::decode_length_delimited(&mut entries)
+            .with_context(|| format!("failed to parse proto hashes from {source}"))?;
+        if raw.hashes.contains_key(&entry.label) {
+            raw.duplicates.insert(entry.label.clone());
+        }
+        raw.hashes.insert(entry.label, entry.hash);
+    }
+    check_raw_hashes(raw, source)
+}
+
+fn parse_json_hashes(data: &[u8], source: &str) -> Result<TargetHashes> {
+    if starts_with_key(data, "baseDigest") {
+        bail!("{source} is a delta hash file; apply it on top of its baseline with --applyDelta");
+    }
+    let raw = if starts_with_key(data, "meta") || starts_with_key(data, "hashes") {
+        serde_json::from_slice::<RawEnvelope>(data).map(|envelope| envelope.hashes)
+    } else {
+        serde_json::from_slice::<RawHashes>(data)
+    }
+    .with_context(|| format!("failed to parse JSON hashes from {source}"))?;
+    check_raw_hashes(raw, source)
+}
+
+fn check_raw_hashes(raw: RawHashes, source: &str) -> Result<TargetHashes> {
+    if !raw.duplicates.is_empty() {
+        bail!(
+            "{source} lists {} label(s) more than once, so it is corrupted or was merged incorrectly: {}",
+            raw.duplicates.len(),
+            raw.duplicates.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    parse_target_hashes(raw.hashes)
+}
+
+/// A label → hash map as read from disk. serde keeps the last value of a
+/// repeated key, so repeated labels are collected here to be reported instead.
+#[derive(Debug, Default)]
+struct RawHashes {
+    hashes: BTreeMap<String, String>,
+    duplicates: BTreeSet<String>,
+}
+
+impl<'de> Deserialize<'de> for RawHashes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawHashesVisitor;
+
+        impl<'de> Visitor<'de> for RawHashesVisitor {
+            type Value = RawHashes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of labels to hashes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RawHashes, A::Error> {
+                let mut raw = RawHashes::default();
+                while let Some((label, RawHashValue(hash))) =
+                    map.next_entry::<String, RawHashValue>()?
+                {
+                    if raw.hashes.contains_key(&label) {
+                        raw.duplicates.insert(label.clone());
+                    }
+                    raw.hashes.insert(label, hash);
+                }
+                Ok(raw)
+            }
+        }
+
+        deserializer.deserialize_map(RawHashesVisitor)
+    }
+}
+
+/// A hash string as read from disk, either written as one or as a
+/// [`TargetHashDetailed`] object, which is rendered back to the string.
+pub(crate) struct RawHashValue(pub(crate) String);
+
+impl<'de> Deserialize<'de> for RawHashValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawHashValueVisitor;
+
+        impl<'de> Visitor<'de> for RawHashValueVisitor {
+            type Value = RawHashValue;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a hash string or a detailed hash object")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, hash: &str) -> Result<RawHashValue, E> {
+                Ok(RawHashValue(hash.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, hash: String) -> Result<RawHashValue, E> {
+                Ok(RawHashValue(hash))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<RawHashValue, A::Error> {
+                let detailed = TargetHashDetailed::deserialize(
+                    serde::de::value::MapAccessDeserializer::new(map),
+                )?;
+                detailed
+                    .render()
+                    .map(RawHashValue)
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(RawHashValueVisitor)
+    }
+}
+
+/// Reading counterpart of [`HashesEnvelope`] and [`CombinedOutput`].
+#[derive(Deserialize)]
+struct RawEnvelope {
+    #[serde(rename = "meta")]
+    _meta: Option<IgnoredAny>,
+    hashes: RawHashes,
+}
+
+/// Hash output schema v2 (`--outputFormat json-v2`): the upstream label → hash
+/// map wrapped with metadata describing how it was produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashesEnvelope {
+    pub meta: OutputMeta,
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// `generate-hashes --combinedOutput`: hashes and dep edges in one document,
+/// readable both as a hash file and as a dep edges file. `dep_edges` is either
+/// dep edges format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CombinedOutput<'a, E> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OutputMeta>,
+    pub hashes: &'a BTreeMap<String, String>,
+    pub dep_edges: E,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated_by: Option<GeneratedBy>,
+    /// Targets a `--timeBudget` run left unhashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<HashCoverage>,
+    /// Algorithm of the hashes; SHA-256 when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_function: Option<HashFunction>,
+    /// Release of the Bazel server that was queried, e.g. `7.4.1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bazel_version: Option<String>,
+    /// Whether targets came from `bazel cquery` rather than `bazel query`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_cquery: Option<bool>,
+    /// Rule attributes left out of the hashes, defaults included; sorted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignored_attributes: Option<Vec<String>>,
+    /// Errors Bazel reported loading packages, whose targets are missing from
+    /// the hashes; empty when the query loaded every package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_errors: Option<Vec<QueryError>>,
+    /// Members of every `test_suite`, keyed by suite; read by
+    /// `get-impacted-targets --expandTestSuites`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_suites: Option<BTreeMap<String, Vec<String>>>,
+    /// Set when the graph was read from BUILD files without Bazel
+    /// (`--noBazel`), so the hashes only approximate Bazel's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approximate: Option<bool>,
+}
+
+/// The `meta` of a json-v2 envelope or combined document at `path`; `None`
+/// for hash files without one.
+pub fn read_hashes_meta(path: &Path) -> Result<Option<OutputMeta>> {
+    let open = || {
+        std::fs::File::open(path)
+            .with_context(|| format!("failed to open hashes file {}", path.display()))
+    };
+    let mut prefix = Vec::new();
+    open()?.take(256).read_to_end(&mut prefix)?;
+    if !starts_with_key(&prefix, "meta") {
+        return Ok(None);
+    }
+    let document: MetaOnly = serde_json::from_reader(std::io::BufReader::new(open()?))
+        .with_context(|| format!("failed to parse the metadata of {}", path.display()))?;
+    Ok(document.meta)
+}
+
+/// [`read_hashes_meta`] for a hash file already in memory.
+pub fn hashes_meta(data: &[u8], source: &str) -> Result<Option<OutputMeta>> {
+    if !starts_with_key(data, "meta") {
+        return Ok(None);
+    }
+    let document: MetaOnly = serde_json::from_slice(data)
+        .with_context(|| format!("failed to parse the metadata of {source}"))?;
+    Ok(document.meta)
+}
+
+/// A document's `meta`, skipping everything else.
+#[derive(Deserialize)]
+struct MetaOnly {
+    meta: Option<OutputMeta>,
+}
+
+/// Envelopes are always written with `meta` first, combined documents with
+/// `meta` or `hashes` first and deltas with `baseDigest` first, so a prefix
+/// check tells them apart from a v1 map (whose keys are labels) without
+/// parsing twice.
+pub(crate) fn starts_with_key(data: &[u8], key: &str) -> bool {
+    let mut rest = data.iter().skip_while(|b| b.is_ascii_whitespace());
+    if rest.next() != Some(&b'{') {
+        return false;
+    }
+    let quoted = format!("\"{key}\"");
+    rest.skip_while(|b| b.is_ascii_whitespace())
+        .take(quoted.len())
+        .copied()
+        .eq(quoted.bytes())
+}
 
+/// Parses a raw label → hash string map as produced by `generate-hashes`.
+pub fn parse_target_hashes(raw_map: BTreeMap<String, String>) -> Result<TargetHashes> {
     raw_map
         .into_iter()
         .map(|(label, raw_hash)| {
-            let parsed = TargetHash::parse(&raw_hash)
+            let parsed = TargetHash::parse_owned(raw_hash)
                 .with_context(|| format!("invalid hash for {label}"))?;
             Ok((label, parsed))
         })
         .collect()
 }
 
+/// Dep edges format v2: every label is stored once and edges refer to labels by
+/// index, serialized as `{"labels": [...], "edges": [[0, [1, 2]], ...]}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexedDependencyEdges {
+    pub labels: Vec<String>,
+    pub edges: Vec<(usize, Vec<usize>)>,
+}
+
+impl IndexedDependencyEdges {
+    pub fn from_edges(edges: &DependencyEdges) -> Self {
+        let labels: BTreeSet<&str> = edges
+            .iter()
+            .flat_map(|(label, deps)| std::iter::once(label).chain(deps.iter()))
+            .map(String::as_str)
+            .collect();
+        let index: BTreeMap<&str, usize> = labels
+            .iter()
+            .enumerate()
+            .map(|(idx, label)| (*label, idx))
+            .collect();
+
+        Self {
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            edges: edges
+                .iter()
+                .map(|(label, deps)| {
+                    (
+                        index[label.as_str()],
+                        deps.iter().map(|d| index[d.as_str()]).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn into_edges(self) -> Result<DependencyEdges> {
+        let lookup = |idx: usize| {
+            self.labels
+                .get(idx)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("dep edge index {idx} is out of range"))
+        };
+        self.edges
+            .iter()
+            .map(|(label, deps)| {
+                let deps = deps
+                    .iter()
+                    .map(|d| lookup(*d))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((lookup(*label)?, deps))
+            })
+            .collect()
+    }
+}
+
+/// Dep edges file holding reverse edges, written by `generate-hashes
+/// --depEdgesDirection reverse|both`: `{"depEdges": E, "reverseDepEdges": E}`,
+/// where `depEdges` is only present with `both` and each `E` is in either dep
+/// edges format. Reverse edges map every target to the targets depending on
+/// it directly. `generate-hashes --execDepEdges` adds `"execDepEdges": E`,
+/// the forward edges to deps only built for the exec platform.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectedDependencyEdges {
+    pub forward: Option<DependencyEdges>,
+    pub reverse: Option<DependencyEdges>,
+    pub exec: Option<DependencyEdges>,
+}
+
+/// Serialized form of [`DirectedDependencyEdges`] with `E` in either dep
+/// edges format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectedDependencyEdgesOutput<E> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dep_edges: Option<E>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverse_dep_edges: Option<E>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_dep_edges: Option<E>,
+}
+
+impl DirectedDependencyEdges {
+    pub fn from_forward(forward: DependencyEdges) -> Self {
+        Self {
+            forward: Some(forward),
+            reverse: None,
+            exec: None,
+        }
+    }
+
+    /// Drops the edges recorded as exec-only, so that a target is no longer
+    /// linked to the tools used to build it. Fails when the file recorded none.
+    pub fn without_exec_edges(self) -> Result<Self> {
+        let Some(exec) = self.exec else {
+            bail!("dep edges file has no execDepEdges; write it with generate-hashes --useCquery --execDepEdges");
+        };
+        let is_exec = |label: &str, dep: &str| {
+            exec.get(label)
+                .is_some_and(|deps| deps.iter().any(|d| d == dep))
+        };
+        let forward = self.forward.map(|mut forward| {
+            for (label, deps) in &mut forward {
+                deps.retain(|dep| !is_exec(label, dep));
+            }
+            forward
+        });
+        let reverse = self.reverse.map(|mut reverse| {
+            for (label, dependents) in &mut reverse {
+                dependents.retain(|dependent| !is_exec(dependent, label));
+            }
+            reverse
+        });
+        Ok(Self {
+            forward,
+            reverse,
+            exec: Some(DependencyEdges::new()),
+        })
+    }
+
+    /// Forward edges, inverting the reverse edges when only those were written.
+    pub fn into_forward(self) -> DependencyEdges {
+        match (self.forward, self.reverse) {
+            (Some(forward), _) => forward,
+            (None, Some(reverse)) => invert_dep_edges(&reverse),
+            (None, None) => DependencyEdges::new(),
+        }
+    }
+
+    /// Reverse edges, inverting the forward edges when only those were written.
+    pub fn reverse(&self) -> Cow<'_, DependencyEdges> {
+        match (&self.reverse, &self.forward) {
+            (Some(reverse), _) => Cow::Borrowed(reverse),
+            (None, Some(forward)) => Cow::Owned(invert_dep_edges(forward)),
+            (None, None) => Cow::Owned(DependencyEdges::new()),
+        }
+    }
+
+    /// Forward edges between `labels`, all that distances over those labels
+    /// need. From reverse edges only the dependents of `labels` are visited,
+    /// so the whole graph is never inverted.
+    pub fn forward_among(&self, labels: &[String]) -> Cow<'_, DependencyEdges> {
+        let (None, Some(reverse)) = (&self.forward, &self.reverse) else {
+            return match &self.forward {
+                Some(forward) => Cow::Borrowed(forward),
+                None => Cow::Owned(DependencyEdges::new()),
+            };
+        };
+        let members: HashSet<&str> = labels.iter().map(String::as_str).collect();
+        let mut forward: DependencyEdges = DependencyEdges::new();
+        for label in labels {
+            for dependent in reverse.get(label).into_iter().flatten() {
+                if members.contains(dependent.as_str()) {
+                    forward
+                        .entry(dependent.clone())
+                        .or_default()
+                        .push(label.clone());
+                }
+            }
+        }
+        Cow::Owned(forward)
+    }
+}
+
+/// Swaps the direction of `edges`: every label maps to the labels listing it.
+/// Every label of `edges` gets an entry, empty when nothing lists it.
+pub fn invert_dep_edges(edges: &DependencyEdges) -> DependencyEdges {
+    let mut inverted = DependencyEdges::new();
+    for (label, targets) in edges {
+        inverted.entry(label.clone()).or_default();
+        for target in targets {
+            inverted
+                .entry(target.clone())
+                .or_default()
+                .push(label.clone());
+        }
+    }
+    inverted
+}
+
+/// Edges of `roots` and, with `transitive`, of every label they reach: the
+/// forward closure of `roots` as its own dep edges map. Roots missing from
+/// `edges` are left out.
+pub fn dep_edges_closure<'a>(
+    edges: &DependencyEdges,
+    roots: impl IntoIterator<Item = &'a str>,
+    transitive: bool,
+) -> DependencyEdges {
+    let mut closure = DependencyEdges::new();
+    let mut pending: Vec<&str> = roots.into_iter().collect();
+    while let Some(label) = pending.pop() {
+        if closure.contains_key(label) {
+            continue;
+        }
+        let Some(deps) = edges.get(label) else {
+            continue;
+        };
+        closure.insert(label.to_string(), deps.clone());
+        if transitive {
+            pending.extend(deps.iter().map(String::as_str));
+        }
+    }
+    closure
+}
+
+/// Reads a `{"label": seconds}` cost file for weighted distances.
+pub fn read_target_costs<P: AsRef<Path>>(path: P) -> Result<TargetCosts> {
+    let path_ref = path.as_ref();
+    let data = std::fs::read(path_ref)
+        .with_context(|| format!("failed to open cost file {}", path_ref.display()))?;
+    let costs: TargetCosts = serde_json::from_slice(&data)
+        .with_context(|| format!("failed to parse costs from {}", path_ref.display()))?;
+    if let Some((label, cost)) = costs.iter().find(|(_, cost)| **cost < 0.0) {
+        bail!(
+            "{}: cost of {label} must be a non-negative number of seconds, got {cost}",
+            path_ref.display()
+        );
+    }
+    Ok(costs)
+}
+
+/// Reads a dep edges file in either the upstream label map format or the
+/// indexed v2 format, as forward edges.
 pub fn read_dep_edges_file<P: AsRef<Path>>(path: P) -> Result<DependencyEdges> {
+    read_directed_dep_edges_file(path).map(DirectedDependencyEdges::into_forward)
+}
+
+/// Same as [`read_dep_edges_file`], keeping reverse edges as written.
+pub fn read_directed_dep_edges_file<P: AsRef<Path>>(path: P) -> Result<DirectedDependencyEdges> {
     let path_ref = path.as_ref();
-    let file = File::open(path_ref)
+    let data = std::fs::read(path_ref)
         .with_context(|| format!("failed to open dep edges file {}", path_ref.display()))?;
-    let reader = BufReader::new(file);
-    serde_json::from_reader(reader)
+    parse_directed_dep_edges(&data)
         .with_context(|| format!("failed to parse dep edges JSON from {}", path_ref.display()))
 }
 
+/// Same as [`read_dep_edges_file`] for dep edges that are not on disk;
+/// `source` names them in errors.
+pub fn read_dep_edges_from<R: Read>(mut reader: R, source: &str) -> Result<DependencyEdges> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read dep edges from {source}"))?;
+    parse_directed_dep_edges(&data)
+        .map(DirectedDependencyEdges::into_forward)
+        .with_context(|| format!("failed to parse dep edges JSON from {source}"))
+}
+
+fn parse_directed_dep_edges(data: &[u8]) -> Result<DirectedDependencyEdges> {
+    if !["depEdges", "reverseDepEdges", "execDepEdges"]
+        .iter()
+        .any(|key| starts_with_key(data, key))
+    {
+        return parse_dep_edges(data).map(DirectedDependencyEdges::from_forward);
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Directed {
+        dep_edges: Option<serde_json::Value>,
+        reverse_dep_edges: Option<serde_json::Value>,
+        exec_dep_edges: Option<serde_json::Value>,
+    }
+    let directed: Directed = serde_json::from_slice(data)?;
+    Ok(DirectedDependencyEdges {
+        forward: directed.dep_edges.map(dep_edges_from_value).transpose()?,
+        reverse: directed
+            .reverse_dep_edges
+            .map(dep_edges_from_value)
+            .transpose()?,
+        exec: directed
+            .exec_dep_edges
+            .map(dep_edges_from_value)
+            .transpose()?,
+    })
+}
+
+/// Dep edges in either format from an already parsed JSON value.
+fn dep_edges_from_value(value: serde_json::Value) -> Result<DependencyEdges> {
+    if let Ok(indexed) = serde_json::from_value::<IndexedDependencyEdges>(value.clone()) {
+        return indexed.into_edges();
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+fn parse_dep_edges(data: &[u8]) -> Result<DependencyEdges> {
+    if starts_with_key(data, "meta") || starts_with_key(data, "hashes") {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Combined {
+            dep_edges: Option<serde_json::Value>,
+        }
+        let Some(dep_edges) = serde_json::from_slice::<Combined>(data)?.dep_edges else {
+            bail!("hash file has no depEdges; write it with generate-hashes --combinedOutput");
+        };
+        return dep_edges_from_value(dep_edges);
+    }
+    if let Ok(indexed) = serde_json::from_slice::<IndexedDependencyEdges>(data) {
+        return indexed.into_edges();
+    }
+    Ok(serde_json::from_slice(data)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,15 +947,332 @@ mod tests {
     fn parses_hash_with_type_and_direct() {
         let parsed = TargetHash::parse("Rule#abc~def").unwrap();
         assert_eq!(parsed.target_type(), Some("Rule"));
-        assert_eq!(parsed.transitive_hash, "abc");
-        assert_eq!(parsed.direct_hash.as_deref(), Some("def"));
+        assert_eq!(parsed.transitive_hash(), "abc");
+        assert_eq!(parsed.direct_hash(), Some("def"));
+    }
+
+    #[test]
+    fn reads_v1_maps_and_v2_envelopes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let v1 = tmp.path().join("v1.json");
+        std::fs::write(&v1, r#"{"//a:b": "abc~def"}"#)?;
+        let v2 = tmp.path().join("v2.json");
+        std::fs::write(
+            &v2,
+            r#" { "meta": {"generatedBy": null}, "hashes": {"//a:b": "abc~def"}}"#,
+        )?;
+        assert_eq!(read_target_hashes(&v1)?, read_target_hashes(&v2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_detailed_hashes_as_hash_strings() -> Result<()> {
+        let hashes = BTreeMap::from([
+            ("//a:b".to_string(), "Rule#abc~def".to_string()),
+            ("//a:c".to_string(), "123".to_string()),
+        ]);
+        let detailed = detailed_hashes(&hashes)?;
+        assert_eq!(
+            serde_json::to_string(&detailed)?,
+            r#"{"//a:b":{"transitive":"abc","direct":"def","type":"Rule"},"//a:c":{"transitive":"123"}}"#
+        );
+        assert_eq!(
+            parse_hashes(&serde_json::to_vec(&detailed)?, "detailed")?,
+            parse_target_hashes(hashes)?
+        );
+        assert_eq!(
+            detailed["//a:b"].to_target_hash()?,
+            TargetHash::parse("Rule#abc~def")?
+        );
+
+        let ambiguous = br#"{"//a:b": {"transitive": "abc~def"}}"#;
+        assert!(parse_hashes(ambiguous, "ambiguous").is_err());
+        let unknown = br#"{"//a:b": {"transitive": "abc", "kind": "Rule"}}"#;
+        assert!(parse_hashes(unknown, "unknown").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_combined_output_as_hashes_and_dep_edges() -> Result<()> {
+        let hashes = BTreeMap::from([("//a:b".to_string(), "abc~def".to_string())]);
+        let dep_edges = BTreeMap::from([("//a:b".to_string(), vec!["//a:c".to_string()])]);
+        let tmp = tempfile::tempdir()?;
+        let v1 = tmp.path().join("combined.json");
+        let v2 = tmp.path().join("combined.v2.json");
+        let meta = Some(OutputMeta::default());
+        std::fs::write(
+            &v1,
+            serde_json::to_vec(&CombinedOutput {
+                meta: None,
+                hashes: &hashes,
+                dep_edges: &dep_edges,
+            })?,
+        )?;
+        std::fs::write(
+            &v2,
+            serde_json::to_vec(&CombinedOutput {
+                meta,
+                hashes: &hashes,
+                dep_edges: IndexedDependencyEdges::from_edges(&dep_edges),
+            })?,
+        )?;
+        for path in [&v1, &v2] {
+            assert_eq!(
+                read_target_hashes(path)?,
+                parse_target_hashes(hashes.clone())?
+            );
+            assert_eq!(read_dep_edges_file(path)?, dep_edges);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_json_map_matches_serde_json() -> Result<()> {
+        let hashes: BTreeMap<String, String> = (0..PARALLEL_JSON_THRESHOLD + 5)
+            .map(|i| (format!("//pkg:t\"{i}"), format!("Rule#{i:x}~{i}")))
+            .collect();
+        let mut parallel = Vec::new();
+        write_json_map(&mut parallel, &hashes)?;
+        assert_eq!(parallel, serde_json::to_vec(&hashes)?);
+
+        let edges: BTreeMap<String, Option<Vec<String>>> = [
+            ("//a".to_string(), Some(vec!["//b".to_string()])),
+            ("//b".to_string(), None),
+        ]
+        .into();
+        let mut small = Vec::new();
+        write_json_map(&mut small, &edges)?;
+        assert_eq!(small, serde_json::to_vec(&edges)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_proto_and_msgpack_hash_files() -> Result<()> {
+        let hashes = BTreeMap::from([
+            ("//a:b".to_string(), "Rule#abc~def".to_string()),
+            ("//a:c".to_string(), "123".to_string()),
+        ]);
+        let proto = encode_proto_hashes(&hashes);
+        let msgpack = encode_msgpack_hashes(&hashes)?;
+        assert_eq!(HashFileFormat::detect(&proto), HashFileFormat::Proto);
+        assert_eq!(HashFileFormat::detect(&msgpack), HashFileFormat::Msgpack);
+        assert_eq!(HashFileFormat::detect(b"{}"), HashFileFormat::Json);
+
+        let tmp = tempfile::tempdir()?;
+        let expected = parse_target_hashes(hashes)?;
+        for (name, data) in [("hashes.pb", &proto), ("hashes.msgpack", &msgpack)] {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, data)?;
+            assert_eq!(read_target_hashes(&path)?, expected);
+            assert_eq!(read_target_hashes_from(data.as_slice(), name)?, expected);
+        }
+
+        let mut duplicated = proto.clone();
+        duplicated.extend(
+            proto_hashes::TargetHashEntry {
+                label: "//a:b".to_string(),
+                hash: "x".to_string(),
+            }
+            .encode_length_delimited_to_vec(),
+        );
+        let err = parse_hashes(&duplicated, "dup.pb").unwrap_err().to_string();
+        assert!(err.ends_with(": //a:b"), "{err}");
+        assert!(parse_hashes(&proto[..proto.len() - 1], "cut.pb").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let v1 = tmp.path().join("v1.json");
+        std::fs::write(
+            &v1,
+            r#"{"//a:b": "1~1", "//c:d": "2~2", "//a:b": "3~3", "//c:d": "2~2"}"#,
+        )?;
+        let v2 = tmp.path().join("v2.json");
+        std::fs::write(
+            &v2,
+            r#"{"meta": {}, "hashes": {"//a:b": "1~1", "//a:b": "3~3"}}"#,
+        )?;
+        let err = read_target_hashes(&v1).unwrap_err().to_string();
+        assert!(err.contains("lists 2 label(s) more than once"), "{err}");
+        assert!(err.ends_with(": //a:b, //c:d"), "{err}");
+        assert!(read_target_hashes(&v2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn splits_namespaced_labels() {
+        assert_eq!(
+            split_namespaced_label("ws2!//pkg:t"),
+            (Some("ws2"), "//pkg:t")
+        );
+        assert_eq!(
+            split_namespaced_label("ws2!@repo//pkg:t"),
+            (Some("ws2"), "@repo//pkg:t")
+        );
+        assert_eq!(split_namespaced_label("//pkg:t"), (None, "//pkg:t"));
+        assert_eq!(
+            split_namespaced_label("//pkg:odd!name"),
+            (None, "//pkg:odd!name")
+        );
+        assert_eq!(namespace_label(Some("ws2"), "//pkg:t"), "ws2!//pkg:t");
+        assert_eq!(namespace_label(None, "//pkg:t"), "//pkg:t");
+    }
+
+    #[test]
+    fn indexed_dep_edges_round_trip() {
+        let edges: DependencyEdges = BTreeMap::from([
+            (
+                "//a:bin".to_string(),
+                vec!["//b:lib".to_string(), "//c:lib".to_string()],
+            ),
+            ("//b:lib".to_string(), vec!["//c:lib".to_string()]),
+            ("//c:lib".to_string(), Vec::new()),
+        ]);
+        let indexed = IndexedDependencyEdges::from_edges(&edges);
+        assert_eq!(indexed.labels, vec!["//a:bin", "//b:lib", "//c:lib"]);
+        assert_eq!(indexed.edges[0], (0, vec![1, 2]));
+
+        let json = serde_json::to_vec(&indexed).unwrap();
+        assert!(json.starts_with(br#"{"labels":["#));
+        assert_eq!(parse_dep_edges(&json).unwrap(), edges);
+
+        let v1 = serde_json::to_vec(&edges).unwrap();
+        assert_eq!(parse_dep_edges(&v1).unwrap(), edges);
+    }
+
+    #[test]
+    fn dep_edges_closure_follows_forward_edges() {
+        let edges: DependencyEdges = BTreeMap::from([
+            ("//a:bin".to_string(), vec!["//b:lib".to_string()]),
+            ("//b:lib".to_string(), vec!["//c:lib".to_string()]),
+            ("//c:lib".to_string(), Vec::new()),
+            ("//d:other".to_string(), vec!["//c:lib".to_string()]),
+        ]);
+        let direct = dep_edges_closure(&edges, ["//a:bin", "//missing:x"], false);
+        assert_eq!(direct.keys().collect::<Vec<_>>(), ["//a:bin"]);
+        let transitive = dep_edges_closure(&edges, ["//a:bin"], true);
+        assert_eq!(
+            transitive.keys().collect::<Vec<_>>(),
+            ["//a:bin", "//b:lib", "//c:lib"]
+        );
+        assert_eq!(transitive["//b:lib"], edges["//b:lib"]);
+    }
+
+    #[test]
+    fn reverse_dep_edges_are_read_without_full_inversion() -> Result<()> {
+        let forward: DependencyEdges = BTreeMap::from([
+            ("//a:bin".to_string(), vec!["//b:lib".to_string()]),
+            ("//b:lib".to_string(), vec!["//c:lib".to_string()]),
+            ("//c:lib".to_string(), Vec::new()),
+        ]);
+        let reverse = invert_dep_edges(&forward);
+        assert_eq!(reverse["//c:lib"], ["//b:lib"]);
+        assert_eq!(reverse["//a:bin"], Vec::<String>::new());
+        assert_eq!(invert_dep_edges(&reverse), forward);
+
+        let json = serde_json::to_vec(&DirectedDependencyEdgesOutput {
+            dep_edges: None,
+            reverse_dep_edges: Some(IndexedDependencyEdges::from_edges(&reverse)),
+            exec_dep_edges: None,
+        })?;
+        let directed = parse_directed_dep_edges(&json)?;
+        assert_eq!(directed.forward, None);
+        let among = ["//b:lib".to_string(), "//c:lib".to_string()];
+        assert_eq!(
+            *directed.forward_among(&among),
+            BTreeMap::from([("//b:lib".to_string(), vec!["//c:lib".to_string()])])
+        );
+        assert_eq!(directed.into_forward(), forward);
+
+        let both = serde_json::to_vec(&DirectedDependencyEdgesOutput {
+            dep_edges: Some(&forward),
+            reverse_dep_edges: Some(&reverse),
+            exec_dep_edges: None,
+        })?;
+        let directed = parse_directed_dep_edges(&both)?;
+        assert_eq!(directed.forward.as_ref(), Some(&forward));
+        assert_eq!(*directed.reverse(), reverse);
+        Ok(())
+    }
+
+    #[test]
+    fn exec_dep_edges_can_be_left_out() -> Result<()> {
+        let forward: DependencyEdges = BTreeMap::from([
+            (
+                "//a:bin".to_string(),
+                vec!["//b:lib".to_string(), "//tools:gen".to_string()],
+            ),
+            ("//b:lib".to_string(), vec!["//tools:gen".to_string()]),
+            ("//tools:gen".to_string(), Vec::new()),
+        ]);
+        let exec: DependencyEdges =
+            BTreeMap::from([("//a:bin".to_string(), vec!["//tools:gen".to_string()])]);
+        let json = serde_json::to_vec(&DirectedDependencyEdgesOutput {
+            dep_edges: Some(&forward),
+            reverse_dep_edges: Some(&invert_dep_edges(&forward)),
+            exec_dep_edges: Some(&exec),
+        })?;
+        let directed = parse_directed_dep_edges(&json)?;
+        assert_eq!(directed.exec.as_ref(), Some(&exec));
+
+        let without = directed.without_exec_edges()?;
+        assert_eq!(
+            without.forward.as_ref().expect("forward")["//a:bin"],
+            ["//b:lib"]
+        );
+        assert_eq!(
+            without.reverse.as_ref().expect("reverse")["//tools:gen"],
+            ["//b:lib"]
+        );
+
+        let forward_only = serde_json::to_vec(&DirectedDependencyEdgesOutput {
+            dep_edges: Some(&forward),
+            reverse_dep_edges: None,
+            exec_dep_edges: Some(&exec),
+        })?;
+        assert_eq!(
+            parse_directed_dep_edges(&forward_only)?.into_forward(),
+            forward
+        );
+
+        let err = DirectedDependencyEdges::from_forward(forward)
+            .without_exec_edges()
+            .unwrap_err();
+        assert!(err.to_string().contains("--execDepEdges"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn indexed_dep_edges_reject_out_of_range_indices() {
+        let indexed = IndexedDependencyEdges {
+            labels: vec!["//a:a".into()],
+            edges: vec![(0, vec![3])],
+        };
+        assert!(indexed.into_edges().is_err());
+    }
+
+    #[test]
+    fn hash_parts_split_at_the_first_separators() {
+        let parsed = TargetHash::parse("a#b#c~d~e").unwrap();
+        assert_eq!(parsed.raw(), "a#b#c~d~e");
+        assert_eq!(parsed.target_type(), Some("a"));
+        assert_eq!(parsed.transitive_hash(), "b#c");
+        assert_eq!(parsed.direct_hash(), Some("d~e"));
+        let untyped = TargetHash::parse_owned("abc~".to_string()).unwrap();
+        assert_eq!(untyped.transitive_hash(), "abc");
+        assert_eq!(untyped.direct_hash(), Some(""));
+        for empty in ["", "Rule#", "Rule#~def", "~def"] {
+            assert!(TargetHash::parse(empty).is_err(), "{empty:?}");
+        }
     }
 
     #[test]
     fn parses_hash_without_type() {
         let parsed = TargetHash::parse("abc123").unwrap();
         assert_eq!(parsed.target_type(), None);
-        assert_eq!(parsed.transitive_hash, "abc123");
-        assert_eq!(parsed.direct_hash, None);
+        assert_eq!(parsed.transitive_hash(), "abc123");
+        assert_eq!(parsed.direct_hash(), None);
     }
 }
@@ -0,0 +1,339 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Bumped whenever [`strip_comments`] changes its output, so source digests
+/// recorded by an older version are not reused.
+const LEXER_VERSION: u32 = 2;
+
+/// Which source files are normalized before hashing, keyed by file extension
+/// (without the dot) or, for files without one, by file name such as `BUILD`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NormalizeOptions {
+    /// Trailing whitespace on every line is ignored.
+    pub whitespace: Vec<String>,
+    /// Comments, trailing whitespace and blank lines are ignored. Each entry
+    /// must have a known comment syntax; see [`CommentSyntax::for_extension`].
+    pub comments: Vec<String>,
+}
+
+impl NormalizeOptions {
+    pub fn is_empty(&self) -> bool {
+        self.whitespace.is_empty() && self.comments.is_empty()
+    }
+}
+
+/// Comment and string syntax understood by the comment-stripping lexer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentSyntax {
+    /// `//` and `/* */` comments, `"`, `'` and `` ` `` strings; a `'` inside
+    /// a number is a C++14 digit separator.
+    CFamily,
+    /// `//` and `/* */` comments, `"` strings and `'` char literals; `'`
+    /// also starts lifetimes.
+    Rust,
+    /// `#` comments at the start of a line or after whitespace, `"`, `'`
+    /// and triple-quoted strings. A `#` inside a word (`${#var}`, `a#b`) is
+    /// kept.
+    Hash,
+}
+
+impl CommentSyntax {
+    pub fn for_extension(extension: &str) -> Option<Self> {
+        Some(match extension {
+            "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "m" | "mm" | "java" | "kt"
+            | "kts" | "scala" | "go" | "js" | "jsx" | "ts" | "tsx" | "swift" | "proto" | "cs" => {
+                Self::CFamily
+            }
+            "rs" => Self::Rust,
+            "py" | "bzl" | "bazel" | "BUILD" | "WORKSPACE" | "sh" | "bash" | "rb" | "yaml"
+            | "yml" | "toml" => Self::Hash,
+            _ => return None,
+        })
+    }
+
+    fn quotes(self) -> &'static [char] {
+        match self {
+            Self::CFamily => &['"', '\'', '`'],
+            Self::Rust => &['"'],
+            Self::Hash => &['"', '\''],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rule {
+    Whitespace,
+    Comments(CommentSyntax),
+}
+
+/// Applies [`NormalizeOptions`] to file contents and records which files it
+/// changed. Cheap to clone; clones share the record.
+#[derive(Debug, Clone)]
+pub(crate) struct SourceNormalizer {
+    rules: Arc<HashMap<String, Rule>>,
+    normalized: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl SourceNormalizer {
+    /// `None` when no extension is configured.
+    pub(crate) fn new(options: &NormalizeOptions) -> Result<Option<Self>> {
+        if options.is_empty() {
+            return Ok(None);
+        }
+        let mut rules = HashMap::new();
+        for extension in &options.whitespace {
+            rules.insert(extension.clone(), Rule::Whitespace);
+        }
+        for extension in &options.comments {
+            let Some(syntax) = CommentSyntax::for_extension(extension) else {
+                bail!("no comment syntax is known for .{extension} files; normalize only their whitespace instead");
+            };
+            rules.insert(extension.clone(), Rule::Comments(syntax));
+        }
+        Ok(Some(Self {
+            rules: Arc::new(rules),
+            normalized: Default::default(),
+        }))
+    }
+
+    /// Stable description of the configuration, for checkpoint fingerprints.
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|(extension, rule)| format!("{extension}={rule:?}"))
+            .collect();
+        rules.sort();
+        format!("v{LEXER_VERSION}:{}", rules.join(","))
+    }
+
+    /// Whether files at `path` are normalized.
+    pub(crate) fn applies_to(&self, path: &Path) -> bool {
+        self.rule(path).is_some()
+    }
+
+    fn rule(&self, path: &Path) -> Option<&Rule> {
+        let key = path
+            .extension()
+            .or_else(|| path.file_name())
+            .and_then(|key| key.to_str());
+        key.and_then(|key| self.rules.get(key))
+    }
+
+    /// Normalizes `data` read from `path` if its extension is configured and
+    /// it is UTF-8, recording `label` when the contents changed.
+    pub(crate) fn normalize(&self, label: &str, path: &Path, data: Vec<u8>) -> Vec<u8> {
+        let Some(rule) = self.rule(path) else {
+            return data;
+        };
+        let Ok(text) = std::str::from_utf8(&data) else {
+            return data;
+        };
+        let normalized = match rule {
+            Rule::Whitespace => strip_trailing_whitespace(text),
+            Rule::Comments(syntax) => strip_comments(text, *syntax),
+        };
+        if normalized.as_bytes() == data.as_slice() {
+            return data;
+        }
+        if let Ok(mut normalized_labels) = self.normalized.lock() {
+            normalized_labels.insert(label.to_string());
+        }
+        normalized.into_bytes()
+    }
+
+    /// Labels of the files whose contents normalization changed, sorted.
+    pub(crate) fn normalized_labels(&self) -> Vec<String> {
+        match self.normalized.lock() {
+            Ok(labels) => labels.iter().cloned().collect(),
+            Err(poisoned) => poisoned.into_inner().iter().cloned().collect(),
+        }
+    }
+}
+
+/// Removes trailing spaces, tabs and carriage returns from every line.
+pub fn strip_trailing_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let (content, newline) = match line.strip_suffix('\n') {
+            Some(content) => (content, "\n"),
+            None => (line, ""),
+        };
+        out.push_str(content.trim_end());
+        out.push_str(newline);
+    }
+    out
+}
+
+/// Removes comments, then trailing whitespace and lines left blank. String
+/// literals are kept verbatim; a block comment becomes a single space so the
+/// tokens around it stay apart.
+pub fn strip_comments(text: &str, syntax: CommentSyntax) -> String {
+    let quotes = syntax.quotes();
+    let mut code = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let before = &text[..text.len() - rest.len()];
+        let line_comment = match syntax {
+            CommentSyntax::Hash => {
+                rest.starts_with('#')
+                    && (before.is_empty() || before.ends_with(char::is_whitespace))
+            }
+            CommentSyntax::CFamily | CommentSyntax::Rust => rest.starts_with("//"),
+        };
+        if line_comment {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if syntax != CommentSyntax::Hash && rest.starts_with("/*") {
+            rest = rest[2..].find("*/").map_or("", |end| &rest[end + 4..]);
+            code.push(' ');
+        } else if syntax == CommentSyntax::Hash
+            && (rest.starts_with("\"\"\"") || rest.starts_with("'''"))
+        {
+            let end = rest[3..].find(&rest[..3]).map_or(rest.len(), |end| end + 6);
+            code.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if syntax == CommentSyntax::CFamily && c == '\'' && in_number(before) {
+            code.push(c);
+            rest = &rest[1..];
+        } else if syntax == CommentSyntax::Rust && c == '\'' {
+            let end = char_literal_len(rest).unwrap_or(1);
+            code.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if quotes.contains(&c) {
+            let end = string_literal_len(rest, c);
+            code.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else {
+            code.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    let mut out = String::with_capacity(code.len());
+    for line in code.lines().map(str::trim_end).filter(|l| !l.is_empty()) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Whether `code` ends inside a numeric literal, where a `'` is a C++14 digit
+/// separator (`1'000`) rather than the start of a char literal (`u8'a'`).
+fn in_number(code: &str) -> bool {
+    let token_start = code
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '\''))
+        .last()
+        .map(|(index, _)| index);
+    token_start.is_some_and(|start| code[start..].starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Length in bytes of the Rust char literal at the start of `text` (`'a'`,
+/// `'"'`, `'\''`); `None` when the `'` starts a lifetime or label instead.
+fn char_literal_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    let (_, first) = chars.next()?;
+    if first == '\\' {
+        return Some(string_literal_len(text, '\''));
+    }
+    match chars.next()? {
+        (index, '\'') => Some(index + 1),
+        _ => None,
+    }
+}
+
+/// Length in bytes of the string literal at the start of `text`, including
+/// its quotes; an unterminated literal runs to the end of `text`.
+fn string_literal_len(text: &str, quote: char) -> usize {
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote {
+            return index + c.len_utf8();
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_comments_but_not_strings() {
+        let c = "int a = 1; // one\n/* block\n comment */int b = 2;  \n\nchar *s = \"// kept\";\n";
+        assert_eq!(
+            strip_comments(c, CommentSyntax::CFamily),
+            "int a = 1;\n int b = 2;\nchar *s = \"// kept\";\n"
+        );
+        let rust = "fn f<'a>(s: &'a str) {} // done\nlet url = \"http://x\";\n";
+        assert_eq!(
+            strip_comments(rust, CommentSyntax::Rust),
+            "fn f<'a>(s: &'a str) {}\nlet url = \"http://x\";\n"
+        );
+        let py = "# header\nx = '#not' # note\ndoc = \"\"\"\n# kept\n\"\"\"\n";
+        assert_eq!(
+            strip_comments(py, CommentSyntax::Hash),
+            "x = '#not'\ndoc = \"\"\"\n# kept\n\"\"\"\n"
+        );
+        assert_eq!(strip_trailing_whitespace("a \t\r\n\nb  "), "a\n\nb");
+    }
+
+    #[test]
+    fn rust_char_literals_are_not_strings() {
+        let rust = "let q = '\"'; // quote\nlet e = '\\''; // escaped\nfn f<'a>() {} // x\n";
+        assert_eq!(
+            strip_comments(rust, CommentSyntax::Rust),
+            "let q = '\"';\nlet e = '\\'';\nfn f<'a>() {}\n"
+        );
+    }
+
+    #[test]
+    fn digit_separators_are_not_char_literals() {
+        let cpp =
+            "int n = 1'000'000; // million\nauto h = 0xFF'FF; // mask\nchar c = u8'a'; // a\n";
+        assert_eq!(
+            strip_comments(cpp, CommentSyntax::CFamily),
+            "int n = 1'000'000;\nauto h = 0xFF'FF;\nchar c = u8'a';\n"
+        );
+    }
+
+    #[test]
+    fn hash_only_starts_comments_after_whitespace() {
+        let sh = "echo ${#var} $# # count\n#!/bin/sh\n";
+        assert_eq!(strip_comments(sh, CommentSyntax::Hash), "echo ${#var} $#\n");
+        let yaml = "key: a#b\ncolor: '#fff' # white\n  # indented\n";
+        assert_eq!(
+            strip_comments(yaml, CommentSyntax::Hash),
+            "key: a#b\ncolor: '#fff'\n"
+        );
+    }
+
+    #[test]
+    fn records_files_it_changed() -> Result<()> {
+        let normalizer = SourceNormalizer::new(&NormalizeOptions {
+            whitespace: vec!["txt".to_string()],
+            comments: vec!["py".to_string(), "BUILD".to_string()],
+        })?
+        .expect("normalizer");
+        let normalize = |label: &str, path: &str, data: &str| {
+            normalizer.normalize(label, Path::new(path), data.as_bytes().to_vec())
+        };
+        assert_eq!(normalize("//:a.py", "a.py", "x = 1  # one\n"), b"x = 1\n");
+        assert_eq!(normalize("//:BUILD", "pkg/BUILD", "# c\nx()\n"), b"x()\n");
+        assert_eq!(normalize("//:b.txt", "b.txt", "clean\n"), b"clean\n");
+        assert_eq!(normalize("//:c.md", "c.md", "kept  \n"), b"kept  \n");
+        assert_eq!(normalizer.normalized_labels(), vec!["//:BUILD", "//:a.py"]);
+
+        let unknown = SourceNormalizer::new(&NormalizeOptions {
+            comments: vec!["md".to_string()],
+            ..Default::default()
+        });
+        assert!(unknown.is_err());
+        Ok(())
+    }
+}
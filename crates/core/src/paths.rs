@@ -0,0 +1,279 @@
+//! Workspace-relative keys are always `/`-separated, whatever the platform, so
+//! content hash maps, modified file lists and fingerprints written on one OS
+//! match on another. Keys looked up against files on disk also go through
+//! [`lookup_key`], so they match however the filesystem spells the name.
+
+use crate::write_guard::WriteGuard;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
+use unicode_normalization::UnicodeNormalization;
+
+/// Canonicalizes `path` without the `\\?\` prefix Windows adds, which would
+/// make canonical paths differ from the ones joined onto the workspace.
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    dunce::canonicalize(path)
+}
+
+/// `key` with the platform separator replaced by `/` and any leading `./`
+/// dropped, e.g. `pkg\data.txt` on Windows becomes `pkg/data.txt`.
+pub fn slash_key(key: &str) -> String {
+    let key = if MAIN_SEPARATOR == '/' {
+        key.to_string()
+    } else {
+        key.replace(MAIN_SEPARATOR, "/")
+    };
+    let mut key = key.as_str();
+    while let Some(rest) = key.strip_prefix("./") {
+        key = rest;
+    }
+    key.to_string()
+}
+
+/// The `/`-separated key of a relative `path`.
+pub fn relative_key(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+/// `base` joined with each segment of the `/`-separated `key`, so the result
+/// only uses the platform separator.
+pub fn join_key(base: &Path, key: &str) -> PathBuf {
+    let mut path = base.to_path_buf();
+    path.extend(
+        key.split('/')
+            .filter(|part| !part.is_empty() && *part != "."),
+    );
+    path
+}
+
+/// Whether a filesystem tells apart names that differ only in case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseSensitivity {
+    /// How the filesystem holding `dir` compares names, found by looking up a
+    /// name already in `dir` with its case changed. When no name there has
+    /// letters to change, a mixed-case file is created and looked up in lower
+    /// case, unless `guard` keeps `dir` from being written. Falls back to the
+    /// platform's usual default when neither works.
+    pub fn probe(dir: &Path, guard: &WriteGuard) -> Self {
+        let probed = probe_existing(dir).or_else(|| {
+            guard.check(dir).ok()?;
+            probe_new_file(dir)
+        });
+        match probed {
+            Some(true) => Self::Insensitive,
+            Some(false) => Self::Sensitive,
+            None if cfg!(any(target_os = "macos", windows)) => Self::Insensitive,
+            None => Self::Sensitive,
+        }
+    }
+}
+
+/// Whether a name in `dir` resolves with its case changed, if there is one to
+/// try.
+fn probe_existing(dir: &Path) -> Option<bool> {
+    let names: HashSet<String> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    names.iter().find_map(|name| {
+        let swapped = [name.to_lowercase(), name.to_uppercase()]
+            .into_iter()
+            .find(|swapped| swapped != name)?;
+        // Both spellings listed means they are different files.
+        if names.contains(&swapped) {
+            return Some(false);
+        }
+        Some(dir.join(swapped).symlink_metadata().is_ok())
+    })
+}
+
+/// Whether a mixed-case file created in `dir` resolves in lower case.
+fn probe_new_file(dir: &Path) -> Option<bool> {
+    let file = tempfile::Builder::new()
+        .prefix(".bazel-differrous-Case-")
+        .tempfile_in(dir)
+        .ok()?;
+    let name = file.path().file_name()?.to_str()?;
+    let folded = name.to_lowercase();
+    (folded != name).then(|| dir.join(folded).symlink_metadata().is_ok())
+}
+
+/// The `/`-separated `key`, case folded when `case` is
+/// [`CaseSensitivity::Insensitive`] and in Unicode NFC where the filesystem
+/// ignores how names are normalized. macOS may hand back names decomposed
+/// (NFD) and in whatever case they were created with, so two spellings of the
+/// same file get the same lookup key.
+pub fn lookup_key(key: &str, case: CaseSensitivity) -> String {
+    let key = slash_key(key);
+    let key = if NORMALIZATION_INSENSITIVE {
+        key.nfc().collect()
+    } else {
+        key
+    };
+    match case {
+        CaseSensitivity::Sensitive => key,
+        CaseSensitivity::Insensitive => case_fold(&key),
+    }
+}
+
+/// Whether the filesystems of this platform treat names in NFC and NFD as
+/// the same file, as APFS and HFS+ do; ext4 and NTFS tell them apart.
+pub(crate) const NORMALIZATION_INSENSITIVE: bool = cfg!(target_os = "macos");
+
+/// `name` with simple, length-preserving case folding applied to each char,
+/// as APFS, HFS+ and NTFS compare names: `ς` and `Σ` match, but `ß` and `SS`
+/// stay different files. Going through upper case first folds the letters
+/// whose lower case mappings alone would keep apart.
+fn case_fold(name: &str) -> String {
+    name.chars()
+        .map(|c| single(c.to_uppercase()).unwrap_or(c))
+        .map(|c| single(c.to_lowercase()).unwrap_or(c))
+        .collect()
+}
+
+/// The only char of a case mapping, or `None` when it maps to several.
+fn single(mut mapped: impl Iterator<Item = char>) -> Option<char> {
+    let c = mapped.next()?;
+    mapped.next().is_none().then_some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_use_forward_slashes() {
+        assert_eq!(slash_key("./pkg/data.txt"), "pkg/data.txt");
+        assert_eq!(
+            relative_key(Path::new("pkg/sub/data.txt")),
+            "pkg/sub/data.txt"
+        );
+        assert_eq!(relative_key(Path::new("./pkg//data.txt")), "pkg/data.txt");
+        let joined = join_key(Path::new("ws"), "pkg/sub/./data.txt");
+        assert_eq!(
+            joined,
+            Path::new("ws").join("pkg").join("sub").join("data.txt")
+        );
+        assert_eq!(
+            relative_key(joined.strip_prefix("ws").unwrap()),
+            "pkg/sub/data.txt"
+        );
+    }
+
+    #[test]
+    fn canonical_paths_match_joined_paths() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = canonicalize(tmp.path())?;
+        std::fs::create_dir_all(root.join("pkg"))?;
+        std::fs::write(root.join("pkg").join("data.txt"), "data")?;
+        assert_eq!(
+            canonicalize(&join_key(&root, "pkg/data.txt"))?,
+            join_key(&root, "pkg/data.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_keys_are_case_folded_when_insensitive() {
+        let decomposed = "pkg/Cafe\u{301}.txt";
+        let composed = "pkg/Caf\u{e9}.txt";
+        let expected = if NORMALIZATION_INSENSITIVE {
+            composed
+        } else {
+            decomposed
+        };
+        assert_eq!(lookup_key(decomposed, CaseSensitivity::Sensitive), expected);
+        assert_eq!(
+            lookup_key(composed, CaseSensitivity::Insensitive),
+            "pkg/caf\u{e9}.txt"
+        );
+        assert_eq!(
+            lookup_key("./PKG/\u{c9}T\u{c9}.txt", CaseSensitivity::Insensitive),
+            lookup_key("pkg/\u{e9}t\u{e9}.txt", CaseSensitivity::Insensitive)
+        );
+        assert_eq!(
+            lookup_key("pkg/\u{3a3}.txt", CaseSensitivity::Insensitive),
+            lookup_key("pkg/\u{3c2}.txt", CaseSensitivity::Insensitive)
+        );
+        // Filesystems only fold case char by char, so these are two files.
+        assert_ne!(
+            lookup_key("STRASSE.txt", CaseSensitivity::Insensitive),
+            lookup_key("stra\u{df}e.txt", CaseSensitivity::Insensitive)
+        );
+        assert_eq!(
+            lookup_key("STRA\u{df}E.txt", CaseSensitivity::Insensitive),
+            lookup_key("stra\u{df}e.txt", CaseSensitivity::Insensitive)
+        );
+        assert_eq!(
+            lookup_key(decomposed, CaseSensitivity::Insensitive)
+                == lookup_key(composed, CaseSensitivity::Insensitive),
+            NORMALIZATION_INSENSITIVE
+        );
+    }
+
+    #[test]
+    fn probing_matches_how_the_filesystem_resolves_names() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("Probe.txt"), "data")?;
+        let insensitive = tmp.path().join("probe.txt").exists();
+        let expected = if insensitive {
+            CaseSensitivity::Insensitive
+        } else {
+            CaseSensitivity::Sensitive
+        };
+        assert_eq!(
+            CaseSensitivity::probe(tmp.path(), &WriteGuard::default()),
+            expected
+        );
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+
+        // Without a name to look up, a probe file is created and removed
+        // again, but never in a read-only directory.
+        let empty = tmp.path().join("empty");
+        std::fs::create_dir(&empty)?;
+        assert_eq!(
+            CaseSensitivity::probe(&empty, &WriteGuard::default()),
+            expected
+        );
+        assert_eq!(std::fs::read_dir(&empty)?.count(), 0);
+        CaseSensitivity::probe(&empty, &WriteGuard::protecting([&empty]));
+        assert_eq!(std::fs::read_dir(&empty)?.count(), 0);
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_separators_become_slashes() {
+        assert_eq!(slash_key(r"pkg\sub\data.txt"), "pkg/sub/data.txt");
+        assert_eq!(slash_key(r".\pkg\data.txt"), "pkg/data.txt");
+        assert_eq!(
+            relative_key(Path::new(r"pkg\sub\data.txt")),
+            "pkg/sub/data.txt"
+        );
+        assert_eq!(
+            join_key(Path::new(r"C:\ws"), "pkg/data.txt"),
+            Path::new(r"C:\ws\pkg\data.txt")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn canonical_paths_have_no_verbatim_prefix() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let canonical = canonicalize(tmp.path())?;
+        assert!(!canonical.to_string_lossy().starts_with(r"\\?\"));
+        Ok(())
+    }
+}
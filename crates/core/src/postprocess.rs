@@ -0,0 +1,77 @@
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Pipes serialized output through a user-supplied filter command and returns
+/// what it printed. The command runs through the platform shell (`sh -c`, or
+/// `cmd /C` on Windows) so pipelines work, e.g. `jq -c 'with_entries(...)'`.
+/// A non-zero exit status fails the run rather than writing partial output.
+pub fn post_process(command: &str, input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to start --postProcess command `{command}`"))?;
+
+    // Feed stdin from a separate thread so a filter that writes before it has
+    // read all of its input cannot deadlock against a full stdout pipe.
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("post-process stdin unavailable")?;
+    let input = input.to_vec();
+    let feeder = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("failed to wait for --postProcess command `{command}`"))?;
+    let fed = feeder
+        .join()
+        .map_err(|_| anyhow::anyhow!("post-process input thread panicked"))?;
+    if !output.status.success() {
+        bail!(
+            "--postProcess command `{command}` failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // A filter may legitimately stop reading early (e.g. `head`).
+    if let Err(err) = fed {
+        if err.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(err).context("failed to write to --postProcess command");
+        }
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_output_and_reports_failures() -> Result<()> {
+        let out = post_process("tr a-z A-Z", b"//pkg:lib\n")?;
+        assert_eq!(out, b"//PKG:LIB\n");
+
+        let err = post_process("echo nope >&2; exit 3", b"{}").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("failed"), "{message}");
+        assert!(message.contains("nope"), "{message}");
+        Ok(())
+    }
+}
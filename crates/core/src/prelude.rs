@@ -0,0 +1,13 @@
+//! The semver-stable surface of the crate in one import:
+//! `use bazel_differrous_core::prelude::*;`.
+//!
+//! Items reach the prelude only through [`api`](crate::api), so it follows
+//! the same policy: a breaking change to anything listed here needs a major
+//! version. `tests/golden/public-api.txt` snapshots it; the `compat` tests in
+//! `crates/integration-tests` fail until the snapshot is updated, so changes
+//! to it are always deliberate.
+
+pub use crate::api::{
+    DependencyEdges, Differ, DifferBuilder, Error, Hashes, ImpactedTargetDistance,
+    ImpactedTargetsResult, QueryOutputFormat, RemoteCache, WorkspaceError,
+};
@@ -0,0 +1,192 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (exclusive, in microseconds) of the histogram buckets; the
+/// last bucket collects everything slower.
+const HISTOGRAM_BOUNDS_MICROS: &[u64] = &[1_000, 10_000, 100_000, 1_000_000];
+
+/// Collects per-target timings while hashing. Cheap to clone; clones share the
+/// same sample buffer.
+#[derive(Debug, Clone, Default)]
+pub struct HashProfiler {
+    samples: Arc<Mutex<Samples>>,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    rules: Vec<TimedEntry>,
+    sources: Vec<TimedEntry>,
+}
+
+impl HashProfiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the time spent hashing a rule, excluding its dependencies.
+    pub fn record_rule(&self, label: &str, elapsed: Duration) {
+        self.push(|s| &mut s.rules, label, elapsed, None);
+    }
+
+    /// Records the time spent reading and hashing a source file.
+    pub fn record_source(&self, label: &str, elapsed: Duration, bytes: u64) {
+        self.push(|s| &mut s.sources, label, elapsed, Some(bytes));
+    }
+
+    fn push(
+        &self,
+        list: impl FnOnce(&mut Samples) -> &mut Vec<TimedEntry>,
+        label: &str,
+        elapsed: Duration,
+        bytes: Option<u64>,
+    ) {
+        if let Ok(mut samples) = self.samples.lock() {
+            list(&mut samples).push(TimedEntry {
+                label: label.to_string(),
+                micros: elapsed.as_micros() as u64,
+                bytes,
+            });
+        }
+    }
+
+    /// Summarizes the samples, keeping the `top_k` slowest rules and sources.
+    pub fn report(&self, top_k: usize) -> HashProfile {
+        let samples = self.samples.lock().map(|s| Samples {
+            rules: s.rules.clone(),
+            sources: s.sources.clone(),
+        });
+        let samples = samples.unwrap_or_default();
+
+        let mut histogram = empty_histogram();
+        for entry in samples.rules.iter().chain(samples.sources.iter()) {
+            add_to_histogram(&mut histogram, entry.micros);
+        }
+
+        HashProfile {
+            rule_count: samples.rules.len(),
+            source_count: samples.sources.len(),
+            total_rule_micros: samples.rules.iter().map(|e| e.micros).sum(),
+            total_source_micros: samples.sources.iter().map(|e| e.micros).sum(),
+            total_source_bytes: samples.sources.iter().filter_map(|e| e.bytes).sum(),
+            histogram,
+            slowest_rules: top(samples.rules, top_k),
+            slowest_sources: top(samples.sources, top_k),
+        }
+    }
+}
+
+/// Timing report emitted by `generate-hashes --hashProfile`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HashProfile {
+    pub rule_count: usize,
+    pub source_count: usize,
+    pub total_rule_micros: u64,
+    pub total_source_micros: u64,
+    pub total_source_bytes: u64,
+    pub histogram: Vec<HistogramBucket>,
+    pub slowest_rules: Vec<TimedEntry>,
+    pub slowest_sources: Vec<TimedEntry>,
+}
+
+impl HashProfile {
+    /// Folds `other` into `self`, re-applying the `top_k` cut-off.
+    pub fn merge(&mut self, other: HashProfile, top_k: usize) {
+        self.rule_count += other.rule_count;
+        self.source_count += other.source_count;
+        self.total_rule_micros += other.total_rule_micros;
+        self.total_source_micros += other.total_source_micros;
+        self.total_source_bytes += other.total_source_bytes;
+        for (mine, theirs) in self.histogram.iter_mut().zip(other.histogram) {
+            mine.count += theirs.count;
+        }
+        self.slowest_rules.extend(other.slowest_rules);
+        self.slowest_sources.extend(other.slowest_sources);
+        self.slowest_rules = top(std::mem::take(&mut self.slowest_rules), top_k);
+        self.slowest_sources = top(std::mem::take(&mut self.slowest_sources), top_k);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimedEntry {
+    pub label: String,
+    pub micros: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistogramBucket {
+    /// Exclusive upper bound in microseconds; `None` for the open-ended bucket.
+    pub below_micros: Option<u64>,
+    pub count: usize,
+}
+
+fn empty_histogram() -> Vec<HistogramBucket> {
+    HISTOGRAM_BOUNDS_MICROS
+        .iter()
+        .map(|bound| Some(*bound))
+        .chain(std::iter::once(None))
+        .map(|below_micros| HistogramBucket {
+            below_micros,
+            count: 0,
+        })
+        .collect()
+}
+
+fn add_to_histogram(histogram: &mut [HistogramBucket], micros: u64) {
+    if let Some(bucket) = histogram.iter_mut().find(|b| match b.below_micros {
+        Some(bound) => micros < bound,
+        None => true,
+    }) {
+        bucket.count += 1;
+    }
+}
+
+fn top(mut entries: Vec<TimedEntry>, k: usize) -> Vec<TimedEntry> {
+    entries.sort_by(|a, b| b.micros.cmp(&a.micros).then_with(|| a.label.cmp(&b.label)));
+    entries.truncate(k);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_keeps_slowest_entries_and_totals() {
+        let profiler = HashProfiler::new();
+        profiler.record_rule("//a:fast", Duration::from_micros(10));
+        profiler.record_rule("//a:slow", Duration::from_millis(20));
+        profiler.record_source("//a:big.bin", Duration::from_secs(2), 4096);
+        profiler.record_source("//a:small.txt", Duration::from_micros(5), 3);
+
+        let report = profiler.report(1);
+        assert_eq!(report.rule_count, 2);
+        assert_eq!(report.source_count, 2);
+        assert_eq!(report.total_source_bytes, 4099);
+        assert_eq!(report.slowest_rules.len(), 1);
+        assert_eq!(report.slowest_rules[0].label, "//a:slow");
+        assert_eq!(report.slowest_sources[0].bytes, Some(4096));
+
+        let counts: Vec<_> = report.histogram.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![2, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn merge_combines_reports() {
+        let a = HashProfiler::new();
+        a.record_rule("//a:r", Duration::from_millis(3));
+        let b = HashProfiler::new();
+        b.record_rule("ws2!//b:r", Duration::from_millis(5));
+
+        let mut merged = a.report(1);
+        merged.merge(b.report(1), 1);
+        assert_eq!(merged.rule_count, 2);
+        assert_eq!(merged.slowest_rules[0].label, "ws2!//b:r");
+        assert_eq!(merged.histogram[1].count, 2);
+    }
+}
@@ -0,0 +1,635 @@
+//! Output digests from a Remote Execution API cache, so generated files are
+//! hashed by the digests their actions recorded instead of being read.
+//!
+//! Action results are keyed by action digest, so the cache is asked only for
+//! the actions of one build, taken from its execution log
+//! (`--execution_log_json_file`). Entries of other builds are never looked at,
+//! which keeps the digests independent of what else the cache holds.
+
+use anyhow::{anyhow, bail, Context, Result};
+use bazel_differrous_proto::remote_execution::action_cache_client::ActionCacheClient;
+use bazel_differrous_proto::remote_execution::{ActionResult, Digest, GetActionResultRequest};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tracing::{info, warn};
+
+/// How long a remote cache may take to answer one action result lookup.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many action results are looked up at once over one gRPC connection.
+const GRPC_CONCURRENCY: usize = 32;
+
+/// Where [`GenerateHashesConfig::remote_cache`](crate::GenerateHashesConfig::remote_cache)
+/// reads output digests from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemoteCache {
+    /// A Bazel `--disk_cache` directory.
+    Disk(PathBuf),
+    /// A cache speaking Bazel's HTTP caching protocol (`GET /ac/<hash>`).
+    Http(String),
+    /// A Remote Execution API cache (`ActionCache.GetActionResult`) at a
+    /// `grpc://` or `grpcs://` URL, like Bazel's `--remote_cache`.
+    Grpc {
+        url: String,
+        /// Bazel's `--remote_instance_name`; empty for the default instance.
+        instance_name: String,
+    },
+}
+
+impl RemoteCache {
+    /// Parses `--remoteCache`: a directory, optionally as a `file://` URL, an
+    /// `http://` or `https://` cache, or a `grpc://` or `grpcs://` cache of
+    /// the default instance.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let Some((scheme, rest)) = spec.split_once("://") else {
+            return Ok(Self::Disk(PathBuf::from(spec)));
+        };
+        match scheme {
+            "file" => Ok(Self::Disk(PathBuf::from(rest))),
+            "http" | "https" => Ok(Self::Http(spec.trim_end_matches('/').to_string())),
+            "grpc" | "grpcs" if !rest.trim_end_matches('/').is_empty() => Ok(Self::Grpc {
+                url: spec.trim_end_matches('/').to_string(),
+                instance_name: String::new(),
+            }),
+            "grpc" | "grpcs" => bail!("remote cache {spec} has no host"),
+            _ => bail!("unsupported remote cache scheme {scheme}:// in {spec}"),
+        }
+    }
+
+    /// Digests of the output files of the cached `actions`, keyed like
+    /// [`bep::read_output_digests`](crate::bep::read_output_digests). Actions
+    /// missing from the cache are skipped; outputs with different contents in
+    /// several configurations are left out.
+    pub(crate) async fn read_output_digests(
+        &self,
+        actions: &[ActionDigest],
+    ) -> Result<HashMap<String, String>> {
+        let results: Vec<Option<ActionResult>> = match self {
+            Self::Disk(dir) => {
+                if !dir.join("ac").is_dir() {
+                    bail!(
+                        "{} is not a Bazel disk cache: it has no ac directory",
+                        dir.display()
+                    );
+                }
+                actions
+                    .par_iter()
+                    .map(|action| read_disk_action_result(dir, &action.hash))
+                    .collect::<Result<_>>()?
+            }
+            Self::Http(url) => {
+                let agent = ureq::AgentBuilder::new().timeout(LOOKUP_TIMEOUT).build();
+                actions
+                    .par_iter()
+                    .map(|action| fetch_action_result(&agent, url, &action.hash))
+                    .collect::<Result<_>>()?
+            }
+            Self::Grpc { url, instance_name } => {
+                fetch_grpc_action_results(url, instance_name, actions).await?
+            }
+        };
+        let cached = results.iter().flatten().count();
+        let digests = output_digests(results.into_iter().flatten());
+        info!(
+            outputs = digests.len(),
+            actions = actions.len(),
+            cached,
+            cache = %self,
+            "read output digests"
+        );
+        Ok(digests)
+    }
+}
+
+impl std::fmt::Display for RemoteCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disk(dir) => write!(f, "{}", dir.display()),
+            Self::Http(url) => f.write_str(url),
+            Self::Grpc { url, instance_name } if instance_name.is_empty() => f.write_str(url),
+            Self::Grpc { url, instance_name } => write!(f, "{url} (instance {instance_name})"),
+        }
+    }
+}
+
+/// The parts of a `SpawnExec` entry of an execution log that identify the
+/// action run.
+#[derive(Deserialize)]
+struct SpawnExec {
+    #[serde(default)]
+    digest: Option<LogDigest>,
+}
+
+#[derive(Deserialize)]
+struct LogDigest {
+    #[serde(default)]
+    hash: String,
+    /// An int64, which the proto3 JSON mapping writes as a string.
+    #[serde(default, rename = "sizeBytes", deserialize_with = "int64_from_json")]
+    size_bytes: i64,
+}
+
+fn int64_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Int64 {
+        Number(i64),
+        String(String),
+    }
+    match Int64::deserialize(deserializer)? {
+        Int64::Number(value) => Ok(value),
+        Int64::String(value) => value.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// The digest of an action, which keys its result in a cache. The disk and
+/// HTTP caches only use the hash; the Remote Execution API needs the size of
+/// the serialized action too.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ActionDigest {
+    pub hash: String,
+    pub size_bytes: i64,
+}
+
+/// Digests of the actions an execution log (`--execution_log_json_file`,
+/// Bazel 6+) lists, sorted and without duplicates.
+pub fn read_action_digests(path: &Path) -> Result<Vec<ActionDigest>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open execution log {}", path.display()))?;
+    let mut digests = BTreeSet::new();
+    let spawns = serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter();
+    for (index, spawn) in spawns.enumerate() {
+        let spawn: SpawnExec = spawn.with_context(|| {
+            format!(
+                "failed to parse spawn {} of execution log {}",
+                index + 1,
+                path.display()
+            )
+        })?;
+        if let Some(digest) = spawn.digest.filter(|digest| !digest.hash.is_empty()) {
+            digests.insert(ActionDigest {
+                hash: digest.hash,
+                size_bytes: digest.size_bytes,
+            });
+        }
+    }
+    if digests.is_empty() {
+        bail!(
+            "execution log {} lists no action digests; it needs Bazel 6 or newer",
+            path.display()
+        );
+    }
+    Ok(digests.into_iter().collect())
+}
+
+/// The action result of `action` in the disk cache `dir`; Bazel shards `ac/`
+/// by the first two characters of the action digest, older versions keep it
+/// flat.
+fn read_disk_action_result(dir: &Path, action: &str) -> Result<Option<ActionResult>> {
+    let ac = dir.join("ac");
+    let sharded = action.get(..2).map(|shard| ac.join(shard).join(action));
+    for path in sharded.into_iter().chain([ac.join(action)]) {
+        match std::fs::read(&path) {
+            Ok(data) => return Ok(decode_action_result(&data, &path.display().to_string())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(anyhow!(err))
+                    .with_context(|| format!("failed to read disk cache entry {}", path.display()))
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn fetch_action_result(
+    agent: &ureq::Agent,
+    url: &str,
+    action: &str,
+) -> Result<Option<ActionResult>> {
+    let url = format!("{url}/ac/{action}");
+    let response = match agent.get(&url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(ureq::Error::Status(code, _)) => bail!("remote cache returned HTTP {code} for {url}"),
+        Err(err) => return Err(anyhow!(err).context(format!("failed to reach {url}"))),
+    };
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read action result from {url}"))?;
+    Ok(decode_action_result(&data, &url))
+}
+
+/// The action results of `actions` in the gRPC cache at `url`, looked up
+/// concurrently over one connection. Actions the cache does not know are
+/// `None`; any other failure fails the lookup, like an HTTP error does.
+async fn fetch_grpc_action_results(
+    url: &str,
+    instance_name: &str,
+    actions: &[ActionDigest],
+) -> Result<Vec<Option<ActionResult>>> {
+    let endpoint = grpc_endpoint(url)?;
+    let channel = endpoint
+        .connect()
+        .await
+        .with_context(|| format!("failed to connect to remote cache {url}"))?;
+    let client = ActionCacheClient::new(channel);
+    let permits = Arc::new(Semaphore::new(GRPC_CONCURRENCY));
+    let mut lookups = JoinSet::new();
+    for action in actions {
+        let mut client = client.clone();
+        let url = url.to_string();
+        let permits = Arc::clone(&permits);
+        let request = GetActionResultRequest {
+            instance_name: instance_name.to_string(),
+            action_digest: Some(Digest {
+                hash: action.hash.clone(),
+                size_bytes: action.size_bytes,
+            }),
+            ..Default::default()
+        };
+        lookups.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            let hash = request
+                .action_digest
+                .as_ref()
+                .map(|digest| digest.hash.clone());
+            match client.get_action_result(request).await {
+                Ok(response) => Ok(Some(response.into_inner())),
+                Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+                Err(status) => bail!(
+                    "remote cache {url} failed to look up action {}: {:?}: {}",
+                    hash.unwrap_or_default(),
+                    status.code(),
+                    status.message()
+                ),
+            }
+        });
+    }
+    let mut results = Vec::with_capacity(actions.len());
+    while let Some(result) = lookups.join_next().await {
+        results.push(result.context("action result lookup panicked")??);
+    }
+    Ok(results)
+}
+
+/// The endpoint of the gRPC cache at `url`: `grpc://` is plaintext HTTP/2,
+/// `grpcs://` uses TLS with the web PKI roots.
+fn grpc_endpoint(url: &str) -> Result<Endpoint> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .with_context(|| format!("remote cache {url} is not a URL"))?;
+    let tls = match scheme {
+        "grpc" => false,
+        "grpcs" => true,
+        _ => bail!("remote cache {url} is not a gRPC URL"),
+    };
+    let scheme = if tls { "https" } else { "http" };
+    let endpoint = Endpoint::from_shared(format!("{scheme}://{rest}"))
+        .with_context(|| format!("invalid remote cache URL {url}"))?
+        .connect_timeout(LOOKUP_TIMEOUT)
+        .timeout(LOOKUP_TIMEOUT);
+    if !tls {
+        return Ok(endpoint);
+    }
+    endpoint
+        .tls_config(ClientTlsConfig::new().with_webpki_roots())
+        .with_context(|| format!("failed to set up TLS for remote cache {url}"))
+}
+
+fn decode_action_result(data: &[u8], source: &str) -> Option<ActionResult> {
+    prost::Message::decode(data)
+        .map_err(
+            |err| warn!(entry = source, %err, "ignoring cache entry that is not an action result"),
+        )
+        .ok()
+}
+
+/// Output digests of `results` keyed by their path below the output
+/// directory. An output built with different contents (by several actions, in
+/// several configurations) is left out.
+fn output_digests(results: impl Iterator<Item = ActionResult>) -> HashMap<String, String> {
+    let mut digests: HashMap<String, Option<String>> = HashMap::new();
+    for file in results.flat_map(|result| result.output_files) {
+        let Some(digest) = file.digest.filter(|digest| !digest.hash.is_empty()) else {
+            continue;
+        };
+        let Some(name) = output_name(&file.path) else {
+            continue;
+        };
+        digests
+            .entry(name.to_string())
+            .and_modify(|known| {
+                if known.as_ref() != Some(&digest.hash) {
+                    *known = None;
+                }
+            })
+            .or_insert(Some(digest.hash));
+    }
+    let ambiguous = digests.values().filter(|digest| digest.is_none()).count();
+    if ambiguous > 0 {
+        warn!(
+            count = ambiguous,
+            "ignoring cached outputs built with different contents in several configurations"
+        );
+    }
+    digests
+        .into_iter()
+        .filter_map(|(name, digest)| Some((name, digest?)))
+        .collect()
+}
+
+/// The path of the exec root output `path` below its configuration's output
+/// directory: `bazel-out/k8-fastbuild/bin/pkg/gen.txt` is `pkg/gen.txt`.
+fn output_name(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("bazel-out/")?;
+    let (_config, rest) = rest.split_once('/')?;
+    let (tree, name) = rest.split_once('/')?;
+    matches!(tree, "bin" | "genfiles").then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_differrous_proto::remote_execution::action_cache_server::{
+        ActionCache, ActionCacheServer,
+    };
+    use bazel_differrous_proto::remote_execution::OutputFile;
+    use prost::Message;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    fn action_result_of(outputs: &[(&str, &str)]) -> ActionResult {
+        ActionResult {
+            output_files: outputs
+                .iter()
+                .map(|(path, hash)| OutputFile {
+                    path: path.to_string(),
+                    digest: Some(Digest {
+                        hash: hash.to_string(),
+                        size_bytes: 1,
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn action_result(outputs: &[(&str, &str)]) -> Vec<u8> {
+        action_result_of(outputs).encode_to_vec()
+    }
+
+    fn write_action_result(cache: &Path, key: &str, outputs: &[(&str, &str)]) -> Result<()> {
+        let dir = cache.join("ac").join(&key[..2]);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(key), action_result(outputs))?;
+        Ok(())
+    }
+
+    fn actions(keys: &[&str]) -> Vec<ActionDigest> {
+        keys.iter()
+            .map(|key| ActionDigest {
+                hash: key.to_string(),
+                size_bytes: 140,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_output_digests_of_the_build_actions_from_a_disk_cache() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cache = tmp.path();
+        write_action_result(
+            cache,
+            "aa01",
+            &[
+                ("bazel-out/k8-fastbuild/bin/pkg/gen.txt", "fresh"),
+                ("bazel-out/k8-fastbuild/bin/pkg/both.txt", "same"),
+            ],
+        )?;
+        // An earlier build of the same output; not an action of this build.
+        write_action_result(
+            cache,
+            "bb02",
+            &[("bazel-out/k8-fastbuild/bin/pkg/gen.txt", "stale")],
+        )?;
+        write_action_result(
+            cache,
+            "cc03",
+            &[
+                ("bazel-out/k8-opt/bin/pkg/both.txt", "same"),
+                ("bazel-out/k8-opt/bin/pkg/split.txt", "opt"),
+                ("bazel-out/k8-fastbuild/genfiles/external/dep/lib.a", "lib"),
+                ("bazel-out/volatile-status.txt", "status"),
+            ],
+        )?;
+        std::fs::write(
+            cache.join("ac").join("dd04"),
+            action_result(&[("bazel-out/k8-fastbuild/bin/pkg/split.txt", "fastbuild")]),
+        )?;
+        std::fs::write(cache.join("ac").join("ee05"), b"\xff\xff not a proto")?;
+
+        let digests = RemoteCache::Disk(cache.to_path_buf())
+            .read_output_digests(&actions(&["aa01", "cc03", "dd04", "ee05", "ff06"]))
+            .await?;
+        assert_eq!(
+            digests,
+            HashMap::from([
+                ("pkg/gen.txt".to_string(), "fresh".to_string()),
+                ("pkg/both.txt".to_string(), "same".to_string()),
+                ("external/dep/lib.a".to_string(), "lib".to_string()),
+            ])
+        );
+
+        assert!(RemoteCache::Disk(tmp.path().join("missing"))
+            .read_output_digests(&actions(&["aa01"]))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_output_digests_from_an_http_cache() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/cache/", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let (status, body) = if request_line.starts_with("GET /cache/ac/aa01 ") {
+                    (
+                        "200 OK",
+                        action_result(&[("bazel-out/k8-fastbuild/bin/pkg/gen.txt", "fresh")]),
+                    )
+                } else {
+                    ("404 Not Found", Vec::new())
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        let cache = RemoteCache::parse(&url)?;
+        let digests = cache
+            .read_output_digests(&actions(&["aa01", "bb02"]))
+            .await?;
+        assert_eq!(
+            digests,
+            HashMap::from([("pkg/gen.txt".to_string(), "fresh".to_string())])
+        );
+        Ok(())
+    }
+
+    /// An action cache holding one result, recording the requests it gets.
+    #[derive(Default)]
+    struct FakeActionCache {
+        requests: Mutex<Vec<GetActionResultRequest>>,
+    }
+
+    #[tonic::async_trait]
+    impl ActionCache for FakeActionCache {
+        async fn get_action_result(
+            &self,
+            request: tonic::Request<GetActionResultRequest>,
+        ) -> Result<tonic::Response<ActionResult>, tonic::Status> {
+            let request = request.into_inner();
+            self.requests.lock().unwrap().push(request.clone());
+            let digest = request.action_digest.unwrap_or_default();
+            match (digest.hash.as_str(), digest.size_bytes) {
+                ("aa01", 140) => Ok(tonic::Response::new(action_result_of(&[(
+                    "bazel-out/k8-fastbuild/bin/pkg/gen.txt",
+                    "fresh",
+                )]))),
+                ("ee05", _) => Err(tonic::Status::permission_denied("no access")),
+                _ => Err(tonic::Status::not_found("no such action")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_output_digests_from_a_grpc_cache() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("grpc://{}/", listener.local_addr()?);
+        let cache = std::sync::Arc::new(FakeActionCache::default());
+        let server = tonic::transport::Server::builder()
+            .add_service(ActionCacheServer::from_arc(cache.clone()))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener));
+        tokio::spawn(server);
+
+        let RemoteCache::Grpc { url, .. } = RemoteCache::parse(&url)? else {
+            panic!("expected a gRPC cache");
+        };
+        let remote = RemoteCache::Grpc {
+            url,
+            instance_name: "main".to_string(),
+        };
+        let digests = remote
+            .read_output_digests(&actions(&["aa01", "bb02"]))
+            .await?;
+        assert_eq!(
+            digests,
+            HashMap::from([("pkg/gen.txt".to_string(), "fresh".to_string())])
+        );
+        let mut requests = cache.requests.lock().unwrap().clone();
+        requests.sort_by_key(|request| request.action_digest.clone().unwrap_or_default().hash);
+        assert_eq!(
+            requests,
+            ["aa01", "bb02"].map(|hash| GetActionResultRequest {
+                instance_name: "main".to_string(),
+                action_digest: Some(Digest {
+                    hash: hash.to_string(),
+                    size_bytes: 140,
+                }),
+                ..Default::default()
+            })
+        );
+
+        let err = remote
+            .read_output_digests(&actions(&["aa01", "ee05"]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("PermissionDenied"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn action_digests_come_from_the_execution_log() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let log = tmp.path().join("exec.json");
+        std::fs::write(
+            &log,
+            r#"{"commandArgs": ["touch"], "digest": {"hash": "bb02", "sizeBytes": "142"}}
+{"commandArgs": ["cp"], "digest": {"hash": "aa01", "sizeBytes": "140"}}
+{"commandArgs": ["cp"], "digest": {"hash": "aa01", "sizeBytes": "140"}}
+{"commandArgs": ["true"]}"#,
+        )?;
+        assert_eq!(
+            read_action_digests(&log)?,
+            [
+                ActionDigest {
+                    hash: "aa01".to_string(),
+                    size_bytes: 140,
+                },
+                ActionDigest {
+                    hash: "bb02".to_string(),
+                    size_bytes: 142,
+                },
+            ]
+        );
+
+        std::fs::write(&log, r#"{"commandArgs": ["true"]}"#)?;
+        let err = read_action_digests(&log).unwrap_err();
+        assert!(err.to_string().contains("Bazel 6"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn disk_http_and_grpc_caches_are_accepted() {
+        assert_eq!(
+            RemoteCache::parse("/var/cache/bazel").unwrap(),
+            RemoteCache::Disk(PathBuf::from("/var/cache/bazel"))
+        );
+        assert_eq!(
+            RemoteCache::parse("file:///var/cache/bazel").unwrap(),
+            RemoteCache::Disk(PathBuf::from("/var/cache/bazel"))
+        );
+        assert_eq!(
+            RemoteCache::parse("https://cache.example.com/").unwrap(),
+            RemoteCache::Http("https://cache.example.com".to_string())
+        );
+        assert_eq!(
+            RemoteCache::parse("grpcs://cache.example.com:443/").unwrap(),
+            RemoteCache::Grpc {
+                url: "grpcs://cache.example.com:443".to_string(),
+                instance_name: String::new(),
+            }
+        );
+        assert!(RemoteCache::parse("grpc://").is_err());
+        assert!(grpc_endpoint("grpcs://cache.example.com:443").is_ok());
+        assert!(RemoteCache::parse("s3://bucket").is_err());
+    }
+}
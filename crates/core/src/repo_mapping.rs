@@ -0,0 +1,154 @@
+//! Canonical names of the repositories the main repository sees, as Bazel
+//! resolves them. Under bzlmod `@rules_go` lives in `external/rules_go+` (or
+//! `rules_go~` before Bazel 8, or deeper for extension repositories), so
+//! external repositories are looked up by the name Bazel reports instead of
+//! guessed from the apparent one.
+
+use crate::command_log::record_command;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use tracing::debug;
+
+/// Apparent to canonical repository names of the main repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepoMapping {
+    canonical: BTreeMap<String, String>,
+}
+
+impl RepoMapping {
+    /// Asks Bazel for the mapping with `bazel mod dump_repo_mapping ""`,
+    /// available since Bazel 7.1. `None` when Bazel can't give one, e.g. for a
+    /// workspace without bzlmod or an older release. `options` are the
+    /// command options of the run, including `--nofetch` and
+    /// `--repository_cache`, so the dump resolves the same module graph as
+    /// the queries without fetching what they would not.
+    pub fn dump(
+        bazel_path: &Path,
+        startup_options: &[String],
+        options: &[String],
+        workspace: &Path,
+    ) -> Option<Self> {
+        let mut cmd = std::process::Command::new(bazel_path);
+        cmd.args(startup_options)
+            .args(["mod", "dump_repo_mapping"])
+            .args(options)
+            .arg("")
+            .current_dir(workspace);
+        let (started, clock) = (SystemTime::now(), Instant::now());
+        let output = cmd.output();
+        record_command(
+            &cmd,
+            started,
+            clock.elapsed(),
+            output.as_ref().ok().map(|output| output.status),
+        );
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                debug!(status = %output.status, "bazel mod dump_repo_mapping failed");
+                return None;
+            }
+            Err(err) => {
+                debug!(error = %err, "failed to run bazel mod dump_repo_mapping");
+                return None;
+            }
+        };
+        match Self::parse_dump(&String::from_utf8_lossy(&output.stdout)) {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                debug!(error = %err, "ignoring unreadable repo mapping");
+                None
+            }
+        }
+    }
+
+    /// Parses the output of `bazel mod dump_repo_mapping ""`: one JSON object
+    /// mapping apparent names to canonical ones. `None` for empty output.
+    pub fn parse_dump(output: &str) -> Result<Option<Self>> {
+        let Some(line) = output.lines().find(|line| !line.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let canonical: BTreeMap<String, String> =
+            serde_json::from_str(line).context("failed to parse the repo mapping")?;
+        Ok(Some(Self { canonical }))
+    }
+
+    /// The canonical name of the repository the main repository calls
+    /// `apparent`.
+    pub fn canonical(&self, apparent: &str) -> Option<&str> {
+        self.canonical.get(apparent).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.canonical.len()
+    }
+}
+
+/// Whether Bazel fetched a repository named `canonical` into `external_root`,
+/// which it records by writing `@<canonical>.marker` next to it.
+pub fn has_marker(external_root: &Path, canonical: &str) -> bool {
+    external_root.join(format!("@{canonical}.marker")).is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_maps_apparent_to_canonical_names() -> Result<()> {
+        let output = r#"{"":"","bazel_tools":"bazel_tools","go_sdk":"rules_go++go_sdk+go_sdk","rules_go":"rules_go+"}"#;
+        let mapping = RepoMapping::parse_dump(&format!("\n{output}\n"))?.expect("a mapping");
+        assert_eq!(mapping.len(), 4);
+        assert_eq!(mapping.canonical("rules_go"), Some("rules_go+"));
+        assert_eq!(mapping.canonical("go_sdk"), Some("rules_go++go_sdk+go_sdk"));
+        assert_eq!(mapping.canonical("rules_go+"), None);
+        assert_eq!(RepoMapping::parse_dump("")?, None);
+        assert!(RepoMapping::parse_dump("ERROR: no MODULE.bazel").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn markers_record_fetched_repositories() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("@rules_go+.marker"), "")?;
+        assert!(has_marker(tmp.path(), "rules_go+"));
+        assert!(!has_marker(tmp.path(), "rules_go"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bazel_without_a_mapping_gives_none() {
+        let workspace = std::env::temp_dir();
+        assert_eq!(
+            RepoMapping::dump(Path::new("false"), &[], &[], &workspace),
+            None
+        );
+        assert_eq!(
+            RepoMapping::dump(Path::new("true"), &[], &[], &workspace),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dump_passes_the_command_options() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir()?;
+        let bazel = tmp.path().join("bazel");
+        std::fs::write(
+            &bazel,
+            "#!/bin/sh\ncase \"$*\" in\n  \"--batch mod dump_repo_mapping --nofetch --repository_cache=/rc \") echo '{\"rules_go\":\"rules_go+\"}' ;;\n  *) exit 2 ;;\nesac\n",
+        )?;
+        std::fs::set_permissions(&bazel, std::fs::Permissions::from_mode(0o755))?;
+        let options = ["--nofetch", "--repository_cache=/rc"].map(String::from);
+        let mapping = RepoMapping::dump(&bazel, &["--batch".to_string()], &options, tmp.path())
+            .expect("a mapping");
+        assert_eq!(mapping.canonical("rules_go"), Some("rules_go+"));
+        assert_eq!(RepoMapping::dump(&bazel, &[], &[], tmp.path()), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use crate::impact::package_segment;
+use crate::models::ImpactedTargetsResult;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+/// Marker emitted at the top of every markdown report so CI steps can find and
+/// update a previously posted comment instead of adding a new one.
+pub const MARKDOWN_REPORT_MARKER: &str = "<!-- bazel-differrous-report -->";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkdownReportOptions {
+    pub title: String,
+    pub max_packages: usize,
+    pub max_targets_per_package: usize,
+}
+
+impl Default for MarkdownReportOptions {
+    fn default() -> Self {
+        Self {
+            title: "Impacted Bazel targets".to_string(),
+            max_packages: 50,
+            max_targets_per_package: 25,
+        }
+    }
+}
+
+/// Renders impacted targets as a collapsible markdown report suitable for a
+/// GitHub/GitLab pull request comment.
+pub fn render_markdown(result: &ImpactedTargetsResult, options: &MarkdownReportOptions) -> String {
+    let mut by_package: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for label in &result.impacted {
+        by_package
+            .entry(package_segment(label))
+            .or_default()
+            .push(label.as_str());
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{MARKDOWN_REPORT_MARKER}");
+    let _ = writeln!(out, "### {}", options.title);
+    let _ = writeln!(out);
+
+    if result.impacted.is_empty() {
+        let _ = writeln!(out, "No targets are impacted by this change.");
+        return out;
+    }
+
+    let _ = writeln!(out, "| | Count |");
+    let _ = writeln!(out, "|---|---:|");
+    let _ = writeln!(out, "| Impacted targets | {} |", result.impacted.len());
+    if let Some(distances) = &result.distances {
+        let direct = distances.iter().filter(|d| d.target_distance == 0).count();
+        let _ = writeln!(out, "| Directly impacted | {direct} |");
+        let _ = writeln!(
+            out,
+            "| Indirectly impacted | {} |",
+            distances.len() - direct
+        );
+    }
+    let _ = writeln!(out, "| Packages | {} |", by_package.len());
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "<details>");
+    let _ = writeln!(out, "<summary>Impacted targets by package</summary>");
+    let _ = writeln!(out);
+    for (package, labels) in by_package.iter().take(options.max_packages) {
+        let _ = writeln!(out, "<details>");
+        let _ = writeln!(
+            out,
+            "<summary><code>{}</code> ({} {})</summary>",
+            escape_html(package),
+            labels.len(),
+            if labels.len() == 1 {
+                "target"
+            } else {
+                "targets"
+            }
+        );
+        let _ = writeln!(out);
+        for label in labels.iter().take(options.max_targets_per_package) {
+            let _ = writeln!(out, "- `{label}`");
+        }
+        if labels.len() > options.max_targets_per_package {
+            let _ = writeln!(
+                out,
+                "- _… and {} more_",
+                labels.len() - options.max_targets_per_package
+            );
+        }
+        let _ = writeln!(out);
+        let _ = writeln!(out, "</details>");
+    }
+    if by_package.len() > options.max_packages {
+        let _ = writeln!(out);
+        let _ = writeln!(
+            out,
+            "_… and {} more packages_",
+            by_package.len() - options.max_packages
+        );
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(out, "</details>");
+    out
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ImpactedTargetDistance;
+
+    fn result(labels: &[&str]) -> ImpactedTargetsResult {
+        ImpactedTargetsResult {
+            impacted: labels.iter().map(|l| l.to_string()).collect(),
+            distances: None,
+        }
+    }
+
+    #[test]
+    fn empty_result_renders_notice() {
+        let md = render_markdown(&result(&[]), &MarkdownReportOptions::default());
+        assert!(md.starts_with(MARKDOWN_REPORT_MARKER));
+        assert!(md.contains("No targets are impacted"));
+        assert!(!md.contains("<details>"));
+    }
+
+    #[test]
+    fn groups_by_package_and_truncates() {
+        let options = MarkdownReportOptions {
+            max_packages: 1,
+            max_targets_per_package: 2,
+            ..Default::default()
+        };
+        let md = render_markdown(&result(&["//a:1", "//a:2", "//a:3", "//b:1"]), &options);
+        assert!(md.contains("| Impacted targets | 4 |"));
+        assert!(md.contains("| Packages | 2 |"));
+        assert!(md.contains("<summary><code>//a</code> (3 targets)</summary>"));
+        assert!(md.contains("- `//a:2`"));
+        assert!(!md.contains("- `//a:3`"));
+        assert!(md.contains("… and 1 more_"));
+        assert!(!md.contains("//b:1"));
+        assert!(md.contains("… and 1 more packages"));
+    }
+
+    #[test]
+    fn summary_counts_direct_and_indirect_when_distances_present() {
+        let mut res = result(&["//a:1", "//a:2"]);
+        res.distances = Some(vec![
+            ImpactedTargetDistance {
+                label: "//a:1".into(),
+                target_distance: 0,
+                package_distance: 0,
+                weighted_distance: None,
+                generating_rule: None,
+            },
+            ImpactedTargetDistance {
+                label: "//a:2".into(),
+                target_distance: 1,
+                package_distance: 0,
+                weighted_distance: None,
+                generating_rule: None,
+            },
+        ]);
+        let md = render_markdown(&res, &MarkdownReportOptions::default());
+        assert!(md.contains("| Directly impacted | 1 |"));
+        assert!(md.contains("| Indirectly impacted | 1 |"));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::labels::CanonicalRepoName;
+use crate::workspace::HybridWorkspace;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::path::Path;
+
+/// Rewrites the rule inputs of cquery results before they are hashed and
+/// matched against other targets. The default mirrors upstream bazel-diff:
+/// inputs from fine-grained repos become canonical `@@repo+//...` labels and
+/// every other external input collapses to `//external:repo`.
+pub trait RuleInputRewriter: Debug + Send + Sync {
+    /// Returns the label `input` should be hashed as. `fine_grained` holds the
+    /// `--fineGrainedHashExternalRepos` names without their leading `@`.
+    fn rewrite(&self, input: &str, fine_grained: &HashSet<String>) -> String;
+
+    /// Identifies the rewriting behaviour in checkpoint fingerprints, so that
+    /// records are never resumed across different rewriters.
+    fn fingerprint(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+}
+
+/// The upstream heuristics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRuleInputRewriter;
+
+impl RuleInputRewriter for DefaultRuleInputRewriter {
+    fn rewrite(&self, input: &str, fine_grained: &HashSet<String>) -> String {
+        crate::hash::transform_rule_input(input, fine_grained)
+    }
+}
+
+/// The default for hybrid workspaces: inputs from fine-grained repos defined
+/// by WORKSPACE keep their own name (`@@repo//...`, with no `+`), since only
+/// bzlmod repositories get canonical names. Everything else is rewritten by
+/// the upstream heuristics.
+#[derive(Debug, Clone)]
+pub struct HybridRuleInputRewriter {
+    hybrid: HybridWorkspace,
+}
+
+impl HybridRuleInputRewriter {
+    pub fn new(hybrid: HybridWorkspace) -> Self {
+        Self { hybrid }
+    }
+}
+
+impl RuleInputRewriter for HybridRuleInputRewriter {
+    fn rewrite(&self, input: &str, fine_grained: &HashSet<String>) -> String {
+        match input.trim_start_matches('@').split_once("//") {
+            Some((repo, target))
+                if self.hybrid.is_workspace_repo(repo) && fine_grained.contains(repo) =>
+            {
+                format!("@@{repo}//{target}")
+            }
+            _ => DefaultRuleInputRewriter.rewrite(input, fine_grained),
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        let repos: Vec<&str> = self
+            .hybrid
+            .module_repos()
+            .iter()
+            .map(String::as_str)
+            .collect();
+        format!("hybrid:{}", repos.join(","))
+    }
+}
+
+/// Per-repository rewrite rules loaded from `--inputRewriteConfig`:
+///
+/// ```json
+/// {"rules": [
+///   {"repo": "corp_*", "action": "template", "template": "//vendor/{repo}/{target}"},
+///   {"repo": "corp_tools", "action": "fineGrained"}
+/// ]}
+/// ```
+///
+/// The first rule whose `repo` matches (exactly, or by prefix with a trailing
+/// `*`) the apparent repository name of an external input decides how it is
+/// rewritten; inputs matching no rule fall back to the upstream heuristics.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    rules: Vec<RewriteRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RewriteRule {
+    repo: String,
+    action: RewriteAction,
+    #[serde(default)]
+    template: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum RewriteAction {
+    /// Leave the input label untouched.
+    Keep,
+    /// Collapse to `//external:<repo>`, hashing the repo as a whole. A module
+    /// repo's canonical name loses its trailing `+` or `~`.
+    External,
+    /// Rewrite to the canonical `@@<repo>+//...` label, as for fine-grained repos.
+    FineGrained,
+    /// Substitute `{repo}` and `{target}` (everything after `//`) into `template`.
+    Template,
+}
+
+impl RewriteConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("failed to read input rewrite config {}", path.display()))?;
+        let config: Self = serde_json::from_slice(&bytes)
+            .with_context(|| format!("failed to parse input rewrite config {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid input rewrite config {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            let pattern = rule.repo.strip_suffix('*').unwrap_or(&rule.repo);
+            if pattern.is_empty() || pattern.contains(['@', '/', '*']) {
+                bail!(
+                    "repo pattern {:?} must be a repository name without @, optionally ending in *",
+                    rule.repo
+                );
+            }
+            match (rule.action, &rule.template) {
+                (RewriteAction::Template, None) => {
+                    bail!("rule for {:?} needs a template", rule.repo)
+                }
+                (RewriteAction::Template, Some(template))
+                    if !(template.starts_with("//") || template.starts_with('@')) =>
+                {
+                    bail!("template {template:?} must produce an absolute label")
+                }
+                (RewriteAction::Template, Some(_)) | (_, None) => {}
+                (_, Some(_)) => bail!(
+                    "rule for {:?} sets a template but its action is not \"template\"",
+                    rule.repo
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn rule_for(&self, repo: &str) -> Option<&RewriteRule> {
+        self.rules
+            .iter()
+            .find(|rule| match rule.repo.strip_suffix('*') {
+                Some(prefix) => repo.starts_with(prefix),
+                None => repo == rule.repo,
+            })
+    }
+}
+
+impl RuleInputRewriter for RewriteConfig {
+    fn rewrite(&self, input: &str, fine_grained: &HashSet<String>) -> String {
+        let Some((repo_part, target)) = input.trim_start_matches('@').split_once("//") else {
+            return DefaultRuleInputRewriter.rewrite(input, fine_grained);
+        };
+        // Rules name repositories by their apparent name, also for canonical
+        // names such as `rules_python++pip+pypi__foo`.
+        let canonical = CanonicalRepoName::parse(repo_part);
+        let repo = canonical.map_or(repo_part, |canonical| canonical.apparent_name());
+        let Some(rule) = (!repo.is_empty()).then(|| self.rule_for(repo)).flatten() else {
+            return DefaultRuleInputRewriter.rewrite(input, fine_grained);
+        };
+        match rule.action {
+            RewriteAction::Keep => input.to_string(),
+            RewriteAction::External => {
+                format!("//external:{}", repo_part.trim_end_matches(['+', '~']))
+            }
+            RewriteAction::FineGrained if canonical.is_some() => format!("@@{repo_part}//{target}"),
+            RewriteAction::FineGrained => format!("@@{repo}+//{target}"),
+            RewriteAction::Template => rule
+                .template
+                .as_deref()
+                .unwrap_or_default()
+                .replace("{repo}", repo)
+                .replace("{target}", target),
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| format!("{}={:?}:{:?}", rule.repo, rule.action, rule.template))
+            .collect();
+        rules.join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Result<RewriteConfig> {
+        let config: RewriteConfig = serde_json::from_str(json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_others_fall_back() -> Result<()> {
+        let rewriter = config(
+            r#"{"rules": [
+                {"repo": "corp_tools", "action": "fineGrained"},
+                {"repo": "corp_*", "action": "template", "template": "//vendor/{repo}/{target}"},
+                {"repo": "pinned", "action": "keep"}
+            ]}"#,
+        )?;
+        let fine = HashSet::new();
+        assert_eq!(
+            rewriter.rewrite("@corp_tools//cli:main", &fine),
+            "@@corp_tools+//cli:main"
+        );
+        assert_eq!(
+            rewriter.rewrite("@@corp_base+//lib:util", &fine),
+            "//vendor/corp_base/lib:util"
+        );
+        assert_eq!(
+            rewriter.rewrite("@@rules_python++pip+corp_tools//:whl", &fine),
+            "@@rules_python++pip+corp_tools//:whl"
+        );
+        assert_eq!(rewriter.rewrite("@pinned//:a", &fine), "@pinned//:a");
+        assert_eq!(
+            rewriter.rewrite("@rules_go//go:def", &fine),
+            DefaultRuleInputRewriter.rewrite("@rules_go//go:def", &fine)
+        );
+        assert_eq!(rewriter.rewrite("//pkg:lib", &fine), "//pkg:lib");
+        Ok(())
+    }
+
+    #[test]
+    fn external_labels_drop_the_canonical_suffix() -> Result<()> {
+        let rewriter = config(
+            r#"{"rules": [
+                {"repo": "rules_go", "action": "external"},
+                {"repo": "pypi__*", "action": "external"}
+            ]}"#,
+        )?;
+        let fine = HashSet::new();
+        for input in [
+            "@rules_go//go:def",
+            "@@rules_go+//go:def",
+            "@@rules_go~//go:def",
+        ] {
+            assert_eq!(rewriter.rewrite(input, &fine), "//external:rules_go");
+        }
+        assert_eq!(
+            rewriter.rewrite("@@rules_python++pip+pypi__foo//:pkg", &fine),
+            "//external:rules_python++pip+pypi__foo"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert!(config(r#"{"rules": [{"repo": "@corp", "action": "keep"}]}"#).is_err());
+        assert!(config(r#"{"rules": [{"repo": "corp", "action": "template"}]}"#).is_err());
+        assert!(config(
+            r#"{"rules": [{"repo": "corp", "action": "keep", "template": "//x:{target}"}]}"#
+        )
+        .is_err());
+        assert!(config(r#"{"rules": [{"repo": "corp", "action": "rename"}]}"#).is_err());
+    }
+
+    #[test]
+    fn hybrid_rewriter_keeps_workspace_repo_names() -> Result<()> {
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/generate/hybrid");
+        let hybrid = HybridWorkspace::detect(&fixture, &[])?.expect("fixture is hybrid");
+        let rewriter = HybridRuleInputRewriter::new(hybrid);
+        let fine: HashSet<String> = ["legacy_toolchain", "rules_go"].map(String::from).into();
+        assert_eq!(
+            rewriter.rewrite("@legacy_toolchain//bin:cc", &fine),
+            "@@legacy_toolchain//bin:cc"
+        );
+        assert_eq!(
+            rewriter.rewrite("@rules_go//go:def", &fine),
+            "@@rules_go+//go:def"
+        );
+        assert_eq!(
+            rewriter.rewrite("@other_legacy//:a", &fine),
+            "//external:other_legacy"
+        );
+        assert_eq!(rewriter.rewrite("//pkg:lib", &fine), "//pkg:lib");
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use crate::labels::normalize_label;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Set operation of `set-op`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Union,
+    Intersect,
+    /// Labels of the first set that are not in the second.
+    Subtract,
+}
+
+impl SetOp {
+    pub fn apply(self, a: &BTreeSet<String>, b: &BTreeSet<String>) -> BTreeSet<String> {
+        match self {
+            Self::Union => a.union(b).cloned().collect(),
+            Self::Intersect => a.intersection(b).cloned().collect(),
+            Self::Subtract => a.difference(b).cloned().collect(),
+        }
+    }
+}
+
+/// JSON shapes of impacted-target lists written by `get-impacted-targets`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LabelList {
+    Labels(Vec<String>),
+    Distances(Vec<Labeled>),
+    Envelope {
+        status: String,
+        #[serde(default)]
+        impacted: Vec<String>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Labeled {
+    label: String,
+}
+
+/// Reads the labels of an impacted-target list: one label per line (blank
+/// lines and `#` comments are skipped), a JSON array of labels, the distance
+/// JSON, or a `{"status": "ok", "impacted": [...]}` envelope. Labels are
+/// normalized with [`normalize_label`] so differently spelled lists compare
+/// equal.
+pub fn read_label_set(path: &Path) -> Result<BTreeSet<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read label list {}", path.display()))?;
+    let labels: Vec<String> = if text.trim_start().starts_with(['[', '{']) {
+        let list: LabelList = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "{} is not an impacted-target list: expected a JSON array of labels, \
+                 distances or a status envelope",
+                path.display()
+            )
+        })?;
+        match list {
+            LabelList::Labels(labels) => labels,
+            LabelList::Distances(entries) => entries.into_iter().map(|e| e.label).collect(),
+            LabelList::Envelope {
+                status, impacted, ..
+            } if status == "ok" => impacted,
+            LabelList::Envelope { error, .. } => bail!(
+                "{} records a failed run: {}",
+                path.display(),
+                error.unwrap_or_default()
+            ),
+        }
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    };
+    labels
+        .iter()
+        .map(|label| normalize_label(label).with_context(|| format!("in {}", path.display())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_list_format_and_combines_sets() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let write = |name: &str, contents: &str| -> Result<_> {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, contents)?;
+            Ok(path)
+        };
+        let lines = write("a.txt", "# quarantined\n//a:x\n\n@//b\n")?;
+        let array = write("b.json", r#"["//b:b", "//c:y"]"#)?;
+        let distances = write(
+            "c.json",
+            r#"[{"label": "//c:y", "targetDistance": 0, "packageDistance": 0}]"#,
+        )?;
+        let envelope = write("d.json", r#"{"status": "ok", "impacted": ["//a:x"]}"#)?;
+        let failed = write("e.json", r#"{"status": "error", "error": "boom"}"#)?;
+
+        let a = read_label_set(&lines)?;
+        let b = read_label_set(&array)?;
+        assert_eq!(a, BTreeSet::from(["//a:x".into(), "//b:b".into()]));
+        assert_eq!(
+            SetOp::Union.apply(&a, &b),
+            BTreeSet::from(["//a:x".into(), "//b:b".into(), "//c:y".into()])
+        );
+        assert_eq!(
+            SetOp::Intersect.apply(&a, &b),
+            BTreeSet::from(["//b:b".into()])
+        );
+        assert_eq!(
+            SetOp::Subtract.apply(&a, &read_label_set(&envelope)?),
+            BTreeSet::from(["//b:b".into()])
+        );
+        assert_eq!(read_label_set(&distances)?.len(), 1);
+        let err = read_label_set(&failed).unwrap_err().to_string();
+        assert!(err.contains("failed run: boom"), "{err}");
+        Ok(())
+    }
+}
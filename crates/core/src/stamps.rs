@@ -0,0 +1,131 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// What to do when source files change while they are being hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrentModificationMode {
+    /// Log the modified files and keep the (possibly inconsistent) output.
+    Warn,
+    /// Fail the run so no inconsistent output is used.
+    Fail,
+}
+
+/// Size and modification time of a file when it was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(metadata: &Metadata) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        }
+    }
+}
+
+/// Records `(mtime, size)` of every file read while hashing so that files
+/// modified mid-run can be detected afterwards. Cheap to clone; clones share
+/// the same records.
+#[derive(Debug, Clone, Default)]
+pub struct FileStampRecorder {
+    stamps: Arc<Mutex<HashMap<PathBuf, FileStamp>>>,
+}
+
+impl FileStampRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `metadata`, which must be taken before the file is read. The
+    /// first stamp of a path wins.
+    pub fn record(&self, path: &Path, metadata: &Metadata) {
+        if let Ok(mut stamps) = self.stamps.lock() {
+            stamps
+                .entry(path.to_path_buf())
+                .or_insert_with(|| FileStamp::of(metadata));
+        }
+    }
+
+    /// Files whose size or modification time differs from when they were read,
+    /// including files that have since been deleted, sorted by path.
+    pub fn modified_files(&self) -> Vec<PathBuf> {
+        let stamps = match self.stamps.lock() {
+            Ok(stamps) => stamps.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let mut modified: Vec<PathBuf> = stamps
+            .into_iter()
+            .filter(|(path, stamp)| {
+                std::fs::metadata(path)
+                    .map(|now| FileStamp::of(&now) != *stamp)
+                    .unwrap_or(true)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        modified.sort();
+        modified
+    }
+
+    /// Re-checks every recorded file and warns or fails according to `mode`.
+    pub fn verify(&self, mode: ConcurrentModificationMode) -> Result<()> {
+        let modified = self.modified_files();
+        if modified.is_empty() {
+            return Ok(());
+        }
+        let listing: Vec<String> = modified.iter().map(|p| p.display().to_string()).collect();
+        match mode {
+            ConcurrentModificationMode::Warn => {
+                warn!(
+                    files = ?listing,
+                    "files changed while hashing; the output may be inconsistent"
+                );
+                Ok(())
+            }
+            ConcurrentModificationMode::Fail => bail!(
+                "{} file(s) changed while hashing, so the output would be inconsistent:\n  {}",
+                listing.len(),
+                listing.join("\n  ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_files_changed_after_reading() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let stable = tmp.path().join("stable.txt");
+        let edited = tmp.path().join("edited.txt");
+        let deleted = tmp.path().join("deleted.txt");
+        for path in [&stable, &edited, &deleted] {
+            std::fs::write(path, "v1")?;
+        }
+
+        let recorder = FileStampRecorder::new();
+        for path in [&stable, &edited, &deleted] {
+            recorder.record(path, &std::fs::metadata(path)?);
+        }
+        recorder.verify(ConcurrentModificationMode::Fail)?;
+
+        std::fs::write(&edited, "version 2")?;
+        std::fs::remove_file(&deleted)?;
+        assert_eq!(recorder.modified_files(), vec![deleted, edited]);
+        recorder.verify(ConcurrentModificationMode::Warn)?;
+        let err = recorder
+            .verify(ConcurrentModificationMode::Fail)
+            .unwrap_err();
+        assert!(err.to_string().contains("2 file(s) changed while hashing"));
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Per-workspace state kept between runs: the source digest cache and the
+//! `--sinceTag` baselines live in `$XDG_CACHE_HOME/bazel-differrous/<key>/`,
+//! where `<key>` identifies the workspace, so [`gc`] can drop the state of
+//! workspaces that are no longer used and keep the rest below a size limit.
+
+use crate::cleanup::{disk_usage, read_dir_sorted, CleanReport};
+use crate::lock::{FileLock, DIR_LOCK_FILE};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// Hex digits of the workspace path digest naming its state directory.
+const KEY_LEN: usize = 16;
+
+/// Where `--sinceTag` baselines were cached, below [`cache_root`], before
+/// state was kept per workspace.
+const LEGACY_BASELINES_DIR: &str = "baselines";
+
+/// `$XDG_CACHE_HOME/bazel-differrous`, falling back to `~/.cache`.
+pub fn cache_root() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache.join("bazel-differrous"))
+}
+
+/// The state directory of one workspace. Nothing is created until a cache
+/// inside it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDir {
+    dir: PathBuf,
+}
+
+impl StateDir {
+    /// The state directory of `workspace` below `root`, keyed by a digest of
+    /// its canonical path so that every spelling of the path shares it.
+    pub fn new(root: &Path, workspace: &Path) -> Self {
+        let workspace = workspace
+            .canonicalize()
+            .unwrap_or_else(|_| workspace.to_path_buf());
+        let digest = hex::encode(Sha256::digest(workspace.to_string_lossy().as_bytes()));
+        Self {
+            dir: root.join(&digest[..KEY_LEN]),
+        }
+    }
+
+    /// The state directory of `workspace` below [`cache_root`].
+    pub fn for_workspace(workspace: &Path) -> Option<Self> {
+        Some(Self::new(&cache_root()?, workspace))
+    }
+
+    #[cfg(test)]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Source digests reused across runs.
+    pub fn content_cache_path(&self) -> PathBuf {
+        self.dir.join("content-cache.msgpack")
+    }
+
+    /// Hashes of `--sinceTag` baselines.
+    pub fn baselines_dir(&self) -> PathBuf {
+        self.dir.join("baselines")
+    }
+}
+
+/// The baseline cache shared by every workspace before state was kept per
+/// workspace. Its entries are moved into a workspace's
+/// [`StateDir::baselines_dir`] when that workspace first looks them up (see
+/// [`LocalBaselineCache::with_legacy_dir`](crate::baseline::LocalBaselineCache::with_legacy_dir)),
+/// and [`gc`] leaves it alone until it is empty.
+pub fn legacy_baselines_dir(root: &Path) -> PathBuf {
+    root.join(LEGACY_BASELINES_DIR)
+}
+
+/// Limits [`gc`] enforces on the entries of the cache root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcPolicy {
+    /// Entries not written for this long are removed.
+    pub max_age: Option<Duration>,
+    /// The least recently written entries are removed until the rest fit.
+    pub max_bytes: Option<u64>,
+}
+
+/// Removes the workspace state directories in `root` that `policy` evicts,
+/// along with any other entry there, such as a content cache from before
+/// state was kept per workspace. An entry was last used when the newest file
+/// in it was written. The legacy baseline cache (see
+/// [`legacy_baselines_dir`]) is only removed once every baseline in it was
+/// migrated.
+///
+/// An entry is removed while holding the locks of it and of the cache
+/// directories directly inside it exclusively, so state a run is reading or
+/// writing is never removed; such entries are skipped and reported in
+/// `in_use` instead.
+pub fn gc(root: &Path, policy: &GcPolicy, report: &mut CleanReport) -> Result<()> {
+    if !root.is_dir() {
+        return Ok(());
+    }
+    let now = SystemTime::now();
+    let legacy_baselines = legacy_baselines_dir(root);
+    let mut entries = Vec::new();
+    for path in read_dir_sorted(root)? {
+        if path == legacy_baselines && has_baselines(&path)? {
+            continue;
+        }
+        let used = last_modified(&path).unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push((used, disk_usage(&path), path));
+    }
+    // Oldest first, so eviction by size keeps the most recently used.
+    entries.sort();
+
+    let mut total: u64 = entries.iter().map(|(_, bytes, _)| bytes).sum();
+    for (used, bytes, path) in entries {
+        let expired = policy
+            .max_age
+            .is_some_and(|max_age| now.duration_since(used).is_ok_and(|idle| idle > max_age));
+        let oversized = policy.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if !expired && !oversized {
+            continue;
+        }
+        let removed = if path.is_dir() {
+            let Some(_locks) = lock_state_dir(&path)? else {
+                report.in_use.push(path);
+                continue;
+            };
+            debug!(path = %path.display(), expired, oversized, "evicting cached state");
+            std::fs::remove_dir_all(&path)
+        } else {
+            debug!(path = %path.display(), expired, oversized, "evicting cached state");
+            std::fs::remove_file(&path)
+        };
+        removed.with_context(|| format!("failed to remove {}", path.display()))?;
+        total -= bytes;
+        report.bytes += bytes;
+        report.removed.push(path);
+    }
+    report.removed.sort();
+    report.in_use.sort();
+    Ok(())
+}
+
+/// Takes the directory locks of the state directory `dir` and of the cache
+/// directories directly inside it that have one, or `None` when a run holds
+/// any of them.
+fn lock_state_dir(dir: &Path) -> Result<Option<Vec<FileLock>>> {
+    let mut dirs = vec![dir.to_path_buf()];
+    for path in read_dir_sorted(dir)? {
+        if path.join(DIR_LOCK_FILE).is_file() {
+            dirs.push(path);
+        }
+    }
+    let mut locks = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        let Ok(lock) = FileLock::exclusive_dir(&dir, Duration::ZERO) else {
+            return Ok(None);
+        };
+        locks.push(lock);
+    }
+    Ok(Some(locks))
+}
+
+/// Whether the baseline cache `dir` still holds a baseline, as opposed to
+/// only lock files and leftovers of killed runs.
+fn has_baselines(dir: &Path) -> Result<bool> {
+    Ok(read_dir_sorted(dir)?
+        .iter()
+        .any(|path| path.extension().is_some_and(|ext| ext == "json")))
+}
+
+/// When the newest file under `path` was written, not following symlinks.
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    let metadata = path.symlink_metadata().ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    std::fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| last_modified(&entry.path()))
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::CacheLock;
+
+    fn write_aged(path: &Path, bytes: usize, age: Duration) -> Result<()> {
+        std::fs::create_dir_all(path.parent().expect("path has a parent"))?;
+        std::fs::write(path, vec![b'x'; bytes])?;
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now() - age)?;
+        Ok(())
+    }
+
+    #[test]
+    fn state_is_keyed_by_canonical_workspace_path() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path().join("ws");
+        std::fs::create_dir_all(workspace.join("pkg"))?;
+        let root = tmp.path().join("cache");
+
+        let state = StateDir::new(&root, &workspace);
+        assert_eq!(state, StateDir::new(&root, &workspace.join("pkg/..")));
+        assert_ne!(state, StateDir::new(&root, &workspace.join("pkg")));
+        assert_eq!(state.dir().parent(), Some(root.as_path()));
+        assert_eq!(state.dir().file_name().map(|n| n.len()), Some(KEY_LEN));
+        assert_eq!(
+            state.content_cache_path(),
+            state.dir().join("content-cache.msgpack")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn gc_evicts_idle_state_then_least_recently_used() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        let day = Duration::from_secs(24 * 60 * 60);
+        write_aged(&root.join("idle/content-cache.msgpack"), 10, day * 40)?;
+        write_aged(&root.join("old/content-cache.msgpack"), 100, day * 5)?;
+        write_aged(&root.join("new/baselines/c1-k.json"), 100, day)?;
+        write_aged(&root.join("new/content-cache.msgpack"), 100, day * 50)?;
+        write_aged(&root.join("content-cache.msgpack"), 1, day * 60)?;
+
+        let mut report = CleanReport::default();
+        let policy = GcPolicy {
+            max_age: Some(day * 30),
+            max_bytes: None,
+        };
+        gc(root, &policy, &mut report)?;
+        assert_eq!(
+            report.removed,
+            [root.join("content-cache.msgpack"), root.join("idle")]
+        );
+        assert_eq!(report.bytes, 11);
+
+        let mut report = CleanReport::default();
+        let policy = GcPolicy {
+            max_age: None,
+            max_bytes: Some(250),
+        };
+        gc(root, &policy, &mut report)?;
+        assert_eq!(report.removed, [root.join("old")]);
+        assert!(root.join("new/content-cache.msgpack").is_file());
+
+        gc(&root.join("missing"), &policy, &mut report)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gc_skips_state_in_use_and_unmigrated_baselines() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        let day = Duration::from_secs(24 * 60 * 60);
+        write_aged(&root.join("busy/baselines/c1-k.json"), 10, day * 40)?;
+        write_aged(&root.join("reading/content-cache.msgpack"), 10, day * 40)?;
+        write_aged(&root.join("baselines/c1-k.json"), 10, day * 40)?;
+        let _writing =
+            CacheLock::exclusive(&root.join("busy/baselines/c1-k.json"), Duration::ZERO)?;
+        let _reading =
+            CacheLock::shared(&root.join("reading/content-cache.msgpack"), Duration::ZERO)?;
+
+        let mut report = CleanReport::default();
+        let policy = GcPolicy {
+            max_age: Some(day * 30),
+            max_bytes: Some(0),
+        };
+        gc(root, &policy, &mut report)?;
+        assert!(report.removed.is_empty());
+        assert_eq!(report.in_use, [root.join("busy"), root.join("reading")]);
+        assert!(root.join("baselines/c1-k.json").is_file());
+
+        std::fs::remove_file(root.join("baselines/c1-k.json"))?;
+        let mut report = CleanReport::default();
+        gc(root, &policy, &mut report)?;
+        assert_eq!(report.removed, [root.join("baselines")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+use crate::checkpoint::{is_ndjson_hashes, ndjson_entries, read_ndjson_hashes, NdjsonEntries};
+use crate::models::{
+    read_target_hashes, starts_with_key, HashFileFormat, RawHashValue, TargetHash,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::{DeserializeSeed, Deserializer, Error as _, IgnoredAny, MapAccess, Visitor};
+use std::collections::btree_map;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// Entries buffered between the parsing thread and the consumer.
+const STREAM_BUFFER: usize = 4096;
+
+/// Label → hash entries of a hash file in label order, parsed incrementally so
+/// the whole map never has to be held in memory.
+///
+/// JSON hash files (v1 maps and v2 envelopes) are parsed on a background
+/// thread; `generate-hashes` always writes their labels sorted, and a stream
+/// over a file written otherwise ends with [`UnsortedHashes`] at its first
+/// out-of-order label, after which the caller can start over with
+/// [`load_target_hashes`]. NDJSON checkpoint files are
+/// unordered, so they are loaded in full and sorted first, as are the compact
+/// proto and MessagePack formats. [`stream_sorted_target_hashes`] never loads
+/// a file in full.
+pub struct HashStream {
+    entries: Entries,
+    path: PathBuf,
+    previous: Option<String>,
+}
+
+enum Entries {
+    Loaded(btree_map::IntoIter<String, TargetHash>),
+    Parsing(Receiver<Result<(String, String)>>),
+    /// NDJSON records that must already be sorted by label.
+    Lines(NdjsonEntries),
+}
+
+/// Opens `path` for streaming; see [`HashStream`].
+pub fn stream_target_hashes(path: &Path) -> Result<HashStream> {
+    open_stream(path, false)
+}
+
+/// Loads `path` in full and yields its entries sorted, for JSON hash files
+/// [`stream_target_hashes`] found not to be sorted.
+pub fn load_target_hashes(path: &Path) -> Result<HashStream> {
+    Ok(HashStream {
+        entries: Entries::Loaded(read_target_hashes(path)?.into_iter()),
+        path: path.to_path_buf(),
+        previous: None,
+    })
+}
+
+/// A JSON hash file whose labels are not sorted, so it can't be merged as it
+/// is parsed.
+#[derive(Debug)]
+pub struct UnsortedHashes {
+    path: PathBuf,
+    label: String,
+    previous: String,
+}
+
+impl fmt::Display for UnsortedHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not sorted by label ({} follows {}); regenerate it with generate-hashes",
+            self.path.display(),
+            self.label,
+            self.previous
+        )
+    }
+}
+
+impl std::error::Error for UnsortedHashes {}
+
+/// Opens `path` for streaming in constant memory: NDJSON files are read a
+/// line at a time and must list their records sorted by label, and proto and
+/// MessagePack files, which can't be read incrementally, are rejected.
+pub fn stream_sorted_target_hashes(path: &Path) -> Result<HashStream> {
+    open_stream(path, true)
+}
+
+fn open_stream(path: &Path, sorted: bool) -> Result<HashStream> {
+    let entries = if is_ndjson_hashes(path)? {
+        if sorted {
+            Entries::Lines(ndjson_entries(path)?)
+        } else {
+            Entries::Loaded(read_ndjson_hashes(path)?.into_iter())
+        }
+    } else {
+        let mut prefix = Vec::new();
+        File::open(path)
+            .with_context(|| format!("failed to open hashes file {}", path.display()))?
+            .take(256)
+            .read_to_end(&mut prefix)?;
+        if starts_with_key(&prefix, "baseDigest") {
+            bail!(
+                "{} is a delta hash file; apply it on top of its baseline with --applyDelta",
+                path.display()
+            );
+        }
+        match HashFileFormat::detect(&prefix) {
+            HashFileFormat::Proto | HashFileFormat::Msgpack if sorted => bail!(
+                "{} is a proto or MessagePack hash file, which can't be streamed; \
+                 compare it without --streaming",
+                path.display()
+            ),
+            HashFileFormat::Proto | HashFileFormat::Msgpack => {
+                Entries::Loaded(read_target_hashes(path)?.into_iter())
+            }
+            HashFileFormat::Json | HashFileFormat::Ndjson => {
+                Entries::Parsing(spawn_parser(path.to_path_buf()))
+            }
+        }
+    };
+    Ok(HashStream {
+        entries,
+        path: path.to_path_buf(),
+        previous: None,
+    })
+}
+
+impl Iterator for HashStream {
+    type Item = Result<(String, TargetHash)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (label, raw) = match &mut self.entries {
+            Entries::Loaded(entries) => return entries.next().map(Ok),
+            Entries::Parsing(receiver) => match receiver.recv().ok()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            },
+            Entries::Lines(lines) => match lines.next()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        if let Some(previous) = &self.previous {
+            if label == *previous {
+                return Some(Err(anyhow!(
+                    "{} lists {label} more than once, so it is corrupted or was merged incorrectly",
+                    self.path.display()
+                )));
+            }
+            if label.as_str() < previous.as_str() {
+                return Some(Err(match self.entries {
+                    Entries::Lines(_) => anyhow!(
+                        "{} is not sorted by label ({label} follows {previous}); NDJSON records \
+                         are written in hashing order; sort them by label or compare without \
+                         --streaming",
+                        self.path.display()
+                    ),
+                    _ => UnsortedHashes {
+                        path: self.path.clone(),
+                        label,
+                        previous: previous.clone(),
+                    }
+                    .into(),
+                }));
+            }
+        }
+        let parsed =
+            TargetHash::parse_owned(raw).with_context(|| format!("invalid hash for {label}"));
+        self.previous = Some(label.clone());
+        Some(parsed.map(|hash| (label, hash)))
+    }
+}
+
+fn spawn_parser(path: PathBuf) -> Receiver<Result<(String, String)>> {
+    let (sender, receiver) = sync_channel(STREAM_BUFFER);
+    thread::spawn(move || {
+        let parsed = File::open(&path)
+            .with_context(|| format!("failed to open hashes file {}", path.display()))
+            .and_then(|file| {
+                let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+                deserializer
+                    .deserialize_map(EntrySink {
+                        sender: &sender,
+                        envelope: true,
+                    })
+                    .and_then(|()| deserializer.end())
+                    .with_context(|| format!("failed to parse JSON hashes from {}", path.display()))
+            });
+        if let Err(err) = parsed {
+            // The consumer may already be gone; nothing is left to report to.
+            let _ = sender.send(Err(err));
+        }
+    });
+    receiver
+}
+
+/// Forwards label → hash entries to the consumer as they are parsed. At the
+/// top level the `meta`, `hashes` and `depEdges` keys of v2 envelopes and
+/// combined documents are recognized; labels never collide with them.
+struct EntrySink<'a> {
+    sender: &'a SyncSender<Result<(String, String)>>,
+    envelope: bool,
+}
+
+impl<'de> Visitor<'de> for EntrySink<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of labels to hashes")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "meta" | "depEdges" if self.envelope => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+                "hashes" if self.envelope => map.next_value_seed(EntrySink {
+                    sender: self.sender,
+                    envelope: false,
+                })?,
+                _ => {
+                    let RawHashValue(hash) = map.next_value()?;
+                    self.sender
+                        .send(Ok((key, hash)))
+                        .map_err(|_| A::Error::custom("hash stream closed by its reader"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for EntrySink<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::read_target_hashes;
+
+    #[test]
+    fn streams_maps_and_envelopes_in_label_order() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let v1 = tmp.path().join("v1.json");
+        let v2 = tmp.path().join("v2.json");
+        let detailed = tmp.path().join("detailed.json");
+        std::fs::write(&v1, r#"{"//a:a": "Rule#1~1", "//b:b": "2~2"}"#)?;
+        std::fs::write(
+            &v2,
+            r#"{"meta": {"generatedBy": null}, "hashes": {"//a:a": "Rule#1~1", "//b:b": "2~2"}}"#,
+        )?;
+        std::fs::write(
+            &detailed,
+            r#"{"//a:a": {"transitive": "1", "direct": "1", "type": "Rule"},
+                "//b:b": {"transitive": "2", "direct": "2"}}"#,
+        )?;
+
+        let expected: Vec<_> = read_target_hashes(&v1)?.into_iter().collect();
+        for path in [&v1, &v2, &detailed] {
+            let streamed = stream_target_hashes(path)?.collect::<Result<Vec<_>>>()?;
+            assert_eq!(streamed, expected);
+        }
+
+        let unsorted = tmp.path().join("unsorted.json");
+        std::fs::write(&unsorted, r#"{"//b:b": "2~2", "//a:a": "1~1"}"#)?;
+        let err = stream_target_hashes(&unsorted)?
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("not sorted by label"));
+        assert!(err.is::<UnsortedHashes>());
+        let labels: Vec<String> = load_target_hashes(&unsorted)?
+            .map(|entry| entry.map(|(label, _)| label))
+            .collect::<Result<_>>()?;
+        assert_eq!(labels, ["//a:a", "//b:b"]);
+
+        let duplicated = tmp.path().join("duplicated.json");
+        std::fs::write(&duplicated, r#"{"//a:a": "1~1", "//a:a": "2~2"}"#)?;
+        let err = stream_target_hashes(&duplicated)?
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("lists //a:a more than once"));
+
+        let truncated = tmp.path().join("truncated.json");
+        std::fs::write(&truncated, r#"{"//a:a": "1~1", "//b:b"#)?;
+        assert!(stream_target_hashes(&truncated)?
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_streams_read_ndjson_by_line_and_reject_binary_files() -> Result<()> {
+        use crate::checkpoint::{CheckpointWriter, Fingerprints};
+
+        let tmp = tempfile::tempdir()?;
+        let write_ndjson = |name: &str, labels: &[&str]| -> Result<PathBuf> {
+            let path = tmp.path().join(name);
+            let fingerprints = Fingerprints {
+                run: "run",
+                sources: None,
+            };
+            let mut writer = CheckpointWriter::create(&path, fingerprints)?;
+            for label in labels {
+                writer.append(label, "1~1", None, None, None)?;
+            }
+            writer.finish()?;
+            Ok(path)
+        };
+
+        let sorted = write_ndjson("sorted.ndjson", &["//a:a", "//b:b"])?;
+        let labels: Vec<String> = stream_sorted_target_hashes(&sorted)?
+            .map(|entry| entry.map(|(label, _)| label))
+            .collect::<Result<_>>()?;
+        assert_eq!(labels, vec!["//a:a", "//b:b"]);
+
+        // Without --streaming unsorted NDJSON is fine; with it, it is an error.
+        let unsorted = write_ndjson("unsorted.ndjson", &["//b:b", "//a:a"])?;
+        assert_eq!(stream_target_hashes(&unsorted)?.count(), 2);
+        let err = stream_sorted_target_hashes(&unsorted)?
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("sort them by label"), "{err}");
+
+        let mut damaged = std::fs::read_to_string(&sorted)?;
+        damaged = damaged.replace("\"1~1\"", "\"2~2\"");
+        std::fs::write(&sorted, damaged)?;
+        let err = stream_sorted_target_hashes(&sorted)?
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+
+        let proto = tmp.path().join("hashes.pb");
+        let hashes = std::collections::BTreeMap::from([("//a:a".to_string(), "1~1".to_string())]);
+        std::fs::write(&proto, crate::models::encode_proto_hashes(&hashes))?;
+        assert_eq!(stream_target_hashes(&proto)?.count(), 1);
+        assert!(stream_sorted_target_hashes(&proto).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::impact::{check_comparable, classify_impact, compute_impacted_targets, ImpactKind};
+use crate::models::{read_hashes_meta, read_target_hashes};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Impact between two consecutive hash snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrendPoint {
+    /// Snapshot name (file stem, typically a date) of the newer snapshot.
+    pub date: String,
+    /// Snapshot name of the snapshot compared against.
+    pub previous: String,
+    pub impacted: usize,
+    /// Impacted targets whose own inputs changed, or that were added or removed.
+    pub direct: usize,
+}
+
+/// Hash snapshots in `dir` (`*.json` / `*.ndjson`), ordered by file name so
+/// that ISO-dated names such as `2024-05-01.json` sort chronologically.
+pub fn snapshot_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to list hash directory {}", dir.display()))?
+    {
+        let path = entry?.path();
+        let is_snapshot = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("json" | "ndjson")
+        );
+        if is_snapshot && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compares every snapshot in `dir` with its predecessor. Only two snapshots
+/// are held in memory at a time. Fails when two consecutive snapshots can't
+/// be compared, e.g. because they were made with different hash functions.
+pub fn compute_trend(dir: &Path) -> Result<Vec<TrendPoint>> {
+    let files = snapshot_files(dir)?;
+    if files.len() < 2 {
+        bail!(
+            "{} needs at least two hash snapshots for a trend, found {}",
+            dir.display(),
+            files.len()
+        );
+    }
+
+    let mut points = Vec::with_capacity(files.len() - 1);
+    let mut previous = read_target_hashes(&files[0])?;
+    for pair in files.windows(2) {
+        check_comparable(read_hashes_meta(&pair[0])?, read_hashes_meta(&pair[1])?).with_context(
+            || format!("cannot compare {} with {}", pair[1].display(), pair[0].display()),
+        )?;
+        let current = read_target_hashes(&pair[1])?;
+        let impacted = compute_impacted_targets(&previous, &current, None)?;
+        let direct = impacted
+            .iter()
+            .filter(|label| {
+                classify_impact(previous.get(*label), current.get(*label)) == ImpactKind::Direct
+            })
+            .count();
+        points.push(TrendPoint {
+            date: snapshot_name(&pair[1]),
+            previous: snapshot_name(&pair[0]),
+            impacted: impacted.len(),
+            direct,
+        });
+        previous = current;
+    }
+    Ok(points)
+}
+
+fn snapshot_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_consecutive_impact_counts() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        std::fs::write(
+            dir.join("2024-05-01.json"),
+            r#"{"//a:lib": "a1~d1", "//a:bin": "b1~e1"}"#,
+        )?;
+        // //a:lib changes its own inputs; //a:bin only changes transitively.
+        std::fs::write(
+            dir.join("2024-05-02.json"),
+            r#"{"//a:lib": "a2~d2", "//a:bin": "b2~e1"}"#,
+        )?;
+        std::fs::write(
+            dir.join("2024-05-03.json"),
+            r#"{"//a:lib": "a2~d2", "//a:bin": "b2~e1", "//a:new": "c~c"}"#,
+        )?;
+        std::fs::write(dir.join("notes.txt"), "ignored")?;
+
+        let trend = compute_trend(dir)?;
+        assert_eq!(
+            trend,
+            vec![
+                TrendPoint {
+                    date: "2024-05-02".into(),
+                    previous: "2024-05-01".into(),
+                    impacted: 2,
+                    direct: 1,
+                },
+                TrendPoint {
+                    date: "2024-05-03".into(),
+                    previous: "2024-05-02".into(),
+                    impacted: 1,
+                    direct: 1,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn needs_two_snapshots() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("only.json"), "{}")?;
+        assert!(compute_trend(tmp.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_snapshots_of_different_hash_functions() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("2024-05-01.json"), r#"{"//a:lib": "a~a"}"#)?;
+        std::fs::write(
+            tmp.path().join("2024-05-02.json"),
+            r#"{"meta": {"hashFunction": "blake3"}, "hashes": {"//a:lib": "b~b"}}"#,
+        )?;
+        let err = compute_trend(tmp.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("hashFunction"), "{err:#}");
+        Ok(())
+    }
+}
@@ -0,0 +1,833 @@
+use crate::checkpoint::{is_ndjson_hashes, HashRecord, Header};
+use crate::labels::validate_label;
+use crate::models::{split_namespaced_label, IndexedDependencyEdges, OutputMeta};
+use anyhow::{Context, Result};
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Diagnostics listed per artifact; the rest are only counted.
+const MAX_LISTED_DIAGNOSTICS: usize = 20;
+/// Characters of a long line shown on either side of a diagnostic's column.
+const EXCERPT_RADIUS: usize = 40;
+
+/// Artifacts written by `generate-hashes` and `convert-dep-edges`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Hashes,
+    HashesEnvelope,
+    HashesDelta,
+    Combined,
+    NdjsonHashes,
+    DepEdges,
+    IndexedDepEdges,
+    DirectedDepEdges,
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Hashes => "hash map",
+            Self::HashesEnvelope => "json-v2 hash envelope",
+            Self::HashesDelta => "hash delta",
+            Self::Combined => "combined hashes and dep edges",
+            Self::NdjsonHashes => "NDJSON hashes",
+            Self::DepEdges => "dep edges (v1)",
+            Self::IndexedDepEdges => "dep edges (v2)",
+            Self::DirectedDepEdges => "directed dep edges",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The artifact will be rejected or misread.
+    Error,
+    /// The artifact is readable but looks suspicious, e.g. a dep edge to a
+    /// label without an entry of its own.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// 1-based line and column the problem was found at, when known.
+    pub position: Option<(usize, usize)>,
+    /// Numbered source lines around `position` with a caret under the column.
+    pub excerpt: Option<String>,
+}
+
+/// Result of checking one artifact; `Display` renders it for the terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactReport {
+    pub path: PathBuf,
+    /// `None` when the file could not be recognized as any artifact.
+    pub kind: Option<ArtifactKind>,
+    /// Labels, records or edges the artifact holds.
+    pub entries: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ArtifactReport {
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.kind.is_some() && self.errors() == 0
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == severity)
+            .count()
+    }
+}
+
+impl fmt::Display for ArtifactReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.path.display())?;
+        match self.kind {
+            Some(kind) => write!(f, "{kind}, {} entries", self.entries)?,
+            None => f.write_str("unrecognized artifact")?,
+        }
+        if self.diagnostics.is_empty() {
+            return writeln!(f, ", ok");
+        }
+        writeln!(
+            f,
+            ", {} error(s), {} warning(s)",
+            self.errors(),
+            self.warnings()
+        )?;
+        for diagnostic in self.diagnostics.iter().take(MAX_LISTED_DIAGNOSTICS) {
+            writeln!(f, "  {}: {}", diagnostic.severity, diagnostic.message)?;
+            if let Some((line, column)) = diagnostic.position {
+                writeln!(f, "    --> {}:{line}:{column}", self.path.display())?;
+            }
+            for excerpt_line in diagnostic.excerpt.iter().flat_map(|e| e.lines()) {
+                writeln!(f, "    {excerpt_line}")?;
+            }
+        }
+        if self.diagnostics.len() > MAX_LISTED_DIAGNOSTICS {
+            writeln!(
+                f,
+                "  ... and {} more",
+                self.diagnostics.len() - MAX_LISTED_DIAGNOSTICS
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the structure of a hash or dep edges file: that it parses, that
+/// labels and hash strings are well formed, that no label is listed twice and
+/// that dep edges only refer to labels with an entry of their own. Only I/O
+/// failures are returned as errors; problems with the content are reported as
+/// diagnostics.
+pub fn validate_artifact(path: &Path) -> Result<ArtifactReport> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let ndjson = is_ndjson_hashes(path)?;
+    let (text, utf8_error) = match std::str::from_utf8(&data) {
+        Ok(text) => (text, None),
+        Err(err) => (
+            std::str::from_utf8(&data[..err.valid_up_to()]).unwrap_or_default(),
+            Some(err),
+        ),
+    };
+
+    let mut checker = Checker::new(text);
+    let (kind, entries) = if let Some(err) = utf8_error {
+        checker.push(
+            Severity::Error,
+            Some(err.valid_up_to()),
+            "file is not valid UTF-8".to_string(),
+        );
+        (None, 0)
+    } else if ndjson {
+        checker.check_ndjson()
+    } else {
+        checker.check_json()
+    };
+
+    Ok(ArtifactReport {
+        path: path.to_path_buf(),
+        kind,
+        entries,
+        diagnostics: checker.diagnostics,
+    })
+}
+
+/// Object members in file order, keeping repeated keys that `Value` would merge.
+struct Members(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for Members {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MembersVisitor;
+
+        impl<'de> Visitor<'de> for MembersVisitor {
+            type Value = Members;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Members, A::Error> {
+                let mut members = Vec::new();
+                while let Some(entry) = map.next_entry::<String, Value>()? {
+                    members.push(entry);
+                }
+                Ok(Members(members))
+            }
+        }
+
+        deserializer.deserialize_map(MembersVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EnvelopeMembers {
+    meta: Option<Value>,
+    hashes: Members,
+    dep_edges: Option<Members>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DirectedMembers {
+    dep_edges: Option<Members>,
+    reverse_dep_edges: Option<Members>,
+    exec_dep_edges: Option<Members>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DeltaMembers {
+    base_digest: String,
+    changed: Members,
+    removed: Vec<String>,
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    /// Offset labels are searched from, so that dep edges in a combined
+    /// document are located in `depEdges` rather than in `hashes`.
+    scope: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            scope: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn check_json(&mut self) -> (Option<ArtifactKind>, usize) {
+        let members: Members = match serde_json::from_str(self.text) {
+            Ok(members) => members,
+            Err(err) => {
+                self.serde_error(&err, 0, "");
+                return (None, 0);
+            }
+        };
+        let has = |key: &str| members.0.iter().any(|(k, _)| k == key);
+
+        if has("baseDigest") {
+            (Some(ArtifactKind::HashesDelta), self.check_delta())
+        } else if has("reverseDepEdges") || has("execDepEdges") {
+            (Some(ArtifactKind::DirectedDepEdges), self.check_directed())
+        } else if has("hashes") && has("depEdges") {
+            (Some(ArtifactKind::Combined), self.check_envelope())
+        } else if has("meta") && has("hashes") {
+            (Some(ArtifactKind::HashesEnvelope), self.check_envelope())
+        } else if has("labels") && has("edges") {
+            let entries = self.check_indexed_dep_edges(members);
+            (Some(ArtifactKind::IndexedDepEdges), entries)
+        } else if matches!(members.0.first(), Some((_, Value::Array(_)))) {
+            (
+                Some(ArtifactKind::DepEdges),
+                self.check_dep_edges(&members.0),
+            )
+        } else {
+            (Some(ArtifactKind::Hashes), self.check_hashes(&members.0))
+        }
+    }
+
+    fn check_envelope(&mut self) -> usize {
+        let envelope: EnvelopeMembers = match serde_json::from_str(self.text) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                self.serde_error(&err, 0, "");
+                return 0;
+            }
+        };
+        if let Some(Err(err)) = envelope.meta.map(serde_json::from_value::<OutputMeta>) {
+            let offset = self.find_key("meta", 0);
+            self.push(Severity::Error, offset, format!("invalid meta: {err}"));
+        }
+        let entries = self.check_hashes(&envelope.hashes.0);
+        if let Some(dep_edges) = envelope.dep_edges {
+            self.check_dep_edges_member("depEdges", dep_edges);
+        }
+        entries
+    }
+
+    fn check_directed(&mut self) -> usize {
+        let directed: DirectedMembers = match serde_json::from_str(self.text) {
+            Ok(directed) => directed,
+            Err(err) => {
+                self.serde_error(&err, 0, "");
+                return 0;
+            }
+        };
+        if let Some(exec_dep_edges) = directed.exec_dep_edges {
+            self.check_dep_edges_member("execDepEdges", exec_dep_edges);
+        }
+        let forward = directed
+            .dep_edges
+            .map(|dep_edges| self.check_dep_edges_member("depEdges", dep_edges));
+        match directed.reverse_dep_edges {
+            Some(reverse) => self.check_dep_edges_member("reverseDepEdges", reverse),
+            None => forward.unwrap_or_default(),
+        }
+    }
+
+    /// Checks dep edges in either format nested under `key`.
+    fn check_dep_edges_member(&mut self, key: &str, dep_edges: Members) -> usize {
+        self.scope = self.find_key(key, 0).unwrap_or_default();
+        let has = |key: &str| dep_edges.0.iter().any(|(k, _)| k == key);
+        let entries = if has("labels") && has("edges") {
+            self.check_indexed_dep_edges(dep_edges)
+        } else {
+            self.check_dep_edges(&dep_edges.0)
+        };
+        self.scope = 0;
+        entries
+    }
+
+    fn check_delta(&mut self) -> usize {
+        let delta: DeltaMembers = match serde_json::from_str(self.text) {
+            Ok(delta) => delta,
+            Err(err) => {
+                self.serde_error(&err, 0, "");
+                return 0;
+            }
+        };
+        if delta.base_digest.len() != 64 || !is_hex(&delta.base_digest) {
+            let offset = self.find_key("baseDigest", 0);
+            self.push(
+                Severity::Error,
+                offset,
+                format!(
+                    "baseDigest {:?} is not a SHA-256 hex digest",
+                    delta.base_digest
+                ),
+            );
+        }
+        let changed = self.check_hashes(&delta.changed.0);
+
+        let changed_labels: HashSet<&str> =
+            delta.changed.0.iter().map(|(l, _)| l.as_str()).collect();
+        let mut removed = HashSet::new();
+        for label in &delta.removed {
+            let offset = self.find_value(label, 0);
+            if !removed.insert(label.as_str()) {
+                self.push(
+                    Severity::Error,
+                    offset,
+                    format!("{label} is removed more than once"),
+                );
+            }
+            if changed_labels.contains(label.as_str()) {
+                self.push(
+                    Severity::Error,
+                    offset,
+                    format!("{label} is both changed and removed"),
+                );
+            }
+            self.check_label(label, offset);
+        }
+        changed + removed.len()
+    }
+
+    fn check_hashes(&mut self, members: &[(String, Value)]) -> usize {
+        let mut occurrences: HashMap<&str, usize> = HashMap::new();
+        for (label, value) in members {
+            let offset = self.entry_offset(&mut occurrences, label);
+            self.check_label(label, offset);
+            match value {
+                Value::String(hash) => {
+                    if let Some((severity, problem)) = hash_problem(hash) {
+                        self.push(
+                            severity,
+                            offset,
+                            format!("hash {hash:?} of {label} {problem}"),
+                        );
+                    }
+                }
+                other => self.push(
+                    Severity::Error,
+                    offset,
+                    format!(
+                        "hash of {label} must be a string, found {}",
+                        json_type(other)
+                    ),
+                ),
+            }
+        }
+        occurrences.len()
+    }
+
+    fn check_dep_edges(&mut self, members: &[(String, Value)]) -> usize {
+        let labels: HashSet<&str> = members.iter().map(|(l, _)| l.as_str()).collect();
+        let mut occurrences: HashMap<&str, usize> = HashMap::new();
+        for (label, value) in members {
+            let offset = self.entry_offset(&mut occurrences, label);
+            self.check_label(label, offset);
+            let Value::Array(deps) = value else {
+                self.push(
+                    Severity::Error,
+                    offset,
+                    format!(
+                        "deps of {label} must be a list of labels, found {}",
+                        json_type(value)
+                    ),
+                );
+                continue;
+            };
+            for dep in deps {
+                match dep {
+                    Value::String(dep) => {
+                        self.check_label(dep, offset);
+                        if !labels.contains(dep.as_str()) {
+                            self.dangling(label, dep, offset);
+                        }
+                    }
+                    other => self.push(
+                        Severity::Error,
+                        offset,
+                        format!("deps of {label} must be labels, found {}", json_type(other)),
+                    ),
+                }
+            }
+        }
+        occurrences.len()
+    }
+
+    fn check_indexed_dep_edges(&mut self, members: Members) -> usize {
+        let object = Value::Object(members.0.into_iter().collect());
+        let indexed: IndexedDependencyEdges = match serde_json::from_value(object) {
+            Ok(indexed) => indexed,
+            Err(err) => {
+                self.push(
+                    Severity::Error,
+                    None,
+                    format!("invalid v2 dep edges: {err}"),
+                );
+                return 0;
+            }
+        };
+
+        let mut seen = HashSet::new();
+        for (index, label) in indexed.labels.iter().enumerate() {
+            let offset = self.find_value(label, 0);
+            if !seen.insert(label.as_str()) {
+                self.push(
+                    Severity::Error,
+                    offset,
+                    format!("{label} is listed more than once in labels (index {index})"),
+                );
+            }
+            self.check_label(label, offset);
+        }
+
+        let count = indexed.labels.len();
+        let edges_offset = self.find_key("edges", 0);
+        let with_edges: HashSet<usize> = indexed.edges.iter().map(|(label, _)| *label).collect();
+        let mut sources = HashSet::new();
+        for (position, (label, deps)) in indexed.edges.iter().enumerate() {
+            for index in std::iter::once(label).chain(deps) {
+                if *index >= count {
+                    self.push(
+                        Severity::Error,
+                        edges_offset,
+                        format!(
+                            "edge {position} refers to label index {index}, but only {count} labels are listed"
+                        ),
+                    );
+                }
+            }
+            let Some(name) = indexed.labels.get(*label) else {
+                continue;
+            };
+            if !sources.insert(*label) {
+                self.push(
+                    Severity::Error,
+                    edges_offset,
+                    format!("edges list the deps of {name} more than once"),
+                );
+            }
+            for dep in deps.iter().filter(|dep| !with_edges.contains(*dep)) {
+                if let Some(dep) = indexed.labels.get(*dep) {
+                    self.dangling(name, dep, edges_offset);
+                }
+            }
+        }
+        indexed.edges.len()
+    }
+
+    fn check_ndjson(&mut self) -> (Option<ArtifactKind>, usize) {
+        let text = self.text;
+        let mut lines = text.split_inclusive('\n');
+        let header_line = lines.next().unwrap_or_default();
+        let header: Header = match serde_json::from_str(header_line.trim_end()) {
+            Ok(header) => header,
+            Err(err) => {
+                self.serde_error(&err, 0, "invalid NDJSON header: ");
+                return (Some(ArtifactKind::NdjsonHashes), 0);
+            }
+        };
+
+        let mut offset = header_line.len();
+        let mut labels: HashSet<String> = HashSet::new();
+        for line in lines {
+            let line_offset = offset;
+            offset += line.len();
+            if !line.ends_with('\n') {
+                self.push(
+                    Severity::Error,
+                    Some(line_offset),
+                    "final line is incomplete; was generate-hashes interrupted?".to_string(),
+                );
+                break;
+            }
+            let record: HashRecord = match serde_json::from_str(line.trim_end()) {
+                Ok(record) => record,
+                Err(err) => {
+                    self.serde_error(&err, line_offset, "invalid record: ");
+                    continue;
+                }
+            };
+            let at = Some(line_offset);
+            if record.check != record.checksum(&header.fingerprint) {
+                self.push(
+                    Severity::Error,
+                    at,
+                    format!(
+                        "checksum of {} does not match the header; the line was edited or comes from a different run",
+                        record.label
+                    ),
+                );
+            }
+            if !labels.insert(record.label.clone()) {
+                self.push(
+                    Severity::Error,
+                    at,
+                    format!("{} is listed more than once", record.label),
+                );
+            }
+            self.check_label(&record.label, at);
+            if let Some((severity, problem)) = hash_problem(&record.hash) {
+                self.push(
+                    severity,
+                    at,
+                    format!("hash {:?} of {} {problem}", record.hash, record.label),
+                );
+            }
+        }
+        (Some(ArtifactKind::NdjsonHashes), labels.len())
+    }
+
+    /// Offset of the `label` key for its current occurrence, reporting repeats.
+    fn entry_offset<'m>(
+        &mut self,
+        occurrences: &mut HashMap<&'m str, usize>,
+        label: &'m str,
+    ) -> Option<usize> {
+        let seen = occurrences.entry(label).or_insert(0);
+        let nth = *seen;
+        *seen += 1;
+        let offset = self.find_key(label, nth);
+        if nth == 1 {
+            self.push(
+                Severity::Error,
+                offset,
+                format!("{label} is listed more than once"),
+            );
+        }
+        offset
+    }
+
+    fn check_label(&mut self, label: &str, offset: Option<usize>) {
+        let (_, plain) = split_namespaced_label(label);
+        if let Err(err) = validate_label(plain) {
+            self.push(Severity::Error, offset, err.to_string());
+        }
+    }
+
+    fn dangling(&mut self, label: &str, dep: &str, offset: Option<usize>) {
+        self.push(
+            Severity::Warning,
+            offset,
+            format!("{label} depends on {dep}, which has no entry of its own"),
+        );
+    }
+
+    /// Reports a serde error whose position is relative to `base`.
+    fn serde_error(&mut self, err: &serde_json::Error, base: usize, prefix: &str) {
+        let message = err.to_string();
+        let suffix = format!(" at line {} column {}", err.line(), err.column());
+        let message = message.strip_suffix(&suffix).unwrap_or(&message);
+        let offset = (err.line() > 0).then(|| {
+            let relative = &self.text[base..];
+            let line_start: usize = relative
+                .split_inclusive('\n')
+                .take(err.line() - 1)
+                .map(str::len)
+                .sum();
+            base + line_start + err.column().saturating_sub(1)
+        });
+        self.push(Severity::Error, offset, format!("{prefix}{message}"));
+    }
+
+    fn push(&mut self, severity: Severity, offset: Option<usize>, message: String) {
+        // Locating and excerpting are only worth it for diagnostics that are shown.
+        let offset = offset.filter(|_| self.diagnostics.len() < MAX_LISTED_DIAGNOSTICS);
+        let offset = offset.map(|offset| floor_char_boundary(self.text, offset));
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message,
+            position: offset.map(|offset| position(self.text, offset)),
+            excerpt: offset.map(|offset| excerpt(self.text, offset)),
+        });
+    }
+
+    /// Offset of the `nth` (0-based) occurrence of `key` as an object key.
+    fn find_key(&self, key: &str, nth: usize) -> Option<usize> {
+        if self.diagnostics.len() >= MAX_LISTED_DIAGNOSTICS {
+            return None;
+        }
+        let quoted = serde_json::to_string(key).ok()?;
+        let text = &self.text[self.scope..];
+        text.match_indices(&quoted)
+            .filter(|(index, _)| text[index + quoted.len()..].trim_start().starts_with(':'))
+            .nth(nth)
+            .map(|(index, _)| self.scope + index)
+    }
+
+    /// Offset of the `nth` (0-based) occurrence of `value` as a JSON string.
+    fn find_value(&self, value: &str, nth: usize) -> Option<usize> {
+        if self.diagnostics.len() >= MAX_LISTED_DIAGNOSTICS {
+            return None;
+        }
+        let quoted = serde_json::to_string(value).ok()?;
+        self.text[self.scope..]
+            .match_indices(&quoted)
+            .nth(nth)
+            .map(|(index, _)| self.scope + index)
+    }
+}
+
+/// Checks the `[Type#]overall[~direct]` hash format. Digests that are not
+/// hexadecimal parse fine but were not written by `generate-hashes`.
+fn hash_problem(raw: &str) -> Option<(Severity, String)> {
+    let (kind, digests) = match raw.split_once('#') {
+        Some((kind, digests)) => (Some(kind), digests),
+        None => (None, raw),
+    };
+    if let Some(kind) = kind {
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Some((
+                Severity::Error,
+                format!("has a target type {kind:?} that is not a type name"),
+            ));
+        }
+    }
+    if digests.contains('#') {
+        return Some((Severity::Error, "has more than one '#'".to_string()));
+    }
+    let parts: Vec<&str> = digests.split('~').collect();
+    if parts.len() > 2 {
+        return Some((Severity::Error, "has more than one '~'".to_string()));
+    }
+    if parts.iter().any(|part| part.is_empty()) {
+        return Some((Severity::Error, "has an empty digest".to_string()));
+    }
+    if !parts.iter().all(|part| is_hex(part)) {
+        return Some((
+            Severity::Warning,
+            "is not hexadecimal, so generate-hashes did not write it".to_string(),
+        ));
+    }
+    None
+}
+
+fn is_hex(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn floor_char_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// 1-based line and column of `offset`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// The line containing `offset` and its neighbours, numbered, with a caret
+/// under the offending column. Long lines (minified JSON) are clipped to a
+/// window around the column.
+fn excerpt(text: &str, offset: usize) -> String {
+    let (line, column) = position(text, offset);
+    let lines: Vec<&str> = text.lines().collect();
+    let start = (column - 1).saturating_sub(EXCERPT_RADIUS);
+    let width = (line + 1).to_string().len();
+
+    let clip = |source: &str| {
+        let chars: Vec<char> = source.chars().collect();
+        let end = chars.len().min(start + 2 * EXCERPT_RADIUS);
+        let mut clipped = String::new();
+        if start > 0 && start < chars.len() {
+            clipped.push('…');
+        }
+        clipped.extend(chars.get(start..end).unwrap_or_default());
+        if end < chars.len() {
+            clipped.push('…');
+        }
+        clipped
+    };
+
+    let mut rendered = Vec::new();
+    for number in line.saturating_sub(1).max(1)..=line + 1 {
+        let Some(source) = lines.get(number - 1) else {
+            continue;
+        };
+        rendered.push(format!("{number:>width$} | {}", clip(source)));
+        if number == line {
+            let marker = column - 1 - start + usize::from(start > 0);
+            rendered.push(format!("{:>width$} | {}^", "", " ".repeat(marker)));
+        }
+    }
+    rendered.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(name: &str, content: &str) -> Result<ArtifactReport> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join(name);
+        std::fs::write(&path, content)?;
+        validate_artifact(&path)
+    }
+
+    fn messages(report: &ArtifactReport) -> Vec<String> {
+        report
+            .diagnostics
+            .iter()
+            .map(|d| format!("{}: {}", d.severity, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn reports_hash_problems_with_positions() -> Result<()> {
+        let report = validate(
+            "hashes.json",
+            "{\n  \"//a:a\": \"Rule#ab~cd\",\n  \"//a:b\": \"ab~\",\n  \"a:c\": \"xyz\",\n  \"//a:a\": \"ab\"\n}\n",
+        )?;
+        assert_eq!(report.kind, Some(ArtifactKind::Hashes));
+        assert_eq!(report.entries, 3);
+        assert_eq!(
+            messages(&report),
+            vec![
+                "error: hash \"ab~\" of //a:b has an empty digest",
+                "error: invalid label \"a:c\": labels must start with //, @ or @@",
+                "warning: hash \"xyz\" of a:c is not hexadecimal, so generate-hashes did not write it",
+                "error: //a:a is listed more than once",
+            ]
+        );
+        assert_eq!(report.diagnostics[0].position, Some((3, 3)));
+        assert_eq!(report.diagnostics[3].position, Some((5, 3)));
+        assert_eq!(
+            report.diagnostics[0].excerpt.as_deref(),
+            Some("2 |   \"//a:a\": \"Rule#ab~cd\",\n3 |   \"//a:b\": \"ab~\",\n  |   ^\n4 |   \"a:c\": \"xyz\",")
+        );
+        assert!(!report.is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn reports_syntax_errors_and_dangling_dep_edges() -> Result<()> {
+        let broken = validate("broken.json", "{\"//a:a\": \"ab\",\n \"//a:b\" \"cd\"}")?;
+        assert_eq!(broken.kind, None);
+        assert_eq!(broken.diagnostics.len(), 1);
+        assert_eq!(broken.diagnostics[0].message, "expected `:`");
+        assert_eq!(broken.diagnostics[0].position, Some((2, 10)));
+
+        let deps = validate("deps.json", r#"{"//a:a": ["//a:b", "//x:y"], "//a:b": []}"#)?;
+        assert_eq!(deps.kind, Some(ArtifactKind::DepEdges));
+        assert_eq!(
+            messages(&deps),
+            vec!["warning: //a:a depends on //x:y, which has no entry of its own"]
+        );
+        assert!(deps.is_valid());
+
+        let indexed = validate(
+            "deps.v2.json",
+            r#"{"labels": ["//a:a", "//a:b"], "edges": [[0, [1, 2]]]}"#,
+        )?;
+        assert_eq!(indexed.kind, Some(ArtifactKind::IndexedDepEdges));
+        assert_eq!(
+            messages(&indexed),
+            vec![
+                "error: edge 0 refers to label index 2, but only 2 labels are listed",
+                "warning: //a:a depends on //a:b, which has no entry of its own",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use crate::bazel::{build_query_expression, query_word, run_query, target_label, BazelOptions};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+/// Comparison of hash-based impacted targets with `rdeps()` ground truth for a
+/// sample of changed files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImpactValidation {
+    pub universe: String,
+    /// Changed files whose reverse dependencies were queried.
+    pub sampled_files: Vec<String>,
+    /// Sampled files that no longer exist in the workspace and cannot be queried.
+    pub skipped_files: Vec<String>,
+    /// Whether every changed file was queried; only then are false positives
+    /// meaningful, since unsampled files may explain extra impacted targets.
+    pub complete: bool,
+    /// Number of targets `rdeps()` reports for the queried files.
+    pub expected: usize,
+    /// Number of main-repository targets reported by the hash comparison.
+    pub reported: usize,
+    /// Targets `rdeps()` reports that the hash comparison missed.
+    pub false_negatives: Vec<String>,
+    /// Targets the hash comparison reports that `rdeps()` does not.
+    pub false_positives: Vec<String>,
+}
+
+/// Queries `rdeps(universe, files)` for up to `sample_size` of `changed_files`
+/// (workspace-relative paths) and compares the result with `impacted`.
+pub async fn validate_impact(
+    opts: &BazelOptions,
+    universe: &str,
+    changed_files: &[String],
+    sample_size: usize,
+    impacted: &[String],
+) -> Result<ImpactValidation> {
+    let sample = sample_changed_files(changed_files, sample_size);
+    let (present, skipped): (Vec<String>, Vec<String>) = sample
+        .into_iter()
+        .partition(|file| opts.workspace.join(file).exists());
+    for file in &skipped {
+        warn!(file = %file, "changed file no longer exists; skipping its rdeps query");
+    }
+
+    let expected = if present.is_empty() {
+        BTreeSet::new()
+    } else {
+        let expression = format!(
+            "rdeps({}, {})",
+            query_word(universe)?,
+            build_query_expression(&present)?
+        );
+        info!(files = present.len(), "querying rdeps ground truth");
+        run_query(opts, &expression)
+            .await
+            .context("failed to query rdeps ground truth")?
+            .iter()
+            .filter_map(target_label)
+            .map(str::to_string)
+            .collect()
+    };
+
+    let distinct = changed_files.iter().collect::<BTreeSet<_>>().len();
+    let complete = skipped.is_empty() && present.len() == distinct;
+    Ok(compare_with_ground_truth(
+        universe, present, skipped, complete, &expected, impacted,
+    ))
+}
+
+/// Picks `sample_size` files spread across the tree: files are ordered by a
+/// hash of their path, so the sample is stable between runs but not biased
+/// towards any directory.
+pub fn sample_changed_files(changed_files: &[String], sample_size: usize) -> Vec<String> {
+    let mut files: Vec<&String> = changed_files
+        .iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    files.sort_by_cached_key(|file| Sha256::digest(file.as_bytes()));
+    files.truncate(sample_size);
+    let mut sample: Vec<String> = files.into_iter().cloned().collect();
+    sample.sort();
+    sample
+}
+
+fn compare_with_ground_truth(
+    universe: &str,
+    sampled_files: Vec<String>,
+    skipped_files: Vec<String>,
+    complete: bool,
+    expected: &BTreeSet<String>,
+    impacted: &[String],
+) -> ImpactValidation {
+    // `rdeps()` only sees the main repository; external and namespaced labels
+    // from the hash comparison have no counterpart.
+    let reported: BTreeSet<&str> = impacted
+        .iter()
+        .map(String::as_str)
+        .filter(|label| label.starts_with("//"))
+        .collect();
+    let false_negatives = expected
+        .iter()
+        .filter(|label| !reported.contains(label.as_str()))
+        .cloned()
+        .collect();
+    let false_positives = reported
+        .iter()
+        .filter(|label| !expected.contains(**label))
+        .map(|label| label.to_string())
+        .collect();
+    ImpactValidation {
+        universe: universe.to_string(),
+        sampled_files,
+        skipped_files,
+        complete,
+        expected: expected.len(),
+        reported: reported.len(),
+        false_negatives,
+        false_positives,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn samples_are_stable_and_bounded() {
+        let files = strings(&["a/1.txt", "b/2.txt", "c/3.txt", "d/4.txt", "a/1.txt"]);
+        let sample = sample_changed_files(&files, 2);
+        assert_eq!(sample.len(), 2);
+        let mut reversed = files.clone();
+        reversed.reverse();
+        assert_eq!(sample_changed_files(&reversed, 2), sample);
+        assert_eq!(sample_changed_files(&files, 10).len(), 4);
+    }
+
+    #[test]
+    fn reports_false_negatives_and_positives() {
+        let expected: BTreeSet<String> = strings(&["//pkg:a.txt", "//pkg:lib", "//app:bin"])
+            .into_iter()
+            .collect();
+        let impacted = strings(&[
+            "//pkg:a.txt",
+            "//pkg:lib",
+            "//pkg:other",
+            "@ext//:dep",
+            "tools!//cli:main",
+        ]);
+        let report = compare_with_ground_truth(
+            "//...",
+            strings(&["pkg/a.txt"]),
+            Vec::new(),
+            true,
+            &expected,
+            &impacted,
+        );
+        assert_eq!(report.expected, 3);
+        assert_eq!(report.reported, 3);
+        assert_eq!(report.false_negatives, strings(&["//app:bin"]));
+        assert_eq!(report.false_positives, strings(&["//pkg:other"]));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::bazel::BazelOptions;
+use crate::bazelrc::imported_files;
+use crate::content_cache::ContentCache;
+use crate::exclude::PackageExclusions;
+use crate::hash::BazelGraph;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tracing::info;
+
+/// Files whose edits can change the query result, besides `.bzl` and
+/// `.bazelrc` files.
+const BUILD_FILE_NAMES: &[&str] = &[
+    "BUILD",
+    "BUILD.bazel",
+    "WORKSPACE",
+    "WORKSPACE.bazel",
+    "WORKSPACE.bzlmod",
+    "MODULE.bazel",
+    "MODULE.bazel.lock",
+    "REPO.bazel",
+    ".bazelignore",
+    ".bazelversion",
+];
+
+/// Query results and source file digests kept in memory across the hashing
+/// runs of one long-lived process, such as `serve`. A query result is reused
+/// until a BUILD, `.bzl`, WORKSPACE, MODULE, `REPO.bazel`, `.bazelignore` or
+/// `.bazelrc` file of the workspace (or an rc file it imports) changes, or a
+/// file is added or removed, which can change what a `glob` matches; source
+/// digests are reused for files whose size,
+/// modification time and inode are unchanged. Cheap to clone; clones share
+/// the cache.
+#[derive(Debug, Clone, Default)]
+pub struct WarmCache {
+    state: Arc<Mutex<WarmState>>,
+}
+
+#[derive(Debug, Default)]
+struct WarmState {
+    /// Query result per query, with the [`build_files_digest`] it was
+    /// loaded at.
+    graphs: HashMap<String, (Vec<u8>, BazelGraph)>,
+    contents: Option<ContentCache>,
+}
+
+impl WarmCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The query result for `opts`, queried again only when a build file
+    /// changed since it was cached.
+    pub(crate) async fn load_graph(
+        &self,
+        opts: &BazelOptions,
+        fine_grained_repos: &HashSet<String>,
+        exclude_external: bool,
+        exclusions: &PackageExclusions,
+    ) -> Result<BazelGraph> {
+        let repos: BTreeSet<&String> = fine_grained_repos.iter().collect();
+        let key = format!("{opts:?}\0{repos:?}\0{exclude_external}\0{exclusions:?}");
+        // Stat before querying, so edits made during the query are noticed by
+        // the next run.
+        let build_files = build_files_digest(&opts.workspace)?;
+        let cached = self
+            .lock()
+            .graphs
+            .get(&key)
+            .and_then(|(digest, graph)| (*digest == build_files).then(|| graph.clone()));
+        if let Some(graph) = cached {
+            info!("reusing the query result of an earlier run");
+            return Ok(graph);
+        }
+        let graph =
+            BazelGraph::load(opts, fine_grained_repos, exclude_external, exclusions).await?;
+        self.lock().graphs.insert(key, (build_files, graph.clone()));
+        Ok(graph)
+    }
+
+    /// Source file digests shared by every run using this cache.
+    pub(crate) fn content_cache(&self) -> ContentCache {
+        self.lock()
+            .contents
+            .get_or_insert_with(ContentCache::in_memory)
+            .clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WarmState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Digest of the path of every file below `workspace`, plus the size and
+/// modification time of those that can change the query result and of the rc
+/// files `.bazelrc` imports. Hidden directories and the `bazel-*` convenience
+/// symlinks are skipped.
+pub(crate) fn build_files_digest(workspace: &Path) -> Result<Vec<u8>> {
+    let mut stamps = Vec::new();
+    let mut pending = vec![workspace.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("failed to list {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to list {}", dir.display()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if !name.starts_with('.') && !name.starts_with("bazel-") {
+                    pending.push(entry.path());
+                }
+            } else if BUILD_FILE_NAMES.contains(&name.as_str())
+                || name.ends_with(".bzl")
+                || name.ends_with(".bazelrc")
+            {
+                stamps.push(stamp(entry.path())?);
+            } else {
+                // Only its presence matters, for the globs that match it.
+                stamps.push((entry.path(), 0, 0));
+            }
+        }
+    }
+    for import in imported_files(&workspace.join(".bazelrc"), workspace)? {
+        if !import.starts_with(workspace) {
+            stamps.push(stamp(import)?);
+        }
+    }
+    stamps.sort();
+    let mut hasher = Sha256::new();
+    for (path, size, mtime) in stamps {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(size.to_le_bytes());
+        hasher.update(mtime.to_le_bytes());
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// The size and modification time of `path`; zero for a missing file.
+fn stamp(path: PathBuf) -> Result<(PathBuf, u64, u128)> {
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((path, 0, 0)),
+        Err(err) => return Err(err).with_context(|| format!("failed to stat {}", path.display())),
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    Ok((path, metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn build_files_digest_ignores_source_contents() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let pkg = tmp.path().join("pkg");
+        std::fs::create_dir_all(&pkg)?;
+        std::fs::create_dir_all(tmp.path().join("bazel-out"))?;
+        std::fs::write(pkg.join("BUILD.bazel"), "filegroup(name = \"a\")\n")?;
+        std::fs::write(pkg.join("a.txt"), "a")?;
+        let before = build_files_digest(tmp.path())?;
+
+        std::fs::write(pkg.join("a.txt"), "changed")?;
+        std::fs::write(tmp.path().join("bazel-out/BUILD"), "")?;
+        assert_eq!(build_files_digest(tmp.path())?, before);
+
+        std::fs::write(pkg.join("defs.bzl"), "")?;
+        let with_bzl = build_files_digest(tmp.path())?;
+        assert_ne!(with_bzl, before);
+        std::fs::File::options()
+            .write(true)
+            .open(pkg.join("defs.bzl"))?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        assert_ne!(build_files_digest(tmp.path())?, with_bzl);
+        Ok(())
+    }
+
+    #[test]
+    fn build_files_digest_sees_new_files_and_imported_rc_files() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path().join("ws");
+        let pkg = workspace.join("pkg");
+        std::fs::create_dir_all(&pkg)?;
+        std::fs::write(
+            pkg.join("BUILD"),
+            "filegroup(name = \"all\", srcs = glob([\"*\"]))\n",
+        )?;
+        let shared = tmp.path().join("shared.bazelrc");
+        std::fs::write(&shared, "build --jobs=4\n")?;
+        std::fs::write(
+            workspace.join(".bazelrc"),
+            format!("import {}\n", shared.display()),
+        )?;
+        let digests = std::cell::RefCell::new(vec![build_files_digest(&workspace)?]);
+        let changed = || -> Result<bool> {
+            let digest = build_files_digest(&workspace)?;
+            let mut digests = digests.borrow_mut();
+            let changed = !digests.contains(&digest);
+            digests.push(digest);
+            Ok(changed)
+        };
+
+        std::fs::write(pkg.join("new.txt"), "")?;
+        assert!(changed()?, "a file a glob can match was added");
+        std::fs::write(workspace.join(".bazelignore"), "pkg\n")?;
+        assert!(changed()?);
+        std::fs::write(workspace.join("REPO.bazel"), "")?;
+        assert!(changed()?);
+        std::fs::write(&shared, "build --jobs=8 --keep_going\n")?;
+        assert!(
+            changed()?,
+            "an imported rc file outside the workspace changed"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use crate::bazelrc::command_flags;
+use crate::labels::CanonicalRepoName;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Files whose presence marks the root of a Bazel workspace.
+pub const WORKSPACE_ROOT_FILES: &[&str] =
+    &["MODULE.bazel", "REPO.bazel", "WORKSPACE.bazel", "WORKSPACE"];
+
+/// Why a `--workspacePath` cannot be hashed, detected before Bazel is run.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WorkspaceError {
+    #[error("workspace {} does not exist or is not a directory", path.display())]
+    Missing { path: PathBuf },
+    #[error(
+        "{} is not a Bazel workspace: it has no MODULE.bazel, REPO.bazel, WORKSPACE.bazel \
+         or WORKSPACE file; pass the directory containing one with --workspacePath",
+        path.display()
+    )]
+    NotAWorkspace { path: PathBuf },
+    #[error(
+        "{} is a subdirectory of the Bazel workspace {}; pass the workspace root instead: \
+         --workspacePath {}",
+        path.display(),
+        root.display(),
+        root.display()
+    )]
+    Subdirectory { path: PathBuf, root: PathBuf },
+}
+
+/// Checks that `path` is the root of a Bazel workspace, suggesting the
+/// enclosing root when it is one of its subdirectories.
+pub fn check_workspace_root(path: &Path) -> Result<(), WorkspaceError> {
+    if !path.is_dir() {
+        return Err(WorkspaceError::Missing {
+            path: path.to_path_buf(),
+        });
+    }
+    if is_workspace_root(path) {
+        return Ok(());
+    }
+    let absolute = crate::paths::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    match absolute
+        .ancestors()
+        .skip(1)
+        .find(|dir| is_workspace_root(dir))
+    {
+        Some(root) => Err(WorkspaceError::Subdirectory {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+        }),
+        None => Err(WorkspaceError::NotAWorkspace {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+fn is_workspace_root(dir: &Path) -> bool {
+    WORKSPACE_ROOT_FILES
+        .iter()
+        .any(|name| dir.join(name).is_file())
+}
+
+/// A workspace defining external repositories both in MODULE.bazel and, with
+/// `--enable_workspace`, in WORKSPACE. Repositories named by `bazel_dep` and
+/// `use_repo` come from bzlmod; every other apparent name comes from
+/// WORKSPACE and lives under its own name in the output base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HybridWorkspace {
+    module_repos: BTreeSet<String>,
+}
+
+impl HybridWorkspace {
+    /// Returns the hybrid setup of `workspace`, or `None` when it uses only
+    /// one of the two worlds. WORKSPACE counts only when `--enable_workspace`
+    /// is set in `command_options` or the workspace `.bazelrc`; the last
+    /// `--[no]enable_workspace` wins.
+    pub fn detect(workspace: &Path, command_options: &[String]) -> Result<Option<Self>> {
+        let module = workspace.join("MODULE.bazel");
+        let has_workspace_file = ["WORKSPACE.bazel", "WORKSPACE"]
+            .iter()
+            .any(|name| workspace.join(name).is_file());
+        if !module.is_file() || !has_workspace_file {
+            return Ok(None);
+        }
+        let bazelrc = workspace.join(".bazelrc");
+        let mut flags = if bazelrc.is_file() {
+            let text = std::fs::read_to_string(&bazelrc)
+                .with_context(|| format!("failed to read {}", bazelrc.display()))?;
+            command_flags(&text, &["common", "build", "query", "cquery"])
+                .with_context(|| format!("in {}", bazelrc.display()))?
+        } else {
+            Vec::new()
+        };
+        flags.extend_from_slice(command_options);
+        let enabled = flags
+            .iter()
+            .rev()
+            .find_map(|flag| enable_workspace_flag(flag))
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&module)
+            .with_context(|| format!("failed to read {}", module.display()))?;
+        Ok(Some(Self {
+            module_repos: module_repo_names(&text),
+        }))
+    }
+
+    /// Whether the external repository `repo`, named with or without its
+    /// leading `@`, is defined by WORKSPACE. Canonical bzlmod names never are.
+    pub fn is_workspace_repo(&self, repo: &str) -> bool {
+        let repo = repo.trim_start_matches('@');
+        !repo.is_empty()
+            && CanonicalRepoName::parse(repo).is_none()
+            && !self.module_repos.contains(repo)
+    }
+
+    /// Apparent names of the repositories MODULE.bazel brings into scope.
+    pub fn module_repos(&self) -> &BTreeSet<String> {
+        &self.module_repos
+    }
+}
+
+fn enable_workspace_flag(flag: &str) -> Option<bool> {
+    match flag {
+        "--enable_workspace" => Some(true),
+        "--noenable_workspace" => Some(false),
+        _ => match flag.strip_prefix("--enable_workspace=")? {
+            "true" | "yes" | "1" => Some(true),
+            _ => Some(false),
+        },
+    }
+}
+
+/// Apparent names from the `bazel_dep` (its `repo_name`, else its `name`) and
+/// `use_repo` calls of a MODULE.bazel file.
+fn module_repo_names(text: &str) -> BTreeSet<String> {
+    let text: String = text
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(code, _)| code))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut names = BTreeSet::new();
+    for (function, args) in calls(&text) {
+        match function {
+            "bazel_dep" => {
+                let keyword = |key: &str| {
+                    args.iter()
+                        .find_map(|arg| keyword_arg(arg).filter(|(k, _)| *k == key))
+                        .and_then(|(_, value)| string_literal(value))
+                };
+                if let Some(name) = keyword("repo_name").or_else(|| keyword("name")) {
+                    names.insert(name.to_string());
+                }
+            }
+            "use_repo" => {
+                for arg in args.iter().skip(1) {
+                    let name = match keyword_arg(arg) {
+                        Some((alias, _)) => Some(alias),
+                        None => string_literal(arg),
+                    };
+                    names.extend(name.map(str::to_string));
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// The `bazel_dep(...)` and `use_repo(...)` calls of `text`, each with its
+/// comma-separated arguments.
+fn calls(text: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut found = Vec::new();
+    for function in ["bazel_dep", "use_repo"] {
+        let opening = format!("{function}(");
+        for (start, _) in text.match_indices(&opening) {
+            let preceded_by_identifier = text[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.');
+            if preceded_by_identifier {
+                continue;
+            }
+            let body = &text[start + opening.len()..];
+            let Some(end) = body.find(')') else {
+                continue;
+            };
+            let args = body[..end]
+                .split(',')
+                .map(str::trim)
+                .filter(|arg| !arg.is_empty())
+                .collect();
+            found.push((function, args));
+        }
+    }
+    found
+}
+
+fn keyword_arg(arg: &str) -> Option<(&str, &str)> {
+    let (key, value) = arg.split_once('=')?;
+    let key = key.trim();
+    key.chars()
+        .all(|c| c.is_alphanumeric() || c == '_')
+        .then_some((key, value.trim()))
+}
+
+fn string_literal(value: &str) -> Option<&str> {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_roots_subdirectories_and_plain_directories() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path().join("repo");
+        let pkg = root.join("pkg/sub");
+        std::fs::create_dir_all(&pkg)?;
+
+        assert_eq!(
+            check_workspace_root(&root),
+            Err(WorkspaceError::NotAWorkspace { path: root.clone() })
+        );
+        std::fs::write(root.join("MODULE.bazel"), "")?;
+        assert_eq!(check_workspace_root(&root), Ok(()));
+        match check_workspace_root(&pkg) {
+            Err(WorkspaceError::Subdirectory { root: found, .. }) => {
+                assert_eq!(found, crate::paths::canonicalize(&root)?)
+            }
+            other => panic!("expected a subdirectory error, got {other:?}"),
+        }
+        assert!(matches!(
+            check_workspace_root(&root.join("missing")),
+            Err(WorkspaceError::Missing { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn hybrid_workspaces_route_repos_by_module_file() -> anyhow::Result<()> {
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/generate/hybrid");
+        let hybrid = HybridWorkspace::detect(&fixture, &[])?.expect("fixture is hybrid");
+        assert_eq!(
+            hybrid.module_repos().iter().collect::<Vec<_>>(),
+            [
+                "com_google_protobuf",
+                "maven",
+                "rules_go",
+                "rules_python",
+                "skylib"
+            ]
+        );
+        assert!(!hybrid.is_workspace_repo("@skylib"));
+        assert!(!hybrid.is_workspace_repo("maven"));
+        assert!(!hybrid.is_workspace_repo("rules_python++pip+pypi"));
+        assert!(hybrid.is_workspace_repo("@legacy_toolchain"));
+        assert!(!hybrid.is_workspace_repo(""));
+
+        let single_world = fixture.with_file_name("workspace");
+        assert_eq!(HybridWorkspace::detect(&single_world, &[])?, None);
+        let disabled = ["--noenable_workspace".to_string()];
+        assert_eq!(HybridWorkspace::detect(&fixture, &disabled)?, None);
+
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("MODULE.bazel"), "bazel_dep(name = \"a\")\n")?;
+        std::fs::write(tmp.path().join("WORKSPACE"), "")?;
+        assert_eq!(HybridWorkspace::detect(tmp.path(), &[])?, None);
+        let enabled = ["--enable_workspace=true".to_string()];
+        assert!(HybridWorkspace::detect(tmp.path(), &enabled)?.is_some());
+        std::fs::remove_file(tmp.path().join("WORKSPACE"))?;
+        assert_eq!(HybridWorkspace::detect(tmp.path(), &enabled)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Keeps a run from writing inside directories that must stay untouched, such
+//! as a workspace CI mounts read-only: every path the run writes to is checked
+//! before it is created, and temporary files go elsewhere.
+
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+/// Directories no file may be written in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteGuard {
+    /// Canonical where the directory exists.
+    protected: Vec<PathBuf>,
+}
+
+impl WriteGuard {
+    /// Refuses writes anywhere below `dirs`.
+    pub fn protecting<I, P>(dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self {
+            protected: dirs.into_iter().map(|dir| resolve(dir.as_ref())).collect(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.protected.is_empty()
+    }
+
+    /// Fails when writing `path` would change a protected directory.
+    pub fn check(&self, path: &Path) -> Result<()> {
+        let resolved = resolve(path);
+        if let Some(dir) = self.protected_dir(&resolved) {
+            bail!(
+                "refusing to write {} inside the read-only directory {}",
+                path.display(),
+                dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Where to put temporary files that would otherwise go in `dir`: `dir`
+    /// itself, or the system temporary directory when `dir` is protected.
+    pub fn scratch_dir(&self, dir: &Path) -> PathBuf {
+        if self.protected_dir(&resolve(dir)).is_some() {
+            std::env::temp_dir()
+        } else {
+            dir.to_path_buf()
+        }
+    }
+
+    fn protected_dir(&self, resolved: &Path) -> Option<&Path> {
+        self.protected
+            .iter()
+            .find(|dir| resolved.starts_with(dir))
+            .map(PathBuf::as_path)
+    }
+}
+
+/// `path` with its longest existing ancestor canonicalized, so symlinks and
+/// `..` cannot lead a write around the guard, and files that do not exist yet
+/// are still placed correctly.
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => cwd.join(path),
+        _ => path.to_path_buf(),
+    };
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest
+                .iter()
+                .rev()
+                .fold(canonical, |dir, name| dir.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_writes_below_protected_directories() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let workspace = tmp.path().join("ws");
+        std::fs::create_dir_all(workspace.join("pkg"))?;
+        let outside = tmp.path().join("out");
+        std::fs::create_dir_all(&outside)?;
+
+        let guard = WriteGuard::protecting([&workspace]);
+        assert!(guard.is_active());
+        let err = guard
+            .check(&workspace.join("pkg/new/hashes.json"))
+            .unwrap_err();
+        assert!(err.to_string().contains("read-only directory"), "{err}");
+        assert!(guard.check(&outside.join("../ws/hashes.json")).is_err());
+        assert!(guard.check(&workspace).is_err());
+        guard.check(&outside.join("hashes.json"))?;
+        guard.check(&tmp.path().join("ws2/hashes.json"))?;
+
+        #[cfg(unix)]
+        {
+            let link = outside.join("link");
+            std::os::unix::fs::symlink(&workspace, &link)?;
+            assert!(guard.check(&link.join("hashes.json")).is_err());
+        }
+
+        assert_eq!(guard.scratch_dir(&workspace), std::env::temp_dir());
+        assert_eq!(guard.scratch_dir(&outside), outside);
+        let open = WriteGuard::default();
+        assert!(!open.is_active());
+        open.check(&workspace.join("hashes.json"))?;
+        assert_eq!(open.scratch_dir(&workspace), workspace);
+        Ok(())
+    }
+}
@@ -63,3 +63,56 @@ fn build_rust_cli() -> Result<PathBuf> {
     }
     Ok(path)
 }
+
+/// Writes a stand-in `bazel` script into `dir` that answers `info` queries
+/// (reporting its working directory as the workspace) and returns an empty
+/// streamed query result, so CLI flows that shell out to Bazel can be
+/// exercised without a real installation. `dir` usually doubles as the
+/// workspace, so it also gets an empty `MODULE.bazel` unless it has one.
+#[cfg(unix)]
+pub fn fake_bazel(dir: &std::path::Path) -> Result<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    const SCRIPT: &str = r#"#!/bin/sh
+value() {
+  case "$1" in
+    output_base) echo "@OUTPUT_BASE@" ;;
+    execution_root) echo "@OUTPUT_BASE@/execroot/_main" ;;
+    release) echo "release 7.4.1" ;;
+    workspace) pwd ;;
+  esac
+}
+info=0
+keys=""
+for arg in "$@"; do
+  case "$arg" in
+    --version) echo "bazel 7.4.1"; exit 0 ;;
+    info) info=1 ;;
+    *) if [ "$info" = 1 ]; then keys="$keys $arg"; fi ;;
+  esac
+done
+if [ "$info" = 1 ]; then
+  set -- $keys
+  if [ "$#" = 1 ]; then
+    value "$1"
+  else
+    for key in "$@"; do echo "$key: $(value "$key")"; done
+  fi
+fi
+exit 0
+"#;
+
+    let module = dir.join("MODULE.bazel");
+    if !module.exists() {
+        std::fs::write(&module, "")?;
+    }
+    let output_base = dir.join("output_base");
+    std::fs::create_dir_all(&output_base)?;
+    let script = dir.join("fake-bazel");
+    std::fs::write(
+        &script,
+        SCRIPT.replace("@OUTPUT_BASE@", &output_base.display().to_string()),
+    )?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+    Ok(script)
+}
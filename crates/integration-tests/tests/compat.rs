@@ -0,0 +1,350 @@
+//! Compatibility checks for `bazel-differrous-core` as a library. The public
+//! surface is listed one item per line, like `cargo public-api` does, and
+//! compared with `tests/golden/public-api.txt`; after an intended change, run
+//! with `UPDATE_PUBLIC_API=1` to rewrite the snapshot and commit it.
+
+use anyhow::{Context, Result};
+use bazel_differrous_core::prelude::*;
+use bazel_differrous_integration_tests::workspace_root;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use syn::{Fields, ImplItem, Item, UseTree, Visibility};
+
+const CRATE: &str = "bazel_differrous_core";
+
+#[test]
+fn public_api_matches_the_snapshot() -> Result<()> {
+    let api = public_api()?;
+    let snapshot = workspace_root().join("tests/golden/public-api.txt");
+    if std::env::var_os("UPDATE_PUBLIC_API").is_some() {
+        std::fs::write(&snapshot, &api)?;
+        return Ok(());
+    }
+    let expected = std::fs::read_to_string(&snapshot)
+        .with_context(|| format!("failed to read {}", snapshot.display()))?;
+    let (expected, actual): (BTreeSet<&str>, BTreeSet<&str>) =
+        (expected.lines().collect(), api.lines().collect());
+    let removed: Vec<_> = expected
+        .difference(&actual)
+        .map(|l| format!("-{l}"))
+        .collect();
+    let added: Vec<_> = actual
+        .difference(&expected)
+        .map(|l| format!("+{l}"))
+        .collect();
+    assert!(
+        removed.is_empty() && added.is_empty(),
+        "the public API changed; if that is intended, rerun with UPDATE_PUBLIC_API=1 \
+         and commit {}:\n{}\n{}",
+        snapshot.display(),
+        removed.join("\n"),
+        added.join("\n"),
+    );
+    Ok(())
+}
+
+#[test]
+fn prelude_compares_hashes_and_reports_structured_errors() -> Result<()> {
+    let hashes = |entries: &[(&str, &str)]| {
+        Hashes::from_map(
+            entries
+                .iter()
+                .map(|(label, hash)| (label.to_string(), hash.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    };
+    let before = hashes(&[("//:a", "a1~a"), ("//:b", "b1~b")]);
+    let after = hashes(&[("//:a", "a1~a"), ("//:b", "b2~b")]);
+    let result: ImpactedTargetsResult = before.impacted_targets(&after)?;
+    assert_eq!(result.impacted, ["//:b"]);
+
+    let tmp = tempfile::tempdir()?;
+    let err = Differ::builder(tmp.path())
+        .query_output(QueryOutputFormat::Proto)
+        .build()
+        .unwrap_err();
+    match err {
+        Error::Workspace(WorkspaceError::NotAWorkspace { .. }) => {}
+        other => panic!("expected a workspace error, got {other:?}"),
+    }
+    Ok(())
+}
+
+/// One line per public item: the public modules and re-exports of the crate
+/// root, and every item of the public modules, including the fields, variants
+/// and methods of the items they re-export from elsewhere in the crate.
+fn public_api() -> Result<String> {
+    let src = workspace_root().join("crates/core/src");
+    let mut lines = BTreeSet::new();
+    let mut modules = Vec::new();
+    for item in parse(&src.join("lib.rs"))?.items {
+        match item {
+            Item::Mod(module) if is_pub(&module.vis) => {
+                lines.insert(format!("pub mod {CRATE}::{}", module.ident));
+                modules.push(module.ident.to_string());
+            }
+            Item::Use(reexport) if is_pub(&reexport.vis) => {
+                list_reexports(&src, CRATE, &modules, &reexport.tree, &mut lines)?;
+                list_item(CRATE, Item::Use(reexport), &mut lines);
+            }
+            Item::Fn(_) => list_item(CRATE, item, &mut lines),
+            _ => {}
+        }
+    }
+    for module in &modules {
+        let path = format!("{CRATE}::{module}");
+        for item in parse(&src.join(format!("{module}.rs")))?.items {
+            if let Item::Use(reexport) = &item {
+                if is_pub(&reexport.vis) {
+                    list_reexports(&src, &path, &modules, &reexport.tree, &mut lines)?;
+                }
+            }
+            list_item(&path, item, &mut lines);
+        }
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Lists the items a `pub use` of `path` brings in from other modules of the
+/// crate as if they were defined in `path`, so a change to them shows up in
+/// the snapshot. Items re-exported from the public `modules` are listed
+/// there already.
+fn list_reexports(
+    src: &Path,
+    path: &str,
+    modules: &[String],
+    tree: &UseTree,
+    lines: &mut BTreeSet<String>,
+) -> Result<()> {
+    let mut leaves = Vec::new();
+    use_leaves(String::new(), tree, &mut leaves);
+    for (source, name) in leaves {
+        let source = source.strip_prefix("crate::").unwrap_or(&source);
+        let parts: Vec<&str> = source.split("::").collect();
+        let [module, ident] = parts.as_slice() else {
+            continue;
+        };
+        if modules.iter().any(|public| public == module) || *ident != name {
+            continue;
+        }
+        for item in parse(&src.join(format!("{module}.rs")))?.items {
+            if defines(&item, ident) {
+                list_item(path, item, lines);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `item` is the definition of `ident` or one of its impl blocks.
+fn defines(item: &Item, ident: &str) -> bool {
+    match item {
+        Item::Fn(item) => item.sig.ident == ident,
+        Item::Const(item) => item.ident == ident,
+        Item::Struct(item) => item.ident == ident,
+        Item::Enum(item) => item.ident == ident,
+        Item::Type(item) => item.ident == ident,
+        Item::Trait(item) => item.ident == ident,
+        Item::Impl(item) => match item.self_ty.as_ref() {
+            syn::Type::Path(ty) => ty
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == ident),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn parse(path: &Path) -> Result<syn::File> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    syn::parse_file(&source).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn list_item(path: &str, item: Item, lines: &mut BTreeSet<String>) {
+    match item {
+        Item::Use(item) if is_pub(&item.vis) => {
+            let mut leaves = Vec::new();
+            use_leaves(String::new(), &item.tree, &mut leaves);
+            for (source, name) in leaves {
+                lines.insert(format!("pub use {path}::{name} = {source}"));
+            }
+        }
+        Item::Fn(item) if is_pub(&item.vis) => {
+            let name = item.sig.ident.to_string();
+            lines.insert(qualify(&render_fn(item.vis, item.sig), "fn", &name, path));
+        }
+        Item::Struct(mut item) if is_pub(&item.vis) => {
+            let name = item.ident.to_string();
+            for (index, field) in item.fields.iter().enumerate() {
+                if !is_pub(&field.vis) {
+                    continue;
+                }
+                let field_name = match &field.ident {
+                    Some(ident) => ident.to_string(),
+                    None => index.to_string(),
+                };
+                let ty = render_type(&field.ty);
+                lines.insert(format!("pub {path}::{name}::{field_name}: {ty}"));
+            }
+            item.attrs.retain(is_api_attr);
+            item.fields = Fields::Unit;
+            item.semi_token = Some(Default::default());
+            lines.insert(qualify(&render(Item::Struct(item)), "struct", &name, path));
+        }
+        Item::Enum(mut item) if is_pub(&item.vis) => {
+            let name = item.ident.to_string();
+            for mut variant in std::mem::take(&mut item.variants) {
+                variant.attrs.clear();
+                variant
+                    .fields
+                    .iter_mut()
+                    .for_each(|field| field.attrs.clear());
+                let wrapper: syn::ItemEnum = syn::parse_quote!(enum E { #variant });
+                let rendered = render(Item::Enum(wrapper));
+                let variant = rendered
+                    .strip_prefix("enum E { ")
+                    .and_then(|rest| rest.strip_suffix(" }"))
+                    .unwrap_or(&rendered)
+                    .trim_end_matches(',');
+                lines.insert(format!("pub {path}::{name}::{variant}"));
+            }
+            item.attrs.retain(is_api_attr);
+            let header = render(Item::Enum(item));
+            lines.insert(qualify(header.trim_end_matches(" {}"), "enum", &name, path));
+        }
+        Item::Impl(mut item) => {
+            let self_ty = render_type(&item.self_ty);
+            let qualified_ty = format!("{path}::{self_ty}");
+            for impl_item in std::mem::take(&mut item.items) {
+                let ImplItem::Fn(method) = impl_item else {
+                    continue;
+                };
+                if item.trait_.is_some() || !is_pub(&method.vis) {
+                    continue;
+                }
+                let name = method.sig.ident.to_string();
+                let rendered = render_fn(method.vis, method.sig);
+                lines.insert(qualify(&rendered, "fn", &name, &qualified_ty));
+            }
+            if item.trait_.is_some() {
+                item.attrs.clear();
+                let header = render(Item::Impl(item));
+                let header = header.trim_end_matches(" {}");
+                lines.insert(header.replacen(
+                    &format!("for {self_ty}"),
+                    &format!("for {qualified_ty}"),
+                    1,
+                ));
+            }
+        }
+        Item::Type(mut item) if is_pub(&item.vis) => {
+            item.attrs.clear();
+            let name = item.ident.to_string();
+            lines.insert(qualify(&render(Item::Type(item)), "type", &name, path));
+        }
+        Item::Const(mut item) if is_pub(&item.vis) => {
+            item.attrs.clear();
+            let name = item.ident.to_string();
+            lines.insert(qualify(&render(Item::Const(item)), "const", &name, path));
+        }
+        Item::Trait(mut item) if is_pub(&item.vis) => {
+            item.attrs.clear();
+            let name = item.ident.to_string();
+            let header = render(Item::Trait(item));
+            lines.insert(qualify(&header, "trait", &name, path));
+        }
+        _ => {}
+    }
+}
+
+/// Every name a `use` tree brings in, with the path it comes from.
+fn use_leaves(prefix: String, tree: &UseTree, leaves: &mut Vec<(String, String)>) {
+    match tree {
+        UseTree::Path(path) => use_leaves(format!("{prefix}{}::", path.ident), &path.tree, leaves),
+        UseTree::Name(name) => {
+            leaves.push((format!("{prefix}{}", name.ident), name.ident.to_string()))
+        }
+        UseTree::Rename(rename) => leaves.push((
+            format!("{prefix}{}", rename.ident),
+            rename.rename.to_string(),
+        )),
+        UseTree::Glob(_) => leaves.push((format!("{prefix}*"), "*".to_string())),
+        UseTree::Group(group) => {
+            for tree in &group.items {
+                use_leaves(prefix.clone(), tree, leaves);
+            }
+        }
+    }
+}
+
+fn is_pub(vis: &Visibility) -> bool {
+    matches!(vis, Visibility::Public(_))
+}
+
+/// Attributes that are part of the API: derived traits and
+/// `#[non_exhaustive]`.
+fn is_api_attr(attr: &syn::Attribute) -> bool {
+    attr.path().is_ident("derive") || attr.path().is_ident("non_exhaustive")
+}
+
+/// `item` formatted by prettyplease on a single line.
+fn render(item: Item) -> String {
+    let formatted = prettyplease::unparse(&syn::File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: vec![item],
+    });
+    formatted
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("( ", "(")
+        .replace(", )", ")")
+        .replace(", }", " }")
+        .trim_end_matches(';')
+        .trim_end_matches(" {}")
+        .trim_end_matches(',')
+        .to_string()
+}
+
+/// The signature of a function; whether its arguments are bound `mut` is not
+/// part of it.
+fn render_fn(vis: Visibility, mut sig: syn::Signature) -> String {
+    for input in &mut sig.inputs {
+        match input {
+            // `mut self`, but not the `mut` of `&mut self`.
+            syn::FnArg::Receiver(receiver) if receiver.reference.is_none() => {
+                receiver.mutability = None
+            }
+            syn::FnArg::Receiver(_) => {}
+            syn::FnArg::Typed(typed) => {
+                if let syn::Pat::Ident(ident) = typed.pat.as_mut() {
+                    ident.mutability = None;
+                }
+            }
+        }
+    }
+    render(Item::Fn(syn::ItemFn {
+        attrs: Vec::new(),
+        vis,
+        sig,
+        block: Box::new(syn::parse_quote!({})),
+    }))
+}
+
+fn render_type(ty: &syn::Type) -> String {
+    let alias = render(Item::Type(syn::parse_quote!(type T = #ty;)));
+    alias.trim_start_matches("type T = ").to_string()
+}
+
+/// Prefixes the name following `keyword` in `rendered` with `path`.
+fn qualify(rendered: &str, keyword: &str, name: &str, path: &str) -> String {
+    rendered.replacen(
+        &format!("{keyword} {name}"),
+        &format!("{keyword} {path}::{name}"),
+        1,
+    )
+}
@@ -0,0 +1,195 @@
+#![cfg(unix)]
+
+use anyhow::Result;
+use assert_cmd::Command;
+use bazel_differrous_integration_tests::{fake_bazel, rust_cli_path, workspace_root};
+use predicates::prelude::*;
+use std::path::Path;
+
+#[test]
+fn diff_against_starting_hashes_reports_removed_targets() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let bazel = fake_bazel(tmp.path())?;
+    let final_out = tmp.path().join("final.json");
+    let start = workspace_root().join("tests/fixtures/impact/starting.json");
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("diff")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(&bazel)
+        .arg("--startingHashes")
+        .arg(&start)
+        .arg("--finalHashesOutput")
+        .arg(&final_out)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let labels: Vec<_> = String::from_utf8(output)?
+        .lines()
+        .map(str::to_string)
+        .collect();
+    assert_eq!(labels.len(), 5);
+    assert!(labels.contains(&"//app:bin".to_string()));
+    assert_eq!(std::fs::read_to_string(&final_out)?, "{}");
+    Ok(())
+}
+
+#[test]
+fn diff_refuses_a_baseline_of_another_hash_function() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let bazel = fake_bazel(tmp.path())?;
+    let sha256 = workspace_root().join("tests/fixtures/impact/starting.json");
+    let diff = |start: &Path| -> Result<Command> {
+        let mut command = Command::new(rust_cli_path()?);
+        command
+            .arg("diff")
+            .arg("-w")
+            .arg(tmp.path())
+            .arg("--bazelPath")
+            .arg(&bazel)
+            .arg("--startingHashes")
+            .arg(start)
+            .args(["--hashFunction", "blake3"]);
+        Ok(command)
+    };
+
+    diff(&sha256)?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the starting hashes were made with sha256 and the final hashes with blake3",
+        ));
+
+    // The final hashes record their function, so comparing them later with
+    // SHA-256 hashes fails too.
+    let start = tmp.path().join("start.json");
+    std::fs::write(&start, r#"{"meta":{"hashFunction":"blake3"},"hashes":{}}"#)?;
+    let final_out = tmp.path().join("final.json");
+    diff(&start)?
+        .arg("--finalHashesOutput")
+        .arg(&final_out)
+        .assert()
+        .success();
+    let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&final_out)?)?;
+    assert_eq!(document["meta"]["hashFunction"], "blake3");
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&sha256)
+        .arg("-fh")
+        .arg(&final_out)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn since_tag_generates_and_then_reuses_a_cached_baseline() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let bazel = fake_bazel(tmp.path())?;
+    let repo = tmp.path().join("repo");
+    let cache = tmp.path().join("cache");
+    std::fs::create_dir_all(&repo)?;
+    std::fs::write(repo.join("MODULE.bazel"), "")?;
+    std::fs::write(repo.join("BUILD"), "")?;
+    for args in [
+        &["init", "--quiet"][..],
+        &["add", "-A"],
+        &[
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "--quiet",
+            "-m",
+            "release",
+        ],
+        &["tag", "v1.0"],
+    ] {
+        assert!(std::process::Command::new("git")
+            .args(args)
+            .current_dir(&repo)
+            .status()?
+            .success());
+    }
+
+    for _ in 0..2 {
+        Command::new(rust_cli_path()?)
+            .arg("diff")
+            .arg("-w")
+            .arg(&repo)
+            .arg("--bazelPath")
+            .arg(&bazel)
+            .args(["--sinceTag", "v1.0", "--baselineCacheDir"])
+            .arg(&cache)
+            .assert()
+            .success()
+            .stdout("");
+    }
+    let cached: Vec<_> = std::fs::read_dir(&cache)?
+        .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<_>>()?;
+    assert_eq!(cached.iter().filter(|n| n.ends_with(".json")).count(), 1);
+    assert!(!cached.iter().any(|n| n.starts_with("worktree-")));
+
+    Command::new(rust_cli_path()?)
+        .arg("diff")
+        .arg("-w")
+        .arg(&repo)
+        .args(["--sinceTag", "no-such-tag", "--baselineCacheDir"])
+        .arg(&cache)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn diff_requires_a_baseline_source() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    Command::new(rust_cli_path()?)
+        .arg("diff")
+        .arg("-w")
+        .arg(tmp.path())
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn cache_prime_file_lists_deps_before_dependents() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/a.txt"), "a")?;
+    let query = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &query,
+        r#"{"type":"RULE","rule":{"name":"//pkg:lib","ruleClass":"genrule","ruleInput":["//pkg:a.txt"]}}
+{"type":"SOURCE_FILE","sourceFile":{"name":"//pkg:a.txt"}}
+"#,
+    )?;
+    let start = tmp.path().join("start.json");
+    std::fs::write(&start, "{}")?;
+    let prime = tmp.path().join("prime.txt");
+
+    Command::new(rust_cli_path()?)
+        .arg("diff")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--queryProtoPath")
+        .arg(&query)
+        .arg("--startingHashes")
+        .arg(&start)
+        .arg("--cachePrimeFile")
+        .arg(&prime)
+        .assert()
+        .success();
+
+    assert_eq!(std::fs::read_to_string(&prime)?, "//pkg:a.txt\n//pkg:lib\n");
+    Ok(())
+}
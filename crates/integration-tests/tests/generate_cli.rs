@@ -0,0 +1,1589 @@
+#![cfg(unix)]
+
+use anyhow::Result;
+use assert_cmd::Command;
+use bazel_differrous_integration_tests::{fake_bazel, rust_cli_path, workspace_root};
+use predicates::prelude::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+fn generate_cmd(workspace: &Path) -> Result<Command> {
+    let bazel = fake_bazel(workspace)?;
+    let mut cmd = Command::new(rust_cli_path()?);
+    cmd.arg("generate-hashes")
+        .arg("-w")
+        .arg(workspace)
+        .arg("--bazelPath")
+        .arg(bazel);
+    Ok(cmd)
+}
+
+#[test]
+fn ndjson_output_can_resume_in_place_and_feeds_impact() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let out = tmp.path().join("hashes.ndjson");
+
+    generate_cmd(tmp.path())?
+        .args(["--outputFormat", "ndjson"])
+        .arg(&out)
+        .assert()
+        .success();
+    let first = std::fs::read_to_string(&out)?;
+    assert!(first.starts_with("{\"fingerprint\":"));
+
+    generate_cmd(tmp.path())?
+        .args(["--outputFormat", "ndjson", "--resumeFrom"])
+        .arg(&out)
+        .arg(&out)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&out)?, first);
+
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&out)
+        .arg("-fh")
+        .arg(&out)
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn binary_output_formats_are_read_back_by_impact() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let mut outputs = Vec::new();
+    for format in ["json", "proto", "msgpack"] {
+        let out = tmp.path().join(format!("hashes.{format}"));
+        generate_cmd(tmp.path())?
+            .args(["--outputFormat", format])
+            .arg(&out)
+            .assert()
+            .success();
+        outputs.push(out);
+    }
+    assert!(!std::fs::read(&outputs[1])?.starts_with(b"{"));
+
+    for pair in outputs.windows(2) {
+        Command::new(rust_cli_path()?)
+            .arg("get-impacted-targets")
+            .arg("-sh")
+            .arg(&pair[0])
+            .arg("-fh")
+            .arg(&pair[1])
+            .assert()
+            .success()
+            .stdout("");
+    }
+    generate_cmd(tmp.path())?
+        .args(["--outputFormat", "proto", "--combinedOutput"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "not supported with --outputFormat proto",
+        ));
+    Ok(())
+}
+
+#[test]
+fn clean_removes_leftover_temp_files_only() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let stale = tmp.path().join(".bazel-differrous-1a2b.query");
+    std::fs::write(&stale, "deps(//...)")?;
+    generate_cmd(tmp.path())?
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .success();
+
+    Command::new(rust_cli_path()?)
+        .env("XDG_CACHE_HOME", tmp.path().join("cache"))
+        .args(["clean", "-w"])
+        .arg(tmp.path())
+        .assert()
+        .success()
+        .stdout(format!("{}\n", stale.display()));
+    assert!(!stale.exists());
+    assert!(tmp.path().join("hashes.json").is_file());
+    Ok(())
+}
+
+#[test]
+fn query_proto_from_stdin_skips_bazel() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(tmp.path().join("no-such-bazel"))
+        .args(["--queryProtoPath", "-"])
+        .write_stdin(Vec::new())
+        .assert()
+        .success()
+        .stdout("{}");
+    Ok(())
+}
+
+#[test]
+fn audit_file_access_writes_a_report() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let audit = tmp.path().join("audit.json");
+    generate_cmd(tmp.path())?
+        .arg("--auditFileAccess")
+        .arg(&audit)
+        .assert()
+        .success();
+
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&audit)?)?;
+    assert_eq!(
+        report,
+        serde_json::json!({"files": [], "totalBytes": 0, "outside": 0})
+    );
+    Ok(())
+}
+
+#[test]
+fn time_budget_records_coverage_in_meta() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    generate_cmd(tmp.path())?
+        .args(["--timeBudget", "60"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--timeBudget requires --outputFormat json-v2 or --combinedOutput",
+        ));
+
+    let output = generate_cmd(tmp.path())?
+        .args(["--timeBudget", "60", "--combinedOutput"])
+        .output()?;
+    assert!(output.status.success());
+    let combined: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        combined["meta"],
+        serde_json::json!({"coverage": {
+            "complete": true,
+            "hashedTargets": 0,
+            "unhashedTargets": 0,
+            "unhashedPackages": []
+        }})
+    );
+    Ok(())
+}
+
+#[test]
+fn streamed_jsonproto_queries_hash_like_archived_results() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(workspace.join("pkg"))?;
+    std::fs::write(workspace.join("pkg/a.txt"), "a")?;
+    let fake = fake_bazel(&workspace)?;
+    let archived = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &archived,
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:a.txt\"}}\n",
+    )?;
+    let bazel = tmp.path().join("jsonproto-bazel");
+    std::fs::write(
+        &bazel,
+        format!(
+            "#!/bin/sh\ncase \" $* \" in *\" query \"*--output=streamed_jsonproto*) exec cat {} ;; esac\n\
+             exec {} \"$@\"\n",
+            archived.display(),
+            fake.display()
+        ),
+    )?;
+    std::fs::set_permissions(&bazel, std::fs::Permissions::from_mode(0o755))?;
+
+    let queried = tmp.path().join("queried.json");
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(&workspace)
+        .arg("--bazelPath")
+        .arg(&bazel)
+        .args(["--queryOutputFormat", "streamed_jsonproto"])
+        .arg(&queried)
+        .assert()
+        .success();
+    let hashes: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&queried)?)?;
+    assert!(hashes.get("//pkg:a.txt").is_some(), "{hashes}");
+
+    let replayed = tmp.path().join("replayed.json");
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(&workspace)
+        .arg("--queryProtoPath")
+        .arg(&archived)
+        .arg(&replayed)
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&queried)?,
+        std::fs::read_to_string(&replayed)?
+    );
+    Ok(())
+}
+
+#[test]
+fn hash_function_is_recorded_and_checked_before_comparing() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/a.txt"), "a")?;
+    let query = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &query,
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:a.txt\"}}\n",
+    )?;
+    let generate = |function: &str, out: &Path| {
+        let mut cmd = generate_cmd(tmp.path())?;
+        cmd.arg("--queryProtoPath")
+            .arg(&query)
+            .args(["--hashFunction", function])
+            .arg(out);
+        anyhow::Ok(cmd)
+    };
+    let sha256 = tmp.path().join("sha256.json");
+    let blake3 = tmp.path().join("blake3.json");
+    generate("sha256", &sha256)?.assert().success();
+    generate("blake3", &blake3)?.assert().success();
+
+    let plain: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&sha256)?)?;
+    let envelope: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&blake3)?)?;
+    assert_eq!(envelope["meta"]["hashFunction"], "blake3");
+    assert_ne!(envelope["hashes"]["//pkg:a.txt"], plain["//pkg:a.txt"]);
+
+    generate("xxh3", &tmp.path().join("hashes.pb"))?
+        .args(["--outputFormat", "proto"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--hashFunction is recorded"));
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&sha256)
+        .arg("-fh")
+        .arg(&blake3)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the starting hashes were made with sha256 and the final hashes with blake3",
+        ));
+    Ok(())
+}
+
+#[test]
+fn previous_hashes_reuse_digests_of_unchanged_files() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/a.txt"), "a")?;
+    std::fs::write(tmp.path().join("pkg/b.txt"), "b")?;
+    let query = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &query,
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:a.txt\"}}\n\
+         {\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:b.txt\"}}\n",
+    )?;
+    let modified = tmp.path().join("modified.txt");
+    let generate = |out: &Path| {
+        let mut cmd = generate_cmd(tmp.path())?;
+        cmd.arg("--queryProtoPath")
+            .arg(&query)
+            .arg("--noContentCache")
+            .args(["--outputFormat", "ndjson"])
+            .arg(out);
+        anyhow::Ok(cmd)
+    };
+    let hashes = |path: &Path| -> Result<BTreeMap<String, String>> {
+        let mut hashes = BTreeMap::new();
+        for line in std::fs::read_to_string(path)?.lines().skip(1) {
+            let record: serde_json::Value = serde_json::from_str(line)?;
+            let label = record["label"].as_str().unwrap_or_default().to_string();
+            hashes.insert(label, record["hash"].to_string());
+        }
+        Ok(hashes)
+    };
+    let previous = tmp.path().join("previous.ndjson");
+    generate(&previous)?.assert().success();
+    std::fs::write(tmp.path().join("pkg/b.txt"), "changed")?;
+
+    // Nothing is listed as modified, so b.txt keeps its previous digest.
+    std::fs::write(&modified, "")?;
+    let stale = tmp.path().join("stale.ndjson");
+    generate(&stale)?
+        .arg("--previousHashes")
+        .arg(&previous)
+        .arg("-m")
+        .arg(&modified)
+        .assert()
+        .success();
+    assert_eq!(hashes(&stale)?, hashes(&previous)?);
+
+    std::fs::write(&modified, "pkg/b.txt\n")?;
+    let incremental = tmp.path().join("incremental.ndjson");
+    generate(&incremental)?
+        .arg("--previousHashes")
+        .arg(&previous)
+        .arg("-m")
+        .arg(&modified)
+        .assert()
+        .success();
+    let full = tmp.path().join("full.ndjson");
+    generate(&full)?.assert().success();
+    assert_eq!(hashes(&incremental)?, hashes(&full)?);
+    assert_ne!(hashes(&incremental)?, hashes(&previous)?);
+
+    let json = tmp.path().join("previous.json");
+    std::fs::write(&json, "{}")?;
+    generate(&tmp.path().join("out.ndjson"))?
+        .arg("--previousHashes")
+        .arg(&json)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not an NDJSON hash file"));
+    Ok(())
+}
+
+#[test]
+fn hash_target_reports_labels_missing_from_the_query_result() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let bazel = fake_bazel(tmp.path())?;
+    Command::new(rust_cli_path()?)
+        .arg("hash-target")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(bazel)
+        .args(["--target", "//pkg:foo"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "//pkg:foo is not in the query result",
+        ));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_reuses_the_query_result_until_a_build_file_changes() -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(&workspace)?;
+    let fake = fake_bazel(&workspace)?;
+    let log = tmp.path().join("bazel.log");
+    let bazel = tmp.path().join("logging-bazel");
+    std::fs::write(
+        &bazel,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\nexec {} \"$@\"\n",
+            log.display(),
+            fake.display()
+        ),
+    )?;
+    std::fs::set_permissions(&bazel, std::fs::Permissions::from_mode(0o755))?;
+
+    let socket = tmp.path().join("serve.sock");
+    let mut server = std::process::Command::new(rust_cli_path()?)
+        .arg("serve")
+        .arg("--socket")
+        .arg(&socket)
+        .spawn()?;
+    let result = (|| -> Result<()> {
+        let started = std::time::Instant::now();
+        let stream = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed().as_secs() < 30 => {
+                    std::thread::sleep(std::time::Duration::from_millis(50))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        let mut responses = BufReader::new(stream.try_clone()?).lines();
+        let mut request = |args: serde_json::Value| -> Result<serde_json::Value> {
+            writeln!(&stream, "{}", serde_json::json!({ "args": args }))?;
+            let line = responses.next().expect("server answers every request")?;
+            Ok(serde_json::from_str(&line)?)
+        };
+        let generate = serde_json::json!([
+            "generate-hashes",
+            "-w",
+            workspace,
+            "--bazelPath",
+            bazel,
+            tmp.path().join("hashes.json"),
+        ]);
+        let queries = || -> Result<usize> {
+            let log = std::fs::read_to_string(&log)?;
+            Ok(log.lines().filter(|line| line.contains("query")).count())
+        };
+
+        assert_eq!(request(generate.clone())?["exitCode"], 0);
+        let cold = queries()?;
+        assert!(cold > 0);
+        assert_eq!(request(generate.clone())?["exitCode"], 0);
+        assert_eq!(queries()?, cold);
+
+        std::fs::write(workspace.join("BUILD.bazel"), "")?;
+        assert_eq!(request(generate)?["exitCode"], 0);
+        assert!(queries()? > cold);
+
+        let failed = request(serde_json::json!(["no-such-command"]))?;
+        assert_eq!(failed["status"], "error");
+        Ok(())
+    })();
+    server.kill()?;
+    server.wait()?;
+    result
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_reports_health_and_metrics_and_drains_on_sigterm() -> Result<()> {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(&workspace)?;
+    let fake = fake_bazel(&workspace)?;
+    // Slow enough for SIGTERM to arrive while a request runs.
+    let bazel = tmp.path().join("slow-bazel");
+    std::fs::write(
+        &bazel,
+        format!("#!/bin/sh\nsleep 1\nexec {} \"$@\"\n", fake.display()),
+    )?;
+    std::fs::set_permissions(&bazel, std::fs::Permissions::from_mode(0o755))?;
+    let address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let socket = tmp.path().join("serve.sock");
+    let mut server = std::process::Command::new(rust_cli_path()?)
+        .arg("serve")
+        .arg("--socket")
+        .arg(&socket)
+        .arg("--httpAddress")
+        .arg(address.to_string())
+        .spawn()?;
+    let result = (|| -> Result<()> {
+        let get = |path: &str| -> Result<String> {
+            let mut stream = TcpStream::connect(address)?;
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let started = std::time::Instant::now();
+        let stream = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed().as_secs() < 30 => {
+                    std::thread::sleep(std::time::Duration::from_millis(50))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        assert!(get("/healthz")?.starts_with("HTTP/1.1 200 OK"));
+        assert!(get("/nope")?.starts_with("HTTP/1.1 404"));
+
+        let mut responses = BufReader::new(stream.try_clone()?).lines();
+        let mut request = |args: serde_json::Value| -> Result<serde_json::Value> {
+            writeln!(&stream, "{}", serde_json::json!({ "args": args }))?;
+            let line = responses.next().expect("server answers every request")?;
+            Ok(serde_json::from_str(&line)?)
+        };
+        assert_eq!(
+            request(serde_json::json!(["no-such-command"]))?["exitCode"],
+            1
+        );
+        let metrics = get("/metrics")?;
+        assert!(
+            metrics.contains("bazel_differrous_serve_requests_total{status=\"error\"} 1\n"),
+            "{metrics}"
+        );
+        assert!(metrics.contains("bazel_differrous_serve_in_flight_requests 0\n"));
+
+        writeln!(
+            &stream,
+            "{}",
+            serde_json::json!({"args": [
+                "generate-hashes",
+                "-w",
+                workspace,
+                "--bazelPath",
+                bazel,
+                tmp.path().join("hashes.json"),
+            ]})
+        )?;
+        let started = std::time::Instant::now();
+        while !get("/metrics")?.contains("bazel_differrous_serve_in_flight_requests 1\n") {
+            assert!(started.elapsed().as_secs() < 30, "request never started");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let killed = std::process::Command::new("kill")
+            .args(["-TERM", &server.id().to_string()])
+            .status()?;
+        assert!(killed.success());
+        let started = std::time::Instant::now();
+        while !get("/healthz")?.starts_with("HTTP/1.1 503") {
+            assert!(started.elapsed().as_secs() < 30, "server never drained");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let line = responses.next().expect("the running request is answered")?;
+        let response: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(response["exitCode"], 0);
+        assert!(tmp.path().join("hashes.json").is_file());
+        Ok(())
+    })();
+    if result.is_err() {
+        server.kill()?;
+    }
+    let status = server.wait()?;
+    result?;
+    assert!(status.success());
+    assert!(!socket.exists());
+    Ok(())
+}
+
+#[test]
+fn project_config_supplies_defaults_and_validates() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    fake_bazel(tmp.path())?;
+    let config = tmp.path().join(".bazel-differrous.toml");
+    std::fs::write(
+        &config,
+        "bazelPath = \"./fake-bazel\"\nexcludeExternalTargets = true\n",
+    )?;
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(tmp.path())
+        .assert()
+        .success()
+        .stdout("{}");
+    Command::new(rust_cli_path()?)
+        .args(["config", "validate", "-w"])
+        .arg(tmp.path())
+        .assert()
+        .success();
+
+    // Command-line flags win over the file, including switches turned off
+    // and lists cleared.
+    std::fs::write(
+        &config,
+        "bazelPath = \"./fake-bazel\"\nnoDefaultIgnoredAttributes = true\n\
+         ignoredRuleHashingAttributes = [\"tags\"]\n",
+    )?;
+    let ignored_attributes = |args: &[&str]| -> Result<serde_json::Value> {
+        let out = tmp.path().join("hashes.json");
+        Command::new(rust_cli_path()?)
+            .arg("generate-hashes")
+            .arg("-w")
+            .arg(tmp.path())
+            .args(["--outputFormat", "json-v2"])
+            .args(args)
+            .arg(&out)
+            .assert()
+            .success();
+        let hashes: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&out)?)?;
+        Ok(hashes["meta"]["ignoredAttributes"].clone())
+    };
+    assert_eq!(ignored_attributes(&[])?, serde_json::json!(["tags"]));
+    assert_eq!(
+        ignored_attributes(&[
+            "--noDefaultIgnoredAttributes=false",
+            "--ignoredRuleHashingAttributes="
+        ])?,
+        serde_json::json!(["generator_location"])
+    );
+
+    std::fs::write(
+        &config,
+        "bazelPth = \"bazel\"\njobs = \"many\"\nuseCquery = true\n",
+    )?;
+    let output = Command::new(rust_cli_path()?)
+        .args(["config", "validate", "-w"])
+        .arg(tmp.path())
+        .assert()
+        .failure()
+        .get_output()
+        .clone();
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("bazelPth: unknown field"), "{stdout}");
+    assert!(stdout.contains("jobs: invalid type"), "{stdout}");
+    assert_eq!(stdout.lines().count(), 2, "{stdout}");
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(tmp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("config validate"));
+    Ok(())
+}
+
+#[test]
+fn non_workspace_directories_are_rejected_before_running_bazel() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let pkg = tmp.path().join("pkg");
+    std::fs::create_dir_all(&pkg)?;
+    let generate = |workspace: &Path| -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("generate-hashes")
+            .arg("-w")
+            .arg(workspace)
+            .arg("--bazelPath")
+            .arg(tmp.path().join("no-such-bazel"));
+        Ok(cmd)
+    };
+
+    generate(&pkg)?
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("is not a Bazel workspace"));
+    std::fs::write(tmp.path().join("WORKSPACE"), "")?;
+    generate(&pkg)?
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("pass the workspace root instead"));
+    Ok(())
+}
+
+#[test]
+fn invalid_fine_grained_repo_is_rejected_before_running_bazel() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    Command::new(rust_cli_path()?)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(tmp.path().join("no-such-bazel"))
+        .args(["--fineGrainedHashExternalRepos", "rules_go"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("must start with @ or @@"));
+    Ok(())
+}
+
+#[test]
+fn json_v2_records_redacted_provenance() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let out = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .args(["--outputFormat", "json-v2", "--redactArgs", "cookie"])
+        .arg("--bazelCommandOptions=--remote_header=Cookie=abc")
+        .arg(&out)
+        .assert()
+        .success();
+
+    let envelope: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+    let generated_by = &envelope["meta"]["generatedBy"];
+    assert_eq!(generated_by["tool"], "bazel-differrous");
+    let command_line = generated_by["commandLine"].to_string();
+    assert!(command_line.contains("--remote_header=Cookie=<redacted>"));
+    assert!(!command_line.contains("abc"));
+    assert_eq!(envelope["meta"]["hashFunction"], "sha256");
+    assert_eq!(envelope["meta"]["useCquery"], false);
+    assert_eq!(envelope["meta"]["bazelVersion"], "7.4.1");
+    assert_eq!(
+        envelope["meta"]["ignoredAttributes"],
+        serde_json::json!(["generator_location"])
+    );
+    assert_eq!(envelope["hashes"], serde_json::json!({}));
+    Ok(())
+}
+
+#[test]
+fn no_default_ignored_attributes_is_recorded_in_meta() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let out = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .args(["--outputFormat", "json-v2", "--noDefaultIgnoredAttributes"])
+        .args(["--ignoredRuleHashingAttributes", "tags"])
+        .arg(&out)
+        .assert()
+        .success();
+
+    let envelope: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+    assert_eq!(
+        envelope["meta"]["ignoredAttributes"],
+        serde_json::json!(["tags"])
+    );
+    Ok(())
+}
+
+#[test]
+fn exec_dep_edges_need_a_live_cquery() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    generate_cmd(tmp.path())?
+        .arg("--execDepEdges")
+        .arg("-d")
+        .arg(tmp.path().join("dep_edges.json"))
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("pass --useCquery"));
+    generate_cmd(tmp.path())?
+        .args(["--execDepEdges", "--useCquery"])
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--depEdgesFile"));
+    Ok(())
+}
+
+#[test]
+fn resume_requires_ndjson_output() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    generate_cmd(tmp.path())?
+        .arg("--resumeFrom")
+        .arg(tmp.path().join("partial.ndjson"))
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn validate_impact_reports_against_rdeps() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path();
+    std::fs::write(workspace.join("present.txt"), "x")?;
+    let changed = workspace.join("changed.txt");
+    std::fs::write(&changed, "present.txt\ndeleted.txt\n")?;
+    let hashes = workspace_root().join("tests/fixtures/impact");
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("validate-impact")
+        .arg("-w")
+        .arg(workspace)
+        .arg("--bazelPath")
+        .arg(fake_bazel(workspace)?)
+        .arg("-sh")
+        .arg(hashes.join("starting.json"))
+        .arg("-fh")
+        .arg(hashes.join("final.json"))
+        .arg("--changedFiles")
+        .arg(&changed)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(report["sampledFiles"], serde_json::json!(["present.txt"]));
+    assert_eq!(report["skippedFiles"], serde_json::json!(["deleted.txt"]));
+    assert_eq!(report["complete"], false);
+    // The stand-in Bazel returns no rdeps, so every impacted target is extra.
+    assert_eq!(report["expected"], 0);
+    assert_eq!(report["falsePositives"].as_array().map(Vec::len), Some(3));
+    Ok(())
+}
+
+#[test]
+fn detect_concurrent_modifications_accepts_optional_mode() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let out = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .arg("--detectConcurrentModifications")
+        .arg(&out)
+        .assert()
+        .success();
+    generate_cmd(tmp.path())?
+        .arg("--detectConcurrentModifications=warn")
+        .arg(&out)
+        .assert()
+        .success();
+    generate_cmd(tmp.path())?
+        .arg("--detectConcurrentModifications=ignore")
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn delta_against_writes_changes_that_apply_on_the_baseline() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let baseline = workspace_root().join("tests/fixtures/impact/starting.json");
+    let delta = tmp.path().join("delta.json");
+    // The stand-in Bazel reports no targets, so every baseline label is removed.
+    generate_cmd(tmp.path())?
+        .arg("--deltaAgainst")
+        .arg(&baseline)
+        .arg(&delta)
+        .assert()
+        .success();
+    let written: serde_json::Value = serde_json::from_slice(&std::fs::read(&delta)?)?;
+    assert_eq!(written["changed"], serde_json::json!({}));
+    assert_eq!(written["removed"].as_array().map(Vec::len), Some(5));
+
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&baseline)
+        .arg("--applyDelta")
+        .arg(&delta)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("//app:bin").and(predicates::str::contains("//pkg:lib")));
+
+    // A delta only applies to the hashes it was generated against.
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(workspace_root().join("tests/fixtures/impact/final.json"))
+        .arg("--applyDelta")
+        .arg(&delta)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("different hashes"));
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&delta)
+        .arg("-fh")
+        .arg(&baseline)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("is a delta hash file"));
+    Ok(())
+}
+
+#[test]
+fn bazel_diff_compat_translates_upstream_spellings() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let upstream_args = [
+        "--useCquery=false",
+        "--includeTargetType=true",
+        "--no-keep_going",
+    ];
+    generate_cmd(tmp.path())?
+        .args(upstream_args)
+        .arg(tmp.path().join("rejected.json"))
+        .assert()
+        .failure();
+
+    let out = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .args(["--compat", "bazel-diff"])
+        .args(upstream_args)
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&out)?, "{}");
+
+    // Invoking the binary as `bazel-diff` enables the same translation.
+    let alias = tmp.path().join("bazel-diff");
+    std::os::unix::fs::symlink(rust_cli_path()?, &alias)?;
+    let aliased_out = tmp.path().join("aliased.json");
+    Command::new(&alias)
+        .arg("generate-hashes")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(fake_bazel(tmp.path())?)
+        .args(upstream_args)
+        .arg(format!("--output={}", aliased_out.display()))
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&aliased_out)?, "{}");
+    Ok(())
+}
+
+#[test]
+fn combined_output_is_one_artifact_on_stdout() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let stdout = generate_cmd(tmp.path())?
+        .arg("--combinedOutput")
+        .args(["-d", "-"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let combined: serde_json::Value = serde_json::from_slice(&stdout)?;
+    assert_eq!(combined, serde_json::json!({"hashes": {}, "depEdges": {}}));
+
+    let artifact = tmp.path().join("combined.json");
+    std::fs::write(&artifact, &stdout)?;
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&artifact)
+        .arg("-fh")
+        .arg(&artifact)
+        .arg("-d")
+        .arg(&artifact)
+        .assert()
+        .success()
+        .stdout("[]");
+
+    // Without framing, hashes and dep edges cannot share stdout.
+    generate_cmd(tmp.path())?
+        .args(["-d", "-"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--combinedOutput"));
+    let hashes = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .args(["-d", "-"])
+        .arg(&hashes)
+        .assert()
+        .success()
+        .stdout("{}");
+    assert_eq!(std::fs::read_to_string(&hashes)?, "{}");
+    Ok(())
+}
+
+#[test]
+fn artifact_report_measures_written_files() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let hashes = tmp.path().join("hashes.json");
+    let report_path = tmp.path().join("report.json");
+    generate_cmd(tmp.path())?
+        .arg(&hashes)
+        .args(["-d", "-"])
+        .arg("--artifactReport")
+        .arg(&report_path)
+        .assert()
+        .success();
+
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&report_path)?)?;
+    assert_eq!(report["labels"], 0);
+    let files = report["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1, "dep edges on stdout are not measured");
+    assert_eq!(files[0]["role"], "hashes");
+    assert_eq!(files[0]["bytes"], std::fs::metadata(&hashes)?.len());
+    Ok(())
+}
+
+#[test]
+fn excluded_packages_are_left_out_of_the_hashes() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    for dir in ["pkg", "vendor", "third_party/chromium"] {
+        std::fs::create_dir_all(tmp.path().join(dir))?;
+        std::fs::write(tmp.path().join(dir).join("a.txt"), dir)?;
+    }
+    std::fs::write(tmp.path().join(".bazelignore"), "vendor\n")?;
+    let query = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &query,
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:a.txt\"}}\n\
+         {\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//vendor:a.txt\"}}\n\
+         {\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//third_party/chromium:a.txt\"}}\n",
+    )?;
+    let out = tmp.path().join("hashes.json");
+    generate_cmd(tmp.path())?
+        .arg("--queryProtoPath")
+        .arg(&query)
+        .args(["--excludePatterns", "//third_party/chromium/..."])
+        .arg(&out)
+        .assert()
+        .success();
+
+    let hashes: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+    let labels: Vec<_> = hashes
+        .as_object()
+        .into_iter()
+        .flat_map(|m| m.keys())
+        .collect();
+    assert_eq!(labels, ["//pkg:a.txt"]);
+
+    generate_cmd(tmp.path())?
+        .args(["--excludePatterns", "@repo//pkg/..."])
+        .arg(&out)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid exclude pattern"));
+    Ok(())
+}
+
+#[test]
+fn bazel_commands_are_logged_as_json_lines() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let log = tmp.path().join("commands.jsonl");
+    generate_cmd(tmp.path())?
+        .arg("--bazelCommandOptions=--remote_header=token=abc")
+        .arg("--logBazelCommands")
+        .arg(&log)
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .success();
+
+    let records = std::fs::read_to_string(&log)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+    let query = records
+        .iter()
+        .find(|record| {
+            record["argv"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|arg| arg == "query")
+        })
+        .expect("the query is logged");
+    assert_eq!(query["exitCode"], 0);
+    assert!(query["durationMs"].is_u64());
+    assert!(query["argv"][0].as_str().unwrap().ends_with("fake-bazel"));
+    assert!(!query.to_string().contains("abc"));
+    assert!(records
+        .iter()
+        .all(|record| record["cwd"] == tmp.path().to_str().unwrap()));
+    Ok(())
+}
+
+#[test]
+fn dry_run_prints_the_bazel_commands_without_running_them() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let log = tmp.path().join("commands.jsonl");
+    let out = tmp.path().join("hashes.json");
+    let assert = generate_cmd(tmp.path())?
+        .args(["--dryRun", "--useCquery"])
+        .arg("--logBazelCommands")
+        .arg(&log)
+        .arg(&out)
+        .assert()
+        .success();
+
+    let script = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(script.contains(" info output_base execution_root release workspace\n"));
+    assert!(script.contains("<<'EOF'\ndeps(//...:all-targets)\nEOF\n"));
+    assert!(script.contains(" cquery --transitions=lite --output=streamed_proto "));
+    assert_eq!(std::fs::read_to_string(&log)?, "");
+    assert!(!out.exists());
+    Ok(())
+}
+
+#[test]
+fn detailed_json_splits_hashes_into_fields() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/a.txt"), "a")?;
+    let query = tmp.path().join("query.jsonl");
+    std::fs::write(
+        &query,
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:a.txt\"}}\n",
+    )?;
+    let mut outputs = Vec::new();
+    for format in ["json", "detailed-json"] {
+        let out = tmp.path().join(format!("hashes.{format}"));
+        generate_cmd(tmp.path())?
+            .arg("--queryProtoPath")
+            .arg(&query)
+            .args(["--includeTargetType", "--outputFormat", format])
+            .arg(&out)
+            .assert()
+            .success();
+        outputs.push(out);
+    }
+
+    let plain: serde_json::Value = serde_json::from_slice(&std::fs::read(&outputs[0])?)?;
+    let detailed: serde_json::Value = serde_json::from_slice(&std::fs::read(&outputs[1])?)?;
+    let entry = &detailed["//pkg:a.txt"];
+    assert_eq!(entry["type"], "SourceFile");
+    assert_eq!(
+        plain["//pkg:a.txt"],
+        format!(
+            "SourceFile#{}~{}",
+            entry["transitive"].as_str().unwrap(),
+            entry["direct"].as_str().unwrap()
+        )
+    );
+
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&outputs[0])
+        .arg("-fh")
+        .arg(&outputs[1])
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn package_loading_errors_are_reported_and_can_fail_the_run() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(&workspace)?;
+    let fake = fake_bazel(&workspace)?;
+    let bazel = tmp.path().join("failing-bazel");
+    std::fs::write(
+        &bazel,
+        format!(
+            "#!/bin/sh\ncase \" $* \" in *\" query \"*)\n\
+             echo \"ERROR: error loading package 'broken': BUILD file has a syntax error\" >&2\n\
+             exit 3 ;;\nesac\nexec {} \"$@\"\n",
+            fake.display()
+        ),
+    )?;
+    std::fs::set_permissions(&bazel, std::fs::Permissions::from_mode(0o755))?;
+    let generate = || -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("generate-hashes")
+            .arg("-w")
+            .arg(&workspace)
+            .arg("--bazelPath")
+            .arg(&bazel);
+        Ok(cmd)
+    };
+
+    let out = tmp.path().join("hashes.json");
+    generate()?
+        .args(["--outputFormat", "json-v2"])
+        .arg(&out)
+        .assert()
+        .success();
+    let hashes: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+    assert_eq!(
+        hashes["meta"]["queryErrors"],
+        serde_json::json!([{
+            "package": "//broken",
+            "message": "error loading package 'broken': BUILD file has a syntax error"
+        }])
+    );
+
+    generate()?
+        .arg("--failOnQueryErrors")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("1 error(s) loading packages"))
+        .stderr(predicate::str::contains("//broken: error loading package"));
+    Ok(())
+}
+
+#[test]
+fn color_follows_the_flag_and_no_color() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::write(
+        tmp.path().join(".bazel-differrous.toml"),
+        "bazelPth = \"x\"\n",
+    )?;
+    let validate = |args: &[&str], no_color: bool| -> Result<(String, String)> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.env_remove("NO_COLOR")
+            .args(args)
+            .args(["config", "validate", "-w"])
+            .arg(tmp.path());
+        if no_color {
+            cmd.env("NO_COLOR", "1");
+        }
+        let output = cmd.assert().failure().get_output().clone();
+        Ok((
+            String::from_utf8(output.stdout)?,
+            String::from_utf8(output.stderr)?,
+        ))
+    };
+
+    let (stdout, stderr) = validate(&["--color", "always"], true)?;
+    assert!(
+        stdout.contains("\x1b[33mbazelPth: unknown field"),
+        "{stdout}"
+    );
+    assert!(stderr.contains("\x1b[1;31m"), "{stderr}");
+    for (args, no_color) in [(&[][..], false), (&["--color=auto"][..], true)] {
+        let (stdout, stderr) = validate(args, no_color)?;
+        assert!(
+            !stdout.contains('\x1b') && !stderr.contains('\x1b'),
+            "{stdout}{stderr}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn impacted_test_suites_expand_into_their_impacted_tests() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/b.sh"), "b")?;
+    let query = tmp.path().join("query.jsonl");
+    let mut lines = Vec::new();
+    for test in ["a", "b"] {
+        lines.push(format!(
+            "{{\"type\":\"SOURCE_FILE\",\"sourceFile\":{{\"name\":\"//pkg:{test}.sh\"}}}}"
+        ));
+        lines.push(format!(
+            "{{\"type\":\"RULE\",\"rule\":{{\"name\":\"//pkg:{test}_test\",\"ruleClass\":\"sh_test\",\
+             \"ruleInput\":[\"//pkg:{test}.sh\"]}}}}"
+        ));
+    }
+    lines.push(
+        "{\"type\":\"RULE\",\"rule\":{\"name\":\"//pkg:suite\",\"ruleClass\":\"test_suite\",\
+         \"attribute\":[{\"name\":\"tests\",\"type\":\"LABEL_LIST\",\
+         \"stringListValue\":[\"//pkg:a_test\",\"//pkg:b_test\"]}],\
+         \"ruleInput\":[\"//pkg:a_test\",\"//pkg:b_test\"]}}"
+            .to_string(),
+    );
+    std::fs::write(&query, lines.join("\n") + "\n")?;
+
+    let hashes = |contents: &str, name: &str| -> Result<std::path::PathBuf> {
+        std::fs::write(tmp.path().join("pkg/a.sh"), contents)?;
+        let out = tmp.path().join(name);
+        Command::new(rust_cli_path()?)
+            .arg("generate-hashes")
+            .arg("-w")
+            .arg(tmp.path())
+            .arg("--queryProtoPath")
+            .arg(&query)
+            .args(["--outputFormat", "json-v2"])
+            .arg(&out)
+            .assert()
+            .success();
+        Ok(out)
+    };
+    let start = hashes("a", "start.json")?;
+    let last = hashes("a changed", "final.json")?;
+    let meta: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&last)?)?;
+    assert_eq!(
+        meta["meta"]["testSuites"],
+        serde_json::json!({"//pkg:suite": ["//pkg:a_test", "//pkg:b_test"]})
+    );
+
+    let impacted = |extra: &[&str]| {
+        let mut cmd = Command::new(rust_cli_path().unwrap());
+        cmd.arg("get-impacted-targets")
+            .arg("-sh")
+            .arg(&start)
+            .arg("-fh")
+            .arg(&last)
+            .args(extra);
+        cmd
+    };
+    impacted(&[])
+        .assert()
+        .success()
+        .stdout("//pkg:a.sh\n//pkg:a_test\n//pkg:suite\n");
+    impacted(&["--expandTestSuites"])
+        .assert()
+        .success()
+        .stdout("//pkg:a.sh\n//pkg:a_test\n");
+
+    let plain = tmp.path().join("plain.json");
+    generate_cmd(tmp.path())?
+        .arg("--queryProtoPath")
+        .arg(&query)
+        .arg(&plain)
+        .assert()
+        .success();
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&plain)
+        .arg("-fh")
+        .arg(&plain)
+        .arg("--expandTestSuites")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--outputFormat json-v2"));
+    Ok(())
+}
+
+#[test]
+fn gc_removes_idle_and_least_recently_used_state() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let root = tmp.path().join("bazel-differrous");
+    let write = |name: &str, bytes: usize, days: u64| -> Result<()> {
+        let path = root.join(name).join("content-cache.msgpack");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, vec![0; bytes])?;
+        let age = std::time::Duration::from_secs(days * 24 * 60 * 60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(std::time::SystemTime::now() - age)?;
+        Ok(())
+    };
+    write("0123456789abcdef", 1 << 20, 40)?;
+    write("1123456789abcdef", 1 << 20, 2)?;
+    write("2123456789abcdef", 1 << 20, 1)?;
+
+    let gc = || -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.env("XDG_CACHE_HOME", tmp.path()).arg("gc");
+        Ok(cmd)
+    };
+    gc()?
+        .assert()
+        .success()
+        .stdout(format!("{}\n", root.join("0123456789abcdef").display()));
+    gc()?
+        .args(["--maxSize", "1"])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", root.join("1123456789abcdef").display()));
+    assert!(root.join("2123456789abcdef").is_dir());
+    Ok(())
+}
+
+#[test]
+fn read_only_keeps_query_files_and_outputs_out_of_the_workspace() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(&workspace)?;
+    let bazel = fake_bazel(&workspace)?;
+    let log = tmp.path().join("bazel.log");
+    let wrapper = tmp.path().join("logging-bazel");
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\nexec {} \"$@\"\n",
+            log.display(),
+            bazel.display()
+        ),
+    )?;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    let generate = || -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("generate-hashes")
+            .arg("-w")
+            .arg(&workspace)
+            .arg("--bazelPath")
+            .arg(&wrapper)
+            .arg("--readOnly");
+        Ok(cmd)
+    };
+
+    generate()?
+        .arg(tmp.path().join("hashes.json"))
+        .assert()
+        .success();
+    let queries = std::fs::read_to_string(&log)?;
+    let query_files: Vec<&str> = queries
+        .split_whitespace()
+        .filter(|arg| arg.ends_with(".query"))
+        .collect();
+    assert!(!query_files.is_empty(), "{queries}");
+    for query_file in query_files {
+        assert!(
+            !Path::new(query_file).starts_with(&workspace),
+            "{query_file}"
+        );
+    }
+
+    generate()?
+        .arg(workspace.join("hashes.json"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("inside the read-only directory"));
+    assert!(!workspace.join("hashes.json").exists());
+    Ok(())
+}
+
+#[test]
+fn no_bazel_hashes_build_files_and_are_marked_approximate() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let workspace = tmp.path().join("ws");
+    std::fs::create_dir_all(workspace.join("pkg"))?;
+    std::fs::write(workspace.join("MODULE.bazel"), "")?;
+    std::fs::write(
+        workspace.join("pkg/BUILD.bazel"),
+        "genrule(\n    name = \"gen\",\n    srcs = glob([\"*.txt\"]),\n    outs = [\"gen.out\"],\n    cmd = \"cat $(SRCS) > $@\",\n)\n\nsh_test(name = \"t\", srcs = [\"t.sh\"], data = [\":gen\"])\n",
+    )?;
+    std::fs::write(workspace.join("pkg/a.txt"), "a")?;
+    std::fs::write(workspace.join("pkg/t.sh"), "true")?;
+    let generate = |output: &Path| -> Result<()> {
+        Command::new(rust_cli_path()?)
+            .arg("generate-hashes")
+            .arg("-w")
+            .arg(&workspace)
+            .arg("--bazelPath")
+            .arg(tmp.path().join("no-such-bazel"))
+            .arg("--noBazel")
+            .arg(output)
+            .assert()
+            .success();
+        Ok(())
+    };
+
+    let start = tmp.path().join("start.json");
+    generate(&start)?;
+    let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&start)?)?;
+    assert_eq!(document["meta"]["approximate"], true);
+    let labels: Vec<&str> = document["hashes"]
+        .as_object()
+        .expect("hashes object")
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "//pkg:a.txt",
+            "//pkg:gen",
+            "//pkg:gen.out",
+            "//pkg:t",
+            "//pkg:t.sh"
+        ]
+    );
+
+    std::fs::write(workspace.join("pkg/a.txt"), "changed")?;
+    let last = tmp.path().join("final.json");
+    generate(&last)?;
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&start)
+        .arg("-fh")
+        .arg(&last)
+        .assert()
+        .success()
+        .stdout("//pkg:a.txt\n//pkg:gen\n//pkg:gen.out\n//pkg:t\n");
+
+    let exact = tmp.path().join("exact.json");
+    std::fs::write(&exact, r#"{"//pkg:a.txt":"h"}"#)?;
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&exact)
+        .arg("-fh")
+        .arg(&last)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("with --noBazel"));
+    Ok(())
+}
+
+#[test]
+fn on_cycle_breaks_cycles_and_reports_them() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let query = tmp.path().join("query.jsonl");
+    let rule = |name: &str, dep: &str| {
+        format!(
+            "{{\"type\":\"RULE\",\"rule\":{{\"name\":\"//pkg:{name}\",\"ruleClass\":\"genrule\",\
+             \"ruleInput\":[\"//pkg:{dep}\"]}}}}"
+        )
+    };
+    std::fs::write(&query, [rule("a", "b"), rule("b", "a")].join("\n") + "\n")?;
+    let generate = |policy: &str| -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("generate-hashes")
+            .arg("-w")
+            .arg(tmp.path())
+            .arg("--queryProtoPath")
+            .arg(&query)
+            .args(["--onCycle", policy]);
+        Ok(cmd)
+    };
+    generate("error")?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Circular dependency detected: //pkg:a -> //pkg:b -> //pkg:a",
+        ));
+
+    let warnings = tmp.path().join("warnings.json");
+    let broken = generate("break")?
+        .arg("--warningsFile")
+        .arg(&warnings)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&warnings)?)?;
+    assert_eq!(
+        report["cycles"],
+        serde_json::json!([["//pkg:a", "//pkg:b", "//pkg:a"]])
+    );
+    let hashes: BTreeMap<String, String> = serde_json::from_slice(&broken)?;
+    assert_eq!(hashes.len(), 2);
+    generate("warn")?
+        .assert()
+        .success()
+        .stdout(String::from_utf8(broken)?)
+        .stderr(predicate::str::contains("breaking a dependency cycle"));
+    Ok(())
+}
+
+#[test]
+fn dep_edges_of_broken_cycles_give_impact_distances() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    let query = tmp.path().join("query.jsonl");
+    let rule = |name: &str, inputs: &[&str]| {
+        let inputs: Vec<String> = inputs.iter().map(|i| format!("\"//pkg:{i}\"")).collect();
+        format!(
+            "{{\"type\":\"RULE\",\"rule\":{{\"name\":\"//pkg:{name}\",\"ruleClass\":\"genrule\",\
+             \"ruleInput\":[{}]}}}}",
+            inputs.join(",")
+        )
+    };
+    let lines = [
+        "{\"type\":\"SOURCE_FILE\",\"sourceFile\":{\"name\":\"//pkg:c.txt\"}}".to_string(),
+        rule("a", &["b"]),
+        rule("b", &["a", "c"]),
+        rule("c", &["c.txt"]),
+    ];
+    std::fs::write(&query, lines.join("\n") + "\n")?;
+    let generate = |contents: &str, name: &str| -> Result<(PathBuf, PathBuf)> {
+        std::fs::write(tmp.path().join("pkg/c.txt"), contents)?;
+        let (hashes, edges) = (
+            tmp.path().join(format!("{name}.json")),
+            tmp.path().join(format!("{name}-deps.json")),
+        );
+        Command::new(rust_cli_path()?)
+            .arg("generate-hashes")
+            .arg("-w")
+            .arg(tmp.path())
+            .arg("--queryProtoPath")
+            .arg(&query)
+            .args(["--onCycle", "break", "--depEdgesFile"])
+            .arg(&edges)
+            .arg(&hashes)
+            .assert()
+            .success();
+        Ok((hashes, edges))
+    };
+    let (start, _) = generate("before", "start")?;
+    let (last, edges) = generate("after", "final")?;
+    let impacted = Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(&start)
+        .arg("-fh")
+        .arg(&last)
+        .arg("-d")
+        .arg(&edges)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let distances: Vec<serde_json::Value> = serde_json::from_slice(&impacted)?;
+    let distances: BTreeMap<&str, u64> = distances
+        .iter()
+        .map(|d| {
+            (
+                d["label"].as_str().unwrap(),
+                d["targetDistance"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        distances,
+        BTreeMap::from([
+            ("//pkg:a", 2),
+            ("//pkg:b", 1),
+            ("//pkg:c", 0),
+            ("//pkg:c.txt", 0)
+        ])
+    );
+    Ok(())
+}
@@ -0,0 +1,788 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use bazel_differrous_integration_tests::{fake_bazel, rust_cli_path, workspace_root};
+use std::path::PathBuf;
+
+fn impact_fixture(name: &str) -> PathBuf {
+    workspace_root().join("tests/fixtures/impact").join(name)
+}
+
+fn impacted_cmd() -> Result<Command> {
+    let mut cmd = Command::new(rust_cli_path()?);
+    cmd.arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(impact_fixture("starting.json"))
+        .arg("-fh")
+        .arg(impact_fixture("final.json"));
+    Ok(cmd)
+}
+
+#[test]
+fn markdown_format_renders_report() -> Result<()> {
+    let output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--format")
+        .arg("markdown")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let markdown = String::from_utf8(output)?;
+
+    assert!(markdown.starts_with("<!-- bazel-differrous-report -->"));
+    assert!(markdown.contains("| Impacted targets | 3 |"));
+    assert!(markdown.contains("| Directly impacted | 2 |"));
+    assert!(markdown.contains("<summary><code>//pkg</code> (2 targets)</summary>"));
+    assert!(markdown.contains("- `//app:bin`"));
+    Ok(())
+}
+
+#[test]
+fn query_expr_format_writes_a_set_expression() -> Result<()> {
+    let output = impacted_cmd()?
+        .args(["--format", "query-expr"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(
+        String::from_utf8(output)?,
+        "set(//pkg:data //app:bin //pkg:lib)\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn validate_accepts_fixtures_and_points_at_problems() -> Result<()> {
+    Command::new(rust_cli_path()?)
+        .arg("validate")
+        .arg(impact_fixture("final.json"))
+        .arg(impact_fixture("dep_edges.json"))
+        .assert()
+        .success();
+
+    let tmp = tempfile::tempdir()?;
+    let broken = tmp.path().join("broken.json");
+    std::fs::write(
+        &broken,
+        "{\n  \"//a:a\": \"ab~cd\",\n  \"//a:b\": \"Rule#ab~\"\n}\n",
+    )?;
+    let output = Command::new(rust_cli_path()?)
+        .arg("validate")
+        .arg(&broken)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let report = String::from_utf8(output)?;
+    assert!(
+        report.contains("hash map, 2 entries, 1 error(s)"),
+        "{report}"
+    );
+    assert!(report.contains("broken.json:3:3"), "{report}");
+    assert!(report.contains("3 |   \"//a:b\": \"Rule#ab~\""), "{report}");
+    Ok(())
+}
+
+#[test]
+fn dep_edges_convert_round_trip_and_v2_input() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let v2 = tmp.path().join("deps.v2.json");
+    Command::new(rust_cli_path()?)
+        .arg("convert-dep-edges")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg(&v2)
+        .args(["--to", "v2"])
+        .assert()
+        .success();
+    let indexed: serde_json::Value = serde_json::from_slice(&std::fs::read(&v2)?)?;
+    assert_eq!(indexed["labels"][0], "//app:bin");
+
+    let from_v1 = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let from_v2 = impacted_cmd()?
+        .arg("-d")
+        .arg(&v2)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(from_v1, from_v2);
+    Ok(())
+}
+
+#[test]
+fn reverse_dep_edges_give_the_same_impact() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let forward_output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    for (direction, format) in [("reverse", "v1"), ("both", "v2")] {
+        let converted = tmp.path().join(format!("{direction}.json"));
+        Command::new(rust_cli_path()?)
+            .arg("convert-dep-edges")
+            .arg(impact_fixture("dep_edges.json"))
+            .arg(&converted)
+            .args(["--to", format, "--depEdgesDirection", direction])
+            .assert()
+            .success();
+        Command::new(rust_cli_path()?)
+            .arg("validate")
+            .arg(&converted)
+            .assert()
+            .success();
+        let output = impacted_cmd()?
+            .arg("-d")
+            .arg(&converted)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert_eq!(output, forward_output, "{direction}");
+    }
+
+    let changed = tmp.path().join("changed.txt");
+    std::fs::write(&changed, "//pkg:lib\n")?;
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("--changedTargetsFile")
+        .arg(&changed)
+        .arg("-d")
+        .arg(tmp.path().join("reverse.json"))
+        .assert()
+        .success()
+        .stdout("//app:bin\n//pkg:lib\n");
+    Ok(())
+}
+
+#[test]
+fn json_format_reports_status_and_none_impacted_exit_code() -> Result<()> {
+    let output = impacted_cmd()?
+        .args(["--format", "json", "--failIfNoneImpacted"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let envelope: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(envelope["status"], "ok");
+    assert_eq!(envelope["impacted"].as_array().unwrap().len(), 3);
+    assert!(envelope.get("distances").is_none());
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(impact_fixture("starting.json"))
+        .arg("-fh")
+        .arg(impact_fixture("starting.json"))
+        .args(["--format", "json", "--failIfNoneImpacted"])
+        .assert()
+        .code(3)
+        .get_output()
+        .stdout
+        .clone();
+    let envelope: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(
+        envelope,
+        serde_json::json!({"status": "ok", "impacted": []})
+    );
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(impact_fixture("missing.json"))
+        .arg("-fh")
+        .arg(impact_fixture("final.json"))
+        .args(["--format", "json"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stdout
+        .clone();
+    let envelope: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(envelope["status"], "error");
+    assert!(envelope["error"].as_str().unwrap().contains("missing.json"));
+    Ok(())
+}
+
+#[test]
+fn cost_file_adds_weighted_distances() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let costs = tmp.path().join("costs.json");
+    std::fs::write(&costs, r#"{"//app:bin": 42.5, "//pkg:lib": 300}"#)?;
+
+    let output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--costFile")
+        .arg(&costs)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let distances: serde_json::Value = serde_json::from_slice(&output)?;
+    let weighted: Vec<_> = distances
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| (d["label"].as_str().unwrap(), d["weightedDistance"].as_f64()))
+        .collect();
+    assert_eq!(
+        weighted,
+        vec![
+            ("//pkg:data", Some(0.0)),
+            ("//app:bin", Some(42.5)),
+            ("//pkg:lib", Some(0.0)),
+        ]
+    );
+
+    std::fs::write(&costs, r#"{"//app:bin": -1}"#)?;
+    impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--costFile")
+        .arg(&costs)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("must be a non-negative number"));
+    Ok(())
+}
+
+#[test]
+fn generated_files_report_and_collapse_into_their_rule() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let write = |name: &str, body: &str| -> Result<PathBuf> {
+        let path = tmp.path().join(name);
+        std::fs::write(&path, body)?;
+        Ok(path)
+    };
+    let start = write(
+        "start.json",
+        r#"{"//pkg:gen": "Rule#a~1", "//pkg:in.txt": "SourceFile#a", "//pkg:out.txt": "GeneratedFile#a~1"}"#,
+    )?;
+    let last = write(
+        "final.json",
+        r#"{"//pkg:gen": "Rule#a~2", "//pkg:in.txt": "SourceFile#b", "//pkg:out.txt": "GeneratedFile#a~2"}"#,
+    )?;
+    let deps = write(
+        "deps.json",
+        r#"{"//pkg:gen": ["//pkg:in.txt"], "//pkg:out.txt": ["//pkg:gen"]}"#,
+    )?;
+    let run = |extra: &[&str]| -> Result<serde_json::Value> {
+        let output = Command::new(rust_cli_path()?)
+            .arg("get-impacted-targets")
+            .arg("-sh")
+            .arg(&start)
+            .arg("-fh")
+            .arg(&last)
+            .arg("-d")
+            .arg(&deps)
+            .args(extra)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        Ok(serde_json::from_slice(&output)?)
+    };
+
+    let distances = run(&[])?;
+    let generated = distances
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|d| d["label"] == "//pkg:out.txt")
+        .unwrap();
+    assert_eq!(generated["generatingRule"], "//pkg:gen");
+
+    let envelope = run(&["--format", "json", "--collapseGeneratedFiles"])?;
+    assert_eq!(
+        envelope["impacted"],
+        serde_json::json!(["//pkg:in.txt", "//pkg:gen"])
+    );
+
+    impacted_cmd()?
+        .arg("--collapseGeneratedFiles")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("needs dep edges"));
+    Ok(())
+}
+
+#[test]
+fn changed_targets_file_reports_rdeps_closure() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let changed = tmp.path().join("changed.txt");
+    std::fs::write(&changed, "//pkg:lib\n")?;
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("--changedTargetsFile")
+        .arg(&changed)
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(String::from_utf8(output)?, "//app:bin\n//pkg:lib\n");
+    Ok(())
+}
+
+#[test]
+fn trend_reports_consecutive_snapshots() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    std::fs::copy(
+        impact_fixture("starting.json"),
+        tmp.path().join("2024-05-01.json"),
+    )?;
+    std::fs::copy(
+        impact_fixture("final.json"),
+        tmp.path().join("2024-05-02.json"),
+    )?;
+
+    let output = Command::new(rust_cli_path()?)
+        .arg("trend")
+        .arg("--hashDir")
+        .arg(tmp.path())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let points: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(points[0]["date"], "2024-05-02");
+    assert_eq!(points[0]["impacted"], 3);
+    assert_eq!(points[0]["direct"], 2);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn post_process_filters_impacted_output() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let out = tmp.path().join("impacted.txt");
+    impacted_cmd()?
+        .args(["--postProcess", "grep -v '^//pkg' | sort -r"])
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success();
+    let impacted = std::fs::read_to_string(&out)?;
+    assert!(!impacted.is_empty());
+    assert!(impacted.lines().all(|l| !l.starts_with("//pkg")));
+
+    impacted_cmd()?
+        .args(["--postProcess", "exit 7"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--postProcess command `exit 7` failed",
+        ));
+    Ok(())
+}
+
+#[test]
+fn group_by_prefix_writes_capped_groups() -> Result<()> {
+    let output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .args(["--groupByPrefix", "depth=1,cap=1"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let groups: serde_json::Value = serde_json::from_slice(&output)?;
+    let prefixes: Vec<_> = groups
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|g| g["prefix"].as_str().unwrap())
+        .collect();
+    assert_eq!(prefixes, ["//app", "//pkg"]);
+    assert_eq!(groups[1]["count"], 2);
+    assert_eq!(groups[1]["targets"].as_array().unwrap().len(), 1);
+    assert_eq!(groups[1]["truncated"], true);
+
+    impacted_cmd()?
+        .args(["--groupByPrefix", "cap=3"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn extract_deps_prints_forward_closure() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let targets = tmp.path().join("targets.txt");
+    std::fs::write(&targets, "//app:bin\n")?;
+    let extract = || -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.args(["extract-deps", "-d"])
+            .arg(impact_fixture("dep_edges.json"))
+            .arg("--targets")
+            .arg(&targets);
+        Ok(cmd)
+    };
+
+    extract()?
+        .assert()
+        .success()
+        .stdout(r#"{"//app:bin":["//pkg:lib"]}"#);
+    extract()?
+        .args(["--transitive", "--format", "list"])
+        .assert()
+        .success()
+        .stdout("//app:bin\n//pkg:lib\n");
+    Ok(())
+}
+
+#[test]
+fn set_op_combines_impacted_lists() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let impacted = tmp.path().join("impacted.json");
+    impacted_cmd()?
+        .args(["--format", "json", "-o"])
+        .arg(&impacted)
+        .assert()
+        .success();
+    let quarantined = tmp.path().join("quarantined.txt");
+    std::fs::write(&quarantined, "# flaky\n@//pkg:lib\n")?;
+
+    let output = Command::new(rust_cli_path()?)
+        .args(["set-op", "subtract"])
+        .arg(&impacted)
+        .arg(&quarantined)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let remaining = String::from_utf8(output)?;
+    assert!(!remaining.is_empty());
+    assert!(!remaining.lines().any(|label| label == "//pkg:lib"));
+
+    Command::new(rust_cli_path()?)
+        .args(["set-op", "intersect", "--format", "json"])
+        .arg(&impacted)
+        .arg(&quarantined)
+        .assert()
+        .success()
+        .stdout(r#"{"status":"ok","impacted":["//pkg:lib"]}"#);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn tests_only_queries_bazel_and_validates_shards() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let bazel = fake_bazel(tmp.path())?;
+    // The stand-in Bazel knows no tests, so nothing is selected.
+    impacted_cmd()?
+        .arg("--testsOnly")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(&bazel)
+        .args(["--shardCount", "2", "--shardIndex", "1"])
+        .assert()
+        .success()
+        .stdout("");
+
+    impacted_cmd()?
+        .arg("--testsOnly")
+        .arg("-w")
+        .arg(tmp.path())
+        .arg("--bazelPath")
+        .arg(&bazel)
+        .args(["--shardCount", "2", "--shardIndex", "2"])
+        .assert()
+        .failure();
+    impacted_cmd()?
+        .args(["--shardCount", "2", "--shardIndex", "0"])
+        .assert()
+        .failure();
+    impacted_cmd()?.arg("--testsOnly").assert().failure();
+    Ok(())
+}
+
+#[test]
+fn filter_script_needs_the_starlark_feature() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let script = tmp.path().join("filter.star");
+    std::fs::write(
+        &script,
+        "def keep(label, type, distance):\n    return True\n",
+    )?;
+    // The test binary is built with default features.
+    impacted_cmd()?
+        .arg("--filterScript")
+        .arg(&script)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--features starlark"));
+    Ok(())
+}
+
+#[test]
+fn streaming_writes_labels_in_label_order() -> Result<()> {
+    impacted_cmd()?
+        .arg("--streaming")
+        .assert()
+        .success()
+        .stdout("//app:bin\n//pkg:data\n//pkg:lib\n");
+    impacted_cmd()?
+        .args(["--streaming", "--targetType", "Rule"])
+        .assert()
+        .success()
+        .stdout("//app:bin\n//pkg:lib\n");
+    impacted_cmd()?
+        .arg("--streaming")
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .assert()
+        .failure();
+    impacted_cmd()?
+        .args(["--streaming", "--format", "json"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("drop --format"));
+
+    let tmp = tempfile::tempdir()?;
+    let unsorted = tmp.path().join("unsorted.json");
+    std::fs::write(&unsorted, r#"{"//pkg:b": "1~1", "//pkg:a": "1~1"}"#)?;
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(impact_fixture("starting.json"))
+        .arg("-fh")
+        .arg(&unsorted)
+        .arg("--streaming")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("not sorted by label"));
+    // Without --streaming an unsorted file is loaded and sorted instead.
+    Command::new(rust_cli_path()?)
+        .arg("get-impacted-targets")
+        .arg("-sh")
+        .arg(impact_fixture("starting.json"))
+        .arg("-fh")
+        .arg(&unsorted)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("//pkg:a"));
+    Ok(())
+}
+
+#[test]
+fn untyped_hashes_compare_to_typed_ones_only_when_normalized() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let untyped = tmp.path().join("untyped.json");
+    std::fs::write(
+        &untyped,
+        r#"{
+  "//app:bin": "bin_trans_v2~bin_direct",
+  "//app:unused": "unused_same~unused_direct",
+  "//pkg:data": "data_v2~data_direct_v2",
+  "//pkg:helper": "helper_same~helper_direct",
+  "//pkg:lib": "lib_trans_v1~lib_direct_v1"
+}"#,
+    )?;
+    let compare = || -> Result<Command> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("get-impacted-targets")
+            .arg("-sh")
+            .arg(impact_fixture("starting.json"))
+            .arg("-fh")
+            .arg(&untyped);
+        Ok(cmd)
+    };
+    compare()?
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "//app:bin has a target type in the starting hashes but not in the final hashes",
+        ));
+    compare()?
+        .arg("--normalizeTargetType")
+        .assert()
+        .success()
+        .stdout("//pkg:data\n//app:bin\n");
+    compare()?
+        .args(["--normalizeTargetType", "--streaming"])
+        .assert()
+        .success()
+        .stdout("//app:bin\n//pkg:data\n");
+    Ok(())
+}
+
+#[test]
+fn coverage_map_adds_tests_covering_impacted_files() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let coverage = tmp.path().join("coverage.json");
+    std::fs::write(
+        &coverage,
+        r#"{"//app:unused": ["pkg/data"], "//pkg:helper": ["pkg/helper"],
+            "//e2e:deleted_test": ["pkg/data"]}"#,
+    )?;
+    // //e2e:deleted_test is not in the final hashes.
+    impacted_cmd()?
+        .arg("--coverageMap")
+        .arg(&coverage)
+        .assert()
+        .success()
+        .stdout("//pkg:data\n//app:bin\n//pkg:lib\n//app:unused\n");
+
+    let modified = tmp.path().join("modified.txt");
+    std::fs::write(&modified, "pkg/helper\n")?;
+    impacted_cmd()?
+        .arg("--coverageMap")
+        .arg(&coverage)
+        .arg("--modified-filepaths")
+        .arg(&modified)
+        .assert()
+        .success()
+        .stdout("//pkg:data\n//app:bin\n//pkg:lib\n//app:unused\n//pkg:helper\n");
+
+    let output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--coverageMap")
+        .arg(&coverage)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let distances: serde_json::Value = serde_json::from_slice(&output)?;
+    let added = distances
+        .as_array()
+        .and_then(|entries| entries.iter().find(|d| d["label"] == "//app:unused"))
+        .expect("the covering test has a distance");
+    assert_eq!(added["targetDistance"], 1);
+    assert_eq!(added["packageDistance"], 1);
+
+    std::fs::write(&coverage, r#"{"//e2e:data_test": ["/abs/path"]}"#)?;
+    impacted_cmd()?
+        .arg("--coverageMap")
+        .arg(&coverage)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "neither a workspace-relative path",
+        ));
+    Ok(())
+}
+
+#[test]
+fn compare_dep_edges_reports_dep_changes_per_impacted_target() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let starting_edges = tmp.path().join("starting_dep_edges.json");
+    std::fs::write(
+        &starting_edges,
+        r#"{"//app:bin": ["//pkg:lib", "//pkg:old"], "//pkg:lib": [], "//pkg:data": []}"#,
+    )?;
+    let output = impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--compareDepEdges")
+        .arg(&starting_edges)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let changes: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(
+        changes,
+        serde_json::json!([
+            {"label": "//pkg:data", "addedDeps": [], "removedDeps": [], "changedDeps": []},
+            {
+                "label": "//app:bin",
+                "addedDeps": [],
+                "removedDeps": ["//pkg:old"],
+                "changedDeps": ["//pkg:lib"]
+            },
+            {"label": "//pkg:lib", "addedDeps": [], "removedDeps": [], "changedDeps": []},
+        ])
+    );
+
+    impacted_cmd()?
+        .arg("--compareDepEdges")
+        .arg(&starting_edges)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn ignore_exec_edges_drops_edges_to_tools() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let with_exec = tmp.path().join("dep_edges.json");
+    std::fs::write(
+        &with_exec,
+        r#"{"depEdges": {"//app:bin": ["//pkg:lib"], "//pkg:lib": []}, "execDepEdges": {"//app:bin": ["//pkg:lib"]}}"#,
+    )?;
+    let reverse = tmp.path().join("reverse.json");
+    Command::new(rust_cli_path()?)
+        .arg("convert-dep-edges")
+        .arg(&with_exec)
+        .arg(&reverse)
+        .args(["--to", "v2", "--depEdgesDirection", "reverse"])
+        .assert()
+        .success();
+    Command::new(rust_cli_path()?)
+        .arg("validate")
+        .arg(&reverse)
+        .assert()
+        .success();
+
+    let changed = tmp.path().join("changed.txt");
+    std::fs::write(&changed, "//pkg:lib\n")?;
+    let rdeps = |dep_edges: &PathBuf, ignore_exec_edges: bool| -> Result<String> {
+        let mut cmd = Command::new(rust_cli_path()?);
+        cmd.arg("get-impacted-targets")
+            .arg("--changedTargetsFile")
+            .arg(&changed)
+            .arg("-d")
+            .arg(dep_edges);
+        if ignore_exec_edges {
+            cmd.arg("--ignoreExecEdges");
+        }
+        Ok(String::from_utf8(
+            cmd.assert().success().get_output().stdout.clone(),
+        )?)
+    };
+    assert_eq!(rdeps(&with_exec, false)?, "//app:bin\n//pkg:lib\n");
+    assert_eq!(rdeps(&with_exec, true)?, "//pkg:lib\n");
+    assert_eq!(rdeps(&reverse, true)?, "//pkg:lib\n");
+
+    impacted_cmd()?
+        .arg("-d")
+        .arg(impact_fixture("dep_edges.json"))
+        .arg("--ignoreExecEdges")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--execDepEdges"));
+    Ok(())
+}
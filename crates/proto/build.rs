@@ -2,13 +2,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=proto");
     println!("cargo:rerun-if-changed=proto/build.proto");
     println!("cargo:rerun-if-changed=proto/analysis_v2.proto");
+    println!("cargo:rerun-if-changed=proto/hashes.proto");
+    println!("cargo:rerun-if-changed=proto/remote_execution.proto");
 
     let protoc = protoc_bin_vendored::protoc_bin_path()?;
     std::env::set_var("PROTOC", protoc);
 
-    prost_build::Config::new().btree_map(["."]).compile_protos(
-        &["proto/build.proto", "proto/analysis_v2.proto"],
-        &["proto"],
-    )?;
+    let descriptor_path =
+        std::path::PathBuf::from(std::env::var("OUT_DIR")?).join("descriptors.bin");
+    prost_build::Config::new()
+        .btree_map(["."])
+        .file_descriptor_set_path(&descriptor_path)
+        .compile_protos(
+            &[
+                "proto/build.proto",
+                "proto/analysis_v2.proto",
+                "proto/hashes.proto",
+            ],
+            &["proto"],
+        )?;
+
+    // The REAPI action cache client; the caller connects the channel, and the
+    // server is only used by tests standing in for a remote cache.
+    tonic_build::configure()
+        .build_transport(false)
+        .compile_protos(&["proto/remote_execution.proto"], &["proto"])?;
+
+    // Proto3 JSON mapping for `--output=streamed_jsonproto` query results.
+    let descriptors = std::fs::read(descriptor_path)?;
+    pbjson_build::Builder::new()
+        .register_descriptors(&descriptors)?
+        .btree_map(["."])
+        .build(&[".blaze_query", ".stardoc_output"])?;
     Ok(())
 }
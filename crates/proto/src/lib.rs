@@ -3,6 +3,7 @@
 
 pub mod blaze_query {
     include!(concat!(env!("OUT_DIR"), "/blaze_query.rs"));
+    include!(concat!(env!("OUT_DIR"), "/blaze_query.serde.rs"));
 }
 
 // Backwards-compatible alias for callers expecting `build::...`.
@@ -12,8 +13,23 @@ pub mod analysis {
     include!(concat!(env!("OUT_DIR"), "/analysis.rs"));
 }
 
+/// Binary hash file format of `generate-hashes --outputFormat proto`.
+pub mod hashes {
+    include!(concat!(env!("OUT_DIR"), "/hashes.rs"));
+}
+
+/// The action cache of the Remote Execution API, for reading the output
+/// digests of cached actions.
+pub mod remote_execution {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/build.bazel.remote.execution.v2.rs"
+    ));
+}
+
 pub mod stardoc_output {
     include!(concat!(env!("OUT_DIR"), "/stardoc_output.rs"));
+    include!(concat!(env!("OUT_DIR"), "/stardoc_output.serde.rs"));
 }
 
 /// Returns a short label to make it obvious the crate linked correctly.